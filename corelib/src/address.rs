@@ -0,0 +1,133 @@
+use std::{fmt, str::FromStr};
+
+use bech32::{primitives::decode::CheckedHrpstring, Bech32m, Hrp};
+use ed25519_dalek::VerifyingKey;
+use hex::FromHex;
+
+use crate::{
+    config::Network,
+    errors::{Error, Result},
+};
+
+// Public key in a form people copy around. The key is bech32m encoded behind
+// the network's prefix, e.g. `aur1...` on mainnet, so the checksum catches
+// typos and swapped characters and an address of another network is told
+// apart before anything is paid to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Address {
+    network: Network,
+    key: [u8; 32],
+}
+
+impl Address {
+    pub fn new(network: Network, key: [u8; 32]) -> Self {
+        Self { network, key }
+    }
+
+    pub fn from_verifying_key(network: Network, key: &VerifyingKey) -> Self {
+        Self::new(network, key.to_bytes())
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn key(&self) -> [u8; 32] {
+        self.key
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hrp = Hrp::parse_unchecked(self.network.address_prefix());
+        bech32::encode_lower_to_fmt::<Bech32m, _>(f, hrp, &self.key).map_err(|_| fmt::Error)
+    }
+}
+
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::InvalidAddress(format!("{s}: {reason}"));
+
+        let checked = CheckedHrpstring::new::<Bech32m>(s).map_err(|e| invalid(&e.to_string()))?;
+        let prefix = checked.hrp().to_lowercase();
+        let network = Network::ALL
+            .into_iter()
+            .find(|network| network.address_prefix() == prefix)
+            .ok_or_else(|| invalid("unknown prefix"))?;
+        let key = <[u8; 32]>::try_from(checked.byte_iter().collect::<Vec<u8>>())
+            .map_err(|_| invalid("expected a 32 byte key"))?;
+
+        // Only keys that can sign can spend what's paid to them
+        VerifyingKey::from_bytes(&key).map_err(|_| invalid("not a public key"))?;
+
+        let address = Self::new(network, key);
+        // Padding bits are part of the checksum but not of the key, only the
+        // canonical encoding is accepted
+        if address.to_string() != s.to_lowercase() {
+            return Err(invalid("non-canonical encoding"));
+        }
+        Ok(address)
+    }
+}
+
+// Key of an address of the network, or of a hex encoded public key as
+// accepted before addresses existed
+pub fn parse_key(input: &str, network: Network) -> Result<[u8; 32]> {
+    if let Ok(key) = <[u8; 32]>::from_hex(input) {
+        return Ok(key);
+    }
+
+    let address: Address = input.parse()?;
+    if address.network != network {
+        return Err(Error::InvalidAddress(format!(
+            "{input} is a {} address, expected {network}",
+            address.network
+        )));
+    }
+    Ok(address.key)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rng::test_key;
+
+    use super::*;
+
+    #[test]
+    fn encodes_keys_with_checksum_and_network() {
+        let key = test_key("alice").verifying_key();
+        let address = Address::from_verifying_key(Network::Mainnet, &key);
+        let encoded = address.to_string();
+        assert!(encoded.starts_with("aur1"));
+
+        assert_eq!(encoded.parse::<Address>().unwrap(), address);
+        assert_eq!(encoded.to_uppercase().parse::<Address>().unwrap(), address);
+        assert_eq!(
+            parse_key(&encoded, Network::Mainnet).unwrap(),
+            key.to_bytes()
+        );
+        assert_eq!(
+            parse_key(&hex::encode(key.to_bytes()), Network::Mainnet).unwrap(),
+            key.to_bytes()
+        );
+
+        // A typo breaks the checksum
+        let mut typo = encoded.clone().into_bytes();
+        let last = typo.len() - 1;
+        typo[last] = if typo[last] == b'q' { b'p' } else { b'q' };
+        assert!(String::from_utf8(typo).unwrap().parse::<Address>().is_err());
+
+        // Addresses of other networks aren't mistaken for this one's
+        let testnet = Address::from_verifying_key(Network::Testnet, &key).to_string();
+        assert_eq!(
+            testnet.parse::<Address>().unwrap().network(),
+            Network::Testnet
+        );
+        assert!(matches!(
+            parse_key(&testnet, Network::Mainnet),
+            Err(Error::InvalidAddress(_))
+        ));
+    }
+}
@@ -1,28 +1,107 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::{errors::Result, merkle, transaction::Transaction};
+use crate::{
+    config::{
+        ChainParams, Network, GENESIS_TIMESTAMP, MAX_BLOCK_SIGOPS, MAX_DIFFICULTY,
+        MAX_FUTURE_BLOCK_TIME, MINING_ROUND, MIN_DIFFICULTY, TIMESTAMP_REFRESH_INTERVAL,
+    },
+    errors::{Error, Result},
+    merkle,
+    metrics::METRICS,
+    transaction::Transaction,
+};
 use borsh::{BorshDeserialize, BorshSerialize};
 
-// Structure of a block
+// Structure of a block, a header and the transactions it commits to
 #[derive(Debug, Clone, BorshDeserialize, BorshSerialize, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Block {
-    // Block height of the block
-    index: u64,
-    // Timestamp the block was "Mined"
-    timestamp: u128,
+    header: BlockHeader,
     // Collection of transactions included in this block
     transactions: Vec<Transaction>,
-    //
-    nonce: u64,
+}
+
+// Everything a block's hash commits to, the transactions through their
+// merkle roots. Peers exchange and validate headers without the transactions,
+// e.g. to check how a chain links together before downloading its blocks
+#[derive(Debug, Clone, BorshDeserialize, BorshSerialize, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockHeader {
+    // Block height of the block
+    pub index: u64,
+    // Bits the miner signals readiness for deployments with
+    pub version: u32,
+    // Timestamp the block was "Mined"
+    pub timestamp: u128,
     // Hash of the previous block
-    previous_hash: String,
+    pub previous_hash: String,
+    // Root of the merkle tree of the transaction ids
+    #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
+    pub merkle_root: [u8; 32],
+    // Root of the merkle tree of the wtxids, commits to the signatures and
+    // unlocking scripts the transaction ids leave out
+    #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
+    pub witness_root: [u8; 32],
+    pub nonce: u64,
+    pub difficulty: u32,
+    // Hash of the header
+    #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
+    pub hash: [u8; 32],
+}
 
-    // Hash of the entire block
-    hash: [u8; 32],
+impl BlockHeader {
+    pub fn calculate_hash(&self) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
 
-    difficulty: u32,
+        hasher.update(&self.index.to_le_bytes());
+        hasher.update(&self.version.to_le_bytes());
+        hasher.update(&self.timestamp.to_le_bytes());
+        hasher.update(self.previous_hash.as_bytes());
+        hasher.update(&self.merkle_root);
+        hasher.update(&self.witness_root);
+        hasher.update(&self.nonce.to_le_bytes());
+        hasher.update(&self.difficulty.to_le_bytes());
+
+        METRICS.record_hashed(8 + 4 + 16 + self.previous_hash.len() + 32 + 32 + 8 + 4);
+
+        *hasher.finalize().as_bytes()
+    }
+
+    // Whether the claimed hash satisfies the difficulty
+    pub fn meets_target(&self) -> bool {
+        meets_target(&self.hash, self.difficulty)
+    }
+
+    // Whether the header hashes to the claimed hash and it satisfies the
+    // difficulty, the proof of work checks out without the transactions
+    pub fn is_valid(&self) -> bool {
+        self.calculate_hash() == self.hash && self.meets_target()
+    }
+
+    // The timestamp can't be older than the median of its ancestors', which
+    // only moves forward, nor too far ahead of the local clock
+    pub fn check_timestamp(&self, median_time_past: u128, now: u128) -> Result<()> {
+        if self.timestamp < median_time_past {
+            return Err(Error::InvalidBlock(
+                "timestamp is older than the median of its ancestors".to_string(),
+            ));
+        }
+        if self.timestamp > now.saturating_add(MAX_FUTURE_BLOCK_TIME) {
+            return Err(Error::FutureBlock);
+        }
+
+        Ok(())
+    }
+
+    // Canonical encoding of the header, the Borsh encoding of its fields in
+    // declaration order
+    pub fn to_bytes(&self) -> Vec<u8> {
+        borsh::to_vec(self).expect("headers are always serializable")
+    }
 
-    merkle_root: merkle::Tree,
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(borsh::from_slice(bytes)?)
+    }
 }
 
 impl Block {
@@ -32,76 +111,443 @@ impl Block {
         previous_hash: String,
         difficulty: u32,
     ) -> Result<Self> {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis();
-        let txn_hashes = transactions
-            .iter()
-            .map(|t| t.hash_id)
-            .collect::<Vec<[u8; 32]>>();
-        let merkle_root = merkle::Tree::with_hashes(&txn_hashes);
+        Self::with_version(index, 0, transactions, previous_hash, difficulty)
+    }
 
+    // Mines a block signaling for the deployments whose bits are set in
+    // `version`
+    pub fn with_version(
+        index: u64,
+        version: u32,
+        transactions: Vec<Transaction>,
+        previous_hash: String,
+        difficulty: u32,
+    ) -> Result<Self> {
+        let mut block = Self::unmined(index, version, transactions, previous_hash, difficulty);
+
+        block.mine_block();
+        Ok(block)
+    }
+
+    // Block with its nonce search yet to be done, e.g. a template for the
+    // miners
+    fn unmined(
+        index: u64,
+        version: u32,
+        transactions: Vec<Transaction>,
+        previous_hash: String,
+        difficulty: u32,
+    ) -> Self {
         let mut block = Block {
-            index,
-            timestamp,
+            header: BlockHeader {
+                index,
+                version,
+                timestamp: now(),
+                previous_hash,
+                merkle_root: merkle_root(&transactions),
+                witness_root: witness_root(&transactions),
+                nonce: 0,
+                difficulty,
+                hash: [0u8; 32],
+            },
             transactions,
-            nonce: 0,
-            previous_hash,
-            hash: [0u8; 32],
-            difficulty,
-            merkle_root,
         };
 
-        block.mine_block();
+        block.header.hash = block.calculate_hash();
+        block
+    }
+
+    // First block of the network's chain, which every node of the network
+    // builds the same way. Its coinbase is tagged with the network's name so
+    // the networks don't share it
+    pub fn genesis(network: Network) -> Block {
+        let coinbase = Transaction::genesis_coinbase(GENESIS_TIMESTAMP, network.name().as_bytes())
+            .expect("network names fit a coinbase tag");
+        let mut block = Self::unmined(0, 0, vec![coinbase], hex::encode([0u8; 32]), MIN_DIFFICULTY);
+        // The nonce search starts over from zero, finding the same nonce
+        block.set_timestamp(GENESIS_TIMESTAMP);
+        while !block.try_mine(MINING_ROUND) {}
+
+        block
+    }
+
+    // Puts a block received as a header and its transactions back together,
+    // the transactions have to be the ones the header commits to
+    pub fn from_parts(header: BlockHeader, transactions: Vec<Transaction>) -> Result<Self> {
+        let block = Block {
+            header,
+            transactions,
+        };
+        block.check_merkle_root()?;
+
         Ok(block)
     }
-    pub fn calculate_hash(&self) -> [u8; 32] {
-        let mut hasher = blake3::Hasher::new();
 
-        hasher.update(&self.index.to_le_bytes());
-        hasher.update(&self.timestamp.to_le_bytes());
-        self.transactions.iter().for_each(|t| {
-            hasher.update(&t.hash_id);
-        });
+    pub fn into_parts(self) -> (BlockHeader, Vec<Transaction>) {
+        (self.header, self.transactions)
+    }
 
-        hasher.update(&self.nonce.to_le_bytes());
-        hasher.update(self.previous_hash.as_bytes());
-        // TODO: handle empty transaction blocks
-        hasher.update(&self.merkle_root.root_hash().unwrap());
+    // Canonical encoding of the block, sent to peers and written to disk:
+    // the header's encoding followed by the Borsh encoded transactions
+    pub fn to_bytes(&self) -> Vec<u8> {
+        borsh::to_vec(self).expect("blocks are always serializable")
+    }
+
+    // Decodes a block from its canonical encoding. Trailing bytes and
+    // transactions the header doesn't commit to are rejected
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let block: Block = borsh::from_slice(bytes)?;
+        block.check_merkle_root()?;
 
-        let result = hasher.finalize();
-        *result.as_bytes()
+        Ok(block)
+    }
+
+    pub fn calculate_hash(&self) -> [u8; 32] {
+        self.header.calculate_hash()
     }
 
     pub fn mine_block(&mut self) {
-        let target = u128::MAX >> self.difficulty;
+        self.mine_with_clock(now);
+    }
 
-        loop {
-            self.hash = self.calculate_hash();
+    // Mines in rounds, moving the timestamp to the current time whenever it
+    // fell `TIMESTAMP_REFRESH_INTERVAL` behind, so blocks that took long to
+    // mine aren't stamped with the time mining started
+    fn mine_with_clock(&mut self, clock: impl Fn() -> u128) {
+        while !self.try_mine(MINING_ROUND) {
+            let now = clock();
+            if now.saturating_sub(self.header.timestamp) >= TIMESTAMP_REFRESH_INTERVAL {
+                self.set_timestamp(now);
+            }
+        }
+    }
+
+    // Tries the next `attempts` nonces, returns whether one of them meets the
+    // target. Miners mine in rounds to check in between if their work is stale
+    pub fn try_mine(&mut self, attempts: u64) -> bool {
+        let header = &mut self.header;
+        for _ in 0..attempts {
+            header.hash = header.calculate_hash();
 
-            let hash_prefix = u128::from_be_bytes(self.hash[..16].try_into().unwrap());
-            if hash_prefix <= target {
-                println!("Block mined! Hash: {}", hex::encode(self.hash));
-                break;
+            if header.meets_target() {
+                return true;
             }
 
-            self.nonce = self.nonce.wrapping_add(1);
+            // Once every nonce was tried the timestamp rolls forward so the
+            // same hashes aren't tried again
+            header.nonce = header.nonce.wrapping_add(1);
+            if header.nonce == 0 {
+                header.timestamp += 1;
+            }
         }
+
+        false
+    }
+
+    // Continues the nonce search from `nonce`, miners working on the same
+    // block each search their own range
+    pub fn set_nonce(&mut self, nonce: u64) {
+        self.header.nonce = nonce;
+        self.header.hash = self.calculate_hash();
+    }
+
+    // Stamps the block with another time, only the timestamp changes so
+    // nothing but the hash has to be recomputed. The nonce search starts
+    // over
+    pub fn set_timestamp(&mut self, timestamp: u128) {
+        self.header.timestamp = timestamp;
+        self.header.nonce = 0;
+        self.header.hash = self.calculate_hash();
+    }
+
+    pub fn index(&self) -> u64 {
+        self.header.index
+    }
+
+    pub fn version(&self) -> u32 {
+        self.header.version
+    }
+
+    // Whether the block signals for the deployment using the version bit
+    pub fn signals(&self, bit: u8) -> bool {
+        bit < 32 && self.header.version & (1 << bit) != 0
+    }
+
+    // Milliseconds since the unix epoch the block was mined at
+    pub fn timestamp(&self) -> u128 {
+        self.header.timestamp
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        self.header.hash
+    }
+
+    pub fn previous_hash(&self) -> &str {
+        &self.header.previous_hash
+    }
+
+    pub fn difficulty(&self) -> u32 {
+        self.header.difficulty
+    }
+
+    pub fn merkle_root(&self) -> [u8; 32] {
+        self.header.merkle_root
+    }
+
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
     }
 
     pub fn is_valid(&self) -> bool {
-        let target = u128::MAX >> self.difficulty;
-        let hash_prefix = u128::from_be_bytes(self.hash[..16].try_into().unwrap());
-        hash_prefix <= target
+        self.header.meets_target()
+    }
+
+    // The header commits to exactly the block's transactions, their
+    // signatures and unlocking scripts included
+    pub fn check_merkle_root(&self) -> Result<()> {
+        if merkle_root(&self.transactions) != self.header.merkle_root {
+            return Err(Error::InvalidBlock(
+                "merkle root doesn't match the transactions".to_string(),
+            ));
+        }
+        if witness_root(&self.transactions) != self.header.witness_root {
+            return Err(Error::InvalidBlock(
+                "witness root doesn't match the transactions".to_string(),
+            ));
+        }
+
+        Ok(())
     }
+
+    // Proof that the transaction is under the block's merkle root, `None`
+    // if the block doesn't contain it
+    pub fn merkle_proof(&self, txid: &[u8; 32]) -> Option<merkle::Proof> {
+        let leaf = self
+            .transactions
+            .iter()
+            .position(|txn| &txn.hash_id == txid)?;
+        let txn_hashes: Vec<[u8; 32]> = self.transactions.iter().map(|t| t.hash_id).collect();
+
+        merkle::Tree::with_hashes(&txn_hashes).generate_proof(leaf as u32)
+    }
+
+    // Only the first transaction, the coinbase, can mint coins and at most
+    // the block subsidy plus the fees of the other transactions of the block
+    pub fn check_coinbase(&self) -> Result<()> {
+        self.check_coinbase_with(&ChainParams::default())
+    }
+
+    // Same as `check_coinbase` with the subsidy of the chain's parameters
+    pub fn check_coinbase_with(&self, params: &ChainParams) -> Result<()> {
+        if self
+            .transactions
+            .iter()
+            .skip(1)
+            .any(Transaction::is_coinbase)
+        {
+            return Err(Error::InvalidBlock(
+                "only the first transaction can be a coinbase".to_string(),
+            ));
+        }
+
+        let Some(coinbase) = self.transactions.first().filter(|t| t.is_coinbase()) else {
+            return Ok(());
+        };
+
+        let fees = self.transactions[1..]
+            .iter()
+            .map(Transaction::declared_fee)
+            .fold(0, u64::saturating_add);
+
+        if coinbase.output_value() > params.block_subsidy(self.header.index).saturating_add(fees) {
+            return Err(Error::InvalidBlock(
+                "coinbase pays more than the subsidy and fees".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn sigops(&self) -> usize {
+        self.transactions.iter().map(Transaction::sigops).sum()
+    }
+
+    // See `BlockHeader::check_timestamp`
+    pub fn check_timestamp(&self, median_time_past: u128, now: u128) -> Result<()> {
+        self.header.check_timestamp(median_time_past, now)
+    }
+
+    // Every transaction's locktime has passed at the block's height and time
+    pub fn check_locktimes(&self) -> Result<()> {
+        match self
+            .transactions
+            .iter()
+            .find(|txn| !txn.is_final(self.header.index, self.header.timestamp))
+        {
+            Some(txn) => Err(Error::InvalidBlock(format!(
+                "transaction {} is locked until {}",
+                hex::encode(txn.hash_id),
+                txn.locktime
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // Bounds the cost of verifying the block's signatures, every transaction
+    // is within the script limits and the block within the sigop limit
+    pub fn check_sigops(&self) -> Result<()> {
+        for txn in self.transactions.iter() {
+            txn.check_script_limits(&txn.unlocking_scripts)
+                .map_err(|e| Error::InvalidBlock(e.to_string()))?;
+        }
+
+        let sigops = self.sigops();
+        if sigops > MAX_BLOCK_SIGOPS {
+            return Err(Error::InvalidBlock(format!(
+                "{sigops} signature operations exceed the limit of {MAX_BLOCK_SIGOPS}"
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+// Assembles a block with a coinbase paying the subsidy and the fees of the
+// block's transactions to the miner as its first transaction
+#[derive(Debug, Clone)]
+pub struct BlockBuilder {
+    index: u64,
+    previous_hash: String,
+    difficulty: u32,
+    miner: [u8; 32],
+    transactions: Vec<Transaction>,
+    version: u32,
+    params: ChainParams,
+    coinbase_tag: Vec<u8>,
+}
+
+impl BlockBuilder {
+    pub fn new(index: u64, previous_hash: String, difficulty: u32, miner: [u8; 32]) -> Self {
+        Self {
+            index,
+            previous_hash,
+            difficulty,
+            miner,
+            transactions: Vec::new(),
+            version: 0,
+            params: ChainParams::default(),
+            coinbase_tag: Vec::new(),
+        }
+    }
+
+    // Tags the coinbase, see `Transaction::set_coinbase_tag`
+    pub fn coinbase_tag(mut self, tag: &[u8]) -> Self {
+        self.coinbase_tag = tag.to_vec();
+        self
+    }
+
+    // Pays the subsidy of the chain's parameters instead of the default one
+    pub fn params(mut self, params: ChainParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    // Signals for the deployments whose bits are set
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn transaction(mut self, txn: Transaction) -> Self {
+        self.transactions.push(txn);
+        self
+    }
+
+    pub fn transactions(mut self, txns: impl IntoIterator<Item = Transaction>) -> Self {
+        self.transactions.extend(txns);
+        self
+    }
+
+    // Adds the coinbase and mines the block
+    pub fn build(self) -> Result<Block> {
+        let mut block = self.template()?;
+        block.mine_block();
+
+        Ok(block)
+    }
+
+    // Adds the coinbase and leaves mining the block to the caller
+    pub fn template(self) -> Result<Block> {
+        let fees = self
+            .transactions
+            .iter()
+            .map(Transaction::declared_fee)
+            .fold(0, u64::saturating_add);
+
+        let reward = self.params.block_subsidy(self.index).saturating_add(fees);
+        let mut coinbase = Transaction::coinbase_paying(self.miner, self.index, reward)?;
+        if !self.coinbase_tag.is_empty() {
+            coinbase.set_coinbase_tag(&self.coinbase_tag)?;
+        }
+        let mut transactions = vec![coinbase];
+        transactions.extend(self.transactions);
+
+        Ok(Block::unmined(
+            self.index,
+            self.version,
+            transactions,
+            self.previous_hash,
+            self.difficulty,
+        ))
+    }
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+}
+
+// Root of the merkle tree of the transaction ids, zeros without transactions
+fn merkle_root(transactions: &[Transaction]) -> [u8; 32] {
+    let txn_hashes: Vec<[u8; 32]> = transactions.iter().map(|t| t.hash_id).collect();
+
+    merkle::Tree::with_hashes(&txn_hashes)
+        .root_hash()
+        .unwrap_or([0u8; 32])
+}
+
+// Root of the merkle tree of the wtxids, zeros without transactions
+fn witness_root(transactions: &[Transaction]) -> [u8; 32] {
+    let wtxids: Vec<[u8; 32]> = transactions.iter().map(Transaction::wtxid).collect();
+
+    merkle::Tree::with_hashes(&wtxids)
+        .root_hash()
+        .unwrap_or([0u8; 32])
+}
+
+// Difficulties outside the bounds never meet the target, headers from peers
+// may claim any difficulty
+fn meets_target(hash: &[u8; 32], difficulty: u32) -> bool {
+    if !(MIN_DIFFICULTY..=MAX_DIFFICULTY).contains(&difficulty) {
+        return false;
+    }
+    let target = u128::MAX >> difficulty;
+    let hash_prefix = u128::from_be_bytes(hash[..16].try_into().unwrap());
+    hash_prefix <= target
 }
 
 #[cfg(test)]
 mod test {
     use crate::{
         block::*,
-        test_utils::{generate_key_pairs, generate_random_utxos},
+        config::{block_subsidy, MAX_FUTURE_BLOCK_TIME},
+        test_utils::{create_mock_transaction, generate_key_pairs, generate_random_utxos},
         transaction::Transaction,
     };
 
@@ -132,23 +578,179 @@ mod test {
         .unwrap();
 
         // Calculating hash manually to compare with block's hash
+        let header = block.header();
         let mut hasher = blake3::Hasher::new();
-        hasher.update(&block.index.to_le_bytes());
-        hasher.update(&block.timestamp.to_le_bytes());
-        transactions.iter().for_each(|t| {
-            hasher.update(&t.hash_id);
-        });
-        hasher.update(&block.nonce.to_le_bytes());
-        hasher.update(block.previous_hash.as_bytes());
-        hasher.update(&block.merkle_root.root_hash().unwrap());
+        hasher.update(&header.index.to_le_bytes());
+        hasher.update(&header.version.to_le_bytes());
+        hasher.update(&header.timestamp.to_le_bytes());
+        hasher.update(header.previous_hash.as_bytes());
+        hasher.update(&merkle_root(&transactions));
+        hasher.update(&witness_root(&transactions));
+        hasher.update(&header.nonce.to_le_bytes());
+        hasher.update(&header.difficulty.to_le_bytes());
 
         let expected_hash = *hasher.finalize().as_bytes();
         assert_eq!(
-            block.hash, expected_hash,
+            block.hash(),
+            expected_hash,
             "Block hash should be correctly calculated."
         );
     }
 
+    #[test]
+    fn encodes_blocks_canonically() {
+        let block = BlockBuilder::new(1, hex::encode([0u8; 32]), 1, [3u8; 32])
+            .build()
+            .unwrap();
+
+        let bytes = block.to_bytes();
+        assert_eq!(
+            &bytes[..block.header().to_bytes().len()],
+            block.header().to_bytes()
+        );
+        assert_eq!(Block::from_bytes(&bytes).unwrap(), block);
+        assert_eq!(
+            BlockHeader::from_bytes(&block.header().to_bytes()).unwrap(),
+            *block.header()
+        );
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(Block::from_bytes(&trailing).is_err());
+
+        // Swapping the transactions breaks the header's commitment
+        let (header, _) = block.into_parts();
+        let other = Block {
+            header,
+            transactions: vec![Transaction::coinbase([4u8; 32], 1, 0).unwrap()],
+        };
+        assert!(matches!(
+            Block::from_bytes(&borsh::to_vec(&other).unwrap()),
+            Err(Error::InvalidBlock(_))
+        ));
+    }
+
+    #[test]
+    fn validates_headers_without_transactions() {
+        let (txn, _) = create_mock_transaction(1_000, 990);
+        let block = BlockBuilder::new(1, "previous_hash_example".to_string(), 1, [3u8; 32])
+            .transaction(txn)
+            .build()
+            .unwrap();
+        assert!(block.header().is_valid());
+
+        let mut header = block.header().clone();
+        header.nonce += 1;
+        assert!(!header.is_valid());
+
+        // Every hash would meet a difficulty of 0, and 128 bits or more can't
+        // be shifted out of the target
+        for difficulty in [0, MAX_DIFFICULTY + 1, u32::MAX] {
+            let mut header = block.header().clone();
+            header.difficulty = difficulty;
+            header.hash = header.calculate_hash();
+            assert!(!header.is_valid());
+        }
+
+        let (header, mut transactions) = block.clone().into_parts();
+        assert_eq!(
+            Block::from_parts(header.clone(), transactions.clone()).unwrap(),
+            block
+        );
+
+        // Signatures leave the transaction ids alone, the header commits to
+        // them through the witness root
+        let mut resigned = transactions.clone();
+        resigned[1].signature[0] ^= 1;
+        assert_eq!(merkle_root(&resigned), header.merkle_root);
+        assert!(matches!(
+            Block::from_parts(header.clone(), resigned),
+            Err(Error::InvalidBlock(_))
+        ));
+
+        transactions.pop();
+        assert!(matches!(
+            Block::from_parts(header, transactions),
+            Err(Error::InvalidBlock(_))
+        ));
+    }
+
+    #[test]
+    fn builder_adds_coinbase_first() {
+        let (txn, _) = create_mock_transaction(1_000, 990);
+        let miner = [3u8; 32];
+
+        let block = BlockBuilder::new(1, "previous_hash_example".to_string(), 1, miner)
+            .transaction(txn.clone())
+            .build()
+            .unwrap();
+
+        let coinbase = &block.transactions()[0];
+        assert!(coinbase.is_coinbase());
+        assert_eq!(coinbase.receiver, miner);
+        assert_eq!(coinbase.output_value(), block_subsidy(1) + 10);
+        assert_eq!(block.transactions()[1], txn);
+        assert!(block.check_coinbase().is_ok());
+
+        let proof = block.merkle_proof(&txn.hash_id).unwrap();
+        assert!(merkle::Tree::verify_proof(
+            txn.hash_id,
+            &proof,
+            block.merkle_root()
+        ));
+        assert!(block.merkle_proof(&[9u8; 32]).is_none());
+
+        // Claiming more than the fees paid is rejected
+        let greedy = Transaction::coinbase(miner, 1, 11).unwrap();
+        let block =
+            Block::new(1, vec![greedy, txn], "previous_hash_example".to_string(), 1).unwrap();
+        assert!(matches!(
+            block.check_coinbase(),
+            Err(Error::InvalidBlock(_))
+        ));
+
+        // So is minting coins anywhere but the first transaction
+        let coinbase = Transaction::coinbase(miner, 1, 0).unwrap();
+        let minted = Transaction::coinbase([4u8; 32], 1, 0).unwrap();
+        let block = Block::new(
+            1,
+            vec![coinbase, minted],
+            "previous_hash_example".to_string(),
+            1,
+        )
+        .unwrap();
+        assert!(matches!(
+            block.check_coinbase(),
+            Err(Error::InvalidBlock(_))
+        ));
+    }
+
+    #[test]
+    fn rolls_timestamp_once_nonces_run_out() {
+        let miner = [3u8; 32];
+        let mut block = BlockBuilder::new(1, "previous_hash_example".to_string(), 1, miner)
+            .build()
+            .unwrap();
+        let timestamp = block.timestamp();
+
+        // No hash meets the highest difficulty
+        block.header.difficulty = 127;
+        block.header.nonce = u64::MAX;
+        assert!(!block.try_mine(2));
+        assert_eq!(block.timestamp(), timestamp + 1);
+        assert_eq!(block.header.nonce, 1);
+
+        block.set_timestamp(timestamp + 5_000);
+        assert_eq!(block.header.nonce, 0);
+        assert_eq!(block.hash(), block.calculate_hash());
+
+        assert!(block.check_timestamp(timestamp, timestamp).is_ok());
+        assert!(block.check_timestamp(timestamp + 5_001, timestamp).is_err());
+        assert!(block
+            .check_timestamp(0, timestamp + 5_000 - MAX_FUTURE_BLOCK_TIME - 1)
+            .is_err());
+    }
+
     #[test]
     fn test_block_mining() {
         let (mut signing_key, _, sender, receiver) = generate_key_pairs().unwrap();
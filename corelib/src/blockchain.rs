@@ -1,15 +1,1629 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    io::{Read, Result as IoResult, Write},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use borsh::{BorshDeserialize, BorshSerialize};
+use hex::FromHex;
+use imbl::{HashMap as SharedMap, Vector};
 
-use crate::{block::Block, mempool::MemPool};
+use crate::{
+    block::{Block, BlockBuilder, BlockHeader},
+    config::{retarget, ChainParams, Network, MEDIAN_TIME_BLOCKS},
+    errors::{Error, Result},
+    memory::{map_entry_usage, MemoryUsage},
+    mempool::{MemPool, SelectionStrategy},
+    transaction::Transaction,
+    utxo::{OutPoint, UTXO},
+};
 
+// The collections are persistent ones sharing their structure between
+// clones, so a copy of the chain, like the snapshot the node's RPC reads, only
+// copies the parts changed after it was taken. They're stored like the
+// standard collections.
 #[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
 pub struct BlockChain {
-    blocks: Vec<Block>,
-    difficulty: u32,
-    mempool: MemPool
+    // Every known block, including the ones on competing branches
+    #[borsh(serialize_with = "serialize_map", deserialize_with = "deserialize_map")]
+    known: SharedMap<[u8; 32], Arc<BlockEntry>>,
+    // Hashes of the best chain blocks, indexed by height
+    #[borsh(
+        serialize_with = "serialize_vector",
+        deserialize_with = "deserialize_vector"
+    )]
+    best: Vector<[u8; 32]>,
+    // Unspent outputs as of the best chain tip
+    #[borsh(serialize_with = "serialize_map", deserialize_with = "deserialize_map")]
+    utxos: SharedMap<OutPoint, UTXO>,
+    // Outputs each best chain block spent, in the order of its transactions
+    // and their inputs. They're put back when the block is disconnected
+    #[borsh(serialize_with = "serialize_map", deserialize_with = "deserialize_map")]
+    spent: SharedMap<[u8; 32], Arc<Vec<UTXO>>>,
+    // Block every known transaction was included in. Entries are kept when
+    // their block is disconnected so lookups can report it as orphaned.
+    #[borsh(serialize_with = "serialize_map", deserialize_with = "deserialize_map")]
+    tx_index: SharedMap<[u8; 32], TxLocation>,
+    // Difficulty of every block on networks that don't retarget. Not
+    // stored, it's set again for the network whenever the chain is loaded
+    #[borsh(skip)]
+    fixed_difficulty: Option<u32>,
+    // Set again whenever the chain is loaded, like the difficulty
+    #[borsh(skip)]
+    params: ChainParams,
+}
+
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+struct BlockEntry {
+    block: Block,
+    // Work of the block and all of its ancestors
+    cumulative_work: u128,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct TxLocation {
+    pub block_hash: [u8; 32],
+    pub height: u64,
+}
+
+// A known block containing a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TxInclusion {
+    #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
+    pub block_hash: [u8; 32],
+    pub height: u64,
+    pub on_best_chain: bool,
+    // Blocks on top of and including the containing block, zero for blocks
+    // off the best chain
+    pub confirmations: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "status", rename_all = "snake_case"))]
+pub enum TxStatus {
+    // Waiting in the mempool to be included in a block
+    Pending,
+    Confirmed {
+        #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
+        block_hash: [u8; 32],
+        height: u64,
+        // Number of blocks on top of and including the containing block
+        confirmations: u64,
+    },
+    // The containing block was disconnected from the best chain
+    Orphaned {
+        #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
+        block_hash: [u8; 32],
+        height: u64,
+    },
+    Unknown,
+}
+
+// Changes to the best chain caused by adding a block
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChainUpdate {
+    // Blocks rolled back by a reorganization, tip first
+    pub disconnected: Vec<Block>,
+    // Blocks applied to the best chain, lowest first
+    pub connected: Vec<Block>,
+}
+
+impl ChainUpdate {
+    pub fn is_reorg(&self) -> bool {
+        !self.disconnected.is_empty()
+    }
+
+    // Combines with the update of a block added afterwards
+    pub fn append(&mut self, next: ChainUpdate) {
+        for block in next.disconnected {
+            match self
+                .connected
+                .iter()
+                .position(|connected| *connected == block)
+            {
+                Some(position) => {
+                    self.connected.remove(position);
+                }
+                None => self.disconnected.push(block),
+            }
+        }
+        self.connected.extend(next.connected);
+    }
 }
 
-impl BlockChain{
+impl BlockChain {
+    // Chain of the network, which has to start from the network's genesis
+    // block
+    pub fn new(genesis: Block, network: Network) -> Result<Self> {
+        if genesis.hash() != network.genesis_hash() {
+            return Err(Error::InvalidBlock(format!(
+                "not the genesis block of {network}"
+            )));
+        }
+
+        Self::from_genesis(genesis, network)
+    }
+
+    // Chain starting from any genesis block, e.g. for tests that build
+    // chains of their own
+    pub(crate) fn from_genesis(genesis: Block, network: Network) -> Result<Self> {
+        if genesis.index() != 0 || !genesis.is_valid() {
+            return Err(Error::InvalidBlock("invalid genesis block".to_string()));
+        }
+        genesis.check_coinbase()?;
+        genesis.check_sigops()?;
+        genesis.check_locktimes()?;
+
+        // Nothing was unspent before the genesis block
+        let mut utxos = SharedMap::new();
+        apply_block(&mut utxos, &genesis)?;
+
+        let mut chain = Self {
+            known: SharedMap::new(),
+            best: Vector::new(),
+            utxos,
+            spent: SharedMap::new(),
+            tx_index: SharedMap::new(),
+            fixed_difficulty: network.fixed_difficulty(),
+            params: ChainParams::default(),
+        };
+
+        let hash = genesis.hash();
+        chain.known.insert(
+            hash,
+            Arc::new(BlockEntry {
+                cumulative_work: block_work(genesis.difficulty()),
+                block: genesis,
+            }),
+        );
+        chain.connect(hash, Vec::new());
+
+        Ok(chain)
+    }
+
+    // Follows the network's parameters instead of the defaults
+    pub fn with_params(mut self, params: ChainParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    // Number of blocks in the chain
+    pub fn height(&self) -> u64 {
+        self.best.len() as u64
+    }
+
+    pub fn tip(&self) -> &Block {
+        // The genesis block is never disconnected
+        let hash = self.best.back().expect("chain always has a genesis block");
+        &self.known[hash].block
+    }
+
+    // Block at the given height of the best chain
+    pub fn block(&self, height: u64) -> Option<&Block> {
+        self.best
+            .get(height as usize)
+            .map(|hash| &self.known[hash].block)
+    }
+
+    // Looks up a block on any known branch
+    pub fn block_by_hash(&self, hash: &[u8; 32]) -> Option<&Block> {
+        self.known.get(hash).map(|entry| &entry.block)
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.known.contains_key(hash)
+    }
+
+    pub fn cumulative_work(&self) -> u128 {
+        self.known[&self.tip().hash()].cumulative_work
+    }
+
+    // Difficulty a block on top of the current tip must have
+    pub fn next_difficulty(&self) -> u32 {
+        self.difficulty_after(&self.tip().hash())
+    }
+
+    // Unmined block on top of the tip paying `miner_pubkey`, with the pool
+    // transactions paying the highest fee rates for their packages. They stay
+    // in the pool until the block is connected
+    pub fn create_block_template(
+        &self,
+        mempool: &MemPool,
+        miner_pubkey: [u8; 32],
+        version: u32,
+        coinbase_tag: &[u8],
+    ) -> Result<Block> {
+        BlockBuilder::new(
+            self.height(),
+            hex::encode(self.tip().hash()),
+            self.next_difficulty(),
+            miner_pubkey,
+        )
+        .version(version)
+        .params(self.params)
+        .coinbase_tag(coinbase_tag)
+        .transactions(mempool.select_for_block(
+            self.params.max_block_size,
+            SelectionStrategy::AncestorPackage,
+        ))
+        .template()
+    }
+
+    pub fn utxos(&self) -> &SharedMap<OutPoint, UTXO> {
+        &self.utxos
+    }
+
+    // Unspent outputs as of a known block, on the block's own branch. The
+    // best chain is rolled back to where the branch leaves it and the
+    // branch's blocks are applied on top
+    pub fn utxos_at(&self, hash: &[u8; 32]) -> Option<SharedMap<OutPoint, UTXO>> {
+        let (fork_height, branch) = self.branch(hash)?;
+
+        let mut utxos = self.utxos.clone();
+        for height in (fork_height as usize + 1..self.best.len()).rev() {
+            let hash = self.best[height];
+            undo_block(&mut utxos, &self.known[&hash].block, &self.spent[&hash]);
+        }
+        for hash in branch.iter().rev() {
+            // Every block was checked against the outputs on its branch when
+            // it was added
+            apply_block(&mut utxos, &self.known[hash].block).ok()?;
+        }
+
+        Some(utxos)
+    }
+
+    // Outputs the transactions of a block extending a known block spend, in
+    // the order of the transactions and their inputs, as stored on the
+    // parent's branch. Fails for blocks spending outputs missing there,
+    // already spent or declared differently than stored
+    pub fn spent_outputs(&self, block: &Block) -> Result<Vec<Vec<UTXO>>> {
+        let parent = <[u8; 32]>::from_hex(block.previous_hash())
+            .map_err(|_| Error::InvalidBlock("malformed previous hash".to_string()))?;
+        let mut utxos = self
+            .utxos_at(&parent)
+            .ok_or_else(|| Error::InvalidBlock("unknown parent block".to_string()))?;
+
+        apply_block(&mut utxos, block)
+    }
+
+    // Looks up a transaction of a block on the best chain
+    pub fn transaction(&self, txid: &[u8; 32]) -> Option<&Transaction> {
+        let location = self.tx_index.get(txid)?;
+
+        self.block_by_hash(&location.block_hash)?
+            .transactions()
+            .iter()
+            .find(|txn| &txn.hash_id == txid)
+    }
+
+    // Unspent outputs paid to the public key
+    pub fn unspent(&self, owner: &[u8; 32]) -> impl Iterator<Item = &UTXO> + '_ {
+        let owner = *owner;
+        self.utxos
+            .values()
+            .filter(move |utxo| utxo.is_owned_by(&owner))
+    }
+
+    // Best chain transactions paying or spending outputs of the public key,
+    // oldest first, along with the height of their block
+    pub fn address_history(
+        &self,
+        owner: &[u8; 32],
+    ) -> impl Iterator<Item = (u64, &Transaction)> + '_ {
+        let owner = *owner;
+        self.best
+            .iter()
+            .enumerate()
+            .flat_map(move |(height, hash)| {
+                self.known[hash]
+                    .block
+                    .transactions()
+                    .iter()
+                    .filter(move |txn| {
+                        txn.inputs
+                            .iter()
+                            .chain(&txn.outputs)
+                            .any(|utxo| utxo.is_owned_by(&owner))
+                    })
+                    .map(move |txn| (height as u64, txn))
+            })
+    }
+
+    // Total value of the unspent outputs paid to the public key
+    pub fn balance(&self, owner: &[u8; 32]) -> u64 {
+        self.unspent(owner).map(UTXO::value).sum()
+    }
+
+    // Approximate memory the unspent outputs take
+    pub fn utxo_memory_usage(&self) -> usize {
+        self.utxos.len() * map_entry_usage::<OutPoint, UTXO>()
+            + self.utxos.values().map(UTXO::heap_usage).sum::<usize>()
+    }
+
+    // Adds a block on top of any known block.
+    //
+    // Blocks on a competing branch are kept, and once the branch has more
+    // cumulative work than the best chain the chain reorganizes onto it.
+    pub fn add_block(&mut self, block: Block) -> Result<ChainUpdate> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        self.add_block_at(block, now)
+    }
+
+    // Like `add_block`, but the block's timestamp is judged against `now`
+    // instead of the local clock, e.g. the network-adjusted time
+    pub fn add_block_at(&mut self, block: Block, now: u128) -> Result<ChainUpdate> {
+        let hash = block.hash();
+        if self.contains(&hash) {
+            return Err(Error::InvalidBlock("block already known".to_string()));
+        }
+        let previous_hash = self.check_block_at(&block, now)?;
+
+        let cumulative_work = self.known[&previous_hash]
+            .cumulative_work
+            .saturating_add(block_work(block.difficulty()));
+        self.known.insert(
+            hash,
+            Arc::new(BlockEntry {
+                block,
+                cumulative_work,
+            }),
+        );
+
+        if cumulative_work <= self.cumulative_work() {
+            return Ok(ChainUpdate::default());
+        }
+
+        self.reorganize(hash).inspect_err(|_| {
+            self.known.remove(&hash);
+        })
+    }
+
+    // Checks `add_block_at` runs before taking a block, without taking it:
+    // that it extends a known block at the next height with the expected
+    // difficulty and a valid proof of work, timestamp and coinbase, and that
+    // its transactions spend outputs unspent on the parent's branch. Returns
+    // the hash of the parent
+    pub fn check_block_at(&self, block: &Block, now: u128) -> Result<[u8; 32]> {
+        let previous_hash = self.check_header_at(block.header(), now)?;
+        block.check_merkle_root()?;
+        block.check_coinbase_with(&self.params)?;
+        block.check_sigops()?;
+        block.check_locktimes()?;
+        self.check_spends(block)?;
+
+        Ok(previous_hash)
+    }
+
+    // Every transaction of the block spends outputs unspent on the parent's
+    // branch, as they're stored there, and pays out at most what they're
+    // worth. The inputs being the stored outputs, the fees the coinbase
+    // claims are the ones actually paid
+    fn check_spends(&self, block: &Block) -> Result<()> {
+        let spent = self.spent_outputs(block)?;
+
+        for (txn, spent) in block.transactions().iter().zip(spent) {
+            if txn.is_coinbase() {
+                continue;
+            }
+            txn.fee_spending(&spent).map_err(|e| {
+                Error::InvalidBlock(format!("transaction {}: {e}", hex::encode(txn.hash_id)))
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // The checks of `check_block_at` that only need the header, e.g. before
+    // a header is relayed ahead of its block. Returns the hash of the parent
+    pub fn check_header_at(&self, header: &BlockHeader, now: u128) -> Result<[u8; 32]> {
+        let previous_hash = <[u8; 32]>::from_hex(&header.previous_hash)
+            .map_err(|_| Error::InvalidBlock("malformed previous hash".to_string()))?;
+        if !self.known.contains_key(&previous_hash) {
+            return Err(Error::InvalidBlock("unknown parent block".to_string()));
+        }
+
+        let recent = self.recent_headers(&previous_hash, header_depth(&self.params));
+        check_next_header(header, &recent, self.fixed_difficulty, &self.params, now)?;
+
+        Ok(previous_hash)
+    }
+
+    // Whether the block of a header extending a known block would have at
+    // least the work of the best chain, so it's worth telling peers about
+    pub fn is_competitive(&self, header: &BlockHeader) -> bool {
+        let Ok(previous_hash) = <[u8; 32]>::from_hex(&header.previous_hash) else {
+            return false;
+        };
+
+        self.known.get(&previous_hash).is_some_and(|parent| {
+            parent
+                .cumulative_work
+                .saturating_add(block_work(header.difficulty))
+                >= self.cumulative_work()
+        })
+    }
+
+    // Removes the tip from the best chain, the genesis block can't be removed
+    pub fn disconnect_tip(&mut self) -> Option<Block> {
+        if self.best.len() <= 1 {
+            return None;
+        }
+
+        let hash = self.best.pop_back()?;
+        let block = self.known[&hash].block.clone();
+
+        let spent = self.spent.remove(&hash).unwrap_or_default();
+        undo_block(&mut self.utxos, &block, &spent);
+
+        Some(block)
+    }
+
+    pub fn is_on_best_chain(&self, block_hash: &[u8; 32], height: u64) -> bool {
+        self.best.get(height as usize) == Some(block_hash)
+    }
+
+    // Hash of the ancestor at `height` of a known block, on the block's own
+    // branch. Once the branch joins the best chain the rest is looked up by
+    // height instead of walked
+    pub fn ancestor(&self, hash: &[u8; 32], height: u64) -> Option<[u8; 32]> {
+        let mut hash = *hash;
+        loop {
+            let block = &self.known.get(&hash)?.block;
+            if block.index() < height {
+                return None;
+            }
+            if block.index() == height {
+                return Some(hash);
+            }
+            if self.is_on_best_chain(&hash, block.index()) {
+                return self.best.get(height as usize).copied();
+            }
+
+            hash = <[u8; 32]>::from_hex(block.previous_hash())
+                .expect("known blocks have valid parent hashes");
+        }
+    }
+
+    // A known block followed by its ancestors down to the genesis block
+    pub fn ancestors<'a>(&'a self, hash: &[u8; 32]) -> impl Iterator<Item = &'a Block> + 'a {
+        let mut next = self.known.get(hash).map(|entry| &entry.block);
+        std::iter::from_fn(move || {
+            let block = next?;
+            next = match block.index() {
+                0 => None,
+                _ => <[u8; 32]>::from_hex(block.previous_hash())
+                    .ok()
+                    .and_then(|previous_hash| self.block_by_hash(&previous_hash)),
+            };
+            Some(block)
+        })
+    }
+
+    // Reports where a transaction was confirmed relative to the current tip.
+    //
+    // Transactions waiting in the mempool aren't known to the chain, so this
+    // never returns `TxStatus::Pending`.
+    pub fn get_tx_confirmations(&self, txid: &[u8; 32]) -> TxStatus {
+        let Some(location) = self.tx_index.get(txid) else {
+            return TxStatus::Unknown;
+        };
+
+        if !self.is_on_best_chain(&location.block_hash, location.height) {
+            return TxStatus::Orphaned {
+                block_hash: location.block_hash,
+                height: location.height,
+            };
+        }
+
+        TxStatus::Confirmed {
+            block_hash: location.block_hash,
+            height: location.height,
+            confirmations: self.tip().index() - location.height + 1,
+        }
+    }
+
+    // Every known block containing the transaction, the one on the best
+    // chain first and then those of competing branches by height. After a
+    // reorganization a transaction may be in a stale block and again in one
+    // of the new branch, or only in the stale one.
+    //
+    // Only best chain transactions are indexed, the blocks off the best
+    // chain are scanned. There are few of them, branches rarely grow long
+    pub fn find_transaction(&self, txid: &[u8; 32]) -> Vec<TxInclusion> {
+        let tip = self.tip().index();
+        let best = self
+            .tx_index
+            .get(txid)
+            .filter(|location| self.is_on_best_chain(&location.block_hash, location.height))
+            .map(|location| TxInclusion {
+                block_hash: location.block_hash,
+                height: location.height,
+                on_best_chain: true,
+                confirmations: tip - location.height + 1,
+            });
+
+        let mut stale: Vec<TxInclusion> = self
+            .known
+            .iter()
+            .filter(|(hash, entry)| !self.is_on_best_chain(hash, entry.block.index()))
+            .filter(|(_, entry)| {
+                entry
+                    .block
+                    .transactions()
+                    .iter()
+                    .any(|txn| &txn.hash_id == txid)
+            })
+            .map(|(hash, entry)| TxInclusion {
+                block_hash: *hash,
+                height: entry.block.index(),
+                on_best_chain: false,
+                confirmations: 0,
+            })
+            .collect();
+        stale.sort_by_key(|inclusion| (inclusion.height, inclusion.block_hash));
+
+        best.into_iter().chain(stale).collect()
+    }
+
+    // Earliest timestamp a block on top of the current tip may have
+    pub fn next_min_timestamp(&self) -> u128 {
+        self.median_time_past(&self.tip().hash())
+    }
+
+    // Median timestamp of a known block and its closest ancestors, on its
+    // branch
+    fn median_time_past(&self, hash: &[u8; 32]) -> u128 {
+        median_time(&self.recent_headers(hash, MEDIAN_TIME_BLOCKS))
+    }
+
+    // Difficulty of the child of a known block, see `next_difficulty`
+    fn difficulty_after(&self, parent_hash: &[u8; 32]) -> u32 {
+        let recent = self.recent_headers(parent_hash, header_depth(&self.params));
+        next_difficulty(&recent, self.fixed_difficulty, &self.params)
+    }
+
+    // Headers of a known block and at most `count - 1` of its ancestors,
+    // latest first
+    fn recent_headers(&self, hash: &[u8; 32], count: usize) -> Vec<&BlockHeader> {
+        self.ancestors(hash)
+            .map(Block::header)
+            .take(count)
+            .collect()
+    }
+
+    // Height where a known block's branch leaves the best chain, and the
+    // hashes of the branch's blocks down to there, the block first. Empty
+    // for best chain blocks
+    fn branch(&self, hash: &[u8; 32]) -> Option<(u64, Vec<[u8; 32]>)> {
+        let mut branch = Vec::new();
+        let mut hash = *hash;
+        loop {
+            let block = &self.known.get(&hash)?.block;
+            if self.is_on_best_chain(&hash, block.index()) {
+                return Some((block.index(), branch));
+            }
+            branch.push(hash);
+
+            hash = <[u8; 32]>::from_hex(block.previous_hash())
+                .expect("known blocks have valid parent hashes");
+        }
+    }
+
+    // Switches the best chain to end at the given known block. The blocks of
+    // the new branch are applied to the outputs unspent where it leaves the
+    // best chain first, the chain is left as it was if they don't apply
+    fn reorganize(&mut self, new_tip: [u8; 32]) -> Result<ChainUpdate> {
+        let (fork_height, branch) = self.branch(&new_tip).expect("the new tip is a known block");
+
+        let mut utxos = self.utxos.clone();
+        let mut disconnected = Vec::new();
+        for height in (fork_height as usize + 1..self.best.len()).rev() {
+            let hash = self.best[height];
+            undo_block(&mut utxos, &self.known[&hash].block, &self.spent[&hash]);
+            disconnected.push(hash);
+        }
+
+        let mut connected = Vec::new();
+        for hash in branch.into_iter().rev() {
+            let spent = apply_block(&mut utxos, &self.known[&hash].block)?;
+            connected.push((hash, spent.concat()));
+        }
+
+        let mut update = ChainUpdate::default();
+        for hash in disconnected {
+            self.best.pop_back();
+            self.spent.remove(&hash);
+            update.disconnected.push(self.known[&hash].block.clone());
+        }
+        for (hash, spent) in connected {
+            self.connect(hash, spent);
+            update.connected.push(self.known[&hash].block.clone());
+        }
+        self.utxos = utxos;
+
+        Ok(update)
+    }
+
+    // Checks a chain restored from disk is consistent: every block hashes to
+    // the hash it's known by and has its merkle root, the best chain links
+    // up from the genesis block to the tip and the UTXO set and transaction
+    // index match what replaying its blocks produces
+    pub fn check_integrity(&self) -> Result<()> {
+        let corrupt = |reason: String| Err(Error::CorruptChain(reason));
+        if self.best.is_empty() {
+            return corrupt("best chain is empty".to_string());
+        }
+
+        let mut utxos = SharedMap::new();
+        let mut parent: Option<[u8; 32]> = None;
+        for (height, hash) in self.best.iter().enumerate() {
+            let Some(entry) = self.known.get(hash) else {
+                return corrupt(format!("block at height {height} is missing"));
+            };
+            let block = &entry.block;
+
+            if block.index() != height as u64 || !matches_hash(block, hash) {
+                return corrupt(format!("block at height {height} doesn't match its hash"));
+            }
+            if let Some(parent) = parent {
+                if block.previous_hash() != hex::encode(parent) {
+                    return corrupt(format!(
+                        "block at height {height} doesn't extend its parent"
+                    ));
+                }
+            }
+
+            for txn in block.transactions() {
+                let location = TxLocation {
+                    block_hash: *hash,
+                    height: height as u64,
+                };
+                if self.tx_index.get(&txn.hash_id) != Some(&location) {
+                    return corrupt(format!(
+                        "transaction {} isn't indexed at height {height}",
+                        hex::encode(txn.hash_id)
+                    ));
+                }
+            }
+
+            let Ok(spent) = apply_block(&mut utxos, block) else {
+                return corrupt(format!("block at height {height} spends missing outputs"));
+            };
+            let spent = spent.concat();
+            let recorded = self.spent.get(hash).map_or(&[][..], |spent| &spent[..]);
+            if spent.len() != recorded.len()
+                || spent.iter().zip(recorded).any(|(a, b)| !a.same_output(b))
+            {
+                return corrupt(format!("outputs spent at height {height} aren't recorded"));
+            }
+            parent = Some(*hash);
+        }
+
+        // Confirmation timestamps differ between replays, compare the
+        // outpoints and values
+        let matches = utxos.len() == self.utxos.len()
+            && utxos.iter().all(|(outpoint, utxo)| {
+                self.utxos
+                    .get(outpoint)
+                    .is_some_and(|stored| stored.value() == utxo.value())
+            });
+        if !matches {
+            return corrupt("UTXO set doesn't match the best chain".to_string());
+        }
+
+        // Blocks of competing branches, those of the best chain were checked
+        for (hash, entry) in self.known.iter() {
+            let height = entry.block.index() as usize;
+            if self.best.get(height) != Some(hash) && !matches_hash(&entry.block, hash) {
+                return corrupt(format!(
+                    "side branch block {} doesn't match its hash",
+                    hex::encode(hash)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Appends a known block to the best chain, indexing its transactions
+    // and keeping the outputs it spent. Its transactions are already applied
+    // to the UTXO set
+    fn connect(&mut self, hash: [u8; 32], spent: Vec<UTXO>) {
+        let block = &self.known[&hash].block;
+        let location = TxLocation {
+            block_hash: hash,
+            height: block.index(),
+        };
+
+        for txn in block.transactions() {
+            self.tx_index.insert(txn.hash_id, location);
+        }
+
+        self.spent.insert(hash, Arc::new(spent));
+        self.best.push_back(hash);
+    }
+}
+
+// Takes the outputs the block's transactions spend out of the UTXO set and
+// adds the ones they create, transaction by transaction so later ones can
+// spend outputs of earlier ones. Returns the outputs each transaction spent,
+// as stored in the set. Fails for inputs spending outputs that aren't in the
+// set, or that declare them differently than stored. The set is left
+// partially updated then
+pub fn apply_block(utxos: &mut SharedMap<OutPoint, UTXO>, block: &Block) -> Result<Vec<Vec<UTXO>>> {
+    let mut spent = Vec::with_capacity(block.transactions().len());
+
+    for txn in block.transactions() {
+        let invalid = |reason: String| {
+            Error::InvalidBlock(format!("transaction {} {reason}", hex::encode(txn.hash_id)))
+        };
+
+        let mut txn_spent = Vec::with_capacity(txn.inputs.len());
+        for input in txn.inputs.iter() {
+            let outpoint = input
+                .outpoint()
+                .ok_or_else(|| invalid("spends an unconfirmed output".to_string()))?;
+            let stored = utxos
+                .remove(&outpoint)
+                .ok_or_else(|| invalid(format!("spends missing or spent output {outpoint}")))?;
+            if !stored.same_output(input) {
+                return Err(invalid(format!(
+                    "declares output {outpoint} differently than stored"
+                )));
+            }
+            txn_spent.push(stored);
+        }
+
+        for utxo in confirmed_outputs(txn, block.index()) {
+            if let Some(outpoint) = utxo.outpoint() {
+                utxos.insert(outpoint, utxo);
+            }
+        }
+        spent.push(txn_spent);
+    }
+
+    Ok(spent)
+}
+
+// Reverts `apply_block`: drops the outputs the block's transactions created
+// and puts back the ones they spent, given in the order they were spent.
+// Transactions are reverted last first, outputs both created and spent
+// within the block end up gone
+fn undo_block(utxos: &mut SharedMap<OutPoint, UTXO>, block: &Block, spent: &[UTXO]) {
+    let mut spent = spent;
+
+    for txn in block.transactions().iter().rev() {
+        for output in txn.outputs.iter() {
+            if output.is_pending() {
+                utxos.remove(&OutPoint::new(txn.hash_id, output.index()));
+            }
+        }
+
+        let (earlier, restored) = spent.split_at(spent.len().saturating_sub(txn.inputs.len()));
+        for utxo in restored {
+            if let Some(outpoint) = utxo.outpoint() {
+                utxos.insert(outpoint, utxo.clone());
+            }
+        }
+        spent = earlier;
+    }
+}
+
+fn serialize_map<K, V, W>(map: &SharedMap<K, V>, writer: &mut W) -> IoResult<()>
+where
+    K: BorshSerialize + Ord + Hash + Clone,
+    V: BorshSerialize + Clone,
+    W: Write,
+{
+    // Sorted like borsh sorts standard maps, the encoding is the same
+    let mut entries: Vec<(&K, &V)> = map.iter().collect();
+    entries.sort_unstable_by_key(|(key, _)| *key);
+
+    u32::try_from(entries.len())
+        .map_err(|_| std::io::ErrorKind::InvalidData)?
+        .serialize(writer)?;
+    for (key, value) in entries {
+        key.serialize(writer)?;
+        value.serialize(writer)?;
+    }
+    Ok(())
+}
+
+fn deserialize_map<K, V, R>(reader: &mut R) -> IoResult<SharedMap<K, V>>
+where
+    K: BorshDeserialize + Ord + Hash + Clone,
+    V: BorshDeserialize + Clone,
+    R: Read,
+{
+    Ok(HashMap::<K, V>::deserialize_reader(reader)?
+        .into_iter()
+        .collect())
+}
+
+fn serialize_vector<T: BorshSerialize + Clone, W: Write>(
+    vector: &Vector<T>,
+    writer: &mut W,
+) -> IoResult<()> {
+    u32::try_from(vector.len())
+        .map_err(|_| std::io::ErrorKind::InvalidData)?
+        .serialize(writer)?;
+    vector.iter().try_for_each(|item| item.serialize(writer))
+}
 
+fn deserialize_vector<T: BorshDeserialize + Clone, R: Read>(reader: &mut R) -> IoResult<Vector<T>> {
+    Ok(Vec::<T>::deserialize_reader(reader)?.into())
 }
 
+// Checks headers received ahead of their blocks, each the way
+// `BlockChain::check_header_at` checks a header once the ones before it are
+// known. The run follows `previous`, headers checked before, latest first.
+// Below those the headers extend a block of `chain`, or start with a genesis
+// block while there's no chain yet. Returns the work the run adds
+pub fn check_headers<'a>(
+    chain: Option<&'a BlockChain>,
+    network: Network,
+    params: &ChainParams,
+    previous: impl Iterator<Item = &'a BlockHeader>,
+    headers: &'a [BlockHeader],
+    now: u128,
+) -> Result<u128> {
+    let depth = header_depth(params);
+    let mut recent: VecDeque<&BlockHeader> = previous.take(depth).collect();
+    let mut headers = headers.iter().peekable();
+    let mut work = 0u128;
+
+    if recent.len() < depth {
+        let lowest = match recent.back() {
+            Some(lowest) => *lowest,
+            None => *headers
+                .peek()
+                .ok_or_else(|| Error::InvalidBlock("no headers".to_string()))?,
+        };
+
+        let previous_hash = <[u8; 32]>::from_hex(&lowest.previous_hash)
+            .map_err(|_| Error::InvalidBlock("malformed previous hash".to_string()))?;
+        match chain {
+            Some(chain) if chain.contains(&previous_hash) => {
+                recent.extend(chain.recent_headers(&previous_hash, depth - recent.len()));
+            }
+            None if lowest.index == 0 => {
+                // Nothing comes before a genesis block, it only has to be the
+                // network's
+                if recent.is_empty() {
+                    let genesis = headers.next().expect("peeked above");
+                    if genesis.hash != network.genesis_hash() || !genesis.is_valid() {
+                        return Err(Error::InvalidBlock("invalid genesis block".to_string()));
+                    }
+                    work = block_work(genesis.difficulty);
+                    recent.push_front(genesis);
+                }
+            }
+            _ => return Err(Error::InvalidBlock("unknown parent block".to_string())),
+        }
+    }
+
+    for header in headers {
+        check_next_header(
+            header,
+            recent.make_contiguous(),
+            network.fixed_difficulty(),
+            params,
+            now,
+        )?;
+        work = work.saturating_add(block_work(header.difficulty));
+
+        recent.push_front(header);
+        recent.truncate(depth);
+    }
+
+    Ok(work)
+}
+
+// Checks a header against the headers before it on its branch, latest first
+// and reaching back at least `header_depth` blocks unless they start at the
+// genesis block
+fn check_next_header(
+    header: &BlockHeader,
+    recent: &[&BlockHeader],
+    fixed_difficulty: Option<u32>,
+    params: &ChainParams,
+    now: u128,
+) -> Result<()> {
+    let parent = recent[0];
+    if header.index != parent.index + 1 {
+        return Err(Error::InvalidBlock(format!(
+            "expected height {}, got {}",
+            parent.index + 1,
+            header.index
+        )));
+    }
+    if header.previous_hash != hex::encode(parent.hash) {
+        return Err(Error::InvalidBlock(format!(
+            "header {} doesn't link up",
+            header.index
+        )));
+    }
+
+    let expected_difficulty = next_difficulty(recent, fixed_difficulty, params);
+    if header.difficulty != expected_difficulty {
+        return Err(Error::InvalidBlock(format!(
+            "expected difficulty {expected_difficulty}, got {}",
+            header.difficulty
+        )));
+    }
+
+    if !header.is_valid() {
+        return Err(Error::InvalidBlock("invalid proof of work".to_string()));
+    }
+    header.check_timestamp(median_time(recent), now)
+}
+
+// Headers before a block its checks look at: the median time past and the
+// retarget window
+fn header_depth(params: &ChainParams) -> usize {
+    MEDIAN_TIME_BLOCKS.max(params.retarget_interval as usize)
+}
+
+// Median timestamp of the latest of the headers, latest first
+fn median_time(recent: &[&BlockHeader]) -> u128 {
+    let mut timestamps: Vec<u128> = recent
+        .iter()
+        .take(MEDIAN_TIME_BLOCKS)
+        .map(|header| header.timestamp)
+        .collect();
+
+    timestamps.sort_unstable();
+    timestamps[timestamps.len() / 2]
+}
+
+// Difficulty of the block after the headers, latest first. It's only
+// recomputed at the start of every retarget window, from the time the window
+// before took.
+fn next_difficulty(
+    recent: &[&BlockHeader],
+    fixed_difficulty: Option<u32>,
+    params: &ChainParams,
+) -> u32 {
+    if let Some(difficulty) = fixed_difficulty {
+        return difficulty;
+    }
+
+    let parent = recent[0];
+    let height = parent.index + 1;
+
+    let interval = params.retarget_interval;
+    if !height.is_multiple_of(interval) {
+        return parent.difficulty;
+    }
+
+    // First block of the window
+    let first = recent
+        .get(interval as usize - 1)
+        .copied()
+        .unwrap_or(recent[recent.len() - 1]);
+
+    let actual_time = parent.timestamp.saturating_sub(first.timestamp);
+    let target_time = (interval - 1) as u128 * params.target_block_time;
+
+    retarget(parent.difficulty, actual_time, target_time)
+}
+
+// Expected number of hashes needed to mine a block at the difficulty
+pub fn block_work(difficulty: u32) -> u128 {
+    1u128.checked_shl(difficulty).unwrap_or(u128::MAX)
+}
+
+// Whether the block's contents still hash to `hash`, through its merkle root
+fn matches_hash(block: &Block, hash: &[u8; 32]) -> bool {
+    block.hash() == *hash && block.calculate_hash() == *hash && block.check_merkle_root().is_ok()
+}
+
+fn confirmed_outputs(txn: &Transaction, height: u64) -> impl Iterator<Item = UTXO> + '_ {
+    let coinbase = txn.is_coinbase();
+
+    txn.outputs.iter().filter_map(move |output| {
+        output
+            .clone()
+            .confirm_utxo(txn.hash_id, height as u32, coinbase)
+            .ok()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        config::{
+            block_subsidy, MAX_FUTURE_BLOCK_TIME, MAX_RETARGET_STEPS, MIN_DIFFICULTY,
+            RETARGET_INTERVAL,
+        },
+        test_utils::{create_mock_transaction, generate_key_pairs, test_entropy},
+    };
+    use rand::Rng;
+
+    use super::*;
+
+    const DIFFICULTY: u32 = 4;
+
+    // Every coinbase pays a miner of its own, so competing blocks at a height
+    // never share a hash
+    fn coinbase(height: u64) -> Transaction {
+        Transaction::coinbase_paying(test_entropy().gen(), height, 1_000).unwrap()
+    }
+
+    fn block_on(parent: &Block, txns: Vec<Transaction>) -> Block {
+        let mut transactions = vec![coinbase(parent.index() + 1)];
+        transactions.extend(txns);
+
+        Block::new(
+            parent.index() + 1,
+            transactions,
+            hex::encode(parent.hash()),
+            DIFFICULTY,
+        )
+        .unwrap()
+    }
+
+    fn next_block(chain: &BlockChain) -> Block {
+        block_on(chain.tip(), vec![])
+    }
+
+    fn genesis_chain() -> BlockChain {
+        chain_of(Network::Mainnet)
+    }
+
+    // Chain of the network from a genesis block of the test's own
+    fn chain_of(network: Network) -> BlockChain {
+        let genesis = Block::new(0, vec![coinbase(0)], hex::encode([0u8; 32]), DIFFICULTY).unwrap();
+
+        BlockChain::from_genesis(genesis, network).unwrap()
+    }
+
+    // Spends the output to a new owner, leaving a fee of 10
+    fn spend(output: &UTXO) -> Transaction {
+        let (mut signing_key, _, _, receiver) = generate_key_pairs().unwrap();
+        let value = output.value() - 10;
+
+        let mut txn = Transaction::new(&mut signing_key, receiver).unwrap();
+        txn.add_inputs(vec![output.clone()], &mut signing_key)
+            .unwrap();
+        txn.add_outputs(
+            vec![UTXO::new(value, 0, receiver).unwrap()],
+            &mut signing_key,
+        )
+        .unwrap();
+        txn
+    }
+
+    // The output the coinbase of the block pays out
+    fn coinbase_output(chain: &BlockChain, block: &Block) -> UTXO {
+        let outpoint = OutPoint::new(block.transactions()[0].hash_id, 0);
+        chain.utxos_at(&block.hash()).unwrap()[&outpoint].clone()
+    }
+
+    #[test]
+    fn copies_share_what_they_didnt_change() {
+        let mut chain = genesis_chain();
+        chain.add_block(next_block(&chain)).unwrap();
+
+        let snapshot = chain.clone();
+        chain.add_block(next_block(&chain)).unwrap();
+        assert_eq!(snapshot.height(), 2);
+        assert_eq!(chain.height(), 3);
+        assert!(Arc::ptr_eq(
+            &snapshot.known[&snapshot.tip().hash()],
+            &chain.known[&snapshot.tip().hash()]
+        ));
+
+        // Stored like the standard collections
+        let bytes = borsh::to_vec(&chain).unwrap();
+        let known: HashMap<[u8; 32], BlockEntry> = chain
+            .known
+            .iter()
+            .map(|(hash, entry)| (*hash, (**entry).clone()))
+            .collect();
+        let best: Vec<[u8; 32]> = chain.best.iter().copied().collect();
+        let utxos: HashMap<OutPoint, UTXO> = chain.utxos.clone().into_iter().collect();
+        let spent: HashMap<[u8; 32], Vec<UTXO>> = chain
+            .spent
+            .iter()
+            .map(|(hash, spent)| (*hash, (**spent).clone()))
+            .collect();
+        let tx_index: HashMap<[u8; 32], TxLocation> = chain.tx_index.clone().into_iter().collect();
+        assert_eq!(
+            bytes,
+            borsh::to_vec(&(known, best, utxos, spent, tx_index)).unwrap()
+        );
+
+        let loaded = BlockChain::try_from_slice(&bytes).unwrap();
+        assert_eq!(loaded.tip(), chain.tip());
+        assert_eq!(loaded.utxos(), chain.utxos());
+        loaded.check_integrity().unwrap();
+    }
+
+    #[test]
+    fn lists_the_history_of_an_address() {
+        let mut chain = genesis_chain();
+        let block = next_block(&chain);
+        let txn = block.transactions()[0].clone();
+        chain.add_block(block).unwrap();
+
+        let history: Vec<_> = chain.address_history(&txn.receiver).collect();
+        assert_eq!(history, vec![(1, &txn)]);
+        assert_eq!(chain.address_history(&[9u8; 32]).count(), 0);
+    }
+
+    #[test]
+    fn tracks_confirmations() {
+        let mut chain = genesis_chain();
+
+        let block = next_block(&chain);
+        let txid = block.transactions()[0].hash_id;
+        let block_hash = block.hash();
+        chain.add_block(block).unwrap();
+
+        assert!(matches!(
+            chain.get_tx_confirmations(&txid),
+            TxStatus::Confirmed {
+                confirmations: 1,
+                height: 1,
+                ..
+            }
+        ));
+
+        chain.add_block(next_block(&chain)).unwrap();
+        assert!(matches!(
+            chain.get_tx_confirmations(&txid),
+            TxStatus::Confirmed {
+                confirmations: 2,
+                ..
+            }
+        ));
+
+        chain.disconnect_tip().unwrap();
+        chain.disconnect_tip().unwrap();
+        assert_eq!(
+            chain.get_tx_confirmations(&txid),
+            TxStatus::Orphaned {
+                block_hash,
+                height: 1
+            }
+        );
+        assert_eq!(chain.get_tx_confirmations(&[9u8; 32]), TxStatus::Unknown);
+    }
+
+    #[test]
+    fn detects_corrupt_chains() {
+        let mut chain = genesis_chain();
+        chain.add_block(next_block(&chain)).unwrap();
+        chain.check_integrity().unwrap();
+
+        let mut missing_utxos = chain.clone();
+        missing_utxos.utxos.clear();
+        assert!(matches!(
+            missing_utxos.check_integrity(),
+            Err(Error::CorruptChain(_))
+        ));
+
+        let mut unindexed = chain.clone();
+        unindexed.tx_index.clear();
+        assert!(unindexed.check_integrity().is_err());
+
+        let mut broken_tip = chain.clone();
+        broken_tip.best[1] = [1u8; 32];
+        assert!(broken_tip.check_integrity().is_err());
+
+        // A block known by a hash it doesn't have, as after bit-rot
+        let mut rotten_branch = chain.clone();
+        let entry = rotten_branch.known[&chain.best[1]].clone();
+        rotten_branch.known.insert([2u8; 32], entry);
+        assert!(rotten_branch.check_integrity().is_err());
+    }
+
+    #[test]
+    fn enforces_timestamp_rules() {
+        let mut chain = genesis_chain();
+        let min_timestamp = chain.next_min_timestamp();
+        let mut block = next_block(&chain);
+
+        block.set_timestamp(min_timestamp - 1);
+        block.mine_block();
+        assert!(matches!(
+            chain.add_block(block.clone()),
+            Err(Error::InvalidBlock(_))
+        ));
+
+        block.set_timestamp(block.timestamp() + MAX_FUTURE_BLOCK_TIME + 60_000);
+        block.mine_block();
+        assert!(matches!(
+            chain.add_block(block.clone()),
+            Err(Error::FutureBlock)
+        ));
+        // Unless the time it's judged against is as far ahead
+        let mut ahead = chain.clone();
+        ahead
+            .add_block_at(block.clone(), block.timestamp() - MAX_FUTURE_BLOCK_TIME)
+            .unwrap();
+
+        block.set_timestamp(min_timestamp);
+        block.mine_block();
+        chain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn rejects_blocks_not_extending_the_tip() {
+        let mut chain = genesis_chain();
+        let block = next_block(&chain);
+        chain.add_block(block.clone()).unwrap();
+
+        assert!(matches!(
+            chain.add_block(block),
+            Err(Error::InvalidBlock(_))
+        ));
+    }
+
+    #[test]
+    fn retargets_difficulty_every_interval() {
+        let mut chain = genesis_chain();
+        while chain.height() < RETARGET_INTERVAL {
+            assert_eq!(chain.next_difficulty(), DIFFICULTY);
+            chain.add_block(next_block(&chain)).unwrap();
+        }
+
+        // The window was mined far faster than the target block time
+        assert_eq!(chain.next_difficulty(), DIFFICULTY + MAX_RETARGET_STEPS);
+
+        let stale = next_block(&chain);
+        assert!(matches!(
+            chain.add_block(stale),
+            Err(Error::InvalidBlock(_))
+        ));
+
+        let tip = chain.tip();
+        let block = Block::new(
+            tip.index() + 1,
+            vec![coinbase(tip.index() + 1)],
+            hex::encode(tip.hash()),
+            DIFFICULTY + MAX_RETARGET_STEPS,
+        )
+        .unwrap();
+        chain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn starts_from_the_networks_genesis_block() {
+        for network in Network::ALL {
+            let genesis = Block::genesis(network);
+            assert_eq!(genesis.hash(), network.genesis_hash());
+            assert!(BlockChain::new(genesis, network).is_ok());
+        }
+
+        // Another network's genesis block or any other first block
+        assert!(BlockChain::new(Block::genesis(Network::Testnet), Network::Mainnet).is_err());
+        let other = genesis_chain().tip().clone();
+        assert!(BlockChain::new(other, Network::Mainnet).is_err());
+    }
+
+    #[test]
+    fn checks_headers_ahead_of_their_blocks() {
+        let chain = genesis_chain();
+        let mut full = chain.clone();
+        while full.height() < RETARGET_INTERVAL {
+            full.add_block(next_block(&full)).unwrap();
+        }
+        let headers: Vec<BlockHeader> = (1..full.height())
+            .map(|height| full.block(height).unwrap().header().clone())
+            .collect();
+        let now = full.tip().timestamp();
+        let params = ChainParams::default();
+        let check = |chain, previous: &[BlockHeader], headers| {
+            check_headers(
+                chain,
+                Network::Mainnet,
+                &params,
+                previous.iter().rev(),
+                headers,
+                now,
+            )
+        };
+
+        let work = block_work(DIFFICULTY) * headers.len() as u128;
+        assert_eq!(check(Some(&chain), &[], &headers).unwrap(), work);
+        let (previous, run) = headers.split_at(4);
+        assert_eq!(
+            check(Some(&chain), previous, run).unwrap(),
+            block_work(DIFFICULTY) * run.len() as u128
+        );
+
+        // Without a chain they start with the network's genesis block
+        let genesis = [Block::genesis(Network::Mainnet).header().clone()];
+        assert_eq!(
+            check(None, &[], &genesis).unwrap(),
+            block_work(MIN_DIFFICULTY)
+        );
+        let mut from_genesis = vec![chain.tip().header().clone()];
+        from_genesis.extend(headers.iter().cloned());
+        assert!(check(None, &[], &from_genesis).is_err());
+        assert!(check(None, &[], &headers).is_err());
+
+        // A header keeping the difficulty past the retarget
+        let mut stale = headers.clone();
+        stale.push(next_block(&full).header().clone());
+        assert!(matches!(
+            check(Some(&chain), &[], &stale),
+            Err(Error::InvalidBlock(_))
+        ));
+
+        // Or a header that doesn't follow the one before
+        let mut unlinked = headers.clone();
+        unlinked.remove(3);
+        assert!(check(Some(&chain), &[], &unlinked).is_err());
+    }
+
+    #[test]
+    fn follows_the_chain_params() {
+        let params = ChainParams {
+            halving_interval: 2,
+            retarget_interval: 3,
+            ..ChainParams::default()
+        };
+        let mut chain = genesis_chain().with_params(params);
+        while chain.height() < 3 {
+            assert_eq!(chain.next_difficulty(), DIFFICULTY);
+            chain.add_block(next_block(&chain)).unwrap();
+        }
+        assert_eq!(chain.next_difficulty(), DIFFICULTY + MAX_RETARGET_STEPS);
+
+        // The template pays the subsidy of the chain's halving interval
+        let template = chain
+            .create_block_template(&MemPool::new(1), [1u8; 32], 0, b"")
+            .unwrap();
+        assert_eq!(
+            template.transactions()[0].output_value(),
+            params.block_subsidy(3)
+        );
+        assert!(template.check_coinbase_with(&params).is_ok());
+
+        // A block claiming the default subsidy pays too much on this chain
+        let greedy = BlockBuilder::new(3, hex::encode(chain.tip().hash()), 1, [1u8; 32])
+            .template()
+            .unwrap();
+        assert!(greedy.check_coinbase_with(&params).is_err());
+    }
+
+    #[test]
+    fn keeps_the_difficulty_of_regtest_fixed() {
+        let mut chain = chain_of(Network::Regtest);
+        let difficulty = Network::Regtest.fixed_difficulty().unwrap();
+
+        while chain.height() <= RETARGET_INTERVAL {
+            assert_eq!(chain.next_difficulty(), difficulty);
+
+            let tip = chain.tip();
+            let block = Block::new(
+                tip.index() + 1,
+                vec![coinbase(tip.index() + 1)],
+                hex::encode(tip.hash()),
+                difficulty,
+            )
+            .unwrap();
+            chain.add_block(block).unwrap();
+        }
+
+        assert!(matches!(
+            chain.add_block(next_block(&chain)),
+            Err(Error::InvalidBlock(_))
+        ));
+    }
+
+    #[test]
+    fn reorganizes_onto_branch_with_most_work() {
+        let mut chain = genesis_chain();
+        let genesis = chain.tip().clone();
+
+        let stale = next_block(&chain);
+        let stale_txid = stale.transactions()[0].hash_id;
+        chain.add_block(stale.clone()).unwrap();
+
+        // A competing block with equal work doesn't replace the tip
+        let fork = block_on(&genesis, vec![]);
+        assert_eq!(
+            chain.add_block(fork.clone()).unwrap(),
+            ChainUpdate::default()
+        );
+        assert_eq!(chain.tip(), &stale);
+
+        let fork_tip = block_on(&fork, vec![]);
+        let update = chain.add_block(fork_tip.clone()).unwrap();
+
+        assert!(update.is_reorg());
+        assert_eq!(update.disconnected, vec![stale]);
+        assert_eq!(update.connected, vec![fork, fork_tip.clone()]);
+        assert_eq!(chain.tip(), &fork_tip);
+        assert_eq!(chain.height(), 3);
+
+        assert!(matches!(
+            chain.get_tx_confirmations(&stale_txid),
+            TxStatus::Orphaned { height: 1, .. }
+        ));
+        assert!(!chain.utxos().values().any(
+            |utxo| matches!(utxo, UTXO::Confirmed { txn_hash, .. } if *txn_hash == stale_txid)
+        ));
+    }
+
+    #[test]
+    fn finds_transactions_on_every_branch() {
+        let mut chain = genesis_chain();
+        let genesis = chain.tip().clone();
+
+        let txn = spend(&coinbase_output(&chain, &genesis));
+        let stale = block_on(&genesis, vec![txn.clone()]);
+        chain.add_block(stale.clone()).unwrap();
+        assert_eq!(
+            chain.find_transaction(&txn.hash_id),
+            vec![TxInclusion {
+                block_hash: stale.hash(),
+                height: 1,
+                on_best_chain: true,
+                confirmations: 1,
+            }]
+        );
+
+        // A competing branch confirms the transaction again and wins
+        let fork = block_on(&genesis, vec![txn.clone()]);
+        chain.add_block(fork.clone()).unwrap();
+        let fork_tip = block_on(&fork, vec![]);
+        assert!(chain.add_block(fork_tip).unwrap().is_reorg());
+
+        assert_eq!(
+            chain.find_transaction(&txn.hash_id),
+            vec![
+                TxInclusion {
+                    block_hash: fork.hash(),
+                    height: 1,
+                    on_best_chain: true,
+                    confirmations: 2,
+                },
+                TxInclusion {
+                    block_hash: stale.hash(),
+                    height: 1,
+                    on_best_chain: false,
+                    confirmations: 0,
+                },
+            ]
+        );
+        assert!(chain.find_transaction(&[9u8; 32]).is_empty());
+    }
+
+    #[test]
+    fn rejects_spends_of_missing_or_spent_outputs() {
+        let mut chain = genesis_chain();
+        let genesis = chain.tip().clone();
+        let output = coinbase_output(&chain, &genesis);
+
+        let block = block_on(&genesis, vec![spend(&output)]);
+        chain.add_block(block.clone()).unwrap();
+        assert!(chain.utxos().get(&output.outpoint().unwrap()).is_none());
+
+        // Spent on the best chain already
+        let double_spend = block_on(&block, vec![spend(&output)]);
+        assert!(matches!(
+            chain.add_block(double_spend.clone()),
+            Err(Error::InvalidBlock(_))
+        ));
+        assert!(!chain.contains(&double_spend.hash()));
+
+        // Never created
+        let (unknown, _) = create_mock_transaction(1_000, 900);
+        assert!(chain.add_block(block_on(&block, vec![unknown])).is_err());
+
+        // Spent twice within the block
+        let output = coinbase_output(&chain, &block);
+        let twice = block_on(&block, vec![spend(&output), spend(&output)]);
+        assert!(chain.add_block(twice).is_err());
+
+        // Declared worth more than stored, the coinbase claiming the
+        // difference as fees
+        let mut forged = output.clone();
+        if let UTXO::Confirmed { value, .. } = &mut forged {
+            *value += 1_000_000;
+        }
+        assert!(chain
+            .add_block(block_on(&block, vec![spend(&forged)]))
+            .is_err());
+
+        chain
+            .add_block(block_on(&block, vec![spend(&output)]))
+            .unwrap();
+        assert_eq!(chain.height(), 3);
+        chain.check_integrity().unwrap();
+    }
+
+    #[test]
+    fn restores_spent_outputs_on_reorg() {
+        let mut chain = genesis_chain();
+        let genesis = chain.tip().clone();
+        let output = coinbase_output(&chain, &genesis);
+        let outpoint = output.outpoint().unwrap();
+
+        let spending = spend(&output);
+        let block = block_on(&genesis, vec![spending.clone()]);
+        chain.add_block(block).unwrap();
+
+        // The competing branch spends the output again, as it's unspent where
+        // the branch leaves the best chain
+        let fork = block_on(&genesis, vec![]);
+        chain.add_block(fork.clone()).unwrap();
+        let respend = spend(&output);
+        let fork_tip = block_on(&fork, vec![respend.clone()]);
+        assert!(chain.add_block(fork_tip).unwrap().is_reorg());
+
+        let spent_on_fork = chain.utxos_at(&fork.hash()).unwrap();
+        assert_eq!(spent_on_fork.get(&outpoint), Some(&output));
+        assert!(chain.utxos().get(&outpoint).is_none());
+        assert!(chain
+            .utxos()
+            .contains_key(&OutPoint::new(respend.hash_id, 0)));
+        assert!(!chain
+            .utxos()
+            .contains_key(&OutPoint::new(spending.hash_id, 0)));
+        chain.check_integrity().unwrap();
+
+        // The output comes back as stored, not as the spender declared it
+        chain.disconnect_tip().unwrap();
+        assert_eq!(chain.utxos().get(&outpoint), Some(&output));
+        assert!(!chain
+            .utxos()
+            .contains_key(&OutPoint::new(respend.hash_id, 0)));
+    }
+
+    #[test]
+    fn creates_templates_from_the_mempool() {
+        let chain = genesis_chain();
+        let mut mempool = MemPool::new(10);
+        let (low, _) = create_mock_transaction(1_000, 990);
+        let (high, _) = create_mock_transaction(1_000, 900);
+        let utxos: SharedMap<_, _> = low
+            .inputs
+            .iter()
+            .chain(high.inputs.iter())
+            .map(|input| (input.outpoint().unwrap(), input.clone()))
+            .collect();
+        mempool.add_transaction(low.clone(), &utxos, 1).unwrap();
+        mempool.add_transaction(high.clone(), &utxos, 1).unwrap();
+
+        let miner = [7u8; 32];
+        let mut template = chain
+            .create_block_template(&mempool, miner, 1, b"pool")
+            .unwrap();
+        assert_eq!(template.index(), chain.height());
+        assert_eq!(template.previous_hash(), hex::encode(chain.tip().hash()));
+        assert_eq!(template.difficulty(), chain.next_difficulty());
+        assert!(template.check_merkle_root().is_ok());
+
+        // Coinbase first, then by fee rate
+        let txns = template.transactions();
+        assert!(txns[0].is_coinbase());
+        assert_eq!(txns[0].coinbase_tag(), Some(&b"pool"[..]));
+        assert_eq!(
+            txns[0].outputs[0].value(),
+            block_subsidy(chain.height()) + 110
+        );
+        assert_eq!(&txns[1..], &[high, low]);
+
+        // Selected transactions stay in the pool until the block is connected
+        assert_eq!(mempool.transactions.len(), 2);
+
+        template.mine_block();
+        assert!(template.header().is_valid());
+    }
+}
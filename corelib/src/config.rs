@@ -1 +1,386 @@
+use std::{fmt, str::FromStr};
 
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use hex::FromHex;
+
+use crate::errors::{Error, Result};
+
+// Smallest units in one coin
+pub const COIN: u64 = 100_000_000;
+
+// Reward of the first blocks, before any halving
+pub const INITIAL_SUBSIDY: u64 = 50 * COIN;
+
+// Number of blocks after which the subsidy halves
+pub const HALVING_INTERVAL: u64 = 210_000;
+
+// Number of blocks between difficulty adjustments
+pub const RETARGET_INTERVAL: u64 = 10;
+
+// Time the network aims to spend on mining a block, in milliseconds
+pub const TARGET_BLOCK_TIME: u128 = 60_000;
+
+// How far ahead of the local clock a block's timestamp may be, in
+// milliseconds
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1_000;
+
+// Ancestors whose median timestamp a block can't be older than
+pub const MEDIAN_TIME_BLOCKS: usize = 11;
+
+// Nonces tried between checks of the clock while mining, and how old the
+// timestamp of the block being mined may get before it's refreshed
+pub const MINING_ROUND: u64 = 100_000;
+pub const TIMESTAMP_REFRESH_INTERVAL: u128 = 1_000;
+
+// Largest change of the difficulty in a single adjustment, every step doubles
+// or halves the work needed to mine a block
+pub const MAX_RETARGET_STEPS: u32 = 2;
+
+// Bounds of the difficulty, which is the number of leading zero bits the
+// block hash must have
+pub const MIN_DIFFICULTY: u32 = 1;
+pub const MAX_DIFFICULTY: u32 = 127;
+
+// Longest locking or unlocking script, in bytes
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+
+// Locktimes below the threshold are block heights, the others unix
+// timestamps in milliseconds
+pub const LOCKTIME_THRESHOLD: u64 = 500_000_000;
+
+// Signature operations a multisig check counts as, the most keys it can check
+pub const MULTISIG_SIGOPS: usize = 20;
+
+// Most signature operations a transaction and a block may need to verify,
+// bounding the worst case verification time of a block
+pub const MAX_TX_SIGOPS: usize = 4_000;
+pub const MAX_BLOCK_SIGOPS: usize = 20_000;
+
+// Serialized size of the transactions a block template takes from the pool
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
+// Bounds of the block size a network may configure, a block has to fit at
+// least its coinbase
+pub const MIN_BLOCK_SIZE: usize = 1_000;
+pub const MAX_BLOCK_SIZE_LIMIT: usize = 4_000_000;
+
+// Time every network's genesis block is stamped with, in milliseconds
+pub const GENESIS_TIMESTAMP: u128 = 1_735_689_600_000;
+
+// Blocks per window deployments count their signaling in, and signaling
+// blocks of a window that lock a deployment in
+pub const DEPLOYMENT_WINDOW: u64 = 1_000;
+pub const DEPLOYMENT_THRESHOLD: u64 = 900;
+
+// Networks a node can run, every network has its own chain, port and data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    // Local network for tests
+    Regtest,
+}
+
+impl Network {
+    pub const ALL: [Network; 3] = [Network::Mainnet, Network::Testnet, Network::Regtest];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Network::Mainnet => 7878,
+            Network::Testnet => 17878,
+            Network::Regtest => 27878,
+        }
+    }
+
+    // Port the JSON-RPC server listens on
+    pub fn default_rpc_port(&self) -> u16 {
+        match self {
+            Network::Mainnet => 7879,
+            Network::Testnet => 17879,
+            Network::Regtest => 27879,
+        }
+    }
+
+    // Bytes every frame of the network's peer protocol starts with, nodes
+    // of different networks tell each other apart by them
+    pub fn magic(&self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => *b"AURL",
+            Network::Testnet => *b"AURT",
+            Network::Regtest => *b"AURR",
+        }
+    }
+
+    // Human-readable part addresses of the network start with
+    pub fn address_prefix(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "aur",
+            Network::Testnet => "taur",
+            Network::Regtest => "raur",
+        }
+    }
+
+    // Hash of the block the network's chain starts from, see
+    // `Block::genesis`. A chain starting from any other block belongs to
+    // another network
+    pub fn genesis_hash(&self) -> [u8; 32] {
+        let hash = match self {
+            Network::Mainnet => "7079568c8280bfa9b3b79780458e80c6437ebefc9fbf3b7d77dc69ecbf6a9388",
+            Network::Testnet => "517e3c2a192d7e743c570059ca4c5bc6805fdef303556277122e6959159702e8",
+            Network::Regtest => "76325b1145a2c7c028980b0e72bdaafe53aca857e1ffb5064a85c220c3f5f215",
+        };
+        <[u8; 32]>::from_hex(hash).expect("genesis hashes are valid hex")
+    }
+
+    pub fn from_magic(magic: &[u8]) -> Option<Network> {
+        Self::ALL
+            .into_iter()
+            .find(|network| network.magic() == magic)
+    }
+
+    // Difficulty every block of the network has, `None` if it retargets.
+    // Regtest blocks are mined right away however fast they come
+    pub fn fixed_difficulty(&self) -> Option<u32> {
+        match self {
+            Network::Mainnet | Network::Testnet => None,
+            Network::Regtest => Some(MIN_DIFFICULTY),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            other => Err(format!("Unknown network {other}")),
+        }
+    }
+}
+
+// Consensus parameters of a chain, the defaults are the constants above.
+//
+// Custom networks override some of them, e.g. a short halving interval to
+// test the subsidy schedule. Nodes check them with `validate` on startup so
+// a typo doesn't leave a network that can't retarget or mine a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainParams {
+    pub halving_interval: u64,
+    pub retarget_interval: u64,
+    // In milliseconds
+    pub target_block_time: u128,
+    pub max_block_size: usize,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self {
+            halving_interval: HALVING_INTERVAL,
+            retarget_interval: RETARGET_INTERVAL,
+            target_block_time: TARGET_BLOCK_TIME,
+            max_block_size: MAX_BLOCK_SIZE,
+        }
+    }
+}
+
+impl ChainParams {
+    pub const NAMES: [&'static str; 4] = [
+        "halving_interval",
+        "retarget_interval",
+        "target_block_time",
+        "max_block_size",
+    ];
+
+    // Newly minted coins a block at the given height may pay to its miner
+    pub fn block_subsidy(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval.max(1);
+
+        INITIAL_SUBSIDY.checked_shr(halvings as u32).unwrap_or(0)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: &str| Err(Error::InvalidChainParams(reason.to_string()));
+
+        if self.halving_interval == 0 {
+            return invalid("halving interval must be at least one block");
+        }
+        // The window's time is measured between its first and last block
+        if self.retarget_interval < 2 {
+            return invalid("retarget interval must be at least two blocks");
+        }
+        if self.target_block_time == 0 {
+            return invalid("target block time must be above zero");
+        }
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE_LIMIT).contains(&self.max_block_size) {
+            return invalid(&format!(
+                "max block size must be between {MIN_BLOCK_SIZE} and {MAX_BLOCK_SIZE_LIMIT} bytes"
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Value of the parameter by name, as shown to users
+    pub fn get(&self, name: &str) -> Option<u128> {
+        match name {
+            "halving_interval" => Some(self.halving_interval as u128),
+            "retarget_interval" => Some(self.retarget_interval as u128),
+            "target_block_time" => Some(self.target_block_time),
+            "max_block_size" => Some(self.max_block_size as u128),
+            _ => None,
+        }
+    }
+
+    // Names of the parameters that differ from the defaults
+    pub fn overridden(&self) -> Vec<&'static str> {
+        let defaults = ChainParams::default();
+
+        Self::NAMES
+            .into_iter()
+            .filter(|name| self.get(name) != defaults.get(name))
+            .collect()
+    }
+}
+
+// Overrides of the defaults, e.g. `halving_interval=150,max_block_size=50000`
+impl FromStr for ChainParams {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut params = ChainParams::default();
+
+        for pair in s.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let invalid =
+                || Error::InvalidChainParams(format!("expected <name>=<value>, got {pair}"));
+            let (name, value) = pair.split_once('=').ok_or_else(invalid)?;
+            let value = value.trim();
+            match name.trim() {
+                "halving_interval" => {
+                    params.halving_interval = value.parse().map_err(|_| invalid())?
+                }
+                "retarget_interval" => {
+                    params.retarget_interval = value.parse().map_err(|_| invalid())?
+                }
+                "target_block_time" => {
+                    params.target_block_time = value.parse().map_err(|_| invalid())?
+                }
+                "max_block_size" => params.max_block_size = value.parse().map_err(|_| invalid())?,
+                other => {
+                    return Err(Error::InvalidChainParams(format!(
+                        "unknown parameter {other}"
+                    )))
+                }
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+// Newly minted coins a block at the given height may pay to its miner under
+// the default parameters
+pub fn block_subsidy(height: u64) -> u64 {
+    ChainParams::default().block_subsidy(height)
+}
+
+// Difficulty of the next window, given the difficulty of the last one and
+// the time its blocks took to mine compared to the target.
+//
+// The difficulty goes up a step for every halving of the time taken compared
+// to the target, and down a step for every doubling.
+pub fn retarget(difficulty: u32, actual_time: u128, target_time: u128) -> u32 {
+    let actual_time = actual_time.max(1);
+    let mut difficulty = difficulty;
+
+    let mut time = actual_time;
+    for _ in 0..MAX_RETARGET_STEPS {
+        if time.saturating_mul(2) > target_time {
+            break;
+        }
+        time *= 2;
+        difficulty += 1;
+    }
+
+    let mut time = actual_time;
+    for _ in 0..MAX_RETARGET_STEPS {
+        if time < target_time.saturating_mul(2) {
+            break;
+        }
+        time /= 2;
+        difficulty = difficulty.saturating_sub(1);
+    }
+
+    difficulty.clamp(MIN_DIFFICULTY, MAX_DIFFICULTY)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retargets_towards_block_time() {
+        assert_eq!(retarget(10, 1_000, 1_000), 10);
+        assert_eq!(retarget(10, 1_900, 1_000), 10);
+        assert_eq!(retarget(10, 500, 1_000), 11);
+        assert_eq!(retarget(10, 1, 1_000), 12);
+        assert_eq!(retarget(10, 2_000, 1_000), 9);
+        assert_eq!(retarget(10, 1_000_000, 1_000), 8);
+        assert_eq!(retarget(MIN_DIFFICULTY, 1_000_000, 1_000), MIN_DIFFICULTY);
+    }
+
+    #[test]
+    fn subsidy_halves_every_interval() {
+        assert_eq!(block_subsidy(0), INITIAL_SUBSIDY);
+        assert_eq!(block_subsidy(HALVING_INTERVAL - 1), INITIAL_SUBSIDY);
+        assert_eq!(block_subsidy(HALVING_INTERVAL), INITIAL_SUBSIDY / 2);
+        assert_eq!(block_subsidy(3 * HALVING_INTERVAL), INITIAL_SUBSIDY / 8);
+        assert_eq!(block_subsidy(64 * HALVING_INTERVAL), 0);
+    }
+
+    #[test]
+    fn validates_and_reports_overridden_params() {
+        let params: ChainParams = "halving_interval=150, max_block_size=50000"
+            .parse()
+            .unwrap();
+        assert!(params.validate().is_ok());
+        assert_eq!(
+            params.overridden(),
+            vec!["halving_interval", "max_block_size"]
+        );
+        assert_eq!(params.block_subsidy(150), INITIAL_SUBSIDY / 2);
+        assert!(ChainParams::default().overridden().is_empty());
+
+        for broken in [
+            "halving_interval=0",
+            "retarget_interval=1",
+            "target_block_time=0",
+            "max_block_size=10",
+        ] {
+            let params: ChainParams = broken.parse().unwrap();
+            assert!(matches!(
+                params.validate(),
+                Err(Error::InvalidChainParams(_))
+            ));
+        }
+        assert!("block_time=5".parse::<ChainParams>().is_err());
+        assert!("halving_interval=-1".parse::<ChainParams>().is_err());
+    }
+}
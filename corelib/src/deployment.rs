@@ -0,0 +1,500 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use crate::{
+    blockchain::BlockChain,
+    config::{Network, DEPLOYMENT_THRESHOLD, DEPLOYMENT_WINDOW},
+};
+
+// Consensus rules added after launch, one bit each.
+//
+// A rule only restricts what was valid before it, so nodes that don't know
+// about it keep following the chain once it activates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Rules(u32);
+
+impl Rules {
+    pub const NONE: Rules = Rules(0);
+    // `OP_CHECKLOCKTIMEVERIFY` and `OP_CHECKSEQUENCEVERIFY` check the spend,
+    // before they only drop their operand
+    pub const TIMELOCKS: Rules = Rules(1 << 0);
+
+    // Every rule this node implements
+    pub const ALL: Rules = Rules::TIMELOCKS;
+
+    const NAMES: [(Rules, &'static str); 1] = [(Rules::TIMELOCKS, "timelocks")];
+
+    pub fn contains(&self, other: Rules) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(&self, other: Rules) -> Rules {
+        Rules(self.0 | other.0)
+    }
+
+    // Names of the rules that are set
+    pub fn names(&self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(rule, _)| self.contains(*rule))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+impl fmt::Display for Rules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.names().join(","))
+    }
+}
+
+impl FromStr for Rules {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Self::NAMES
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(rule, _)| *rule)
+            .ok_or_else(|| format!("Unknown rule {s}"))
+    }
+}
+
+// When the rules of a deployment take effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activation {
+    // From the block at the height on
+    Height(u64),
+    // Once enough blocks of a signaling window from `start_height` on set
+    // the version bit, from the window after the next one on. Miners get a
+    // window to upgrade after the rules locked in
+    Signaling { bit: u8, start_height: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeploymentState {
+    // Signaling hasn't started yet
+    Defined,
+    // Blocks signal whether their miner is ready for the rules
+    Started,
+    // Enough miners are ready, the rules activate with the next window
+    LockedIn,
+    Active,
+}
+
+impl DeploymentState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeploymentState::Defined => "defined",
+            DeploymentState::Started => "started",
+            DeploymentState::LockedIn => "locked_in",
+            DeploymentState::Active => "active",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deployment {
+    pub rules: Rules,
+    pub activation: Activation,
+}
+
+// Deployments of a network and the windows their signaling is counted in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Deployments {
+    deployments: Vec<Deployment>,
+    // Blocks per signaling window, windows start at multiples of it
+    window: u64,
+    // Signaling blocks of a window that lock a deployment in
+    threshold: u64,
+}
+
+impl Default for Deployments {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl Deployments {
+    pub fn new(deployments: Vec<Deployment>) -> Self {
+        Self {
+            deployments,
+            window: DEPLOYMENT_WINDOW,
+            threshold: DEPLOYMENT_THRESHOLD,
+        }
+    }
+
+    // Test networks activate the rules right away, the main network waits
+    // for its miners to signal
+    pub fn for_network(network: Network) -> Self {
+        let timelocks = match network {
+            Network::Mainnet => Activation::Signaling {
+                bit: 0,
+                start_height: 0,
+            },
+            Network::Testnet | Network::Regtest => Activation::Height(0),
+        };
+
+        Self::new(vec![Deployment {
+            rules: Rules::TIMELOCKS,
+            activation: timelocks,
+        }])
+    }
+
+    pub fn with_window(mut self, window: u64, threshold: u64) -> Self {
+        self.window = window.max(1);
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+
+    pub fn threshold(&self) -> u64 {
+        self.threshold
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Deployment> {
+        self.deployments.iter()
+    }
+
+    // Activates the deployment of the rules at a configured height instead
+    pub fn activate_at(&mut self, rules: Rules, height: u64) {
+        for deployment in self
+            .deployments
+            .iter_mut()
+            .filter(|deployment| deployment.rules == rules)
+        {
+            deployment.activation = Activation::Height(height);
+        }
+    }
+
+    // State of the deployment for a block on top of the known block
+    // `parent`, which only depends on the parent's branch. Blocks on top of
+    // an unknown parent have no rules deployed.
+    //
+    // The state only changes from one window to the next, so it's cached for
+    // the last block of every window. Only the windows since the last cached
+    // one on the branch are counted
+    pub fn state(
+        &self,
+        deployment: &Deployment,
+        chain: &BlockChain,
+        parent: &[u8; 32],
+        cache: &mut StateCache,
+    ) -> DeploymentState {
+        let Some(height) = chain.block_by_hash(parent).map(|block| block.index() + 1) else {
+            return DeploymentState::Defined;
+        };
+        let (bit, start_height) = match deployment.activation {
+            Activation::Height(activation_height) if height >= activation_height => {
+                return DeploymentState::Active
+            }
+            Activation::Height(_) => return DeploymentState::Defined,
+            Activation::Signaling { bit, start_height } => (bit, start_height),
+        };
+
+        // Last blocks of the windows before, latest first, up to the first
+        // one with a cached state
+        let mut uncached = Vec::new();
+        let mut state = DeploymentState::Defined;
+        let mut window = height / self.window;
+        let mut last = *parent;
+        while window > 0 {
+            let Some(hash) = chain.ancestor(&last, window * self.window - 1) else {
+                break;
+            };
+            if let Some(cached) = cache.get(&hash, deployment.rules) {
+                state = cached;
+                break;
+            }
+            uncached.push((window, hash));
+            last = hash;
+            window -= 1;
+        }
+        if window == 0 {
+            state = self.next_state(state, 0, start_height, 0);
+        }
+
+        for (window, hash) in uncached.into_iter().rev() {
+            let signaling = self.signaling(chain, bit, &hash, (window - 1) * self.window);
+            state = self.next_state(state, window, start_height, signaling);
+            cache.insert(hash, deployment.rules, state);
+        }
+
+        state
+    }
+
+    // State of a deployment in `window`, given its state in the window before
+    // and how many blocks of it signaled
+    fn next_state(
+        &self,
+        state: DeploymentState,
+        window: u64,
+        start_height: u64,
+        signaling: u64,
+    ) -> DeploymentState {
+        match state {
+            DeploymentState::Defined if window * self.window >= start_height => {
+                DeploymentState::Started
+            }
+            DeploymentState::Started if window > 0 && signaling >= self.threshold => {
+                DeploymentState::LockedIn
+            }
+            DeploymentState::LockedIn | DeploymentState::Active => DeploymentState::Active,
+            state => state,
+        }
+    }
+
+    // Blocks of the window a block on top of `parent` goes in that signaled
+    // for the deployment so far, none for deployments activating at a height
+    pub fn signaling_in_window(
+        &self,
+        deployment: &Deployment,
+        chain: &BlockChain,
+        parent: &[u8; 32],
+    ) -> u64 {
+        let Some(height) = chain.block_by_hash(parent).map(|block| block.index() + 1) else {
+            return 0;
+        };
+
+        match deployment.activation {
+            Activation::Signaling { bit, .. } => {
+                self.signaling(chain, bit, parent, height - height % self.window)
+            }
+            Activation::Height(_) => 0,
+        }
+    }
+
+    // Blocks with the version bit set among `last` and its ancestors in the
+    // window from `window_start` on
+    fn signaling(&self, chain: &BlockChain, bit: u8, last: &[u8; 32], window_start: u64) -> u64 {
+        chain
+            .ancestors(last)
+            .take_while(|block| block.index() >= window_start)
+            .filter(|block| block.signals(bit))
+            .count() as u64
+    }
+
+    // Rules a block on top of `parent` and its transactions are checked
+    // against
+    pub fn active_rules(
+        &self,
+        chain: &BlockChain,
+        parent: &[u8; 32],
+        cache: &mut StateCache,
+    ) -> Rules {
+        self.deployments
+            .iter()
+            .filter(|deployment| {
+                self.state(deployment, chain, parent, cache) == DeploymentState::Active
+            })
+            .fold(Rules::NONE, |rules, deployment| {
+                rules.union(deployment.rules)
+            })
+    }
+
+    // Version of a block mined on top of `parent`, signaling for every
+    // deployment this node is ready for that hasn't activated yet
+    pub fn block_version(
+        &self,
+        chain: &BlockChain,
+        parent: &[u8; 32],
+        cache: &mut StateCache,
+    ) -> u32 {
+        self.deployments
+            .iter()
+            .filter_map(|deployment| match deployment.activation {
+                Activation::Signaling { bit, .. }
+                    if matches!(
+                        self.state(deployment, chain, parent, cache),
+                        DeploymentState::Started | DeploymentState::LockedIn
+                    ) =>
+                {
+                    Some(1u32 << bit)
+                }
+                _ => None,
+            })
+            .fold(0, |version, bit| version | bit)
+    }
+}
+
+// Deployment states as of the last block of each window, keyed by the
+// block's hash. The state of a block is final, so entries stay valid
+// across reorganizations
+#[derive(Debug, Clone, Default)]
+pub struct StateCache {
+    states: BTreeMap<([u8; 32], Rules), DeploymentState>,
+}
+
+impl StateCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get(&self, hash: &[u8; 32], rules: Rules) -> Option<DeploymentState> {
+        self.states.get(&(*hash, rules)).copied()
+    }
+
+    fn insert(&mut self, hash: [u8; 32], rules: Rules, state: DeploymentState) {
+        self.states.insert((hash, rules), state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        block::{Block, BlockBuilder},
+        config::Network,
+    };
+
+    const WINDOW: u64 = 4;
+    const THRESHOLD: u64 = 3;
+
+    fn block(index: u64, previous: Option<&Block>, signals: bool, miner: u8) -> Block {
+        let previous_hash = hex::encode(previous.map_or([0u8; 32], Block::hash));
+        BlockBuilder::new(index, previous_hash, 1, [miner; 32])
+            .version(u32::from(signals))
+            .build()
+            .unwrap()
+    }
+
+    // Chain of `length` blocks, the ones below `signaling` signal bit 0
+    fn chain(length: u64, signaling: u64) -> BlockChain {
+        let mut chain =
+            BlockChain::from_genesis(block(0, None, signaling > 0, 0), Network::Mainnet).unwrap();
+        for index in 1..length {
+            let next = block(index, Some(chain.tip()), index < signaling, index as u8);
+            chain.add_block(next).unwrap();
+        }
+        chain
+    }
+
+    // Hash of the best chain block below `height`
+    fn parent(chain: &BlockChain, height: u64) -> [u8; 32] {
+        chain.block(height - 1).unwrap().hash()
+    }
+
+    #[test]
+    fn activates_deployments_by_height_and_signaling() {
+        let deployment = Deployment {
+            rules: Rules::TIMELOCKS,
+            activation: Activation::Signaling {
+                bit: 0,
+                start_height: 0,
+            },
+        };
+        let deployments = Deployments::new(vec![deployment]).with_window(WINDOW, THRESHOLD);
+
+        let cache = &mut StateCache::new();
+
+        let chain = chain(2 * WINDOW + 1, THRESHOLD);
+        assert_eq!(
+            deployments.state(&deployment, &chain, &parent(&chain, 1), cache),
+            DeploymentState::Started
+        );
+        assert_eq!(
+            deployments.signaling_in_window(&deployment, &chain, &parent(&chain, THRESHOLD - 1)),
+            THRESHOLD - 1
+        );
+        // The next window counts from scratch
+        assert_eq!(
+            deployments.signaling_in_window(&deployment, &chain, &parent(&chain, WINDOW)),
+            0
+        );
+        assert_eq!(
+            deployments.state(&deployment, &chain, &parent(&chain, WINDOW), cache),
+            DeploymentState::LockedIn
+        );
+        assert_eq!(
+            deployments.state(&deployment, &chain, &parent(&chain, 2 * WINDOW), cache),
+            DeploymentState::Active
+        );
+
+        assert_eq!(
+            deployments.active_rules(&chain, &parent(&chain, 1), cache),
+            Rules::NONE
+        );
+        assert_eq!(
+            deployments.block_version(&chain, &parent(&chain, WINDOW), cache),
+            1
+        );
+        assert_eq!(
+            deployments.active_rules(&chain, &parent(&chain, 2 * WINDOW), cache),
+            Rules::TIMELOCKS
+        );
+        assert_eq!(
+            deployments.block_version(&chain, &parent(&chain, 2 * WINDOW), cache),
+            0
+        );
+
+        // One block short of the threshold keeps signaling going
+        let chain = self::chain(2 * WINDOW + 1, THRESHOLD - 1);
+        assert_eq!(
+            deployments.state(&deployment, &chain, &parent(&chain, 2 * WINDOW), cache),
+            DeploymentState::Started
+        );
+
+        let mut deployments = deployments;
+        deployments.activate_at(Rules::TIMELOCKS, 5);
+        assert_eq!(
+            deployments.active_rules(&chain, &parent(&chain, 4), cache),
+            Rules::NONE
+        );
+        assert_eq!(
+            deployments.active_rules(&chain, &parent(&chain, 5), cache),
+            Rules::TIMELOCKS
+        );
+        assert_eq!("timelocks".parse::<Rules>().unwrap(), Rules::TIMELOCKS);
+    }
+
+    #[test]
+    fn follows_the_branch_of_the_block() {
+        let deployment = Deployment {
+            rules: Rules::TIMELOCKS,
+            activation: Activation::Signaling {
+                bit: 0,
+                start_height: 0,
+            },
+        };
+        let deployments = Deployments::new(vec![deployment]).with_window(WINDOW, THRESHOLD);
+        let cache = &mut StateCache::new();
+
+        // The best chain locks the deployment in, a shorter branch off the
+        // genesis block doesn't signal
+        let mut chain = chain(2 * WINDOW, WINDOW);
+        let mut branch = chain.block(0).unwrap().clone();
+        for index in 1..WINDOW {
+            branch = block(index, Some(&branch), false, 100 + index as u8);
+            chain.add_block(branch.clone()).unwrap();
+        }
+        assert_eq!(chain.height(), 2 * WINDOW);
+        assert_eq!(
+            chain.ancestor(&branch.hash(), 0),
+            Some(chain.block(0).unwrap().hash())
+        );
+
+        let best_tip = chain.tip().hash();
+        assert_eq!(
+            deployments.state(&deployment, &chain, &best_tip, cache),
+            DeploymentState::Active
+        );
+        assert_eq!(
+            deployments.state(&deployment, &chain, &branch.hash(), cache),
+            DeploymentState::Started
+        );
+        assert_eq!(
+            deployments.signaling_in_window(&deployment, &chain, &branch.hash()),
+            0
+        );
+        // One state per window and branch is kept
+        assert_eq!(cache.states.len(), 3);
+
+        // Unknown parents have nothing deployed
+        assert_eq!(
+            deployments.state(&deployment, &chain, &[0u8; 32], cache),
+            DeploymentState::Defined
+        );
+    }
+}
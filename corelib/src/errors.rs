@@ -1,14 +1,22 @@
 use std::time::SystemTimeError;
 
-use ed25519_dalek::{ed25519, SignatureError};
+use ed25519_dalek::ed25519;
 use hex::FromHexError;
 use thiserror::Error;
 
+use crate::utxo::OutPoint;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Network Error")]
     Network,
 
+    #[error("Failed to listen on {address}: {source}")]
+    Listen {
+        address: std::net::SocketAddr,
+        source: std::io::Error,
+    },
+
     #[error("Error serializing/deserializing")]
     IO(#[from] std::io::Error),
 
@@ -27,21 +35,54 @@ pub enum Error {
     #[error("Insufficient funds to carry out transaction")]
     InsufficientFunds,
 
+    #[error("Values add up to more than fits in 64 bits")]
+    ValueOverflow,
+
     #[error("Unauthorized to perform action")]
     UnAuthorized,
 
+    #[error("Transaction id doesn't match its contents")]
+    TxidMismatch,
+
+    #[error("Unsupported transaction version {0}")]
+    UnsupportedTxVersion(u8),
+
+    #[error("Version {0} transactions have no extension")]
+    UnexpectedExtension(u8),
+
     #[error("UTXO not confirmed")]
     PendingUTXO,
 
     #[error("UTXO already confirmed")]
     ConfirmedUTXO,
 
+    #[error("UTXO is not in the UTXO set")]
+    UnknownUTXO,
+
+    #[error("Input {0} doesn't match the output it spends")]
+    InputMismatch(usize),
+
+    #[error("Invalid outpoint {0}, expected <txid>:<vout>")]
+    InvalidOutPoint(String),
+
+    #[error("Invalid address {0}")]
+    InvalidAddress(String),
+
+    #[error("Script of {0} bytes exceeds the maximum size")]
+    ScriptTooLarge(usize),
+
+    #[error("{0} signature operations exceed the limit")]
+    TooManySigops(usize),
+
     #[error("Invalid UTXO value")]
     InvalidUTXOValue,
 
     #[error("Invalid unlocking script used")]
     InvalidUnlockingScript,
 
+    #[error("Invalid script: {0}")]
+    InvalidScript(String),
+
     #[error("Invalid u8 length: length {0}")]
     InvalidU8Length(usize),
 
@@ -56,6 +97,52 @@ pub enum Error {
 
     #[error("Low fee transaction")]
     TxnLowFee,
+
+    #[error("Transaction exceeds the mempool's package limits: {0}")]
+    PackageLimit(String),
+
+    #[error("Fee rate of zero bytes")]
+    InvalidFeeRate,
+
+    #[error("Outpoint {0} is already spent by a mempool transaction")]
+    DoubleSpend(OutPoint),
+
+    #[error("Invalid signature")]
+    InvalidSignature,
+
+    #[error("Input {0} can't be signed with this sighash type")]
+    InvalidSigHash(usize),
+
+    #[error("Transactions without inputs are only valid as a block's coinbase")]
+    MintOutsideCoinbase,
+
+    #[error("Coinbase tag of {0} bytes exceeds the maximum size")]
+    CoinbaseTagTooLong(usize),
+
+    #[error("Spend is timelocked")]
+    Timelocked,
+
+    #[error("Invalid block: {0}")]
+    InvalidBlock(String),
+
+    // Not invalid, the block can be accepted once the clock catches up
+    #[error("Block timestamp is too far in the future")]
+    FutureBlock,
+
+    #[error("Invalid chain parameters: {0}")]
+    InvalidChainParams(String),
+
+    #[error("Corrupt chain: {0}")]
+    CorruptChain(String),
+
+    #[error("Wrong keystore passphrase")]
+    WrongPassphrase,
+
+    #[error("Invalid keystore: {0}")]
+    InvalidKeystore(String),
+
+    #[error("Unsupported keystore version: {0}")]
+    UnsupportedKeystoreVersion(u16),
 }
 
 #[derive(Error, Debug)]
@@ -77,6 +164,18 @@ pub enum ProtocolError {
 
     #[error("Error serializing: {0}")]
     SerializationError(String),
+
+    #[error("Payload of {0} bytes exceeds the maximum size")]
+    PayloadTooLarge(usize),
+
+    #[error("Frame doesn't start with the network magic")]
+    InvalidMagic,
+
+    #[error("Payload doesn't match its checksum")]
+    ChecksumMismatch,
+
+    #[error("Key exchange with the peer failed")]
+    KeyExchangeFailed,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,270 @@
+use std::{collections::VecDeque, fmt};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use crate::errors::{Error, Result};
+
+// Bytes a fee rate is quoted for
+const KILOBYTE: u128 = 1_000;
+
+// Recent blocks the estimator learns from by default, about a day's worth
+pub const DEFAULT_FEE_HISTORY: usize = 144;
+// Share of the block size in percent from which a block counts as full,
+// below it any fee rate would have made it in
+const FULL_BLOCK_PERCENT: u64 = 90;
+// Chance in percent an estimate aims for of confirming within the target
+const CONFIDENCE_PERCENT: f64 = 95.0;
+
+// Fee paid per 1000 bytes of serialized size. Quoting per kilobyte keeps the
+// fee of small transactions, which rounds away when divided by their size,
+// so the pool can still tell low fees apart.
+//
+// Rates computed from a fee round down and fees computed from a rate round
+// up, neither side ever looks like it pays more than it does
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    BorshSerialize,
+    BorshDeserialize,
+)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FeeRate(u64);
+
+impl FeeRate {
+    pub const ZERO: FeeRate = FeeRate(0);
+
+    // Rate of a fee paid for `size` bytes
+    pub fn new(fee: u64, size: u64) -> Result<Self> {
+        if size == 0 {
+            return Err(Error::InvalidFeeRate);
+        }
+
+        let rate = fee as u128 * KILOBYTE / size as u128;
+        Ok(Self(rate.try_into().unwrap_or(u64::MAX)))
+    }
+
+    pub fn from_per_kb(fee: u64) -> Self {
+        Self(fee)
+    }
+
+    pub fn from_per_byte(fee: u64) -> Self {
+        Self(fee.saturating_mul(KILOBYTE as u64))
+    }
+
+    pub fn per_kb(&self) -> u64 {
+        self.0
+    }
+
+    // Fee of `size` bytes at this rate
+    pub fn fee_for(&self, size: u64) -> u64 {
+        let fee = (self.0 as u128 * size as u128).div_ceil(KILOBYTE);
+        fee.try_into().unwrap_or(u64::MAX)
+    }
+}
+
+impl fmt::Display for FeeRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/kB", self.0)
+    }
+}
+
+// Suggests fee rates confirming a transaction within a number of blocks.
+//
+// Two things are looked at. The recent blocks show the lowest rate a block
+// took, a full block turning away everything below it, and a rate taken by
+// enough of them is likely to be taken by one of the next blocks too. The
+// waiting transactions show the congestion right now, a transaction has to
+// outbid all but the ones the next blocks have room for. The estimate is
+// the higher of the two
+#[derive(Debug, Clone)]
+pub struct FeeEstimator {
+    max_block_size: u64,
+    history: usize,
+    // Lowest rate each recent block took, oldest first. Blocks with room to
+    // spare took any rate, full ones without a known rate are `None` so a
+    // disconnected block always takes its own sample along
+    block_rates: VecDeque<Option<FeeRate>>,
+}
+
+impl FeeEstimator {
+    pub fn new(max_block_size: usize) -> Self {
+        Self {
+            max_block_size: max_block_size as u64,
+            history: DEFAULT_FEE_HISTORY,
+            block_rates: VecDeque::new(),
+        }
+    }
+
+    // Learns from the last `blocks` blocks instead
+    pub fn with_history(mut self, blocks: usize) -> Self {
+        self.history = blocks.max(1);
+        self
+    }
+
+    // Records a block added to the chain from its serialized size and the
+    // rates of the transactions whose fee is known. A full block where none
+    // is known tells nothing and isn't counted in estimates
+    pub fn record_block(&mut self, size: u64, rates: impl IntoIterator<Item = FeeRate>) {
+        let full = size as u128 * 100 >= self.max_block_size as u128 * FULL_BLOCK_PERCENT as u128;
+        let rate = if full {
+            rates.into_iter().min()
+        } else {
+            Some(FeeRate::ZERO)
+        };
+
+        self.block_rates.push_back(rate);
+        while self.block_rates.len() > self.history {
+            self.block_rates.pop_front();
+        }
+    }
+
+    // Forgets the newest block after it was disconnected from the chain
+    pub fn disconnect_block(&mut self) {
+        self.block_rates.pop_back();
+    }
+
+    // Fee rate confirming within `target_blocks` blocks, given the rates and
+    // sizes of the transactions waiting for a block. Targets below one block
+    // are taken as one
+    pub fn estimate_fee_rate(
+        &self,
+        target_blocks: u64,
+        pending: impl IntoIterator<Item = (FeeRate, u64)>,
+    ) -> FeeRate {
+        let target_blocks = target_blocks.max(1);
+        self.congestion_rate(target_blocks, pending)
+            .max(self.history_rate(target_blocks))
+    }
+
+    // Rate outbidding the waiting transactions that don't fit in the target's
+    // blocks, as long as nothing else arrives
+    fn congestion_rate(
+        &self,
+        target_blocks: u64,
+        pending: impl IntoIterator<Item = (FeeRate, u64)>,
+    ) -> FeeRate {
+        let mut pending: Vec<(FeeRate, u64)> = pending.into_iter().collect();
+        pending.sort_unstable_by_key(|(rate, _)| std::cmp::Reverse(*rate));
+
+        let room = self.max_block_size.saturating_mul(target_blocks);
+        let mut used: u64 = 0;
+        for (rate, size) in pending {
+            used = used.saturating_add(size);
+            // Rates tie by age, outbidding by one puts it ahead
+            if used > room {
+                return FeeRate(rate.0.saturating_add(1));
+            }
+        }
+        FeeRate::ZERO
+    }
+
+    // Lowest rate enough of the recent blocks took that one of the target's
+    // blocks takes it with the aimed for confidence
+    fn history_rate(&self, target_blocks: u64) -> FeeRate {
+        let mut rates: Vec<FeeRate> = self.block_rates.iter().flatten().copied().collect();
+        if rates.is_empty() {
+            return FeeRate::ZERO;
+        }
+        rates.sort_unstable();
+
+        // Every block has to take the rate with this chance for it to confirm
+        // within the target with the confidence
+        let miss = 1.0 - CONFIDENCE_PERCENT / 100.0;
+        let per_block = 1.0 - miss.powf(1.0 / target_blocks as f64);
+        let needed = (per_block * rates.len() as f64).ceil() as usize;
+
+        rates[needed.clamp(1, rates.len()) - 1]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rounds_rates_down_and_fees_up() {
+        let rate = FeeRate::new(1, 300).unwrap();
+        assert_eq!(rate.per_kb(), 3);
+        assert!(rate > FeeRate::ZERO);
+        assert!(FeeRate::new(2, 300).unwrap() > rate);
+
+        assert_eq!(rate.fee_for(300), 1);
+        assert_eq!(rate.fee_for(334), 2);
+        assert_eq!(FeeRate::from_per_byte(2).fee_for(250), 500);
+
+        assert!(matches!(FeeRate::new(1, 0), Err(Error::InvalidFeeRate)));
+        assert_eq!(FeeRate::new(u64::MAX, 1).unwrap().per_kb(), u64::MAX);
+        assert_eq!(FeeRate::from_per_kb(u64::MAX).fee_for(u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn estimates_from_recent_blocks_and_congestion() {
+        let mut estimator = FeeEstimator::new(1_000).with_history(10);
+        assert_eq!(estimator.estimate_fee_rate(1, []), FeeRate::ZERO);
+
+        // Blocks with room to spare take anything
+        estimator.record_block(500, [FeeRate(50)]);
+        assert_eq!(estimator.estimate_fee_rate(1, []), FeeRate::ZERO);
+
+        // Nine full blocks took 100 and up, one of them only 200 and up
+        for _ in 0..8 {
+            estimator.record_block(950, [FeeRate(100), FeeRate(300)]);
+        }
+        estimator.record_block(1_000, [FeeRate(200)]);
+        assert_eq!(estimator.estimate_fee_rate(1, []), FeeRate(200));
+        // Waiting longer, a lower rate that most blocks took does
+        assert_eq!(estimator.estimate_fee_rate(3, []), FeeRate(100));
+        assert_eq!(estimator.estimate_fee_rate(0, []), FeeRate(200));
+
+        // The oldest block is forgotten, a disconnected one too
+        estimator.record_block(1_000, [FeeRate(100)]);
+        estimator.disconnect_block();
+        assert_eq!(estimator.block_rates.len(), 9);
+        assert!(estimator
+            .block_rates
+            .iter()
+            .all(|rate| *rate >= Some(FeeRate(100))));
+
+        // A backlog of two blocks worth at 500 has to be outbid to get into
+        // the next block, but not to get into the third
+        let backlog = vec![(FeeRate(500), 400); 5];
+        assert_eq!(
+            estimator.estimate_fee_rate(1, backlog.clone()),
+            FeeRate(501)
+        );
+        assert_eq!(estimator.estimate_fee_rate(3, backlog), FeeRate(100));
+    }
+
+    #[test]
+    fn reorgs_forget_the_disconnected_blocks_samples() {
+        let mut estimator = FeeEstimator::new(1_000).with_history(4);
+        for _ in 0..3 {
+            estimator.record_block(1_000, [FeeRate(100)]);
+        }
+        let before = estimator.estimate_fee_rate(1, []);
+
+        // A full block of transactions this node never saw, then a reorg
+        // replacing it
+        estimator.record_block(1_000, []);
+        assert_eq!(estimator.estimate_fee_rate(1, []), before);
+        estimator.disconnect_block();
+        estimator.record_block(500, []);
+
+        assert_eq!(
+            estimator.block_rates,
+            [
+                Some(FeeRate(100)),
+                Some(FeeRate(100)),
+                Some(FeeRate(100)),
+                Some(FeeRate::ZERO)
+            ]
+        );
+    }
+}
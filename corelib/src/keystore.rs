@@ -0,0 +1,308 @@
+use std::{fs, io::Write, path::Path};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use borsh::{BorshDeserialize, BorshSerialize};
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    Key, KeyInit, XChaCha20Poly1305, XNonce,
+};
+use ed25519_dalek::{Signer, SigningKey};
+use rand::{rngs::OsRng, RngCore};
+use zeroize::Zeroizing;
+
+use crate::errors::{Error, Result};
+
+// Start of keystore files, followed by the borsh encoded keystore
+const MAGIC: &[u8; 4] = b"AKEY";
+pub const KEYSTORE_VERSION: u16 = 1;
+
+// Files asking for more memory than this, 1 GiB, are refused instead of
+// letting a crafted file exhaust the machine
+const MAX_MEMORY_KIB: u32 = 1 << 20;
+
+// Argon2id cost of deriving the encryption key from the passphrase
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    // OWASP's recommended minimum for argon2id
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+// Signing key encrypted at rest. The encryption key is derived from the
+// passphrase with argon2id and the secret sealed with XChaCha20-Poly1305.
+// Everything but the ciphertext is authenticated too, so a keystore whose
+// public key or KDF parameters were tampered with doesn't unlock
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Keystore {
+    version: u16,
+    public_key: [u8; 32],
+    kdf: KdfParams,
+    salt: [u8; 16],
+    nonce: [u8; 24],
+    ciphertext: Vec<u8>,
+}
+
+impl Keystore {
+    pub fn create(signing_key: &SigningKey, passphrase: &str) -> Result<Self> {
+        Self::create_with(signing_key, passphrase, KdfParams::default())
+    }
+
+    pub fn create_with(signing_key: &SigningKey, passphrase: &str, kdf: KdfParams) -> Result<Self> {
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 24];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+
+        let mut keystore = Self {
+            version: KEYSTORE_VERSION,
+            public_key: signing_key.verifying_key().to_bytes(),
+            kdf,
+            salt,
+            nonce,
+            ciphertext: Vec::new(),
+        };
+        keystore.ciphertext = seal(
+            passphrase,
+            &kdf,
+            &salt,
+            &nonce,
+            signing_key.as_bytes(),
+            &keystore.header()?,
+        )?;
+
+        Ok(keystore)
+    }
+
+    // Readable without the passphrase, e.g. to pay mined blocks to the key
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public_key
+    }
+
+    // Decrypts the signing key, a wrong passphrase fails authentication
+    pub fn unlock(&self, passphrase: &str) -> Result<SigningKey> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(Error::UnsupportedKeystoreVersion(self.version));
+        }
+
+        let secret = open(
+            passphrase,
+            &self.kdf,
+            &self.salt,
+            &self.nonce,
+            &self.ciphertext,
+            &self.header()?,
+        )?;
+        let secret = <&[u8; 32]>::try_from(secret.as_slice())
+            .map_err(|_| Error::InvalidKeystore("expected a 32 byte key".to_string()))?;
+
+        let signing_key = SigningKey::from_bytes(secret);
+        if signing_key.verifying_key().to_bytes() != self.public_key {
+            return Err(Error::InvalidKeystore(
+                "key doesn't match its public key".to_string(),
+            ));
+        }
+        Ok(signing_key)
+    }
+
+    // Signs with the key, which is only decrypted for the signature
+    pub fn sign(&self, passphrase: &str, message: &[u8]) -> Result<[u8; 64]> {
+        Ok(self.unlock(passphrase)?.sign(message).to_bytes())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(borsh::to_vec(self)?);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let body = bytes
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| Error::InvalidKeystore("not a keystore file".to_string()))?;
+        let keystore =
+            Self::try_from_slice(body).map_err(|e| Error::InvalidKeystore(e.to_string()))?;
+
+        if keystore.version != KEYSTORE_VERSION {
+            return Err(Error::UnsupportedKeystoreVersion(keystore.version));
+        }
+        Ok(keystore)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    // Written to a temporary file first so a crash never leaves a torn file,
+    // on unix only the owner can read it
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(&tmp)?.write_all(&self.to_bytes()?)?;
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    // Associated data of the ciphertext
+    fn header(&self) -> Result<Vec<u8>> {
+        Ok(borsh::to_vec(&(
+            self.version,
+            self.public_key,
+            self.kdf,
+            self.salt,
+            self.nonce,
+        ))?)
+    }
+}
+
+// Encrypts `plaintext` with a key derived from the passphrase, `aad` is
+// authenticated along with it. Also used for wallet backups
+pub fn seal(
+    passphrase: &str,
+    kdf: &KdfParams,
+    salt: &[u8; 16],
+    nonce: &[u8; 24],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let payload = Payload {
+        msg: plaintext,
+        aad,
+    };
+    cipher(passphrase, kdf, salt)?
+        .encrypt(XNonce::from_slice(nonce), payload)
+        .map_err(|_| Error::InvalidKeystore("encryption failed".to_string()))
+}
+
+// Reverses `seal`, a wrong passphrase or any tampering with the ciphertext or
+// `aad` fails authentication
+pub fn open(
+    passphrase: &str,
+    kdf: &KdfParams,
+    salt: &[u8; 16],
+    nonce: &[u8; 24],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Zeroizing<Vec<u8>>> {
+    let payload = Payload {
+        msg: ciphertext,
+        aad,
+    };
+    Ok(Zeroizing::new(
+        cipher(passphrase, kdf, salt)?
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| Error::WrongPassphrase)?,
+    ))
+}
+
+fn cipher(passphrase: &str, kdf: &KdfParams, salt: &[u8; 16]) -> Result<XChaCha20Poly1305> {
+    let invalid = |e: argon2::Error| Error::InvalidKeystore(e.to_string());
+    if kdf.memory_kib > MAX_MEMORY_KIB {
+        return Err(Error::InvalidKeystore(format!(
+            "argon2 memory of {} KiB exceeds {MAX_MEMORY_KIB} KiB",
+            kdf.memory_kib
+        )));
+    }
+
+    let params =
+        Params::new(kdf.memory_kib, kdf.iterations, kdf.parallelism, Some(32)).map_err(invalid)?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(invalid)?;
+
+    Ok(XChaCha20Poly1305::new(Key::from_slice(key.as_ref())))
+}
+
+#[cfg(test)]
+mod test {
+    use ed25519_dalek::{Signature, Verifier};
+
+    use crate::rng::test_key;
+
+    use super::*;
+
+    // Cheap parameters keeping the tests fast
+    const TEST_KDF: KdfParams = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn encrypts_keys_behind_a_passphrase() {
+        let signing_key = test_key("miner");
+        let keystore = Keystore::create_with(&signing_key, "correct horse", TEST_KDF).unwrap();
+        assert_eq!(
+            keystore.public_key(),
+            signing_key.verifying_key().to_bytes()
+        );
+        assert!(!keystore
+            .to_bytes()
+            .unwrap()
+            .windows(32)
+            .any(|window| window == signing_key.as_bytes()));
+
+        let file = std::env::temp_dir().join(format!("keystore-{}", uuid::Uuid::new_v4()));
+        keystore.save(&file).unwrap();
+        let loaded = Keystore::load(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(loaded, keystore);
+
+        assert_eq!(
+            loaded.unlock("correct horse").unwrap().to_bytes(),
+            signing_key.to_bytes()
+        );
+        assert!(matches!(
+            loaded.unlock("battery staple"),
+            Err(Error::WrongPassphrase)
+        ));
+
+        let signature = loaded.sign("correct horse", b"block reward").unwrap();
+        signing_key
+            .verifying_key()
+            .verify(b"block reward", &Signature::from_bytes(&signature))
+            .unwrap();
+    }
+
+    #[test]
+    fn refuses_tampered_keystores() {
+        let keystore = Keystore::create_with(&test_key("miner"), "passphrase", TEST_KDF).unwrap();
+
+        // Swapping the public key would pay somebody else
+        let mut swapped = keystore.clone();
+        swapped.public_key = test_key("thief").verifying_key().to_bytes();
+        assert!(swapped.unlock("passphrase").is_err());
+
+        let mut weakened = keystore.clone();
+        weakened.kdf.iterations = 2;
+        assert!(weakened.unlock("passphrase").is_err());
+
+        let mut future = keystore.to_bytes().unwrap();
+        future[MAGIC.len()] = 2;
+        assert!(matches!(
+            Keystore::from_bytes(&future),
+            Err(Error::UnsupportedKeystoreVersion(2))
+        ));
+        assert!(matches!(
+            Keystore::from_bytes(b"wallet"),
+            Err(Error::InvalidKeystore(_))
+        ));
+    }
+}
@@ -1,12 +1,23 @@
+pub mod address;
 pub mod block;
-mod config;
+pub mod config;
+pub mod deployment;
 pub mod errors;
+pub mod fee;
+#[cfg(feature = "keystore")]
+pub mod keystore;
 pub mod net;
 pub mod transaction;
 pub mod utxo;
+pub mod script;
 pub mod sign;
 mod utils;
+#[cfg(test)]
 mod test_utils;
 pub mod merkle;
 pub mod blockchain;
 pub mod mempool;
+pub mod metrics;
+pub mod memory;
+#[cfg(any(feature = "io", test))]
+pub mod rng;
@@ -0,0 +1,86 @@
+use std::mem::size_of;
+
+use crate::{block::Block, transaction::Transaction, utxo::UTXO};
+
+// Approximate memory a value takes, used to keep the pools and the UTXO set
+// within their budgets. Allocator overhead and spare capacity aren't counted,
+// so the numbers only shrink and grow with what's stored
+pub trait MemoryUsage {
+    // Bytes the value owns on the heap
+    fn heap_usage(&self) -> usize;
+
+    // Bytes of the value itself and what it owns on the heap
+    fn memory_usage(&self) -> usize
+    where
+        Self: Sized,
+    {
+        size_of::<Self>() + self.heap_usage()
+    }
+}
+
+impl MemoryUsage for UTXO {
+    fn heap_usage(&self) -> usize {
+        match self {
+            UTXO::Confirmed { script_pubkey, .. } => script_pubkey.len(),
+            UTXO::Pending { .. } | UTXO::PendingScriptHash { .. } => 0,
+        }
+    }
+}
+
+impl MemoryUsage for Transaction {
+    fn heap_usage(&self) -> usize {
+        self.extension.len()
+            + self
+                .unlocking_scripts
+                .iter()
+                .map(|script| size_of::<String>() + script.len())
+                .sum::<usize>()
+            + self
+                .inputs
+                .iter()
+                .chain(self.outputs.iter())
+                .map(MemoryUsage::memory_usage)
+                .sum::<usize>()
+    }
+}
+
+impl MemoryUsage for Block {
+    fn heap_usage(&self) -> usize {
+        self.previous_hash().len()
+            + self
+                .transactions()
+                .iter()
+                .map(MemoryUsage::memory_usage)
+                .sum::<usize>()
+    }
+}
+
+// Bytes a hash map entry takes besides what the key and value own, the
+// entry itself and the control byte of its slot
+pub fn map_entry_usage<K, V>() -> usize {
+    size_of::<(K, V)>() + 1
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_owned_outputs() {
+        let output = UTXO::new(10, 0, [1u8; 32]).unwrap();
+        let confirmed = output.clone().confirm_utxo([2u8; 32], 1, false).unwrap();
+        assert_eq!(output.memory_usage(), size_of::<UTXO>());
+        assert!(confirmed.memory_usage() > output.memory_usage());
+
+        let txn = Transaction::coinbase([1u8; 32], 10, 1).unwrap();
+        assert_eq!(
+            txn.memory_usage(),
+            size_of::<Transaction>()
+                + txn
+                    .outputs
+                    .iter()
+                    .map(MemoryUsage::memory_usage)
+                    .sum::<usize>()
+        );
+    }
+}
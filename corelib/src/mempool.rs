@@ -1,26 +1,58 @@
 use std::{
-    collections::{BinaryHeap, HashMap},
-    time::{SystemTime, UNIX_EPOCH},
+    cmp::Reverse,
+    collections::{BTreeSet, BinaryHeap, HashMap, HashSet},
+    fmt, iter,
+    mem::size_of,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use borsh::{BorshDeserialize, BorshSerialize};
+use imbl::HashMap as SharedMap;
 
 use crate::{
     errors::{Error, Result},
+    fee::FeeRate,
+    memory::{map_entry_usage, MemoryUsage},
     transaction::Transaction,
+    utxo::{OutPoint, UTXO},
 };
 
+// Serialized bytes of transactions the pool holds by default
+pub const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+// How long a transaction waits in the pool by default before it expires
+pub const DEFAULT_TTL: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+// Most transactions a pool transaction and its pool ancestors may count, and
+// the most serialized bytes they may take. The same holds for a pool
+// transaction and its pool descendants
+pub const MAX_PACKAGE_COUNT: usize = 25;
+pub const MAX_PACKAGE_BYTES: usize = 101_000;
+
 #[derive(Debug, Clone)]
 pub struct MemPool {
     pub transactions: HashMap<[u8; 32], Transaction>,
-    pub priority_queue: BinaryHeap<PriorityEntry>,
+    // Priority entries of the pool transactions, the last is evicted first
+    pub priority_queue: BTreeSet<PriorityEntry>,
     pub max_size: usize,
+    // Budget for the serialized size of all the transactions in the pool
+    pub max_bytes: usize,
+    // Time in milliseconds a transaction may stay in the pool
+    pub ttl: u128,
+    // Serialized size of all the transactions in the pool
+    bytes: usize,
+    // Pool transaction spending each outpoint
+    spent: HashMap<OutPoint, [u8; 32]>,
+    // Pool transactions spending outputs of each pool transaction
+    children: HashMap<[u8; 32], HashSet<[u8; 32]>>,
+    // Priority entry of each pool transaction, to find it in the queue
+    priorities: HashMap<[u8; 32], PriorityEntry>,
 }
 
 impl BorshSerialize for MemPool {
     fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
-        // Serialize max_size
+        // Serialize the limits
         self.max_size.serialize(writer)?;
+        self.max_bytes.serialize(writer)?;
+        self.ttl.serialize(writer)?;
 
         // Serialize transactions
         let txn_vec: Vec<(&[u8; 32], &Transaction)> = self.transactions.iter().collect();
@@ -36,31 +68,173 @@ impl BorshSerialize for MemPool {
 
 impl BorshDeserialize for MemPool {
     fn deserialize_reader<R: std::io::Read>(reader: &mut R) -> std::io::Result<Self> {
-        // Deserialize max_size
+        // Deserialize the limits
         let max_size = usize::deserialize_reader(reader)?;
+        let max_bytes = usize::deserialize_reader(reader)?;
+        let ttl = u128::deserialize_reader(reader)?;
 
         // Deserialize transactions
         let txn_vec: Vec<([u8; 32], Transaction)> = Vec::deserialize_reader(reader)?;
-        let transactions = txn_vec.into_iter().collect();
 
         // Deserialize priority_queue
         let priority_vec: Vec<PriorityEntry> = Vec::deserialize_reader(reader)?;
-        let priority_queue = BinaryHeap::from(priority_vec);
 
-        Ok(Self {
-            transactions,
-            priority_queue,
+        Ok(Self::from_parts(
             max_size,
-        })
+            max_bytes,
+            ttl,
+            txn_vec,
+            priority_vec,
+        ))
+    }
+}
+
+// In serde formats the pool is its limits, its transactions and their
+// priority entries, like in Borsh. The transactions are listed rather than
+// mapped by hash, formats like JSON only have string keys
+#[cfg(feature = "serde")]
+impl serde::Serialize for MemPool {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        let mut pool = serializer.serialize_struct("MemPool", 5)?;
+        pool.serialize_field("max_size", &self.max_size)?;
+        pool.serialize_field("max_bytes", &self.max_bytes)?;
+        pool.serialize_field("ttl", &self.ttl)?;
+        pool.serialize_field(
+            "transactions",
+            &self.transactions.values().collect::<Vec<_>>(),
+        )?;
+        pool.serialize_field(
+            "priority_queue",
+            &self.priority_queue.iter().collect::<Vec<_>>(),
+        )?;
+        pool.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for MemPool {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(serde::Deserialize)]
+        struct Parts {
+            max_size: usize,
+            max_bytes: usize,
+            ttl: u128,
+            transactions: Vec<Transaction>,
+            priority_queue: Vec<PriorityEntry>,
+        }
+
+        let parts = Parts::deserialize(deserializer)?;
+        Ok(Self::from_parts(
+            parts.max_size,
+            parts.max_bytes,
+            parts.ttl,
+            parts
+                .transactions
+                .into_iter()
+                .map(|txn| (txn.hash_id, txn))
+                .collect(),
+            parts.priority_queue,
+        ))
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PriorityEntry {
-    pub fee_per_byte: u64,
+    pub fee: u64,
+    pub fee_rate: FeeRate,
     pub timestamp: u128,
     pub size: u64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
+    pub txn_hash: [u8; 32],
+}
+
+// A pool transaction along with the fees and sizes of its in-pool ancestors
+// and descendants. The ancestor and descendant totals include the transaction
+// itself
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct MemPoolEntry {
+    pub txn_hash: [u8; 32],
+    pub fee: u64,
+    // Serialized size in bytes
+    pub size: u64,
+    pub fee_rate: FeeRate,
+    // Milliseconds since the transaction entered the pool
+    pub time_in_pool: u128,
+    pub ancestor_count: usize,
+    pub ancestor_fees: u64,
+    pub ancestor_size: u64,
+    pub descendant_count: usize,
+    pub descendant_fees: u64,
+    pub descendant_size: u64,
+    // Whether a conflicting transaction paying more can replace it
+    pub replaceable: bool,
+}
+
+// How transactions are ranked when filling a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionStrategy {
+    // By the transaction's own fee per byte
+    FeeRate,
+    // By the fee per byte of the transaction together with its unselected
+    // pool ancestors, so a high fee child pulls in a low fee parent
+    AncestorPackage,
+}
+
+// Why a transaction left the mempool
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize)]
+pub enum RemovalReason {
+    // Included in a block
+    Mined,
+    // Pushed out of a full pool by a transaction paying more per byte
+    LowFee,
+    // Waited in the pool for too long
+    Expired,
+    // Replaced by another transaction spending the same inputs
+    Replaced,
+    // Spends an input already spent by a transaction in a block
+    ConflictsWithBlock,
+}
+
+impl fmt::Display for RemovalReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            RemovalReason::Mined => "included in a block",
+            RemovalReason::LowFee => "evicted for a higher fee transaction",
+            RemovalReason::Expired => "expired",
+            RemovalReason::Replaced => "replaced by a conflicting transaction",
+            RemovalReason::ConflictsWithBlock => "inputs were spent by a block",
+        };
+        f.write_str(reason)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Removal {
     pub txn_hash: [u8; 32],
+    pub reason: RemovalReason,
+    // Time of removal in milliseconds since the unix epoch
+    pub timestamp: u128,
+}
+
+impl Removal {
+    pub fn new(txn_hash: [u8; 32], reason: RemovalReason) -> Result<Self> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+
+        Ok(Self {
+            txn_hash,
+            reason,
+            timestamp,
+        })
+    }
 }
 
 impl PartialOrd for PriorityEntry {
@@ -69,12 +243,16 @@ impl PartialOrd for PriorityEntry {
     }
 }
 
+// The greatest entry is evicted first: the lowest fee rate, among equal fee
+// rates the oldest and then the lowest txid. Ties are broken the same way on
+// every node, so nodes that saw the same transactions keep the same ones
 impl Ord for PriorityEntry {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         other
-            .fee_per_byte
-            .cmp(&self.fee_per_byte)
-            .then_with(|| self.timestamp.cmp(&other.timestamp))
+            .fee_rate
+            .cmp(&self.fee_rate)
+            .then_with(|| other.timestamp.cmp(&self.timestamp))
+            .then_with(|| other.txn_hash.cmp(&self.txn_hash))
     }
 }
 
@@ -82,87 +260,708 @@ impl MemPool {
     pub fn new(max_size: usize) -> Self {
         MemPool {
             transactions: HashMap::new(),
-            priority_queue: BinaryHeap::new(),
+            priority_queue: BTreeSet::new(),
             max_size,
+            max_bytes: DEFAULT_MAX_BYTES,
+            ttl: DEFAULT_TTL.as_millis(),
+            bytes: 0,
+            spent: HashMap::new(),
+            children: HashMap::new(),
+            priorities: HashMap::new(),
         }
     }
 
-    pub fn add_transaction(&mut self, txn: Transaction, fee: u64) -> Result<()> {
-        let txn_hash = txn.hash_id;
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl.as_millis();
+        self
+    }
+
+    // Pool of deserialized transactions, the outpoints they spend, the
+    // children spending them and the bytes they take are derived again
+    fn from_parts(
+        max_size: usize,
+        max_bytes: usize,
+        ttl: u128,
+        transactions: Vec<([u8; 32], Transaction)>,
+        priority_entries: Vec<PriorityEntry>,
+    ) -> Self {
+        let transactions: HashMap<[u8; 32], Transaction> = transactions.into_iter().collect();
+        let spent: HashMap<OutPoint, [u8; 32]> = transactions
+            .iter()
+            .flat_map(|(txn_hash, txn)| txn.spent_outpoints().map(|outpoint| (outpoint, *txn_hash)))
+            .collect();
+        let mut children: HashMap<[u8; 32], HashSet<[u8; 32]>> = HashMap::new();
+        for (outpoint, child) in spent.iter() {
+            if transactions.contains_key(&outpoint.txid) {
+                children.entry(outpoint.txid).or_default().insert(*child);
+            }
+        }
+        let priorities: HashMap<[u8; 32], PriorityEntry> = priority_entries
+            .into_iter()
+            .map(|entry| (entry.txn_hash, entry))
+            .collect();
+        let priority_queue: BTreeSet<PriorityEntry> = priorities.values().cloned().collect();
+        let bytes = priority_queue.iter().map(|entry| entry.size as usize).sum();
+
+        Self {
+            transactions,
+            priority_queue,
+            max_size,
+            max_bytes,
+            ttl,
+            bytes,
+            spent,
+            children,
+            priorities,
+        }
+    }
+
+    // Fee, rate and size of every transaction in the pool, in no order
+    pub fn entries(&self) -> impl Iterator<Item = &PriorityEntry> {
+        self.priority_queue
+            .iter()
+            .filter(|entry| self.transactions.contains_key(&entry.txn_hash))
+    }
+
+    // Serialized size of all the transactions in the pool
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    // Approximate memory the pool's transactions and their indexes take
+    pub fn memory_usage(&self) -> usize {
+        self.transactions.values().map(Self::txn_memory_usage).sum()
+    }
+
+    // Evicts the least prioritized transactions, along with the pool
+    // transactions spending their outputs, until the pool takes at most
+    // `max_memory` bytes. Returns the hashes of the evicted transactions
+    pub fn trim_to_memory(&mut self, max_memory: usize) -> Vec<[u8; 32]> {
+        let mut usage = self.memory_usage();
+        let mut evicted = Vec::new();
+
+        while usage > max_memory {
+            let Some(lowest_priority) = self.priority_queue.last().map(|entry| entry.txn_hash)
+            else {
+                break;
+            };
+
+            for txn_hash in self
+                .descendants(&lowest_priority)
+                .into_iter()
+                .chain(iter::once(lowest_priority))
+            {
+                if let Some(txn) = self.remove_transaction(&txn_hash) {
+                    usage -= Self::txn_memory_usage(&txn);
+                    evicted.push(txn_hash);
+                }
+            }
+        }
+
+        evicted
+    }
+
+    // Memory of a pool transaction along with its priority entry and the
+    // entries of the outpoints it spends
+    fn txn_memory_usage(txn: &Transaction) -> usize {
+        map_entry_usage::<[u8; 32], Transaction>()
+            + txn.heap_usage()
+            + size_of::<PriorityEntry>()
+            + txn.inputs.len() * map_entry_usage::<OutPoint, [u8; 32]>()
+    }
 
-        if self.transactions.contains_key(&txn_hash) {
+    // Pool transaction spending the outpoint, if any
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<[u8; 32]> {
+        self.spent.get(outpoint).copied()
+    }
+
+    // Outputs the transaction's inputs spend, in the order of the inputs:
+    // unspent outputs of the chain, or outputs of pool transactions as
+    // they'll be stored once confirmed by the block at `height`. Fails for
+    // outputs that are neither
+    pub fn spent_outputs(
+        &self,
+        txn: &Transaction,
+        utxos: &SharedMap<OutPoint, UTXO>,
+        height: u64,
+    ) -> Result<Vec<UTXO>> {
+        txn.inputs
+            .iter()
+            .map(|input| {
+                let outpoint = input.outpoint().ok_or(Error::PendingUTXO)?;
+                if let Some(utxo) = utxos.get(&outpoint) {
+                    return Ok(utxo.clone());
+                }
+
+                let parent = self
+                    .transactions
+                    .get(&outpoint.txid)
+                    .ok_or(Error::UnknownUTXO)?;
+                parent
+                    .outputs
+                    .iter()
+                    .find(|output| output.index() == outpoint.vout)
+                    .ok_or(Error::UnknownUTXO)?
+                    .clone()
+                    .confirm_utxo(parent.hash_id, height as u32, false)
+            })
+            .collect()
+    }
+
+    // Adds a transaction to the pool, returns the hashes of the transactions
+    // it replaced or that were evicted to make room for it, with the reason
+    // each was removed.
+    //
+    // Every input has to spend an unspent output of the chain or an output of
+    // a pool transaction, as it's stored there, and the fee is what the
+    // outputs spent are worth over the transaction's outputs. The transaction
+    // would be included by the block at `height`.
+    //
+    // A transaction spending outpoints already spent by pool transactions
+    // replaces them if they are replaceable and it pays more than all of
+    // them together and more per byte than each of them. Otherwise, or when
+    // it spends the same outpoint twice, it's rejected.
+    pub fn add_transaction(
+        &mut self,
+        txn: Transaction,
+        utxos: &SharedMap<OutPoint, UTXO>,
+        height: u64,
+    ) -> Result<Vec<([u8; 32], RemovalReason)>> {
+        // Coins are only minted by the coinbase of a block
+        if txn.is_coinbase() {
+            return Err(Error::MintOutsideCoinbase);
+        }
+        if self.transactions.contains_key(&txn.hash_id) {
             return Err(Error::TxnExistInMempool);
         }
 
-        let size = txn.size() as u64;
-        let fee_per_byte = fee / size;
+        let spent = self.spent_outputs(&txn, utxos, height)?;
+        if let Some(input) = (0..spent.len()).find(|&i| !spent[i].same_output(&txn.inputs[i])) {
+            return Err(Error::InputMismatch(input));
+        }
+        let fee = txn.fee_spending(&spent)?;
+
+        self.insert(txn, fee)
+    }
+
+    // Admits a transaction paying `fee`, its inputs already looked up
+    fn insert(&mut self, txn: Transaction, fee: u64) -> Result<Vec<([u8; 32], RemovalReason)>> {
+        let txn_hash = txn.hash_id;
+
+        let mut outpoints = HashSet::new();
+        let mut replaced = HashSet::new();
+        let mut conflict = None;
+        for outpoint in txn.spent_outpoints() {
+            if !outpoints.insert(outpoint) {
+                return Err(Error::DoubleSpend(outpoint));
+            }
+            if let Some(spender) = self.spent.get(&outpoint) {
+                conflict.get_or_insert(outpoint);
+                replaced.insert(*spender);
+            }
+        }
+
+        let size = txn.serialized_size() as u64;
+        let fee_rate = FeeRate::new(fee, size)?;
 
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
 
         let entry = PriorityEntry {
-            fee_per_byte,
+            fee,
+            fee_rate,
             size,
             timestamp,
             txn_hash,
         };
 
-        // If number of transactions is greater than max size,
-        // check for the least prioritized transaction
-        // if the new transaction has a higher fee per byte remove the older and
-        // add the new or vice versa
-        if self.transactions.len() >= self.max_size {
-            if let Some(lowest_priority) = self.priority_queue.peek() {
-                if lowest_priority.fee_per_byte < entry.fee_per_byte {
-                    if let Some(removed) = self.priority_queue.pop() {
-                        self.remove_transaction(&removed.txn_hash);
-                    }
-                } else {
-                    return Err(Error::TxnLowFee);
+        if let Some(outpoint) = conflict {
+            if !self.replaceable_by(&replaced, &entry) {
+                return Err(Error::DoubleSpend(outpoint));
+            }
+        }
+        self.check_package_limits(&txn, &replaced, entry.size as usize)?;
+
+        if entry.size as usize > self.max_bytes {
+            return Err(Error::TxnLowFee);
+        }
+
+        // While the pool is over its count or byte budget the least
+        // prioritized transactions make room, along with the pool
+        // transactions spending their outputs, as long as the new transaction
+        // pays more per byte than every one of them
+        let mut evicted = Vec::new();
+        let mut evicting = HashSet::new();
+        let mut count = self.transactions.len() - replaced.len();
+        let mut bytes = self.bytes
+            - self
+                .priority_queue
+                .iter()
+                .filter(|entry| replaced.contains(&entry.txn_hash))
+                .map(|entry| entry.size as usize)
+                .sum::<usize>();
+        let mut lowest_first: Vec<&PriorityEntry> = self
+            .priority_queue
+            .iter()
+            .filter(|entry| !replaced.contains(&entry.txn_hash))
+            .collect();
+
+        while count >= self.max_size || bytes + entry.size as usize > self.max_bytes {
+            let Some(lowest_priority) = lowest_first.pop() else {
+                break;
+            };
+            if evicting.contains(&lowest_priority.txn_hash) {
+                continue;
+            }
+            if lowest_priority.fee_rate >= entry.fee_rate {
+                return Err(Error::TxnLowFee);
+            }
+
+            for txn_hash in self
+                .descendants(&lowest_priority.txn_hash)
+                .into_iter()
+                .chain(iter::once(lowest_priority.txn_hash))
+            {
+                if replaced.contains(&txn_hash) || !evicting.insert(txn_hash) {
+                    continue;
                 }
+                count -= 1;
+                bytes -= self.priorities[&txn_hash].size as usize;
+                evicted.push(txn_hash);
             }
         }
 
+        // Evicting a parent would leave the transaction spending nothing
+        if txn
+            .spent_outpoints()
+            .any(|outpoint| evicting.contains(&outpoint.txid))
+        {
+            return Err(Error::TxnLowFee);
+        }
+
+        let removed: Vec<([u8; 32], RemovalReason)> = replaced
+            .into_iter()
+            .map(|txn_hash| (txn_hash, RemovalReason::Replaced))
+            .chain(
+                evicted
+                    .into_iter()
+                    .map(|txn_hash| (txn_hash, RemovalReason::LowFee)),
+            )
+            .collect();
+        for (txn_hash, _) in removed.iter() {
+            self.remove_transaction(txn_hash);
+        }
+
+        self.bytes += entry.size as usize;
+        for outpoint in outpoints.iter() {
+            if self.transactions.contains_key(&outpoint.txid) {
+                self.children
+                    .entry(outpoint.txid)
+                    .or_default()
+                    .insert(txn_hash);
+            }
+        }
+        // Pool transactions may spend its outputs already, e.g. when it comes
+        // back from a disconnected block
+        let spenders: HashSet<[u8; 32]> = txn
+            .outputs
+            .iter()
+            .filter_map(|output| self.spent.get(&OutPoint::new(txn_hash, output.index())))
+            .copied()
+            .collect();
+        if !spenders.is_empty() {
+            self.children.insert(txn_hash, spenders);
+        }
+        self.spent
+            .extend(outpoints.into_iter().map(|outpoint| (outpoint, txn_hash)));
         self.transactions.insert(txn_hash, txn);
-        self.priority_queue.push(entry);
+        self.priorities.insert(txn_hash, entry.clone());
+        self.priority_queue.insert(entry);
+
+        Ok(removed)
+    }
+
+    // A pool transaction can be replaced as long as no other pool transaction
+    // spends its outputs
+    pub fn is_replaceable(&self, txn_hash: &[u8; 32]) -> bool {
+        self.transactions.contains_key(txn_hash) && self.descendants(txn_hash).is_empty()
+    }
+
+    // The fee, size and package totals of a pool transaction, `now` is in
+    // milliseconds since the unix epoch
+    pub fn entry(&self, txn_hash: &[u8; 32], now: u128) -> Option<MemPoolEntry> {
+        let entries = &self.priorities;
+        let entry = entries.get(txn_hash)?;
+
+        let ancestors = self.package(txn_hash, &HashSet::new());
+        let mut descendants = self.descendants(txn_hash);
+        descendants.push(*txn_hash);
+
+        let fees = |hashes: &[[u8; 32]]| -> u64 {
+            hashes
+                .iter()
+                .filter_map(|hash| entries.get(hash))
+                .map(|entry| entry.fee)
+                .sum()
+        };
+        let size = |hashes: &[[u8; 32]]| -> u64 {
+            hashes
+                .iter()
+                .filter_map(|hash| entries.get(hash))
+                .map(|entry| entry.size)
+                .sum()
+        };
+
+        Some(MemPoolEntry {
+            txn_hash: *txn_hash,
+            fee: entry.fee,
+            size: entry.size,
+            fee_rate: entry.fee_rate,
+            time_in_pool: now.saturating_sub(entry.timestamp),
+            ancestor_count: ancestors.len(),
+            ancestor_fees: fees(&ancestors),
+            ancestor_size: size(&ancestors),
+            descendant_count: descendants.len(),
+            descendant_fees: fees(&descendants),
+            descendant_size: size(&descendants),
+            replaceable: descendants.len() == 1,
+        })
+    }
+
+    // Whether a transaction with the given priority can replace all the
+    // conflicting pool transactions
+    fn replaceable_by(&self, conflicts: &HashSet<[u8; 32]>, entry: &PriorityEntry) -> bool {
+        let mut fees: u64 = 0;
+
+        for txn_hash in conflicts {
+            let Some(conflict) = self.priorities.get(txn_hash) else {
+                return false;
+            };
+            if !self.is_replaceable(txn_hash) || conflict.fee_rate >= entry.fee_rate {
+                return false;
+            }
+            fees = fees.saturating_add(conflict.fee);
+        }
+
+        entry.fee > fees
+    }
+
+    // Fails when the transaction would take its pool ancestors, or any of
+    // them its pool descendants, past `MAX_PACKAGE_COUNT` or
+    // `MAX_PACKAGE_BYTES`. The transactions it replaces are left out. The
+    // limits keep every package the pool works out small
+    fn check_package_limits(
+        &self,
+        txn: &Transaction,
+        replaced: &HashSet<[u8; 32]>,
+        size: usize,
+    ) -> Result<()> {
+        let mut ancestors = HashSet::new();
+        for parent in txn.spent_outpoints().map(|outpoint| outpoint.txid) {
+            if replaced.contains(&parent) {
+                continue;
+            }
+            let package = self.package(&parent, &ancestors);
+            ancestors.extend(package);
+        }
+
+        let bytes = |hashes: &mut dyn Iterator<Item = &[u8; 32]>| -> usize {
+            hashes
+                .filter_map(|hash| self.priorities.get(hash))
+                .map(|entry| entry.size as usize)
+                .sum()
+        };
+        let count = ancestors.len() + 1;
+        let total = bytes(&mut ancestors.iter()) + size;
+        if count > MAX_PACKAGE_COUNT || total > MAX_PACKAGE_BYTES {
+            return Err(Error::PackageLimit(format!(
+                "{count} transactions of {total} bytes with its ancestors"
+            )));
+        }
+
+        for ancestor in ancestors.iter() {
+            let descendants: Vec<[u8; 32]> = self
+                .descendants(ancestor)
+                .into_iter()
+                .filter(|hash| !replaced.contains(hash))
+                .collect();
+            let count = descendants.len() + 2;
+            let total = bytes(&mut descendants.iter().chain(iter::once(ancestor))) + size;
+            if count > MAX_PACKAGE_COUNT || total > MAX_PACKAGE_BYTES {
+                return Err(Error::PackageLimit(format!(
+                    "{count} transactions of {total} bytes with the descendants of {}",
+                    hex::encode(ancestor)
+                )));
+            }
+        }
 
         Ok(())
     }
 
+    // Pool transactions spending outputs of the transaction, directly or
+    // through other pool transactions
+    fn descendants(&self, txn_hash: &[u8; 32]) -> Vec<[u8; 32]> {
+        let mut descendants = Vec::new();
+        let mut visited = HashSet::from([*txn_hash]);
+        let mut pending = vec![*txn_hash];
+
+        while let Some(parent) = pending.pop() {
+            for child in self.children.get(&parent).into_iter().flatten() {
+                if visited.insert(*child) {
+                    descendants.push(*child);
+                    pending.push(*child);
+                }
+            }
+        }
+
+        descendants
+    }
+
     pub fn remove_transaction(&mut self, tx_hash: &[u8; 32]) -> Option<Transaction> {
-        self.priority_queue = self
+        if let Some(entry) = self.priorities.remove(tx_hash) {
+            self.priority_queue.remove(&entry);
+            self.bytes -= entry.size as usize;
+        }
+
+        let removed = self.transactions.remove(tx_hash)?;
+        for outpoint in removed.spent_outpoints() {
+            self.spent.remove(&outpoint);
+            if let Some(siblings) = self.children.get_mut(&outpoint.txid) {
+                siblings.remove(tx_hash);
+                if siblings.is_empty() {
+                    self.children.remove(&outpoint.txid);
+                }
+            }
+        }
+        self.children.remove(tx_hash);
+        Some(removed)
+    }
+
+    // Removes the transactions that entered the pool more than `ttl` before
+    // `now`, in milliseconds since the unix epoch, along with the pool
+    // transactions spending their outputs. Returns the hashes of the removed
+    // transactions
+    pub fn evict_expired(&mut self, now: u128) -> Vec<[u8; 32]> {
+        let expired: Vec<[u8; 32]> = self
             .priority_queue
-            .clone()
+            .iter()
+            .filter(|entry| entry.timestamp.saturating_add(self.ttl) <= now)
+            .map(|entry| entry.txn_hash)
+            .collect();
+
+        let mut evicted = Vec::new();
+        for expired in expired {
+            for txn_hash in self
+                .descendants(&expired)
+                .into_iter()
+                .chain(iter::once(expired))
+            {
+                if self.remove_transaction(&txn_hash).is_some() {
+                    evicted.push(txn_hash);
+                }
+            }
+        }
+
+        evicted
+    }
+
+    // Removes the transactions spending an input spent by one of the given
+    // block transactions, returns the hashes of the removed transactions
+    pub fn remove_conflicts(&mut self, block_txns: &[Transaction]) -> Vec<[u8; 32]> {
+        let mined: HashSet<[u8; 32]> = block_txns.iter().map(|txn| txn.hash_id).collect();
+
+        let conflicts: Vec<[u8; 32]> = block_txns
+            .iter()
+            .flat_map(Transaction::spent_outpoints)
+            .filter_map(|outpoint| self.spent.get(&outpoint).copied())
+            .filter(|txn_hash| !mined.contains(txn_hash))
+            .collect::<HashSet<_>>()
             .into_iter()
-            .filter(|entry| &entry.txn_hash != tx_hash)
-            .collect::<BinaryHeap<_>>();
-        self.transactions.remove(tx_hash)
+            .collect();
+
+        for txn_hash in conflicts.iter() {
+            self.remove_transaction(txn_hash);
+        }
+
+        conflicts
+    }
+
+    // Takes the transactions to include in a block out of the pool, their
+    // total serialized size is at most `max_block_size`.
+    //
+    // Transactions spending outputs of other pool transactions are selected
+    // together with those ancestors, which come first in the returned order.
+    // Transactions whose package doesn't fit are skipped in favor of smaller
+    // ones.
+    pub fn get_transactions_for_block(
+        &mut self,
+        max_block_size: usize,
+        strategy: SelectionStrategy,
+    ) -> Vec<Transaction> {
+        self.selection(max_block_size, strategy)
+            .iter()
+            .filter_map(|txn_hash| self.remove_transaction(txn_hash))
+            .collect()
+    }
+
+    // The transactions `get_transactions_for_block` would take, left in the
+    // pool for when the block they're selected for never makes it into the
+    // chain
+    pub fn select_for_block(
+        &self,
+        max_block_size: usize,
+        strategy: SelectionStrategy,
+    ) -> Vec<Transaction> {
+        self.selection(max_block_size, strategy)
+            .iter()
+            .filter_map(|txn_hash| self.transactions.get(txn_hash).cloned())
+            .collect()
     }
 
-    pub fn get_transactions_for_block(&mut self, max_block_size: usize) -> Vec<Transaction> {
-        let mut block_txns = vec![];
+    // Hashes of the selected transactions, ancestors first.
+    //
+    // Every candidate is ranked along with its ancestors not selected yet. The
+    // fee and size of those packages are worked out once and, after each pick,
+    // only the packages of the picked transactions' descendants shrink, so
+    // they're the only candidates ranked again
+    fn selection(&self, max_block_size: usize, strategy: SelectionStrategy) -> Vec<[u8; 32]> {
+        let entries: HashMap<[u8; 32], &PriorityEntry> = self
+            .priority_queue
+            .iter()
+            .filter(|entry| self.transactions.contains_key(&entry.txn_hash))
+            .map(|entry| (entry.txn_hash, entry))
+            .collect();
+
+        // Fee and size of each candidate together with its unselected ancestors
+        let mut packages: HashMap<[u8; 32], (u64, u64)> = entries
+            .keys()
+            .map(|txn_hash| {
+                let package = self.package(txn_hash, &HashSet::new());
+                let fee = package.iter().map(|hash| entries[hash].fee).sum();
+                let size = package.iter().map(|hash| entries[hash].size).sum();
+                (*txn_hash, (fee, size))
+            })
+            .collect();
+
+        let rank = |txn_hash: &[u8; 32], (fee, size): (u64, u64)| {
+            let entry = entries[txn_hash];
+            let rate = match strategy {
+                SelectionStrategy::FeeRate => entry.fee_rate,
+                SelectionStrategy::AncestorPackage => {
+                    FeeRate::new(fee, size).unwrap_or(entry.fee_rate)
+                }
+            };
+            Candidate {
+                rate,
+                timestamp: Reverse(entry.timestamp),
+                txn_hash: Reverse(*txn_hash),
+                package: (fee, size),
+            }
+        };
+
+        let mut candidates: BinaryHeap<Candidate> = packages
+            .iter()
+            .map(|(txn_hash, package)| rank(txn_hash, *package))
+            .collect();
+        let mut selected: Vec<[u8; 32]> = Vec::new();
+        let mut included: HashSet<[u8; 32]> = HashSet::new();
         let mut block_size = 0;
 
-        while let Some(entry) = self.priority_queue.peek() {
-            if block_size + entry.size < max_block_size as u64 {
-                if let Some(txn) = self.transactions.get(&entry.txn_hash) {
-                    block_txns.push(txn.clone());
-                    block_size += entry.size;
-                } else {
-                    self.priority_queue.pop();
+        while let Some(candidate) = candidates.pop() {
+            let txn_hash = candidate.txn_hash.0;
+            // Selected with another package, or ranked again since it was pushed
+            if included.contains(&txn_hash) || packages.get(&txn_hash) != Some(&candidate.package) {
+                continue;
+            }
+            // Comes back if its package shrinks
+            let (_, size) = candidate.package;
+            if block_size + size > max_block_size as u64 {
+                continue;
+            }
+
+            let package = self.package(&txn_hash, &included);
+            block_size += size;
+            included.extend(package.iter().copied());
+
+            let mut changed = HashSet::new();
+            for hash in package.iter() {
+                let (fee, size) = (entries[hash].fee, entries[hash].size);
+                let mut visited = HashSet::new();
+                let mut pending = vec![*hash];
+                while let Some(parent) = pending.pop() {
+                    for child in self.children.get(&parent).into_iter().flatten() {
+                        if !visited.insert(*child) {
+                            continue;
+                        }
+                        pending.push(*child);
+                        if included.contains(child) {
+                            continue;
+                        }
+                        if let Some(package) = packages.get_mut(child) {
+                            package.0 -= fee;
+                            package.1 -= size;
+                            changed.insert(*child);
+                        }
+                    }
                 }
-            } else {
-                break;
             }
+            for txn_hash in changed {
+                candidates.push(rank(&txn_hash, packages[&txn_hash]));
+            }
+
+            selected.extend(package);
         }
 
-        block_txns.iter().for_each(|t| {
-            self.remove_transaction(&t.hash_id);
-        });
+        selected
+    }
+
+    // The transaction and its pool ancestors not yet in `included`, ancestors
+    // first. A transaction is only added once the parents pushed after it
+    // were, the parents of every input in the order of the inputs
+    fn package(&self, txn_hash: &[u8; 32], included: &HashSet<[u8; 32]>) -> Vec<[u8; 32]> {
+        let mut package = Vec::new();
+        let mut visited = HashSet::new();
+        let mut pending = vec![(*txn_hash, false)];
+
+        while let Some((hash, parents_added)) = pending.pop() {
+            if parents_added {
+                package.push(hash);
+                continue;
+            }
+            if included.contains(&hash) || !visited.insert(hash) {
+                continue;
+            }
+            let Some(txn) = self.transactions.get(&hash) else {
+                continue;
+            };
+
+            pending.push((hash, true));
+            let parents: Vec<[u8; 32]> = txn
+                .spent_outpoints()
+                .map(|outpoint| outpoint.txid)
+                .filter(|parent| self.transactions.contains_key(parent))
+                .collect();
+            pending.extend(parents.into_iter().rev().map(|parent| (parent, false)));
+        }
 
-        block_txns
+        package
     }
 }
 
+// A transaction waiting to be selected for a block, ranked by fee rate, then
+// the oldest, then by txid. `package` is the fee and size it was ranked with
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Candidate {
+    rate: FeeRate,
+    timestamp: Reverse<u128>,
+    txn_hash: Reverse<[u8; 32]>,
+    package: (u64, u64),
+}
+
 #[cfg(test)]
 mod test {
 
@@ -170,40 +969,510 @@ mod test {
 
     use super::*;
 
+    // UTXO set holding the outputs the transactions' inputs declare
+    fn utxo_set(txns: &[&Transaction]) -> SharedMap<OutPoint, UTXO> {
+        txns.iter()
+            .flat_map(|txn| txn.inputs.iter())
+            .filter_map(|input| Some((input.outpoint()?, input.clone())))
+            .collect()
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn round_trips_through_json() {
+        let mut mempool = MemPool::new(5);
+        let (txn, _) = create_mock_transaction(1000, 990);
+        mempool
+            .add_transaction(txn.clone(), &utxo_set(&[&txn]), 1)
+            .unwrap();
+
+        let json = serde_json::to_value(&mempool).unwrap();
+        assert_eq!(
+            json["transactions"][0]["hash_id"],
+            serde_json::json!(hex::encode(txn.hash_id))
+        );
+
+        let decoded: MemPool = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.transactions, mempool.transactions);
+        assert_eq!(decoded.bytes, mempool.bytes);
+        assert_eq!(decoded.spent, mempool.spent);
+        assert_eq!(decoded.priority_queue, mempool.priority_queue);
+    }
+
     #[test]
     fn test_add_transaction() {
         let mut mempool = MemPool::new(5);
-        let (txn1, us1) = create_mock_transaction(1000, 999);
-        let (_, _, fee) = txn1.verify(&us1).unwrap();
-        assert!(mempool.add_transaction(txn1, fee).is_ok());
+        let (txn1, _) = create_mock_transaction(1000, 999);
+        let (txn2, _) = create_mock_transaction(1000, 996);
+        let utxos = utxo_set(&[&txn1, &txn2]);
+        assert!(mempool.add_transaction(txn1, &utxos, 1).is_ok());
 
         assert!(mempool.transactions.len() == 1);
 
-        let (txn2, us2) = create_mock_transaction(1000, 996);
-        let (_, _, fee) = txn2.verify(&us2).unwrap();
-        assert!(mempool.add_transaction(txn2.clone(), fee).is_ok());
+        assert!(mempool.add_transaction(txn2.clone(), &utxos, 1).is_ok());
         assert!(mempool.transactions.len() == 2);
 
-        let result = mempool.add_transaction(txn2, fee);
+        let result = mempool.add_transaction(txn2, &utxos, 1);
 
         match result {
             Ok(_) => panic!("Shouldn't work"),
             Err(Error::TxnExistInMempool) => println!("Passed"),
             Err(e) => panic!("shoundn't have given this error:{}", e),
         }
+
+        // Minted coins never enter the mempool
+        let minted = Transaction::coinbase([1u8; 32], 1, 0).unwrap();
+        assert!(matches!(
+            mempool.add_transaction(minted, &utxos, 1),
+            Err(Error::MintOutsideCoinbase)
+        ));
     }
 
     #[test]
     fn reject_low_fee() {
         let mut mempool = MemPool::new(1);
-        let (txn1, us1) = create_mock_transaction(1000000, 99000);
-        let (_, _, fee) = txn1.verify(&us1).unwrap();
-        mempool.add_transaction(txn1.clone(), fee).unwrap();
+        let (txn1, _) = create_mock_transaction(1000000, 99000);
+        let (txn2, _) = create_mock_transaction(1000, 996);
+        let utxos = utxo_set(&[&txn1, &txn2]);
+        mempool.add_transaction(txn1.clone(), &utxos, 1).unwrap();
 
-        let (txn2, us2) = create_mock_transaction(1000, 996);
-        let (_, _, fee) = txn2.verify(&us2).unwrap();
-        assert!(mempool.add_transaction(txn2.clone(), fee).is_err());
+        assert!(mempool.add_transaction(txn2.clone(), &utxos, 1).is_err());
 
         assert!(mempool.transactions.contains_key(&txn1.hash_id))
     }
+
+    #[test]
+    fn reports_evicted_and_conflicting_transactions() {
+        let mut mempool = MemPool::new(2);
+        let (low, _) = create_mock_transaction(1000, 999);
+        let (double_spend, _) = create_mock_transaction(100000, 90000);
+        let (high, _) = create_mock_transaction(1000000, 99000);
+        let utxos = utxo_set(&[&low, &double_spend, &high]);
+        assert!(mempool
+            .add_transaction(low.clone(), &utxos, 1)
+            .unwrap()
+            .is_empty());
+
+        mempool
+            .add_transaction(double_spend.clone(), &utxos, 1)
+            .unwrap();
+
+        assert_eq!(
+            mempool.add_transaction(high.clone(), &utxos, 1).unwrap(),
+            vec![(low.hash_id, RemovalReason::LowFee)]
+        );
+
+        // A block spending the same inputs as a pool transaction evicts it,
+        // while the transactions it includes are left to the caller
+        let mut block_txn = high.clone();
+        block_txn.hash_id = [9u8; 32];
+        block_txn.inputs = double_spend.inputs.clone();
+        assert_eq!(
+            mempool.remove_conflicts(&[block_txn, high.clone()]),
+            vec![double_spend.hash_id]
+        );
+        assert!(mempool.transactions.contains_key(&high.hash_id));
+    }
+
+    #[test]
+    fn evicts_by_byte_budget_and_age() {
+        let (low, _) = create_mock_transaction(1000, 999);
+        let (high, _) = create_mock_transaction(1000000, 99000);
+        let utxos = utxo_set(&[&low, &high]);
+
+        // Only one of the transactions fits in the byte budget
+        let max_bytes = low.serialized_size().max(high.serialized_size());
+        let mut mempool = MemPool::new(10).with_max_bytes(max_bytes);
+
+        mempool.add_transaction(low.clone(), &utxos, 1).unwrap();
+        assert_eq!(mempool.bytes(), low.serialized_size());
+        assert_eq!(
+            mempool.add_transaction(high.clone(), &utxos, 1).unwrap(),
+            vec![(low.hash_id, RemovalReason::LowFee)]
+        );
+        assert_eq!(mempool.bytes(), high.serialized_size());
+        assert!(matches!(
+            mempool.add_transaction(low, &utxos, 1),
+            Err(Error::TxnLowFee)
+        ));
+
+        let mut mempool = mempool.with_ttl(Duration::from_secs(60));
+        let added = mempool.priority_queue.last().unwrap().timestamp;
+
+        assert!(mempool.evict_expired(added + 59_999).is_empty());
+        assert_eq!(mempool.evict_expired(added + 60_000), vec![high.hash_id]);
+        assert!(mempool.transactions.is_empty());
+        assert_eq!(mempool.bytes(), 0);
+    }
+
+    #[test]
+    fn evicts_descendants_along_with_their_parents() {
+        let (parent, _) = create_mock_transaction(1000, 999);
+        let (mut child, _) = create_mock_transaction(1000, 990);
+        let (other, _) = create_mock_transaction(1000, 990);
+        let (high, _) = create_mock_transaction(1_000_000, 99_000);
+        child.inputs[0] = parent.outputs[0]
+            .clone()
+            .confirm_utxo(parent.hash_id, 1, false)
+            .unwrap();
+
+        // Making room for a transaction takes the child along with the parent
+        let mut mempool = MemPool::new(2);
+        mempool.insert(parent.clone(), 1).unwrap();
+        mempool.insert(child.clone(), 100_000).unwrap();
+        assert_eq!(
+            mempool
+                .add_transaction(high.clone(), &utxo_set(&[&high]), 1)
+                .unwrap(),
+            vec![
+                (child.hash_id, RemovalReason::LowFee),
+                (parent.hash_id, RemovalReason::LowFee)
+            ]
+        );
+        assert_eq!(mempool.transactions.len(), 1);
+        assert_eq!(mempool.bytes(), high.serialized_size());
+        assert_eq!(
+            mempool.spender(&child.spent_outpoints().next().unwrap()),
+            None
+        );
+
+        // Nor can a child make room by evicting its own parent
+        let mut mempool = MemPool::new(2);
+        mempool.insert(parent.clone(), 1).unwrap();
+        mempool.insert(other.clone(), 1_000).unwrap();
+        assert!(matches!(
+            mempool.insert(child.clone(), 100_000),
+            Err(Error::TxnLowFee)
+        ));
+        assert_eq!(mempool.transactions.len(), 2);
+
+        // The child of an expired transaction expires with it
+        let mut mempool = MemPool::new(10).with_ttl(Duration::from_secs(60));
+        mempool.insert(parent.clone(), 1).unwrap();
+        let added = mempool.priority_queue.last().unwrap().timestamp;
+        mempool.insert(child.clone(), 100_000).unwrap();
+        assert_eq!(
+            mempool.evict_expired(added + 60_000),
+            vec![child.hash_id, parent.hash_id]
+        );
+        assert!(mempool.transactions.is_empty());
+        assert_eq!(mempool.bytes(), 0);
+    }
+
+    #[test]
+    fn trims_lowest_fee_rates_to_the_memory_budget() {
+        let (low, _) = create_mock_transaction(1000, 999);
+        let (high, _) = create_mock_transaction(1000000, 99000);
+        let utxos = utxo_set(&[&low, &high]);
+
+        let mut mempool = MemPool::new(10);
+        mempool.add_transaction(low.clone(), &utxos, 1).unwrap();
+        let low_usage = mempool.memory_usage();
+        mempool.add_transaction(high.clone(), &utxos, 1).unwrap();
+        let usage = mempool.memory_usage();
+        assert!(usage > low_usage);
+
+        assert!(mempool.trim_to_memory(usage).is_empty());
+        assert_eq!(mempool.trim_to_memory(usage - 1), vec![low.hash_id]);
+        assert!(mempool.transactions.contains_key(&high.hash_id));
+        assert_eq!(mempool.memory_usage(), usage - low_usage);
+
+        assert_eq!(mempool.trim_to_memory(0), vec![high.hash_id]);
+        assert_eq!(mempool.memory_usage(), 0);
+    }
+
+    #[test]
+    fn evicts_equal_fee_rates_oldest_first_then_by_txid() {
+        let mut mempool = MemPool::new(10);
+        let mut txns = Vec::new();
+        for _ in 0..3 {
+            let (txn, _) = create_mock_transaction(1000, 999);
+            mempool
+                .add_transaction(txn.clone(), &utxo_set(&[&txn]), 1)
+                .unwrap();
+            txns.push(txn.hash_id);
+        }
+
+        // All pay the same per byte, the last one added is the oldest and the
+        // others entered together
+        let entries = std::mem::take(&mut mempool.priority_queue);
+        let fee_rate = entries.first().unwrap().fee_rate;
+        for mut entry in entries {
+            entry.fee_rate = fee_rate;
+            entry.timestamp = if entry.txn_hash == txns[2] { 50 } else { 100 };
+            mempool.priorities.insert(entry.txn_hash, entry.clone());
+            mempool.priority_queue.insert(entry);
+        }
+
+        let mut evicted = Vec::new();
+        while !mempool.transactions.is_empty() {
+            evicted.extend(mempool.trim_to_memory(mempool.memory_usage() - 1));
+        }
+        let (lower, higher) = (txns[0].min(txns[1]), txns[0].max(txns[1]));
+        assert_eq!(evicted, vec![txns[2], lower, higher]);
+    }
+
+    #[test]
+    fn rejects_double_spends_of_pool_outpoints() {
+        let mut mempool = MemPool::new(10);
+        let (first, _) = create_mock_transaction(1000, 990);
+        let utxos = utxo_set(&[&first]);
+        mempool.add_transaction(first.clone(), &utxos, 1).unwrap();
+
+        let outpoint = first.spent_outpoints().next().unwrap();
+        assert_eq!(mempool.spender(&outpoint), Some(first.hash_id));
+
+        let mut double_spend = first.clone();
+        double_spend.hash_id = [9u8; 32];
+        assert!(matches!(
+            mempool.add_transaction(double_spend.clone(), &utxos, 1),
+            Err(Error::DoubleSpend(spent)) if spent == outpoint
+        ));
+
+        // The outpoint can be spent again once the spender leaves the pool
+        mempool.remove_transaction(&first.hash_id);
+        assert_eq!(mempool.spender(&outpoint), None);
+        mempool.add_transaction(double_spend, &utxos, 1).unwrap();
+        assert_eq!(mempool.spender(&outpoint), Some([9u8; 32]));
+    }
+
+    #[test]
+    fn admits_spends_of_chain_and_pool_outputs_as_stored() {
+        let mut mempool = MemPool::new(10);
+        let (parent, _) = create_mock_transaction(1000, 999);
+        let (mut child, _) = create_mock_transaction(1000, 1);
+        let (mut forged, _) = create_mock_transaction(1000, 990);
+        let (unknown, _) = create_mock_transaction(1000, 990);
+        let utxos = utxo_set(&[&parent, &forged]);
+
+        // The child spends an output of the parent, which only the pool has
+        child.inputs = vec![parent.outputs[0]
+            .clone()
+            .confirm_utxo(parent.hash_id, 1, false)
+            .unwrap()];
+        assert!(matches!(
+            mempool.add_transaction(child.clone(), &utxos, 1),
+            Err(Error::UnknownUTXO)
+        ));
+        mempool.add_transaction(parent.clone(), &utxos, 1).unwrap();
+        mempool.add_transaction(child.clone(), &utxos, 1).unwrap();
+        let added = mempool.priority_queue.last().unwrap().timestamp;
+        assert_eq!(
+            mempool.entry(&child.hash_id, added).unwrap().fee,
+            child.declared_fee()
+        );
+
+        // Neither the chain nor the pool has the outputs spent
+        assert!(matches!(
+            mempool.add_transaction(unknown, &utxos, 1),
+            Err(Error::UnknownUTXO)
+        ));
+
+        // Declaring more than the output stored is worth doesn't raise the fee
+        let outpoint = forged.inputs[0].outpoint().unwrap();
+        forged.inputs[0] = UTXO::new(1_000_000, outpoint.vout, [9u8; 32])
+            .unwrap()
+            .confirm_utxo(outpoint.txid, 1, false)
+            .unwrap();
+        assert!(matches!(
+            mempool.add_transaction(forged, &utxos, 1),
+            Err(Error::InputMismatch(0))
+        ));
+    }
+
+    #[test]
+    fn fills_blocks_by_fee_rate_and_skips_what_does_not_fit() {
+        let mut mempool = MemPool::new(10);
+        let (low, _) = create_mock_transaction(1000, 999);
+        let (high, _) = create_mock_transaction(1_000_000, 99_000);
+        mempool.insert(low.clone(), 1).unwrap();
+        mempool.insert(high.clone(), 900_000).unwrap();
+
+        // Only the high fee transaction fits
+        let max_block_size = high.serialized_size();
+        let selected =
+            mempool.get_transactions_for_block(max_block_size, SelectionStrategy::FeeRate);
+        assert_eq!(selected, vec![high]);
+        assert!(mempool.transactions.contains_key(&low.hash_id));
+
+        assert!(mempool
+            .get_transactions_for_block(low.serialized_size() - 1, SelectionStrategy::FeeRate)
+            .is_empty());
+    }
+
+    #[test]
+    fn selects_parents_before_children_by_package_fee_rate() {
+        let mut mempool = MemPool::new(10);
+        let (parent, _) = create_mock_transaction(1000, 999);
+        let (mut child, _) = create_mock_transaction(1000, 990);
+        let (other, _) = create_mock_transaction(1000, 990);
+
+        // The child spends an output of the parent, which is still in the pool
+        child.inputs[0] = parent.outputs[0]
+            .clone()
+            .confirm_utxo(parent.hash_id, 1, false)
+            .unwrap();
+
+        mempool.insert(parent.clone(), 1).unwrap();
+        mempool.insert(child.clone(), 100_000).unwrap();
+        mempool.insert(other.clone(), 1_000).unwrap();
+
+        let max_block_size =
+            parent.serialized_size() + child.serialized_size() + other.serialized_size();
+        let selected =
+            mempool.get_transactions_for_block(max_block_size, SelectionStrategy::AncestorPackage);
+
+        assert_eq!(selected, vec![parent, child, other]);
+        assert!(mempool.transactions.is_empty());
+        assert_eq!(mempool.bytes(), 0);
+    }
+
+    #[test]
+    fn ranks_descendants_again_without_selected_ancestors() {
+        let mut mempool = MemPool::new(10);
+        let (parent, _) = create_mock_transaction(1000, 999);
+        let (mut child, _) = create_mock_transaction(1000, 990);
+        let (mut grandchild, _) = create_mock_transaction(1000, 990);
+        let (other, _) = create_mock_transaction(1000, 990);
+
+        child.inputs[0] = parent.outputs[0]
+            .clone()
+            .confirm_utxo(parent.hash_id, 1, false)
+            .unwrap();
+        grandchild.inputs[0] = child.outputs[0]
+            .clone()
+            .confirm_utxo(child.hash_id, 1, false)
+            .unwrap();
+
+        mempool.insert(parent.clone(), 1).unwrap();
+        mempool.insert(child.clone(), 10_000).unwrap();
+        mempool.insert(grandchild.clone(), 500).unwrap();
+        mempool.insert(other.clone(), 1_000).unwrap();
+
+        // The parent and child go first together. Ranked along with them the
+        // grandchild would come next, on its own it pays less than the other
+        let max_block_size = [&parent, &child, &grandchild, &other]
+            .iter()
+            .map(|txn| txn.serialized_size())
+            .sum();
+        let selected = mempool.select_for_block(max_block_size, SelectionStrategy::AncestorPackage);
+
+        assert_eq!(selected, vec![parent, child, other, grandchild]);
+    }
+
+    #[test]
+    fn limits_the_packages_of_pool_transactions() {
+        // Spends the parent's output `vout`
+        let spending = |parent: &Transaction, vout: u32| {
+            let (mut txn, _) = create_mock_transaction(1000, 990);
+            txn.inputs[0] = UTXO::new(1000, vout, [1u8; 32])
+                .unwrap()
+                .confirm_utxo(parent.hash_id, 1, false)
+                .unwrap();
+            txn
+        };
+
+        // A chain of transactions, each spending the one before
+        let mut mempool = MemPool::new(100);
+        let (root, _) = create_mock_transaction(1000, 990);
+        let mut chain = vec![root];
+        mempool.insert(chain[0].clone(), 10).unwrap();
+        while chain.len() < MAX_PACKAGE_COUNT {
+            let next = spending(chain.last().unwrap(), 0);
+            mempool.insert(next.clone(), 10).unwrap();
+            chain.push(next);
+        }
+        let tip = chain.last().unwrap();
+        assert_eq!(
+            mempool.entry(&tip.hash_id, 0).unwrap().ancestor_count,
+            MAX_PACKAGE_COUNT
+        );
+        assert!(matches!(
+            mempool.insert(spending(tip, 0), 10),
+            Err(Error::PackageLimit(_))
+        ));
+
+        // Nor can the root take another descendant
+        assert_eq!(
+            mempool
+                .entry(&chain[0].hash_id, 0)
+                .unwrap()
+                .descendant_count,
+            MAX_PACKAGE_COUNT
+        );
+        assert!(matches!(
+            mempool.insert(spending(&chain[0], 1), 10),
+            Err(Error::PackageLimit(_))
+        ));
+
+        // Once the root leaves the pool, its child can
+        mempool.remove_transaction(&chain[0].hash_id);
+        assert_eq!(
+            mempool.descendants(&chain[1].hash_id).len(),
+            MAX_PACKAGE_COUNT - 2
+        );
+        mempool.insert(spending(&chain[1], 1), 10).unwrap();
+        assert_eq!(
+            mempool
+                .entry(&chain[1].hash_id, 0)
+                .unwrap()
+                .descendant_count,
+            MAX_PACKAGE_COUNT
+        );
+    }
+
+    #[test]
+    fn reports_package_details_and_replaces_by_fee() {
+        let mut mempool = MemPool::new(10);
+        let (parent, _) = create_mock_transaction(1000, 999);
+        let (mut child, _) = create_mock_transaction(1000, 990);
+
+        child.inputs[0] = parent.outputs[0]
+            .clone()
+            .confirm_utxo(parent.hash_id, 1, false)
+            .unwrap();
+
+        mempool.insert(parent.clone(), 1).unwrap();
+        mempool.insert(child.clone(), 100_000).unwrap();
+
+        let added = mempool.priority_queue.last().unwrap().timestamp;
+        let entry = mempool.entry(&parent.hash_id, added + 5).unwrap();
+        assert_eq!(entry.fee, 1);
+        assert_eq!(entry.size, parent.serialized_size() as u64);
+        assert_eq!(entry.ancestor_count, 1);
+        assert_eq!(entry.descendant_count, 2);
+        assert_eq!(entry.descendant_fees, 100_001);
+        assert!(!entry.replaceable);
+
+        let entry = mempool.entry(&child.hash_id, added).unwrap();
+        assert_eq!(entry.ancestor_count, 2);
+        assert_eq!(entry.ancestor_fees, 100_001);
+        assert_eq!(
+            entry.ancestor_size,
+            (parent.serialized_size() + child.serialized_size()) as u64
+        );
+        assert_eq!(entry.descendant_count, 1);
+        assert!(entry.replaceable);
+
+        // A conflicting transaction has to pay more to replace the child
+        let mut replacement = child.clone();
+        replacement.hash_id = [9u8; 32];
+        assert!(matches!(
+            mempool.insert(replacement.clone(), 100_000),
+            Err(Error::DoubleSpend(_))
+        ));
+        assert_eq!(
+            mempool.insert(replacement.clone(), 200_000).unwrap(),
+            vec![(child.hash_id, RemovalReason::Replaced)]
+        );
+        assert!(!mempool.transactions.contains_key(&child.hash_id));
+
+        // The parent has a descendant, so it can't be replaced
+        let mut parent_replacement = parent.clone();
+        parent_replacement.hash_id = [8u8; 32];
+        assert!(matches!(
+            mempool.insert(parent_replacement, 1_000_000),
+            Err(Error::DoubleSpend(_))
+        ));
+    }
 }
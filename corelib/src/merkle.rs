@@ -1,6 +1,10 @@
 #![allow(unused)]
+use std::time::Instant;
+
 use borsh::{BorshDeserialize, BorshSerialize};
 
+use crate::metrics::METRICS;
+
 type Hash = [u8; 32];
 
 #[derive(Debug,PartialEq, Eq, Default, Clone, BorshDeserialize, BorshSerialize)]
@@ -32,6 +36,7 @@ impl Node {
         hasher.update(&left.hash);
         hasher.update(&right.hash);
         let hash = *hasher.finalize().as_bytes();
+        METRICS.record_hashed(64);
 
         Self {
             hash,
@@ -61,13 +66,16 @@ impl Tree {
     where
         T: AsRef<[Hash]>,
     {
+        let started = Instant::now();
         let mut nodes: Vec<Node> = hashes
             .as_ref()
             .iter()
             .map(|h| Node::with_hash(*h))
             .collect();
+        let leaves = nodes.len();
 
         self.root = Tree::build(nodes).map(|n| *n);
+        METRICS.record_merkle_build(leaves, started.elapsed());
     }
 
     pub fn build<T: AsRef<[Node]>>(nodes: T) -> Option<Box<Node>> {
@@ -103,23 +111,77 @@ impl Tree {
     // So for example if there are 5 transactions and we want to get a proof for the
     // 3rd transaction the leaf_number will be 3 despite the node holding that
     // leaf may not have index 3
-    pub fn generate_proof(&self, leaf_number: u32) -> Option<Vec<Hash>> {
-        todo!()
+    pub fn generate_proof(&self, leaf_number: u32) -> Option<Proof> {
+        let mut node = self.root.as_ref()?;
+        let mut leaves = Tree::leaf_count(node);
+        let mut leaf_number = leaf_number as usize;
+        if leaf_number >= leaves {
+            return None;
+        }
+
+        // Walks down the same way `build` split the leaves, remembering the
+        // subtree not taken at every level
+        let mut siblings = Vec::new();
+        while let (Some(left), Some(right)) = (node.left.as_deref(), node.right.as_deref()) {
+            let half = leaves / 2;
+
+            if leaf_number < half {
+                siblings.push(Sibling {
+                    hash: right.hash,
+                    left: false,
+                });
+                node = left;
+                leaves = half;
+            } else {
+                siblings.push(Sibling {
+                    hash: left.hash,
+                    left: true,
+                });
+                node = right;
+                leaf_number -= half;
+                leaves -= half;
+            }
+        }
+        siblings.reverse();
+
+        Some(Proof { siblings })
+    }
+
+    pub fn verify_proof(leaf_hash: Hash, proof: &Proof, root_hash: Hash) -> bool {
+        let computed = proof.siblings.iter().fold(leaf_hash, |hash, sibling| {
+            let mut hasher = blake3::Hasher::new();
+            if sibling.left {
+                hasher.update(&sibling.hash);
+                hasher.update(&hash);
+            } else {
+                hasher.update(&hash);
+                hasher.update(&sibling.hash);
+            }
+            *hasher.finalize().as_bytes()
+        });
+
+        computed == root_hash
     }
 
-    pub fn verify_proof(leaf_hash: Hash, proof: &[Hash], root_hash: Hash) -> bool {
-        todo!()
+    fn leaf_count(node: &Node) -> usize {
+        match (node.left.as_deref(), node.right.as_deref()) {
+            (Some(left), Some(right)) => Tree::leaf_count(left) + Tree::leaf_count(right),
+            _ => 1,
+        }
     }
 }
 
+// Hashes needed to recompute the root from a leaf, ordered from the leaf up
+#[derive(Debug, Clone, Default, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
 pub struct Proof {
-    hash: Hash,
-    proofs: Vec<IndexedHash>,
+    pub siblings: Vec<Sibling>,
 }
 
-pub struct IndexedHash {
-    index: u32,
-    hash: Hash,
+#[derive(Debug, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
+pub struct Sibling {
+    pub hash: Hash,
+    // Whether the sibling is hashed in on the left
+    pub left: bool,
 }
 
 #[cfg(test)]
@@ -135,17 +197,28 @@ mod test {
         tree.build_tree(hashes.clone());
         let root_hash = tree.root_hash();
 
-        // TODO: test proofs
-        // for (index, hash) in hashes.iter().enumerate() {
-        //     let proof = tree.generate_proof(index as u32);
-        //     assert!(proof.is_some(), "Proof for leaf {} should exist", index);
-        //
-        //     let proof = proof.unwrap();
-        //     assert!(
-        //         Tree::verify_proof(*hash, &proof, root_hash.unwrap()),
-        //         "Proof verification for leaf {} should pass",
-        //         index
-        //     );
-        // }
+        for (index, hash) in hashes.iter().enumerate() {
+            let proof = tree.generate_proof(index as u32);
+            assert!(proof.is_some(), "Proof for leaf {} should exist", index);
+
+            let proof = proof.unwrap();
+            assert!(
+                Tree::verify_proof(*hash, &proof, root_hash.unwrap()),
+                "Proof verification for leaf {} should pass",
+                index
+            );
+        }
+
+        // Uneven trees and leaves that aren't in the tree
+        let hashes: Vec<[u8; 32]> = (1..=5u8).map(|i| [i; 32]).collect();
+        let tree = Tree::with_hashes(&hashes);
+        let root_hash = tree.root_hash().unwrap();
+
+        for (index, hash) in hashes.iter().enumerate() {
+            let proof = tree.generate_proof(index as u32).unwrap();
+            assert!(Tree::verify_proof(*hash, &proof, root_hash));
+            assert!(!Tree::verify_proof([9u8; 32], &proof, root_hash));
+        }
+        assert!(tree.generate_proof(5).is_none());
     }
 }
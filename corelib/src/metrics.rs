@@ -0,0 +1,160 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// Merkle builds are bucketed by leaf count, bucket `i` holds builds of up to
+// 2^i leaves and the last one everything larger
+const LEAF_BUCKETS: usize = 17;
+
+// Process wide counters of the hashing work done by corelib
+pub static METRICS: HashingMetrics = HashingMetrics::new();
+
+#[derive(Debug)]
+pub struct HashingMetrics {
+    merkle_builds: AtomicU64,
+    merkle_leaves: AtomicU64,
+    merkle_build_nanos: AtomicU64,
+    bucket_builds: [AtomicU64; LEAF_BUCKETS],
+    bucket_nanos: [AtomicU64; LEAF_BUCKETS],
+    bytes_hashed: AtomicU64,
+}
+
+impl HashingMetrics {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const fn new() -> Self {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            merkle_builds: ZERO,
+            merkle_leaves: ZERO,
+            merkle_build_nanos: ZERO,
+            bucket_builds: [ZERO; LEAF_BUCKETS],
+            bucket_nanos: [ZERO; LEAF_BUCKETS],
+            bytes_hashed: ZERO,
+        }
+    }
+
+    pub fn record_merkle_build(&self, leaves: usize, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        let bucket = leaf_bucket(leaves);
+
+        self.merkle_builds.fetch_add(1, Ordering::Relaxed);
+        self.merkle_leaves
+            .fetch_add(leaves as u64, Ordering::Relaxed);
+        self.merkle_build_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.bucket_builds[bucket].fetch_add(1, Ordering::Relaxed);
+        self.bucket_nanos[bucket].fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub fn record_hashed(&self, bytes: usize) {
+        self.bytes_hashed.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let build_times = (0..LEAF_BUCKETS)
+            .map(|bucket| LeafBucket {
+                max_leaves: (bucket + 1 < LEAF_BUCKETS).then(|| 1 << bucket),
+                builds: self.bucket_builds[bucket].load(Ordering::Relaxed),
+                total: Duration::from_nanos(self.bucket_nanos[bucket].load(Ordering::Relaxed)),
+            })
+            .collect();
+
+        MetricsSnapshot {
+            merkle_builds: self.merkle_builds.load(Ordering::Relaxed),
+            merkle_leaves: self.merkle_leaves.load(Ordering::Relaxed),
+            merkle_build_time: Duration::from_nanos(
+                self.merkle_build_nanos.load(Ordering::Relaxed),
+            ),
+            build_times,
+            bytes_hashed: self.bytes_hashed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn leaf_bucket(leaves: usize) -> usize {
+    let bucket = leaves.max(1).next_power_of_two().trailing_zeros() as usize;
+    bucket.min(LEAF_BUCKETS - 1)
+}
+
+// Merkle builds of trees with up to `max_leaves` leaves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeafBucket {
+    // `None` for the bucket of the largest trees
+    pub max_leaves: Option<usize>,
+    pub builds: u64,
+    pub total: Duration,
+}
+
+impl LeafBucket {
+    pub fn average(&self) -> Option<Duration> {
+        (self.builds > 0).then(|| self.total / self.builds as u32)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub merkle_builds: u64,
+    pub merkle_leaves: u64,
+    pub merkle_build_time: Duration,
+    pub build_times: Vec<LeafBucket>,
+    // Bytes fed to blake3 while hashing blocks, transactions and merkle nodes
+    pub bytes_hashed: u64,
+}
+
+impl MetricsSnapshot {
+    // Merkle builds per second between an earlier snapshot and this one
+    pub fn merkle_builds_per_sec(&self, earlier: &MetricsSnapshot, elapsed: Duration) -> f64 {
+        per_sec(self.merkle_builds - earlier.merkle_builds, elapsed)
+    }
+
+    // Hashing throughput between an earlier snapshot and this one
+    pub fn bytes_hashed_per_sec(&self, earlier: &MetricsSnapshot, elapsed: Duration) -> f64 {
+        per_sec(self.bytes_hashed - earlier.bytes_hashed, elapsed)
+    }
+}
+
+fn per_sec(count: u64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        return 0.0;
+    }
+    count as f64 / elapsed.as_secs_f64()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets_builds_by_leaf_count() {
+        let metrics = HashingMetrics::new();
+        metrics.record_merkle_build(1, Duration::from_micros(1));
+        metrics.record_merkle_build(3, Duration::from_micros(2));
+        metrics.record_merkle_build(4, Duration::from_micros(4));
+        metrics.record_merkle_build(1 << 20, Duration::from_micros(8));
+        metrics.record_hashed(64);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.merkle_builds, 4);
+        assert_eq!(snapshot.merkle_leaves, 8 + (1 << 20));
+        assert_eq!(snapshot.bytes_hashed, 64);
+
+        assert_eq!(snapshot.build_times[0].builds, 1);
+        assert_eq!(snapshot.build_times[2].max_leaves, Some(4));
+        assert_eq!(
+            snapshot.build_times[2].average(),
+            Some(Duration::from_micros(3))
+        );
+        assert_eq!(snapshot.build_times[LEAF_BUCKETS - 1].builds, 1);
+        assert_eq!(snapshot.build_times[LEAF_BUCKETS - 1].max_leaves, None);
+
+        let later = MetricsSnapshot {
+            merkle_builds: 14,
+            ..snapshot.clone()
+        };
+        assert_eq!(
+            later.merkle_builds_per_sec(&snapshot, Duration::from_secs(2)),
+            5.0
+        );
+    }
+}
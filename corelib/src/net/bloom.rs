@@ -0,0 +1,133 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use crate::{transaction::Transaction, utxo::UTXO};
+
+// Largest filter a peer may register, in bytes
+pub const MAX_FILTER_SIZE: usize = 36_000;
+// Hash functions a filter may use at most
+pub const MAX_FILTER_HASHES: u32 = 50;
+
+// Probabilistic set of the keys, transaction ids and outpoints a light
+// client is interested in. It may match items the client never inserted,
+// which hides from the full node which ones are really the client's, but
+// never misses one that was inserted
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+    // Picked by the client so filters of the same items differ
+    tweak: u32,
+}
+
+impl BloomFilter {
+    // Filter sized for the number of items at the false positive rate, both
+    // capped to what peers accept
+    pub fn new(items: usize, false_positive_rate: f64, tweak: u32) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let items = items.max(1) as f64;
+        let rate = false_positive_rate.clamp(1e-9, 1.0);
+
+        let bits = (-items * rate.ln() / (ln2 * ln2)).ceil().max(8.0);
+        let size = ((bits / 8.0).ceil() as usize).min(MAX_FILTER_SIZE);
+        let hashes = ((size * 8) as f64 / items * ln2).round() as u32;
+
+        Self {
+            bits: vec![0u8; size],
+            hashes: hashes.clamp(1, MAX_FILTER_HASHES),
+            tweak,
+        }
+    }
+
+    // Whether a filter received from a peer is within the limits, larger ones
+    // would make matching transactions expensive
+    pub fn is_valid(&self) -> bool {
+        !self.bits.is_empty()
+            && self.bits.len() <= MAX_FILTER_SIZE
+            && (1..=MAX_FILTER_HASHES).contains(&self.hashes)
+    }
+
+    pub fn insert(&mut self, data: &[u8]) {
+        for bit in self.bit_indexes(data) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    pub fn contains(&self, data: &[u8]) -> bool {
+        self.bit_indexes(data)
+            .into_iter()
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    // Whether the transaction concerns the client: its id, the keys sending
+    // and receiving it, the owners of its outputs or the outputs it spends
+    // are in the filter
+    pub fn matches(&self, txn: &Transaction) -> bool {
+        if [txn.hash_id, txn.sender, txn.receiver]
+            .iter()
+            .any(|item| self.contains(item))
+        {
+            return true;
+        }
+
+        txn.outputs
+            .iter()
+            .chain(txn.inputs.iter())
+            .any(|utxo| match utxo {
+                UTXO::Pending { owner, .. } => self.contains(owner),
+                UTXO::PendingScriptHash { script_hash, .. } => self.contains(script_hash),
+                UTXO::Confirmed { txn_hash, .. } => self.contains(txn_hash),
+            })
+    }
+
+    // Bits of the item, 8 bytes of the extended hash of it and the tweak
+    // for every hash function
+    fn bit_indexes(&self, data: &[u8]) -> Vec<usize> {
+        let mut output = vec![0u8; self.hashes as usize * 8];
+        blake3::Hasher::new()
+            .update(&self.tweak.to_le_bytes())
+            .update(data)
+            .finalize_xof()
+            .fill(&mut output);
+        let bits = (self.bits.len() * 8) as u64;
+
+        output
+            .chunks_exact(8)
+            .map(|chunk| (u64::from_le_bytes(chunk.try_into().expect("8 bytes")) % bits) as usize)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::create_mock_transaction;
+
+    use super::*;
+
+    #[test]
+    fn matches_inserted_items() {
+        let mut filter = BloomFilter::new(10, 0.0001, 7);
+        assert!(filter.is_valid());
+        filter.insert(&[1u8; 32]);
+
+        assert!(filter.contains(&[1u8; 32]));
+        assert!(!filter.contains(&[2u8; 32]));
+        // The tweak changes which bits an item sets
+        let mut other = BloomFilter::new(10, 0.0001, 8);
+        other.insert(&[1u8; 32]);
+        assert_ne!(filter, other);
+
+        let (mut txn, _) = create_mock_transaction(1, 1);
+        assert!(!filter.matches(&txn));
+        txn.outputs.push(UTXO::new(10, 1, [1u8; 32]).unwrap());
+        assert!(filter.matches(&txn));
+
+        // Filters past the limits are refused
+        let oversized = BloomFilter {
+            bits: vec![0u8; MAX_FILTER_SIZE + 1],
+            hashes: 1,
+            tweak: 0,
+        };
+        assert!(!oversized.is_valid());
+        assert!(BloomFilter::new(1_000_000, 0.0, 0).is_valid());
+    }
+}
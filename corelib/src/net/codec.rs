@@ -0,0 +1,239 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    config::Network,
+    errors::{Error, ProtocolError, Result},
+};
+
+pub use super::protocol::MAX_PAYLOAD_SIZE;
+use super::protocol::{header_size, Header, Request, Response, MAGIC_LEN};
+
+// Reads a single request or response frame.
+//
+// The header tells how many payload bytes follow it, those are read exactly so
+// a frame split over several TCP segments is reassembled. Bytes before the
+// next magic sequence are skipped, so after a malformed frame the following
+// read picks up at the next frame. Frames of every network are read, it's up
+// to the caller to drop the ones of other networks. Returns `None` if the
+// stream was closed before a new frame started.
+pub async fn read_frame<R>(reader: &mut R, max_payload_size: usize) -> Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let Some(magic) = find_magic(reader).await? else {
+        return Ok(None);
+    };
+
+    // The version following the magic tells the size of the rest of the
+    // header, which is followed by the command/status byte
+    let mut frame = vec![0u8; MAGIC_LEN + 2];
+    frame[..MAGIC_LEN].copy_from_slice(&magic);
+    reader.read_exact(&mut frame[MAGIC_LEN..]).await?;
+
+    let version = u16::from_be_bytes([frame[MAGIC_LEN], frame[MAGIC_LEN + 1]]);
+    let prefix_size = header_size(version)? + 1;
+    frame.resize(prefix_size, 0);
+    reader.read_exact(&mut frame[MAGIC_LEN + 2..]).await?;
+
+    let header = Header::from_bytes(&frame)?;
+    let content_size = header.content_size() as usize;
+
+    if content_size > max_payload_size {
+        return Err(Error::Protocol(ProtocolError::PayloadTooLarge(
+            content_size,
+        )));
+    }
+
+    frame.resize(prefix_size + content_size, 0);
+    reader.read_exact(&mut frame[prefix_size..]).await?;
+
+    Ok(Some(frame))
+}
+
+// Consumes the stream up to and including the next magic sequence of any
+// network and returns it, `None` if the stream was closed first
+async fn find_magic<R>(reader: &mut R) -> Result<Option<[u8; MAGIC_LEN]>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut window = [0u8; MAGIC_LEN];
+
+    if reader.read(&mut window[..1]).await? == 0 {
+        return Ok(None);
+    }
+    // In a healthy stream the magic comes right away, so it's read in one go
+    // and only scanned for byte by byte after garbage
+    if reader.read_exact(&mut window[1..]).await.is_err() {
+        return Ok(None);
+    }
+
+    while Network::from_magic(&window).is_none() {
+        let mut byte = [0u8; 1];
+        if reader.read(&mut byte).await? == 0 {
+            return Ok(None);
+        }
+
+        window.rotate_left(1);
+        window[MAGIC_LEN - 1] = byte[0];
+    }
+
+    Ok(Some(window))
+}
+
+pub async fn read_request<R>(reader: &mut R, max_payload_size: usize) -> Result<Option<Request>>
+where
+    R: AsyncRead + Unpin,
+{
+    read_frame(reader, max_payload_size)
+        .await?
+        .map(|frame| Request::from_bytes(&frame))
+        .transpose()
+}
+
+pub async fn read_response<R>(reader: &mut R, max_payload_size: usize) -> Result<Option<Response>>
+where
+    R: AsyncRead + Unpin,
+{
+    read_frame(reader, max_payload_size)
+        .await?
+        .map(|frame| Response::from_bytes(&frame))
+        .transpose()
+}
+
+pub async fn write_request<W>(writer: &mut W, request: &Request) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(&request.to_bytes()?).await?;
+    Ok(())
+}
+
+pub async fn write_response<W>(writer: &mut W, response: &Response) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(&response.to_bytes()?).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::duplex;
+
+    use crate::net::{
+        message::Message,
+        protocol::{Command, StatusCode, HEADER_SIZE},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reassembles_fragmented_frames() {
+        let (mut client, mut server) = duplex(1024);
+        let request = Request::new(
+            Command::Post,
+            Some(Message::PeerIntroduction("127.0.0.1:7878".to_string())),
+        )
+        .unwrap();
+        let bytes = request.to_bytes().unwrap();
+
+        let writer = tokio::spawn(async move {
+            for chunk in bytes.chunks(3) {
+                client.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            let response = Response::new(StatusCode::OK, None).unwrap();
+            write_response(&mut client, &response).await.unwrap();
+        });
+
+        let received = read_request(&mut server, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.payload(), request.payload());
+
+        let response = read_response(&mut server, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status(), &StatusCode::OK);
+
+        writer.await.unwrap();
+        assert!(read_request(&mut server, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn resynchronizes_after_corrupted_frame() {
+        let (mut client, mut server) = duplex(1024);
+        let request = Request::new(
+            Command::Post,
+            Some(Message::PeerIntroduction("127.0.0.1:7878".to_string())),
+        )
+        .unwrap();
+
+        let mut corrupted = request.to_bytes().unwrap();
+        // Unknown command, then line noise before the next frame
+        corrupted[HEADER_SIZE] = 0xff;
+        client.write_all(&corrupted).await.unwrap();
+        client.write_all(b"noise").await.unwrap();
+        write_request(&mut client, &request).await.unwrap();
+        drop(client);
+
+        assert!(read_request(&mut server, MAX_PAYLOAD_SIZE).await.is_err());
+
+        let received = read_request(&mut server, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.payload(), request.payload());
+
+        assert!(read_request(&mut server, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_payloads() {
+        let (mut client, mut server) = duplex(1024);
+        let request = Request::new(
+            Command::Post,
+            Some(Message::InvalidTransactionAlert("x".repeat(100))),
+        )
+        .unwrap();
+        write_request(&mut client, &request).await.unwrap();
+
+        assert!(matches!(
+            read_request(&mut server, 64).await,
+            Err(Error::Protocol(ProtocolError::PayloadTooLarge(_)))
+        ));
+        // Payloads past what a u16 could describe go through, ones past the
+        // cap can't even be built
+        let (mut client, mut server) = duplex(1 << 20);
+        let large = Request::new(
+            Command::Post,
+            Some(Message::InvalidTransactionAlert("x".repeat(100_000))),
+        )
+        .unwrap();
+        let writer = tokio::spawn(async move { write_request(&mut client, &large).await });
+        let received = read_request(&mut server, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received.header().content_size() > u16::MAX as u32);
+        writer.await.unwrap().unwrap();
+
+        assert!(matches!(
+            Request::new(
+                Command::Post,
+                Some(Message::InvalidTransactionAlert(
+                    "x".repeat(MAX_PAYLOAD_SIZE)
+                )),
+            ),
+            Err(Error::Protocol(ProtocolError::PayloadTooLarge(_)))
+        ));
+    }
+}
@@ -0,0 +1,109 @@
+use std::fmt;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+// Optional protocol extensions a node supports, one bit each.
+//
+// Peers exchange their features when they connect and only use the
+// extensions both of them support.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize)]
+pub struct Features(u64);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    // Blocks relayed as short transaction ids the receiver fills from its
+    // mempool
+    pub const COMPACT_BLOCKS: Features = Features(1 << 0);
+    pub const COMPRESSION: Features = Features(1 << 1);
+    // Transactions and blocks relayed filtered by a bloom filter the peer
+    // sets, for light clients
+    pub const BLOOM_FILTERS: Features = Features(1 << 2);
+    // Chain synced by downloading the headers before the blocks
+    pub const HEADERS_FIRST: Features = Features(1 << 3);
+    // Blocks requested by hash, which is unambiguous across forks
+    pub const BLOCKS_BY_HASH: Features = Features(1 << 4);
+    // Blocks downloaded in chunks, for blocks too large for one frame
+    pub const BLOCK_CHUNKS: Features = Features(1 << 5);
+    // Mempool transactions announced on request, for peers that just started
+    pub const MEMPOOL: Features = Features(1 << 6);
+    // Unspent outputs and balances of a public key served on request, for
+    // light wallets without the chain
+    pub const UTXO_QUERIES: Features = Features(1 << 7);
+
+    // Extensions this node implements
+    pub const SUPPORTED: Features = Features(
+        Features::BLOOM_FILTERS.0
+            | Features::HEADERS_FIRST.0
+            | Features::BLOCKS_BY_HASH.0
+            | Features::BLOCK_CHUNKS.0
+            | Features::MEMPOOL.0
+            | Features::UTXO_QUERIES.0,
+    );
+
+    const NAMES: [(Features, &'static str); 8] = [
+        (Features::COMPACT_BLOCKS, "compact_blocks"),
+        (Features::COMPRESSION, "compression"),
+        (Features::BLOOM_FILTERS, "bloom_filters"),
+        (Features::HEADERS_FIRST, "headers_first"),
+        (Features::BLOCKS_BY_HASH, "blocks_by_hash"),
+        (Features::BLOCK_CHUNKS, "block_chunks"),
+        (Features::MEMPOOL, "mempool"),
+        (Features::UTXO_QUERIES, "utxo_queries"),
+    ];
+
+    pub fn from_bits(bits: u64) -> Self {
+        Features(bits)
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(&self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(&self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+
+    // Features both sides support, the only ones a connection may use
+    pub fn negotiate(&self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+
+    // Names of the known features that are set, unknown bits are skipped
+    pub fn names(&self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(feature, _)| self.contains(*feature))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.names().join(","))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn negotiates_common_features() {
+        let ours = Features::HEADERS_FIRST.union(Features::COMPACT_BLOCKS);
+        // Bits of extensions this node doesn't know about are carried along
+        let theirs = Features::from_bits(Features::HEADERS_FIRST.bits() | 1 << 40)
+            .union(Features::BLOOM_FILTERS);
+
+        let negotiated = ours.negotiate(theirs);
+        assert_eq!(negotiated, Features::HEADERS_FIRST);
+        assert!(!negotiated.contains(Features::COMPACT_BLOCKS));
+        assert_eq!(theirs.names(), vec!["bloom_filters", "headers_first"]);
+        assert_eq!(ours.to_string(), "compact_blocks,headers_first");
+        assert_eq!(Features::NONE.negotiate(theirs), Features::NONE);
+    }
+}
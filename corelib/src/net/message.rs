@@ -2,7 +2,40 @@ use std::io::Write;
 
 use borsh::{BorshDeserialize, BorshSerialize};
 
-use crate::{block::Block, errors::Result, transaction::Transaction};
+use crate::{
+    block::{Block, BlockHeader},
+    errors::{Error, ProtocolError, Result},
+    merkle::{Proof, Tree},
+    transaction::Transaction,
+    utxo::UTXO,
+};
+
+use super::{
+    bloom::BloomFilter,
+    protocol::{Handshake, MAX_PAYLOAD_SIZE},
+};
+
+// Items announced in an `Inv` at most, larger announcements are cut off
+pub const MAX_INVENTORY: usize = 1_000;
+
+// Bytes of a block sent in one `BlockChunk`, small enough for a version 1
+// frame to carry
+pub const BLOCK_CHUNK_SIZE: usize = 60 * 1024;
+
+// Transaction or block announced by its hash
+#[derive(Debug, Clone, Copy, BorshSerialize, BorshDeserialize, PartialEq, Eq, Hash)]
+pub enum Inventory {
+    Transaction([u8; 32]),
+    Block([u8; 32]),
+}
+
+impl Inventory {
+    pub fn hash(&self) -> [u8; 32] {
+        match self {
+            Inventory::Transaction(hash) | Inventory::Block(hash) => *hash,
+        }
+    }
+}
 
 #[allow(unused)]
 #[derive(Debug, Clone, BorshSerialize, BorshDeserialize, PartialEq, Eq)]
@@ -21,6 +54,209 @@ pub enum Message {
     InvalidTransactionAlert(String),
 
     Ping,
+
+    // Addresses of the peers known to the sender
+    PeerList(Vec<String>),
+
+    // Best chain headers starting at the given height
+    GetHeaders(u64),
+    Headers(Vec<BlockHeader>),
+
+    // Best chain blocks with heights in `start..end`
+    GetBlocks(u64, u64),
+    Blocks(Vec<Block>),
+
+    // Header of a block extending a known block that passes the header
+    // checks, sent ahead of the full block while it's still being validated
+    HeaderAnnouncement(BlockHeader),
+
+    // Versions, height and extensions of the connecting node, answered with
+    // the other side's in a `VerAck`
+    Version(Handshake),
+    VerAck(Handshake),
+
+    // Block with the hash on any known branch, answered with a
+    // `BlockResponse`
+    BlockRequestByHash([u8; 32]),
+    // Blocks with the hashes on any known branch in the requested order,
+    // answered with `Blocks`. Unknown hashes are left out
+    GetBlocksByHash(Vec<[u8; 32]>),
+
+    // Transactions and blocks the sender has, answered with a `GetData` of
+    // the ones the receiver is missing
+    Inv(Vec<Inventory>),
+    // Announced items the sender wants, sent as `PaymentTransaction` and
+    // `BlockProposal` requests. Sent as a request it's answered with the
+    // pool `Transactions` among them
+    GetData(Vec<Inventory>),
+
+    // Part of the encoding of the block with the hash starting at the
+    // offset, answered with a `BlockChunk`
+    GetBlockChunk([u8; 32], u32),
+    BlockChunk(BlockChunk),
+
+    // Transactions in the receiver's mempool, answered with an `Inv` of the
+    // best ones, parents ahead of the transactions spending them
+    GetMempool,
+    Transactions(Vec<Transaction>),
+
+    // Lowest protocol version the sender accepts and why, sent to a peer
+    // whose versions are all older before disconnecting it
+    Outdated(u16, String),
+
+    // Confirmed outputs the public key can spend, answered with `Utxos`
+    GetUtxos([u8; 32]),
+    Utxos(Vec<UTXO>),
+    // Confirmed balance of the public key, answered with `Balance`
+    GetBalance([u8; 32]),
+    Balance(u64),
+
+    // Bloom filter of the items a light client is interested in. Once set
+    // only the transactions matching it are announced to the client and
+    // blocks are sent to it as a `FilteredBlock`
+    SetFilter(BloomFilter),
+    ClearFilter,
+    // Block with the hash reduced to the transactions matching the filter,
+    // answered with a `FilteredBlock`
+    GetFilteredBlock([u8; 32]),
+    FilteredBlock(FilteredBlock),
+
+    // Connecting side's signature over the nonce of the `VerAck` it was
+    // answered with, see `Handshake::prove`. The handshake is authenticated
+    // in both directions once the receiver checked it
+    Authenticate([u8; 64]),
+}
+
+// Header of a block along with the transactions matching a bloom filter,
+// each with the proof that it's in the block
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize, PartialEq, Eq)]
+pub struct FilteredBlock {
+    pub header: BlockHeader,
+    pub transactions: Vec<(Transaction, Proof)>,
+}
+
+impl FilteredBlock {
+    // All of the block's transactions without a filter
+    pub fn of(block: &Block, filter: Option<&BloomFilter>) -> Self {
+        let txn_hashes: Vec<[u8; 32]> = block.transactions().iter().map(|t| t.hash_id).collect();
+        let tree = Tree::with_hashes(&txn_hashes);
+
+        let transactions = block
+            .transactions()
+            .iter()
+            .enumerate()
+            .filter(|(_, txn)| filter.is_none_or(|filter| filter.matches(txn)))
+            .filter_map(|(leaf, txn)| Some((txn.clone(), tree.generate_proof(leaf as u32)?)))
+            .collect();
+
+        Self {
+            header: block.header().clone(),
+            transactions,
+        }
+    }
+
+    // Whether the header hashes to its hash and every transaction is proven
+    // to be under its merkle root
+    pub fn verify(&self) -> bool {
+        self.header.calculate_hash() == self.header.hash
+            && self
+                .transactions
+                .iter()
+                .all(|(txn, proof)| Tree::verify_proof(txn.hash_id, proof, self.header.merkle_root))
+    }
+}
+
+// Part of a block's canonical encoding, see `Block::to_bytes`
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize, PartialEq, Eq)]
+pub struct BlockChunk {
+    pub hash: [u8; 32],
+    // Size of the whole encoding
+    pub total: u32,
+    pub offset: u32,
+    pub data: Vec<u8>,
+}
+
+impl BlockChunk {
+    // Chunk of the block starting at the offset, `None` past its end
+    pub fn of(block: &Block, offset: u32) -> Option<Self> {
+        let bytes = block.to_bytes();
+        let start = offset as usize;
+        if start >= bytes.len() {
+            return None;
+        }
+        let end = bytes.len().min(start + BLOCK_CHUNK_SIZE);
+
+        Some(Self {
+            hash: block.hash(),
+            total: bytes.len() as u32,
+            offset,
+            data: bytes[start..end].to_vec(),
+        })
+    }
+}
+
+// Block being downloaded chunk by chunk, in order
+#[derive(Debug, Clone, Default)]
+pub struct ChunkedBlock {
+    bytes: Vec<u8>,
+}
+
+impl ChunkedBlock {
+    // Offset of the next chunk to request
+    pub fn received(&self) -> u32 {
+        self.bytes.len() as u32
+    }
+
+    // Appends the next chunk, returns the block once all of it arrived.
+    // Chunks out of order, past the announced size or adding up to a block
+    // with another hash are rejected
+    pub fn push(&mut self, chunk: &BlockChunk) -> Result<Option<Block>> {
+        let total = chunk.total as usize;
+        if chunk.offset != self.received()
+            || chunk.data.is_empty()
+            || total > MAX_PAYLOAD_SIZE
+            || self.bytes.len() + chunk.data.len() > total
+        {
+            return Err(Error::Protocol(ProtocolError::InvalidMessageFormat));
+        }
+
+        self.bytes.extend_from_slice(&chunk.data);
+        if self.bytes.len() < total {
+            return Ok(None);
+        }
+
+        let block = Block::from_bytes(&self.bytes)?;
+        if block.hash() != chunk.hash {
+            return Err(Error::Protocol(ProtocolError::HeaderMismatch));
+        }
+        Ok(Some(block))
+    }
+}
+
+impl Message {
+    // Transactions and blocks the message carries or announces, the sender
+    // has them all
+    pub fn inventory(&self) -> Vec<Inventory> {
+        match self {
+            Message::PaymentTransaction(txn) => vec![Inventory::Transaction(txn.hash_id)],
+            Message::BlockProposal(block) | Message::BlockResponse(block) => {
+                vec![Inventory::Block(block.hash())]
+            }
+            Message::Blocks(blocks) => blocks
+                .iter()
+                .map(|block| Inventory::Block(block.hash()))
+                .collect(),
+            Message::HeaderAnnouncement(header) => vec![Inventory::Block(header.hash)],
+            Message::BlockChunk(chunk) => vec![Inventory::Block(chunk.hash)],
+            Message::Transactions(txns) => txns
+                .iter()
+                .map(|txn| Inventory::Transaction(txn.hash_id))
+                .collect(),
+            Message::Inv(items) => items.clone(),
+            Message::FilteredBlock(block) => vec![Inventory::Block(block.header.hash)],
+            _ => Vec::new(),
+        }
+    }
 }
 
 pub fn deserialize(message: &[u8]) -> Result<Message> {
@@ -41,3 +277,68 @@ pub fn serialize(node_message: &Message, mut writer: impl Write) -> Result<()> {
     })?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{block::BlockBuilder, test_utils::create_mock_transaction};
+
+    use super::*;
+
+    #[test]
+    fn reassembles_blocks_from_chunks() {
+        let block = BlockBuilder::new(1, hex::encode([0u8; 32]), 1, [3u8; 32])
+            .transactions((0..600).map(|_| create_mock_transaction(1_000, 990).0))
+            .build()
+            .unwrap();
+        let size = block.to_bytes().len();
+        assert!(size > 2 * BLOCK_CHUNK_SIZE);
+
+        let mut download = ChunkedBlock::default();
+        let first = BlockChunk::of(&block, 0).unwrap();
+        assert_eq!(first.data.len(), BLOCK_CHUNK_SIZE);
+        assert_eq!(download.push(&first).unwrap(), None);
+        // A chunk arriving twice is out of order
+        assert!(download.push(&first).is_err());
+
+        let mut assembled = None;
+        while assembled.is_none() {
+            let chunk = BlockChunk::of(&block, download.received()).unwrap();
+            assembled = download.push(&chunk).unwrap();
+        }
+        assert_eq!(assembled, Some(block.clone()));
+        assert!(BlockChunk::of(&block, size as u32).is_none());
+
+        // The pieces have to add up to the announced block
+        let mut forged = BlockChunk::of(&block, 0).unwrap();
+        forged.total = forged.data.len() as u32;
+        assert!(ChunkedBlock::default().push(&forged).is_err());
+    }
+
+    #[test]
+    fn proves_the_transactions_of_filtered_blocks() {
+        let txns: Vec<Transaction> = (0..5)
+            .map(|_| create_mock_transaction(1_000, 990).0)
+            .collect();
+        let block = BlockBuilder::new(1, hex::encode([0u8; 32]), 1, [3u8; 32])
+            .transactions(txns.clone())
+            .build()
+            .unwrap();
+
+        let mut filter = BloomFilter::new(2, 0.0001, 1);
+        filter.insert(&txns[2].receiver);
+        let filtered = FilteredBlock::of(&block, Some(&filter));
+        assert!(filtered.verify());
+        assert_eq!(filtered.transactions.len(), 1);
+        assert_eq!(filtered.transactions[0].0, txns[2]);
+
+        // Without a filter every transaction is sent
+        let unfiltered = FilteredBlock::of(&block, None);
+        assert_eq!(unfiltered.transactions.len(), block.transactions().len());
+        assert!(unfiltered.verify());
+
+        // A transaction swapped in fails its proof
+        let mut forged = filtered.clone();
+        forged.transactions[0].0 = txns[3].clone();
+        assert!(!forged.verify());
+    }
+}
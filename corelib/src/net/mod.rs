@@ -1,12 +1,24 @@
+pub mod bloom;
+#[cfg(feature = "io")]
+pub mod codec;
+pub mod features;
 pub mod message;
 pub mod protocol;
+#[cfg(feature = "io")]
+pub mod transport;
 
 use borsh::{BorshDeserialize, BorshSerialize};
-use message::Message;
+use message::{serialize, Message};
+use protocol::SupportedVersions;
+#[cfg(feature = "io")]
+use std::net::SocketAddr;
+#[cfg(feature = "io")]
 use tokio::net::TcpListener;
 
-use crate::errors::{self, Result};
+use crate::errors::{Error, ProtocolError, Result};
 
+// Payload of a frame, the message along with the protocol version it was
+// encoded with and a checksum of its encoding
 #[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
 pub struct Payload {
     version: u8,
@@ -14,10 +26,61 @@ pub struct Payload {
     checksum: u64,
 }
 
-pub async fn start_listening(port: u16) -> Result<TcpListener> {
-    let listener = TcpListener::bind(format!("127.0.0.1:{port}"))
+impl Payload {
+    // Bytes the version and checksum add to the encoded message
+    pub const OVERHEAD: usize = 1 + 8;
+
+    // First 8 bytes of the blake3 hash of the encoded message
+    pub fn checksum(message_bytes: &[u8]) -> u64 {
+        let hash = blake3::hash(message_bytes);
+        u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+    }
+
+    // Appends the encoded payload of the message to the buffer. The layout
+    // is the Borsh encoding of `Payload`, the message is only serialized once
+    pub fn encode(message: &Message, version: u16, buffer: &mut Vec<u8>) -> Result<()> {
+        buffer.push(version as u8);
+
+        let start = buffer.len();
+        serialize(message, &mut *buffer)?;
+        let checksum = Self::checksum(&buffer[start..]);
+
+        buffer.extend_from_slice(&checksum.to_le_bytes());
+        Ok(())
+    }
+
+    // Decodes an encoded payload, payloads whose message doesn't match the
+    // checksum were corrupted on the way and are rejected
+    pub fn decode(bytes: &[u8]) -> Result<Message> {
+        if bytes.len() < Self::OVERHEAD {
+            return Err(Error::Protocol(ProtocolError::InvalidMessageFormat));
+        }
+
+        let (message_bytes, checksum) = bytes[1..].split_at(bytes.len() - Self::OVERHEAD);
+        if Self::checksum(message_bytes) != u64::from_le_bytes(checksum.try_into().unwrap()) {
+            return Err(Error::Protocol(ProtocolError::ChecksumMismatch));
+        }
+
+        let payload = Payload::try_from_slice(bytes)
+            .map_err(|e| Error::Protocol(ProtocolError::SerializationError(e.to_string())))?;
+        if !SupportedVersions::all().contains(&(payload.version as u16)) {
+            return Err(Error::Protocol(ProtocolError::UnknownVersion(
+                payload.version as u16,
+            )));
+        }
+
+        Ok(payload.message)
+    }
+}
+
+// Binds a listener to the address, IPv4 or IPv6, and returns it with the
+// address actually bound, which tells the port picked for port 0
+#[cfg(feature = "io")]
+pub async fn start_listening(address: SocketAddr) -> Result<(TcpListener, SocketAddr)> {
+    let listener = TcpListener::bind(address)
         .await
-        .map_err(|_| errors::Error::Network)?;
+        .map_err(|source| Error::Listen { address, source })?;
+    let address = listener.local_addr()?;
 
-    Ok(listener)
+    Ok((listener, address))
 }
@@ -1,26 +1,226 @@
 #![allow(unused)]
-use std::io::Write;
+use std::{io::Write, time::Duration};
 
 use borsh::{BorshDeserialize, BorshSerialize};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
 
-use crate::errors::{Error, ProtocolError, Result};
+use crate::{
+    config::Network,
+    errors::{Error, ProtocolError, Result},
+};
 
-use super::message::{deserialize, serialize, Message};
+use super::{features::Features, message::Message, Payload};
 
 #[derive(Default)]
 pub enum SupportedVersions {
     #[default]
     One = 1,
+    // Content size widened to a u32, version 1 frames carry at most 64 KiB
+    Two = 2,
 }
 
-pub const VERSION: SupportedVersions = SupportedVersions::One;
+pub const VERSION: SupportedVersions = SupportedVersions::Two;
+
+// Length of the magic bytes every frame starts with, see `Network::magic`.
+// A reader that lost track of the frame boundaries scans for them to find
+// the start of the next frame
+pub const MAGIC_LEN: usize = 4;
+
+// Domain separation context of handshake signatures
+const HANDSHAKE_CONTEXT: &str = "aurelius 2026-10 handshake";
+// Domain separation context of the connecting side's answer to the `VerAck`
+const PROOF_CONTEXT: &str = "aurelius 2026-10 handshake proof";
+
+// Milliseconds a connection may go without a message before it's closed,
+// unless the other side asks for longer
+pub const DEFAULT_IDLE_TIMEOUT: u32 = 90_000;
+// Longest idle time a peer can ask for, so a half-open connection to it is
+// still reaped eventually
+pub const MAX_IDLE_TIMEOUT: u32 = 600_000;
+
+// Size of an encoded header: magic, version and content size
+pub const HEADER_SIZE: usize = MAGIC_LEN + 2 + 4;
+// Version 1 headers encode the content size as a u16
+pub const HEADER_SIZE_V1: usize = MAGIC_LEN + 2 + 2;
+// Largest payload a frame may carry, anything bigger is refused before it's
+// read. It leaves room for a block of the largest size a network may
+// configure, see `MAX_BLOCK_SIZE_LIMIT`
+pub const MAX_PAYLOAD_SIZE: usize = 4 * 1024 * 1024;
+
+// Size of an encoded header of the version
+pub fn header_size(version: u16) -> Result<usize> {
+    match version {
+        1 => Ok(HEADER_SIZE_V1),
+        2 => Ok(HEADER_SIZE),
+        n => Err(Error::Protocol(ProtocolError::UnknownVersion(n))),
+    }
+}
 
 impl SupportedVersions {
     pub fn as_u16(&self) -> u16 {
         match self {
             Self::One => 1,
+            Self::Two => 2,
+        }
+    }
+
+    // Every version this node speaks
+    pub fn all() -> Vec<u16> {
+        vec![Self::One.as_u16(), Self::Two.as_u16()]
+    }
+
+    // Highest version both sides speak
+    pub fn negotiate(theirs: &[u16]) -> Result<u16> {
+        Self::all()
+            .into_iter()
+            .filter(|version| theirs.contains(version))
+            .max()
+            .ok_or_else(|| {
+                let latest = theirs.iter().copied().max().unwrap_or_default();
+                Error::Protocol(ProtocolError::UnknownVersion(latest))
+            })
+    }
+}
+
+// What a node advertises when a connection is set up, sent as `Version` by
+// the connecting side and answered with `VerAck`
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Handshake {
+    pub versions: Vec<u16>,
+    // Height of the sender's best chain
+    pub height: u64,
+    pub features: Features,
+    // Milliseconds the sender lets a connection idle before closing it
+    pub idle_timeout: u32,
+    // Sender's clock in milliseconds since the unix epoch, zero if unknown.
+    // Peers derive the network-adjusted time from it
+    pub timestamp: u64,
+    // Identity key of the sender, see `node_id`
+    pub public_key: [u8; 32],
+    // Picked by each side for the other to sign, the `VerAck` as its
+    // challenge and the connecting side in its `Authenticate`, so neither
+    // side's handshake recorded on another connection can be replayed
+    pub nonce: u64,
+    pub challenge: u64,
+    // Public half of the sender's ephemeral transport key, all zeroes if it
+    // doesn't want the connection encrypted. The rest of the connection is
+    // encrypted once both sides sent one, see `transport`
+    pub ephemeral: [u8; 32],
+    // Sender's signature over the rest of the handshake
+    pub signature: [u8; 64],
+}
+
+impl Handshake {
+    pub fn new(height: u64) -> Self {
+        Self {
+            versions: SupportedVersions::all(),
+            height,
+            features: Features::SUPPORTED,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            timestamp: 0,
+            public_key: [0u8; 32],
+            nonce: 0,
+            challenge: 0,
+            ephemeral: [0u8; 32],
+            signature: [0u8; 64],
         }
     }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout.as_millis().min(u32::MAX as u128) as u32;
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn with_nonce(mut self, nonce: u64) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    pub fn with_ephemeral(mut self, ephemeral: [u8; 32]) -> Self {
+        self.ephemeral = ephemeral;
+        self
+    }
+
+    // Whether the sender offered an encrypted transport
+    pub fn is_encrypted(&self) -> bool {
+        self.ephemeral != [0u8; 32]
+    }
+
+    // Answers the connecting side's handshake
+    pub fn answering(mut self, theirs: &Handshake) -> Self {
+        self.challenge = theirs.nonce;
+        self
+    }
+
+    // Signs the handshake with the node's identity key, done last as any
+    // later change invalidates the signature
+    pub fn sign(mut self, identity: &SigningKey) -> Self {
+        self.public_key = identity.verifying_key().to_bytes();
+        self.signature = identity.sign(&self.signed_hash()).to_bytes();
+        self
+    }
+
+    // Checks the handshake was signed by the key it carries
+    pub fn verify(&self) -> Result<()> {
+        VerifyingKey::from_bytes(&self.public_key)?
+            .verify_strict(&self.signed_hash(), &Signature::from_bytes(&self.signature))
+            .map_err(|_| Error::InvalidSignature)
+    }
+
+    pub fn node_id(&self) -> String {
+        node_id(&self.public_key)
+    }
+
+    // Signature of the connecting side over this `VerAck`'s nonce, proving
+    // it holds the key of its `Version` on this connection
+    pub fn prove(&self, identity: &SigningKey) -> [u8; 64] {
+        identity.sign(&self.proof_hash()).to_bytes()
+    }
+
+    // Checks the proof was signed by the key of `version`, the handshake
+    // this `VerAck` answered
+    pub fn verify_proof(&self, version: &Handshake, proof: &[u8; 64]) -> Result<()> {
+        VerifyingKey::from_bytes(&version.public_key)?
+            .verify_strict(&self.proof_hash(), &Signature::from_bytes(proof))
+            .map_err(|_| Error::InvalidSignature)
+    }
+
+    fn proof_hash(&self) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new_derive_key(PROOF_CONTEXT);
+        hasher.update(&self.nonce.to_le_bytes());
+        hasher.update(&self.public_key);
+
+        *hasher.finalize().as_bytes()
+    }
+
+    fn signed_hash(&self) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new_derive_key(HANDSHAKE_CONTEXT);
+        let fields = (
+            &self.versions,
+            self.height,
+            self.features,
+            self.idle_timeout,
+            self.timestamp,
+            self.public_key,
+            self.nonce,
+            self.challenge,
+            self.ephemeral,
+        );
+        hasher.update(&borsh::to_vec(&fields).expect("handshakes are always serializable"));
+
+        *hasher.finalize().as_bytes()
+    }
+}
+
+// Id a node is known by to its peers, the hex encoded public key of its
+// identity
+pub fn node_id(public_key: &[u8; 32]) -> String {
+    hex::encode(public_key)
 }
 
 #[repr(u8)]
@@ -46,37 +246,80 @@ impl TryFrom<u8> for Command {
 
 #[derive(Debug, Clone, BorshDeserialize, BorshSerialize)]
 pub struct Header {
+    // Network the frame belongs to, encoded as its magic
+    network: Network,
     version: u16,
-    content_size: u16,
+    content_size: u32,
 }
 
 impl Header {
-    pub fn new(content_size: u16) -> Self {
+    pub fn new(content_size: u32) -> Self {
         Header {
+            network: Network::Mainnet,
             version: VERSION.as_u16(),
             content_size,
         }
     }
 
+    // Payloads too large for a version 1 header are refused
     pub fn to_bytes(&self, buffer: &mut Vec<u8>) -> Result<()> {
+        buffer.write_all(&self.network.magic())?;
         buffer.write_all(&self.version.to_be_bytes())?;
-        buffer.write_all(&self.content_size.to_be_bytes())?;
+        match self.version {
+            1 => {
+                let content_size = u16::try_from(self.content_size).map_err(|_| {
+                    Error::Protocol(ProtocolError::PayloadTooLarge(self.content_size as usize))
+                })?;
+                buffer.write_all(&content_size.to_be_bytes())?;
+            }
+            2 => buffer.write_all(&self.content_size.to_be_bytes())?,
+            n => return Err(Error::Protocol(ProtocolError::UnknownVersion(n))),
+        }
         Ok(())
     }
 
+    // Size of the header once encoded
+    pub fn size(&self) -> usize {
+        header_size(self.version).unwrap_or(HEADER_SIZE)
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn content_size(&self) -> u32 {
+        self.content_size
+    }
+
+    // Reads a header of any supported version, the version following the
+    // magic tells how wide the content size is
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        if bytes.len() < 4 {
+        if bytes.len() < HEADER_SIZE_V1 {
             return Err(Error::Protocol(ProtocolError::InvalidMessageFormat));
         }
 
-        let version = u16::from_be_bytes([bytes[0], bytes[1]]);
-        let content_size = u16::from_be_bytes([bytes[2], bytes[3]]);
+        let network = Network::from_magic(&bytes[..MAGIC_LEN])
+            .ok_or(Error::Protocol(ProtocolError::InvalidMagic))?;
 
-        if version != VERSION.as_u16() {
-            return Err(Error::Protocol(ProtocolError::UnknownVersion(version)));
+        let version = u16::from_be_bytes([bytes[MAGIC_LEN], bytes[MAGIC_LEN + 1]]);
+        let size = header_size(version)?;
+        if bytes.len() < size {
+            return Err(Error::Protocol(ProtocolError::InvalidMessageFormat));
         }
 
+        let bytes = &bytes[MAGIC_LEN + 2..size];
+        let content_size = match bytes {
+            [a, b] => u16::from_be_bytes([*a, *b]) as u32,
+            [a, b, c, d] => u32::from_be_bytes([*a, *b, *c, *d]),
+            _ => unreachable!("headers carry a u16 or u32 content size"),
+        };
+
         Ok(Header {
+            network,
             version,
             content_size,
         })
@@ -92,12 +335,9 @@ pub struct Request {
 
 impl Request {
     pub fn new(command: Command, payload: Option<Message>) -> Result<Self> {
-        let content_size = if let Some(ref p) = payload {
-            let mut serialized_payload = Vec::new();
-            serialize(p, &mut serialized_payload)?;
-            serialized_payload.len() as u16
-        } else {
-            0
+        let content_size = match payload.as_ref() {
+            Some(message) => payload_size(message)?,
+            None => 0,
         };
         let header = Header::new(content_size);
         Ok(Request {
@@ -130,6 +370,22 @@ impl Request {
         })
     }
 
+    // Sends the request on the network instead of mainnet
+    pub fn with_network(mut self, network: Network) -> Self {
+        self.header.network = network;
+        self
+    }
+
+    // Encodes the request with an older protocol version a peer speaks
+    pub fn with_version(mut self, version: u16) -> Self {
+        self.header.version = version;
+        self
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
     pub fn command(&self) -> &Command {
         &self.command
     }
@@ -146,6 +402,8 @@ pub enum StatusCode {
     OK = 0,
     NotFound = 1,
     Error = 2,
+    // The request was dropped, the peer sends faster than it's allowed to
+    RateLimited = 3,
 }
 
 impl TryFrom<u8> for StatusCode {
@@ -156,6 +414,7 @@ impl TryFrom<u8> for StatusCode {
             0 => Ok(StatusCode::OK),
             1 => Ok(StatusCode::NotFound),
             2 => Ok(StatusCode::Error),
+            3 => Ok(StatusCode::RateLimited),
             n => Err(ProtocolError::UnsupportedStatusCode(n)),
         }
     }
@@ -170,12 +429,9 @@ pub struct Response {
 
 impl Response {
     pub fn new(status: StatusCode, payload: Option<Message>) -> Result<Self> {
-        let content_size = if let Some(ref p) = payload {
-            let mut serialized_payload = Vec::new();
-            serialize(p, &mut serialized_payload)?;
-            serialized_payload.len() as u16
-        } else {
-            0
+        let content_size = match payload.as_ref() {
+            Some(message) => payload_size(message)?,
+            None => 0,
         };
         let header = Header::new(content_size);
         Ok(Response {
@@ -207,6 +463,22 @@ impl Response {
         })
     }
 
+    // Sends the response on the network instead of mainnet
+    pub fn with_network(mut self, network: Network) -> Self {
+        self.header.network = network;
+        self
+    }
+
+    // Encodes the response with the version the request came in
+    pub fn with_version(mut self, version: u16) -> Self {
+        self.header.version = version;
+        self
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
     pub fn status(&self) -> &StatusCode {
         &self.status
     }
@@ -242,32 +514,45 @@ fn write_to_buffer(
 
     buffer.write_all(&[command_or_status.as_u8()])?;
 
-    if let Some(p) = payload {
-        serialize(p, buffer)?;
+    if let Some(message) = payload {
+        Payload::encode(message, header.version, buffer)?;
     }
 
     Ok(())
 }
 
+// Size of the encoded payload of a message, messages peers would refuse to
+// read are rejected up front
+fn payload_size(message: &Message) -> Result<u32> {
+    let size = borsh::object_length(message)
+        .map_err(|e| Error::Protocol(ProtocolError::SerializationError(e.to_string())))?
+        + Payload::OVERHEAD;
+    if size > MAX_PAYLOAD_SIZE {
+        return Err(Error::Protocol(ProtocolError::PayloadTooLarge(size)));
+    }
+
+    Ok(size as u32)
+}
+
 fn read_from_buffer<T>(bytes: &[u8]) -> Result<(Header, T, Option<Message>)>
 where
     T: TryFrom<u8> + Copy,
     T::Error: Into<ProtocolError>,
 {
-    if bytes.len() < 5 {
+    let header = Header::from_bytes(bytes)?;
+    let size = header.size();
+    if bytes.len() < size + 1 {
         return Err(Error::Protocol(ProtocolError::InvalidMessageFormat));
     }
 
-    let header = Header::from_bytes(&bytes[..4])?;
-
-    let command_or_status = T::try_from(bytes[4]).map_err(|e| Error::Protocol(e.into()))?;
+    let command_or_status = T::try_from(bytes[size]).map_err(|e| Error::Protocol(e.into()))?;
 
-    let payload_bytes = &bytes[5..];
+    let payload_bytes = &bytes[size + 1..];
 
     let payload = if payload_bytes.len() != header.content_size as usize {
         return Err(Error::Protocol(ProtocolError::HeaderMismatch));
     } else if header.content_size > 0 {
-        Some(deserialize(payload_bytes)?)
+        Some(Payload::decode(payload_bytes)?)
     } else {
         None
     };
@@ -317,6 +602,127 @@ mod tests {
         }
     }
 
+    #[test]
+    fn negotiates_highest_common_version() {
+        assert_eq!(SupportedVersions::negotiate(&[1, 7]).unwrap(), 1);
+        assert!(matches!(
+            SupportedVersions::negotiate(&[5, 7]),
+            Err(Error::Protocol(ProtocolError::UnknownVersion(7)))
+        ));
+        assert!(SupportedVersions::negotiate(&[]).is_err());
+    }
+
+    #[test]
+    fn reads_version_1_frames() {
+        let message = Message::PeerIntroduction("127.0.0.1:7878".to_string());
+        let request = Request::new(Command::Post, Some(message.clone()))
+            .unwrap()
+            .with_version(1);
+        let serialized = request.to_bytes().unwrap();
+        assert_eq!(
+            serialized.len(),
+            HEADER_SIZE_V1 + 1 + request.header().content_size() as usize
+        );
+
+        let deserialized = Request::from_bytes(&serialized).unwrap();
+        assert_eq!(deserialized.header().version(), 1);
+        assert_eq!(deserialized.payload(), &Some(message));
+
+        // Only version 2 headers fit payloads past 64 KiB
+        let large = Message::PeerList(vec!["127.0.0.1:7878".to_string(); 5_000]);
+        let request = Request::new(Command::Post, Some(large)).unwrap();
+        assert!(request.header().content_size() > u16::MAX as u32);
+        assert!(Request::from_bytes(&request.to_bytes().unwrap()).is_ok());
+        assert!(matches!(
+            request.with_version(1).to_bytes(),
+            Err(Error::Protocol(ProtocolError::PayloadTooLarge(_)))
+        ));
+    }
+
+    #[test]
+    fn signs_handshakes_with_the_identity_key() {
+        let identity = SigningKey::from_bytes(&[5u8; 32]);
+        let version = Handshake::new(3).with_nonce(42).sign(&identity);
+        assert!(version.verify().is_ok());
+        assert_eq!(
+            version.node_id(),
+            hex::encode(identity.verifying_key().to_bytes())
+        );
+
+        let answer = Handshake::new(7)
+            .answering(&version)
+            .sign(&SigningKey::from_bytes(&[6u8; 32]));
+        assert_eq!(answer.challenge, 42);
+        assert!(answer.verify().is_ok());
+
+        // Neither the fields nor the key can be swapped
+        let mut tampered = answer.clone();
+        tampered.challenge = 43;
+        assert!(matches!(tampered.verify(), Err(Error::InvalidSignature)));
+        let mut tampered = answer.clone();
+        tampered.ephemeral = [9u8; 32];
+        assert!(tampered.verify().is_err());
+        let mut tampered = answer.clone();
+        tampered.public_key = version.public_key;
+        assert!(tampered.verify().is_err());
+        assert!(Handshake::new(3).verify().is_err());
+
+        // The connecting side proves its key for the answer's nonce only
+        let answer = answer
+            .with_nonce(7)
+            .sign(&SigningKey::from_bytes(&[6u8; 32]));
+        let proof = answer.prove(&identity);
+        assert!(answer.verify_proof(&version, &proof).is_ok());
+        let other = answer.clone().with_nonce(8);
+        assert!(other.verify_proof(&version, &proof).is_err());
+        let impostor = Handshake::new(3).sign(&SigningKey::from_bytes(&[7u8; 32]));
+        assert!(answer.verify_proof(&impostor, &proof).is_err());
+    }
+
+    #[test]
+    fn rejects_frames_without_magic() {
+        let request = Request::new(Command::Ping, None).unwrap();
+        let mut serialized = request.to_bytes().unwrap();
+        serialized[0] ^= 0xff;
+
+        assert!(matches!(
+            Request::from_bytes(&serialized),
+            Err(Error::Protocol(ProtocolError::InvalidMagic))
+        ));
+    }
+
+    #[test]
+    fn tells_networks_apart_by_their_magic() {
+        let request = Request::new(Command::Ping, None)
+            .unwrap()
+            .with_network(Network::Regtest);
+        let serialized = request.to_bytes().unwrap();
+        assert_eq!(serialized[..MAGIC_LEN], Network::Regtest.magic());
+
+        let deserialized = Request::from_bytes(&serialized).unwrap();
+        assert_eq!(deserialized.header().network(), Network::Regtest);
+
+        let response = Response::new(StatusCode::OK, None).unwrap();
+        let deserialized = Response::from_bytes(&response.to_bytes().unwrap()).unwrap();
+        assert_eq!(deserialized.header().network(), Network::Mainnet);
+    }
+
+    #[test]
+    fn rejects_corrupted_payloads() {
+        let message = Message::PeerIntroduction("127.0.0.1:7878".to_string());
+        let request = Request::new(Command::Post, Some(message)).unwrap();
+        let mut serialized = request.to_bytes().unwrap();
+
+        // Still a well formed message, only the checksum tells it apart
+        let last_char = serialized.len() - Payload::OVERHEAD;
+        serialized[last_char] = b'9';
+
+        assert!(matches!(
+            Request::from_bytes(&serialized),
+            Err(Error::Protocol(ProtocolError::ChecksumMismatch))
+        ));
+    }
+
     #[test]
     fn test_empty_payload_request() -> Result<()> {
         let request = Request::new(Command::Get, None)?;
@@ -0,0 +1,389 @@
+use std::io;
+
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use curve25519_dalek::MontgomeryPoint;
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    errors::{Error, ProtocolError, Result},
+    rng::Entropy,
+};
+
+use super::{
+    codec::{self, MAX_PAYLOAD_SIZE},
+    protocol::{Handshake, Request, Response, HEADER_SIZE},
+};
+
+// HKDF labels of the session keys, one per direction
+const TO_RESPONDER: &[u8] = b"aurelius transport initiator to responder";
+const TO_INITIATOR: &[u8] = b"aurelius transport responder to initiator";
+
+// Bytes of the Poly1305 tag authenticating a sealed frame
+pub const TAG_LEN: usize = 16;
+
+// Ephemeral X25519 key of one side of a connection. The public halves are
+// exchanged in the signed handshakes
+pub struct EphemeralKey {
+    secret: [u8; 32],
+}
+
+impl EphemeralKey {
+    pub fn generate(entropy: &Entropy) -> Self {
+        Self {
+            secret: entropy.bytes(),
+        }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        MontgomeryPoint::mul_base_clamped(self.secret).to_bytes()
+    }
+
+    // Ciphers for the frames this side sends and receives, from the `Version`
+    // and `VerAck` the two sides exchanged. Both must be signed and carry
+    // the ephemeral keys, the one of this side being `ours`.
+    //
+    // The keys are derived with HKDF-SHA256 from the X25519 secret, salted
+    // with a hash of both handshakes. That binds the session to the identity
+    // keys, nonce and ephemeral keys both sides signed, a session set up
+    // with handshakes swapped on the way has different keys on each end
+    pub fn agree(
+        &self,
+        ours: &Handshake,
+        theirs: &Handshake,
+        initiator: bool,
+    ) -> Result<(Cipher, Cipher)> {
+        let refused = || Error::Protocol(ProtocolError::KeyExchangeFailed);
+        if ours.ephemeral != self.public_key() || !theirs.is_encrypted() {
+            return Err(refused());
+        }
+        ours.verify()?;
+        theirs.verify()?;
+
+        let (version, ver_ack) = match initiator {
+            true => (ours, theirs),
+            false => (theirs, ours),
+        };
+        if ver_ack.challenge != version.nonce {
+            return Err(refused());
+        }
+
+        let shared = MontgomeryPoint(theirs.ephemeral)
+            .mul_clamped(self.secret)
+            .to_bytes();
+        // Low order points would leave the session keys up to the peer
+        if shared == [0u8; 32] {
+            return Err(refused());
+        }
+
+        let transcript = Sha256::new()
+            .chain_update(borsh::to_vec(version)?)
+            .chain_update(borsh::to_vec(ver_ack)?)
+            .finalize();
+        let keys = Hkdf::<Sha256>::new(Some(&transcript), &shared);
+        let cipher = |label: &[u8]| {
+            let mut key = [0u8; 32];
+            keys.expand(label, &mut key)
+                .expect("32 bytes is a valid HKDF-SHA256 output");
+            Cipher::new(&key)
+        };
+        let (to_responder, to_initiator) = (cipher(TO_RESPONDER), cipher(TO_INITIATOR));
+
+        Ok(match initiator {
+            true => (to_responder, to_initiator),
+            false => (to_initiator, to_responder),
+        })
+    }
+}
+
+// Encrypts or decrypts the frames going one way with ChaCha20-Poly1305. The
+// nonce is the frame's position in the stream, so frames can't be altered,
+// dropped, replayed or reordered
+#[derive(Clone)]
+pub struct Cipher {
+    aead: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl std::fmt::Debug for Cipher {
+    // Leaves the key out
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cipher")
+            .field("counter", &self.counter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Cipher {
+    fn new(key: &[u8; 32]) -> Self {
+        Self {
+            aead: ChaCha20Poly1305::new(Key::from_slice(key)),
+            counter: 0,
+        }
+    }
+
+    // Encrypted frame followed by its tag
+    pub fn seal(&mut self, frame: &[u8]) -> Vec<u8> {
+        let sealed = self
+            .aead
+            .encrypt(&self.nonce(), frame)
+            .expect("frames are far below the ChaCha20-Poly1305 limit");
+
+        self.counter += 1;
+        sealed
+    }
+
+    pub fn open(&mut self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < TAG_LEN {
+            return Err(Error::Protocol(ProtocolError::InvalidMessageFormat));
+        }
+        let frame = self
+            .aead
+            .decrypt(&self.nonce(), sealed)
+            .map_err(|_| Error::Protocol(ProtocolError::ChecksumMismatch))?;
+
+        self.counter += 1;
+        Ok(frame)
+    }
+
+    fn nonce(&self) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_le_bytes());
+        *Nonce::from_slice(&nonce)
+    }
+}
+
+// How the frames of a connection travel one way, in the clear until the
+// handshake negotiated a session.
+//
+// A sealed frame is its length as a big endian u32 followed by the sealed
+// bytes. A stream that fails to authenticate can't be resynchronized, so
+// errors reading it are I/O errors closing the connection rather than
+// protocol errors skipping a frame
+#[derive(Debug, Clone, Default)]
+pub enum Transport {
+    #[default]
+    Plain,
+    Encrypted(Cipher),
+}
+
+impl Transport {
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, Transport::Encrypted(_))
+    }
+
+    pub async fn read_frame<R>(
+        &mut self,
+        reader: &mut R,
+        max_payload_size: usize,
+    ) -> Result<Option<Vec<u8>>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let cipher = match self {
+            Transport::Plain => return codec::read_frame(reader, max_payload_size).await,
+            Transport::Encrypted(cipher) => cipher,
+        };
+
+        let mut length = [0u8; 4];
+        match reader.read_exact(&mut length).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let length = u32::from_be_bytes(length) as usize;
+        if length > max_payload_size.min(MAX_PAYLOAD_SIZE) + HEADER_SIZE + 1 + TAG_LEN {
+            return Err(corrupt("sealed frame is too large"));
+        }
+
+        let mut sealed = vec![0u8; length];
+        reader.read_exact(&mut sealed).await?;
+        cipher
+            .open(&sealed)
+            .map(Some)
+            .map_err(|_| corrupt("sealed frame failed to authenticate"))
+    }
+
+    pub async fn write_frame<W>(&mut self, writer: &mut W, frame: &[u8]) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        match self {
+            Transport::Plain => writer.write_all(frame).await?,
+            Transport::Encrypted(cipher) => {
+                let sealed = cipher.seal(frame);
+                let mut bytes = (sealed.len() as u32).to_be_bytes().to_vec();
+                bytes.extend(sealed);
+                writer.write_all(&bytes).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn read_request<R>(
+        &mut self,
+        reader: &mut R,
+        max_payload_size: usize,
+    ) -> Result<Option<Request>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.read_frame(reader, max_payload_size)
+            .await?
+            .map(|frame| Request::from_bytes(&frame))
+            .transpose()
+    }
+
+    pub async fn read_response<R>(
+        &mut self,
+        reader: &mut R,
+        max_payload_size: usize,
+    ) -> Result<Option<Response>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.read_frame(reader, max_payload_size)
+            .await?
+            .map(|frame| Response::from_bytes(&frame))
+            .transpose()
+    }
+
+    pub async fn write_request<W>(&mut self, writer: &mut W, request: &Request) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.write_frame(writer, &request.to_bytes()?).await
+    }
+
+    pub async fn write_response<W>(&mut self, writer: &mut W, response: &Response) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.write_frame(writer, &response.to_bytes()?).await
+    }
+}
+
+fn corrupt(reason: &str) -> Error {
+    Error::IO(io::Error::new(io::ErrorKind::InvalidData, reason))
+}
+
+#[cfg(test)]
+mod test {
+    use ed25519_dalek::SigningKey;
+    use tokio::io::duplex;
+
+    use crate::net::{message::Message, protocol::Command};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn seals_frames_with_agreed_keys() {
+        let entropy = Entropy::os();
+        let (initiator, responder) = (
+            EphemeralKey::generate(&entropy),
+            EphemeralKey::generate(&entropy),
+        );
+        let (version, ver_ack) = handshakes(&initiator, &responder, [1u8; 32], [2u8; 32]);
+        let (send, _) = initiator.agree(&version, &ver_ack, true).unwrap();
+        let (_, receive) = responder.agree(&ver_ack, &version, false).unwrap();
+
+        let (mut client, mut server) = duplex(4096);
+        let (mut writer, mut reader) = (Transport::Encrypted(send), Transport::Encrypted(receive));
+        let request = Request::new(
+            Command::Post,
+            Some(Message::PeerIntroduction("127.0.0.1:7878".to_string())),
+        )
+        .unwrap();
+        for _ in 0..2 {
+            writer.write_request(&mut client, &request).await.unwrap();
+        }
+
+        // Nothing of the frame is readable on the wire
+        let mut sealed = vec![0u8; 4 + request.to_bytes().unwrap().len() + TAG_LEN];
+        server.read_exact(&mut sealed).await.unwrap();
+        assert!(!sealed.windows(9).any(|window| window == b"127.0.0.1"));
+
+        let mut replayed = sealed.as_slice();
+        assert!(reader
+            .read_request(&mut replayed, MAX_PAYLOAD_SIZE)
+            .await
+            .is_ok());
+        let received = reader
+            .read_request(&mut server, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received.payload(), request.payload());
+
+        // A replayed or altered frame fails to authenticate
+        let mut replayed = sealed.as_slice();
+        assert!(matches!(
+            reader.read_request(&mut replayed, MAX_PAYLOAD_SIZE).await,
+            Err(Error::IO(_))
+        ));
+        drop(client);
+        assert!(reader
+            .read_request(&mut server, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn binds_sessions_to_both_handshakes() {
+        let entropy = Entropy::os();
+        let (initiator, responder) = (
+            EphemeralKey::generate(&entropy),
+            EphemeralKey::generate(&entropy),
+        );
+        let (version, ver_ack) = handshakes(&initiator, &responder, [1u8; 32], [2u8; 32]);
+        let (mut send, _) = initiator.agree(&version, &ver_ack, true).unwrap();
+        let (_, mut receive) = responder.agree(&ver_ack, &version, false).unwrap();
+        assert_eq!(receive.open(&send.seal(b"frame")).unwrap(), b"frame");
+
+        // Somebody in the middle passing the initiator's ephemeral key on
+        // under its own identity leaves the two ends with different keys
+        let (relayed, answer) = handshakes(&initiator, &responder, [3u8; 32], [2u8; 32]);
+        assert_eq!(answer, ver_ack);
+        let (_, mut receive) = responder.agree(&answer, &relayed, false).unwrap();
+        assert!(receive.open(&send.seal(b"frame")).is_err());
+
+        // Unsigned, unanswered or low order handshakes set up nothing
+        let responder_identity = SigningKey::from_bytes(&[2u8; 32]);
+        let mut unsigned = ver_ack.clone();
+        unsigned.height += 1;
+        assert!(initiator.agree(&version, &unsigned, true).is_err());
+        let unanswered = Handshake {
+            challenge: 0,
+            ..ver_ack.clone()
+        }
+        .sign(&responder_identity);
+        assert!(initiator.agree(&version, &unanswered, true).is_err());
+        let mut low_order = [0u8; 32];
+        low_order[0] = 1;
+        let low_order = ver_ack
+            .clone()
+            .with_ephemeral(low_order)
+            .sign(&responder_identity);
+        assert!(initiator.agree(&version, &low_order, true).is_err());
+    }
+
+    // Signed `Version` and `VerAck` offering the ephemeral keys
+    fn handshakes(
+        initiator: &EphemeralKey,
+        responder: &EphemeralKey,
+        initiator_identity: [u8; 32],
+        responder_identity: [u8; 32],
+    ) -> (Handshake, Handshake) {
+        let version = Handshake::new(0)
+            .with_nonce(42)
+            .with_ephemeral(initiator.public_key())
+            .sign(&SigningKey::from_bytes(&initiator_identity));
+        let ver_ack = Handshake::new(0)
+            .answering(&version)
+            .with_ephemeral(responder.public_key())
+            .sign(&SigningKey::from_bytes(&responder_identity));
+        (version, ver_ack)
+    }
+}
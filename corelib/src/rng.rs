@@ -0,0 +1,126 @@
+use std::sync::Arc;
+
+use ed25519_dalek::SigningKey;
+use parking_lot::Mutex;
+use rand::{
+    rngs::{OsRng, StdRng},
+    CryptoRng, RngCore, SeedableRng,
+};
+
+// Domain separation context of the deterministic test keys
+const TEST_KEY_CONTEXT: &str = "aurelius 2026-10 deterministic test key";
+
+// Randomness for nonces, ephemeral keys and picking peers. Production draws
+// from the OS, tests, simulations and benchmarks seed it to replay a run.
+//
+// Clones share the seeded stream, so whoever draws first gets the next
+// bytes. Give every node of a simulation its own `fork` to keep the draws
+// of one from shifting the others'
+#[derive(Debug, Clone, Default)]
+pub struct Entropy {
+    seeded: Option<Arc<Mutex<StdRng>>>,
+}
+
+impl Entropy {
+    pub fn os() -> Self {
+        Self::default()
+    }
+
+    // Stream of a cryptographically secure generator started from the seed,
+    // predictable to anyone knowing the seed
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            seeded: Some(Arc::new(Mutex::new(StdRng::seed_from_u64(seed)))),
+        }
+    }
+
+    pub fn is_seeded(&self) -> bool {
+        self.seeded.is_some()
+    }
+
+    // Independent stream seeded from this one, or the OS again
+    pub fn fork(&self) -> Self {
+        match self.seeded {
+            Some(_) => Self {
+                seeded: Some(Arc::new(Mutex::new(StdRng::from_seed(self.bytes())))),
+            },
+            None => Self::os(),
+        }
+    }
+
+    pub fn fill(&self, dest: &mut [u8]) {
+        match &self.seeded {
+            Some(rng) => rng.lock().fill_bytes(dest),
+            None => OsRng.fill_bytes(dest),
+        }
+    }
+
+    pub fn bytes<const N: usize>(&self) -> [u8; N] {
+        let mut bytes = [0u8; N];
+        self.fill(&mut bytes);
+        bytes
+    }
+
+    pub fn signing_key(&self) -> SigningKey {
+        SigningKey::from_bytes(&self.bytes())
+    }
+}
+
+impl RngCore for Entropy {
+    fn next_u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.bytes())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.bytes())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.fill(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill(dest);
+        Ok(())
+    }
+}
+
+// Both sources are cryptographically secure, a seeded one just isn't secret
+impl CryptoRng for Entropy {}
+
+// Signing key named by a label, the same in every run. The key is derived
+// from the label rather than drawn from a seeded stream, so tests get the
+// same key however many draws happened before, and no label's key is ever
+// the output of another generator
+pub fn test_key(label: &str) -> SigningKey {
+    SigningKey::from_bytes(&blake3::derive_key(TEST_KEY_CONTEXT, label.as_bytes()))
+}
+
+#[cfg(test)]
+mod test {
+    use rand::seq::IteratorRandom;
+
+    use super::*;
+
+    #[test]
+    fn seeded_streams_replay() {
+        let (first, second) = (Entropy::seeded(42), Entropy::seeded(42));
+        let replayed = first.bytes::<32>();
+        assert_eq!(replayed, second.bytes::<32>());
+        assert_ne!(replayed, Entropy::seeded(43).bytes::<32>());
+        assert!(!Entropy::os().is_seeded());
+
+        // Clones share the stream, forks get their own
+        let clone = first.clone();
+        assert_eq!(clone.bytes::<8>(), second.bytes::<8>());
+        let (fork, other) = (first.fork(), second.fork());
+        first.bytes::<8>();
+        assert_eq!(fork.bytes::<8>(), other.bytes::<8>());
+
+        let pick = |entropy: &Entropy| (0..100).choose_multiple(&mut entropy.clone(), 5);
+        assert_eq!(pick(&Entropy::seeded(7)), pick(&Entropy::seeded(7)));
+
+        assert_eq!(test_key("alice").to_bytes(), test_key("alice").to_bytes());
+        assert_ne!(test_key("alice").to_bytes(), test_key("bob").to_bytes());
+    }
+}
@@ -0,0 +1,740 @@
+use std::{fmt, str::FromStr};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+use crate::{
+    config::{LOCKTIME_THRESHOLD, MULTISIG_SIGOPS},
+    deployment::Rules,
+    errors::{Error, Result},
+    utils::{convert_u8_to_u832, convert_u8_to_u864},
+};
+
+// Public keys a single `OP_CHECKMULTISIG` can check signatures against
+pub const MAX_MULTISIG_KEYS: usize = 16;
+// Signatures checked by scripts end with their sighash type
+pub const SIGNATURE_LEN: usize = 65;
+// Serialized size of the script unlocking a pay-to-pubkey-hash output, the
+// hex encoded signature and public key after the length prefix
+pub const P2PKH_UNLOCKING_SIZE: usize = 4 + 2 * SIGNATURE_LEN + 1 + 2 * 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    // `OP_1` to `OP_16`, pushes the number
+    Num(u8),
+    // Duplicates the top item
+    Dup,
+    // Replaces the top item with its blake3 hash
+    Hash,
+    // Pushes whether the top two items are equal
+    Equal,
+    // `OP_EQUAL` failing the script if they aren't
+    EqualVerify,
+    // Pops a public key and a signature, pushes whether the signature is valid
+    CheckSig,
+    CheckSigVerify,
+    // Pops `n`, n public keys, `m` and m signatures, pushes whether every
+    // signature is valid for one of the keys, in the same order
+    CheckMultiSig,
+    CheckMultiSigVerify,
+    // Pops a locktime, fails the script unless the spending transaction's
+    // locktime is at least as late and of the same kind
+    CheckLockTimeVerify,
+    // Pops a number of blocks, fails the script unless the spent output has
+    // been confirmed for at least that many blocks
+    CheckSequenceVerify,
+}
+
+impl Opcode {
+    // Signature operations executing the opcode costs
+    pub fn sigops(&self) -> usize {
+        match self {
+            Self::CheckSig | Self::CheckSigVerify => 1,
+            Self::CheckMultiSig | Self::CheckMultiSigVerify => MULTISIG_SIGOPS,
+            _ => 0,
+        }
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Num(n) => write!(f, "OP_{n}"),
+            Self::Dup => write!(f, "OP_DUP"),
+            Self::Hash => write!(f, "OP_HASH"),
+            Self::Equal => write!(f, "OP_EQUAL"),
+            Self::EqualVerify => write!(f, "OP_EQUALVERIFY"),
+            Self::CheckSig => write!(f, "OP_CHECKSIG"),
+            Self::CheckSigVerify => write!(f, "OP_CHECKSIGVERIFY"),
+            Self::CheckMultiSig => write!(f, "OP_CHECKMULTISIG"),
+            Self::CheckMultiSigVerify => write!(f, "OP_CHECKMULTISIGVERIFY"),
+            Self::CheckLockTimeVerify => write!(f, "OP_CHECKLOCKTIMEVERIFY"),
+            Self::CheckSequenceVerify => write!(f, "OP_CHECKSEQUENCEVERIFY"),
+        }
+    }
+}
+
+impl FromStr for Opcode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let opcode = match s {
+            "OP_DUP" => Self::Dup,
+            "OP_HASH" => Self::Hash,
+            "OP_EQUAL" => Self::Equal,
+            "OP_EQUALVERIFY" => Self::EqualVerify,
+            "OP_CHECKSIG" => Self::CheckSig,
+            "OP_CHECKSIGVERIFY" => Self::CheckSigVerify,
+            "OP_CHECKMULTISIG" => Self::CheckMultiSig,
+            "OP_CHECKMULTISIGVERIFY" => Self::CheckMultiSigVerify,
+            "OP_CHECKLOCKTIMEVERIFY" => Self::CheckLockTimeVerify,
+            "OP_CHECKSEQUENCEVERIFY" => Self::CheckSequenceVerify,
+            _ => match s.strip_prefix("OP_").and_then(|n| n.parse::<u8>().ok()) {
+                Some(n @ 1..=16) => Self::Num(n),
+                _ => return Err(Error::InvalidScript(format!("unknown opcode {s}"))),
+            },
+        };
+
+        Ok(opcode)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Op(Opcode),
+    // Data pushed onto the stack, hex encoded in the script's text form
+    Push(Vec<u8>),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Op(opcode) => opcode.fmt(f),
+            Self::Push(data) => write!(f, "{}", hex::encode(data)),
+        }
+    }
+}
+
+// Parts of the spending transaction a signature commits to, appended to
+// the signature as its last byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize)]
+#[borsh(use_discriminant = true)]
+#[repr(u8)]
+pub enum SigHash {
+    // Every input and output
+    All = 1,
+    // Every input but none of the outputs, which anyone can then change
+    None = 2,
+    // Every input and only the output at the index of the signed input
+    Single = 3,
+}
+
+impl SigHash {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(Self::All),
+            2 => Some(Self::None),
+            3 => Some(Self::Single),
+            _ => None,
+        }
+    }
+}
+
+// Digests signatures of every sighash type sign for the input being spent,
+// none where the spend has no transaction or the type can't be used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SigHashes {
+    pub all: Option<[u8; 32]>,
+    pub none: Option<[u8; 32]>,
+    pub single: Option<[u8; 32]>,
+}
+
+impl SigHashes {
+    // Digest for signatures of a single type, where the spend is known from
+    // its digest alone
+    pub fn only(sighash: SigHash, digest: [u8; 32]) -> Self {
+        let mut sighashes = Self::default();
+        match sighash {
+            SigHash::All => sighashes.all = Some(digest),
+            SigHash::None => sighashes.none = Some(digest),
+            SigHash::Single => sighashes.single = Some(digest),
+        }
+
+        sighashes
+    }
+
+    pub fn get(&self, sighash: SigHash) -> Option<[u8; 32]> {
+        match sighash {
+            SigHash::All => self.all,
+            SigHash::None => self.none,
+            SigHash::Single => self.single,
+        }
+    }
+}
+
+// What signature and timelock opcodes check a spend against
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpendContext {
+    // Locktime of the spending transaction
+    pub locktime: u64,
+    // Blocks the spent output has been confirmed for, counting the block
+    // including the spend
+    pub confirmations: u64,
+    pub sighashes: SigHashes,
+    // Deployed rules active for the spend
+    pub rules: Rules,
+}
+
+// Parsed locking or unlocking script.
+//
+// Scripts are stored as whitespace separated tokens, `OP_` prefixed opcodes
+// and hex encoded data to push.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Script {
+    tokens: Vec<Token>,
+}
+
+impl Script {
+    pub fn new(tokens: Vec<Token>) -> Self {
+        Self { tokens }
+    }
+
+    // `OP_DUP OP_HASH <pubkey hash> OP_EQUALVERIFY OP_CHECKSIG`, spent with
+    // `<signature> <pubkey>`
+    pub fn pay_to_pubkey_hash(owner: &[u8; 32]) -> Self {
+        Self::new(vec![
+            Token::Op(Opcode::Dup),
+            Token::Op(Opcode::Hash),
+            Token::Push(blake3::hash(owner).as_bytes().to_vec()),
+            Token::Op(Opcode::EqualVerify),
+            Token::Op(Opcode::CheckSig),
+        ])
+    }
+
+    // `OP_HASH <script hash> OP_EQUAL`, spent with the data the redeem script
+    // needs followed by the redeem script itself
+    pub fn pay_to_script_hash(script_hash: &[u8; 32]) -> Self {
+        Self::new(vec![
+            Token::Op(Opcode::Hash),
+            Token::Push(script_hash.to_vec()),
+            Token::Op(Opcode::Equal),
+        ])
+    }
+
+    // `OP_<m> <pubkey>... OP_<n> OP_CHECKMULTISIG`, spent with signatures of
+    // `required` of the keys in the order the keys are listed
+    pub fn multisig(required: usize, public_keys: &[[u8; 32]]) -> Result<Self> {
+        let key_count = public_keys.len();
+        if required == 0 || required > key_count || key_count > MAX_MULTISIG_KEYS {
+            return Err(Error::InvalidScript(format!(
+                "can't require {required} of {key_count} signatures"
+            )));
+        }
+
+        let mut tokens = vec![Token::Op(Opcode::Num(required as u8))];
+        tokens.extend(public_keys.iter().map(|key| Token::Push(key.to_vec())));
+        tokens.push(Token::Op(Opcode::Num(key_count as u8)));
+        tokens.push(Token::Op(Opcode::CheckMultiSig));
+
+        Ok(Self::new(tokens))
+    }
+
+    // Required signature count and public keys of a multisig script
+    pub fn multisig_keys(&self) -> Option<(usize, Vec<[u8; 32]>)> {
+        let (Token::Op(Opcode::Num(required)), rest) = self.tokens.split_first()? else {
+            return None;
+        };
+        let [keys @ .., Token::Op(Opcode::Num(key_count)), Token::Op(Opcode::CheckMultiSig)] = rest
+        else {
+            return None;
+        };
+        if *key_count as usize != keys.len() || required > key_count {
+            return None;
+        }
+
+        let public_keys = keys
+            .iter()
+            .map(|key| match key {
+                Token::Push(key) => key.as_slice().try_into().ok(),
+                Token::Op(_) => None,
+            })
+            .collect::<Option<Vec<[u8; 32]>>>()?;
+        Some((*required as usize, public_keys))
+    }
+
+    // `<locktime> OP_CHECKLOCKTIMEVERIFY` followed by the script, which can
+    // only be spent by transactions with a locktime of at least `locktime`
+    pub fn locked_until(locktime: u64, script: Script) -> Self {
+        let mut tokens = vec![
+            Token::Push(locktime.to_le_bytes().to_vec()),
+            Token::Op(Opcode::CheckLockTimeVerify),
+        ];
+        tokens.extend(script.tokens);
+
+        Self::new(tokens)
+    }
+
+    // `<blocks> OP_CHECKSEQUENCEVERIFY` followed by the script, which can only
+    // be spent once the output has been confirmed for `blocks` blocks
+    pub fn locked_for(blocks: u64, script: Script) -> Self {
+        let mut tokens = vec![
+            Token::Push(blocks.to_le_bytes().to_vec()),
+            Token::Op(Opcode::CheckSequenceVerify),
+        ];
+        tokens.extend(script.tokens);
+
+        Self::new(tokens)
+    }
+
+    // Hash pay-to-script-hash outputs lock to, of the script's text form
+    pub fn script_hash(&self) -> [u8; 32] {
+        *blake3::hash(self.to_string().as_bytes()).as_bytes()
+    }
+
+    // Redeem script hash of a pay-to-script-hash locking script
+    pub fn locked_script_hash(&self) -> Option<[u8; 32]> {
+        match self.tokens.as_slice() {
+            [Token::Op(Opcode::Hash), Token::Push(hash), Token::Op(Opcode::Equal)] => {
+                hash.as_slice().try_into().ok()
+            }
+            _ => None,
+        }
+    }
+
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    pub fn sigops(&self) -> usize {
+        self.tokens
+            .iter()
+            .map(|token| match token {
+                Token::Op(opcode) => opcode.sigops(),
+                Token::Push(_) => 0,
+            })
+            .sum()
+    }
+
+    pub fn is_push_only(&self) -> bool {
+        self.tokens
+            .iter()
+            .all(|token| matches!(token, Token::Push(_) | Token::Op(Opcode::Num(_))))
+    }
+
+    // Runs the unlocking script and then the locking script on the same
+    // stack. The unlocking script may only push data, the spend is valid if
+    // a single true item is left.
+    //
+    // Pay-to-script-hash locking scripts only check the hash of the redeem
+    // script pushed last, the redeem script then runs on the rest of the
+    // unlocking script's items.
+    pub fn execute(unlocking: &Script, locking: &Script, context: &SpendContext) -> Result<()> {
+        if !unlocking.is_push_only() {
+            return Err(Error::InvalidUnlockingScript);
+        }
+
+        let mut stack = Stack::default();
+        unlocking.run(&mut stack, context)?;
+        let redeem_stack = locking.locked_script_hash().map(|_| Stack {
+            items: stack.items.clone(),
+        });
+        locking.run(&mut stack, context)?;
+
+        if let Some(mut redeem_stack) = redeem_stack {
+            if !stack.pop_bool()? {
+                return Err(Error::InvalidUnlockingScript);
+            }
+
+            let redeem_script = redeem_stack.pop()?;
+            let redeem_script = std::str::from_utf8(&redeem_script)
+                .map_err(|_| Error::InvalidScript("redeem script isn't text".to_string()))?
+                .parse::<Script>()?;
+            redeem_script.run(&mut redeem_stack, context)?;
+            stack = redeem_stack;
+        }
+
+        if stack.items.len() == 1 && stack.pop_bool()? {
+            Ok(())
+        } else {
+            Err(Error::InvalidUnlockingScript)
+        }
+    }
+
+    fn run(&self, stack: &mut Stack, context: &SpendContext) -> Result<()> {
+        for token in self.tokens.iter() {
+            let opcode = match token {
+                Token::Push(data) => {
+                    stack.push(data.clone());
+                    continue;
+                }
+                Token::Op(opcode) => *opcode,
+            };
+
+            match opcode {
+                Opcode::Num(n) => stack.push(vec![n]),
+                Opcode::Dup => {
+                    let top = stack.pop()?;
+                    stack.push(top.clone());
+                    stack.push(top);
+                }
+                Opcode::Hash => {
+                    let top = stack.pop()?;
+                    stack.push(blake3::hash(&top).as_bytes().to_vec());
+                }
+                Opcode::Equal | Opcode::EqualVerify => {
+                    let equal = stack.pop()? == stack.pop()?;
+                    stack.push_bool(equal);
+                }
+                Opcode::CheckSig | Opcode::CheckSigVerify => {
+                    let public_key = stack.pop()?;
+                    let signature = stack.pop()?;
+                    stack.push_bool(check_signature(&public_key, &signature, &context.sighashes));
+                }
+                Opcode::CheckMultiSig | Opcode::CheckMultiSigVerify => {
+                    let valid = check_multisig(stack, &context.sighashes)?;
+                    stack.push_bool(valid);
+                }
+                // Before their deployment the timelock opcodes only drop the
+                // number
+                Opcode::CheckLockTimeVerify | Opcode::CheckSequenceVerify
+                    if !context.rules.contains(Rules::TIMELOCKS) =>
+                {
+                    stack.pop_number()?;
+                }
+                Opcode::CheckLockTimeVerify => {
+                    let locktime = stack.pop_number()?;
+                    let same_kind =
+                        (locktime < LOCKTIME_THRESHOLD) == (context.locktime < LOCKTIME_THRESHOLD);
+                    if !same_kind || locktime > context.locktime {
+                        return Err(Error::Timelocked);
+                    }
+                }
+                Opcode::CheckSequenceVerify => {
+                    if stack.pop_number()? > context.confirmations {
+                        return Err(Error::Timelocked);
+                    }
+                }
+            }
+
+            if matches!(
+                opcode,
+                Opcode::EqualVerify | Opcode::CheckSigVerify | Opcode::CheckMultiSigVerify
+            ) && !stack.pop_bool()?
+            {
+                return Err(Error::InvalidUnlockingScript);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, token) in self.tokens.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            token.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Script {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let tokens = s
+            .split_whitespace()
+            .map(|token| {
+                if token.starts_with("OP_") {
+                    return Ok(Token::Op(token.parse()?));
+                }
+
+                hex::decode(token)
+                    .map(Token::Push)
+                    .map_err(|_| Error::InvalidScript(format!("invalid push data {token}")))
+            })
+            .collect::<Result<Vec<Token>>>()?;
+
+        Ok(Self { tokens })
+    }
+}
+
+// Signature operations executing the script costs, unparseable tokens cost
+// nothing as the script can't be executed anyway
+pub fn count_sigops(script: &str) -> usize {
+    script
+        .split_whitespace()
+        .filter_map(|token| token.parse::<Opcode>().ok())
+        .map(|opcode| opcode.sigops())
+        .sum()
+}
+
+// Signature operations of the redeem script a pay-to-script-hash spend
+// pushes last, nothing if that push isn't a script
+pub fn count_redeem_sigops(unlocking_script: &str) -> usize {
+    unlocking_script
+        .split_whitespace()
+        .last()
+        .and_then(|push| hex::decode(push).ok())
+        .and_then(|redeem_script| String::from_utf8(redeem_script).ok())
+        .map_or(0, |redeem_script| count_sigops(&redeem_script))
+}
+
+#[derive(Debug, Default)]
+struct Stack {
+    items: Vec<Vec<u8>>,
+}
+
+impl Stack {
+    fn push(&mut self, item: Vec<u8>) {
+        self.items.push(item);
+    }
+
+    fn push_bool(&mut self, value: bool) {
+        self.items.push(if value { vec![1] } else { Vec::new() });
+    }
+
+    fn pop(&mut self) -> Result<Vec<u8>> {
+        self.items.pop().ok_or(Error::EmptyStack)
+    }
+
+    // Any non zero byte is true
+    fn pop_bool(&mut self) -> Result<bool> {
+        Ok(self.pop()?.iter().any(|byte| *byte != 0))
+    }
+
+    // Little endian number of up to 8 bytes, or pushed with `OP_1` to `OP_16`
+    fn pop_number(&mut self) -> Result<u64> {
+        let item = self.pop()?;
+        if item.len() > 8 {
+            return Err(Error::InvalidScript(format!(
+                "number of {} bytes is too long",
+                item.len()
+            )));
+        }
+
+        let mut bytes = [0u8; 8];
+        bytes[..item.len()].copy_from_slice(&item);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    // Key and signature counts are pushed with `OP_1` to `OP_16`
+    fn pop_count(&mut self) -> Result<usize> {
+        match self.pop()?.as_slice() {
+            [n] if (*n as usize) <= MAX_MULTISIG_KEYS => Ok(*n as usize),
+            _ => Err(Error::InvalidUnlockingScript),
+        }
+    }
+}
+
+fn check_multisig(stack: &mut Stack, sighashes: &SigHashes) -> Result<bool> {
+    let key_count = stack.pop_count()?;
+    let public_keys = (0..key_count)
+        .map(|_| stack.pop())
+        .collect::<Result<Vec<_>>>()?;
+
+    let signature_count = stack.pop_count()?;
+    if signature_count > key_count {
+        return Err(Error::InvalidUnlockingScript);
+    }
+    let signatures = (0..signature_count)
+        .map(|_| stack.pop())
+        .collect::<Result<Vec<_>>>()?;
+
+    // Both were popped in reverse, each signature has to match a key after
+    // the one matching the previous signature
+    let mut keys = public_keys.iter().rev();
+    Ok(signatures
+        .iter()
+        .rev()
+        .all(|signature| keys.any(|public_key| check_signature(public_key, signature, sighashes))))
+}
+
+// Signature checked by `OP_CHECKSIG`, over the digest of the given type
+pub fn sign_digest(
+    signing_key: &SigningKey,
+    digest: &[u8; 32],
+    sighash: SigHash,
+) -> [u8; SIGNATURE_LEN] {
+    let mut signature = [0u8; SIGNATURE_LEN];
+    signature[..64].copy_from_slice(&signing_key.sign(digest).to_bytes());
+    signature[64] = sighash as u8;
+
+    signature
+}
+
+// The signature's last byte picks the digest it has to be valid for
+pub(crate) fn check_signature(public_key: &[u8], signature: &[u8], sighashes: &SigHashes) -> bool {
+    let verify = || -> Result<()> {
+        let (&sighash, signature) = signature.split_last().ok_or(Error::InvalidSignature)?;
+        let digest = SigHash::from_byte(sighash)
+            .and_then(|sighash| sighashes.get(sighash))
+            .ok_or(Error::InvalidSignature)?;
+
+        let verifier = VerifyingKey::from_bytes(convert_u8_to_u832(public_key)?)?;
+        let signature = Signature::from_bytes(convert_u8_to_u864(signature)?);
+
+        Ok(verifier.verify_strict(&digest, &signature)?)
+    };
+
+    verify().is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+
+    const DIGEST: [u8; 32] = [9u8; 32];
+
+    // Spend whose `SigHash::All` signatures sign `DIGEST`
+    fn context() -> SpendContext {
+        SpendContext {
+            sighashes: SigHashes {
+                all: Some(DIGEST),
+                ..SigHashes::default()
+            },
+            rules: Rules::ALL,
+            ..SpendContext::default()
+        }
+    }
+
+    fn key(seed: u8) -> (SigningKey, String, String) {
+        let signing_key = SigningKey::from_bytes(&[seed; 32]);
+        let public_key = signing_key.verifying_key().to_bytes();
+        let signature = sign_digest(&signing_key, &DIGEST, SigHash::All);
+
+        (signing_key, hex::encode(public_key), hex::encode(signature))
+    }
+
+    fn execute(unlocking: &str, locking: &str) -> Result<()> {
+        Script::execute(&unlocking.parse()?, &locking.parse()?, &context())
+    }
+
+    #[test]
+    fn executes_pay_to_pubkey_hash() {
+        let (signing_key, public_key, signature) = key(1);
+        let locking = Script::pay_to_pubkey_hash(&signing_key.verifying_key().to_bytes());
+
+        assert_eq!(locking.sigops(), 1);
+        assert_eq!(locking.to_string().parse::<Script>().unwrap(), locking);
+        execute(&format!("{signature} {public_key}"), &locking.to_string()).unwrap();
+
+        let (_, other_key, other_signature) = key(2);
+        assert!(execute(
+            &format!("{other_signature} {other_key}"),
+            &locking.to_string()
+        )
+        .is_err());
+        assert!(execute(&format!("{signature} {other_key}"), &locking.to_string()).is_err());
+        // Unlocking scripts can only push data
+        assert!(execute(&format!("{signature} {public_key} OP_DUP"), "OP_EQUAL").is_err());
+        assert!(matches!(
+            "OP_NOPE".parse::<Script>(),
+            Err(Error::InvalidScript(_))
+        ));
+    }
+
+    #[test]
+    fn checks_signatures_against_their_sighash_type() {
+        let (signing_key, public_key, _) = key(1);
+        let locking = format!("{public_key} OP_CHECKSIG");
+
+        // A signature is only valid for the digest of its own type
+        let none = hex::encode(sign_digest(&signing_key, &DIGEST, SigHash::None));
+        assert!(execute(&none, &locking).is_err());
+
+        let mut context = context();
+        context.sighashes.none = Some([8u8; 32]);
+        let none = hex::encode(sign_digest(&signing_key, &[8u8; 32], SigHash::None));
+        Script::execute(&none.parse().unwrap(), &locking.parse().unwrap(), &context).unwrap();
+
+        // Without the type byte, or without a transaction to sign, nothing
+        // verifies
+        let (_, _, signature) = key(1);
+        assert!(execute(&signature[..128], &locking).is_err());
+        assert!(Script::execute(
+            &signature.parse().unwrap(),
+            &locking.parse().unwrap(),
+            &SpendContext::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn executes_pay_to_script_hash() {
+        let (_, key_1, signature_1) = key(1);
+        let (_, key_2, signature_2) = key(2);
+        let redeem: Script = format!("OP_2 {key_1} {key_2} OP_2 OP_CHECKMULTISIG")
+            .parse()
+            .unwrap();
+        let locking = Script::pay_to_script_hash(&redeem.script_hash()).to_string();
+        let redeem_push = hex::encode(redeem.to_string());
+
+        assert_eq!(
+            locking.parse::<Script>().unwrap().locked_script_hash(),
+            Some(redeem.script_hash())
+        );
+        execute(
+            &format!("{signature_1} {signature_2} {redeem_push}"),
+            &locking,
+        )
+        .unwrap();
+
+        // The redeem script has to be satisfied, not just match the hash
+        assert!(execute(&format!("{signature_1} {redeem_push}"), &locking).is_err());
+        let other = hex::encode(format!("{key_1} OP_CHECKSIG"));
+        assert!(execute(&format!("{signature_1} {other}"), &locking).is_err());
+    }
+
+    #[test]
+    fn executes_multisig() {
+        let (_, key_1, signature_1) = key(1);
+        let (_, key_2, _) = key(2);
+        let (_, key_3, signature_3) = key(3);
+        let locking = format!("OP_2 {key_1} {key_2} {key_3} OP_3 OP_CHECKMULTISIG");
+
+        assert_eq!(count_sigops(&locking), MULTISIG_SIGOPS);
+        execute(&format!("{signature_1} {signature_3}"), &locking).unwrap();
+        // Signatures have to be in the order of the keys
+        assert!(execute(&format!("{signature_3} {signature_1}"), &locking).is_err());
+        assert!(execute(&format!("{signature_1} {signature_1}"), &locking).is_err());
+    }
+
+    #[test]
+    fn executes_timelocks() {
+        let (signing_key, public_key, signature) = key(1);
+        let owner = Script::pay_to_pubkey_hash(&signing_key.verifying_key().to_bytes());
+        let unlocking: Script = format!("{signature} {public_key}").parse().unwrap();
+        let at = |locktime, confirmations| SpendContext {
+            locktime,
+            confirmations,
+            ..context()
+        };
+
+        let locked = Script::locked_until(100, owner.clone());
+        assert_eq!(locked.to_string().parse::<Script>().unwrap(), locked);
+        Script::execute(&unlocking, &locked, &at(100, 0)).unwrap();
+        assert!(matches!(
+            Script::execute(&unlocking, &locked, &at(99, 0)),
+            Err(Error::Timelocked)
+        ));
+        // Heights can't be satisfied with a time
+        assert!(Script::execute(&unlocking, &locked, &at(LOCKTIME_THRESHOLD, 0)).is_err());
+
+        let locked = Script::locked_for(10, owner);
+        Script::execute(&unlocking, &locked, &at(0, 10)).unwrap();
+        assert!(Script::execute(&unlocking, &locked, &at(0, 9)).is_err());
+
+        // Until the rules are deployed the locks aren't enforced
+        let undeployed = SpendContext {
+            rules: Rules::NONE,
+            ..at(0, 0)
+        };
+        Script::execute(&unlocking, &locked, &undeployed).unwrap();
+    }
+}
@@ -0,0 +1,63 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+use crate::errors::{Error, Result};
+
+// Domain separation context, a signed message can never be mistaken for a
+// transaction or unlocking script signature
+const MESSAGE_CONTEXT: &str = "aurelius 2024-11 signed message";
+
+// Hash actually signed when proving ownership of an address, the message is
+// length prefixed so concatenations of messages can't collide
+pub fn message_hash(message: &[u8]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_derive_key(MESSAGE_CONTEXT);
+    hasher.update(&(message.len() as u64).to_le_bytes());
+    hasher.update(message);
+
+    *hasher.finalize().as_bytes()
+}
+
+pub fn sign_message(signing_key: &SigningKey, message: &[u8]) -> [u8; 64] {
+    signing_key.sign(&message_hash(message)).to_bytes()
+}
+
+// Verifies that `signature` was made over `message` by the key of `address`
+pub fn verify_message(address: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> Result<()> {
+    let verifying_key = VerifyingKey::from_bytes(address)?;
+    let signature = Signature::from_bytes(signature);
+
+    verifying_key
+        .verify_strict(&message_hash(message), &signature)
+        .map_err(|_| Error::InvalidSignature)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::generate_key_pairs;
+
+    use super::*;
+
+    #[test]
+    fn signs_and_verifies_messages() {
+        let (signing_key, _, address, other) = generate_key_pairs().unwrap();
+        let signature = sign_message(&signing_key, b"I own this address");
+
+        verify_message(&address, b"I own this address", &signature).unwrap();
+
+        assert!(matches!(
+            verify_message(&address, b"I own that address", &signature),
+            Err(Error::InvalidSignature)
+        ));
+        assert!(matches!(
+            verify_message(&other, b"I own this address", &signature),
+            Err(Error::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_signatures_without_domain_separation() {
+        let (signing_key, _, address, _) = generate_key_pairs().unwrap();
+        let signature = signing_key.sign(b"raw message").to_bytes();
+
+        assert!(verify_message(&address, b"raw message", &signature).is_err());
+    }
+}
@@ -1,15 +1,29 @@
-use ed25519_dalek::{ed25519::signature::SignerMut, SigningKey};
-use rand::{rngs::OsRng, Rng};
+use ed25519_dalek::SigningKey;
+use rand::Rng;
+
+use crate::{errors::Result, rng::Entropy, script::SigHash, transaction::Transaction, utxo::UTXO};
+
+thread_local! {
+    // Every test runs on its own thread and replays the same draws, set
+    // AURELIUS_TEST_SEED to try others
+    static ENTROPY: Entropy = Entropy::seeded(
+        std::env::var("AURELIUS_TEST_SEED")
+            .ok()
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or(0),
+    );
+}
 
-use crate::{errors::Result, transaction::Transaction, utxo::UTXO};
+pub fn test_entropy() -> Entropy {
+    ENTROPY.with(Entropy::clone)
+}
 
 #[allow(unused)]
 pub fn generate_key_pairs() -> Result<(SigningKey, SigningKey, [u8; 32], [u8; 32])> {
-    let mut csprng = OsRng;
+    let entropy = test_entropy();
 
-    let signing_key = SigningKey::generate(&mut csprng);
-    let mut csprng2 = OsRng;
-    let receiver_singing_key = SigningKey::generate(&mut csprng2);
+    let signing_key = entropy.signing_key();
+    let receiver_singing_key = entropy.signing_key();
 
     let sender = signing_key.verifying_key().to_bytes();
     let receiver = receiver_singing_key.verifying_key().to_bytes();
@@ -25,22 +39,23 @@ pub fn generate_random_utxos(
     input_value: u32,
     output_value: u32,
 ) -> Result<(Vec<UTXO>, Vec<UTXO>)> {
-    let mut rand_gen = rand::thread_rng();
+    let mut rand_gen = test_entropy();
 
     let mut inputs: Vec<UTXO> = Vec::new();
 
     let mut input_value = input_value;
+    // Sample hash of the transaction the inputs were created by
+    let funding_hash: [u8; 32] = rand_gen.gen();
 
     let mut i = 0;
     while input_value > 0 {
-        let min_input = input_value % 100;
+        let min_input = (input_value % 100).max(1);
         let input_val = rand_gen.gen_range(min_input..=input_value);
         i += 1;
 
         input_value -= input_val;
-        let new_utxo = UTXO::new(input_val as u64, i).unwrap();
-        // sample transaction hash
-        let confirmed_utxo = new_utxo.confirm_utxo(sender, [1u8; 32], 1, i == 0)?;
+        let new_utxo = UTXO::new(input_val as u64, i, sender).unwrap();
+        let confirmed_utxo = new_utxo.confirm_utxo(funding_hash, 1, i == 0)?;
         inputs.push(confirmed_utxo);
     }
 
@@ -50,18 +65,19 @@ pub fn generate_random_utxos(
 
     let mut o = 0;
     while output_value > 0 {
-        let min_output = output_value % 100;
+        let min_output = (output_value % 100).max(1);
 
         let output_val = rand_gen.gen_range(min_output..=output_value);
         o += 1;
 
         output_value -= output_val;
-        outputs.push(UTXO::new(output_val as u64, o).unwrap());
+        outputs.push(UTXO::new(output_val as u64, o, sender).unwrap());
     }
 
     Ok((inputs, outputs))
 }
 
+#[allow(unused)]
 pub fn create_mock_transaction(value_to_send: u32, value_to_receive: u32) -> (Transaction, String) {
     let (mut signing_key, _, sender, receiver) = generate_key_pairs().unwrap();
 
@@ -77,8 +93,9 @@ pub fn create_mock_transaction(value_to_send: u32, value_to_receive: u32) -> (Tr
         .add_outputs(output_utxo, &mut signing_key)
         .unwrap();
 
-    let sender_hash = blake3::hash(&sender);
-    let signature = signing_key.sign(sender_hash.as_bytes()).to_bytes();
+    let signature = transaction
+        .sign_input(0, SigHash::All, &signing_key)
+        .unwrap();
 
     let unlocking_script = format!("{} {}", hex::encode(signature), hex::encode(sender));
 
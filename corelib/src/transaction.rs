@@ -1,60 +1,385 @@
 use borsh::{BorshDeserialize, BorshSerialize};
 use ed25519_dalek::{ed25519::signature::SignerMut, Signature, SigningKey, VerifyingKey};
+use imbl::HashMap as SharedMap;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone, BorshSerialize, BorshDeserialize, PartialEq, Eq)]
-#[borsh(use_discriminant = true)]
-pub enum SupportedVersions {
-    One = 1,
-}
-
 use crate::{
+    config::{block_subsidy, LOCKTIME_THRESHOLD, MAX_SCRIPT_SIZE, MAX_TX_SIGOPS},
+    deployment::Rules,
     errors::{Error, Result},
-    utxo::UTXO,
+    fee::FeeRate,
+    metrics::METRICS,
+    script::{
+        check_signature, sign_digest, Script, SigHash, SigHashes, SpendContext, SIGNATURE_LEN,
+    },
+    utxo::{OutPoint, UTXO},
 };
 
+// Version of the transactions this node creates
+pub const TX_VERSION: u8 = 1;
+// Latest version whose rules this node knows. Later versions append fields
+// this node can't interpret, it keeps them as an opaque extension so the
+// transactions can still be validated by the known rules and relayed
+pub const MAX_TX_VERSION: u8 = 1;
+
+// Longest tag a miner can put in a coinbase. It takes the bytes of the
+// sender after the block height, so it's covered by the txid
+pub const MAX_COINBASE_TAG: usize = 24;
+
+// Serialized size of the fixed-size fields of a transaction
+pub const BASE_SIZE: usize = 32 // hash_id
+    + 1 // version
+    + 32 // sender
+    + 32 // receiver
+    + 16 // timestamp
+    + 8 // locktime
+    + 64 // signature
+    + 4 // inputs length
+    + 4 // outputs length
+    + 4; // unlocking scripts length
+
 #[allow(unused)]
-#[derive(Debug, Clone, BorshSerialize, BorshDeserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Transaction {
+    #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
     pub hash_id: [u8; 32],
-    pub version: SupportedVersions,
+    pub version: u8,
+    #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
     pub sender: [u8; 32],
+    #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
     pub receiver: [u8; 32],
     pub timestamp: u128,
+    // Earliest block height, or block time in milliseconds from
+    // `LOCKTIME_THRESHOLD` on, the transaction can be included at. Zero for
+    // no lock
+    pub locktime: u64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
     pub signature: [u8; 64],
     // For newly minted coins there will be no inputs
     pub inputs: Vec<UTXO>,
     pub outputs: Vec<UTXO>,
+    // Script unlocking each input, in the order of the inputs. The txid and
+    // the signatures of the inputs can't cover them, the wtxid does
+    pub unlocking_scripts: Vec<String>,
+    // Fields appended by versions after the first, length prefixed so nodes
+    // that don't know the version can skip them. Always empty for version 1
+    #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
+    pub extension: Vec<u8>,
+}
+
+impl BorshSerialize for Transaction {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.hash_id.serialize(writer)?;
+        self.version.serialize(writer)?;
+        self.sender.serialize(writer)?;
+        self.receiver.serialize(writer)?;
+        self.timestamp.serialize(writer)?;
+        self.locktime.serialize(writer)?;
+        self.signature.serialize(writer)?;
+        self.inputs.serialize(writer)?;
+        self.outputs.serialize(writer)?;
+        self.unlocking_scripts.serialize(writer)?;
+
+        // Version 1 transactions end with their unlocking scripts
+        if self.version > 1 {
+            self.extension.serialize(writer)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl BorshDeserialize for Transaction {
+    fn deserialize_reader<R: std::io::Read>(reader: &mut R) -> std::io::Result<Self> {
+        let hash_id = <[u8; 32]>::deserialize_reader(reader)?;
+        let version = u8::deserialize_reader(reader)?;
+        let sender = <[u8; 32]>::deserialize_reader(reader)?;
+        let receiver = <[u8; 32]>::deserialize_reader(reader)?;
+        let timestamp = u128::deserialize_reader(reader)?;
+        let locktime = u64::deserialize_reader(reader)?;
+        let signature = <[u8; 64]>::deserialize_reader(reader)?;
+        let inputs = Vec::deserialize_reader(reader)?;
+        let outputs = Vec::deserialize_reader(reader)?;
+        let unlocking_scripts = Vec::deserialize_reader(reader)?;
+        let extension = match version {
+            0 | 1 => Vec::new(),
+            _ => Vec::deserialize_reader(reader)?,
+        };
+
+        Ok(Self {
+            hash_id,
+            version,
+            sender,
+            receiver,
+            timestamp,
+            locktime,
+            signature,
+            inputs,
+            outputs,
+            unlocking_scripts,
+            extension,
+        })
+    }
 }
 
 impl Transaction {
     pub fn new(signing_key: &mut SigningKey, receiver: [u8; 32]) -> Result<Self> {
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
 
+        Ok(Self::with_timestamp(signing_key, receiver, timestamp))
+    }
+
+    // Creates a transaction stamped with the given milliseconds since the unix
+    // epoch, for targets without a system clock such as wasm in the browser
+    pub fn with_timestamp(
+        signing_key: &mut SigningKey,
+        receiver: [u8; 32],
+        timestamp: u128,
+    ) -> Self {
         let sender = signing_key.verifying_key().to_bytes();
 
         let mut txn = Self {
             hash_id: [0u8; 32],
-            version: SupportedVersions::One,
+            version: TX_VERSION,
             sender,
             receiver,
             timestamp,
+            locktime: 0,
             signature: [0u8; 64],
             inputs: vec![],
             outputs: vec![],
+            unlocking_scripts: Vec::new(),
+            extension: Vec::new(),
         };
 
         txn.calculate_hash(signing_key);
 
+        txn
+    }
+
+    // Creates the transaction paying the block subsidy and the fees of the
+    // block's other transactions to the miner.
+    //
+    // A coinbase has no sender to sign it, the sender field carries the block
+    // height instead so coinbases of different blocks never share a hash,
+    // followed by the miner's tag if any, see `set_coinbase_tag`
+    pub fn coinbase(miner_pubkey: [u8; 32], block_height: u64, fees: u64) -> Result<Self> {
+        Self::coinbase_paying(
+            miner_pubkey,
+            block_height,
+            block_subsidy(block_height).saturating_add(fees),
+        )
+    }
+
+    // Coinbase paying `reward` to the miner, for chains whose subsidy differs
+    // from the default one
+    pub fn coinbase_paying(miner_pubkey: [u8; 32], block_height: u64, reward: u64) -> Result<Self> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+
+        let mut sender = [0u8; 32];
+        sender[..8].copy_from_slice(&block_height.to_le_bytes());
+
+        let mut txn = Self {
+            hash_id: [0u8; 32],
+            version: TX_VERSION,
+            sender,
+            receiver: miner_pubkey,
+            timestamp,
+            locktime: 0,
+            signature: [0u8; 64],
+            inputs: vec![],
+            outputs: vec![UTXO::new(reward, 0, miner_pubkey)?],
+            unlocking_scripts: Vec::new(),
+            extension: Vec::new(),
+        };
+        txn.hash_id = txn.compute_hash();
+
         Ok(txn)
     }
 
+    // Coinbase of a genesis block, stamped with a fixed time instead of the
+    // current one so every node builds the same block. Its subsidy goes to
+    // the all zero key, which no one can sign for
+    pub(crate) fn genesis_coinbase(timestamp: u128, tag: &[u8]) -> Result<Self> {
+        let mut txn = Self::coinbase([0u8; 32], 0, 0)?;
+        txn.timestamp = timestamp;
+        txn.set_coinbase_tag(tag)?;
+
+        Ok(txn)
+    }
+
+    // Tags the coinbase with up to `MAX_COINBASE_TAG` bytes of the miner's
+    // choice, e.g. a pool name. Only its size is checked
+    pub fn set_coinbase_tag(&mut self, tag: &[u8]) -> Result<()> {
+        if tag.len() > MAX_COINBASE_TAG {
+            return Err(Error::CoinbaseTagTooLong(tag.len()));
+        }
+        if !self.is_coinbase() {
+            return Err(Error::UnAuthorized);
+        }
+
+        self.sender[8..].fill(0);
+        self.sender[8..8 + tag.len()].copy_from_slice(tag);
+        self.hash_id = self.compute_hash();
+        Ok(())
+    }
+
+    // Tag the miner put in the coinbase, trailing zero bytes left out. None
+    // for other transactions and untagged coinbases
+    pub fn coinbase_tag(&self) -> Option<&[u8]> {
+        if !self.is_coinbase() {
+            return None;
+        }
+
+        let tag = &self.sender[8..];
+        let len = tag.iter().rposition(|byte| *byte != 0)? + 1;
+        Some(&tag[..len])
+    }
+
+    // Locks the transaction until the block height or time, see `locktime`
+    pub fn set_locktime(&mut self, locktime: u64, signing_key: &mut SigningKey) {
+        self.locktime = locktime;
+        self.calculate_hash(signing_key);
+    }
+
+    // Whether the transaction can be included in the block at `height` mined
+    // at `time`, in milliseconds since the unix epoch
+    pub fn is_final(&self, height: u64, time: u128) -> bool {
+        match self.locktime {
+            0 => true,
+            locktime if locktime < LOCKTIME_THRESHOLD => locktime <= height,
+            locktime => locktime as u128 <= time,
+        }
+    }
+
+    // Id blocks and the mempool refer to the transaction by. It covers
+    // everything but the signature, so re-signing doesn't change it
+    pub fn txid(&self) -> [u8; 32] {
+        self.hash_id
+    }
+
+    // Id covering the signature and the unlocking scripts as well, tells
+    // apart copies of a transaction that only differ in them
+    pub fn wtxid(&self) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.hash_id);
+        hasher.update(&self.signature);
+        for script in self.unlocking_scripts.iter() {
+            hasher.update(&(script.len() as u32).to_le_bytes());
+            hasher.update(script.as_bytes());
+        }
+
+        *hasher.finalize().as_bytes()
+    }
+
+    // Transactions without inputs mint new coins
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    // Total value of the outputs, `u64::MAX` if they add up to more
+    pub fn output_value(&self) -> u64 {
+        total_value(&self.outputs).unwrap_or(u64::MAX)
+    }
+
+    // Inputs minus outputs according to the input values carried by the
+    // transaction, zero for coinbases, overspending transactions and values
+    // adding up to more than a `u64`
+    pub fn declared_fee(&self) -> u64 {
+        match (total_value(&self.inputs), total_value(&self.outputs)) {
+            (Ok(input), Ok(output)) => input.saturating_sub(output),
+            _ => 0,
+        }
+    }
+
+    // Signature operations verifying the transaction's inputs with its
+    // unlocking scripts costs
+    pub fn sigops(&self) -> usize {
+        self.spending_sigops(&self.unlocking_scripts)
+    }
+
+    // Signature operations verifying the inputs with the given unlocking
+    // scripts costs, redeem scripts of pay-to-script-hash inputs included
+    pub fn spending_sigops<S: AsRef<str>>(&self, unlocking_scripts: &[S]) -> usize {
+        self.inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| {
+                input.spending_sigops(unlocking_scripts.get(i).map_or("", AsRef::as_ref))
+            })
+            .sum()
+    }
+
+    // Checks the sizes of the scripts of the inputs and their signature
+    // operations with the given unlocking scripts are within the consensus
+    // limits
+    pub fn check_script_limits<S: AsRef<str>>(&self, unlocking_scripts: &[S]) -> Result<()> {
+        if let Some(script) = self
+            .inputs
+            .iter()
+            .filter_map(UTXO::script_pubkey)
+            .find(|script| script.len() > MAX_SCRIPT_SIZE)
+        {
+            return Err(Error::ScriptTooLarge(script.len()));
+        }
+
+        let sigops = self.spending_sigops(unlocking_scripts);
+        if sigops > MAX_TX_SIGOPS {
+            return Err(Error::TooManySigops(sigops));
+        }
+
+        Ok(())
+    }
+
+    // Outputs of earlier transactions spent by this transaction
+    pub fn spent_outpoints(&self) -> impl Iterator<Item = OutPoint> + '_ {
+        self.inputs.iter().filter_map(UTXO::outpoint)
+    }
+
+    // Inputs minus outputs with the input values looked up in the UTXO set
+    pub fn fee(&self, utxo_set: &SharedMap<OutPoint, UTXO>) -> Result<u64> {
+        let spent = self
+            .inputs
+            .iter()
+            .map(|input| {
+                input
+                    .outpoint()
+                    .and_then(|outpoint| utxo_set.get(&outpoint))
+                    .cloned()
+                    .ok_or(Error::UnknownUTXO)
+            })
+            .collect::<Result<Vec<UTXO>>>()?;
+
+        self.fee_spending(&spent)
+    }
+
+    // Value of the outputs spent minus the outputs, the spent outputs as
+    // stored rather than as the inputs declare them
+    pub fn fee_spending(&self, spent: &[UTXO]) -> Result<u64> {
+        total_value(spent)?
+            .checked_sub(total_value(&self.outputs)?)
+            .ok_or(Error::InsufficientFunds)
+    }
+
+    // Fee rate paid for the serialized size, the fee looked up in the UTXO
+    // set
+    pub fn fee_per_byte(&self, utxo_set: &SharedMap<OutPoint, UTXO>) -> Result<FeeRate> {
+        FeeRate::new(self.fee(utxo_set)?, self.serialized_size() as u64)
+    }
+
     fn calculate_hash(&mut self, signing_key: &mut SigningKey) {
+        self.hash_id = self.compute_hash();
+        self.signature = signing_key.sign(&self.hash_id).to_bytes();
+    }
+
+    fn compute_hash(&self) -> [u8; 32] {
         let mut serialized = Vec::new();
 
         serialized.extend(&self.sender);
         serialized.extend(&self.receiver);
         serialized.extend(&self.timestamp.to_le_bytes());
+        serialized.extend(&self.locktime.to_le_bytes());
 
         for input in self.inputs.iter() {
             serialized.extend(input.to_bytes())
@@ -63,8 +388,90 @@ impl Transaction {
         for output in self.outputs.iter() {
             serialized.extend(output.to_bytes())
         }
-        self.hash_id = *blake3::hash(serialized.as_slice()).as_bytes();
-        self.signature = signing_key.sign(&self.hash_id).to_bytes();
+
+        // Ids of version 1 transactions predate the version field
+        if self.version > 1 {
+            serialized.push(self.version);
+            serialized.extend(&self.extension);
+        }
+        METRICS.record_hashed(serialized.len());
+        *blake3::hash(serialized.as_slice()).as_bytes()
+    }
+
+    // Digest a signature of the given type over input `input` signs: the
+    // sighash type, the sender, receiver and locks, every input, and the
+    // outputs the type commits to. Only signatures that don't commit to
+    // every output commit to their input's index, the others are valid for
+    // every input owned by the same key. None for `SigHash::Single` without
+    // an output at the input's index.
+    pub fn signature_hash(&self, input: usize, sighash: SigHash) -> Option<[u8; 32]> {
+        if input >= self.inputs.len() {
+            return None;
+        }
+
+        let mut serialized = vec![sighash as u8];
+        serialized.extend(&self.sender);
+        serialized.extend(&self.receiver);
+        serialized.extend(&self.timestamp.to_le_bytes());
+        serialized.extend(&self.locktime.to_le_bytes());
+
+        for input in self.inputs.iter() {
+            serialized.extend(input.to_bytes())
+        }
+
+        match sighash {
+            SigHash::All => {
+                for output in self.outputs.iter() {
+                    serialized.extend(output.to_bytes())
+                }
+            }
+            SigHash::None => serialized.extend(&(input as u32).to_le_bytes()),
+            SigHash::Single => {
+                serialized.extend(&(input as u32).to_le_bytes());
+                serialized.extend(self.outputs.get(input)?.to_bytes());
+            }
+        }
+
+        if self.version > 1 {
+            serialized.push(self.version);
+            serialized.extend(&self.extension);
+        }
+
+        METRICS.record_hashed(serialized.len());
+        Some(*blake3::hash(serialized.as_slice()).as_bytes())
+    }
+
+    // Digests of every sighash type for input `input`, what its unlocking
+    // script's signatures are checked against
+    pub fn sighashes(&self, input: usize) -> SigHashes {
+        SigHashes {
+            all: self.signature_hash(input, SigHash::All),
+            none: self.signature_hash(input, SigHash::None),
+            single: self.signature_hash(input, SigHash::Single),
+        }
+    }
+
+    // Signature for the unlocking script of input `input`. Inputs and
+    // outputs have to be added first, changing what the sighash type
+    // commits to invalidates it.
+    pub fn sign_input(
+        &self,
+        input: usize,
+        sighash: SigHash,
+        signing_key: &SigningKey,
+    ) -> Result<[u8; SIGNATURE_LEN]> {
+        let digest = self
+            .signature_hash(input, sighash)
+            .ok_or(Error::InvalidSigHash(input))?;
+
+        Ok(sign_digest(signing_key, &digest, sighash))
+    }
+
+    // Attaches the scripts unlocking each input, in the order of the inputs,
+    // once every input and output is added. Neither the txid nor the
+    // sender's signature change
+    pub fn set_unlocking_scripts(&mut self, unlocking_scripts: Vec<String>) {
+        self.unlocking_scripts = unlocking_scripts;
     }
 
     pub fn add_inputs(
@@ -72,7 +479,7 @@ impl Transaction {
         new_inputs: Vec<UTXO>,
         signing_key: &mut SigningKey,
     ) -> Result<()> {
-        if new_inputs.iter().any(|u| matches!(u, UTXO::Pending { .. })) {
+        if new_inputs.iter().any(UTXO::is_pending) {
             return Err(Error::PendingUTXO);
         }
 
@@ -112,44 +519,94 @@ impl Transaction {
     // This verifies the sender holds sufficient funds to carry out the
     // transaction.
     // It also checks that the transaction was initiated by the rightful owner as well
-    // as the ownership of the inputs are also verified
+    // as the ownership of the inputs are also verified, all of them with the
+    // same unlocking script
     pub fn verify(&self, unlocking_script: &str) -> Result<(u64, u64, u64)> {
-        let pub_key = VerifyingKey::from_bytes(&self.sender)?;
+        self.verify_inputs(&vec![unlocking_script; self.inputs.len()])
+    }
 
-        // Check if any inputs are unfonfirmed yet, and sum them
-        let input: u64 = self
-            .inputs
-            .iter()
-            .map(|utxo| match utxo {
-                UTXO::Confirmed { value, .. } => Ok(*value),
-                UTXO::Pending { .. } => Err(Error::PendingUTXO),
-            })
-            .collect::<Result<Vec<u64>>>()?
-            .iter()
-            .sum();
+    // Same as `verify` with a separate unlocking script for every input, for
+    // transactions spending outputs with different locking scripts or owned
+    // by different keys. Every owner signs its own input, the sender's
+    // signature only authorizes the transaction as a whole. Without
+    // a height no block confirmed the outputs yet, relative timelocks fail.
+    // Every deployed rule this node knows is enforced.
+    pub fn verify_inputs<S: AsRef<str>>(&self, unlocking_scripts: &[S]) -> Result<(u64, u64, u64)> {
+        self.verify_inputs_at(unlocking_scripts, 0, Rules::ALL)
+    }
 
-        // Check if any outputs are confirmed already, and sum them
-        let output: u64 = self
-            .outputs
-            .iter()
-            .map(|utxo| match utxo {
-                UTXO::Pending { value, .. } => Ok(*value),
-                UTXO::Confirmed { .. } => Err(Error::ConfirmedUTXO),
-            })
-            .collect::<Result<Vec<u64>>>()?
+    // Same as `verify_inputs` with the relative timelocks of the outputs
+    // checked as if the transaction was included in the block at `height`,
+    // under the rules active there. The outputs spent are taken as the
+    // inputs declare them, nodes check transactions against their UTXO set
+    // with `verify_spending`
+    pub fn verify_inputs_at<S: AsRef<str>>(
+        &self,
+        unlocking_scripts: &[S],
+        height: u64,
+        rules: Rules,
+    ) -> Result<(u64, u64, u64)> {
+        self.verify_spending(&self.inputs, unlocking_scripts, height, rules)
+    }
+
+    // Same as `verify_inputs_at` against the outputs the inputs spend as
+    // stored in the UTXO set, in the order of the inputs. The input values,
+    // locking scripts and confirmation heights are the stored ones, inputs
+    // declaring another output than the one they spend fail
+    pub fn verify_spending<S: AsRef<str>>(
+        &self,
+        spent: &[UTXO],
+        unlocking_scripts: &[S],
+        height: u64,
+        rules: Rules,
+    ) -> Result<(u64, u64, u64)> {
+        let pub_key = VerifyingKey::from_bytes(&self.sender)?;
+
+        if self.compute_hash() != self.hash_id {
+            return Err(Error::TxidMismatch);
+        }
+        self.check_version()?;
+        if unlocking_scripts.len() != self.inputs.len() {
+            return Err(Error::InvalidUnlockingScript);
+        }
+        if let Some(script) = unlocking_scripts
             .iter()
-            .sum();
+            .map(AsRef::as_ref)
+            .find(|script| script.len() > MAX_SCRIPT_SIZE)
+        {
+            return Err(Error::ScriptTooLarge(script.len()));
+        }
 
-        if output > input {
-            return Err(Error::InsufficientFunds);
+        // Check if any inputs are unfonfirmed yet, and that they are the
+        // outputs spent
+        if self.inputs.iter().any(UTXO::is_pending) {
+            return Err(Error::PendingUTXO);
+        }
+        if spent.len() != self.inputs.len() {
+            return Err(Error::UnknownUTXO);
         }
+        if let Some(input) = (0..spent.len()).find(|&i| !spent[i].same_output(&self.inputs[i])) {
+            return Err(Error::InputMismatch(input));
+        }
+        self.check_script_limits(unlocking_scripts)?;
+        let input = total_value(spent)?;
 
-        // Subtract only if input >= output to prevent subtraction overflow
-        let fee = input - output;
+        // Check if any outputs are confirmed already, and sum them
+        if !self.outputs.iter().all(UTXO::is_pending) {
+            return Err(Error::ConfirmedUTXO);
+        }
+        let output = total_value(&self.outputs)?;
+        let fee = self.fee_spending(spent)?;
 
-        // Unlock the utxo using the unlocking script
-        for utxo in self.inputs.iter() {
-            utxo.unlock(unlocking_script)?;
+        // Unlock the spent outputs using the unlocking scripts
+        for (input, (utxo, unlocking_script)) in spent.iter().zip(unlocking_scripts).enumerate() {
+            let context = SpendContext {
+                locktime: self.locktime,
+                confirmations: utxo.confirmations_at(height),
+                sighashes: self.sighashes(input),
+                rules,
+            };
+            utxo.unlock(unlocking_script.as_ref(), &context)?;
         }
 
         let signature: Signature = Signature::from_bytes(&self.signature);
@@ -161,36 +618,169 @@ impl Transaction {
         Ok((input, output, fee))
     }
 
-    pub fn size(&self) -> usize {
-        let mut size: usize = 0;
+    // Versions this node knows are checked by their own rules. Later versions
+    // are held to the rules of the latest known one, their extension is left
+    // for the nodes that know them
+    pub fn check_version(&self) -> Result<()> {
+        match self.version {
+            0 => Err(Error::UnsupportedTxVersion(0)),
+            1 if !self.extension.is_empty() => Err(Error::UnexpectedExtension(1)),
+            _ => Ok(()),
+        }
+    }
+
+    // Size of the Borsh encoding, the canonical serialization of transactions
+    pub fn serialized_size(&self) -> usize {
+        borsh::object_length(self).expect("transactions are always serializable")
+    }
+
+    // Collects the signatures of several cosigners of a multisig script
+    // spent by input `input` at once, when all of their keys are at hand
+    pub fn sign_multisig(
+        &self,
+        input: usize,
+        sighash: SigHash,
+        script: &Script,
+        signing_keys: &[SigningKey],
+    ) -> Result<MultisigSignatures> {
+        let mut signatures = MultisigSignatures::new(script, self, input, sighash)?;
+        for signing_key in signing_keys {
+            signatures.sign(signing_key)?;
+        }
+
+        Ok(signatures)
+    }
+}
+
+// Sum of the values, fails instead of wrapping around
+fn total_value<'a>(utxos: impl IntoIterator<Item = &'a UTXO>) -> Result<u64> {
+    utxos.into_iter().try_fold(0u64, |total, utxo| {
+        total.checked_add(utxo.value()).ok_or(Error::ValueOverflow)
+    })
+}
+
+// Signatures of the keys of an M-of-N multisig script, gathered one cosigner
+// at a time. It's Borsh encoded to pass it on to the next cosigner until
+// enough of them signed to spend the output. Cosigners sign the digest of
+// the input spending it, so they all sign the same transaction.
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct MultisigSignatures {
+    required: u8,
+    public_keys: Vec<[u8; 32]>,
+    sighash: SigHash,
+    digest: [u8; 32],
+    // Signature of the key at the same position, once it signed
+    signatures: Vec<Option<[u8; SIGNATURE_LEN]>>,
+}
+
+impl MultisigSignatures {
+    pub fn new(
+        script: &Script,
+        transaction: &Transaction,
+        input: usize,
+        sighash: SigHash,
+    ) -> Result<Self> {
+        let (required, public_keys) = script
+            .multisig_keys()
+            .ok_or_else(|| Error::InvalidScript("not a multisig script".to_string()))?;
+        let digest = transaction
+            .signature_hash(input, sighash)
+            .ok_or(Error::InvalidSigHash(input))?;
+
+        Ok(Self {
+            required: required as u8,
+            signatures: vec![None; public_keys.len()],
+            public_keys,
+            sighash,
+            digest,
+        })
+    }
+
+    pub fn sign(&mut self, signing_key: &SigningKey) -> Result<()> {
+        let public_key = signing_key.verifying_key().to_bytes();
+        let signature = sign_digest(signing_key, &self.digest, self.sighash);
+
+        self.add(public_key, signature)
+    }
+
+    // Adds the signature of a cosigner who signed elsewhere
+    pub fn add(&mut self, public_key: [u8; 32], signature: [u8; SIGNATURE_LEN]) -> Result<()> {
+        let position = self
+            .public_keys
+            .iter()
+            .position(|key| *key == public_key)
+            .ok_or(Error::OwnerMismatch)?;
+        let sighashes = SigHashes::only(self.sighash, self.digest);
+        if !check_signature(&public_key, &signature, &sighashes) {
+            return Err(Error::InvalidSignature);
+        }
+
+        self.signatures[position] = Some(signature);
+        Ok(())
+    }
+
+    // Number of keys that signed so far
+    pub fn signed(&self) -> usize {
+        self.signatures.iter().flatten().count()
+    }
 
-        // Fixed-size fields
-        size += 32; // hash_id
-        size += 1; // version
-        size += 32; // sender
-        size += 32; // receiver
-        size += 16; // timestamp
-        size += 64; // signature
+    pub fn is_complete(&self) -> bool {
+        self.signed() >= self.required as usize
+    }
 
-        // Variable-size fields
-        size += self.inputs.iter().map(|utxo| utxo.size()).sum::<usize>();
-        size += self.outputs.iter().map(|utxo| utxo.size()).sum::<usize>();
+    pub fn script(&self) -> Result<Script> {
+        Script::multisig(self.required as usize, &self.public_keys)
+    }
+
+    // Signatures spending the multisig script, the first `required` ones in
+    // the order of the keys
+    pub fn unlocking_script(&self) -> Result<String> {
+        if !self.is_complete() {
+            return Err(Error::InvalidUnlockingScript);
+        }
 
-        size
+        Ok(self
+            .signatures
+            .iter()
+            .flatten()
+            .take(self.required as usize)
+            .map(hex::encode)
+            .collect::<Vec<_>>()
+            .join(" "))
+    }
+
+    // Unlocking script of an output paid to the hash of the multisig script,
+    // the signatures followed by the script itself
+    pub fn script_hash_unlocking_script(&self) -> Result<String> {
+        Ok(format!(
+            "{} {}",
+            self.unlocking_script()?,
+            hex::encode(self.script()?.to_string())
+        ))
     }
 }
 
 #[cfg(test)]
 mod test {
+    use imbl::HashMap as SharedMap;
 
-    use ed25519_dalek::ed25519::signature::SignerMut;
+    use ed25519_dalek::SigningKey;
 
     use crate::{
+        config::{LOCKTIME_THRESHOLD, MAX_SCRIPT_SIZE, MAX_TX_SIGOPS, MULTISIG_SIGOPS},
+        deployment::Rules,
         errors::Error,
-        test_utils::{generate_key_pairs, generate_random_utxos},
+        fee::FeeRate,
+        test_utils::{create_mock_transaction, generate_key_pairs, generate_random_utxos},
     };
 
-    use super::Transaction;
+    use super::{
+        MultisigSignatures, Transaction, BASE_SIZE, MAX_COINBASE_TAG, MAX_TX_VERSION, TX_VERSION,
+    };
+    use crate::{
+        script::{Script, SigHash, SpendContext},
+        utxo::UTXO,
+    };
 
     #[test]
     fn create_and_verify_txn() {
@@ -212,8 +802,9 @@ mod test {
             .add_inputs(input_utxo, &mut signing_key)
             .unwrap();
 
-        let sender_hash = blake3::hash(&sender);
-        let signature = signing_key.sign(sender_hash.as_bytes()).to_bytes();
+        let signature = transaction
+            .sign_input(0, SigHash::All, &signing_key)
+            .unwrap();
 
         let unlocking_script = format!("{} {}", hex::encode(signature), hex::encode(sender));
 
@@ -222,6 +813,102 @@ mod test {
         assert_eq!(fee, 10)
     }
 
+    #[test]
+    fn txid_excludes_the_signature() {
+        let (transaction, unlocking_script) = create_mock_transaction(1_000, 900);
+        assert_eq!(transaction.txid(), transaction.hash_id);
+
+        let mut resigned = transaction.clone();
+        resigned.signature[0] ^= 1;
+        assert_eq!(resigned.txid(), transaction.txid());
+        assert_ne!(resigned.wtxid(), transaction.wtxid());
+        assert!(matches!(
+            resigned.verify(&unlocking_script),
+            Err(Error::UnAuthorized)
+        ));
+
+        // Changing the contents without a new id is caught
+        let mut tampered = transaction;
+        tampered.timestamp += 1;
+        assert!(matches!(
+            tampered.verify(&unlocking_script),
+            Err(Error::TxidMismatch)
+        ));
+    }
+
+    #[test]
+    fn tags_coinbases_within_the_size_limit() {
+        let mut coinbase = Transaction::coinbase([1u8; 32], 7, 0).unwrap();
+        assert_eq!(coinbase.coinbase_tag(), None);
+        let untagged = coinbase.hash_id;
+
+        coinbase.set_coinbase_tag(b"/aurelius pool/").unwrap();
+        assert_eq!(coinbase.coinbase_tag(), Some(&b"/aurelius pool/"[..]));
+        assert_ne!(coinbase.hash_id, untagged);
+        // The height stays in front of the tag
+        assert_eq!(coinbase.sender[..8], 7u64.to_le_bytes());
+
+        assert!(matches!(
+            coinbase.set_coinbase_tag(&[1u8; MAX_COINBASE_TAG + 1]),
+            Err(Error::CoinbaseTagTooLong(_))
+        ));
+        let (mut txn, _) = create_mock_transaction(1_000, 900);
+        assert!(txn.set_coinbase_tag(b"pool").is_err());
+        assert_eq!(txn.coinbase_tag(), None);
+    }
+
+    #[test]
+    fn relays_later_versions_and_commits_to_their_extension() {
+        let (mut signing_key, _, sender, receiver) = generate_key_pairs().unwrap();
+        let mut transaction = Transaction::new(&mut signing_key, receiver).unwrap();
+        let (input_utxo, output_utxo) = generate_random_utxos(sender, 1_000, 900).unwrap();
+        transaction
+            .add_inputs(input_utxo, &mut signing_key)
+            .unwrap();
+        transaction
+            .add_outputs(output_utxo, &mut signing_key)
+            .unwrap();
+
+        assert_eq!(transaction.version, TX_VERSION);
+        let v1_id = transaction.txid();
+
+        let mut later = transaction.clone();
+        later.version = MAX_TX_VERSION + 1;
+        later.extension = vec![1, 2, 3];
+        later.calculate_hash(&mut signing_key);
+        assert_ne!(later.txid(), v1_id);
+
+        // Only later versions carry the length prefixed extension
+        let encoded = borsh::to_vec(&later).unwrap();
+        assert_eq!(encoded.len(), transaction.serialized_size() + 4 + 3);
+        let decoded = borsh::from_slice::<Transaction>(&encoded).unwrap();
+        assert_eq!(decoded, later);
+
+        let signature = later.sign_input(0, SigHash::All, &signing_key).unwrap();
+        let unlocking_script = format!("{} {}", hex::encode(signature), hex::encode(sender));
+        assert_eq!(later.verify(&unlocking_script).unwrap().2, 100);
+
+        let mut tampered = later.clone();
+        tampered.extension[0] ^= 1;
+        assert!(matches!(
+            tampered.verify(&unlocking_script),
+            Err(Error::TxidMismatch)
+        ));
+
+        let mut extended = transaction.clone();
+        extended.extension = vec![1];
+        assert!(matches!(
+            extended.check_version(),
+            Err(Error::UnexpectedExtension(1))
+        ));
+        let mut unversioned = transaction;
+        unversioned.version = 0;
+        assert!(matches!(
+            unversioned.check_version(),
+            Err(Error::UnsupportedTxVersion(0))
+        ));
+    }
+
     #[test]
     fn fails_on_insufficient_funds() {
         let (mut signing_key, _, sender, receiver) = generate_key_pairs().unwrap();
@@ -241,8 +928,9 @@ mod test {
             .add_outputs(output_utxo, &mut signing_key)
             .unwrap();
 
-        let sender_hash = blake3::hash(&sender);
-        let signature = signing_key.sign(sender_hash.as_bytes()).to_bytes();
+        let signature = transaction
+            .sign_input(0, SigHash::All, &signing_key)
+            .unwrap();
 
         let unlocking_script = format!("{} {}", hex::encode(signature), hex::encode(sender));
 
@@ -252,6 +940,48 @@ mod test {
         ));
     }
 
+    #[test]
+    fn rejects_values_adding_up_past_u64() {
+        let mut signing_key = SigningKey::from_bytes(&[1u8; 32]);
+        let owner = signing_key.verifying_key().to_bytes();
+        let inputs = [u64::MAX, 2]
+            .into_iter()
+            .enumerate()
+            .map(|(index, value)| {
+                UTXO::new(value, index as u32, owner)
+                    .unwrap()
+                    .confirm_utxo([1u8; 32], 1, false)
+                    .unwrap()
+            })
+            .collect();
+        let mut transaction = Transaction::new(&mut signing_key, owner).unwrap();
+        transaction.add_inputs(inputs, &mut signing_key).unwrap();
+        transaction
+            .add_outputs(vec![UTXO::new(10, 0, owner).unwrap()], &mut signing_key)
+            .unwrap();
+
+        // Wrapped around, the inputs would only be worth 1
+        let signature = transaction
+            .sign_input(0, SigHash::All, &signing_key)
+            .unwrap();
+        let unlocking_script = format!("{} {}", hex::encode(signature), hex::encode(owner));
+        assert!(matches!(
+            transaction.verify(&unlocking_script),
+            Err(Error::ValueOverflow)
+        ));
+        assert_eq!(transaction.declared_fee(), 0);
+
+        let utxo_set: SharedMap<_, _> = transaction
+            .inputs
+            .iter()
+            .map(|utxo| (utxo.outpoint().unwrap(), utxo.clone()))
+            .collect();
+        assert!(matches!(
+            transaction.fee(&utxo_set),
+            Err(Error::ValueOverflow)
+        ));
+    }
+
     #[test]
     fn fails_on_wrong_sender() {
         let (mut s, mut signing_key, sender, receiver) = generate_key_pairs().unwrap();
@@ -267,8 +997,7 @@ mod test {
         transaction.add_inputs(input_utxo, &mut s).unwrap();
         transaction.add_outputs(output_utxo, &mut s).unwrap();
 
-        let sender_hash = blake3::hash(&sender);
-        let signature = s.sign(sender_hash.as_bytes()).to_bytes();
+        let signature = transaction.sign_input(0, SigHash::All, &s).unwrap();
 
         let unlocking_script = format!("{} {}", hex::encode(signature), hex::encode(sender));
 
@@ -277,4 +1006,468 @@ mod test {
             Err(Error::UnAuthorized)
         ))
     }
+
+    #[test]
+    fn fee_from_utxo_set_and_serialized_size() {
+        let (mut signing_key, _, sender, receiver) = generate_key_pairs().unwrap();
+
+        let mut transaction = Transaction::new(&mut signing_key, receiver).unwrap();
+        let (input_utxo, output_utxo) = generate_random_utxos(sender, 1_000, 990).unwrap();
+
+        transaction
+            .add_inputs(input_utxo.clone(), &mut signing_key)
+            .unwrap();
+        transaction
+            .add_outputs(output_utxo, &mut signing_key)
+            .unwrap();
+
+        let size = transaction.serialized_size();
+        assert_eq!(size, borsh::to_vec(&transaction).unwrap().len());
+        assert_eq!(
+            size,
+            BASE_SIZE
+                + transaction.inputs.iter().map(UTXO::size).sum::<usize>()
+                + transaction.outputs.iter().map(UTXO::size).sum::<usize>()
+        );
+        assert!(matches!(
+            transaction.fee(&SharedMap::new()),
+            Err(Error::UnknownUTXO)
+        ));
+
+        let utxo_set: SharedMap<_, _> = input_utxo
+            .into_iter()
+            .map(|utxo| (utxo.outpoint().unwrap(), utxo))
+            .collect();
+        assert_eq!(transaction.fee(&utxo_set).unwrap(), 10);
+        assert_eq!(
+            transaction.fee_per_byte(&utxo_set).unwrap(),
+            FeeRate::new(10, size as u64).unwrap()
+        );
+    }
+
+    #[test]
+    fn enforces_script_limits() {
+        let (mut signing_key, _, sender, receiver) = generate_key_pairs().unwrap();
+
+        let mut transaction = Transaction::new(&mut signing_key, receiver).unwrap();
+        let (input_utxo, output_utxo) = generate_random_utxos(sender, 1_000, 990).unwrap();
+
+        transaction
+            .add_inputs(input_utxo, &mut signing_key)
+            .unwrap();
+        transaction
+            .add_outputs(output_utxo, &mut signing_key)
+            .unwrap();
+
+        assert_eq!(transaction.sigops(), transaction.inputs.len());
+        assert!(transaction.check_script_limits(&[""]).is_ok());
+
+        let oversized = "0".repeat(MAX_SCRIPT_SIZE + 1);
+        assert!(matches!(
+            transaction.verify(&oversized),
+            Err(Error::ScriptTooLarge(_))
+        ));
+
+        // Each multisig check counts as the most keys it could check
+        let multisig = vec!["OP_CHECKMULTISIG"; MAX_TX_SIGOPS].join(" ");
+        if let UTXO::Confirmed { script_pubkey, .. } = &mut transaction.inputs[0] {
+            *script_pubkey = multisig;
+        }
+        assert!(matches!(
+            transaction.check_script_limits(&[""]),
+            Err(Error::ScriptTooLarge(_))
+        ));
+
+        let multisig = vec!["OP_CHECKMULTISIG"; MAX_TX_SIGOPS / MULTISIG_SIGOPS + 1].join(" ");
+        if let UTXO::Confirmed { script_pubkey, .. } = &mut transaction.inputs[0] {
+            *script_pubkey = multisig;
+        }
+        assert!(matches!(
+            transaction.check_script_limits(&[""]),
+            Err(Error::TooManySigops(_))
+        ));
+    }
+
+    #[test]
+    fn counts_the_sigops_of_redeem_scripts() {
+        let (mut signing_key, _, _, receiver) = generate_key_pairs().unwrap();
+        let keys = [[1u8; 32], [2u8; 32]];
+        let redeem_script = Script::multisig(2, &keys).unwrap();
+        let input = UTXO::new_multisig(1_000, 0, 2, &keys)
+            .unwrap()
+            .confirm_utxo([3u8; 32], 1, false)
+            .unwrap();
+
+        let mut transaction = Transaction::new(&mut signing_key, receiver).unwrap();
+        transaction
+            .add_inputs(vec![input], &mut signing_key)
+            .unwrap();
+
+        // The locking script only checks the hash of the redeem script, which
+        // checks the signatures
+        let unlocking_script = format!(
+            "{} {}",
+            hex::encode([0u8; 64]),
+            hex::encode(redeem_script.to_string())
+        );
+        assert_eq!(transaction.sigops(), 0);
+        assert_eq!(
+            transaction.spending_sigops(&[&unlocking_script]),
+            MULTISIG_SIGOPS
+        );
+        transaction.set_unlocking_scripts(vec![unlocking_script]);
+        assert_eq!(transaction.sigops(), MULTISIG_SIGOPS);
+
+        let redeem_script = vec!["OP_CHECKMULTISIG"; MAX_TX_SIGOPS / MULTISIG_SIGOPS + 1].join(" ");
+        assert!(matches!(
+            transaction.check_script_limits(&[hex::encode(redeem_script)]),
+            Err(Error::TooManySigops(_))
+        ));
+    }
+
+    #[test]
+    fn spends_multisig_outputs() {
+        let keys: Vec<SigningKey> = (1..=3u8)
+            .map(|i| SigningKey::from_bytes(&[i; 32]))
+            .collect();
+        let public_keys: Vec<[u8; 32]> = keys
+            .iter()
+            .map(|key| key.verifying_key().to_bytes())
+            .collect();
+        let script = Script::multisig(2, &public_keys).unwrap();
+        assert_eq!(script.multisig_keys(), Some((2, public_keys.clone())));
+        assert!(Script::multisig(4, &public_keys).is_err());
+
+        let input = UTXO::new_multisig(1_000, 0, 2, &public_keys)
+            .unwrap()
+            .confirm_utxo([1u8; 32], 1, false)
+            .unwrap();
+        let mut signing_key = keys[0].clone();
+        let mut transaction = Transaction::new(&mut signing_key, public_keys[1]).unwrap();
+        transaction
+            .add_inputs(vec![input], &mut signing_key)
+            .unwrap();
+        transaction
+            .add_outputs(
+                vec![UTXO::new(900, 0, public_keys[1]).unwrap()],
+                &mut signing_key,
+            )
+            .unwrap();
+
+        // Cosigners sign one after the other, passing the signatures along
+        let mut signatures =
+            MultisigSignatures::new(&script, &transaction, 0, SigHash::All).unwrap();
+        signatures.sign(&keys[2]).unwrap();
+        assert!(!signatures.is_complete());
+        assert!(signatures.unlocking_script().is_err());
+
+        let mut signatures: MultisigSignatures =
+            borsh::from_slice(&borsh::to_vec(&signatures).unwrap()).unwrap();
+        signatures.sign(&keys[0]).unwrap();
+        let unlocking_script = signatures.script_hash_unlocking_script().unwrap();
+        assert_eq!(
+            transaction.verify_inputs(&[unlocking_script]).unwrap(),
+            (1_000, 900, 100)
+        );
+
+        let (outsider, ..) = generate_key_pairs().unwrap();
+        assert!(matches!(
+            signatures.sign(&outsider),
+            Err(Error::OwnerMismatch)
+        ));
+
+        let signatures = transaction
+            .sign_multisig(0, SigHash::All, &script, &keys[1..])
+            .unwrap();
+        assert_eq!(signatures.signed(), 2);
+        assert!(transaction
+            .verify_inputs(&[signatures.script_hash_unlocking_script().unwrap()])
+            .is_ok());
+        assert!(transaction
+            .verify_inputs(&[signatures.unlocking_script().unwrap()])
+            .is_err());
+    }
+
+    #[test]
+    fn spends_script_hash_outputs_as_stored() {
+        let keys: Vec<SigningKey> = (1..=2u8)
+            .map(|i| SigningKey::from_bytes(&[i; 32]))
+            .collect();
+        let public_keys: Vec<[u8; 32]> = keys
+            .iter()
+            .map(|key| key.verifying_key().to_bytes())
+            .collect();
+        let script = Script::multisig(2, &public_keys).unwrap();
+        let spent = [UTXO::new_multisig(1_000, 0, 2, &public_keys)
+            .unwrap()
+            .confirm_utxo([1u8; 32], 1, false)
+            .unwrap()];
+
+        // One cosigner declaring the output paid to their own key alone
+        let mut signing_key = keys[0].clone();
+        let mut declared = spent[0].clone();
+        if let UTXO::Confirmed { script_pubkey, .. } = &mut declared {
+            *script_pubkey = Script::pay_to_pubkey_hash(&public_keys[0]).to_string();
+        }
+        let mut transaction = Transaction::new(&mut signing_key, public_keys[0]).unwrap();
+        transaction
+            .add_inputs(vec![declared], &mut signing_key)
+            .unwrap();
+        let signature = transaction
+            .sign_input(0, SigHash::All, &signing_key)
+            .unwrap();
+        let scripts = [format!(
+            "{} {}",
+            hex::encode(signature),
+            hex::encode(public_keys[0])
+        )];
+        assert!(transaction.verify_inputs(&scripts).is_ok());
+        assert!(matches!(
+            transaction.verify_spending(&spent, &scripts, 1, Rules::ALL),
+            Err(Error::InputMismatch(0))
+        ));
+
+        // Declared as stored it takes the redeem script and both signatures
+        let mut transaction = Transaction::new(&mut signing_key, public_keys[0]).unwrap();
+        transaction
+            .add_inputs(vec![spent[0].clone()], &mut signing_key)
+            .unwrap();
+        let signatures = transaction
+            .sign_multisig(0, SigHash::All, &script, &keys)
+            .unwrap();
+        let scripts = [signatures.script_hash_unlocking_script().unwrap()];
+        assert!(transaction
+            .verify_spending(&spent, &scripts, 1, Rules::ALL)
+            .is_ok());
+    }
+
+    #[test]
+    fn binds_signatures_to_inputs() {
+        let mut signing_key = SigningKey::from_bytes(&[1u8; 32]);
+        let owner = signing_key.verifying_key().to_bytes();
+        let inputs = (0..2u32)
+            .map(|index| {
+                UTXO::new(500, index, owner)
+                    .unwrap()
+                    .confirm_utxo([1u8; 32], 1, false)
+                    .unwrap()
+            })
+            .collect();
+        let mut transaction = Transaction::new(&mut signing_key, owner).unwrap();
+        transaction.add_inputs(inputs, &mut signing_key).unwrap();
+        transaction
+            .add_outputs(vec![UTXO::new(900, 0, owner).unwrap()], &mut signing_key)
+            .unwrap();
+
+        let unlocking_script = |sighash| {
+            let signature = transaction.sign_input(0, sighash, &signing_key).unwrap();
+            format!("{} {}", hex::encode(signature), hex::encode(owner))
+        };
+        let all = unlocking_script(SigHash::All);
+        let none = unlocking_script(SigHash::None);
+        let single = unlocking_script(SigHash::Single);
+
+        // Signatures over every output are valid for every input of the key,
+        // the others only for the input they signed
+        assert!(transaction.verify(&all).is_ok());
+        assert!(transaction.verify(&none).is_err());
+        assert!(transaction.verify_inputs(&[&none, &all]).is_ok());
+        assert!(transaction.verify_inputs(&[&single, &all]).is_ok());
+        assert!(matches!(
+            transaction.sign_input(1, SigHash::Single, &signing_key),
+            Err(Error::InvalidSigHash(1))
+        ));
+
+        // Paying someone else invalidates signatures over every output, and
+        // over the changed output
+        transaction.outputs[0] = UTXO::new(900, 0, [2u8; 32]).unwrap();
+        let context = SpendContext {
+            sighashes: transaction.sighashes(0),
+            ..SpendContext::default()
+        };
+        let input = &transaction.inputs[0];
+        assert!(input.unlock(&none, &context).is_ok());
+        assert!(input.unlock(&all, &context).is_err());
+        assert!(input.unlock(&single, &context).is_err());
+    }
+
+    #[test]
+    fn verifies_inputs_owned_by_different_keys() {
+        let mut sender = SigningKey::from_bytes(&[1u8; 32]);
+        let cosigner = SigningKey::from_bytes(&[2u8; 32]);
+        let owners = [sender.verifying_key(), cosigner.verifying_key()].map(|key| key.to_bytes());
+
+        let inputs = owners
+            .iter()
+            .map(|owner| {
+                UTXO::new(500, 0, *owner)
+                    .unwrap()
+                    .confirm_utxo(*owner, 1, false)
+                    .unwrap()
+            })
+            .collect();
+        let mut transaction = Transaction::new(&mut sender, owners[0]).unwrap();
+        transaction.add_inputs(inputs, &mut sender).unwrap();
+        transaction
+            .add_outputs(vec![UTXO::new(900, 0, owners[0]).unwrap()], &mut sender)
+            .unwrap();
+
+        let scripts: Vec<String> = [&sender, &cosigner]
+            .iter()
+            .enumerate()
+            .map(|(input, key)| {
+                let signature = transaction.sign_input(input, SigHash::All, key).unwrap();
+                format!(
+                    "{} {}",
+                    hex::encode(signature),
+                    hex::encode(key.verifying_key().to_bytes())
+                )
+            })
+            .collect();
+
+        assert_eq!(transaction.verify_inputs(&scripts).unwrap().2, 100);
+        // Each script only unlocks the input of its key
+        assert!(transaction.verify(&scripts[0]).is_err());
+        assert!(transaction
+            .verify_inputs(&[&scripts[1], &scripts[0]])
+            .is_err());
+        assert!(matches!(
+            transaction.verify_inputs(&scripts[..1]),
+            Err(Error::InvalidUnlockingScript)
+        ));
+
+        // Carried along with the transaction, covered by the wtxid only
+        let mut unlocked = transaction.clone();
+        unlocked.set_unlocking_scripts(scripts);
+        assert_eq!(unlocked.txid(), transaction.txid());
+        assert_ne!(unlocked.wtxid(), transaction.wtxid());
+        let decoded: Transaction = borsh::from_slice(&borsh::to_vec(&unlocked).unwrap()).unwrap();
+        assert_eq!(decoded, unlocked);
+        assert!(decoded.verify_inputs(&decoded.unlocking_scripts).is_ok());
+    }
+
+    #[test]
+    fn verifies_against_the_outputs_spent() {
+        let mut signing_key = SigningKey::from_bytes(&[1u8; 32]);
+        let owner = signing_key.verifying_key().to_bytes();
+        let stored = UTXO::new(1_000, 0, owner)
+            .unwrap()
+            .confirm_utxo([1u8; 32], 1, false)
+            .unwrap();
+
+        // Declared worth more than stored
+        let mut inflated = stored.clone();
+        if let UTXO::Confirmed { value, .. } = &mut inflated {
+            *value = 5_000;
+        }
+        let mut transaction = Transaction::new(&mut signing_key, owner).unwrap();
+        transaction
+            .add_inputs(vec![inflated], &mut signing_key)
+            .unwrap();
+        transaction
+            .add_outputs(vec![UTXO::new(4_000, 0, owner).unwrap()], &mut signing_key)
+            .unwrap();
+        let signature = transaction
+            .sign_input(0, SigHash::All, &signing_key)
+            .unwrap();
+        let scripts = [format!("{} {}", hex::encode(signature), hex::encode(owner))];
+
+        assert_eq!(transaction.verify_inputs(&scripts).unwrap().2, 1_000);
+        assert!(matches!(
+            transaction.verify_spending(&[stored], &scripts, 1, Rules::ALL),
+            Err(Error::InputMismatch(0))
+        ));
+        assert!(matches!(
+            transaction.verify_spending(&[], &scripts, 1, Rules::ALL),
+            Err(Error::UnknownUTXO)
+        ));
+    }
+
+    #[test]
+    fn counts_relative_timelocks_from_the_stored_output() {
+        let mut signing_key = SigningKey::from_bytes(&[1u8; 32]);
+        let owner = signing_key.verifying_key().to_bytes();
+        let script = Script::locked_for(3, Script::pay_to_pubkey_hash(&owner));
+        let output = UTXO::new_script_hash(1_000, 0, script.script_hash()).unwrap();
+        let stored = output.clone().confirm_utxo([1u8; 32], 10, false).unwrap();
+
+        // Declared confirmed long before the block that confirmed it
+        let forged = output.confirm_utxo([1u8; 32], 1, false).unwrap();
+        let mut transaction = Transaction::new(&mut signing_key, owner).unwrap();
+        transaction
+            .add_inputs(vec![forged], &mut signing_key)
+            .unwrap();
+        let signature = transaction
+            .sign_input(0, SigHash::All, &signing_key)
+            .unwrap();
+        let scripts = [format!(
+            "{} {} {}",
+            hex::encode(signature),
+            hex::encode(owner),
+            hex::encode(script.to_string())
+        )];
+
+        assert!(transaction
+            .verify_inputs_at(&scripts, 11, Rules::ALL)
+            .is_ok());
+        let spent = [stored];
+        assert!(matches!(
+            transaction.verify_spending(&spent, &scripts, 11, Rules::ALL),
+            Err(Error::Timelocked)
+        ));
+        assert!(transaction
+            .verify_spending(&spent, &scripts, 12, Rules::ALL)
+            .is_ok());
+    }
+
+    #[test]
+    fn enforces_locktimes() {
+        let mut signing_key = SigningKey::from_bytes(&[1u8; 32]);
+        let owner = signing_key.verifying_key().to_bytes();
+        let script = Script::locked_for(3, Script::pay_to_pubkey_hash(&owner));
+
+        let input = UTXO::new_script_hash(1_000, 0, script.script_hash())
+            .unwrap()
+            .confirm_utxo([1u8; 32], 10, false)
+            .unwrap();
+        let mut transaction = Transaction::new(&mut signing_key, owner).unwrap();
+        transaction
+            .add_inputs(vec![input], &mut signing_key)
+            .unwrap();
+        transaction
+            .add_outputs(vec![UTXO::new(900, 0, owner).unwrap()], &mut signing_key)
+            .unwrap();
+
+        let signature = transaction
+            .sign_input(0, SigHash::All, &signing_key)
+            .unwrap();
+        let unlocking_script = format!(
+            "{} {} {}",
+            hex::encode(signature),
+            hex::encode(owner),
+            hex::encode(script.to_string())
+        );
+        let scripts = [unlocking_script];
+        assert!(matches!(
+            transaction.verify_inputs_at(&scripts, 11, Rules::ALL),
+            Err(Error::Timelocked)
+        ));
+        assert!(transaction
+            .verify_inputs_at(&scripts, 12, Rules::ALL)
+            .is_ok());
+        assert!(transaction
+            .verify_inputs_at(&scripts, 11, Rules::NONE)
+            .is_ok());
+
+        assert!(transaction.is_final(0, 0));
+        let hash = transaction.hash_id;
+        transaction.set_locktime(20, &mut signing_key);
+        assert_ne!(transaction.hash_id, hash);
+        assert!(!transaction.is_final(19, u128::MAX));
+        assert!(transaction.is_final(20, 0));
+
+        transaction.set_locktime(LOCKTIME_THRESHOLD + 5, &mut signing_key);
+        assert!(!transaction.is_final(u64::MAX, LOCKTIME_THRESHOLD as u128));
+        assert!(transaction.is_final(0, LOCKTIME_THRESHOLD as u128 + 5));
+    }
 }
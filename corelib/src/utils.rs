@@ -9,7 +9,6 @@ pub fn convert_u8_to_u832(raw: &[u8]) -> Result<&[u8; 32]> {
     }
 }
 
-
 pub fn convert_u8_to_u864(raw: &[u8]) -> Result<&[u8; 64]> {
     if raw.len() != 64 {
         Err(Error::InvalidU8Length(raw.len()))
@@ -19,5 +18,27 @@ pub fn convert_u8_to_u864(raw: &[u8]) -> Result<&[u8; 64]> {
     }
 }
 
+// Byte arrays and vectors as hex strings in JSON, for
+// `#[serde(with = "crate::utils::hex_serde")]`
+#[cfg(feature = "serde")]
+pub mod hex_serde {
+    use hex::FromHex;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
 
+    pub fn serialize<S, T>(bytes: &T, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: AsRef<[u8]>,
+    {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
 
+    pub fn deserialize<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromHex,
+    {
+        let encoded = String::deserialize(deserializer)?;
+        T::from_hex(encoded).map_err(|_| D::Error::custom("expected hex of the right length"))
+    }
+}
@@ -1,15 +1,67 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use borsh::{BorshDeserialize, BorshSerialize};
-use ed25519_dalek::{Signature, VerifyingKey};
+use hex::FromHex;
 
 use crate::{
     errors::{Error, Result},
-    utils::{convert_u8_to_u832, convert_u8_to_u864},
+    script::{count_redeem_sigops, count_sigops, Script, SpendContext},
 };
 
+// Serialized size of a pending output: variant + `value` + `index` + `owner`
+pub const PENDING_SIZE: usize = 1 + 8 + 4 + 32;
+
+// Id of the output at `index` of the transaction once it's confirmed
+pub fn output_id(txn_hash: &[u8; 32], index: u32) -> [u8; 32] {
+    *blake3::hash(&[txn_hash.as_ref(), &index.to_le_bytes()].concat()).as_bytes()
+}
+
+// Reference to the output at index `vout` of transaction `txid`.
+//
+// Displayed as `<txid>:<vout>` with the txid hex encoded in the byte order it
+// is hashed in, without reversing it.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, BorshSerialize, BorshDeserialize,
+)]
+pub struct OutPoint {
+    pub txid: [u8; 32],
+    pub vout: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: [u8; 32], vout: u32) -> Self {
+        Self { txid, vout }
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", hex::encode(self.txid), self.vout)
+    }
+}
+
+impl FromStr for OutPoint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidOutPoint(s.to_string());
+
+        let (txid, vout) = s.split_once(':').ok_or_else(invalid)?;
+        let txid = <[u8; 32]>::from_hex(txid).map_err(|_| invalid())?;
+        let vout = vout.parse::<u32>().map_err(|_| invalid())?;
+
+        Ok(Self { txid, vout })
+    }
+}
+
 #[allow(clippy::style)]
 #[derive(Debug, Clone, Hash, Eq, PartialEq, BorshSerialize, BorshDeserialize)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "snake_case"))]
 pub enum UTXO {
     Pending {
         // hash used to identify UTXO
@@ -17,11 +69,16 @@ pub enum UTXO {
         value: u64,
         // Index of the utxo in the transaction
         index: u32,
+        // Public key of the account the output is paid to
+        #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
+        owner: [u8; 32],
     },
     Confirmed {
+        #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
         id: [u8; 32],
         script_pubkey: String,
         value: u64,
+        #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
         txn_hash: [u8; 32],
         index: u32,
         // Timestamp of the block the UTXO was created
@@ -31,47 +88,86 @@ pub enum UTXO {
         // Coin earned from mining
         is_coinbase: bool,
     },
+    // Output locked to the hash of a redeem script instead of a key
+    PendingScriptHash {
+        value: u64,
+        index: u32,
+        #[cfg_attr(feature = "serde", serde(with = "crate::utils::hex_serde"))]
+        script_hash: [u8; 32],
+    },
 }
 
 impl UTXO {
-    pub fn new(value: u64, index: u32) -> Result<Self> {
+    pub fn new(value: u64, index: u32, owner: [u8; 32]) -> Result<Self> {
         if value == 0 {
             return Err(Error::InvalidUTXOValue);
         }
 
-        Ok(Self::Pending { value, index })
+        Ok(Self::Pending {
+            value,
+            index,
+            owner,
+        })
+    }
+
+    pub fn new_script_hash(value: u64, index: u32, script_hash: [u8; 32]) -> Result<Self> {
+        if value == 0 {
+            return Err(Error::InvalidUTXOValue);
+        }
+
+        Ok(Self::PendingScriptHash {
+            value,
+            index,
+            script_hash,
+        })
+    }
+
+    // Output spendable with `required` signatures of the public keys, paid to
+    // the hash of the multisig script like any other redeem script
+    pub fn new_multisig(
+        value: u64,
+        index: u32,
+        required: usize,
+        public_keys: &[[u8; 32]],
+    ) -> Result<Self> {
+        let script = Script::multisig(required, public_keys)?;
+        Self::new_script_hash(value, index, script.script_hash())
     }
 
     pub fn confirm_utxo(
         self,
-        owner: [u8; 32],
         txn_hash: [u8; 32],
         block_height: u32,
         coinbase: bool,
     ) -> Result<UTXO> {
-        match self {
-            UTXO::Pending { value, index } => {
-                let mut id = [0u8; 32];
-                let id_hash = blake3::hash(&[txn_hash.as_ref(), &index.to_le_bytes()].concat());
-                id.copy_from_slice(id_hash.as_bytes());
+        let (value, index, script_pubkey) = match self {
+            UTXO::Pending {
+                value,
+                index,
+                owner,
+            } => (value, index, Script::pay_to_pubkey_hash(&owner)),
+            UTXO::PendingScriptHash {
+                value,
+                index,
+                script_hash,
+            } => (value, index, Script::pay_to_script_hash(&script_hash)),
+            UTXO::Confirmed { .. } => return Err(Error::ConfirmedUTXO),
+        };
 
-                let created_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u32;
+        let id = output_id(&txn_hash, index);
 
-                let owner_hash = blake3::hash(&owner);
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u32;
 
-                Ok(UTXO::Confirmed {
-                    id,
-                    script_pubkey: format!("{} OP_CHECKSIG", owner_hash),
-                    value,
-                    txn_hash,
-                    index,
-                    created_at,
-                    block_height,
-                    is_coinbase: coinbase,
-                })
-            }
-            UTXO::Confirmed { .. } => Err(Error::ConfirmedUTXO),
-        }
+        Ok(UTXO::Confirmed {
+            id,
+            script_pubkey: script_pubkey.to_string(),
+            value,
+            txn_hash,
+            index,
+            created_at,
+            block_height,
+            is_coinbase: coinbase,
+        })
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -96,77 +192,98 @@ impl UTXO {
                 bytes
             }
 
-            UTXO::Pending { value, index, .. } => {
+            UTXO::Pending {
+                value,
+                index,
+                owner,
+            } => {
                 let mut bytes = Vec::new();
                 bytes.extend(&value.to_le_bytes()); // 8 bytes
                 bytes.extend(&index.to_le_bytes()); // 4 bytes
-                                                    //
+                bytes.extend(owner); // 32 bytes
+
+                bytes
+            }
+
+            UTXO::PendingScriptHash {
+                value,
+                index,
+                script_hash,
+            } => {
+                // Tagged so the output can't hash the same as one paid to a key
+                let mut bytes = b"P2SH".to_vec();
+                bytes.extend(&value.to_le_bytes()); // 8 bytes
+                bytes.extend(&index.to_le_bytes()); // 4 bytes
+                bytes.extend(script_hash); // 32 bytes
+
                 bytes
             }
         }
     }
 
-    pub fn unlock(&self, unlocking_script: &str) -> Result<()> {
-        match self {
-            UTXO::Pending { .. } => Err(Error::PendingUTXO),
-            UTXO::Confirmed { script_pubkey, .. } => {
-                let mut stack = Vec::new();
-
-                for token in unlocking_script.split_whitespace() {
-                    stack.push(token);
+    // Whether both are the same output. When and at which height confirmed
+    // outputs were confirmed is left out: every node stamps the outputs it
+    // confirms with its own clock, and spenders of outputs still in the
+    // mempool can't know the height. Both are taken from the stored output
+    pub fn same_output(&self, other: &UTXO) -> bool {
+        match (self, other) {
+            (
+                UTXO::Confirmed {
+                    created_at,
+                    block_height,
+                    ..
+                },
+                UTXO::Confirmed { .. },
+            ) => {
+                let mut other = other.clone();
+                if let UTXO::Confirmed {
+                    created_at: other_created_at,
+                    block_height: other_block_height,
+                    ..
+                } = &mut other
+                {
+                    *other_created_at = *created_at;
+                    *other_block_height = *block_height;
                 }
+                *self == other
+            }
+            _ => self == other,
+        }
+    }
 
-                for token in script_pubkey.split_whitespace() {
-                    match token {
-                        // Duplicate the top value on the stack
-                        "OP_CHECKSIG" => {
-                            if stack.len() < 3 {
-                                return Err(Error::InvalidUnlockingScript);
-                            }
-
-                            let public_key_hash = stack.pop().ok_or_else(|| Error::EmptyStack)?;
-                            let public_key =
-                                hex::decode(stack.pop().ok_or_else(|| Error::EmptyStack)?)?;
-                            let signature =
-                                hex::decode(stack.pop().ok_or_else(|| Error::EmptyStack)?)?;
-                            let new_hash = blake3::hash(public_key.as_slice());
-
-                            if public_key_hash != new_hash.to_string() {
-                                return Err(Error::InvalidUnlockingScript);
-                            }
-                            if verify_signature(
-                                public_key.as_slice(),
-                                signature.as_slice(),
-                                new_hash.as_bytes(),
-                            )
-                            .is_err()
-                            {
-                                return Err(Error::InvalidUnlockingScript);
-                            }
-
-                            stack.push("true");
-                        }
-
-                        _ => stack.push(token),
-                    }
-                }
+    // Runs the unlocking script against the UTXO's locking script, with
+    // signatures and timelocks checked against the spend's context. It's
+    // run on the output as stored in the UTXO set, an input only names the
+    // output it spends and may declare it locked by another script
+    pub fn unlock(&self, unlocking_script: &str, context: &SpendContext) -> Result<()> {
+        match self {
+            UTXO::Pending { .. } | UTXO::PendingScriptHash { .. } => Err(Error::PendingUTXO),
+            UTXO::Confirmed { script_pubkey, .. } => {
+                Script::execute(&unlocking_script.parse()?, &script_pubkey.parse()?, context)
+            }
+        }
+    }
 
-                if stack.len() == 1 && stack.pop().ok_or_else(|| Error::EmptyStack)? == "true" {
-                    Ok(())
-                } else {
-                    Err(Error::InvalidUnlockingScript)
-                }
+    // Blocks a confirmed UTXO has been confirmed for once the block at
+    // `height` is added, including its own block. Only the UTXO set knows
+    // where an output was confirmed, relative timelocks are checked with
+    // the stored output rather than the input spending it
+    pub fn confirmations_at(&self, height: u64) -> u64 {
+        match self {
+            UTXO::Pending { .. } | UTXO::PendingScriptHash { .. } => 0,
+            UTXO::Confirmed { block_height, .. } => {
+                (height + 1).saturating_sub(*block_height as u64)
             }
         }
     }
+
     pub fn size(&self) -> usize {
         match self {
-            UTXO::Pending { .. } => {
-                8 + 4 // size of `value` + size of `index`
-            }
+            UTXO::Pending { .. } | UTXO::PendingScriptHash { .. } => PENDING_SIZE,
             UTXO::Confirmed { script_pubkey, .. } => {
-                32                  // id
-                + script_pubkey.len() // script_pubkey size
+                1                    // variant
+                + 32                 // id
+                + 4 + script_pubkey.len() // script_pubkey size
                 + 8                  // value
                 + 32                 // txn_hash
                 + 4                  // index
@@ -177,40 +294,106 @@ impl UTXO {
         }
     }
 
-    pub fn value(&self) -> u64   {
+    pub fn value(&self) -> u64 {
         match self {
             UTXO::Pending { value, .. } => *value,
             UTXO::Confirmed { value, .. } => *value,
+            UTXO::PendingScriptHash { value, .. } => *value,
+        }
+    }
+
+    // Position of the output in the transaction that created it
+    pub fn index(&self) -> u32 {
+        match self {
+            UTXO::Pending { index, .. } => *index,
+            UTXO::Confirmed { index, .. } => *index,
+            UTXO::PendingScriptHash { index, .. } => *index,
+        }
+    }
+
+    // Outputs of a transaction that isn't in a block yet
+    pub fn is_pending(&self) -> bool {
+        !matches!(self, UTXO::Confirmed { .. })
+    }
+
+    // Identifier of a confirmed UTXO, pending outputs don't have one yet
+    pub fn id(&self) -> Option<[u8; 32]> {
+        match self {
+            UTXO::Pending { .. } | UTXO::PendingScriptHash { .. } => None,
+            UTXO::Confirmed { id, .. } => Some(*id),
+        }
+    }
+
+    // Locking script of a confirmed UTXO, pending outputs aren't locked yet
+    pub fn script_pubkey(&self) -> Option<&str> {
+        match self {
+            UTXO::Pending { .. } | UTXO::PendingScriptHash { .. } => None,
+            UTXO::Confirmed { script_pubkey, .. } => Some(script_pubkey),
         }
     }
-}
 
-fn verify_signature(public_key: &[u8], signature: &[u8], txn_hash: &[u8]) -> Result<()> {
-    let verifier = VerifyingKey::from_bytes(convert_u8_to_u832(public_key)?)?;
+    // Signature operations spending the UTXO costs
+    pub fn sigops(&self) -> usize {
+        self.script_pubkey().map_or(0, count_sigops)
+    }
 
-    let signature = Signature::from_bytes(convert_u8_to_u864(signature)?);
+    // Signature operations spending the UTXO with the unlocking script costs,
+    // for pay-to-script-hash outputs that includes the redeem script
+    pub fn spending_sigops(&self, unlocking_script: &str) -> usize {
+        let pays_to_script_hash = self
+            .script_pubkey()
+            .and_then(|script| script.parse::<Script>().ok())
+            .is_some_and(|script| script.locked_script_hash().is_some());
+        if pays_to_script_hash {
+            self.sigops() + count_redeem_sigops(unlocking_script)
+        } else {
+            self.sigops()
+        }
+    }
 
-    Ok(verifier.verify_strict(txn_hash, &signature)?)
+    // Output a confirmed UTXO was created by, pending outputs don't have a
+    // transaction id yet
+    pub fn outpoint(&self) -> Option<OutPoint> {
+        match self {
+            UTXO::Pending { .. } | UTXO::PendingScriptHash { .. } => None,
+            UTXO::Confirmed {
+                txn_hash, index, ..
+            } => Some(OutPoint::new(*txn_hash, *index)),
+        }
+    }
+
+    // Checks whether the UTXO is paid to the given public key, or to the
+    // given redeem script hash
+    pub fn is_owned_by(&self, owner: &[u8; 32]) -> bool {
+        match self {
+            UTXO::Pending { owner: o, .. } => o == owner,
+            UTXO::PendingScriptHash { script_hash, .. } => script_hash == owner,
+            UTXO::Confirmed { script_pubkey, .. } => {
+                *script_pubkey == Script::pay_to_pubkey_hash(owner).to_string()
+                    || *script_pubkey == Script::pay_to_script_hash(owner).to_string()
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use ed25519_dalek::{ed25519::signature::SignerMut, SigningKey};
-    use rand::rngs::OsRng;
-
     use super::*;
+    use crate::{
+        rng::test_key,
+        script::{sign_digest, SigHash, SigHashes},
+    };
 
     #[test]
     fn test_valid_utxo_lifecycle() {
-        let mut csprng = OsRng;
-        let mut signing_key = SigningKey::generate(&mut csprng);
+        let signing_key = test_key("owner");
 
         let owner = signing_key.verifying_key().to_bytes();
         let txn_hash = [1u8; 32];
-        let pending_utxo = UTXO::new(1000, 1).expect("Failed to create UTXO");
+        let pending_utxo = UTXO::new(1000, 1, owner).expect("Failed to create UTXO");
 
         let confirmed_utxo = pending_utxo
-            .confirm_utxo(owner, txn_hash, 100, false)
+            .confirm_utxo(txn_hash, 100, false)
             .expect("Failed to confirm UTXO");
 
         if let UTXO::Confirmed {
@@ -224,15 +407,88 @@ mod test {
             assert_eq!(block_height, 100);
             assert!(!is_coinbase);
 
-            let owner_hash = blake3::hash(&owner);
+            let digest = [7u8; 32];
+            let context = SpendContext {
+                sighashes: SigHashes::only(SigHash::All, digest),
+                ..SpendContext::default()
+            };
 
-            let signature = signing_key.sign(owner_hash.as_bytes()).to_bytes();
+            let signature = sign_digest(&signing_key, &digest, SigHash::All);
 
             let unlocking_script = format!("{} {}", hex::encode(signature), hex::encode(owner));
 
-            confirmed_utxo.unlock(&unlocking_script).unwrap();
+            assert!(confirmed_utxo.is_owned_by(&owner));
+            confirmed_utxo.unlock(&unlocking_script, &context).unwrap();
+            assert!(confirmed_utxo
+                .unlock(&unlocking_script, &SpendContext::default())
+                .is_err());
         } else {
             panic!("Expected a Confirmed UTXO");
         }
     }
+
+    #[test]
+    fn unlocks_only_the_stored_locking_script() {
+        let owner = test_key("owner").verifying_key().to_bytes();
+        let thief = test_key("thief");
+        let stored = UTXO::new(1000, 0, owner)
+            .unwrap()
+            .confirm_utxo([1u8; 32], 5, false)
+            .unwrap();
+
+        // The same output as confirmed by another node, spent by an input
+        // that can't know the height
+        let mut restamped = stored.clone();
+        if let UTXO::Confirmed {
+            created_at,
+            block_height,
+            ..
+        } = &mut restamped
+        {
+            *created_at += 1;
+            *block_height = 0;
+        }
+        assert!(stored.same_output(&restamped));
+
+        // Declared locked to the thief's key
+        let mut relocked = stored.clone();
+        if let UTXO::Confirmed { script_pubkey, .. } = &mut relocked {
+            *script_pubkey =
+                Script::pay_to_pubkey_hash(&thief.verifying_key().to_bytes()).to_string();
+        }
+        assert!(!stored.same_output(&relocked));
+
+        let digest = [7u8; 32];
+        let context = SpendContext {
+            sighashes: SigHashes::only(SigHash::All, digest),
+            ..SpendContext::default()
+        };
+        let signature = sign_digest(&thief, &digest, SigHash::All);
+        let unlocking_script = format!(
+            "{} {}",
+            hex::encode(signature),
+            hex::encode(thief.verifying_key().to_bytes())
+        );
+        assert!(relocked.unlock(&unlocking_script, &context).is_ok());
+        assert!(stored.unlock(&unlocking_script, &context).is_err());
+    }
+
+    #[test]
+    fn outpoints_round_trip_through_hex() {
+        let utxo = UTXO::new(1000, 3, [2u8; 32])
+            .unwrap()
+            .confirm_utxo([0xab; 32], 1, false)
+            .unwrap();
+        let outpoint = utxo.outpoint().unwrap();
+
+        let displayed = outpoint.to_string();
+        assert_eq!(displayed, format!("{}:3", "ab".repeat(32)));
+        assert_eq!(displayed.parse::<OutPoint>().unwrap(), outpoint);
+
+        assert!(matches!(
+            "ab:3".parse::<OutPoint>(),
+            Err(Error::InvalidOutPoint(_))
+        ));
+        assert!("abcd".parse::<OutPoint>().is_err());
+    }
 }
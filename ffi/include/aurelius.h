@@ -0,0 +1,93 @@
+#ifndef AURELIUS_H
+#define AURELIUS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Keys, including the receivers and owners of payments, are 32 bytes,
+ * signatures 64 bytes. Strings, e.g. addresses, are NUL terminated. Buffers
+ * returned by the library are released with aurelius_buffer_free. */
+
+#define AURELIUS_ADDRESS_LEN 64
+
+typedef enum AureliusStatus {
+    AURELIUS_OK = 0,
+    AURELIUS_NULL_POINTER = 1,
+    AURELIUS_INVALID_ADDRESS = 2,
+    AURELIUS_INVALID_UTXO = 3,
+    AURELIUS_INVALID_TRANSACTION = 4,
+    AURELIUS_INVALID_SIGNATURE = 5,
+    AURELIUS_INVALID_STRING = 6,
+    AURELIUS_INVALID_NETWORK = 7,
+} AureliusStatus;
+
+typedef struct AureliusBuffer {
+    uint8_t *data;
+    size_t len;
+} AureliusBuffer;
+
+typedef struct AureliusAmounts {
+    uint64_t input;
+    uint64_t output;
+    uint64_t fee;
+} AureliusAmounts;
+
+typedef struct AureliusTransaction AureliusTransaction;
+
+AureliusStatus aurelius_public_key(const uint8_t *secret_key, uint8_t *public_key_out);
+
+/* Addresses are bech32m behind the prefix of the network, which is named,
+ * e.g. "mainnet". address_out must hold AURELIUS_ADDRESS_LEN bytes */
+AureliusStatus aurelius_address_encode(const uint8_t *public_key, const char *network,
+                                       char *address_out);
+AureliusStatus aurelius_address_decode(const char *address, const char *network,
+                                       uint8_t *public_key_out);
+
+/* Returns NULL if the receiver isn't a valid address. timestamp is in
+ * milliseconds since the unix epoch */
+AureliusTransaction *aurelius_transaction_new(const uint8_t *receiver, uint64_t timestamp);
+void aurelius_transaction_free(AureliusTransaction *transaction);
+
+/* utxo is a Borsh encoded confirmed UTXO as listed by the node */
+AureliusStatus aurelius_transaction_add_input(AureliusTransaction *transaction,
+                                              const uint8_t *utxo, size_t utxo_len);
+AureliusStatus aurelius_transaction_add_output(AureliusTransaction *transaction,
+                                               uint64_t value, const uint8_t *owner);
+
+/* Writes the Borsh encoded signed transaction to out */
+AureliusStatus aurelius_transaction_sign(const AureliusTransaction *transaction,
+                                         const uint8_t *secret_key, AureliusBuffer *out);
+
+/* Writes the NUL terminated script unlocking input `input` of a Borsh encoded
+ * signed transaction, spending an output owned by the key, to out */
+AureliusStatus aurelius_unlocking_script(const uint8_t *transaction, size_t transaction_len,
+                                         size_t input, const uint8_t *secret_key,
+                                         AureliusBuffer *out);
+
+AureliusStatus aurelius_transaction_verify(const uint8_t *transaction, size_t transaction_len,
+                                           const char *unlocking_script,
+                                           AureliusAmounts *amounts_out);
+/* Verifies with unlocking_scripts[i] unlocking input i, for inputs owned by
+ * different keys */
+AureliusStatus aurelius_transaction_verify_inputs(const uint8_t *transaction,
+                                                  size_t transaction_len,
+                                                  const char *const *unlocking_scripts,
+                                                  size_t scripts_len,
+                                                  AureliusAmounts *amounts_out);
+
+AureliusStatus aurelius_sign_message(const uint8_t *secret_key, const uint8_t *message,
+                                     size_t message_len, uint8_t *signature_out);
+AureliusStatus aurelius_verify_message(const uint8_t *address, const uint8_t *message,
+                                       size_t message_len, const uint8_t *signature);
+
+void aurelius_buffer_free(AureliusBuffer buffer);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
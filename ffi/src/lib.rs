@@ -0,0 +1,611 @@
+// C ABI over the wallet side of corelib: keys, addresses and creating, signing
+// and verifying transactions. The declarations are in `include/aurelius.h`.
+//
+// Keys, including the receivers and owners of payments, are passed as
+// pointers to 32 bytes and signatures to 64 bytes, addresses as strings.
+// Pointers must be valid for the length the header documents, strings must be
+// NUL terminated. Buffers returned by the library are owned by the caller and
+// released with `aurelius_buffer_free`.
+#![allow(clippy::missing_safety_doc)]
+
+use std::{
+    ffi::{c_char, CStr},
+    ptr, slice,
+};
+
+use borsh::BorshDeserialize;
+use corelib::{
+    address::Address, config::Network, script::SigHash, sign, transaction::Transaction, utxo::UTXO,
+};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+
+// Size of the longest address, a testnet one, including the NUL terminator
+pub const AURELIUS_ADDRESS_LEN: usize = 64;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AureliusStatus {
+    Ok = 0,
+    NullPointer = 1,
+    InvalidAddress = 2,
+    InvalidUtxo = 3,
+    InvalidTransaction = 4,
+    InvalidSignature = 5,
+    InvalidString = 6,
+    InvalidNetwork = 7,
+}
+
+// Bytes allocated by the library
+#[repr(C)]
+#[derive(Debug)]
+pub struct AureliusBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl AureliusBuffer {
+    fn new(bytes: Vec<u8>) -> Self {
+        let bytes = bytes.into_boxed_slice();
+        let len = bytes.len();
+
+        Self {
+            data: Box::into_raw(bytes) as *mut u8,
+            len,
+        }
+    }
+}
+
+// Amounts of a transaction that passed verification
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AureliusAmounts {
+    pub input: u64,
+    pub output: u64,
+    pub fee: u64,
+}
+
+// Transaction being put together, opaque to C
+pub struct AureliusTransaction {
+    receiver: [u8; 32],
+    timestamp: u64,
+    inputs: Vec<UTXO>,
+    outputs: Vec<UTXO>,
+}
+
+unsafe fn key<'a>(key: *const u8) -> Result<&'a [u8; 32], AureliusStatus> {
+    if key.is_null() {
+        return Err(AureliusStatus::NullPointer);
+    }
+    Ok(&*(key as *const [u8; 32]))
+}
+
+unsafe fn address(address: *const u8) -> Result<[u8; 32], AureliusStatus> {
+    let address = *key(address)?;
+    VerifyingKey::from_bytes(&address).map_err(|_| AureliusStatus::InvalidAddress)?;
+
+    Ok(address)
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Result<&'a [u8], AureliusStatus> {
+    if data.is_null() {
+        return Err(AureliusStatus::NullPointer);
+    }
+    Ok(slice::from_raw_parts(data, len))
+}
+
+unsafe fn string<'a>(string: *const c_char) -> Result<&'a str, AureliusStatus> {
+    if string.is_null() {
+        return Err(AureliusStatus::NullPointer);
+    }
+    CStr::from_ptr(string)
+        .to_str()
+        .map_err(|_| AureliusStatus::InvalidString)
+}
+
+// Network of its name, e.g. `mainnet`
+unsafe fn network(name: *const c_char) -> Result<Network, AureliusStatus> {
+    string(name)?
+        .parse()
+        .map_err(|_| AureliusStatus::InvalidNetwork)
+}
+
+fn status(result: Result<(), AureliusStatus>) -> AureliusStatus {
+    result.err().unwrap_or(AureliusStatus::Ok)
+}
+
+// Writes the public key of `secret_key` to `public_key_out`
+#[no_mangle]
+pub unsafe extern "C" fn aurelius_public_key(
+    secret_key: *const u8,
+    public_key_out: *mut u8,
+) -> AureliusStatus {
+    status((|| {
+        let public_key = SigningKey::from_bytes(key(secret_key)?)
+            .verifying_key()
+            .to_bytes();
+        if public_key_out.is_null() {
+            return Err(AureliusStatus::NullPointer);
+        }
+
+        ptr::copy_nonoverlapping(public_key.as_ptr(), public_key_out, public_key.len());
+        Ok(())
+    })())
+}
+
+// Writes the address of `public_key` on the named network to `address_out`,
+// which must hold `AURELIUS_ADDRESS_LEN` bytes
+#[no_mangle]
+pub unsafe extern "C" fn aurelius_address_encode(
+    public_key: *const u8,
+    network_name: *const c_char,
+    address_out: *mut c_char,
+) -> AureliusStatus {
+    status((|| {
+        let encoded = Address::new(network(network_name)?, address(public_key)?).to_string();
+        if address_out.is_null() {
+            return Err(AureliusStatus::NullPointer);
+        }
+
+        ptr::copy_nonoverlapping(encoded.as_ptr(), address_out as *mut u8, encoded.len());
+        *address_out.add(encoded.len()) = 0;
+        Ok(())
+    })())
+}
+
+// Writes the public key of an address to `public_key_out`, addresses of
+// another network than the named one are invalid
+#[no_mangle]
+pub unsafe extern "C" fn aurelius_address_decode(
+    address_string: *const c_char,
+    network_name: *const c_char,
+    public_key_out: *mut u8,
+) -> AureliusStatus {
+    status((|| {
+        let network = network(network_name)?;
+        let public_key = string(address_string)?
+            .parse::<Address>()
+            .ok()
+            .filter(|address| address.network() == network)
+            .ok_or(AureliusStatus::InvalidAddress)?
+            .key();
+        if public_key_out.is_null() {
+            return Err(AureliusStatus::NullPointer);
+        }
+
+        ptr::copy_nonoverlapping(public_key.as_ptr(), public_key_out, public_key.len());
+        Ok(())
+    })())
+}
+
+// Starts a payment to `receiver`, `timestamp` is in milliseconds since the
+// unix epoch. Returns null if the receiver isn't a valid address
+#[no_mangle]
+pub unsafe extern "C" fn aurelius_transaction_new(
+    receiver: *const u8,
+    timestamp: u64,
+) -> *mut AureliusTransaction {
+    match address(receiver) {
+        Ok(receiver) => Box::into_raw(Box::new(AureliusTransaction {
+            receiver,
+            timestamp,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+        })),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn aurelius_transaction_free(transaction: *mut AureliusTransaction) {
+    if !transaction.is_null() {
+        drop(Box::from_raw(transaction));
+    }
+}
+
+// Spends a Borsh encoded confirmed UTXO, as listed by the node
+#[no_mangle]
+pub unsafe extern "C" fn aurelius_transaction_add_input(
+    transaction: *mut AureliusTransaction,
+    utxo: *const u8,
+    utxo_len: usize,
+) -> AureliusStatus {
+    status((|| {
+        let transaction = transaction.as_mut().ok_or(AureliusStatus::NullPointer)?;
+        let utxo = UTXO::try_from_slice(bytes(utxo, utxo_len)?)
+            .map_err(|_| AureliusStatus::InvalidUtxo)?;
+        if utxo.outpoint().is_none() {
+            return Err(AureliusStatus::InvalidUtxo);
+        }
+
+        transaction.inputs.push(utxo);
+        Ok(())
+    })())
+}
+
+// Pays `value` to `owner`, outputs are indexed in the order they're added
+#[no_mangle]
+pub unsafe extern "C" fn aurelius_transaction_add_output(
+    transaction: *mut AureliusTransaction,
+    value: u64,
+    owner: *const u8,
+) -> AureliusStatus {
+    status((|| {
+        let transaction = transaction.as_mut().ok_or(AureliusStatus::NullPointer)?;
+        let output = UTXO::new(value, transaction.outputs.len() as u32, address(owner)?)
+            .map_err(|_| AureliusStatus::InvalidUtxo)?;
+
+        transaction.outputs.push(output);
+        Ok(())
+    })())
+}
+
+// Signs the transaction with `secret_key` and writes its Borsh encoding, the
+// bytes the node's `sendrawtransaction` takes hex encoded, to `out`
+#[no_mangle]
+pub unsafe extern "C" fn aurelius_transaction_sign(
+    transaction: *const AureliusTransaction,
+    secret_key: *const u8,
+    out: *mut AureliusBuffer,
+) -> AureliusStatus {
+    status((|| {
+        let transaction = transaction.as_ref().ok_or(AureliusStatus::NullPointer)?;
+        let mut signing_key = SigningKey::from_bytes(key(secret_key)?);
+        if out.is_null() {
+            return Err(AureliusStatus::NullPointer);
+        }
+
+        let mut signed = Transaction::with_timestamp(
+            &mut signing_key,
+            transaction.receiver,
+            transaction.timestamp as u128,
+        );
+        signed
+            .add_inputs(transaction.inputs.clone(), &mut signing_key)
+            .map_err(|_| AureliusStatus::InvalidTransaction)?;
+        signed
+            .add_outputs(transaction.outputs.clone(), &mut signing_key)
+            .map_err(|_| AureliusStatus::InvalidTransaction)?;
+
+        let encoded = borsh::to_vec(&signed).map_err(|_| AureliusStatus::InvalidTransaction)?;
+        *out = AureliusBuffer::new(encoded);
+        Ok(())
+    })())
+}
+
+// Writes the NUL terminated script unlocking input `input` of a Borsh
+// encoded signed transaction, spending an output owned by `secret_key`, to
+// `out`
+#[no_mangle]
+pub unsafe extern "C" fn aurelius_unlocking_script(
+    transaction: *const u8,
+    transaction_len: usize,
+    input: usize,
+    secret_key: *const u8,
+    out: *mut AureliusBuffer,
+) -> AureliusStatus {
+    status((|| {
+        let transaction = Transaction::try_from_slice(bytes(transaction, transaction_len)?)
+            .map_err(|_| AureliusStatus::InvalidTransaction)?;
+        let signing_key = SigningKey::from_bytes(key(secret_key)?);
+        if out.is_null() {
+            return Err(AureliusStatus::NullPointer);
+        }
+
+        let signature = transaction
+            .sign_input(input, SigHash::All, &signing_key)
+            .map_err(|_| AureliusStatus::InvalidTransaction)?;
+        let script = format!(
+            "{} {}\0",
+            hex::encode(signature),
+            hex::encode(signing_key.verifying_key().to_bytes())
+        );
+
+        *out = AureliusBuffer::new(script.into_bytes());
+        Ok(())
+    })())
+}
+
+// Verifies a Borsh encoded transaction, writing its amounts to `amounts_out`
+#[no_mangle]
+pub unsafe extern "C" fn aurelius_transaction_verify(
+    transaction: *const u8,
+    transaction_len: usize,
+    unlocking_script: *const c_char,
+    amounts_out: *mut AureliusAmounts,
+) -> AureliusStatus {
+    status((|| {
+        let transaction = Transaction::try_from_slice(bytes(transaction, transaction_len)?)
+            .map_err(|_| AureliusStatus::InvalidTransaction)?;
+        let unlocking_script = string(unlocking_script)?;
+        if amounts_out.is_null() {
+            return Err(AureliusStatus::NullPointer);
+        }
+
+        let (input, output, fee) = transaction
+            .verify(unlocking_script)
+            .map_err(|_| AureliusStatus::InvalidTransaction)?;
+        *amounts_out = AureliusAmounts { input, output, fee };
+        Ok(())
+    })())
+}
+
+// Same as `aurelius_transaction_verify` with an unlocking script for every
+// input, `unlocking_scripts` holds `scripts_len` of them in input order
+#[no_mangle]
+pub unsafe extern "C" fn aurelius_transaction_verify_inputs(
+    transaction: *const u8,
+    transaction_len: usize,
+    unlocking_scripts: *const *const c_char,
+    scripts_len: usize,
+    amounts_out: *mut AureliusAmounts,
+) -> AureliusStatus {
+    status((|| {
+        let transaction = Transaction::try_from_slice(bytes(transaction, transaction_len)?)
+            .map_err(|_| AureliusStatus::InvalidTransaction)?;
+        if unlocking_scripts.is_null() || amounts_out.is_null() {
+            return Err(AureliusStatus::NullPointer);
+        }
+        let unlocking_scripts = slice::from_raw_parts(unlocking_scripts, scripts_len)
+            .iter()
+            .map(|script| string(*script))
+            .collect::<Result<Vec<&str>, AureliusStatus>>()?;
+
+        let (input, output, fee) = transaction
+            .verify_inputs(&unlocking_scripts)
+            .map_err(|_| AureliusStatus::InvalidTransaction)?;
+        *amounts_out = AureliusAmounts { input, output, fee };
+        Ok(())
+    })())
+}
+
+// Writes the signature proving `secret_key` controls its address over
+// `message` to `signature_out`, which must hold 64 bytes
+#[no_mangle]
+pub unsafe extern "C" fn aurelius_sign_message(
+    secret_key: *const u8,
+    message: *const u8,
+    message_len: usize,
+    signature_out: *mut u8,
+) -> AureliusStatus {
+    status((|| {
+        let signing_key = SigningKey::from_bytes(key(secret_key)?);
+        let signature = sign::sign_message(&signing_key, bytes(message, message_len)?);
+        if signature_out.is_null() {
+            return Err(AureliusStatus::NullPointer);
+        }
+
+        ptr::copy_nonoverlapping(signature.as_ptr(), signature_out, signature.len());
+        Ok(())
+    })())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn aurelius_verify_message(
+    address: *const u8,
+    message: *const u8,
+    message_len: usize,
+    signature: *const u8,
+) -> AureliusStatus {
+    status((|| {
+        if signature.is_null() {
+            return Err(AureliusStatus::NullPointer);
+        }
+        let signature = &*(signature as *const [u8; 64]);
+
+        sign::verify_message(key(address)?, bytes(message, message_len)?, signature)
+            .map_err(|_| AureliusStatus::InvalidSignature)
+    })())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn aurelius_buffer_free(buffer: AureliusBuffer) {
+    if !buffer.data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            buffer.data,
+            buffer.len,
+        )));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn creates_signs_and_verifies_transactions() {
+        let secret_key = [7u8; 32];
+        let mut sender = [0u8; 32];
+        let mut receiver = [0u8; 32];
+        let mut encoded = [0 as c_char; AURELIUS_ADDRESS_LEN];
+
+        unsafe {
+            assert_eq!(
+                aurelius_public_key(secret_key.as_ptr(), sender.as_mut_ptr()),
+                AureliusStatus::Ok
+            );
+            aurelius_public_key([8u8; 32].as_ptr(), receiver.as_mut_ptr());
+
+            assert_eq!(
+                aurelius_address_encode(
+                    receiver.as_ptr(),
+                    c"testnet".as_ptr(),
+                    encoded.as_mut_ptr()
+                ),
+                AureliusStatus::Ok
+            );
+            let address = CStr::from_ptr(encoded.as_ptr());
+            assert_eq!(
+                address.to_str().unwrap(),
+                Address::new(Network::Testnet, receiver).to_string()
+            );
+            assert_eq!(address.count_bytes() + 1, AURELIUS_ADDRESS_LEN);
+
+            let mut decoded = [0u8; 32];
+            assert_eq!(
+                aurelius_address_decode(
+                    encoded.as_ptr(),
+                    c"testnet".as_ptr(),
+                    decoded.as_mut_ptr()
+                ),
+                AureliusStatus::Ok
+            );
+            assert_eq!(decoded, receiver);
+            // Addresses of another network, hex keys and unknown networks
+            // are refused
+            for (address, network, status) in [
+                (encoded.as_ptr(), c"mainnet", AureliusStatus::InvalidAddress),
+                (c"abcd".as_ptr(), c"testnet", AureliusStatus::InvalidAddress),
+                (encoded.as_ptr(), c"devnet", AureliusStatus::InvalidNetwork),
+            ] {
+                assert_eq!(
+                    aurelius_address_decode(address, network.as_ptr(), decoded.as_mut_ptr()),
+                    status
+                );
+            }
+            let hex_key = std::ffi::CString::new(hex::encode(receiver)).unwrap();
+            assert_eq!(
+                aurelius_address_decode(
+                    hex_key.as_ptr(),
+                    c"testnet".as_ptr(),
+                    decoded.as_mut_ptr()
+                ),
+                AureliusStatus::InvalidAddress
+            );
+
+            let utxo = UTXO::new(1_000, 0, sender)
+                .unwrap()
+                .confirm_utxo([1u8; 32], 1, false)
+                .unwrap();
+            let utxo = borsh::to_vec(&utxo).unwrap();
+
+            let transaction = aurelius_transaction_new(receiver.as_ptr(), 1_700_000_000_000);
+            assert_eq!(
+                aurelius_transaction_add_input(transaction, utxo.as_ptr(), utxo.len()),
+                AureliusStatus::Ok
+            );
+            assert_eq!(
+                aurelius_transaction_add_output(transaction, 900, receiver.as_ptr()),
+                AureliusStatus::Ok
+            );
+
+            let mut signed = AureliusBuffer {
+                data: ptr::null_mut(),
+                len: 0,
+            };
+            assert_eq!(
+                aurelius_transaction_sign(transaction, secret_key.as_ptr(), &mut signed),
+                AureliusStatus::Ok
+            );
+            aurelius_transaction_free(transaction);
+
+            let mut script = AureliusBuffer {
+                data: ptr::null_mut(),
+                len: 0,
+            };
+            assert_eq!(
+                aurelius_unlocking_script(
+                    signed.data,
+                    signed.len,
+                    0,
+                    secret_key.as_ptr(),
+                    &mut script
+                ),
+                AureliusStatus::Ok
+            );
+
+            let mut amounts = AureliusAmounts::default();
+            assert_eq!(
+                aurelius_transaction_verify(
+                    signed.data,
+                    signed.len,
+                    script.data as *const c_char,
+                    &mut amounts
+                ),
+                AureliusStatus::Ok
+            );
+            assert_eq!(
+                (amounts.input, amounts.output, amounts.fee),
+                (1_000, 900, 100)
+            );
+
+            let mut scripts = [script.data as *const c_char];
+            assert_eq!(
+                aurelius_transaction_verify_inputs(
+                    signed.data,
+                    signed.len,
+                    scripts.as_ptr(),
+                    scripts.len(),
+                    &mut amounts
+                ),
+                AureliusStatus::Ok
+            );
+            scripts[0] = ptr::null();
+            assert_eq!(
+                aurelius_transaction_verify_inputs(
+                    signed.data,
+                    signed.len,
+                    scripts.as_ptr(),
+                    scripts.len(),
+                    &mut amounts
+                ),
+                AureliusStatus::NullPointer
+            );
+            assert_eq!(
+                aurelius_transaction_verify_inputs(
+                    signed.data,
+                    signed.len,
+                    scripts.as_ptr(),
+                    0,
+                    &mut amounts
+                ),
+                AureliusStatus::InvalidTransaction
+            );
+
+            // Flips a bit of the signature, after the hash, version, sender,
+            // receiver and timestamp
+            *signed.data.add(32 + 1 + 32 + 32 + 16 + 8) ^= 1;
+            assert_eq!(
+                aurelius_transaction_verify(
+                    signed.data,
+                    signed.len,
+                    script.data as *const c_char,
+                    &mut amounts
+                ),
+                AureliusStatus::InvalidTransaction
+            );
+
+            aurelius_buffer_free(signed);
+            aurelius_buffer_free(script);
+        }
+    }
+
+    #[test]
+    fn signs_and_verifies_messages() {
+        let secret_key = [7u8; 32];
+        let mut address = [0u8; 32];
+        let mut signature = [0u8; 64];
+
+        unsafe {
+            aurelius_public_key(secret_key.as_ptr(), address.as_mut_ptr());
+            aurelius_sign_message(
+                secret_key.as_ptr(),
+                b"hi".as_ptr(),
+                2,
+                signature.as_mut_ptr(),
+            );
+
+            assert_eq!(
+                aurelius_verify_message(address.as_ptr(), b"hi".as_ptr(), 2, signature.as_ptr()),
+                AureliusStatus::Ok
+            );
+            assert_eq!(
+                aurelius_verify_message(address.as_ptr(), b"ho".as_ptr(), 2, signature.as_ptr()),
+                AureliusStatus::InvalidSignature
+            );
+            assert_eq!(
+                aurelius_verify_message(ptr::null(), b"hi".as_ptr(), 2, signature.as_ptr()),
+                AureliusStatus::NullPointer
+            );
+        }
+    }
+}
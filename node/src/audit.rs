@@ -0,0 +1,304 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use borsh::{BorshDeserialize, BorshSerialize};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+use crate::storage::write_secret;
+
+const LOG_FILE: &str = "audit.log";
+// Secret the entries are signed with, it never leaves the data directory
+const KEY_FILE: &str = "audit.key";
+const KEY_LEN: usize = 32;
+
+// Administrative action taken on the node
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum AuditAction {
+    // The stored chain was dropped to be downloaded again
+    Reindex,
+    // RPC call changing the node's state
+    Rpc { method: String },
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditAction::Reindex => f.write_str("reindex"),
+            AuditAction::Rpc { method } => write!(f, "rpc {method}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct AuditEntry {
+    pub sequence: u64,
+    // Milliseconds since the unix epoch
+    pub timestamp: u128,
+    pub action: AuditAction,
+    // Hash of the entry before, zeros for the first one
+    pub previous: [u8; 32],
+    // Commits to the fields above, and through `previous` to every entry
+    // before
+    pub hash: [u8; 32],
+    // HMAC-SHA256 of the hash with the node's audit key
+    pub signature: [u8; 32],
+}
+
+impl AuditEntry {
+    fn compute_hash(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.previous);
+        hasher.update(self.sequence.to_le_bytes());
+        hasher.update(self.timestamp.to_le_bytes());
+        hasher.update(borsh::to_vec(&self.action).expect("actions are always serializable"));
+
+        hasher.finalize().into()
+    }
+}
+
+// Sequence and hash the next entry continues from
+#[derive(Debug, Default)]
+struct Head {
+    sequence: u64,
+    hash: [u8; 32],
+}
+
+// Append-only log of the administrative actions taken on the node.
+//
+// Every entry commits to the one before and is signed with a key kept next
+// to the log, so editing, reordering or dropping entries in the middle shows
+// up when the log is verified. Cutting entries off the end can't be told
+// apart from them never being written, compare the head with a copy of it
+// kept elsewhere for that
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    key: [u8; KEY_LEN],
+    head: Arc<Mutex<Head>>,
+}
+
+impl AuditLog {
+    // Opens the log of the data directory, creating the key on first use
+    pub async fn open(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).await?;
+
+        let key_path = dir.join(KEY_FILE);
+        let key = match fs::read(&key_path).await {
+            Ok(key) => <[u8; KEY_LEN]>::try_from(key.as_slice())
+                .map_err(|_| anyhow!("{KEY_FILE} is corrupt"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut key = [0u8; KEY_LEN];
+                OsRng.fill_bytes(&mut key);
+                // Whoever reads the key can forge entries
+                write_secret(&key_path, &key).await?;
+                key
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let log = Self {
+            path: dir.join(LOG_FILE),
+            key,
+            head: Arc::new(Mutex::new(Head::default())),
+        };
+        let (entries, length) = log.read().await?;
+        if let Some(last) = entries.last() {
+            *log.head.lock().await = Head {
+                sequence: last.sequence + 1,
+                hash: last.hash,
+            };
+        }
+
+        // New entries go right after the last complete one, not after the
+        // remains of a torn write
+        if let Ok(metadata) = fs::metadata(&log.path).await {
+            if metadata.len() > length as u64 {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .open(&log.path)
+                    .await?
+                    .set_len(length as u64)
+                    .await?;
+            }
+        }
+
+        Ok(log)
+    }
+
+    // Appends a signed entry for the action
+    pub async fn record(&self, action: AuditAction) -> anyhow::Result<AuditEntry> {
+        let mut head = self.head.lock().await;
+
+        let mut entry = AuditEntry {
+            sequence: head.sequence,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis()),
+            action,
+            previous: head.hash,
+            hash: [0u8; 32],
+            signature: [0u8; 32],
+        };
+        entry.hash = entry.compute_hash();
+        entry.signature = self.sign(&entry.hash);
+
+        // Entries are length prefixed so a torn write only loses the last one
+        let record = borsh::to_vec(&entry)?;
+        let mut bytes = (record.len() as u32).to_le_bytes().to_vec();
+        bytes.extend(record);
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(&bytes).await?;
+        file.sync_data().await?;
+
+        *head = Head {
+            sequence: entry.sequence + 1,
+            hash: entry.hash,
+        };
+        Ok(entry)
+    }
+
+    // Every entry in the order they were written. A truncated last entry,
+    // left by a crash while appending it, is skipped
+    pub async fn entries(&self) -> anyhow::Result<Vec<AuditEntry>> {
+        Ok(self.read().await?.0)
+    }
+
+    // The complete entries and the number of bytes they take
+    async fn read(&self) -> anyhow::Result<(Vec<AuditEntry>, usize)> {
+        let bytes = match fs::read(&self.path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut entries = Vec::new();
+        let mut rest = bytes.as_slice();
+        while rest.len() >= 4 {
+            let (length, tail) = rest.split_at(4);
+            let length = u32::from_le_bytes(length.try_into()?) as usize;
+            if tail.len() < length {
+                break;
+            }
+
+            let (record, tail) = tail.split_at(length);
+            entries.push(AuditEntry::try_from_slice(record)?);
+            rest = tail;
+        }
+
+        Ok((entries, bytes.len() - rest.len()))
+    }
+
+    // Checks that every entry is signed, links to the one before and wasn't
+    // changed since it was written
+    pub fn verify(&self, entries: &[AuditEntry]) -> anyhow::Result<()> {
+        let mut previous = Head::default();
+
+        for entry in entries {
+            if entry.sequence != previous.sequence || entry.previous != previous.hash {
+                return Err(anyhow!(
+                    "Audit entry {} doesn't follow entry {}",
+                    entry.sequence,
+                    previous.sequence.wrapping_sub(1)
+                ));
+            }
+            if entry.compute_hash() != entry.hash || self.sign(&entry.hash) != entry.signature {
+                return Err(anyhow!("Audit entry {} was tampered with", entry.sequence));
+            }
+
+            previous = Head {
+                sequence: entry.sequence + 1,
+                hash: entry.hash,
+            };
+        }
+
+        Ok(())
+    }
+
+    fn sign(&self, hash: &[u8; 32]) -> [u8; 32] {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(hash);
+
+        mac.finalize().into_bytes().into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn chains_and_signs_entries() {
+        let dir = std::env::temp_dir().join(format!("aurelius-{}", uuid::Uuid::new_v4()));
+
+        let log = AuditLog::open(&dir).await.unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let metadata = std::fs::metadata(dir.join(KEY_FILE)).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+        log.record(AuditAction::Reindex).await.unwrap();
+        log.record(AuditAction::Rpc {
+            method: "sendrawtransaction".into(),
+        })
+        .await
+        .unwrap();
+
+        // Reopening continues the chain
+        let log = AuditLog::open(&dir).await.unwrap();
+        let last = log.record(AuditAction::Reindex).await.unwrap();
+        assert_eq!(last.sequence, 2);
+
+        let entries = log.entries().await.unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].action.to_string(), "rpc sendrawtransaction");
+        assert!(log.verify(&entries).is_ok());
+
+        let mut edited = entries.clone();
+        edited[1].action = AuditAction::Reindex;
+        assert!(log.verify(&edited).is_err());
+
+        // Rehashing the edit doesn't help without the key
+        edited[1].hash = edited[1].compute_hash();
+        edited[2].previous = edited[1].hash;
+        edited[2].hash = edited[2].compute_hash();
+        assert!(log.verify(&edited).is_err());
+
+        let mut dropped = entries;
+        dropped.remove(1);
+        assert!(log.verify(&dropped).is_err());
+
+        // A torn last entry is skipped
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap();
+        std::io::Write::write_all(&mut file, &[200, 0, 0, 0, 1]).unwrap();
+        assert_eq!(log.entries().await.unwrap().len(), 3);
+
+        // and cut off before the next entry is appended
+        let log = AuditLog::open(&dir).await.unwrap();
+        log.record(AuditAction::Reindex).await.unwrap();
+        let entries = log.entries().await.unwrap();
+        assert_eq!(entries.len(), 4);
+        assert!(log.verify(&entries).is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
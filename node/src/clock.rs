@@ -0,0 +1,123 @@
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+// Peers sampled at most, a new sample replaces the oldest one
+const MAX_SAMPLES: usize = 200;
+// Networks samples have to come from before the local clock is adjusted
+const MIN_SOURCES: usize = 5;
+// Largest adjustment in milliseconds. A median further off is ignored, the
+// local clock is more likely right than that many peers
+pub const MAX_ADJUSTMENT: i64 = 70 * 60 * 1_000;
+// Median offset in milliseconds past which the operator is warned to check
+// the local clock
+pub const SKEW_WARNING: i64 = 5 * 60 * 1_000;
+
+// Network-adjusted time, the local clock shifted by the median offset of the
+// clocks peers sent in their handshakes
+#[derive(Debug, Default)]
+pub struct NetworkTime {
+    // Offsets of the peers' clocks to the local one in milliseconds, by the
+    // network of the peer's IP, see `source`. Node ids are made up at will,
+    // a host reconnecting under new ones still counts once
+    samples: VecDeque<(IpAddr, i64)>,
+    offset: i64,
+}
+
+impl NetworkTime {
+    // Records the clock a peer at `ip` sent, received at `now`, both in
+    // milliseconds since the unix epoch. Returns the median offset of the
+    // samples, none if the peer sent no clock or its network was sampled
+    // before
+    pub fn add_sample(&mut self, ip: IpAddr, their_time: u64, now: u128) -> Option<i64> {
+        let source = source(ip);
+        if their_time == 0 || self.samples.iter().any(|(sampled, _)| *sampled == source) {
+            return None;
+        }
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        let offset = (their_time as i128 - now as i128).clamp(i64::MIN as i128, i64::MAX as i128);
+        self.samples.push_back((source, offset as i64));
+
+        let median = self.median()?;
+        self.offset = match self.samples.len() >= MIN_SOURCES && median.abs() <= MAX_ADJUSTMENT {
+            true => median,
+            false => 0,
+        };
+        Some(median)
+    }
+
+    pub fn median(&self) -> Option<i64> {
+        let mut offsets: Vec<i64> = self.samples.iter().map(|(_, offset)| *offset).collect();
+        offsets.sort_unstable();
+        offsets.get(offsets.len() / 2).copied()
+    }
+
+    // Milliseconds the local clock is adjusted by
+    pub fn offset(&self) -> i64 {
+        self.offset
+    }
+
+    pub fn adjust(&self, now: u128) -> u128 {
+        now.saturating_add_signed(self.offset as i128)
+    }
+}
+
+// Network a sample counts for, the /16 of IPv4 peers and the /32 of IPv6
+// ones. Addresses in one network are cheap to come by for a single host
+fn source(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, 0, 0))
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, ..] = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(a, b, 0, 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn adjusts_by_the_median_offset_within_bounds() {
+        let mut time = NetworkTime::default();
+        let now = 1_000_000_000;
+
+        let peer = |network: u8| IpAddr::from([10, network, 0, 1]);
+
+        // Too few samples to adjust yet
+        for (network, offset) in [(1, 4_000), (2, -1_000), (3, 2_000), (4, 3_000)] {
+            time.add_sample(peer(network), (now as i64 + offset) as u64, now as u128);
+        }
+        assert_eq!(time.offset(), 0);
+        assert_eq!(time.median(), Some(3_000));
+
+        // Neither other hosts of a sampled network nor missing clocks count
+        let neighbour = IpAddr::from([10, 1, 200, 7]);
+        assert_eq!(time.add_sample(neighbour, now + 9_000, now as u128), None);
+        assert_eq!(time.add_sample(peer(5), 0, now as u128), None);
+        let mapped = "::ffff:10.2.3.4".parse().unwrap();
+        assert_eq!(time.add_sample(mapped, now + 9_000, now as u128), None);
+
+        assert_eq!(
+            time.add_sample(peer(5), now + 2_500, now as u128),
+            Some(2_500)
+        );
+        assert_eq!(time.offset(), 2_500);
+        assert_eq!(time.adjust(now as u128), now as u128 + 2_500);
+
+        // Once the median is too far off the local clock is trusted instead
+        let skewed = now + MAX_ADJUSTMENT as u64 + 1;
+        for network in 6..12 {
+            time.add_sample(peer(network), skewed, now as u128);
+        }
+        assert!(time.median().unwrap() > MAX_ADJUSTMENT);
+        assert_eq!(time.offset(), 0);
+    }
+}
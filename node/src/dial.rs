@@ -0,0 +1,178 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use tokio::{
+    net::TcpStream,
+    sync::{Mutex, Semaphore},
+};
+
+// Addresses of host names and when they expire
+type Resolved = HashMap<String, (Vec<SocketAddr>, Instant)>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DialConfig {
+    // Connections being set up at once, further dials wait for a slot
+    pub max_parallel: usize,
+    // How long resolved host names are reused
+    pub dns_ttl: Duration,
+    // How long an address isn't dialed again after a failed dial
+    pub cooldown: Duration,
+    // Time a dial may take before it's given up
+    pub timeout: Duration,
+}
+
+impl Default for DialConfig {
+    fn default() -> Self {
+        Self {
+            max_parallel: 8,
+            dns_ttl: Duration::from_secs(300),
+            cooldown: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+// Every outbound connection goes through the queue. When many peers drop at
+// once and their addresses are dialed again, only `max_parallel` dials run
+// while the others wait, addresses that just failed are skipped and the
+// seeds' host names are resolved once per TTL instead of on every dial
+#[derive(Debug, Clone)]
+pub struct DialQueue {
+    config: DialConfig,
+    slots: Arc<Semaphore>,
+    resolved: Arc<Mutex<Resolved>>,
+    // When the last failed dial of an address was
+    failed: Arc<Mutex<HashMap<SocketAddr, Instant>>>,
+}
+
+impl Default for DialQueue {
+    fn default() -> Self {
+        Self::new(DialConfig::default())
+    }
+}
+
+impl DialQueue {
+    pub fn new(config: DialConfig) -> Self {
+        Self {
+            config,
+            slots: Arc::new(Semaphore::new(config.max_parallel.max(1))),
+            resolved: Arc::new(Mutex::new(HashMap::new())),
+            failed: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn dial(&self, address: SocketAddr) -> anyhow::Result<TcpStream> {
+        self.check_cooldown(&address).await?;
+        let _slot = self.slots.acquire().await?;
+        // Another dial may have failed while this one waited for a slot
+        self.check_cooldown(&address).await?;
+
+        let dialed =
+            match tokio::time::timeout(self.config.timeout, TcpStream::connect(address)).await {
+                Ok(dialed) => dialed.map_err(anyhow::Error::from),
+                Err(_) => Err(anyhow!("Dial timed out after {:?}", self.config.timeout)),
+            };
+
+        let mut failed = self.failed.lock().await;
+        match dialed {
+            Ok(stream) => {
+                failed.remove(&address);
+                Ok(stream)
+            }
+            Err(e) => {
+                let now = Instant::now();
+                failed.retain(|_, at| now.duration_since(*at) < self.config.cooldown);
+                failed.insert(address, now);
+                Err(e)
+            }
+        }
+    }
+
+    // Addresses of `host:port`, from the cache while the TTL lasts. IP
+    // addresses aren't looked up
+    pub async fn resolve(&self, host: &str) -> anyhow::Result<Vec<SocketAddr>> {
+        if let Ok(address) = host.parse::<SocketAddr>() {
+            return Ok(vec![address]);
+        }
+
+        let now = Instant::now();
+        if let Some((addresses, expiry)) = self.resolved.lock().await.get(host) {
+            if now < *expiry {
+                return Ok(addresses.clone());
+            }
+        }
+
+        let addresses: Vec<SocketAddr> = tokio::net::lookup_host(host).await?.collect();
+        if addresses.is_empty() {
+            bail!("{host} resolved to no addresses");
+        }
+        self.resolved.lock().await.insert(
+            host.to_string(),
+            (addresses.clone(), now + self.config.dns_ttl),
+        );
+
+        Ok(addresses)
+    }
+
+    async fn check_cooldown(&self, address: &SocketAddr) -> anyhow::Result<()> {
+        if let Some(at) = self.failed.lock().await.get(address) {
+            let elapsed = at.elapsed();
+            if elapsed < self.config.cooldown {
+                bail!(
+                    "Dial to {address} failed {}s ago, retrying after {}s",
+                    elapsed.as_secs(),
+                    self.config.cooldown.as_secs()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn limits_dials_and_cools_down_failed_addresses() {
+        let queue = DialQueue::new(DialConfig {
+            max_parallel: 1,
+            ..DialConfig::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        assert!(queue.dial(address).await.is_ok());
+
+        // A held slot keeps the next dial waiting
+        let slot = queue.slots.clone().acquire_owned().await.unwrap();
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.dial(address).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        drop(slot);
+        assert!(waiting.await.unwrap().is_ok());
+
+        // Nothing listens on the address once the listener is gone
+        drop(listener);
+        assert!(queue.dial(address).await.is_err());
+        let error = queue.dial(address).await.unwrap_err();
+        assert!(error.to_string().contains("retrying after"));
+
+        assert_eq!(
+            queue.resolve(&address.to_string()).await.unwrap(),
+            vec![address]
+        );
+        let resolved = queue.resolve("localhost:7878").await.unwrap();
+        assert!(resolved.iter().all(|address| address.port() == 7878));
+        assert!(queue.resolved.lock().await.contains_key("localhost:7878"));
+    }
+}
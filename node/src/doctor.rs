@@ -0,0 +1,401 @@
+use std::{
+    fmt,
+    net::IpAddr,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail};
+use tokio::{
+    fs,
+    net::{TcpListener, UdpSocket},
+};
+
+use crate::{
+    clock::{MAX_ADJUSTMENT, SKEW_WARNING},
+    storage::Storage,
+    supervisor::{ChainConfig, Supervisor},
+    webhooks::WebhookDispatcher,
+};
+
+pub const DEFAULT_NTP_SERVER: &str = "pool.ntp.org:123";
+const NTP_TIMEOUT: Duration = Duration::from_secs(3);
+// Seconds from the NTP epoch, 1900, to the unix epoch
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+// File written and removed again to check the data directory is writable
+const PROBE_FILE: &str = ".doctor";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    // The node runs, but likely not as intended
+    Warning,
+    // The node won't start or can't follow the chain
+    Failed,
+}
+
+// Result of one check, with what to do about it unless it passed
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub check: String,
+    pub status: Status,
+    pub detail: String,
+    pub advice: Option<String>,
+}
+
+impl Diagnostic {
+    fn ok(check: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            check: check.into(),
+            status: Status::Ok,
+            detail: detail.into(),
+            advice: None,
+        }
+    }
+
+    fn warning(check: impl Into<String>, detail: impl Into<String>, advice: &str) -> Self {
+        Self {
+            status: Status::Warning,
+            advice: Some(advice.to_string()),
+            ..Self::ok(check, detail)
+        }
+    }
+
+    fn failed(check: impl Into<String>, detail: impl Into<String>, advice: &str) -> Self {
+        Self {
+            status: Status::Failed,
+            advice: Some(advice.to_string()),
+            ..Self::ok(check, detail)
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self.status {
+            Status::Ok => " ok ",
+            Status::Warning => "warn",
+            Status::Failed => "FAIL",
+        };
+        write!(f, "[{status}] {}: {}", self.check, self.detail)?;
+        if let Some(advice) = self.advice.as_ref() {
+            write!(f, "\n       {advice}")?;
+        }
+        Ok(())
+    }
+}
+
+// Checks a node with these chains could start and follow the network: the
+// config, the data directories and what's stored in them, that the ports are
+// free and that the clock agrees with the NTP server. Nothing is changed,
+// an existing data directory is only written a probe file to
+pub async fn examine(configs: &[ChainConfig], ntp_server: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![check_config(configs).await];
+
+    for config in configs {
+        let network = config.network;
+        diagnostics.push(check_data_dir(network.name(), &config.data_dir).await);
+        diagnostics.extend(check_storage(config).await);
+
+        let ports = [
+            ("peer", Some((config.bind, config.port))),
+            ("RPC", Some((IpAddr::from([127, 0, 0, 1]), config.rpc_port))),
+            (
+                "explorer",
+                config
+                    .explorer_port
+                    .map(|port| (IpAddr::from([127, 0, 0, 1]), port)),
+            ),
+        ];
+        for (name, address) in ports {
+            if let Some((ip, port)) = address {
+                diagnostics.push(check_port(&format!("{network} {name} port"), ip, port).await);
+            }
+        }
+    }
+
+    diagnostics.push(check_clock(ntp_server).await);
+    diagnostics
+}
+
+async fn check_config(configs: &[ChainConfig]) -> Diagnostic {
+    const CHECK: &str = "config";
+
+    let chains = configs
+        .iter()
+        .try_fold(Supervisor::new(), |supervisor, config| {
+            supervisor.chain(config.clone())
+        });
+    if let Err(e) = chains {
+        return Diagnostic::failed(
+            CHECK,
+            e.to_string(),
+            "Fix the flags or AURELIUS_* variables it names",
+        );
+    }
+
+    for config in configs {
+        let Some(path) = config.webhooks.as_ref() else {
+            continue;
+        };
+        let parsed = match fs::read_to_string(path).await {
+            Ok(json) => WebhookDispatcher::parse_config(&json),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = parsed {
+            return Diagnostic::failed(
+                CHECK,
+                format!("webhooks file {}: {e}", path.display()),
+                "Point AURELIUS_WEBHOOKS at a JSON list of webhooks with valid URLs",
+            );
+        }
+    }
+
+    let networks: Vec<&str> = configs.iter().map(|config| config.network.name()).collect();
+    Diagnostic::ok(CHECK, format!("valid for {}", networks.join(", ")))
+}
+
+async fn check_data_dir(network: &str, dir: &Path) -> Diagnostic {
+    let check = format!("{network} data directory");
+
+    let metadata = match fs::metadata(dir).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Diagnostic::ok(
+                check,
+                format!("{} is created on the first start", dir.display()),
+            );
+        }
+        Err(e) => {
+            return Diagnostic::failed(
+                check,
+                format!("{}: {e}", dir.display()),
+                "Make the directory accessible to the user running the node",
+            );
+        }
+    };
+    if !metadata.is_dir() {
+        return Diagnostic::failed(
+            check,
+            format!("{} isn't a directory", dir.display()),
+            "Set AURELIUS_DATA_DIR to a directory",
+        );
+    }
+
+    let probe = dir.join(PROBE_FILE);
+    let written = match fs::write(&probe, b"").await {
+        Ok(()) => fs::remove_file(&probe).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
+        return Diagnostic::failed(
+            check,
+            format!("{} isn't writable: {e}", dir.display()),
+            "Give the user running the node ownership of the directory",
+        );
+    }
+
+    // The directory holds the node's identity and audit keys
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        if metadata.permissions().mode() & 0o077 != 0 {
+            return Diagnostic::warning(
+                check,
+                format!("{} is accessible to other users", dir.display()),
+                &format!("Restrict it with `chmod 700 {}`", dir.display()),
+            );
+        }
+    }
+
+    Diagnostic::ok(check, format!("{} is writable", dir.display()))
+}
+
+// Loads what the node loads on start, checksums and all, and checks the
+// chain's UTXO set and index against its blocks
+async fn check_storage(config: &ChainConfig) -> Vec<Diagnostic> {
+    let (network, dir) = (config.network, &config.data_dir);
+    let check = format!("{network} storage");
+    if !dir.is_dir() {
+        return Vec::new();
+    }
+    let storage = match Storage::open(dir).await {
+        Ok(storage) => storage,
+        Err(e) => {
+            return vec![Diagnostic::failed(
+                check,
+                e.to_string(),
+                "Make the directory accessible to the user running the node",
+            )]
+        }
+    };
+    let reindex = "Restart the node with --reindex to download the chain again";
+
+    let chain = match storage.load_chain(network, config.params).await {
+        Ok(Some(chain)) => chain,
+        Ok(None) => return vec![Diagnostic::ok(check, "no chain stored yet")],
+        Err(e) => return vec![Diagnostic::failed(check, e.to_string(), reindex)],
+    };
+    if let Err(e) = chain.check_integrity() {
+        return vec![Diagnostic::failed(check, e.to_string(), reindex)];
+    }
+
+    let mut diagnostics = Vec::new();
+    if let Err(e) = storage.load_checkpoint().await {
+        diagnostics.push(Diagnostic::failed(check.clone(), e.to_string(), reindex));
+    }
+    if let Err(e) = storage.load_stats().await {
+        diagnostics.push(Diagnostic::failed(check.clone(), e.to_string(), reindex));
+    }
+    if diagnostics.is_empty() {
+        diagnostics.push(Diagnostic::ok(
+            check,
+            format!("chain of {} blocks is intact", chain.height()),
+        ));
+    }
+    diagnostics
+}
+
+async fn check_port(check: &str, ip: IpAddr, port: u16) -> Diagnostic {
+    match TcpListener::bind((ip, port)).await {
+        Ok(_) => Diagnostic::ok(check, format!("{ip}:{port} is free")),
+        Err(e) => Diagnostic::failed(
+            check,
+            format!("can't listen on {ip}:{port}: {e}"),
+            "Stop whatever uses the port, e.g. a running node, or pick another port",
+        ),
+    }
+}
+
+async fn check_clock(ntp_server: &str) -> Diagnostic {
+    const CHECK: &str = "clock";
+
+    let offset = match tokio::time::timeout(NTP_TIMEOUT, ntp_offset(ntp_server)).await {
+        Ok(Ok(offset)) => offset,
+        Ok(Err(e)) => {
+            return Diagnostic::warning(
+                CHECK,
+                format!("no time from {ntp_server}: {e}"),
+                "Allow UDP port 123 or set AURELIUS_NTP_SERVER to a reachable server",
+            )
+        }
+        Err(_) => {
+            return Diagnostic::warning(
+                CHECK,
+                format!("{ntp_server} didn't answer in {NTP_TIMEOUT:?}"),
+                "Allow UDP port 123 or set AURELIUS_NTP_SERVER to a reachable server",
+            )
+        }
+    };
+
+    let direction = if offset < 0 { "ahead of" } else { "behind" };
+    let detail = format!(
+        "{:.1}s {direction} {ntp_server}",
+        offset.unsigned_abs() as f64 / 1_000.0
+    );
+    let advice = "Sync the system clock, e.g. enable NTP with `timedatectl set-ntp true`";
+
+    // Peers can only adjust for so much, past that blocks are judged with
+    // the wrong time
+    match offset.abs() {
+        skew if skew > MAX_ADJUSTMENT => Diagnostic::failed(CHECK, detail, advice),
+        skew if skew > SKEW_WARNING => Diagnostic::warning(CHECK, detail, advice),
+        _ => Diagnostic::ok(CHECK, detail),
+    }
+}
+
+// Milliseconds the server's clock is ahead of the local one, by a single
+// SNTP request timed from both ends
+async fn ntp_offset(server: &str) -> anyhow::Result<i64> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    socket.connect(server).await?;
+
+    // Version 4, client mode
+    let mut request = [0u8; 48];
+    request[0] = 0x23;
+    let sent = now_millis();
+    socket.send(&request).await?;
+
+    let mut response = [0u8; 48];
+    if socket.recv(&mut response).await? < response.len() {
+        bail!("truncated response");
+    }
+    let received = now_millis();
+
+    // Transmit timestamp, seconds and a binary fraction of a second
+    let seconds = u32::from_be_bytes(response[40..44].try_into()?) as u64;
+    let fraction = u32::from_be_bytes(response[44..48].try_into()?) as u64;
+    let server_time = seconds
+        .checked_sub(NTP_UNIX_OFFSET)
+        .ok_or_else(|| anyhow!("server sent no time"))?
+        * 1_000
+        + ((fraction * 1_000) >> 32);
+
+    let local = (sent + received) / 2;
+    Ok((server_time as i128 - local as i128) as i64)
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis())
+}
+
+#[cfg(test)]
+mod test {
+    use corelib::config::Network;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_taken_ports_corrupt_storage_and_bad_config() {
+        let dir = std::env::temp_dir().join(format!("aurelius-{}", uuid::Uuid::new_v4()));
+        let mut config = ChainConfig::new(Network::Regtest, &dir);
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        config.port = taken.local_addr().unwrap().port();
+        config.rpc_port = 0;
+
+        // Nothing stored yet, the clock can't be checked against a server
+        // that isn't there
+        let diagnostics = examine(&[config.clone()], "127.0.0.1:9").await;
+        let status = |check: &str| {
+            diagnostics
+                .iter()
+                .find(|diagnostic| diagnostic.check == check)
+                .map(|diagnostic| diagnostic.status)
+        };
+        assert_eq!(status("config"), Some(Status::Ok));
+        assert_eq!(status("regtest data directory"), Some(Status::Ok));
+        assert_eq!(status("regtest peer port"), Some(Status::Failed));
+        assert_eq!(status("regtest RPC port"), Some(Status::Ok));
+        assert_eq!(status("clock"), Some(Status::Warning));
+        assert!(diagnostics[2]
+            .to_string()
+            .starts_with("[FAIL] regtest peer port"));
+
+        let storage = Storage::open(&config.data_dir).await.unwrap();
+        storage.save_stats(&Default::default()).await.unwrap();
+        let stats = config.data_dir.join("stats.bin");
+        let mut bytes = std::fs::read(&stats).unwrap();
+        bytes[0] ^= 1;
+        std::fs::write(&stats, bytes).unwrap();
+        let genesis = corelib::block::Block::genesis(config.network);
+        storage.append_blocks(&[genesis]).await.unwrap();
+
+        let diagnostics = check_storage(&config).await;
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].status, Status::Failed);
+        assert!(diagnostics[0].detail.contains("stats.bin"));
+        assert!(diagnostics[0]
+            .advice
+            .as_ref()
+            .unwrap()
+            .contains("--reindex"));
+
+        config.rpc_port = config.port;
+        assert_eq!(check_config(&[config]).await.status, Status::Failed);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
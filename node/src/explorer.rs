@@ -0,0 +1,175 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use corelib::{address, block::Block};
+use hex::FromHex;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tracing::info;
+
+use crate::node::Node;
+
+// Blocks listed by `/blocks/latest`, tip first
+const LATEST_BLOCKS: u64 = 10;
+
+type ApiResult = Result<Json<Value>, (StatusCode, Json<Value>)>;
+
+// Read-only HTTP API for block explorers, served on its own port next to the
+// RPC. Unlike the RPC, blocks, transactions and UTXOs are plain JSON with
+// byte arrays hex encoded:
+//
+// - `GET /blocks/latest` headers of the latest blocks of the best chain
+// - `GET /block/{hash}` a block on any known branch
+// - `GET /tx/{hash}` a mempool or confirmed transaction and its status
+// - `GET /address/{address}/utxos` confirmed outputs an address, or a hex
+//   encoded public key, can spend
+#[derive(Debug, Clone)]
+pub struct Explorer {
+    node: Node,
+}
+
+impl Explorer {
+    pub fn new(node: Node) -> Self {
+        Self { node }
+    }
+
+    // Serves forever, only local clients can connect
+    pub async fn serve(self, port: u16) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+        info!("Explorer listening on {}", listener.local_addr()?);
+
+        axum::serve(listener, self.router()).await?;
+        Ok(())
+    }
+
+    fn router(self) -> Router {
+        Router::new()
+            .route("/blocks/latest", get(latest_blocks))
+            .route("/block/:hash", get(block))
+            .route("/tx/:hash", get(transaction))
+            .route("/address/:address/utxos", get(utxos))
+            .with_state(self.node)
+    }
+}
+
+async fn latest_blocks(State(node): State<Node>) -> ApiResult {
+    let Some(chain) = node.chain_snapshot().await else {
+        return Ok(Json(json!([])));
+    };
+    let height = chain.height();
+
+    let blocks: Vec<Value> = (height.saturating_sub(LATEST_BLOCKS)..height)
+        .rev()
+        .filter_map(|height| chain.block(height))
+        .map(summary)
+        .collect();
+    Ok(Json(json!(blocks)))
+}
+
+async fn block(State(node): State<Node>, Path(hash): Path<String>) -> ApiResult {
+    let hash = parse_hash(&hash)?;
+
+    match node.get_block(&hash).await {
+        Some(block) => Ok(Json(json!(block))),
+        None => Err(error(StatusCode::NOT_FOUND, "Block not found")),
+    }
+}
+
+async fn transaction(State(node): State<Node>, Path(hash): Path<String>) -> ApiResult {
+    let hash = parse_hash(&hash)?;
+    let Some(txn) = node.get_raw_transaction(&hash).await else {
+        return Err(error(StatusCode::NOT_FOUND, "Transaction not found"));
+    };
+    let status = node.get_tx_confirmations(&hash).await;
+    Ok(Json(json!({ "transaction": txn, "status": status })))
+}
+
+async fn utxos(State(node): State<Node>, Path(owner): Path<String>) -> ApiResult {
+    let owner = address::parse_key(&owner, node.network())
+        .map_err(|e| error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+    Ok(Json(json!(node.get_unspent(&owner).await)))
+}
+
+// Header of a block with its transactions counted rather than listed
+fn summary(block: &Block) -> Value {
+    json!({
+        "header": block.header(),
+        "transactions": block.transactions().len(),
+    })
+}
+
+fn parse_hash(hash: &str) -> Result<[u8; 32], (StatusCode, Json<Value>)> {
+    <[u8; 32]>::from_hex(hash)
+        .map_err(|_| error(StatusCode::BAD_REQUEST, "Expected 32 hex encoded bytes"))
+}
+
+fn error(status: StatusCode, message: &str) -> (StatusCode, Json<Value>) {
+    (status, Json(json!({ "error": message })))
+}
+
+#[cfg(test)]
+mod test {
+    use corelib::{block::BlockBuilder, config::Network, deployment::Deployments};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_blocks_transactions_and_utxos_as_json() {
+        let (node, _) = Node::new(0);
+        let node = node.with_deployments(Deployments::for_network(Network::Mainnet));
+        let genesis = Block::genesis(Network::Mainnet);
+        node.process_block(genesis.clone()).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        tokio::spawn(Explorer::new(node.clone()).serve(port));
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let get = |path: String| async move {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let request =
+                format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+            stream.write_all(request.as_bytes()).await.unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            let status = head.split(' ').nth(1).unwrap().parse::<u16>().unwrap();
+            (status, serde_json::from_str::<Value>(body).unwrap())
+        };
+
+        let (status, blocks) = get("/blocks/latest".into()).await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            blocks[0]["header"]["hash"],
+            json!(hex::encode(genesis.hash()))
+        );
+        assert_eq!(blocks[0]["transactions"], json!(1));
+
+        let (_, block) = get(format!("/block/{}", hex::encode(genesis.hash()))).await;
+        assert_eq!(serde_json::from_value::<Block>(block).unwrap(), genesis);
+        assert_eq!(get(format!("/block/{}", "00".repeat(32))).await.0, 404);
+        assert_eq!(get("/block/not-hex".into()).await.0, 400);
+
+        let coinbase = &genesis.transactions()[0];
+        let (_, found) = get(format!("/tx/{}", hex::encode(coinbase.hash_id))).await;
+        assert_eq!(
+            found["transaction"]["receiver"],
+            json!(hex::encode([0u8; 32]))
+        );
+        assert_eq!(found["status"]["status"], json!("confirmed"));
+        assert_eq!(found["status"]["confirmations"], json!(1));
+
+        let (_, utxos) = get(format!("/address/{}/utxos", hex::encode([0u8; 32]))).await;
+        assert_eq!(utxos.as_array().unwrap().len(), 1);
+        assert_eq!(utxos[0]["kind"], json!("confirmed"));
+    }
+}
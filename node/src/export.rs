@@ -0,0 +1,241 @@
+use std::{fmt::Write as _, path::Path};
+
+use corelib::{block::Block, blockchain::BlockChain, transaction::Transaction, utxo::UTXO};
+use tokio::{fs, io::AsyncWriteExt};
+
+const BLOCKS_HEADER: &str =
+    "height,hash,previous_hash,timestamp,version,difficulty,nonce,merkle_root,transactions";
+const TRANSACTIONS_HEADER: &str =
+    "txid,block_hash,height,position,version,sender,receiver,timestamp,locktime,coinbase,size";
+const INPUTS_HEADER: &str = "txid,input,spent_txid,spent_vout,value,script_pubkey";
+const OUTPUTS_HEADER: &str = "txid,vout,value,script_pubkey";
+
+// Rows written to every table
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportSummary {
+    pub blocks: usize,
+    pub transactions: usize,
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+// Rows of the tables, one CSV line each
+#[derive(Debug, Default)]
+struct Tables {
+    blocks: String,
+    transactions: String,
+    inputs: String,
+    outputs: String,
+    summary: ExportSummary,
+}
+
+// Writes the best chain from `from` on to `blocks.csv`, `transactions.csv`,
+// `inputs.csv` and `outputs.csv` in the directory, for analysis in tools that
+// can't read the node's storage.
+//
+// Exporting from height 0 replaces the tables, later heights append to them
+// so a previous export can be continued from the height after its last block
+pub async fn export_chain(
+    chain: &BlockChain,
+    dir: &Path,
+    from: u64,
+) -> anyhow::Result<ExportSummary> {
+    fs::create_dir_all(dir).await?;
+
+    let mut tables = Tables::default();
+    for height in from..chain.height() {
+        let block = chain
+            .block(height)
+            .expect("heights below the chain height exist");
+        tables.add_block(block);
+    }
+
+    for (name, header, rows) in [
+        ("blocks.csv", BLOCKS_HEADER, &tables.blocks),
+        (
+            "transactions.csv",
+            TRANSACTIONS_HEADER,
+            &tables.transactions,
+        ),
+        ("inputs.csv", INPUTS_HEADER, &tables.inputs),
+        ("outputs.csv", OUTPUTS_HEADER, &tables.outputs),
+    ] {
+        let path = dir.join(name);
+        let fresh = from == 0 || !fs::try_exists(&path).await?;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(!fresh)
+            .truncate(fresh)
+            .open(&path)
+            .await?;
+        if fresh {
+            file.write_all(format!("{header}\n").as_bytes()).await?;
+        }
+        file.write_all(rows.as_bytes()).await?;
+        file.flush().await?;
+    }
+
+    Ok(tables.summary)
+}
+
+impl Tables {
+    fn add_block(&mut self, block: &Block) {
+        let header = block.header();
+        row(
+            &mut self.blocks,
+            &[
+                header.index.to_string(),
+                hex::encode(header.hash),
+                header.previous_hash.clone(),
+                header.timestamp.to_string(),
+                header.version.to_string(),
+                header.difficulty.to_string(),
+                header.nonce.to_string(),
+                hex::encode(header.merkle_root),
+                block.transactions().len().to_string(),
+            ],
+        );
+        self.summary.blocks += 1;
+
+        for (position, txn) in block.transactions().iter().enumerate() {
+            self.add_transaction(block, position, txn);
+        }
+    }
+
+    fn add_transaction(&mut self, block: &Block, position: usize, txn: &Transaction) {
+        let txid = hex::encode(txn.hash_id);
+        row(
+            &mut self.transactions,
+            &[
+                txid.clone(),
+                hex::encode(block.hash()),
+                block.index().to_string(),
+                position.to_string(),
+                txn.version.to_string(),
+                hex::encode(txn.sender),
+                hex::encode(txn.receiver),
+                txn.timestamp.to_string(),
+                txn.locktime.to_string(),
+                txn.is_coinbase().to_string(),
+                txn.serialized_size().to_string(),
+            ],
+        );
+        self.summary.transactions += 1;
+
+        for (input, spent) in txn.inputs.iter().enumerate() {
+            let outpoint = spent.outpoint();
+            row(
+                &mut self.inputs,
+                &[
+                    txid.clone(),
+                    input.to_string(),
+                    outpoint.map(|o| hex::encode(o.txid)).unwrap_or_default(),
+                    outpoint.map(|o| o.vout.to_string()).unwrap_or_default(),
+                    spent.value().to_string(),
+                    spent.script_pubkey().unwrap_or_default().to_string(),
+                ],
+            );
+            self.summary.inputs += 1;
+        }
+
+        for output in txn.outputs.iter() {
+            // Outputs are stored as paid, the script is the one they're
+            // confirmed with
+            let script_pubkey = output
+                .clone()
+                .confirm_utxo(txn.hash_id, block.index() as u32, txn.is_coinbase())
+                .ok()
+                .as_ref()
+                .and_then(UTXO::script_pubkey)
+                .map(str::to_string)
+                .unwrap_or_default();
+            row(
+                &mut self.outputs,
+                &[
+                    txid.clone(),
+                    output.index().to_string(),
+                    output.value().to_string(),
+                    script_pubkey,
+                ],
+            );
+            self.summary.outputs += 1;
+        }
+    }
+}
+
+// Appends a CSV line, fields with separators or quotes are quoted
+fn row(table: &mut String, fields: &[String]) {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            table.push(',');
+        }
+        if field.contains([',', '"', '\n']) {
+            let _ = write!(table, "\"{}\"", field.replace('"', "\"\""));
+        } else {
+            table.push_str(field);
+        }
+    }
+    table.push('\n');
+}
+
+#[cfg(test)]
+mod test {
+    use corelib::{block::BlockBuilder, config::Network};
+
+    use super::*;
+
+    fn mined(index: u64, previous: &Block) -> Block {
+        let mut block = BlockBuilder::new(index, hex::encode(previous.hash()), 1, [4u8; 32])
+            .template()
+            .unwrap();
+        block.mine_block();
+        block
+    }
+
+    async fn lines(path: &Path) -> Vec<String> {
+        let contents = fs::read_to_string(path).await.unwrap();
+        contents.lines().map(str::to_string).collect()
+    }
+
+    #[tokio::test]
+    async fn exports_tables_and_continues_from_a_height() {
+        let dir = std::env::temp_dir().join(format!("aurelius-{}", uuid::Uuid::new_v4()));
+        let genesis = Block::genesis(Network::Mainnet);
+        let mut chain = BlockChain::new(genesis.clone(), Network::Mainnet).unwrap();
+        let first = mined(1, &genesis);
+        chain.add_block(first.clone()).unwrap();
+
+        let summary = export_chain(&chain, &dir, 0).await.unwrap();
+        assert_eq!(
+            summary,
+            ExportSummary {
+                blocks: 2,
+                transactions: 2,
+                inputs: 0,
+                outputs: 2,
+            }
+        );
+        let blocks = lines(&dir.join("blocks.csv")).await;
+        assert_eq!(blocks[0], BLOCKS_HEADER);
+        assert!(blocks[2].starts_with(&format!("1,{}", hex::encode(first.hash()))));
+        assert_eq!(lines(&dir.join("inputs.csv")).await, vec![INPUTS_HEADER]);
+
+        chain.add_block(mined(2, &first)).unwrap();
+        let summary = export_chain(&chain, &dir, 2).await.unwrap();
+        assert_eq!(summary.blocks, 1);
+        assert_eq!(lines(&dir.join("blocks.csv")).await.len(), 4);
+        assert_eq!(lines(&dir.join("outputs.csv")).await.len(), 4);
+
+        // Starting over replaces the tables
+        export_chain(&chain, &dir, 0).await.unwrap();
+        assert_eq!(lines(&dir.join("transactions.csv")).await.len(), 4);
+
+        let mut table = String::new();
+        row(&mut table, &["a,b".to_string(), "say \"hi\"".to_string()]);
+        assert_eq!(table, "\"a,b\",\"say \"\"hi\"\"\"\n");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
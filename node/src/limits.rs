@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+
+// Inbound connections served at once and how fast each may send messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    pub max_inbound: usize,
+    pub messages_per_sec: u32,
+    // Messages a connection may send at once after being quiet
+    pub burst: u32,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_inbound: 125,
+            messages_per_sec: 50,
+            burst: 200,
+        }
+    }
+}
+
+// Messages a connection is allowed to send, refilled at the configured rate
+// up to the burst. A peer sending faster runs out and gets its messages
+// dropped until the bucket refilled
+#[derive(Debug, Clone)]
+pub struct MessageRate {
+    per_sec: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl MessageRate {
+    pub fn new(limits: &ConnectionLimits, now: Instant) -> Self {
+        Self {
+            per_sec: limits.messages_per_sec as f64,
+            burst: limits.burst.max(1) as f64,
+            tokens: limits.burst.max(1) as f64,
+            refilled: now,
+        }
+    }
+
+    // Takes a message from the bucket, false if there's none left
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.per_sec).min(self.burst);
+        self.refilled = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    // Time until the next message is allowed
+    pub fn retry_after(&self) -> Duration {
+        if self.tokens >= 1.0 || self.per_sec == 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - self.tokens) / self.per_sec)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allows_bursts_then_the_configured_rate() {
+        let limits = ConnectionLimits {
+            max_inbound: 1,
+            messages_per_sec: 10,
+            burst: 3,
+        };
+        let start = Instant::now();
+        let mut rate = MessageRate::new(&limits, start);
+
+        assert!((0..3).all(|_| rate.allow(start)));
+        assert!(!rate.allow(start));
+        assert_eq!(rate.retry_after(), Duration::from_millis(100));
+
+        // Refills at 10 a second, never past the burst
+        assert!(rate.allow(start + Duration::from_millis(100)));
+        assert!(!rate.allow(start + Duration::from_millis(150)));
+        let later = start + Duration::from_secs(60);
+        assert!((0..3).all(|_| rate.allow(later)));
+        assert!(!rate.allow(later));
+    }
+}
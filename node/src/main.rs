@@ -1,22 +1,579 @@
 #![allow(unused)]
 
-use corelib::{block::Block, transaction::Transaction, utxo::UTXO};
-use std::{collections::HashSet, io::Read, time::Duration};
+use corelib::{
+    block::Block,
+    config::{ChainParams, Network},
+    deployment::Rules,
+    keystore::Keystore,
+    metrics::METRICS,
+    net::protocol::SupportedVersions,
+    rng::Entropy,
+    transaction::{Transaction, MAX_COINBASE_TAG},
+    utxo::UTXO,
+};
+use std::{
+    collections::HashSet,
+    io::Read,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::anyhow;
-use node::Node;
+use audit::AuditLog;
+use dial::DialConfig;
+use hex::FromHex;
+use limits::ConnectionLimits;
+use memory::MemoryBudget;
+use miner::MiningConfig;
+use misbehavior::BanConfig;
+use relay::{LocalRelayConfig, SeenCacheConfig};
+use storage::Storage;
+use supervisor::{ChainConfig, Supervisor};
 use tokio::{
     io::{AsyncReadExt as _, AsyncWriteExt},
     net::{TcpListener, TcpStream},
 };
 use tracing::{error, info};
 
+mod audit;
+mod clock;
+mod dial;
+mod doctor;
 pub mod errors;
+mod explorer;
+mod export;
+mod limits;
+mod memory;
+mod mempool;
+mod miner;
+mod misbehavior;
 mod node;
+mod peer;
+mod pipeline;
+mod relay;
+mod rpc;
+mod stats;
+mod storage;
+mod supervisor;
+mod sync;
+mod template;
+mod webhooks;
+
+const DEFAULT_DATA_DIR: &str = "data";
+const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+// Peers a delayed local transaction is announced to first
+const DEFAULT_RELAY_FANOUT: usize = 2;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
 
-    let node = Node::new();
+    // usage: node [--network <name>]... [--reindex] [--encrypt] [--audit-log]
+    //             [--export <dir> [--export-from <height>]] [doctor] [port]
+    //             [seed address...]
+    //
+    // Every network runs on its default port unless a single network is
+    // given, which can be run on another port and with seed addresses.
+    // `--reindex` drops the stored chains and downloads them again, which
+    // gets a node out of safe mode after its storage was found corrupt.
+    // `--encrypt` encrypts the connections with peers that encrypt too.
+    // `--audit-log` prints and verifies the networks' audit logs instead of
+    // running the node. `--export` writes the stored chains as CSV tables to
+    // a subdirectory per network, from the given height on if continuing an
+    // earlier export. `doctor` checks the node could start with the config
+    // and follow the network instead of running it, printing what to fix
+    let mut networks = Vec::new();
+    let mut reindex = false;
+    let mut encrypt = false;
+    let mut audit_log = false;
+    let mut export_dir = None;
+    let mut export_from = 0;
+    let mut doctor = false;
+    let mut args = std::env::args().skip(1).peekable();
+    loop {
+        match args.peek().map(String::as_str) {
+            Some("--network") => {
+                args.next();
+                let network = args
+                    .next()
+                    .ok_or_else(|| anyhow!("Missing network name"))?
+                    .parse::<Network>()
+                    .map_err(|e| anyhow!(e))?;
+                networks.push(network);
+            }
+            Some("--reindex") => {
+                args.next();
+                reindex = true;
+            }
+            Some("--encrypt") => {
+                args.next();
+                encrypt = true;
+            }
+            Some("--audit-log") => {
+                args.next();
+                audit_log = true;
+            }
+            Some("--export") => {
+                args.next();
+                let dir = args
+                    .next()
+                    .ok_or_else(|| anyhow!("Missing export directory"))?;
+                export_dir = Some(PathBuf::from(dir));
+            }
+            Some("doctor") => {
+                args.next();
+                doctor = true;
+            }
+            Some("--export-from") => {
+                args.next();
+                export_from = args
+                    .next()
+                    .ok_or_else(|| anyhow!("Missing export height"))?
+                    .parse::<u64>()
+                    .map_err(|e| anyhow!("Invalid export height: {e}"))?;
+            }
+            _ => break,
+        }
+    }
+    if networks.is_empty() {
+        networks.push(Network::Mainnet);
+    }
+
+    let port = args
+        .next()
+        .map(|p| p.parse::<u16>())
+        .transpose()
+        .map_err(|e| anyhow!("Invalid port: {e}"))?;
+    // Seeds are `host:port`, host names are resolved when dialing
+    let seeds = args
+        .map(|seed| match seed.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(seed),
+            _ => Err(anyhow!(
+                "Invalid seed address {seed}, expected <host>:<port>"
+            )),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if networks.len() > 1 && (port.is_some() || !seeds.is_empty()) {
+        return Err(anyhow!(
+            "A port and seeds can only be given when running a single network"
+        ));
+    }
+
+    let data_dir =
+        std::env::var("AURELIUS_DATA_DIR").unwrap_or_else(|_| DEFAULT_DATA_DIR.to_string());
+    if audit_log {
+        for network in networks {
+            print_audit_log(network, &ChainConfig::new(network, &data_dir).data_dir).await?;
+        }
+        return Ok(());
+    }
+    // Consensus parameters of a custom network, e.g.
+    // `halving_interval=150,retarget_interval=5`
+    let chain_params = std::env::var("AURELIUS_CHAIN_PARAMS")
+        .ok()
+        .map(|params| params.parse::<ChainParams>())
+        .transpose()?
+        .unwrap_or_default();
+
+    if let Some(export_dir) = export_dir {
+        for network in networks {
+            let data_dir = ChainConfig::new(network, &data_dir).data_dir;
+            export(
+                network,
+                chain_params,
+                &data_dir,
+                &export_dir.join(network.name()),
+                export_from,
+            )
+            .await?;
+        }
+        return Ok(());
+    }
+    // Address peers connect to, e.g. 0.0.0.0 or :: to accept other machines
+    let bind = std::env::var("AURELIUS_BIND")
+        .ok()
+        .map(|ip| ip.parse::<IpAddr>())
+        .transpose()
+        .map_err(|e| anyhow!("Invalid bind address: {e}"))?;
+    // Webhooks are configured with a JSON file listing the URLs and events
+    let webhooks = std::env::var("AURELIUS_WEBHOOKS").ok().map(PathBuf::from);
+    let rpc_port = std::env::var("AURELIUS_RPC_PORT")
+        .ok()
+        .map(|p| p.parse::<u16>())
+        .transpose()
+        .map_err(|e| anyhow!("Invalid RPC port: {e}"))?;
+    if networks.len() > 1 && rpc_port.is_some() {
+        return Err(anyhow!(
+            "An RPC port can only be given when running a single network"
+        ));
+    }
+    // Replays the randomness of a simulation or benchmark run
+    let rng_seed = std::env::var("AURELIUS_RNG_SEED")
+        .ok()
+        .map(|seed| seed.parse::<u64>())
+        .transpose()
+        .map_err(|e| anyhow!("Invalid RNG seed: {e}"))?;
+    // The block explorer API only runs when given a port
+    let explorer_port = std::env::var("AURELIUS_EXPLORER_PORT")
+        .ok()
+        .map(|p| p.parse::<u16>())
+        .transpose()
+        .map_err(|e| anyhow!("Invalid explorer port: {e}"))?;
+    if networks.len() > 1 && explorer_port.is_some() {
+        return Err(anyhow!(
+            "An explorer port can only be given when running a single network"
+        ));
+    }
+
+    // Transactions submitted over RPC are held back for the delay and then
+    // announced to a few peers first, hiding that they originate here
+    let relay_delay = std::env::var("AURELIUS_TX_RELAY_DELAY_MS")
+        .ok()
+        .map(|ms| ms.parse::<u64>())
+        .transpose()
+        .map_err(|e| anyhow!("Invalid transaction relay delay: {e}"))?;
+    let relay_fanout = std::env::var("AURELIUS_TX_RELAY_FANOUT")
+        .ok()
+        .map(|n| n.parse::<usize>())
+        .transpose()
+        .map_err(|e| anyhow!("Invalid transaction relay fanout: {e}"))?
+        .unwrap_or(DEFAULT_RELAY_FANOUT);
+    let local_relay = relay_delay.filter(|ms| *ms > 0).map(|ms| LocalRelayConfig {
+        delay: Duration::from_millis(ms),
+        fanout: relay_fanout,
+    });
+
+    // Transactions and blocks peers sent are remembered to drop repeats,
+    // e.g. 50000 of them for 600 seconds
+    let mut seen_cache = SeenCacheConfig::default();
+    if let Ok(capacity) = std::env::var("AURELIUS_SEEN_CACHE_SIZE") {
+        seen_cache.capacity = capacity
+            .parse::<usize>()
+            .map_err(|e| anyhow!("Invalid seen cache size: {e}"))?;
+    }
+    if let Ok(secs) = std::env::var("AURELIUS_SEEN_CACHE_EXPIRY_SECS") {
+        seen_cache.expiry = Duration::from_secs(
+            secs.parse::<u64>()
+                .map_err(|e| anyhow!("Invalid seen cache expiry: {e}"))?,
+        );
+    }
+
+    // Peers are banned once their misbehavior adds up to the threshold, e.g.
+    // 50 for 3600 seconds
+    let mut bans = BanConfig::default();
+    if let Ok(threshold) = std::env::var("AURELIUS_BAN_THRESHOLD") {
+        bans.threshold = threshold
+            .parse::<u32>()
+            .map_err(|e| anyhow!("Invalid ban threshold: {e}"))?;
+    }
+    if let Ok(secs) = std::env::var("AURELIUS_BAN_SECS") {
+        bans.duration = Duration::from_secs(
+            secs.parse::<u64>()
+                .map_err(|e| anyhow!("Invalid ban duration: {e}"))?,
+        );
+    }
+
+    // Outbound connections set up at once, how long resolved seed names are
+    // reused and how long a failed address isn't dialed again, e.g. 8, 300
+    // and 30 seconds
+    let mut dial = DialConfig::default();
+    if let Ok(max) = std::env::var("AURELIUS_MAX_PARALLEL_DIALS") {
+        dial.max_parallel = max
+            .parse::<usize>()
+            .map_err(|e| anyhow!("Invalid number of parallel dials: {e}"))?;
+    }
+    if let Ok(secs) = std::env::var("AURELIUS_DNS_TTL_SECS") {
+        dial.dns_ttl = Duration::from_secs(
+            secs.parse::<u64>()
+                .map_err(|e| anyhow!("Invalid DNS cache TTL: {e}"))?,
+        );
+    }
+    if let Ok(secs) = std::env::var("AURELIUS_DIAL_COOLDOWN_SECS") {
+        dial.cooldown = Duration::from_secs(
+            secs.parse::<u64>()
+                .map_err(|e| anyhow!("Invalid dial cooldown: {e}"))?,
+        );
+    }
+
+    // How often the stored chain is read back and checked for bit-rot, 0
+    // turns the scrubbing off
+    let scrub_interval = std::env::var("AURELIUS_SCRUB_INTERVAL_SECS")
+        .ok()
+        .map(|secs| secs.parse::<u64>())
+        .transpose()
+        .map_err(|e| anyhow!("Invalid scrub interval: {e}"))?
+        .map(|secs| (secs > 0).then(|| Duration::from_secs(secs)));
+
+    // Deployments can be activated at a height instead, e.g. `timelocks=100`
+    // to test an upgrade on a local network
+    let activation_heights = std::env::var("AURELIUS_ACTIVATION_HEIGHTS")
+        .ok()
+        .map(|heights| parse_activation_heights(&heights))
+        .transpose()?
+        .unwrap_or_default();
+
+    // Inbound connections served at once and messages a second each of them
+    // may send, e.g. 32 and 20
+    let mut limits = ConnectionLimits::default();
+    if let Ok(max) = std::env::var("AURELIUS_MAX_INBOUND") {
+        limits.max_inbound = max
+            .parse::<usize>()
+            .map_err(|e| anyhow!("Invalid number of inbound connections: {e}"))?;
+    }
+    if let Ok(rate) = std::env::var("AURELIUS_MAX_MESSAGES_PER_SEC") {
+        limits.messages_per_sec = rate
+            .parse::<u32>()
+            .map_err(|e| anyhow!("Invalid message rate: {e}"))?;
+    }
+    // Peers only speaking older protocol versions are told to upgrade and
+    // disconnected, e.g. 2
+    let min_peer_version = std::env::var("AURELIUS_MIN_PEER_VERSION")
+        .ok()
+        .map(|version| version.parse::<u16>())
+        .transpose()
+        .map_err(|e| anyhow!("Invalid minimum peer version: {e}"))?;
+    if let Some(version) = min_peer_version {
+        if !SupportedVersions::all().contains(&version) {
+            return Err(anyhow!(
+                "Minimum peer version {version} isn't one of {:?}",
+                SupportedVersions::all()
+            ));
+        }
+    }
+
+    // Memory budgets in MiB, e.g. `mempool=100,orphans=8`
+    let memory_budget = std::env::var("AURELIUS_MEMORY_BUDGET")
+        .ok()
+        .map(|budget| budget.parse::<MemoryBudget>())
+        .transpose()?
+        .unwrap_or_default();
+
+    // Blocks are mined and paid to the address when it's set, on as many
+    // threads as the machine has by default
+    let mining_address = std::env::var("AURELIUS_MINING_ADDRESS")
+        .ok()
+        .map(|address| <[u8; 32]>::from_hex(address.trim()))
+        .transpose()
+        .map_err(|e| anyhow!("Invalid mining address: {e}"))?;
+    // Or they're paid to the key of a keystore, which `wallet import-keystore`
+    // turns into a wallet spending the rewards
+    let mining_address = match (
+        mining_address,
+        std::env::var("AURELIUS_MINING_KEYSTORE").ok(),
+    ) {
+        (Some(_), Some(_)) => {
+            return Err(anyhow!(
+                "Set AURELIUS_MINING_ADDRESS or AURELIUS_MINING_KEYSTORE, not both"
+            ))
+        }
+        (None, Some(path)) => Some(mining_key(Path::new(&path))?),
+        (address, None) => address,
+    };
+    let mining_threads = std::env::var("AURELIUS_MINING_THREADS")
+        .ok()
+        .map(|n| n.parse::<usize>())
+        .transpose()
+        .map_err(|e| anyhow!("Invalid number of mining threads: {e}"))?
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, usize::from));
+    // Short tag put in the coinbase of mined blocks, e.g. a pool name
+    let coinbase_tag = std::env::var("AURELIUS_COINBASE_TAG")
+        .unwrap_or_default()
+        .into_bytes();
+    if coinbase_tag.len() > MAX_COINBASE_TAG {
+        return Err(anyhow!(
+            "Coinbase tag is {} bytes, at most {MAX_COINBASE_TAG} fit",
+            coinbase_tag.len()
+        ));
+    }
+    let mining = mining_address.map(|address| MiningConfig {
+        address,
+        workers: mining_threads,
+        coinbase_tag,
+    });
+
+    let mut configs = Vec::new();
+    for network in networks {
+        let mut config = ChainConfig::new(network, &data_dir);
+        config.bind = bind.unwrap_or(config.bind);
+        config.port = port.unwrap_or(config.port);
+        config.rpc_port = rpc_port.unwrap_or(config.rpc_port);
+        config.explorer_port = explorer_port;
+        config.rng_seed = rng_seed;
+        config.seeds = seeds.clone();
+        config.webhooks = webhooks.clone();
+        config.reindex = reindex;
+        config.encrypted_transport = encrypt;
+        config.min_peer_version = min_peer_version.unwrap_or(config.min_peer_version);
+        config.local_relay = local_relay;
+        config.seen_cache = seen_cache;
+        config.bans = bans;
+        config.dial = dial;
+        config.params = chain_params;
+        config.limits = limits;
+        config.memory_budget = memory_budget;
+        config.scrub_interval = scrub_interval.unwrap_or(config.scrub_interval);
+        config.mining = mining.clone();
+        for (rules, height) in activation_heights.iter() {
+            config.deployments.activate_at(*rules, *height);
+        }
+        configs.push(config);
+    }
+
+    if doctor {
+        let ntp_server = std::env::var("AURELIUS_NTP_SERVER")
+            .unwrap_or_else(|_| doctor::DEFAULT_NTP_SERVER.to_string());
+        return run_doctor(&configs, &ntp_server).await;
+    }
+
+    let mut supervisor = Supervisor::new();
+    for config in configs {
+        supervisor = supervisor.chain(config)?;
+    }
+
+    tokio::spawn(report_metrics(METRICS_INTERVAL));
+
+    supervisor.run().await
+}
+
+// Public key of the mining keystore. A missing keystore is created with a new
+// key encrypted with AURELIUS_KEYSTORE_PASSPHRASE, an existing one is only
+// unlocked when the passphrase is set, to notice a forgotten passphrase
+// before anything is mined
+fn mining_key(path: &Path) -> anyhow::Result<[u8; 32]> {
+    let passphrase = std::env::var("AURELIUS_KEYSTORE_PASSPHRASE").ok();
+
+    if path.exists() {
+        let keystore = Keystore::load(path)
+            .map_err(|e| anyhow!("Failed to read keystore {}: {e}", path.display()))?;
+        if let Some(passphrase) = passphrase {
+            keystore.unlock(&passphrase)?;
+        }
+        return Ok(keystore.public_key());
+    }
+
+    let passphrase = passphrase
+        .filter(|passphrase| !passphrase.is_empty())
+        .ok_or_else(|| {
+            anyhow!(
+                "Keystore {} doesn't exist, set AURELIUS_KEYSTORE_PASSPHRASE to create it",
+                path.display()
+            )
+        })?;
+    let keystore = Keystore::create(&Entropy::os().signing_key(), &passphrase)?;
+    keystore.save(path)?;
+    info!(
+        "Created mining keystore {} for key {}",
+        path.display(),
+        hex::encode(keystore.public_key())
+    );
+
+    Ok(keystore.public_key())
+}
+
+// Prints a line per check, fails if any check failed
+async fn run_doctor(configs: &[ChainConfig], ntp_server: &str) -> anyhow::Result<()> {
+    let diagnostics = doctor::examine(configs, ntp_server).await;
+    for diagnostic in diagnostics.iter() {
+        println!("{diagnostic}");
+    }
+
+    let failed = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.status == doctor::Status::Failed)
+        .count();
+    if failed > 0 {
+        return Err(anyhow!("{failed} checks failed"));
+    }
+    Ok(())
+}
+
+// Prints every entry of the audit log, fails if it was tampered with
+async fn print_audit_log(network: Network, data_dir: &Path) -> anyhow::Result<()> {
+    let audit = AuditLog::open(data_dir).await?;
+    let entries = audit.entries().await?;
+
+    println!("{network} audit log, {} entries", entries.len());
+    for entry in entries.iter() {
+        println!(
+            "{} {} {} {}",
+            entry.sequence,
+            entry.timestamp,
+            entry.action,
+            hex::encode(entry.hash)
+        );
+    }
+
+    audit.verify(&entries)
+}
+
+async fn export(
+    network: Network,
+    params: ChainParams,
+    data_dir: &Path,
+    dir: &Path,
+    from: u64,
+) -> anyhow::Result<()> {
+    let chain = Storage::open(data_dir)
+        .await?
+        .load_chain(network, params)
+        .await?
+        .ok_or_else(|| anyhow!("No {network} chain is stored in {}", data_dir.display()))?;
+
+    let summary = export::export_chain(&chain, dir, from).await?;
+    println!(
+        "Exported {} blocks, {} transactions, {} inputs and {} outputs of the {network} chain to {}",
+        summary.blocks,
+        summary.transactions,
+        summary.inputs,
+        summary.outputs,
+        dir.display()
+    );
+    Ok(())
+}
+
+// Comma separated `<rule>=<height>` pairs
+fn parse_activation_heights(heights: &str) -> anyhow::Result<Vec<(Rules, u64)>> {
+    heights
+        .split(',')
+        .map(|pair| {
+            let (rule, height) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected <rule>=<height>, got {pair}"))?;
+            let rules = rule.trim().parse::<Rules>().map_err(|e| anyhow!(e))?;
+            let height = height
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| anyhow!("Invalid activation height: {e}"))?;
+            Ok((rules, height))
+        })
+        .collect()
+}
+
+// Logs the hashing throughput, slow merkle builds point at pathological blocks
+async fn report_metrics(interval: Duration) {
+    let mut previous = METRICS.snapshot();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        let current = METRICS.snapshot();
+
+        info!(
+            "Merkle builds: {:.2}/s, hashed: {:.0} B/s",
+            current.merkle_builds_per_sec(&previous, interval),
+            current.bytes_hashed_per_sec(&previous, interval)
+        );
+        for bucket in current.build_times.iter() {
+            if let Some(average) = bucket.average() {
+                match bucket.max_leaves {
+                    Some(max) => info!("Merkle build up to {max} leaves: {average:?} on average"),
+                    None => info!("Merkle build of larger trees: {average:?} on average"),
+                }
+            }
+        }
+
+        previous = current;
+    }
 }
@@ -0,0 +1,89 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+
+const MIB: usize = 1024 * 1024;
+
+// Memory the node's in-memory state may take, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryBudget {
+    // Over budget the lowest fee rate transactions are evicted
+    pub mempool: usize,
+    // The chain needs every unspent output, going over budget only warns so
+    // the budget can be raised before the machine runs out of memory
+    pub utxos: usize,
+    // Over budget the oldest orphan blocks are dropped
+    pub orphans: usize,
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        Self {
+            mempool: 300 * MIB,
+            utxos: 450 * MIB,
+            orphans: 16 * MIB,
+        }
+    }
+}
+
+// Comma separated `<pool>=<MiB>` pairs, pools left out keep their default
+// budget, e.g. `mempool=100,orphans=8`
+impl FromStr for MemoryBudget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut budget = Self::default();
+
+        for pair in s.split(',') {
+            let (pool, mib) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected <pool>=<MiB>, got {pair}"))?;
+            let bytes = mib
+                .trim()
+                .parse::<usize>()
+                .map_err(|e| anyhow!("Invalid memory budget: {e}"))?
+                .saturating_mul(MIB);
+
+            match pool.trim() {
+                "mempool" => budget.mempool = bytes,
+                "utxos" => budget.utxos = bytes,
+                "orphans" => budget.orphans = bytes,
+                pool => return Err(anyhow!("Unknown memory pool {pool}")),
+            }
+        }
+
+        Ok(budget)
+    }
+}
+
+// Approximate memory taken by the node's state next to its budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryInfo {
+    pub mempool: usize,
+    pub utxos: usize,
+    pub orphans: usize,
+    pub budget: MemoryBudget,
+}
+
+impl MemoryInfo {
+    pub fn total(&self) -> usize {
+        self.mempool + self.utxos + self.orphans
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_budgets_in_mib() {
+        let budget = "mempool=100, orphans=8".parse::<MemoryBudget>().unwrap();
+        assert_eq!(budget.mempool, 100 * MIB);
+        assert_eq!(budget.orphans, 8 * MIB);
+        assert_eq!(budget.utxos, MemoryBudget::default().utxos);
+
+        assert!("mempool".parse::<MemoryBudget>().is_err());
+        assert!("blocks=1".parse::<MemoryBudget>().is_err());
+        assert!("utxos=lots".parse::<MemoryBudget>().is_err());
+    }
+}
@@ -0,0 +1,431 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use corelib::{
+    block::Block,
+    blockchain::BlockChain,
+    errors::Result,
+    fee::FeeRate,
+    mempool::{MemPool, MemPoolEntry, Removal, RemovalReason, SelectionStrategy},
+    transaction::Transaction,
+    utxo::{OutPoint, UTXO},
+};
+use imbl::HashMap as SharedMap;
+use tokio::sync::{broadcast, RwLock};
+
+// Capacity of the change notification channel, slow subscribers lag behind
+// instead of blocking the pool
+const EVENT_CAPACITY: usize = 1024;
+// Number of removals remembered for `MemPoolHandle::removals`
+const MAX_REMOVALS: usize = 1000;
+
+// Size and limits of the pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemPoolInfo {
+    pub size: usize,
+    pub bytes: usize,
+    pub max_size: usize,
+    pub max_bytes: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemPoolEvent {
+    Added([u8; 32]),
+    Removed(Removal),
+}
+
+// Shared handle to the node's mempool.
+//
+// Cloning the handle is cheap, every clone points to the same pool so the
+// networking tasks, RPC and miner can all use it concurrently.
+#[derive(Debug, Clone)]
+pub struct MemPoolHandle {
+    pool: Arc<RwLock<MemPool>>,
+    events: broadcast::Sender<MemPoolEvent>,
+    // Most recent removals, oldest first
+    removals: Arc<RwLock<VecDeque<Removal>>>,
+}
+
+impl MemPoolHandle {
+    pub fn new(max_size: usize) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+
+        Self {
+            pool: Arc::new(RwLock::new(MemPool::new(max_size))),
+            events,
+            removals: Arc::new(RwLock::new(VecDeque::new())),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<MemPoolEvent> {
+        self.events.subscribe()
+    }
+
+    // Admits a transaction spending unspent outputs of `utxos` or of pool
+    // transactions, see `MemPool::add_transaction`
+    pub async fn add(
+        &self,
+        txn: Transaction,
+        utxos: &SharedMap<OutPoint, UTXO>,
+        height: u64,
+    ) -> Result<()> {
+        let txn_hash = txn.hash_id;
+        let removed = self
+            .pool
+            .write()
+            .await
+            .add_transaction(txn, utxos, height)?;
+
+        for (txn_hash, reason) in removed {
+            self.record(txn_hash, reason).await;
+        }
+        self.notify(MemPoolEvent::Added(txn_hash));
+        Ok(())
+    }
+
+    pub async fn remove(&self, txn_hash: &[u8; 32], reason: RemovalReason) -> Option<Transaction> {
+        let removed = self.pool.write().await.remove_transaction(txn_hash);
+
+        if removed.is_some() {
+            self.record(*txn_hash, reason).await;
+        }
+        removed
+    }
+
+    // Drops the transactions included in a block along with the ones
+    // conflicting with it, returns the fee rates of the included ones that
+    // were in the pool
+    pub async fn remove_for_block(&self, block_txns: &[Transaction]) -> Vec<FeeRate> {
+        let (rates, mined, conflicts) = {
+            let mut pool = self.pool.write().await;
+
+            let included: HashSet<[u8; 32]> = block_txns.iter().map(|txn| txn.hash_id).collect();
+            let rates = pool
+                .entries()
+                .filter(|entry| included.contains(&entry.txn_hash))
+                .map(|entry| entry.fee_rate)
+                .collect();
+
+            let mined: Vec<[u8; 32]> = block_txns
+                .iter()
+                .filter_map(|txn| pool.remove_transaction(&txn.hash_id))
+                .map(|txn| txn.hash_id)
+                .collect();
+
+            (rates, mined, pool.remove_conflicts(block_txns))
+        };
+
+        for txn_hash in mined {
+            self.record(txn_hash, RemovalReason::Mined).await;
+        }
+        for txn_hash in conflicts {
+            self.record(txn_hash, RemovalReason::ConflictsWithBlock)
+                .await;
+        }
+        rates
+    }
+
+    // Fee rates and sizes of the waiting transactions
+    pub async fn fee_rates(&self) -> Vec<(FeeRate, u64)> {
+        self.pool
+            .read()
+            .await
+            .entries()
+            .map(|entry| (entry.fee_rate, entry.size))
+            .collect()
+    }
+
+    // Drops the transactions that waited in the pool for longer than its TTL
+    pub async fn evict_expired(&self) -> Vec<[u8; 32]> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        let expired = self.pool.write().await.evict_expired(now);
+
+        for txn_hash in expired.iter() {
+            self.record(*txn_hash, RemovalReason::Expired).await;
+        }
+        expired
+    }
+
+    // Takes the highest priority transactions fitting in a block out of the pool
+    pub async fn select(
+        &self,
+        max_block_size: usize,
+        strategy: SelectionStrategy,
+    ) -> Vec<Transaction> {
+        let selected = self
+            .pool
+            .write()
+            .await
+            .get_transactions_for_block(max_block_size, strategy);
+
+        for txn in selected.iter() {
+            self.record(txn.hash_id, RemovalReason::Mined).await;
+        }
+        selected
+    }
+
+    pub async fn entry(&self, txn_hash: &[u8; 32]) -> Option<MemPoolEntry> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        self.pool.read().await.entry(txn_hash, now)
+    }
+
+    // Hashes of the pool's transactions in the order a block would take
+    // them, parents ahead of the transactions spending them
+    pub async fn inventory(&self, limit: usize) -> Vec<[u8; 32]> {
+        self.pool
+            .read()
+            .await
+            .select_for_block(usize::MAX, SelectionStrategy::AncestorPackage)
+            .iter()
+            .take(limit)
+            .map(|txn| txn.hash_id)
+            .collect()
+    }
+
+    pub async fn get(&self, txn_hash: &[u8; 32]) -> Option<Transaction> {
+        self.pool.read().await.transactions.get(txn_hash).cloned()
+    }
+
+    // Outputs the transaction spends, in `utxos` or of pool transactions,
+    // see `MemPool::spent_outputs`
+    pub async fn spent_outputs(
+        &self,
+        txn: &Transaction,
+        utxos: &SharedMap<OutPoint, UTXO>,
+        height: u64,
+    ) -> Result<Vec<UTXO>> {
+        self.pool.read().await.spent_outputs(txn, utxos, height)
+    }
+
+    pub async fn contains(&self, txn_hash: &[u8; 32]) -> bool {
+        self.pool.read().await.transactions.contains_key(txn_hash)
+    }
+
+    // The outputs no pool transaction spends yet
+    pub async fn unspent(&self, utxos: impl IntoIterator<Item = UTXO>) -> Vec<UTXO> {
+        let pool = self.pool.read().await;
+        utxos
+            .into_iter()
+            .filter(|utxo| {
+                utxo.outpoint()
+                    .is_none_or(|outpoint| pool.spender(&outpoint).is_none())
+            })
+            .collect()
+    }
+
+    pub async fn len(&self) -> usize {
+        self.pool.read().await.transactions.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    pub async fn info(&self) -> MemPoolInfo {
+        let pool = self.pool.read().await;
+
+        MemPoolInfo {
+            size: pool.transactions.len(),
+            bytes: pool.bytes(),
+            max_size: pool.max_size,
+            max_bytes: pool.max_bytes,
+        }
+    }
+
+    // Unmined block on top of the chain's tip with the pool's best
+    // transactions, which stay in the pool
+    pub async fn block_template(
+        &self,
+        chain: &BlockChain,
+        miner_pubkey: [u8; 32],
+        version: u32,
+        coinbase_tag: &[u8],
+    ) -> Result<Block> {
+        chain.create_block_template(
+            &*self.pool.read().await,
+            miner_pubkey,
+            version,
+            coinbase_tag,
+        )
+    }
+
+    // Approximate memory the pool takes
+    pub async fn memory_usage(&self) -> usize {
+        self.pool.read().await.memory_usage()
+    }
+
+    // Evicts the lowest fee rate transactions until the pool takes at most
+    // `max_memory` bytes
+    pub async fn trim(&self, max_memory: usize) -> Vec<[u8; 32]> {
+        let evicted = self.pool.write().await.trim_to_memory(max_memory);
+
+        for txn_hash in evicted.iter() {
+            self.record(*txn_hash, RemovalReason::LowFee).await;
+        }
+        evicted
+    }
+
+    // Recent removals with the reason each transaction left the pool
+    pub async fn removals(&self) -> Vec<Removal> {
+        self.removals.read().await.iter().cloned().collect()
+    }
+
+    async fn record(&self, txn_hash: [u8; 32], reason: RemovalReason) {
+        let Ok(removal) = Removal::new(txn_hash, reason) else {
+            return;
+        };
+
+        let mut removals = self.removals.write().await;
+        if removals.len() >= MAX_REMOVALS {
+            removals.pop_front();
+        }
+        removals.push_back(removal.clone());
+        drop(removals);
+
+        self.notify(MemPoolEvent::Removed(removal));
+    }
+
+    fn notify(&self, event: MemPoolEvent) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use corelib::{errors::Error, fee::FeeRate};
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+
+    // Transaction spending the output `outpoint` names, paying `receiver`
+    // all of it but the fee
+    fn spending(outpoint: u8, receiver: u8, fee: u64) -> Transaction {
+        let mut signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let mut txn = Transaction::new(&mut signing_key, [receiver; 32]).unwrap();
+        let input = UTXO::new(5_000, 0, [7u8; 32])
+            .unwrap()
+            .confirm_utxo([outpoint; 32], 1, false)
+            .unwrap();
+        txn.add_inputs(vec![input], &mut signing_key).unwrap();
+        let output = UTXO::new(5_000 - fee, 0, [receiver; 32]).unwrap();
+        txn.add_outputs(vec![output], &mut signing_key).unwrap();
+        txn
+    }
+
+    // UTXO set holding the outputs the transactions' inputs declare
+    fn utxo_set(txns: &[&Transaction]) -> SharedMap<OutPoint, UTXO> {
+        txns.iter()
+            .flat_map(|txn| txn.inputs.iter())
+            .filter_map(|input| Some((input.outpoint()?, input.clone())))
+            .collect()
+    }
+
+    fn reasons(removals: &[Removal]) -> Vec<([u8; 32], RemovalReason)> {
+        removals
+            .iter()
+            .map(|removal| (removal.txn_hash, removal.reason))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn evicts_the_lowest_fee_rate_first() {
+        let mem_pool = MemPoolHandle::new(2);
+        let (cheap, dear, middle, cheapest) = (
+            spending(1, 1, 1_000),
+            spending(2, 1, 3_000),
+            spending(3, 1, 2_000),
+            spending(4, 1, 500),
+        );
+        let utxos = utxo_set(&[&cheap, &dear, &middle, &cheapest]);
+        mem_pool.add(cheap.clone(), &utxos, 1).await.unwrap();
+        mem_pool.add(dear.clone(), &utxos, 1).await.unwrap();
+
+        mem_pool.add(middle.clone(), &utxos, 1).await.unwrap();
+        assert!(!mem_pool.contains(&cheap.hash_id).await);
+        assert_eq!(
+            reasons(&mem_pool.removals().await),
+            vec![(cheap.hash_id, RemovalReason::LowFee)]
+        );
+
+        // Paying less than everything in a full pool isn't enough
+        assert!(matches!(
+            mem_pool.add(cheapest, &utxos, 1).await,
+            Err(Error::TxnLowFee)
+        ));
+        let mut rates = mem_pool.fee_rates().await;
+        rates.sort();
+        let size = dear.serialized_size() as u64;
+        assert_eq!(
+            rates,
+            vec![
+                (FeeRate::new(2_000, size).unwrap(), size),
+                (FeeRate::new(3_000, size).unwrap(), size)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_what_a_block_includes_or_conflicts_with() {
+        let mem_pool = MemPoolHandle::new(10);
+        let (mined, conflicting, waiting) = (
+            spending(1, 1, 2_000),
+            spending(2, 1, 2_000),
+            spending(3, 1, 2_000),
+        );
+        let utxos = utxo_set(&[&mined, &conflicting, &waiting]);
+        for txn in [&mined, &conflicting, &waiting] {
+            mem_pool.add(txn.clone(), &utxos, 1).await.unwrap();
+        }
+
+        // The block spends the conflicting transaction's input elsewhere and
+        // includes one the pool never saw
+        let block = [mined.clone(), spending(2, 2, 2_000), spending(4, 1, 2_000)];
+        let rates = mem_pool.remove_for_block(&block).await;
+        assert_eq!(
+            rates,
+            vec![FeeRate::new(2_000, mined.serialized_size() as u64).unwrap()]
+        );
+
+        assert_eq!(mem_pool.len().await, 1);
+        assert!(mem_pool.contains(&waiting.hash_id).await);
+        assert_eq!(
+            reasons(&mem_pool.removals().await),
+            vec![
+                (mined.hash_id, RemovalReason::Mined),
+                (conflicting.hash_id, RemovalReason::ConflictsWithBlock)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_out_outputs_pool_transactions_spend() {
+        let mem_pool = MemPoolHandle::new(10);
+        let txn = spending(1, 1, 2_000);
+        mem_pool
+            .add(txn.clone(), &utxo_set(&[&txn]), 1)
+            .await
+            .unwrap();
+
+        let unspent = UTXO::new(5_000, 0, [7u8; 32])
+            .unwrap()
+            .confirm_utxo([2u8; 32], 1, false)
+            .unwrap();
+        let utxos = vec![txn.inputs[0].clone(), unspent.clone()];
+        assert_eq!(mem_pool.unspent(utxos).await, vec![unspent]);
+
+        // Spendable again once the spending transaction leaves the pool
+        mem_pool.remove(&txn.hash_id, RemovalReason::Expired).await;
+        assert_eq!(
+            mem_pool.unspent(vec![txn.inputs[0].clone()]).await,
+            vec![txn.inputs[0].clone()]
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use corelib::{
+    block::Block,
+    config::{MINING_ROUND, TIMESTAMP_REFRESH_INTERVAL},
+};
+use tokio::sync::mpsc;
+
+// Who mined blocks are paid to and how many threads mine them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MiningConfig {
+    pub address: [u8; 32],
+    pub workers: usize,
+    // Put in the coinbase of every mined block, e.g. the pool's name
+    pub coinbase_tag: Vec<u8>,
+}
+
+// Mines blocks off the async runtime.
+//
+// Every worker runs on a blocking thread and searches its own range of
+// nonces of the same block, so they never hash the same header twice
+#[derive(Debug, Clone, Copy)]
+pub struct Miner {
+    workers: usize,
+}
+
+impl Miner {
+    pub fn new(workers: usize) -> Self {
+        Self {
+            workers: workers.max(1),
+        }
+    }
+
+    // Starts the workers on the block, the job reports the first solution
+    pub fn start(&self, template: Block) -> MiningJob {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (solutions, solved) = mpsc::channel(1);
+
+        for nonces in nonce_ranges(self.workers) {
+            let block = template.clone();
+            let cancelled = cancelled.clone();
+            let solutions = solutions.clone();
+
+            tokio::task::spawn_blocking(move || {
+                if let Some(block) = search(block, nonces, &cancelled) {
+                    // The other workers can stop, their block is solved
+                    cancelled.store(true, Ordering::Relaxed);
+                    let _ = solutions.try_send(block);
+                }
+            });
+        }
+
+        MiningJob { cancelled, solved }
+    }
+}
+
+// Work of the miner on one block template. Dropping the job cancels it
+#[derive(Debug)]
+pub struct MiningJob {
+    cancelled: Arc<AtomicBool>,
+    solved: mpsc::Receiver<Block>,
+}
+
+impl MiningJob {
+    // Waits for a worker to solve the block, `None` once the job was
+    // cancelled or every worker searched its range in vain
+    pub async fn solved(&mut self) -> Option<Block> {
+        self.solved.recv().await
+    }
+
+    // Stops the workers after their current round, e.g. when a competing
+    // block arrived and the template went stale
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for MiningJob {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+// Splits the nonces evenly between the workers, the last one also takes the
+// remainder
+fn nonce_ranges(workers: usize) -> Vec<Range<u64>> {
+    let workers = workers as u64;
+    let size = u64::MAX / workers;
+
+    (0..workers)
+        .map(|worker| {
+            let start = worker * size;
+            let end = if worker + 1 == workers {
+                u64::MAX
+            } else {
+                start + size
+            };
+            start..end
+        })
+        .collect()
+}
+
+// Tries the nonces of the range in rounds, checking in between whether the
+// job was cancelled. A block that took long to mine gets a fresh timestamp,
+// after which the range is searched again
+fn search(mut block: Block, nonces: Range<u64>, cancelled: &AtomicBool) -> Option<Block> {
+    block.set_nonce(nonces.start);
+
+    while !cancelled.load(Ordering::Relaxed) {
+        let remaining = nonces.end.saturating_sub(block.header().nonce);
+        if remaining == 0 {
+            return None;
+        }
+        if block.try_mine(remaining.min(MINING_ROUND)) {
+            return Some(block);
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        if now.saturating_sub(block.timestamp()) >= TIMESTAMP_REFRESH_INTERVAL {
+            block.set_timestamp(now);
+            block.set_nonce(nonces.start);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use corelib::block::BlockBuilder;
+
+    use super::*;
+
+    fn template(difficulty: u32) -> Block {
+        BlockBuilder::new(0, hex::encode([0u8; 32]), difficulty, [1u8; 32])
+            .template()
+            .unwrap()
+    }
+
+    #[test]
+    fn splits_nonces_between_workers() {
+        let ranges = nonce_ranges(3);
+        assert_eq!(ranges[0].start, 0);
+        assert_eq!(ranges[0].end, ranges[1].start);
+        assert_eq!(ranges[1].end, ranges[2].start);
+        assert_eq!(ranges[2].end, u64::MAX);
+
+        assert_eq!(nonce_ranges(1), vec![0..u64::MAX]);
+    }
+
+    #[tokio::test]
+    async fn reports_solved_blocks_and_stops_when_cancelled() {
+        let miner = Miner::new(2);
+
+        let mut job = miner.start(template(8));
+        let block = job.solved().await.unwrap();
+        assert!(block.header().is_valid());
+
+        // No hash meets the highest difficulty, the workers only stop once
+        // cancelled
+        let mut job = miner.start(template(127));
+        job.cancel();
+        assert_eq!(job.solved().await, None);
+    }
+}
@@ -0,0 +1,169 @@
+use std::{collections::HashMap, fmt, net::IpAddr, time::Duration};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+// Ways a peer can break the protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    // Block or header failing validation
+    InvalidBlock,
+    // Frame that couldn't be decoded
+    MalformedMessage,
+    // Message that isn't allowed, e.g. one of another network
+    ProtocolViolation,
+    // Message sent past the rate limit
+    Flooding,
+}
+
+impl Misbehavior {
+    // Added to the peer's score, a peer reaching the threshold is banned
+    pub fn score(&self) -> u32 {
+        match self {
+            Misbehavior::InvalidBlock => 100,
+            Misbehavior::MalformedMessage => 10,
+            Misbehavior::ProtocolViolation => 20,
+            Misbehavior::Flooding => 1,
+        }
+    }
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Misbehavior::InvalidBlock => "invalid block",
+            Misbehavior::MalformedMessage => "malformed message",
+            Misbehavior::ProtocolViolation => "protocol violation",
+            Misbehavior::Flooding => "flooding",
+        })
+    }
+}
+
+// Score at which peers are banned and for how long
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BanConfig {
+    pub threshold: u32,
+    pub duration: Duration,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 100,
+            duration: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+// Address banned until the time, in milliseconds since the unix epoch
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Ban {
+    pub address: String,
+    pub until: u128,
+}
+
+// Misbehavior scores of the peers and the addresses banned for reaching the
+// threshold.
+//
+// Peers are tracked by IP so reconnecting from another port doesn't start
+// over. Scores only last as long as the process, bans are persisted so a
+// restart doesn't lift them
+#[derive(Debug)]
+pub struct BanList {
+    config: BanConfig,
+    scores: HashMap<IpAddr, u32>,
+    banned: HashMap<IpAddr, u128>,
+}
+
+impl BanList {
+    pub fn new(config: BanConfig) -> Self {
+        Self {
+            config,
+            scores: HashMap::new(),
+            banned: HashMap::new(),
+        }
+    }
+
+    // Brings back the bans saved by an earlier run, expired ones are dropped
+    pub fn restore(&mut self, bans: Vec<Ban>, now: u128) {
+        for ban in bans.into_iter().filter(|ban| ban.until > now) {
+            if let Ok(address) = ban.address.parse() {
+                self.banned.insert(address, ban.until);
+            }
+        }
+    }
+
+    // Adds the misbehavior to the address's score, returns true if that got
+    // it banned
+    pub fn record(&mut self, address: IpAddr, misbehavior: Misbehavior, now: u128) -> bool {
+        let score = self.scores.entry(address).or_default();
+        *score = score.saturating_add(misbehavior.score());
+        if *score < self.config.threshold {
+            return false;
+        }
+
+        self.scores.remove(&address);
+        self.banned
+            .insert(address, now + self.config.duration.as_millis());
+        true
+    }
+
+    pub fn is_banned(&self, address: &IpAddr, now: u128) -> bool {
+        self.banned.get(address).is_some_and(|until| *until > now)
+    }
+
+    pub fn score(&self, address: &IpAddr) -> u32 {
+        self.scores.get(address).copied().unwrap_or_default()
+    }
+
+    // Bans still in force, in the order they end
+    pub fn bans(&self, now: u128) -> Vec<Ban> {
+        let mut bans: Vec<Ban> = self
+            .banned
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(address, until)| Ban {
+                address: address.to_string(),
+                until: *until,
+            })
+            .collect();
+        bans.sort_by_key(|ban| ban.until);
+        bans
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bans_addresses_reaching_the_threshold() {
+        let mut list = BanList::new(BanConfig {
+            threshold: 30,
+            duration: Duration::from_secs(60),
+        });
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+
+        assert!(!list.record(peer, Misbehavior::MalformedMessage, 0));
+        assert_eq!(list.score(&peer), 10);
+        assert!(list.record(peer, Misbehavior::ProtocolViolation, 1_000));
+        assert!(list.is_banned(&peer, 60_999));
+        assert!(!list.is_banned(&peer, 61_000));
+        assert_eq!(list.score(&peer), 0);
+
+        // Bans survive a restart, expired ones don't
+        let bans = list.bans(1_000);
+        assert_eq!(
+            bans,
+            vec![Ban {
+                address: "10.0.0.1".into(),
+                until: 61_000,
+            }]
+        );
+        let mut restarted = BanList::new(BanConfig::default());
+        restarted.restore(bans.clone(), 2_000);
+        assert!(restarted.is_banned(&peer, 2_000));
+        let mut later = BanList::new(BanConfig::default());
+        later.restore(bans, 61_000);
+        assert!(later.bans(61_000).is_empty());
+    }
+}
@@ -1,42 +1,4769 @@
 use corelib::{
-    block::Block, blockchain::BlockChain, mempool::MemPool, transaction::Transaction, utxo::UTXO,
+    block::{Block, BlockBuilder, BlockHeader},
+    blockchain::{apply_block, check_headers, BlockChain, ChainUpdate, TxStatus},
+    config::{ChainParams, Network, MIN_DIFFICULTY},
+    deployment::{Activation, DeploymentState, Deployments, Rules, StateCache},
+    fee::{FeeEstimator, FeeRate},
+    memory::MemoryUsage,
+    mempool::{MemPool, MemPoolEntry, Removal},
+    net::{
+        bloom::BloomFilter,
+        codec::MAX_PAYLOAD_SIZE,
+        features::Features,
+        message::{BlockChunk, ChunkedBlock, FilteredBlock, Inventory, Message, MAX_INVENTORY},
+        protocol::{
+            node_id, Command, Handshake, Request, Response, StatusCode, SupportedVersions,
+            DEFAULT_IDLE_TIMEOUT, MAX_IDLE_TIMEOUT,
+        },
+        start_listening,
+        transport::{EphemeralKey, Transport},
+    },
+    rng::Entropy,
+    transaction::Transaction,
+    utxo::UTXO,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Read,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use std::{collections::HashSet, io::Read, time::Duration};
 
-use anyhow::{anyhow, bail};
+use anyhow::{anyhow, bail, Context};
+use ed25519_dalek::SigningKey;
+use hex::FromHex;
 use tokio::{
     io::{AsyncReadExt as _, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    sync::{mpsc, RwLock, Semaphore},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    audit::{AuditAction, AuditLog},
+    clock::{NetworkTime, SKEW_WARNING},
+    dial::DialConfig,
+    limits::{ConnectionLimits, MessageRate},
+    memory::{MemoryBudget, MemoryInfo},
+    mempool::{MemPoolHandle, MemPoolInfo},
+    miner::Miner,
+    misbehavior::{Ban, BanConfig, BanList, Misbehavior},
+    peer::{PeerInfo, PeerManager, PeerResponse},
+    pipeline::{BlockCheck, BlockContext, CheckFailure, CheckedBlock, SyncPipeline},
+    relay::{LocalRelay, LocalRelayConfig, RelayState, SeenCache, SeenCacheConfig},
+    stats::{NodeStats, StatCounters},
+    storage::Storage,
+    sync::{check_proof_of_work, SyncCheckpoint, SyncState},
+    template::{TemplateNotifier, TemplateWatch, DEFAULT_MIN_FEE_INCREASE},
+    webhooks::WebhookDispatcher,
 };
-use tracing::{error, info};
+
+// Upper bound of outbound peer connections
+const MAX_PEERS: usize = 8;
+// Upper bound of blocks buffered while waiting for their parent
+const MAX_ORPHAN_BLOCKS: usize = 100;
+// Headers sent in answer to a single `GetHeaders`
+const MAX_HEADERS: usize = 256;
+// Blocks requested from a peer at once during the block download, with the
+// in-flight limit this stays below the orphan buffer size
+const BLOCK_WINDOW: u64 = 8;
+const MAX_WINDOWS_IN_FLIGHT: usize = 8;
+// Chunked downloads without a new chunk for this long are given up
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockOutcome {
+    // The block and this many buffered descendants were added to the chain,
+    // either to the best chain or to a competing branch
+    Connected(usize),
+    // The parent is unknown, the block waits in the orphan buffer
+    Orphaned,
+    // The block is already part of the chain
+    Known,
+}
+
+// Why a block was refused. Only invalid blocks break the consensus rules and
+// count against the peer that sent them, the other failures say nothing about
+// the block, e.g. safe mode, a block ahead of this node's clock or
+// transactions swapped on the way
+#[derive(Debug, thiserror::Error)]
+pub enum BlockError {
+    #[error("{0}")]
+    Invalid(anyhow::Error),
+    #[error(transparent)]
+    Local(#[from] anyhow::Error),
+}
+
+// Block downloaded in chunks from a peer
+#[derive(Debug)]
+struct Download {
+    peer: SocketAddr,
+    // When the download started or the last chunk arrived
+    progressed: Instant,
+    chunks: ChunkedBlock,
+}
+
+// State of a deployment for the next block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeploymentInfo {
+    pub rules: Rules,
+    pub activation: Activation,
+    pub state: DeploymentState,
+    // Blocks of the current signaling window that signaled so far
+    pub signaling: u64,
+}
 
 #[derive(Debug, Clone)]
 pub struct Node {
-    id: String,
-    mem_pool: MemPool,
-    utxo_set: HashSet<UTXO>,
-    peers: Vec<Node>,
-    blockchain: Option<BlockChain>,
-    current_block: Option<Block>,
-    pending_blocks: Vec<Block>,
+    // Signs the handshakes, peers know the node by its public key
+    identity: SigningKey,
+    // Address other nodes can reach this node on
+    listen_address: SocketAddr,
+    mem_pool: MemPoolHandle,
+    peers: PeerManager,
+    // Replaced rather than changed while a snapshot of it is read, see
+    // `chain_snapshot`
+    blockchain: Arc<RwLock<Option<Arc<BlockChain>>>>,
+    // Orphan blocks waiting for their parent to arrive
+    pending_blocks: Arc<RwLock<Vec<Block>>>,
+    // Blocks being downloaded in chunks, by hash
+    downloads: Arc<RwLock<HashMap<[u8; 32], Download>>>,
+    storage: Option<Storage>,
+    // Block download progress, the checkpoint is persisted along with the chain
+    sync: Arc<RwLock<SyncState>>,
+    // Queues between the stages of the block download
+    pipeline: SyncPipeline,
+    webhooks: Option<WebhookDispatcher>,
+    // Headers and blocks already forwarded to peers
+    relay: Arc<RwLock<RelayState>>,
+    // Transactions and blocks peers sent lately, repeats are dropped
+    seen: Arc<RwLock<SeenCache>>,
+    // Misbehavior of the peers and the addresses banned for it
+    bans: Arc<RwLock<BanList>>,
+    // Holds back transactions submitted to this node, if configured
+    local_relay: Option<Arc<RwLock<LocalRelay>>>,
+    stats: Arc<StatCounters>,
+    // Tells miners when their block template is stale
+    templates: TemplateNotifier,
+    // Learns from the connected blocks which fee rates get confirmed
+    fee_estimator: Arc<RwLock<FeeEstimator>>,
+    // Why the storage was found corrupt, the node then only answers queries.
+    // Entered at startup or when scrubbing finds the chain corrupt in memory
+    // too, see `scrub_storage`
+    safe_mode: Arc<std::sync::RwLock<Option<String>>>,
+    // Rules added after launch and when they activate
+    deployments: Deployments,
+    // States of the signaling deployments per window, shared by the clones
+    deployment_states: Arc<std::sync::Mutex<StateCache>>,
+    memory_budget: MemoryBudget,
+    // Administrative actions are recorded to it, if configured
+    audit: Option<AuditLog>,
+    // Peers of other networks are disconnected
+    network: Network,
+    // Consensus parameters of the network, defaults unless overridden
+    params: ChainParams,
+    // Time a connection may go without a message before it's closed, peers
+    // may negotiate a longer one in the handshake
+    idle_timeout: Duration,
+    // Whether connections are encrypted with peers offering it too
+    encrypted_transport: bool,
+    // Lowest protocol version peers may speak, older ones are told so and
+    // disconnected
+    min_peer_version: u16,
+    limits: ConnectionLimits,
+    // Free inbound connection slots
+    inbound: Arc<Semaphore>,
+    // Clocks the peers sent in their handshakes, blocks' timestamps are
+    // judged against the time adjusted by them
+    network_time: Arc<RwLock<NetworkTime>>,
+    // Randomness of the identity, handshakes and relaying, seeded only to
+    // replay a run
+    entropy: Entropy,
 }
 
 impl Node {
-    pub fn new() -> Self {
-        Self {
-            id: uuid::Uuid::new_v4().to_string(),
-            mem_pool: MemPool::new(50),
-            utxo_set: HashSet::new(),
-            peers: Vec::new(),
-            blockchain: None,
-            current_block: None,
-            pending_blocks: Vec::new(),
+    pub fn new(port: u16) -> (Self, mpsc::UnboundedReceiver<PeerResponse>) {
+        let (peers, responses) = PeerManager::new(MAX_PEERS);
+        let stats = Arc::new(StatCounters::default());
+
+        let node = Self {
+            identity: Entropy::os().signing_key(),
+            listen_address: SocketAddr::from(([127, 0, 0, 1], port)),
+            mem_pool: MemPoolHandle::new(50),
+            peers: peers.with_stats(stats.clone()),
+            blockchain: Arc::new(RwLock::new(None)),
+            pending_blocks: Arc::new(RwLock::new(Vec::new())),
+            downloads: Arc::new(RwLock::new(HashMap::new())),
+            storage: None,
+            sync: Arc::new(RwLock::new(SyncState::default())),
+            pipeline: SyncPipeline::default(),
+            webhooks: None,
+            relay: Arc::new(RwLock::new(RelayState::default())),
+            seen: Arc::new(RwLock::new(SeenCache::new(SeenCacheConfig::default()))),
+            bans: Arc::new(RwLock::new(BanList::new(BanConfig::default()))),
+            local_relay: None,
+            stats,
+            templates: TemplateNotifier::new(DEFAULT_MIN_FEE_INCREASE),
+            fee_estimator: Arc::new(RwLock::new(FeeEstimator::new(
+                ChainParams::default().max_block_size,
+            ))),
+            safe_mode: Arc::new(std::sync::RwLock::new(None)),
+            deployments: Deployments::default(),
+            deployment_states: Arc::new(std::sync::Mutex::new(StateCache::new())),
+            memory_budget: MemoryBudget::default(),
+            audit: None,
+            network: Network::Mainnet,
+            params: ChainParams::default(),
+            idle_timeout: Duration::from_millis(DEFAULT_IDLE_TIMEOUT as u64),
+            encrypted_transport: false,
+            min_peer_version: SupportedVersions::One.as_u16(),
+            limits: ConnectionLimits::default(),
+            inbound: Arc::new(Semaphore::new(ConnectionLimits::default().max_inbound)),
+            network_time: Arc::new(RwLock::new(NetworkTime::default())),
+            entropy: Entropy::os(),
+        };
+
+        (node, responses)
+    }
+
+    // Joins the network instead of mainnet, set before the chain is loaded
+    // so it follows the network's difficulty rules
+    pub fn with_network(mut self, network: Network) -> Self {
+        self.network = network;
+        self.peers = self.peers.with_network(network);
+        self
+    }
+
+    // Follows the consensus parameters instead of the defaults, set before
+    // the chain is loaded like the network
+    pub fn with_chain_params(mut self, params: ChainParams) -> Self {
+        self.params = params;
+        self.fee_estimator = Arc::new(RwLock::new(FeeEstimator::new(params.max_block_size)));
+        self
+    }
+
+    pub fn chain_params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    // Identity the handshakes are signed with instead of a random one, a
+    // node with storage keeps its identity there
+    pub fn with_identity(mut self, identity: SigningKey) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    // Closes connections quiet for longer than the timeout instead of the
+    // default one, unless the other side asks for longer
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self.peers = self.peers.with_idle_timeout(idle_timeout);
+        self
+    }
+
+    // Encrypts the connections to and from peers that support it, the
+    // session keys are agreed on in the signed handshake
+    pub fn with_encrypted_transport(mut self, encrypted_transport: bool) -> Self {
+        self.encrypted_transport = encrypted_transport;
+        self.peers = self.peers.with_encrypted_transport(encrypted_transport);
+        self
+    }
+
+    // Limits and caches of dialing peers instead of the defaults
+    pub fn with_dial_config(mut self, config: DialConfig) -> Self {
+        self.peers = self.peers.with_dial_config(config);
+        self
+    }
+
+    // Draws the node's randomness from the entropy instead of the OS, a new
+    // identity included. A seeded one makes simulations and benchmarks
+    // reproducible but lets anyone knowing the seed predict the keys
+    pub fn with_entropy(mut self, entropy: Entropy) -> Self {
+        self.identity = entropy.signing_key();
+        self.peers = self.peers.with_entropy(entropy.clone());
+        self.entropy = entropy;
+        self
+    }
+
+    // Restores the chain and block download progress saved by a previous
+    // run, connected blocks are persisted to the storage from now on.
+    //
+    // Corrupt state doesn't stop the node, it starts in safe mode instead:
+    // whatever could be loaded is served read-only and nothing is written
+    // back until the chain is reindexed
+    pub async fn with_storage(mut self, storage: Storage) -> anyhow::Result<Self> {
+        let mut corruption = Vec::new();
+
+        storage.recover_blocks().await?;
+        let chain = storage
+            .load_chain(self.network, self.params)
+            .await
+            .unwrap_or_else(|e| {
+                corruption.push(e.to_string());
+                None
+            });
+        if let Some(Err(e)) = chain.as_ref().map(BlockChain::check_integrity) {
+            corruption.push(e.to_string());
+        }
+        let mut checkpoint = storage.load_checkpoint().await.unwrap_or_else(|e| {
+            corruption.push(e.to_string());
+            SyncCheckpoint::default()
+        });
+        let stats = storage.load_stats().await.unwrap_or_else(|e| {
+            corruption.push(e.to_string());
+            NodeStats::default()
+        });
+        self.stats.resume(stats);
+        match storage.load_identity().await? {
+            Some(secret) => self.identity = SigningKey::from_bytes(&secret),
+            None => storage.save_identity(&self.identity.to_bytes()).await?,
+        }
+        // Losing the bans only gives banned peers another chance, it's no
+        // reason for safe mode
+        match storage.load_bans().await {
+            Ok(bans) => self.bans.write().await.restore(bans, now_millis()),
+            Err(e) => warn!("Failed to load the banned peers: {e}"),
+        }
+
+        if !corruption.is_empty() {
+            let reason = corruption.join(", ");
+            error!("Storage is corrupt: {reason}. Starting in safe mode, restart with --reindex to rebuild the chain");
+            self.enter_safe_mode(reason);
+        }
+
+        // The chain is written before the checkpoint, so it's the source of
+        // truth if the node stopped in between
+        let height = chain.as_ref().map_or(0, BlockChain::height);
+        checkpoint.set_validated(height);
+
+        info!(
+            "Resuming block download at height {height} with {} windows in flight",
+            checkpoint.in_flight().len()
+        );
+
+        self.blockchain = Arc::new(RwLock::new(chain.map(Arc::new)));
+        self.sync = Arc::new(RwLock::new(SyncState::new(checkpoint)));
+        self.storage = Some(storage);
+
+        Ok(self)
+    }
+
+    // Transactions submitted to this node are announced in delayed batches
+    // from now on, see `relay_local_transactions`
+    pub fn with_local_relay(mut self, config: LocalRelayConfig) -> Self {
+        self.local_relay = Some(Arc::new(RwLock::new(LocalRelay::new(config))));
+        self
+    }
+
+    // Serves as many inbound connections and lets them send as fast as
+    // configured instead of the default
+    pub fn with_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.limits = limits;
+        self.inbound = Arc::new(Semaphore::new(limits.max_inbound));
+        self
+    }
+
+    // Disconnects peers that only speak versions older than `version`, which
+    // retires them ahead of a network upgrade
+    pub fn with_min_peer_version(mut self, version: u16) -> Self {
+        self.min_peer_version = version;
+        self
+    }
+
+    // Remembers the transactions and blocks peers send for as long and as
+    // many as configured instead of the default
+    pub fn with_seen_cache(mut self, config: SeenCacheConfig) -> Self {
+        self.seen = Arc::new(RwLock::new(SeenCache::new(config)));
+        self
+    }
+
+    // Bans peers at the score and for as long as configured instead of the
+    // default, set before the storage so the saved bans are kept
+    pub fn with_ban_config(mut self, config: BanConfig) -> Self {
+        self.bans = Arc::new(RwLock::new(BanList::new(config)));
+        self
+    }
+
+    // Rules activate as the network's deployments say instead of never
+    pub fn with_deployments(mut self, deployments: Deployments) -> Self {
+        self.deployments = deployments;
+        self.deployment_states = Arc::new(std::sync::Mutex::new(StateCache::new()));
+        self
+    }
+
+    // Keeps the mempool and orphan blocks within the budget instead of the
+    // default one
+    pub fn with_memory_budget(mut self, memory_budget: MemoryBudget) -> Self {
+        self.memory_budget = memory_budget;
+        self
+    }
+
+    // Administrative actions are recorded to the log from now on
+    pub fn with_audit_log(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    pub fn audit_log(&self) -> Option<&AuditLog> {
+        self.audit.as_ref()
+    }
+
+    // Records the action to the audit log. A failed write doesn't stop the
+    // action, it's only logged
+    pub async fn audit(&self, action: AuditAction) {
+        let Some(audit) = self.audit.as_ref() else {
+            return;
+        };
+
+        if let Err(e) = audit.record(action.clone()).await {
+            error!("Failed to record {action} to the audit log: {e}");
+        }
+    }
+
+    // Chain updates are sent to the webhooks from now on
+    pub fn with_webhooks(mut self, webhooks: WebhookDispatcher) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    // Listens on the address instead of the loopback one, e.g. `0.0.0.0` or
+    // `[::]` to accept peers from other machines. Port 0 picks a free port
+    pub fn with_listen_address(mut self, address: SocketAddr) -> Self {
+        self.listen_address = address;
+        self
+    }
+
+    pub fn listen_address(&self) -> SocketAddr {
+        self.listen_address
+    }
+
+    // Binds the listener. The node introduces itself to peers with the
+    // address actually bound, so it's bound before the node is shared
+    pub async fn listen(mut self) -> anyhow::Result<(Self, TcpListener)> {
+        let (listener, address) = start_listening(self.listen_address).await?;
+        self.listen_address = address;
+
+        Ok((self, listener))
+    }
+
+    // Accepts connections forever, every connection is served on its own task
+    pub async fn run(&self, listener: TcpListener) -> anyhow::Result<()> {
+        info!(
+            "Node {} listening on {}",
+            self.node_id(),
+            self.listen_address
+        );
+
+        loop {
+            let (stream, address) = listener.accept().await?;
+            if self.is_banned(&address).await {
+                info!("Refused connection from banned address {address}");
+                continue;
+            }
+            let Ok(slot) = self.inbound.clone().try_acquire_owned() else {
+                warn!("Refused connection from {address}, all inbound slots are taken");
+                continue;
+            };
+            let node = self.clone();
+
+            tokio::spawn(async move {
+                // The slot frees up once the connection is closed
+                let _slot = slot;
+                if let Err(e) = node.handle_connection(stream, address).await {
+                    error!("Connection with {address} failed: {e}");
+                }
+            });
         }
     }
 
-    fn validate_transaction(&self, transaction: &Transaction) -> anyhow::Result<()> {
-        let n = transaction.verify("")?;
+    async fn handle_connection(
+        &self,
+        mut stream: TcpStream,
+        address: SocketAddr,
+    ) -> anyhow::Result<()> {
+        info!("Accepted connection from {address}");
+        // Our own until the peer's handshake negotiates one
+        let mut idle_timeout = self.idle_timeout;
+        // Address the peer introduced itself with, the items it sends aren't
+        // announced back to it on an outbound connection to that address
+        let mut origin = None;
+        let mut rate = MessageRate::new(&self.limits, Instant::now());
+        // In the clear until the handshake set up a session
+        let (mut incoming, mut outgoing) = (Transport::Plain, Transport::Plain);
+        // Bloom filter a light client set, the mempool and blocks it asks
+        // for are filtered by it
+        let mut filter = None;
+        // The peer's `Version` and the `VerAck` answering it, until the peer
+        // proved it holds the key of its `Version`
+        let mut unproven: Option<(Handshake, Handshake)> = None;
+        // Id of the node the peer proved to be
+        let mut authenticated = None;
+
+        loop {
+            let Ok(read) = tokio::time::timeout(
+                idle_timeout,
+                incoming.read_request(&mut stream, MAX_PAYLOAD_SIZE),
+            )
+            .await
+            else {
+                // Half-open or stalled, the peer would have pinged otherwise
+                warn!("{address} sent nothing for {idle_timeout:?}, closing the connection");
+                self.stats.record_reaped();
+                break;
+            };
 
+            let request = match read {
+                Ok(Some(request)) if request.header().network() != self.network => {
+                    warn!(
+                        "{address} is on the {} network, closing the connection",
+                        request.header().network()
+                    );
+                    self.misbehaving(address, Misbehavior::ProtocolViolation)
+                        .await;
+                    break;
+                }
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(corelib::errors::Error::Protocol(e)) => {
+                    // The next read skips ahead to the next frame's magic
+                    warn!("Malformed request from {address}: {e}");
+                    if self
+                        .misbehaving(address, Misbehavior::MalformedMessage)
+                        .await
+                    {
+                        break;
+                    }
+                    let response =
+                        Response::new(StatusCode::Error, None)?.with_network(self.network);
+                    outgoing.write_response(&mut stream, &response).await?;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if !rate.allow(Instant::now()) {
+                warn!(
+                    "{address} sends faster than {} messages a second, dropped a request, retry in {:?}",
+                    self.limits.messages_per_sec,
+                    rate.retry_after()
+                );
+                if self.misbehaving(address, Misbehavior::Flooding).await {
+                    break;
+                }
+                let response = Response::new(StatusCode::RateLimited, None)?
+                    .with_network(self.network)
+                    .with_version(request.header().version());
+                outgoing.write_response(&mut stream, &response).await?;
+                continue;
+            }
+            // Answered in the version the peer speaks
+            let version = request.header().version();
+            // Kept to check the peer's proof against once it's answered
+            let mut version_received = None;
+            match request.payload() {
+                Some(Message::Version(theirs)) => {
+                    idle_timeout = self.negotiate_idle_timeout(theirs);
+                    version_received = Some(theirs.clone());
+                }
+                Some(Message::PeerIntroduction(introduced)) => origin = introduced.parse().ok(),
+                _ => {}
+            }
+            if let (Some(origin), Some(message)) = (origin, request.payload()) {
+                self.peers.mark_known(&origin, &message.inventory()).await;
+            }
+
+            // Blocks and headers failing validation count against the peer,
+            // not blocks this node failed to process for reasons of its own
+            let mut invalid = matches!(request.payload(), Some(Message::HeaderAnnouncement(_)));
+            // Set up once the answer went out in the clear
+            let mut session = None;
+            let response = match request.payload() {
+                Some(Message::Version(theirs))
+                    if self.encrypted_transport && theirs.is_encrypted() =>
+                {
+                    let key = EphemeralKey::generate(&self.entropy);
+                    let answer = self.answer_version(theirs, key.public_key()).await?;
+                    if let Some(Message::VerAck(ours)) = answer.payload() {
+                        session = Some(key.agree(ours, theirs, false)?);
+                    }
+                    Ok(answer)
+                }
+                Some(Message::Authenticate(proof)) => match unproven.take() {
+                    Some((theirs, ours)) if ours.verify_proof(&theirs, proof).is_ok() => {
+                        info!("Node {} at {address} authenticated", theirs.node_id());
+                        authenticated = Some(theirs.node_id());
+                        // A filter set before counts for relay from now on
+                        let relay_to = self
+                            .proven_origin(address, origin, authenticated.as_deref())
+                            .await;
+                        if let Some(relay_to) = relay_to.filter(|_| filter.is_some()) {
+                            self.peers.set_filter(&relay_to, filter.clone()).await;
+                        }
+                        Response::new(StatusCode::OK, None)
+                    }
+                    // A `Version` replayed from another connection
+                    _ => {
+                        warn!("{address} didn't prove its identity, closing the connection");
+                        self.misbehaving(address, Misbehavior::ProtocolViolation)
+                            .await;
+                        break;
+                    }
+                },
+                Some(Message::SetFilter(set)) => {
+                    let relay_to = self
+                        .proven_origin(address, origin, authenticated.as_deref())
+                        .await;
+                    self.set_filter(address, relay_to, &mut filter, Some(set.clone()))
+                        .await
+                }
+                Some(Message::ClearFilter) => {
+                    let relay_to = self
+                        .proven_origin(address, origin, authenticated.as_deref())
+                        .await;
+                    self.set_filter(address, relay_to, &mut filter, None).await
+                }
+                Some(Message::GetFilteredBlock(hash)) => {
+                    self.filtered_block(hash, filter.as_ref()).await
+                }
+                Some(Message::GetMempool) if filter.is_some() => {
+                    self.mempool_inventory(filter.as_ref()).await
+                }
+                Some(Message::BlockProposal(block)) => {
+                    let processed = self.receive_proposal(block).await;
+                    invalid = matches!(processed, Err(BlockError::Invalid(_)));
+                    proposal_response(processed)
+                }
+                _ => self.handle_request(request).await,
+            }?
+            .with_network(self.network)
+            .with_version(version);
+            if invalid
+                && *response.status() == StatusCode::Error
+                && self.misbehaving(address, Misbehavior::InvalidBlock).await
+            {
+                break;
+            }
+            match outgoing.write_response(&mut stream, &response).await {
+                // Blocks past 64 KiB don't fit a version 1 frame, the peer
+                // has to download them in chunks
+                Err(corelib::errors::Error::Protocol(
+                    corelib::errors::ProtocolError::PayloadTooLarge(_),
+                )) => {
+                    let response = Response::new(StatusCode::NotFound, None)?
+                        .with_network(self.network)
+                        .with_version(version);
+                    outgoing.write_response(&mut stream, &response).await?;
+                }
+                written => written?,
+            }
+            if matches!(response.payload(), Some(Message::Outdated(..))) {
+                break;
+            }
+            if let (Some(theirs), Some(Message::VerAck(ours))) =
+                (version_received, response.payload())
+            {
+                unproven = Some((theirs, ours.clone()));
+            }
+            if let Some((send, receive)) = session {
+                info!("Encrypted the connection from {address}");
+                (incoming, outgoing) = (Transport::Encrypted(receive), Transport::Encrypted(send));
+            }
+        }
+
+        info!("Connection from {address} closed");
         Ok(())
     }
+
+    async fn handle_request(&self, request: Request) -> corelib::errors::Result<Response> {
+        match (request.command(), request.payload()) {
+            (Command::Ping, _) => Response::new(StatusCode::OK, Some(Message::Ping)),
+
+            (Command::Get, Some(Message::BlockRequest(height))) => {
+                let blockchain = self.blockchain.read().await;
+
+                match blockchain.as_ref().and_then(|chain| chain.block(*height)) {
+                    Some(block) => {
+                        Response::new(StatusCode::OK, Some(Message::BlockResponse(block.clone())))
+                    }
+                    None => Response::new(StatusCode::NotFound, None),
+                }
+            }
+
+            (Command::Get, Some(Message::BlockRequestByHash(hash))) => {
+                match self.get_block(hash).await {
+                    Some(block) => {
+                        Response::new(StatusCode::OK, Some(Message::BlockResponse(block)))
+                    }
+                    None => Response::new(StatusCode::NotFound, None),
+                }
+            }
+
+            (Command::Get, Some(Message::GetBlockChunk(hash, offset))) => {
+                match self
+                    .get_block(hash)
+                    .await
+                    .and_then(|block| BlockChunk::of(&block, *offset))
+                {
+                    Some(chunk) => Response::new(StatusCode::OK, Some(Message::BlockChunk(chunk))),
+                    None => Response::new(StatusCode::NotFound, None),
+                }
+            }
+
+            // Outputs past what fits in one response are left out, the
+            // balance counts them all
+            (Command::Get, Some(Message::GetUtxos(owner))) => {
+                let unspent = self.get_unspent(owner).await;
+                Response::new(
+                    StatusCode::OK,
+                    Some(Message::Utxos(fit_in_payload(unspent.iter()))),
+                )
+            }
+
+            (Command::Get, Some(Message::GetBalance(owner))) => Response::new(
+                StatusCode::OK,
+                Some(Message::Balance(self.get_balance(owner).await)),
+            ),
+
+            (Command::Get, Some(Message::GetMempool)) => self.mempool_inventory(None).await,
+
+            // Unfiltered on connections without a bloom filter
+            (Command::Get, Some(Message::GetFilteredBlock(hash))) => {
+                self.filtered_block(hash, None).await
+            }
+
+            // Blocks are sent by hash or in chunks, only pool transactions
+            // are answered
+            (Command::Get, Some(Message::GetData(items))) => {
+                let mut txns = Vec::new();
+                for item in items.iter().take(MAX_INVENTORY) {
+                    if let Inventory::Transaction(txid) = item {
+                        txns.extend(self.mem_pool.get(txid).await);
+                    }
+                }
+
+                Response::new(
+                    StatusCode::OK,
+                    Some(Message::Transactions(fit_in_payload(txns.iter()))),
+                )
+            }
+
+            (Command::Get, Some(Message::GetHeaders(start))) => {
+                let blockchain = self.blockchain.read().await;
+                let tip = blockchain.as_ref().map_or(0, |chain| chain.height());
+
+                let headers = (*start..start.saturating_add(MAX_HEADERS as u64).min(tip))
+                    .map_while(|height| blockchain.as_ref()?.block(height))
+                    .map(|block| block.header().clone())
+                    .collect();
+
+                Response::new(StatusCode::OK, Some(Message::Headers(headers)))
+            }
+
+            (Command::Get, Some(Message::GetBlocks(start, end))) => {
+                let blockchain = self.blockchain.read().await;
+                let tip = blockchain.as_ref().map_or(0, |chain| chain.height());
+
+                let blocks = fit_in_payload(
+                    (*start..(*end).min(start.saturating_add(BLOCK_WINDOW)).min(tip))
+                        .map_while(|height| blockchain.as_ref()?.block(height)),
+                );
+
+                Response::new(StatusCode::OK, Some(Message::Blocks(blocks)))
+            }
+
+            (Command::Get, Some(Message::GetBlocksByHash(hashes))) => {
+                let blockchain = self.blockchain.read().await;
+
+                let blocks = fit_in_payload(
+                    hashes
+                        .iter()
+                        .take(BLOCK_WINDOW as usize)
+                        .filter_map(|hash| blockchain.as_ref()?.block_by_hash(hash)),
+                );
+
+                Response::new(StatusCode::OK, Some(Message::Blocks(blocks)))
+            }
+
+            (Command::Post, Some(Message::PaymentTransaction(txn))) => {
+                if !self.first_seen(Inventory::Transaction(txn.hash_id)).await {
+                    return Response::new(StatusCode::OK, None);
+                }
+
+                match self.submit_transaction(txn.clone()).await {
+                    Ok(()) => {
+                        self.announce_transactions(std::slice::from_ref(txn)).await;
+                        Response::new(StatusCode::OK, None)
+                    }
+                    Err(e) => {
+                        warn!("Rejected transaction {}: {e}", hex::encode(txn.hash_id));
+                        Response::new(StatusCode::Error, None)
+                    }
+                }
+            }
+
+            (Command::Post, Some(Message::BlockProposal(block))) => {
+                proposal_response(self.receive_proposal(block).await)
+            }
+
+            (Command::Post, Some(Message::HeaderAnnouncement(header))) => {
+                if let Err(e) = self.announce_header(header.clone()).await {
+                    warn!("Rejected header {}: {e:#}", hex::encode(header.hash));
+                    return Response::new(StatusCode::Error, None);
+                }
+
+                Response::new(StatusCode::OK, None)
+            }
+
+            (Command::Post, Some(Message::Inv(items))) => {
+                let missing = self.missing_inventory(items).await;
+                Response::new(
+                    StatusCode::OK,
+                    (!missing.is_empty()).then_some(Message::GetData(missing)),
+                )
+            }
+
+            // Without a common version or a valid signature the connection
+            // is dropped
+            (Command::Post, Some(Message::Version(theirs))) => {
+                self.answer_version(theirs, [0u8; 32]).await
+            }
+
+            // The peer closes the connection after it
+            (Command::Post, Some(Message::Outdated(version, reason))) => {
+                warn!("Peer requires protocol version {version} or later: {reason}");
+                Response::new(StatusCode::OK, None)
+            }
+
+            (Command::Post, Some(Message::PeerIntroduction(address))) => {
+                let Ok(address) = address.parse::<SocketAddr>() else {
+                    return Response::new(StatusCode::Error, None);
+                };
+
+                self.learn_peer(address, true).await;
+
+                let known = self
+                    .peers
+                    .known_addresses()
+                    .await
+                    .into_iter()
+                    .filter(|known| *known != address)
+                    .map(|known| known.to_string())
+                    .collect();
+
+                Response::new(StatusCode::OK, Some(Message::PeerList(known)))
+            }
+
+            _ => Response::new(StatusCode::Error, None),
+        }
+    }
+
+    // Sets or clears the bloom filter of an inbound connection. It also
+    // filters what is relayed on the outbound connection to `relay_to`, see
+    // `proven_origin`
+    async fn set_filter(
+        &self,
+        address: SocketAddr,
+        relay_to: Option<SocketAddr>,
+        filter: &mut Option<BloomFilter>,
+        set: Option<BloomFilter>,
+    ) -> corelib::errors::Result<Response> {
+        if set.as_ref().is_some_and(|set| !set.is_valid()) {
+            warn!("{address} set a bloom filter past the limits");
+            self.misbehaving(address, Misbehavior::ProtocolViolation)
+                .await;
+            return Response::new(StatusCode::Error, None);
+        }
+
+        if let Some(relay_to) = relay_to {
+            self.peers.set_filter(&relay_to, set.clone()).await;
+        }
+        *filter = set;
+        Response::new(StatusCode::OK, None)
+    }
+
+    // Address an inbound connection introduced itself with, once it's proven
+    // to be the same node as the outbound peer there: the same IP, and the
+    // node id authenticated on both connections. Anyone can claim an address
+    // otherwise
+    async fn proven_origin(
+        &self,
+        address: SocketAddr,
+        origin: Option<SocketAddr>,
+        node_id: Option<&str>,
+    ) -> Option<SocketAddr> {
+        let origin = origin.filter(|origin| origin.ip() == address.ip())?;
+        let peer_id = self.peers.node_id(&origin).await?;
+
+        (Some(peer_id.as_str()) == node_id).then_some(origin)
+    }
+
+    // `Inv` of the best pool transactions, only the ones matching the filter
+    // if there is one
+    async fn mempool_inventory(
+        &self,
+        filter: Option<&BloomFilter>,
+    ) -> corelib::errors::Result<Response> {
+        let mut items = Vec::new();
+        for txid in self.mem_pool.inventory(MAX_INVENTORY).await {
+            let matches = match filter {
+                Some(filter) => self
+                    .mem_pool
+                    .get(&txid)
+                    .await
+                    .is_some_and(|txn| filter.matches(&txn)),
+                None => true,
+            };
+            if matches {
+                items.push(Inventory::Transaction(txid));
+            }
+        }
+
+        Response::new(
+            StatusCode::OK,
+            (!items.is_empty()).then_some(Message::Inv(items)),
+        )
+    }
+
+    async fn filtered_block(
+        &self,
+        hash: &[u8; 32],
+        filter: Option<&BloomFilter>,
+    ) -> corelib::errors::Result<Response> {
+        match self.get_block(hash).await {
+            Some(block) => Response::new(
+                StatusCode::OK,
+                Some(Message::FilteredBlock(FilteredBlock::of(&block, filter))),
+            ),
+            None => Response::new(StatusCode::NotFound, None),
+        }
+    }
+
+    pub async fn handle_response(&self, address: SocketAddr, response: Response) {
+        if let Some(message) = response.payload() {
+            self.peers.mark_known(&address, &message.inventory()).await;
+        }
+
+        // Likely a block being downloaded from the peer, which would wait
+        // for chunks that never come
+        if *response.status() == StatusCode::NotFound {
+            self.drop_downloads(|download| download.peer != address)
+                .await;
+        }
+
+        match response.payload() {
+            Some(Message::PeerList(addresses)) => {
+                for peer in addresses.iter().filter_map(|a| a.parse().ok()) {
+                    self.learn_peer(peer, false).await;
+                }
+            }
+            Some(Message::Headers(headers)) => self.receive_headers(address, headers).await,
+            Some(Message::Blocks(blocks)) => self.receive_blocks(blocks).await,
+            // Parents of orphan blocks, requested outside of the block download
+            Some(Message::BlockResponse(block)) => self.receive_block(address, block.clone()).await,
+            Some(Message::BlockChunk(chunk)) => self.receive_chunk(address, chunk).await,
+            Some(Message::VerAck(handshake)) => self.complete_handshake(address, handshake).await,
+            Some(Message::Outdated(version, reason)) => {
+                warn!("Peer {address} requires protocol version {version} or later: {reason}");
+                self.peers.remove_peer(&address).await;
+            }
+            // Keep-alive answers, receiving them is all that matters
+            Some(Message::Ping) => {}
+            Some(Message::GetData(items)) => self.send_data(address, items).await,
+            // The peer's mempool, asked for after the handshake
+            Some(Message::Inv(items)) => self.request_transactions(address, items).await,
+            Some(Message::Transactions(txns)) => self.receive_transactions(txns).await,
+            _ => info!(
+                "Received {:?} response from peer {address}",
+                response.status()
+            ),
+        }
+    }
+
+    // Connects to the seed peers and introduces this node to them, their
+    // answers carry the addresses of further peers to connect to. The block
+    // download starts once a peer supporting headers-first sync answers.
+    //
+    // Seeds are `host:port`, a host name is tried at every address it
+    // resolves to
+    pub async fn bootstrap(&self, seeds: &[String]) {
+        for seed in seeds {
+            let addresses = match self.peers.resolve(seed).await {
+                Ok(addresses) => addresses,
+                Err(e) => {
+                    warn!("Failed to resolve seed {seed}: {e}");
+                    continue;
+                }
+            };
+
+            for address in addresses {
+                self.peers.add_known(address).await;
+
+                if let Err(e) = self.introduce(address).await {
+                    warn!("Failed to introduce to seed {address}: {e}");
+                }
+            }
+        }
+    }
+
+    // Bootstraps from the seeds again whenever every peer dropped, checked
+    // every interval. The dial queue keeps the redials from piling up
+    pub async fn keep_connected(&self, seeds: &[String], interval: Duration) {
+        if seeds.is_empty() {
+            return;
+        }
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            if self.peers.peers().await.is_empty() {
+                info!("Lost every peer, bootstrapping from the seeds again");
+                self.bootstrap(seeds).await;
+            }
+        }
+    }
+
+    // Starts the block download by asking a peer for the headers above the
+    // chain tip
+    pub async fn start_sync(&self) {
+        let Some(peer) = self
+            .peers
+            .peers()
+            .await
+            .iter()
+            .find(|peer| peer.features.contains(Features::HEADERS_FIRST))
+            .map(|peer| peer.address)
+        else {
+            info!("No peers to sync with");
+            return;
+        };
+
+        if let Err(e) = self.request_headers(peer).await {
+            warn!("Failed to request headers from {peer}: {e}");
+        }
+    }
+
+    async fn request_headers(&self, peer: SocketAddr) -> anyhow::Result<()> {
+        let start = self.sync.read().await.header_height(&peer);
+        self.request_headers_from(peer, start).await
+    }
+
+    async fn request_headers_from(&self, peer: SocketAddr, start: u64) -> anyhow::Result<()> {
+        let request = Request::new(Command::Get, Some(Message::GetHeaders(start)))?;
+
+        self.peers.send(&peer, request).await
+    }
+
+    async fn receive_headers(&self, address: SocketAddr, headers: &[BlockHeader]) {
+        self.pipeline.push_headers(address, headers.to_vec()).await;
+    }
+
+    async fn receive_blocks(&self, blocks: &[Block]) {
+        for block in blocks {
+            self.pipeline.push_block(block.clone()).await;
+        }
+    }
+
+    // Runs the stages of the block download until the node stops, see
+    // `SyncPipeline`. Only the first call runs them
+    pub async fn run_sync_pipeline(&self) {
+        let Some(stages) = self.pipeline.take_stages().await else {
+            return;
+        };
+
+        tokio::join!(
+            self.check_headers(stages.headers),
+            self.check_blocks(stages.blocks, stages.checks_tx),
+            self.connect_blocks(stages.checks),
+        );
+    }
+
+    // Header stage: checks the proof of work of header batches on a blocking
+    // worker and links them up with the known headers
+    async fn check_headers(&self, mut batches: mpsc::Receiver<(SocketAddr, Vec<BlockHeader>)>) {
+        while let Some((address, headers)) = batches.recv().await {
+            let (headers, checked) = tokio::task::spawn_blocking(move || {
+                let checked = check_proof_of_work(&headers);
+                (headers, checked)
+            })
+            .await
+            .unwrap_or_else(|e| (Vec::new(), Err(e.to_string())));
+
+            match checked {
+                Ok(()) => self.link_headers(address, headers).await,
+                Err(e) => {
+                    warn!("Rejected headers from {address}: {e}");
+                    self.misbehaving(address, Misbehavior::InvalidBlock).await;
+                }
+            }
+        }
+    }
+
+    // Checks headers that passed the proof of work check against the ones
+    // before them, like the headers of blocks about to be connected, and adds
+    // them to their branch. The download follows the branch with the most
+    // work
+    async fn link_headers(&self, address: SocketAddr, headers: Vec<BlockHeader>) {
+        let Some(first) = headers.first() else {
+            return;
+        };
+        let (start, count) = (first.index, headers.len());
+
+        let blockchain = self.blockchain.read().await;
+        let mut sync = self.sync.write().await;
+
+        let tip = blockchain.as_ref().map(|chain| chain.tip().header());
+        let attachment = match sync.attachment(&address, tip, first) {
+            Ok(attachment) => attachment,
+            Err(e) => {
+                let validated = sync.checkpoint().validated();
+                drop(sync);
+                drop(blockchain);
+                warn!("Rejected headers from {address}: {e}");
+
+                // The peer's branch may leave the known headers lower down,
+                // it's asked once for all of its headers above the chain tip
+                if start > validated {
+                    if let Err(e) = self.request_headers_from(address, validated).await {
+                        warn!("Failed to request headers from {address}: {e}");
+                    }
+                }
+                return;
+            }
+        };
+
+        let checked = check_headers(
+            blockchain.as_deref(),
+            self.network,
+            &self.params,
+            sync.previous(&address, attachment),
+            &headers,
+            now_millis(),
+        );
+        if checked.is_ok() {
+            sync.add_headers(address, attachment, headers);
+        }
+        drop(sync);
+        drop(blockchain);
+
+        match checked {
+            Ok(_) => {}
+            // Ahead of this node's clock, which may just be behind
+            Err(e @ corelib::errors::Error::FutureBlock) => {
+                warn!("Rejected headers from {address}: {e}");
+                return;
+            }
+            Err(e) => {
+                warn!("Rejected headers from {address}: {e}");
+                self.misbehaving(address, Misbehavior::InvalidBlock).await;
+                return;
+            }
+        }
+
+        // A full batch means the peer probably has more
+        if count == MAX_HEADERS {
+            if let Err(e) = self.request_headers(address).await {
+                warn!("Failed to request headers from {address}: {e}");
+            }
+        }
+
+        self.schedule_downloads().await;
+    }
+
+    // Script check stage: blocks matching the synced headers are checked on
+    // blocking workers, several at once. The checks are queued in download
+    // order for the connect stage
+    async fn check_blocks(
+        &self,
+        mut blocks: mpsc::Receiver<Block>,
+        checks: mpsc::Sender<BlockCheck>,
+    ) {
+        // Outputs unspent after the last block sent to be checked, along
+        // with its hash. Blocks extending it are checked against it while
+        // their parent is still connecting
+        let mut view = None;
+
+        while let Some(block) = blocks.recv().await {
+            let expected = self.sync.read().await.expected_hash(block.index());
+            if expected != Some(block.hash()) {
+                warn!(
+                    "Block {} at height {} doesn't match the synced headers",
+                    hex::encode(block.hash()),
+                    block.index()
+                );
+                self.sync.write().await.retry(block.index());
+                continue;
+            }
+
+            // The block's parent may still be connecting, the block is then
+            // checked under the rules of the next block, and again before
+            // it's connected if its own turn out to differ. Blocks spending
+            // outputs that aren't known yet are checked once connected
+            let (rules, spent) = {
+                let blockchain = self.blockchain.read().await;
+                let rules = self
+                    .rules_for(blockchain.as_deref(), &block)
+                    .or_else(|| {
+                        let chain = blockchain.as_ref()?;
+                        Some(self.rules_after(chain, &chain.tip().hash()))
+                    })
+                    .unwrap_or(Rules::NONE);
+
+                let parent = <[u8; 32]>::from_hex(block.previous_hash()).ok();
+                let utxos = match view.take() {
+                    Some((tip, utxos)) if Some(tip) == parent => Some(utxos),
+                    _ => blockchain
+                        .as_ref()
+                        .zip(parent)
+                        .and_then(|(chain, parent)| chain.utxos_at(&parent)),
+                };
+                let spent = utxos.map(|mut utxos| {
+                    let spent = apply_block(&mut utxos, &block);
+                    if spent.is_ok() {
+                        view = Some((block.hash(), utxos));
+                    }
+                    spent
+                });
+                (rules, spent)
+            };
+
+            let node = self.clone();
+            let check = tokio::task::spawn_blocking(move || {
+                let checked = match node.check_header(&block) {
+                    Err(e) => Err(CheckFailure::Uncommitted(e)),
+                    Ok(()) => match spent {
+                        Some(spent) => spent
+                            .map_err(anyhow::Error::from)
+                            .and_then(|spent| {
+                                let context = BlockContext { rules, spent };
+                                node.validate_block(&block, &context)?;
+                                Ok(Some(context))
+                            })
+                            .map_err(CheckFailure::Invalid),
+                        None => Ok(None),
+                    },
+                };
+                (block, checked)
+            });
+
+            if checks.send(check).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Connect stage: connects the checked blocks in the order they were
+    // downloaded and requests more blocks as windows complete
+    async fn connect_blocks(&self, mut checks: mpsc::Receiver<BlockCheck>) {
+        while let Some(check) = checks.recv().await {
+            let (block, checked) = match check.await {
+                Ok(check) => check,
+                Err(e) => {
+                    error!("Block check failed: {e}");
+                    continue;
+                }
+            };
+            let (height, hash) = (block.index(), block.hash());
+
+            let processed = match checked {
+                Ok(context) => {
+                    self.process_checked_block(CheckedBlock { block, context })
+                        .await
+                }
+                Err(CheckFailure::Invalid(e)) => {
+                    self.relay.write().await.reject(hash);
+                    Err(BlockError::Invalid(e))
+                }
+                // Another peer may send the block the header commits to, the
+                // window is requested again
+                Err(CheckFailure::Uncommitted(e)) => {
+                    self.sync.write().await.retry(height);
+                    Err(BlockError::Local(e))
+                }
+            };
+            if let Err(e) = processed {
+                warn!("Rejected block {}: {e}", hex::encode(hash));
+                if matches!(e, BlockError::Invalid(_)) {
+                    self.window_failed(height, hash).await;
+                }
+            }
+
+            // Keep the download going while later blocks are still checked
+            if checks.is_empty() {
+                self.schedule_downloads().await;
+            }
+        }
+    }
+
+    // The block at `height` of the synced headers is invalid, so is their
+    // branch from there. It's dropped, the peer that sent it penalised and
+    // the peers asked for their headers again
+    async fn window_failed(&self, height: u64, hash: [u8; 32]) {
+        let source = {
+            let mut sync = self.sync.write().await;
+            if sync.expected_hash(height) != Some(hash) {
+                return;
+            }
+            sync.drop_headers(height)
+        };
+
+        if let Some(source) = source {
+            warn!("Dropped the headers of {source} from height {height}");
+            self.misbehaving(source, Misbehavior::InvalidBlock).await;
+        }
+        self.start_sync().await;
+    }
+
+    // Requests the next block windows from the connected peers
+    async fn schedule_downloads(&self) {
+        let peer_info = self.peers.peers().await;
+        let peers: Vec<SocketAddr> = peer_info.iter().map(|peer| peer.address).collect();
+
+        let mut sync = self.sync.write().await;
+        let requests = sync.schedule(&peers, BLOCK_WINDOW, MAX_WINDOWS_IN_FLIGHT);
+
+        // Peers that can are asked for the blocks of the synced headers by
+        // hash, a height may hold another block on their best chain
+        let requests: Vec<(SocketAddr, Message)> = requests
+            .into_iter()
+            .map(|(peer, window)| {
+                let by_hash = peer_info.iter().any(|info| {
+                    info.address == peer && info.features.contains(Features::BLOCKS_BY_HASH)
+                });
+
+                let message = match sync.window_hashes(window).filter(|_| by_hash) {
+                    Some(hashes) => Message::GetBlocksByHash(hashes),
+                    None => Message::GetBlocks(window.start, window.end),
+                };
+                (peer, message)
+            })
+            .collect();
+        drop(sync);
+
+        for (peer, message) in requests {
+            let request = Request::new(Command::Get, Some(message));
+            let sent = match request {
+                Ok(request) => self.peers.send(&peer, request).await,
+                Err(e) => Err(e.into()),
+            };
+
+            if let Err(e) = sent {
+                warn!("Failed to request blocks from {peer}: {e}");
+            }
+        }
+
+        if self.sync.read().await.is_synced() {
+            info!("Block download complete");
+        }
+    }
+
+    // Connects to a peer with a version handshake and introduces this node
+    async fn introduce(&self, address: SocketAddr) -> anyhow::Result<()> {
+        if self.is_banned(&address).await {
+            bail!("{address} is banned");
+        }
+        if !self.peers.is_connected(&address).await {
+            self.peers.connect(address).await?;
+
+            let nonce = self
+                .peers
+                .handshake_nonce(&address)
+                .await
+                .unwrap_or_default();
+            let ephemeral = self
+                .peers
+                .handshake_ephemeral(&address)
+                .await
+                .unwrap_or_default();
+            let handshake = self
+                .handshake(self.get_block_count().await)
+                .with_nonce(nonce)
+                .with_ephemeral(ephemeral)
+                .sign(&self.identity);
+            let version = Message::Version(handshake);
+            self.peers
+                .send(&address, Request::new(Command::Post, Some(version))?)
+                .await?;
+        }
+
+        let introduction = Message::PeerIntroduction(self.listen_address.to_string());
+        self.peers
+            .send(&address, Request::new(Command::Post, Some(introduction))?)
+            .await
+    }
+
+    // Settles on the highest common version and the extensions both sides
+    // support, peers without a common version are disconnected. Headers are
+    // requested from peers ahead of this node supporting headers-first sync.
+    // The answer has to be signed for the nonce this node sent, or the peer
+    // isn't who it claims to be
+    async fn complete_handshake(&self, address: SocketAddr, theirs: &Handshake) {
+        let nonce = self.peers.handshake_nonce(&address).await;
+        if theirs.verify().is_err() || nonce != Some(theirs.challenge) {
+            warn!("Dropping peer {address}: handshake isn't signed for this connection");
+            self.misbehaving(address, Misbehavior::ProtocolViolation)
+                .await;
+            self.peers.remove_peer(&address).await;
+            return;
+        }
+
+        let version = match self.negotiate_version(theirs) {
+            Ok(version) => version,
+            Err(reason) => {
+                warn!("Dropping peer {address}: its protocol versions aren't accepted");
+                let notice = Message::Outdated(self.min_peer_version, reason);
+                self.peers.dismiss(&address, notice).await;
+                return;
+            }
+        };
+
+        // The peer checks this node holds the key of its `Version` in turn
+        let proof = Message::Authenticate(theirs.prove(&self.identity));
+        let sent = match Request::new(Command::Post, Some(proof)) {
+            Ok(request) => self.peers.send(&address, request).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = sent {
+            warn!("Failed to authenticate to {address}: {e}");
+        }
+
+        self.sample_clock(address, theirs).await;
+        let features = Features::SUPPORTED.negotiate(theirs.features);
+        let idle_timeout = self.negotiate_idle_timeout(theirs);
+        self.peers
+            .complete_handshake(
+                &address,
+                theirs.node_id(),
+                version,
+                features,
+                theirs.height,
+                idle_timeout,
+            )
+            .await;
+        info!(
+            "Handshake with node {} at {address}, height {}: version {version}, features [{features}], idle timeout {idle_timeout:?}",
+            theirs.node_id(),
+            theirs.height
+        );
+
+        if features.contains(Features::HEADERS_FIRST)
+            && theirs.height > self.sync.read().await.target_height()
+        {
+            if let Err(e) = self.request_headers(address).await {
+                warn!("Failed to request headers from {address}: {e}");
+            }
+        }
+        if features.contains(Features::MEMPOOL) {
+            let sent = match Request::new(Command::Get, Some(Message::GetMempool)) {
+                Ok(request) => self.peers.send(&address, request).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = sent {
+                warn!("Failed to request the mempool of {address}: {e}");
+            }
+        }
+    }
+
+    // `VerAck` answering a peer's handshake, offering the ephemeral key if it
+    // isn't all zeroes
+    async fn answer_version(
+        &self,
+        theirs: &Handshake,
+        ephemeral: [u8; 32],
+    ) -> corelib::errors::Result<Response> {
+        theirs.verify()?;
+        if let Err(reason) = self.negotiate_version(theirs) {
+            warn!(
+                "Disconnecting node {}: its protocol versions aren't accepted",
+                theirs.node_id()
+            );
+            let notice = Message::Outdated(self.min_peer_version, reason);
+            return Response::new(StatusCode::Error, Some(notice));
+        }
+        info!("Node {} connected", theirs.node_id());
+
+        let handshake = self
+            .handshake(self.get_block_count().await)
+            .answering(theirs)
+            .with_nonce(u64::from_le_bytes(self.entropy.bytes()))
+            .with_ephemeral(ephemeral)
+            .sign(&self.identity);
+        Response::new(StatusCode::OK, Some(Message::VerAck(handshake)))
+    }
+
+    // Highest version both sides speak. If there's none at or above the
+    // minimum the rejection is counted and the reason told to the peer is
+    // returned
+    fn negotiate_version(&self, theirs: &Handshake) -> Result<u16, String> {
+        match SupportedVersions::negotiate(&theirs.versions) {
+            Ok(version) if version >= self.min_peer_version => Ok(version),
+            _ => {
+                let latest = theirs.versions.iter().copied().max().unwrap_or_default();
+                self.stats.record_rejected_version(latest);
+
+                let accepted: Vec<u16> = SupportedVersions::all()
+                    .into_iter()
+                    .filter(|version| *version >= self.min_peer_version)
+                    .collect();
+                Err(format!(
+                    "protocol version {latest} is no longer accepted, upgrade to one of {accepted:?}"
+                ))
+            }
+        }
+    }
+
+    // What this node advertises at the height
+    fn handshake(&self, height: u64) -> Handshake {
+        Handshake::new(height)
+            .with_idle_timeout(self.idle_timeout)
+            .with_timestamp(now_millis() as u64)
+    }
+
+    // Local time shifted by the median offset of the peers' clocks
+    pub async fn adjusted_time(&self) -> u128 {
+        self.network_time.read().await.adjust(now_millis())
+    }
+
+    // Milliseconds the network-adjusted time is ahead of the local clock
+    pub async fn time_offset(&self) -> i64 {
+        self.network_time.read().await.offset()
+    }
+
+    // Samples the clock an outbound peer sent in its `VerAck`. Only peers
+    // this node picked are sampled, anyone can connect in as many times as
+    // it likes
+    async fn sample_clock(&self, address: SocketAddr, theirs: &Handshake) {
+        let mut network_time = self.network_time.write().await;
+        let previous = network_time.offset();
+        let Some(median) = network_time.add_sample(address.ip(), theirs.timestamp, now_millis())
+        else {
+            return;
+        };
+
+        if median.abs() > SKEW_WARNING {
+            warn!(
+                "The local clock is {}s off the median of the peers' clocks, check that it's set correctly",
+                median / 1_000
+            );
+        }
+        if network_time.offset() != previous {
+            info!(
+                "Network-adjusted time is {}ms off the local clock",
+                network_time.offset()
+            );
+        }
+    }
+
+    // Idle time both sides tolerate. The longer one is picked so neither side
+    // closes a connection the other only keeps alive at its own pace, the
+    // peer's up to `MAX_IDLE_TIMEOUT`
+    fn negotiate_idle_timeout(&self, theirs: &Handshake) -> Duration {
+        let theirs = theirs.idle_timeout.min(MAX_IDLE_TIMEOUT);
+        self.idle_timeout.max(Duration::from_millis(theirs as u64))
+    }
+
+    // Handles an address learned from gossip. New addresses are connected to
+    // while there are free peer slots, and relayed to the other peers if they
+    // were announced directly by their owner.
+    async fn learn_peer(&self, address: SocketAddr, relay: bool) {
+        if address == self.listen_address
+            || self.is_banned(&address).await
+            || !self.peers.add_known(address).await
+        {
+            return;
+        }
+        info!("Discovered peer {address}");
+
+        if relay {
+            if let Err(e) = self
+                .broadcast(Message::PeerIntroduction(address.to_string()))
+                .await
+            {
+                warn!("Failed to relay peer {address}: {e}");
+            }
+        }
+
+        if !self.peers.is_full().await {
+            let node = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = node.introduce(address).await {
+                    warn!("Failed to connect to peer {address}: {e}");
+                }
+            });
+        }
+    }
+
+    // Validates a block received from the network and appends it to the chain.
+    //
+    // The header is announced to the peers as soon as it passes the header
+    // checks so they hear about the block while its transactions are
+    // validated.
+    // Blocks whose parent is unknown are buffered until the parent arrives,
+    // every block that ends up connected is relayed to the peers.
+    pub async fn process_block(&self, block: Block) -> Result<BlockOutcome, BlockError> {
+        self.ensure_writable()?;
+        self.check_header(&block).map_err(BlockError::Invalid)?;
+        if let Some(outcome) = self.screen_block(&block).await? {
+            return Ok(outcome);
+        }
+
+        // The header is checked against its parent's before anything else, a
+        // header failing its checks fails the block as well
+        self.announce_header(block.header().clone())
+            .await
+            .map_err(BlockError::Invalid)?;
+
+        // Blocks whose parent is unknown are validated once it arrives
+        let Some(context) = self.block_context(&block).await else {
+            return self.connect_validated(block, None).await;
+        };
+
+        // The signatures are verified on a blocking worker
+        let node = self.clone();
+        let (block, checked) = tokio::task::spawn_blocking(move || {
+            let checked = context.and_then(|context| {
+                node.validate_block(&block, &context)?;
+                Ok(context)
+            });
+            (block, checked)
+        })
+        .await
+        .map_err(anyhow::Error::from)?;
+
+        let context = match checked {
+            Ok(context) => context,
+            Err(e) => {
+                self.relay.write().await.reject(block.hash());
+                return Err(BlockError::Invalid(e));
+            }
+        };
+
+        self.connect_validated(block, Some(context)).await
+    }
+
+    // Processes a block checked ahead of time by the sync pipeline
+    async fn process_checked_block(
+        &self,
+        checked: CheckedBlock,
+    ) -> Result<BlockOutcome, BlockError> {
+        let CheckedBlock { block, context } = checked;
+
+        self.ensure_writable()?;
+        if let Some(outcome) = self.screen_block(&block).await? {
+            return Ok(outcome);
+        }
+
+        self.announce_header(block.header().clone())
+            .await
+            .map_err(BlockError::Invalid)?;
+
+        self.connect_validated(block, context).await
+    }
+
+    // Checks that need nothing but the block itself: its proof of work and
+    // that the header commits to its transactions
+    fn check_header(&self, block: &Block) -> anyhow::Result<()> {
+        if !block.header().is_valid() {
+            bail!("Invalid proof of work");
+        }
+        block.check_merkle_root()?;
+
+        Ok(())
+    }
+
+    // Fails for blocks rejected before, the outcome for blocks already known
+    async fn screen_block(&self, block: &Block) -> Result<Option<BlockOutcome>, BlockError> {
+        if self.relay.read().await.is_invalid(&block.hash()) {
+            return Err(BlockError::Invalid(anyhow!("Block was rejected before")));
+        }
+        if let Some(chain) = self.blockchain.read().await.as_ref() {
+            if chain.contains(&block.hash()) {
+                return Ok(Some(BlockOutcome::Known));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Connects a block validated in `context`, or buffers it until its
+    // parent arrives. Blocks are validated again before they're connected
+    // unless that's the context on their own branch, orphans always are
+    async fn connect_validated(
+        &self,
+        block: Block,
+        context: Option<BlockContext>,
+    ) -> Result<BlockOutcome, BlockError> {
+        self.stats.record_validated();
+
+        let now = self.adjusted_time().await;
+        let mut blockchain = self.blockchain.write().await;
+
+        if let Some(chain) = blockchain.as_ref() {
+            if chain.contains(&block.hash()) {
+                return Ok(BlockOutcome::Known);
+            }
+        }
+
+        if !has_parent(blockchain.as_deref(), &block) {
+            drop(blockchain);
+            let parent = (block.index(), block.previous_hash().to_string());
+            self.buffer_orphan(block).await;
+            self.request_parent(parent).await;
+            return Ok(BlockOutcome::Orphaned);
+        }
+
+        if let Err(e) = self.revalidate(blockchain.as_deref(), &block, context.as_ref()) {
+            self.relay.write().await.reject(block.hash());
+            return Err(BlockError::Invalid(e));
+        }
+
+        let mut update = match connect_block(
+            &mut blockchain,
+            block.clone(),
+            now,
+            self.network,
+            self.params,
+        ) {
+            Ok(update) => update,
+            // Judged by this node's clock, the block may be fine later
+            Err(e @ corelib::errors::Error::FutureBlock) => return Err(anyhow!(e).into()),
+            Err(e) => {
+                self.relay.write().await.reject(block.hash());
+                return Err(BlockError::Invalid(e.into()));
+            }
+        };
+        let mut accepted = vec![block];
+
+        // Buffered descendants can be connected now that their parent is known
+        let mut pending_blocks = self.pending_blocks.write().await;
+        while let Some(position) = pending_blocks
+            .iter()
+            .position(|orphan| has_parent(blockchain.as_deref(), orphan))
+        {
+            let orphan = pending_blocks.remove(position);
+
+            if let Err(e) = self.revalidate(blockchain.as_deref(), &orphan, None) {
+                warn!("Dropped orphan block {}: {e}", hex::encode(orphan.hash()));
+                self.relay.write().await.reject(orphan.hash());
+                continue;
+            }
+
+            match connect_block(
+                &mut blockchain,
+                orphan.clone(),
+                now,
+                self.network,
+                self.params,
+            ) {
+                Ok(next) => {
+                    update.append(next);
+                    accepted.push(orphan);
+                }
+                Err(e) => warn!("Dropped orphan block {}: {e}", hex::encode(orphan.hash())),
+            }
+        }
+        drop(pending_blocks);
+
+        // The blocks are written once the chain is unlocked
+        let checkpoint = match blockchain.as_ref() {
+            Some(chain) => {
+                let mut sync = self.sync.write().await;
+                sync.set_validated(chain.height());
+                Some(sync.checkpoint().clone())
+            }
+            None => None,
+        };
+        drop(blockchain);
+        if let Some(checkpoint) = checkpoint {
+            if let Err(e) = self.persist(&accepted, &checkpoint).await {
+                error!("Failed to persist the chain: {e}");
+            }
+        }
+
+        self.apply_to_mempool(&update).await;
+        if let Some(tip) = update.connected.last() {
+            self.templates.new_tip(tip.hash(), tip.index());
+        }
+        if let Some(webhooks) = self.webhooks.as_ref() {
+            webhooks.notify(&update).await;
+        }
+
+        for block in accepted.iter() {
+            info!(
+                "Accepted block {} at height {}",
+                hex::encode(block.hash()),
+                block.index()
+            );
+
+            if self.relay.write().await.relay(block.hash()) {
+                self.announce(&[Inventory::Block(block.hash())]).await;
+            }
+        }
+
+        Ok(BlockOutcome::Connected(accepted.len() - 1))
+    }
+
+    // Checks the transactions of a block whose proof of work is valid,
+    // under the rules active at its height and against the outputs they
+    // spend on its branch
+    fn validate_block(&self, block: &Block, context: &BlockContext) -> anyhow::Result<()> {
+        block.check_coinbase_with(&self.params)?;
+        block.check_sigops()?;
+        block.check_locktimes()?;
+        if context.spent.len() != block.transactions().len() {
+            bail!("Spent outputs don't match the transactions");
+        }
+        for (txn, spent) in block
+            .transactions()
+            .iter()
+            .zip(context.spent.iter())
+            .filter(|(txn, _)| !txn.is_coinbase())
+        {
+            self.validate_transaction(txn, spent, block.index(), context.rules)
+                .with_context(|| format!("transaction {}", hex::encode(txn.hash_id)))?;
+        }
+
+        Ok(())
+    }
+
+    // Validates a block whose parent is known again, unless `checked` is the
+    // context it has on its branch in the given chain
+    fn revalidate(
+        &self,
+        blockchain: Option<&BlockChain>,
+        block: &Block,
+        checked: Option<&BlockContext>,
+    ) -> anyhow::Result<()> {
+        let context = self
+            .context_for(blockchain, block)
+            .ok_or_else(|| anyhow!("Unknown parent block"))??;
+        if checked == Some(&context) {
+            return Ok(());
+        }
+
+        self.validate_block(block, &context)
+    }
+
+    // Runs every check peers run on a block, before anything about it is
+    // announced to them: the proof of work, the transactions under the rules
+    // active at its height and where it goes in the chain. The error names
+    // the check the block fails
+    async fn self_validate(&self, block: &Block) -> anyhow::Result<()> {
+        self.check_header(block).context("header")?;
+
+        let context = self
+            .block_context(block)
+            .await
+            .ok_or_else(|| anyhow!("unknown parent block"))??;
+        self.validate_block(block, &context)
+            .with_context(|| format!("block under rules {:?}", context.rules))?;
+
+        let now = self.adjusted_time().await;
+        if let Some(chain) = self.blockchain.read().await.as_ref() {
+            chain
+                .check_block_at(block, now)
+                .context("position in the chain")?;
+        }
+
+        Ok(())
+    }
+
+    // Forwards a header to the peers once it extends a known block, passes
+    // the checks its block will be held to and has the work to compete with
+    // the best chain. Headers announced before, of rejected blocks or of
+    // blocks already in the chain aren't forwarded again. Fails for headers
+    // that can't be valid, headers only judged later are skipped
+    async fn announce_header(&self, header: BlockHeader) -> anyhow::Result<()> {
+        if !header.is_valid() {
+            bail!("invalid proof of work");
+        }
+
+        let now = self.adjusted_time().await;
+        {
+            let blockchain = self.blockchain.read().await;
+            let Some(chain) = blockchain.as_ref() else {
+                return Ok(());
+            };
+            let parent = <[u8; 32]>::from_hex(&header.previous_hash)
+                .map_err(|_| anyhow!("malformed previous hash"))?;
+            if chain.contains(&header.hash) || !chain.contains(&parent) {
+                return Ok(());
+            }
+            match chain.check_header_at(&header, now) {
+                // The local clock may be behind, the full block is judged again
+                Err(corelib::errors::Error::FutureBlock) => return Ok(()),
+                checked => checked?,
+            };
+            if !chain.is_competitive(&header) {
+                return Ok(());
+            }
+        }
+        if !self.relay.write().await.announce(header.hash) {
+            return Ok(());
+        }
+
+        let hash = header.hash;
+        if let Err(e) = self.broadcast(Message::HeaderAnnouncement(header)).await {
+            warn!("Failed to announce header {}: {e}", hex::encode(hash));
+        }
+        Ok(())
+    }
+
+    // Transactions of blocks rolled back by a reorganization go back to the
+    // mempool unless the new branch includes them too
+    async fn apply_to_mempool(&self, update: &ChainUpdate) {
+        if update.is_reorg() {
+            warn!(
+                "Chain reorganization: {} blocks disconnected, {} connected",
+                update.disconnected.len(),
+                update.connected.len()
+            );
+        }
+
+        let included: HashSet<[u8; 32]> = update
+            .connected
+            .iter()
+            .flat_map(|block| block.transactions().iter().map(|txn| txn.hash_id))
+            .collect();
+
+        for block in update.disconnected.iter() {
+            for txn in block.transactions() {
+                if included.contains(&txn.hash_id) {
+                    continue;
+                }
+
+                if let Err(e) = self.submit_transaction(txn.clone()).await {
+                    info!(
+                        "Dropped transaction {} of a disconnected block: {e}",
+                        hex::encode(txn.hash_id)
+                    );
+                }
+            }
+        }
+
+        let mut fee_estimator = self.fee_estimator.write().await;
+        for _ in update.disconnected.iter() {
+            fee_estimator.disconnect_block();
+        }
+        for block in update.connected.iter() {
+            let rates = self.mem_pool.remove_for_block(block.transactions()).await;
+            let size = block
+                .transactions()
+                .iter()
+                .map(|txn| txn.serialized_size() as u64)
+                .sum();
+            fee_estimator.record_block(size, rates);
+        }
+    }
+
+    // Fee rate likely to confirm a transaction within `target_blocks` blocks,
+    // from the recent blocks and the transactions waiting in the mempool
+    pub async fn estimate_fee_rate(&self, target_blocks: u64) -> FeeRate {
+        let pending = self.mem_pool.fee_rates().await;
+        self.fee_estimator
+            .read()
+            .await
+            .estimate_fee_rate(target_blocks, pending)
+    }
+
+    // Why the node is in safe mode, if it is
+    pub fn safe_mode(&self) -> Option<String> {
+        self.safe_mode
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn enter_safe_mode(&self, reason: String) {
+        *self.safe_mode.write().unwrap_or_else(|e| e.into_inner()) = Some(reason);
+    }
+
+    fn ensure_writable(&self) -> anyhow::Result<()> {
+        match self.safe_mode() {
+            Some(reason) => bail!("Node is in safe mode, storage is corrupt: {reason}"),
+            None => Ok(()),
+        }
+    }
+
+    // Appends newly connected blocks to the block store, then saves the
+    // download progress they make
+    async fn persist(&self, blocks: &[Block], checkpoint: &SyncCheckpoint) -> anyhow::Result<()> {
+        if let Some(storage) = self.storage.as_ref() {
+            storage.append_blocks(blocks).await?;
+            storage.save_checkpoint(checkpoint).await?;
+        }
+
+        Ok(())
+    }
+
+    // Saves the lifetime statistics every interval
+    pub async fn persist_stats(&self, interval: Duration) {
+        let Some(storage) = self.storage.as_ref().filter(|_| self.safe_mode().is_none()) else {
+            return;
+        };
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            if let Err(e) = storage.save_stats(&self.stats.snapshot()).await {
+                error!("Failed to persist the node statistics: {e}");
+            }
+        }
+    }
+
+    // Reads the stored chain back every interval and checks its blocks still
+    // hash to the hashes they're indexed by, to catch bit-rot or a torn write
+    // before the node restarts into it. A broken copy is rewritten from the
+    // chain in memory if that checks out, otherwise the node goes into safe
+    // mode rather than build on a corrupt chain
+    pub async fn scrub_storage(&self, interval: Duration) {
+        if self.storage.is_none() {
+            return;
+        }
+        let mut ticker = tokio::time::interval(interval);
+        // The chain was checked when it was loaded
+        ticker.tick().await;
+
+        while self.safe_mode().is_none() {
+            ticker.tick().await;
+            self.scrub().await;
+        }
+    }
+
+    async fn scrub(&self) {
+        let Some(storage) = self.storage.as_ref() else {
+            return;
+        };
+        let stored = match storage.load_chain(self.network, self.params).await {
+            Ok(Some(chain)) => check_in_background(Arc::new(chain)).await,
+            Ok(None) => return,
+            Err(e) => Err(e),
+        };
+        let Err(corruption) = stored else {
+            return;
+        };
+        warn!("Stored chain is corrupt: {corruption}");
+
+        let Some(chain) = self.chain_snapshot().await else {
+            return;
+        };
+        if let Err(e) = check_in_background(chain.clone()).await {
+            let reason = format!("stored chain is corrupt: {corruption}, and in memory: {e}");
+            error!("{reason}. Entering safe mode, restart with --reindex to rebuild the chain");
+            self.enter_safe_mode(reason);
+            return;
+        }
+
+        // Holding the read lock keeps a newer chain from being overwritten
+        // with this one. Blocks connected before still being appended are
+        // part of it, they're skipped when the chain is loaded
+        let blockchain = self.blockchain.read().await;
+        if !blockchain
+            .as_ref()
+            .is_some_and(|current| Arc::ptr_eq(current, &chain))
+        {
+            return;
+        }
+        match storage.rewrite_blocks(&chain).await {
+            Ok(()) => warn!("Rewrote the stored chain from memory"),
+            Err(e) => error!("Failed to rewrite the stored chain: {e}"),
+        }
+    }
+
+    // Mines on top of the best tip, paying the blocks to `address` with their
+    // coinbase tagged with `coinbase_tag`, until the node stops. Work on a
+    // template is dropped as soon as it goes stale, e.g. when a competing
+    // block arrives. Solved blocks go through the same checks as blocks from
+    // peers before any peer hears of them, see `submit_mined_block`
+    pub async fn mine(&self, miner: Miner, address: [u8; 32], coinbase_tag: &[u8]) {
+        let mut templates = self.subscribe_templates();
+
+        loop {
+            let template = match self.block_template(address, coinbase_tag).await {
+                Ok(template) => template,
+                Err(e) => {
+                    error!("Failed to build a block template: {e}");
+                    return;
+                }
+            };
+            let mut job = miner.start(template);
+
+            tokio::select! {
+                Some(block) = job.solved() => {
+                    info!("Mined block {}", hex::encode(block.hash()));
+                    if self.submit_mined_block(block).await.is_ok() {
+                        self.stats.record_mined();
+                    }
+                    // The update for our own block is no reason to start over
+                    templates.is_stale();
+                }
+                update = templates.changed() => {
+                    if update.is_none() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    // Connects and relays a block mined by this node. `process_block`
+    // announces the header before checking the transactions, which is fine
+    // for blocks from peers as the proof of work makes spamming costly, but a
+    // bug in building templates would have this node announce blocks every
+    // peer rejects. Mined blocks are validated in full first and refused
+    // with the failing check logged
+    pub async fn submit_mined_block(&self, block: Block) -> anyhow::Result<BlockOutcome> {
+        let hash = hex::encode(block.hash());
+
+        if let Err(e) = self.self_validate(&block).await {
+            error!("Refusing to relay mined block {hash}, it fails {e:#}");
+            self.relay.write().await.reject(block.hash());
+            return Err(e);
+        }
+
+        Ok(self.process_block(block).await.inspect_err(|e| {
+            warn!("Mined block {hash} was rejected: {e}");
+        })?)
+    }
+
+    // Unmined block on top of the best tip. A node without a chain starts it
+    // from the network's genesis block, which is never mined anew
+    async fn block_template(
+        &self,
+        address: [u8; 32],
+        coinbase_tag: &[u8],
+    ) -> anyhow::Result<Block> {
+        if self.blockchain.read().await.is_none() {
+            self.process_block(Block::genesis(self.network)).await?;
+        }
+        let blockchain = self.blockchain.read().await;
+        let Some(chain) = blockchain.as_ref() else {
+            bail!("No chain to build a block on");
+        };
+
+        let version = {
+            let mut states = self
+                .deployment_states
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            self.deployments
+                .block_version(chain, &chain.tip().hash(), &mut states)
+        };
+        Ok(self
+            .mem_pool
+            .block_template(chain, address, version, coinbase_tag)
+            .await?)
+    }
+
+    // Updates telling a miner to rebuild its block template, on every new tip
+    // and whenever enough fees entered the mempool
+    pub fn subscribe_templates(&self) -> TemplateWatch {
+        self.templates.subscribe()
+    }
+
+    // Counts the fees entering the mempool towards the next template update
+    pub async fn watch_templates(&self) {
+        self.templates.watch_mempool(self.mem_pool.clone()).await;
+    }
+
+    // Whether peer connections are sealed after the handshake
+    pub fn encrypted_transport(&self) -> bool {
+        self.encrypted_transport
+    }
+
+    // Oldest protocol version a peer may speak to complete a handshake
+    pub fn min_peer_version(&self) -> u16 {
+        self.min_peer_version
+    }
+
+    // Handshakes rejected for the peer's protocol versions, by the latest
+    // one it advertised
+    pub fn get_rejected_versions(&self) -> BTreeMap<u16, u64> {
+        self.stats.rejected_versions()
+    }
+
+    // Id peers know this node by, derived from its identity key
+    pub fn node_id(&self) -> String {
+        node_id(&self.identity.verifying_key().to_bytes())
+    }
+
+    // Connected peers with the extensions negotiated with each
+    pub async fn get_peer_info(&self) -> Vec<PeerInfo> {
+        self.peers.peers().await
+    }
+
+    // Lifetime statistics, including the runs before the last restart
+    pub fn get_node_stats(&self) -> NodeStats {
+        self.stats.snapshot()
+    }
+
+    // Queues the message for every peer, returns the number of peers it was
+    // queued for
+    async fn broadcast(&self, message: Message) -> anyhow::Result<usize> {
+        let size = borsh::object_length(&message)? as u64;
+        let sent = self.peers.broadcast(message).await?;
+        self.stats.record_relayed(size * sent as u64);
+
+        Ok(sent)
+    }
+
+    // Announces the items to the peers that don't have them yet, they ask
+    // for the ones they're missing with a `GetData`
+    async fn announce(&self, items: &[Inventory]) {
+        let sent = self.peers.announce(items).await;
+        self.record_announced(items, sent);
+    }
+
+    // Announces the transactions to the peers that don't have them yet,
+    // peers with a bloom filter only get the ones matching it
+    async fn announce_transactions(&self, txns: &[Transaction]) {
+        let sent = self.peers.announce_transactions(txns).await;
+        self.record_announced(&transaction_items(txns), sent);
+    }
+
+    fn record_announced(&self, items: &[Inventory], sent: usize) {
+        let size = borsh::object_length(&Message::Inv(items.to_vec())).unwrap_or_default() as u64;
+        self.stats.record_relayed(size * sent as u64);
+    }
+
+    // Adds the misbehavior to the score of the peer's address. Once that
+    // gets it banned it's disconnected and the ban is saved, returns true
+    // if the connection should be closed
+    async fn misbehaving(&self, address: SocketAddr, misbehavior: Misbehavior) -> bool {
+        let ip = address.ip();
+        warn!("{address} misbehaved: {misbehavior}");
+        if !self
+            .bans
+            .write()
+            .await
+            .record(ip, misbehavior, now_millis())
+        {
+            return false;
+        }
+        warn!("Banned {ip} for misbehaving");
+
+        for peer in self.peers.peers().await {
+            if peer.address.ip() == ip {
+                self.peers.remove_peer(&peer.address).await;
+            }
+        }
+        if let (Some(storage), None) = (self.storage.as_ref(), self.safe_mode()) {
+            let bans = self.list_banned().await;
+            if let Err(e) = storage.save_bans(&bans).await {
+                error!("Failed to save the banned peers: {e}");
+            }
+        }
+
+        true
+    }
+
+    async fn is_banned(&self, address: &SocketAddr) -> bool {
+        self.bans
+            .read()
+            .await
+            .is_banned(&address.ip(), now_millis())
+    }
+
+    // Bans in force, in the order they end
+    pub async fn list_banned(&self) -> Vec<Ban> {
+        self.bans.read().await.bans(now_millis())
+    }
+
+    // Misbehavior counted against the address since its last ban
+    pub async fn misbehavior_score(&self, address: &IpAddr) -> u32 {
+        self.bans.read().await.score(address)
+    }
+
+    // Whether a peer sent the item for the first time in a while, repeats
+    // are neither validated nor relayed again
+    async fn first_seen(&self, item: Inventory) -> bool {
+        self.seen.write().await.insert(item, Instant::now())
+    }
+
+    // Announced items this node doesn't have, leaving out rejected blocks
+    // and items peers sent lately
+    async fn missing_inventory(&self, items: &[Inventory]) -> Vec<Inventory> {
+        let mut missing = Vec::new();
+
+        for item in items.iter().take(MAX_INVENTORY) {
+            if self.seen.read().await.contains(item, Instant::now()) {
+                continue;
+            }
+            let known = match item {
+                Inventory::Transaction(txid) => self.get_raw_transaction(txid).await.is_some(),
+                Inventory::Block(hash) => {
+                    self.relay.read().await.is_invalid(hash)
+                        || self.get_block(hash).await.is_some()
+                        || self
+                            .pending_blocks
+                            .read()
+                            .await
+                            .iter()
+                            .any(|block| block.hash() == *hash)
+                }
+            };
+            if !known && !missing.contains(item) {
+                missing.push(*item);
+            }
+        }
+
+        missing
+    }
+
+    // Sends a peer the announced items it asked for, ones this node no
+    // longer has are left out. Peers with a bloom filter get blocks filtered
+    async fn send_data(&self, address: SocketAddr, items: &[Inventory]) {
+        let filter = self.peers.filter(&address).await;
+        let block_message = |block: Block| match filter.as_ref() {
+            Some(filter) => Message::FilteredBlock(FilteredBlock::of(&block, Some(filter))),
+            None => Message::BlockProposal(block),
+        };
+        for item in items.iter().take(MAX_INVENTORY) {
+            let message = match item {
+                Inventory::Transaction(txid) => self
+                    .get_raw_transaction(txid)
+                    .await
+                    .map(Message::PaymentTransaction),
+                Inventory::Block(hash) => self.get_block(hash).await.map(block_message),
+            };
+            let Some(message) = message else {
+                continue;
+            };
+
+            let size = borsh::object_length(&message).unwrap_or_default() as u64;
+            let sent = match Request::new(Command::Post, Some(message)) {
+                Ok(request) => self.peers.send(&address, request).await,
+                Err(e) => Err(e.into()),
+            };
+            match sent {
+                Ok(()) => self.stats.record_relayed(size),
+                Err(e) => warn!(
+                    "Failed to send {} to peer {address}: {e}",
+                    hex::encode(item.hash())
+                ),
+            }
+        }
+    }
+
+    // Asks a peer for the transactions of its mempool this node is missing
+    async fn request_transactions(&self, address: SocketAddr, items: &[Inventory]) {
+        let missing: Vec<Inventory> = self
+            .missing_inventory(items)
+            .await
+            .into_iter()
+            .filter(|item| matches!(item, Inventory::Transaction(_)))
+            .collect();
+        if missing.is_empty() {
+            return;
+        }
+
+        let sent = match Request::new(Command::Get, Some(Message::GetData(missing))) {
+            Ok(request) => self.peers.send(&address, request).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = sent {
+            warn!("Failed to request transactions from {address}: {e}");
+        }
+    }
+
+    // Adds the transactions of a peer's mempool to this node's. They may
+    // conflict with transactions this node has, which isn't held against the
+    // peer
+    async fn receive_transactions(&self, txns: &[Transaction]) {
+        let mut added = Vec::new();
+        for txn in txns {
+            if !self.first_seen(Inventory::Transaction(txn.hash_id)).await {
+                continue;
+            }
+            match self.submit_transaction(txn.clone()).await {
+                Ok(()) => added.push(txn.clone()),
+                Err(e) => warn!("Rejected transaction {}: {e}", hex::encode(txn.hash_id)),
+            }
+        }
+
+        if !added.is_empty() {
+            info!("Added {} transactions of a peer's mempool", added.len());
+            self.announce_transactions(&added).await;
+        }
+    }
+
+    // Asks a peer for the parent of an orphan block, unless the block
+    // download brings it anyway
+    async fn request_parent(&self, (height, hash): (u64, String)) {
+        let Ok(hash) = <[u8; 32]>::from_hex(&hash) else {
+            return;
+        };
+        let Some(parent_height) = height.checked_sub(1) else {
+            return;
+        };
+        if self.sync.read().await.expected_hash(parent_height) == Some(hash) {
+            return;
+        }
+
+        // Peers able to send it in chunks are preferred, the block may be too
+        // large for a single frame
+        let peers = self.peers.peers().await;
+        let Some(peer) = peers
+            .iter()
+            .find(|peer| peer.features.contains(Features::BLOCK_CHUNKS))
+            .or_else(|| {
+                peers
+                    .iter()
+                    .find(|peer| peer.features.contains(Features::BLOCKS_BY_HASH))
+            })
+        else {
+            return;
+        };
+
+        let sent = if peer.features.contains(Features::BLOCK_CHUNKS) {
+            self.download_chunked(peer.address, hash).await
+        } else {
+            match Request::new(Command::Get, Some(Message::BlockRequestByHash(hash))) {
+                Ok(request) => self.peers.send(&peer.address, request).await,
+                Err(e) => Err(e.into()),
+            }
+        };
+        if let Err(e) = sent {
+            warn!(
+                "Failed to request block {} from {}: {e}",
+                hex::encode(hash),
+                peer.address
+            );
+        }
+    }
+
+    // Starts downloading a block in chunks, unless it's downloaded already
+    async fn download_chunked(&self, address: SocketAddr, hash: [u8; 32]) -> anyhow::Result<()> {
+        {
+            let mut downloads = self.downloads.write().await;
+            if downloads.contains_key(&hash) || downloads.len() >= MAX_ORPHAN_BLOCKS {
+                return Ok(());
+            }
+            let download = Download {
+                peer: address,
+                progressed: Instant::now(),
+                chunks: ChunkedBlock::default(),
+            };
+            downloads.insert(hash, download);
+        }
+
+        let requested = self.request_chunk(address, hash, 0).await;
+        if requested.is_err() {
+            self.downloads.write().await.remove(&hash);
+        }
+        requested
+    }
+
+    // Gives up the chunked downloads whose peer disconnected or stopped
+    // sending chunks, every interval
+    pub async fn expire_downloads(&self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+            self.drop_stale_downloads(Instant::now()).await;
+        }
+    }
+
+    async fn drop_stale_downloads(&self, now: Instant) {
+        let connected: HashSet<SocketAddr> = self
+            .peers
+            .peers()
+            .await
+            .iter()
+            .map(|peer| peer.address)
+            .collect();
+
+        self.drop_downloads(|download| {
+            connected.contains(&download.peer)
+                && now.saturating_duration_since(download.progressed) < DOWNLOAD_TIMEOUT
+        })
+        .await;
+    }
+
+    // Drops the downloads not to be kept, they're requested again when
+    // another orphan needs them
+    async fn drop_downloads(&self, keep: impl Fn(&Download) -> bool) {
+        self.downloads.write().await.retain(|hash, download| {
+            let kept = keep(download);
+            if !kept {
+                info!(
+                    "Gave up downloading block {} from {}",
+                    hex::encode(hash),
+                    download.peer
+                );
+            }
+            kept
+        });
+    }
+
+    async fn request_chunk(
+        &self,
+        address: SocketAddr,
+        hash: [u8; 32],
+        offset: u32,
+    ) -> anyhow::Result<()> {
+        let request = Request::new(Command::Get, Some(Message::GetBlockChunk(hash, offset)))?;
+        self.peers.send(&address, request).await
+    }
+
+    // Adds a chunk of a block being downloaded and asks the peer for the next
+    // one, the block is processed once it's complete. Chunks of blocks that
+    // weren't requested are dropped
+    async fn receive_chunk(&self, address: SocketAddr, chunk: &BlockChunk) {
+        let block = {
+            let mut downloads = self.downloads.write().await;
+            let Some(download) = downloads
+                .get_mut(&chunk.hash)
+                .filter(|download| download.peer == address)
+            else {
+                return;
+            };
+            download.progressed = Instant::now();
+
+            match download.chunks.push(chunk) {
+                Ok(Some(block)) => {
+                    downloads.remove(&chunk.hash);
+                    block
+                }
+                Ok(None) => {
+                    let offset = download.chunks.received();
+                    drop(downloads);
+                    if let Err(e) = self.request_chunk(address, chunk.hash, offset).await {
+                        warn!(
+                            "Failed to request block {} from {address}: {e}",
+                            hex::encode(chunk.hash)
+                        );
+                        self.downloads.write().await.remove(&chunk.hash);
+                    }
+                    return;
+                }
+                Err(e) => {
+                    downloads.remove(&chunk.hash);
+                    drop(downloads);
+                    warn!(
+                        "Bad chunk of block {} from {address}: {e}",
+                        hex::encode(chunk.hash)
+                    );
+                    self.misbehaving(address, Misbehavior::MalformedMessage)
+                        .await;
+                    return;
+                }
+            }
+        };
+
+        self.receive_block(address, block).await;
+    }
+
+    // Processes a block a peer proposed, repeats of recently seen blocks are
+    // ignored
+    async fn receive_proposal(&self, block: &Block) -> Result<(), BlockError> {
+        if !self.first_seen(Inventory::Block(block.hash())).await {
+            return Ok(());
+        }
+
+        self.process_block(block.clone()).await.inspect_err(|e| {
+            warn!("Rejected block {}: {e}", hex::encode(block.hash()));
+        })?;
+        Ok(())
+    }
+
+    // Processes a block a peer sent, the peer is only blamed when the block
+    // is invalid
+    async fn receive_block(&self, address: SocketAddr, block: Block) {
+        let hash = hex::encode(block.hash());
+        match self.process_block(block).await {
+            Ok(_) => {}
+            Err(e @ BlockError::Invalid(_)) => {
+                warn!("Rejected block {hash}: {e}");
+                self.misbehaving(address, Misbehavior::InvalidBlock).await;
+            }
+            Err(e) => warn!("Couldn't process block {hash} from {address}: {e}"),
+        }
+    }
+
+    async fn buffer_orphan(&self, block: Block) {
+        let mut pending_blocks = self.pending_blocks.write().await;
+
+        if pending_blocks
+            .iter()
+            .any(|orphan| orphan.hash() == block.hash())
+        {
+            return;
+        }
+        if pending_blocks.len() >= MAX_ORPHAN_BLOCKS {
+            // Make room by dropping the oldest orphan
+            pending_blocks.remove(0);
+        }
+
+        info!(
+            "Buffered orphan block {} at height {}",
+            hex::encode(block.hash()),
+            block.index()
+        );
+        pending_blocks.push(block);
+
+        // Over the memory budget the oldest orphans go as well, the newest
+        // one is kept to let the download make progress
+        while pending_blocks.len() > 1
+            && orphans_memory_usage(&pending_blocks) > self.memory_budget.orphans
+        {
+            pending_blocks.remove(0);
+        }
+    }
+
+    // Mempool transactions are reported as pending, anything else is looked up
+    // in the chain
+    pub async fn get_tx_confirmations(&self, txid: &[u8; 32]) -> TxStatus {
+        if self.mem_pool.contains(txid).await {
+            return TxStatus::Pending;
+        }
+
+        match self.blockchain.read().await.as_ref() {
+            Some(chain) => chain.get_tx_confirmations(txid),
+            None => TxStatus::Unknown,
+        }
+    }
+
+    // Fee and package details of a mempool transaction, for deciding how to
+    // bump its fee
+    pub async fn get_mempool_entry(&self, txid: &[u8; 32]) -> Option<MemPoolEntry> {
+        self.mem_pool.entry(txid).await
+    }
+
+    // Unspent outputs of the public key on the chain, less those transactions
+    // waiting in the mempool already spend
+    pub async fn unspent_outputs(&self, chain: Option<&BlockChain>, owner: &[u8; 32]) -> Vec<UTXO> {
+        let utxos = chain
+            .into_iter()
+            .flat_map(|chain| chain.unspent(owner))
+            .cloned();
+        self.mem_pool.unspent(utxos).await
+    }
+
+    // Chain as of its current tip. Reads spanning the whole chain work on it
+    // without holding up block connects or seeing them halfway, a block
+    // connected meanwhile goes to a copy
+    pub async fn chain_snapshot(&self) -> Option<Arc<BlockChain>> {
+        self.blockchain.read().await.clone()
+    }
+
+    // Number of blocks in the best chain
+    pub async fn get_block_count(&self) -> u64 {
+        self.blockchain
+            .read()
+            .await
+            .as_deref()
+            .map_or(0, BlockChain::height)
+    }
+
+    // Block on any known branch
+    pub async fn get_block(&self, hash: &[u8; 32]) -> Option<Block> {
+        self.blockchain
+            .read()
+            .await
+            .as_ref()?
+            .block_by_hash(hash)
+            .cloned()
+    }
+
+    // Block at the given height of the best chain
+    pub async fn get_block_at(&self, height: u64) -> Option<Block> {
+        self.blockchain
+            .read()
+            .await
+            .as_ref()?
+            .block(height)
+            .cloned()
+    }
+
+    // Transaction waiting in the mempool or confirmed on the best chain
+    pub async fn get_raw_transaction(&self, txid: &[u8; 32]) -> Option<Transaction> {
+        if let Some(txn) = self.mem_pool.get(txid).await {
+            return Some(txn);
+        }
+
+        self.blockchain
+            .read()
+            .await
+            .as_ref()?
+            .transaction(txid)
+            .cloned()
+    }
+
+    // Adds a transaction to the mempool and relays it to the peers
+    pub async fn send_raw_transaction(&self, txn: Transaction) -> anyhow::Result<()> {
+        self.submit_transaction(txn.clone()).await?;
+
+        if let Some(local_relay) = self.local_relay.as_ref() {
+            local_relay.write().await.queue(txn);
+            return Ok(());
+        }
+
+        self.announce_transactions(&[txn]).await;
+        Ok(())
+    }
+
+    // Announces the transactions submitted to this node every delay, first
+    // to a few random peers and a delay later to every peer
+    pub async fn relay_local_transactions(&self) {
+        let Some(local_relay) = self.local_relay.as_ref() else {
+            return;
+        };
+        let config = local_relay.read().await.config();
+        let mut ticker = tokio::time::interval(config.delay);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            let (first, spread) = local_relay.write().await.next_batch();
+
+            if !first.is_empty() {
+                let sent = self
+                    .peers
+                    .announce_transactions_to_random(&first, config.fanout)
+                    .await;
+                self.record_announced(&transaction_items(&first), sent);
+            }
+            if !spread.is_empty() {
+                self.announce_transactions(&spread).await;
+            }
+        }
+    }
+
+    pub async fn get_mempool_info(&self) -> MemPoolInfo {
+        self.mem_pool.info().await
+    }
+
+    // Approximate memory taken by the mempool, UTXO set and orphan blocks
+    pub async fn get_memory_info(&self) -> MemoryInfo {
+        let utxos = self
+            .blockchain
+            .read()
+            .await
+            .as_deref()
+            .map_or(0, BlockChain::utxo_memory_usage);
+
+        MemoryInfo {
+            mempool: self.mem_pool.memory_usage().await,
+            utxos,
+            orphans: orphans_memory_usage(&self.pending_blocks.read().await),
+            budget: self.memory_budget,
+        }
+    }
+
+    // Logs the memory usage every interval, warning once the UTXO set
+    // outgrows its budget
+    pub async fn report_memory(&self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            let info = self.get_memory_info().await;
+            info!(
+                "Memory: mempool {} B, UTXO set {} B, orphan blocks {} B",
+                info.mempool, info.utxos, info.orphans
+            );
+            if info.utxos > info.budget.utxos {
+                warn!(
+                    "UTXO set takes {} B, more than its budget of {} B",
+                    info.utxos, info.budget.utxos
+                );
+            }
+        }
+    }
+
+    // Confirmed balance of a public key
+    pub async fn get_balance(&self, owner: &[u8; 32]) -> u64 {
+        self.blockchain
+            .read()
+            .await
+            .as_ref()
+            .map_or(0, |chain| chain.balance(owner))
+    }
+
+    // Confirmed outputs a public key can spend
+    pub async fn get_unspent(&self, owner: &[u8; 32]) -> Vec<UTXO> {
+        self.blockchain
+            .read()
+            .await
+            .as_ref()
+            .map_or(Vec::new(), |chain| chain.unspent(owner).cloned().collect())
+    }
+
+    // Recently removed mempool transactions and why they were removed
+    pub async fn get_mempool_removals(&self) -> Vec<Removal> {
+        self.mem_pool.removals().await
+    }
+
+    // Evicts the mempool transactions that waited for too long, every interval
+    pub async fn expire_transactions(&self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            let expired = self.mem_pool.evict_expired().await;
+            if !expired.is_empty() {
+                info!("Evicted {} expired transactions", expired.len());
+            }
+        }
+    }
+
+    async fn submit_transaction(&self, txn: Transaction) -> anyhow::Result<()> {
+        self.ensure_writable()?;
+
+        // Only transactions the next block can include enter the mempool
+        let next_height = self.get_block_count().await;
+        if !txn.is_final(next_height, now_millis()) {
+            bail!("Transaction is locked until {}", txn.locktime);
+        }
+        let Some(chain) = self.chain_snapshot().await else {
+            bail!("No chain to spend outputs of");
+        };
+        let spent = self
+            .mem_pool
+            .spent_outputs(&txn, chain.utxos(), next_height)
+            .await?;
+        let rules = self.next_block_rules().await;
+        self.validate_transaction(&txn, &spent, next_height, rules)?;
+        self.mem_pool.add(txn, chain.utxos(), next_height).await?;
+
+        let evicted = self.mem_pool.trim(self.memory_budget.mempool).await;
+        if !evicted.is_empty() {
+            info!(
+                "Evicted {} transactions to keep the mempool within its memory budget",
+                evicted.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    // Verifies the transaction spending the given outputs, as stored in the
+    // UTXO set, in the block at `height`. Returns the fee it pays
+    fn validate_transaction(
+        &self,
+        transaction: &Transaction,
+        spent: &[UTXO],
+        height: u64,
+        rules: Rules,
+    ) -> anyhow::Result<u64> {
+        let (_, _, fee) =
+            transaction.verify_spending(spent, &transaction.unlocking_scripts, height, rules)?;
+
+        Ok(fee)
+    }
+
+    // Rules the block is checked against and the outputs its transactions
+    // spend, both on its own branch. `None` while the block's parent isn't
+    // known, fails for blocks spending outputs that aren't there
+    async fn block_context(&self, block: &Block) -> Option<anyhow::Result<BlockContext>> {
+        self.context_for(self.blockchain.read().await.as_deref(), block)
+    }
+
+    // Same as `block_context` on the given chain. Nothing is unspent before
+    // the genesis block
+    fn context_for(
+        &self,
+        blockchain: Option<&BlockChain>,
+        block: &Block,
+    ) -> Option<anyhow::Result<BlockContext>> {
+        let rules = self.rules_for(blockchain, block)?;
+        let spent = match blockchain {
+            Some(chain) => chain.spent_outputs(block),
+            None => Ok(vec![Vec::new(); block.transactions().len()]),
+        };
+
+        Some(
+            spent
+                .map(|spent| BlockContext { rules, spent })
+                .map_err(Into::into),
+        )
+    }
+
+    // Rules the block is checked against, the ones deployed on its own
+    // branch. `None` while the block's parent isn't known, the block can't
+    // be checked yet. Nothing is deployed before the genesis block
+    fn rules_for(&self, blockchain: Option<&BlockChain>, block: &Block) -> Option<Rules> {
+        if !has_parent(blockchain, block) {
+            return None;
+        }
+        match blockchain {
+            Some(chain) => {
+                let parent = <[u8; 32]>::from_hex(block.previous_hash()).ok()?;
+                Some(self.rules_after(chain, &parent))
+            }
+            None => Some(Rules::NONE),
+        }
+    }
+
+    // Rules a block on top of the best chain is checked against
+    async fn next_block_rules(&self) -> Rules {
+        match self.blockchain.read().await.as_ref() {
+            Some(chain) => self.rules_after(chain, &chain.tip().hash()),
+            None => Rules::NONE,
+        }
+    }
+
+    fn rules_after(&self, chain: &BlockChain, parent: &[u8; 32]) -> Rules {
+        let mut states = self
+            .deployment_states
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        self.deployments.active_rules(chain, parent, &mut states)
+    }
+
+    // Deployment states for the next block and the version it signals with
+    pub async fn get_deployment_info(&self) -> (u32, Vec<DeploymentInfo>) {
+        let blockchain = self.blockchain.read().await;
+        let Some(chain) = blockchain.as_ref() else {
+            return (0, Vec::new());
+        };
+
+        let tip = chain.tip().hash();
+        let mut states = self
+            .deployment_states
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let deployments = self
+            .deployments
+            .iter()
+            .map(|deployment| DeploymentInfo {
+                rules: deployment.rules,
+                activation: deployment.activation,
+                state: self.deployments.state(deployment, chain, &tip, &mut states),
+                signaling: self
+                    .deployments
+                    .signaling_in_window(deployment, chain, &tip),
+            })
+            .collect();
+
+        (
+            self.deployments.block_version(chain, &tip, &mut states),
+            deployments,
+        )
+    }
+}
+
+// Checking is slow on a long chain, it runs off the async workers
+async fn check_in_background(chain: Arc<BlockChain>) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || chain.check_integrity()).await??;
+    Ok(())
+}
+
+// Milliseconds since the unix epoch
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis())
+}
+
+fn transaction_items(txns: &[Transaction]) -> Vec<Inventory> {
+    txns.iter()
+        .map(|txn| Inventory::Transaction(txn.hash_id))
+        .collect()
+}
+
+// Leading blocks, transactions or outputs whose encoding fits in a single
+// response
+fn fit_in_payload<'a, T>(items: impl Iterator<Item = &'a T>) -> Vec<T>
+where
+    T: borsh::BorshSerialize + Clone + 'a,
+{
+    // Leave room for the enum tag and vector length
+    let mut budget = MAX_PAYLOAD_SIZE - 16;
+
+    items
+        .take_while(|item| {
+            let size = borsh::object_length(*item).unwrap_or(usize::MAX);
+            budget = budget.saturating_sub(size);
+            budget > 0
+        })
+        .cloned()
+        .collect()
+}
+
+// Whether the block's parent is known, without a chain only a genesis block
+// can be connected
+fn has_parent(blockchain: Option<&BlockChain>, block: &Block) -> bool {
+    match blockchain {
+        Some(chain) => <[u8; 32]>::from_hex(block.previous_hash())
+            .is_ok_and(|previous_hash| chain.contains(&previous_hash)),
+        None => block.index() == 0,
+    }
+}
+
+// Adds the block to the chain, judging its timestamp against `now`. A chain
+// still shared with snapshots is copied first, which shares everything but
+// the parts the block changes with them. Without a chain only the network's
+// genesis block connects
+fn connect_block(
+    blockchain: &mut Option<Arc<BlockChain>>,
+    block: Block,
+    now: u128,
+    network: Network,
+    params: ChainParams,
+) -> corelib::errors::Result<ChainUpdate> {
+    match blockchain {
+        Some(chain) => Arc::make_mut(chain).add_block_at(block, now),
+        None => {
+            let update = ChainUpdate {
+                disconnected: Vec::new(),
+                connected: vec![block.clone()],
+            };
+            *blockchain = Some(Arc::new(
+                BlockChain::new(block, network)?.with_params(params),
+            ));
+            Ok(update)
+        }
+    }
+}
+
+// Answer to a proposed block, whether or not the peer is to blame for a
+// failure
+fn proposal_response(processed: Result<(), BlockError>) -> corelib::errors::Result<Response> {
+    match processed {
+        Ok(()) => Response::new(StatusCode::OK, None),
+        Err(_) => Response::new(StatusCode::Error, None),
+    }
+}
+
+// Approximate memory the buffered orphan blocks take
+fn orphans_memory_usage(orphans: &[Block]) -> usize {
+    orphans.iter().map(MemoryUsage::memory_usage).sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use corelib::script::{Script, SigHash};
+
+    fn next_block(index: u64, previous: Option<&Block>) -> Block {
+        let mut signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let txn = Transaction::new(&mut signing_key, [1u8; 32]).unwrap();
+        let previous_hash = hex::encode(previous.map_or([0u8; 32], Block::hash));
+
+        Block::new(index, vec![txn], previous_hash, 1).unwrap()
+    }
+
+    // Genesis block of the network test nodes run
+    fn genesis() -> Block {
+        Block::genesis(Network::Mainnet)
+    }
+
+    // Block on top of the genesis block paying its coinbase to the sender of
+    // `spendable_transaction`
+    fn funding_block() -> Block {
+        let owner = SigningKey::from_bytes(&[7u8; 32])
+            .verifying_key()
+            .to_bytes();
+        BlockBuilder::new(1, hex::encode(genesis().hash()), 1, owner)
+            .build()
+            .unwrap()
+    }
+
+    // Connects the funding block and the genesis block below it
+    async fn connect_funding(node: &Node, funding: &Block) {
+        node.process_block(genesis()).await.unwrap();
+        node.process_block(funding.clone()).await.unwrap();
+    }
+
+    // Output the block's coinbase pays, as the chain stores it
+    fn coinbase_output(block: &Block) -> UTXO {
+        let coinbase = &block.transactions()[0];
+        coinbase.outputs[0]
+            .clone()
+            .confirm_utxo(coinbase.hash_id, block.index() as u32, true)
+            .unwrap()
+    }
+
+    // Transaction the mempool accepts once the funding block is connected,
+    // spending the pay-to-pubkey-hash output it pays the sender
+    fn spendable_transaction(funding: &Block) -> Transaction {
+        let mut signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let owner = signing_key.verifying_key().to_bytes();
+        let mut txn = Transaction::new(&mut signing_key, [1u8; 32]).unwrap();
+        txn.add_inputs(vec![coinbase_output(funding)], &mut signing_key)
+            .unwrap();
+
+        let signature = txn.sign_input(0, SigHash::All, &signing_key).unwrap();
+        txn.set_unlocking_scripts(vec![format!(
+            "{} {}",
+            hex::encode(signature),
+            hex::encode(owner)
+        )]);
+        txn
+    }
+
+    // Node answering peers' responses, as `main` runs it
+    fn responsive_node(node: Node, mut responses: mpsc::UnboundedReceiver<PeerResponse>) -> Node {
+        let handler = node.clone();
+        tokio::spawn(async move {
+            while let Some((address, response)) = responses.recv().await {
+                handler.handle_response(address, response).await;
+            }
+        });
+        node
+    }
+
+    #[tokio::test]
+    async fn answers_ping_and_missing_blocks() {
+        let (node, _) = Node::new(0);
+
+        let ping = Request::new(Command::Ping, None).unwrap();
+        let response = node.handle_request(ping).await.unwrap();
+        assert_eq!(response.status(), &StatusCode::OK);
+        assert_eq!(response.payload(), &Some(Message::Ping));
+
+        let get = Request::new(Command::Get, Some(Message::BlockRequest(0))).unwrap();
+        let response = node.handle_request(get).await.unwrap();
+        assert_eq!(response.status(), &StatusCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn listens_on_the_bound_address() {
+        let (node, _) = Node::new(0);
+        let (node, _listener) = node.listen().await.unwrap();
+        assert_ne!(node.listen_address().port(), 0);
+        TcpStream::connect(node.listen_address()).await.unwrap();
+
+        // The port is taken, the error says why
+        let (taken, _) = Node::new(0);
+        let error = taken
+            .with_listen_address(node.listen_address())
+            .listen()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<corelib::errors::Error>(),
+            Some(corelib::errors::Error::Listen { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn closes_connections_of_other_networks() {
+        use corelib::net::codec::{read_response, write_request};
+
+        let (node, _) = Node::new(0);
+        let node = node.with_network(Network::Regtest);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            node.handle_connection(stream, peer).await
+        });
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let ping = Request::new(Command::Ping, None)
+            .unwrap()
+            .with_network(Network::Regtest);
+        write_request(&mut stream, &ping).await.unwrap();
+        let response = read_response(&mut stream, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.header().network(), Network::Regtest);
+
+        // A mainnet frame ends the connection unanswered
+        let ping = Request::new(Command::Ping, None).unwrap();
+        write_request(&mut stream, &ping).await.unwrap();
+        assert!(read_response(&mut stream, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .is_none());
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn bans_misbehaving_peers_across_restarts() {
+        use corelib::net::codec::{read_response, write_request};
+
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let config = BanConfig {
+            threshold: 20,
+            duration: Duration::from_secs(60),
+        };
+        let (node, _) = Node::new(0);
+        let node = node
+            .with_network(Network::Regtest)
+            .with_ban_config(config)
+            .with_storage(Storage::open(&dir).await.unwrap())
+            .await
+            .unwrap();
+        let (node, listener) = node.listen().await.unwrap();
+        let address = node.listen_address();
+        {
+            let node = node.clone();
+            tokio::spawn(async move { node.run(listener).await });
+        }
+
+        // Talking on another network is enough to reach the threshold
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let ping = Request::new(Command::Ping, None).unwrap();
+        write_request(&mut stream, &ping).await.unwrap();
+        assert!(read_response(&mut stream, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .is_none());
+        let bans = node.list_banned().await;
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].address, "127.0.0.1");
+
+        // Connections from the address are refused from now on
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let ping = ping.with_network(Network::Regtest);
+        let _ = write_request(&mut stream, &ping).await;
+        assert!(!matches!(
+            read_response(&mut stream, MAX_PAYLOAD_SIZE).await,
+            Ok(Some(_))
+        ));
+
+        let (restarted, _) = Node::new(0);
+        let restarted = restarted
+            .with_network(Network::Regtest)
+            .with_ban_config(config)
+            .with_storage(Storage::open(&dir).await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(restarted.list_banned().await, bans);
+        assert!(restarted.is_banned(&address).await);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn throttles_messages_and_limits_inbound_slots() {
+        use corelib::net::codec::{read_response, write_request};
+
+        let (node, _) = Node::new(0);
+        let node = node.with_limits(ConnectionLimits {
+            max_inbound: 1,
+            messages_per_sec: 1,
+            burst: 2,
+        });
+        let (node, listener) = node.listen().await.unwrap();
+        let address = node.listen_address();
+        {
+            let node = node.clone();
+            tokio::spawn(async move { node.run(listener).await });
+        }
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let ping = Request::new(Command::Ping, None).unwrap();
+        let mut statuses = Vec::new();
+        for _ in 0..3 {
+            write_request(&mut stream, &ping).await.unwrap();
+            let response = read_response(&mut stream, MAX_PAYLOAD_SIZE)
+                .await
+                .unwrap()
+                .unwrap();
+            statuses.push(*response.status());
+        }
+        assert_eq!(
+            statuses,
+            vec![StatusCode::OK, StatusCode::OK, StatusCode::RateLimited]
+        );
+        assert_eq!(
+            node.misbehavior_score(&address.ip()).await,
+            Misbehavior::Flooding.score()
+        );
+
+        // The only slot is taken, a second connection is closed right away
+        let mut second = TcpStream::connect(address).await.unwrap();
+        let _ = write_request(&mut second, &ping).await;
+        assert!(!matches!(
+            read_response(&mut second, MAX_PAYLOAD_SIZE).await,
+            Ok(Some(_))
+        ));
+
+        // Closing the first connection frees the slot
+        drop(stream);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut third = TcpStream::connect(address).await.unwrap();
+        write_request(&mut third, &ping).await.unwrap();
+        assert!(read_response(&mut third, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn reaps_connections_quiet_past_the_negotiated_timeout() {
+        use corelib::net::codec::{read_response, write_request};
+
+        let (node, _) = Node::new(0);
+        let node = node.with_idle_timeout(Duration::from_millis(100));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = {
+            let node = node.clone();
+            tokio::spawn(async move {
+                let (stream, peer) = listener.accept().await.unwrap();
+                node.handle_connection(stream, peer).await
+            })
+        };
+
+        // The peer asks for a longer timeout than the node's
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let version = Handshake::new(0)
+            .with_idle_timeout(Duration::from_millis(400))
+            .sign(&SigningKey::from_bytes(&[3u8; 32]));
+        let request = Request::new(Command::Post, Some(Message::Version(version))).unwrap();
+        write_request(&mut stream, &request).await.unwrap();
+        let response = read_response(&mut stream, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .unwrap();
+        let Some(Message::VerAck(theirs)) = response.payload() else {
+            panic!("expected a VerAck, got {:?}", response.payload());
+        };
+        assert_eq!(theirs.idle_timeout, 100);
+
+        let quiet = std::time::Instant::now();
+        assert!(read_response(&mut stream, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(quiet.elapsed() >= Duration::from_millis(400));
+        server.await.unwrap().unwrap();
+        assert_eq!(node.get_node_stats().connections_reaped, 1);
+    }
+
+    #[test]
+    fn caps_the_idle_timeout_peers_ask_for() {
+        let (node, _) = Node::new(0);
+        let node = node.with_idle_timeout(Duration::from_millis(100));
+
+        let longer = Handshake::new(0).with_idle_timeout(Duration::from_millis(400));
+        assert_eq!(
+            node.negotiate_idle_timeout(&longer),
+            Duration::from_millis(400)
+        );
+
+        // A peer that never wants to be reaped still is
+        let endless = Handshake::new(0).with_idle_timeout(Duration::MAX);
+        assert_eq!(
+            node.negotiate_idle_timeout(&endless),
+            Duration::from_millis(MAX_IDLE_TIMEOUT as u64)
+        );
+    }
+
+    #[tokio::test]
+    async fn relays_blocks_by_inventory() {
+        let (receiver, _) = Node::new(0);
+        let (receiver, listener) = receiver.listen().await.unwrap();
+        {
+            let receiver = receiver.clone();
+            tokio::spawn(async move { receiver.run(listener).await });
+        }
+
+        let (sender, mut responses) = Node::new(0);
+        sender
+            .peers
+            .connect(receiver.listen_address())
+            .await
+            .unwrap();
+        {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                while let Some((address, response)) = responses.recv().await {
+                    sender.handle_response(address, response).await;
+                }
+            });
+        }
+
+        // The block is announced, the receiver asks for it and gets it
+        let genesis = genesis();
+        sender.process_block(genesis.clone()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while receiver.get_block(&genesis.hash()).await.is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // Known and repeated items aren't asked for
+        let first = next_block(1, Some(&genesis));
+        let items = [
+            Inventory::Block(genesis.hash()),
+            Inventory::Block(first.hash()),
+            Inventory::Block(first.hash()),
+        ];
+        assert_eq!(
+            receiver.missing_inventory(&items).await,
+            vec![Inventory::Block(first.hash())]
+        );
+    }
+
+    #[tokio::test]
+    async fn fetches_the_mempool_of_new_peers() {
+        let (sender, _) = Node::new(0);
+        let (sender, listener) = sender.listen().await.unwrap();
+        {
+            let sender = sender.clone();
+            tokio::spawn(async move { sender.run(listener).await });
+        }
+        let funding = funding_block();
+        connect_funding(&sender, &funding).await;
+        let txn = spendable_transaction(&funding);
+        sender.submit_transaction(txn.clone()).await.unwrap();
+
+        let (receiver, responses) = Node::new(0);
+        let receiver = responsive_node(receiver, responses);
+        connect_funding(&receiver, &funding).await;
+
+        // The handshake is followed by the sender's mempool
+        receiver.introduce(sender.listen_address()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !receiver.mem_pool.contains(&txn.hash_id).await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let response = sender
+            .handle_request(Request::new(Command::Get, Some(Message::GetMempool)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            response.payload(),
+            &Some(Message::Inv(vec![Inventory::Transaction(txn.hash_id)]))
+        );
+    }
+
+    #[tokio::test]
+    async fn relays_only_headers_extending_the_chain() {
+        let (node, _) = Node::new(0);
+        let genesis = genesis();
+        node.process_block(genesis.clone()).await.unwrap();
+        let announce = |header: &BlockHeader| {
+            Request::new(
+                Command::Post,
+                Some(Message::HeaderAnnouncement(header.clone())),
+            )
+            .unwrap()
+        };
+
+        // Of a block whose parent isn't known, it can't be judged yet
+        let stranger = next_block(5, Some(&next_block(4, Some(&genesis))));
+        let response = node.handle_request(announce(stranger.header())).await;
+        assert_eq!(*response.unwrap().status(), StatusCode::OK);
+        assert!(node.relay.write().await.announce(stranger.hash()));
+
+        // Mined at a difficulty other than the chain's
+        let harder = Block::new(
+            1,
+            next_block(1, None).transactions().to_vec(),
+            hex::encode(genesis.hash()),
+            2,
+        )
+        .unwrap();
+        let response = node.handle_request(announce(harder.header())).await;
+        assert_eq!(*response.unwrap().status(), StatusCode::Error);
+        assert!(node.relay.write().await.announce(harder.hash()));
+
+        let child = next_block(1, Some(&genesis));
+        let response = node.handle_request(announce(child.header())).await;
+        assert_eq!(*response.unwrap().status(), StatusCode::OK);
+        assert!(!node.relay.write().await.announce(child.hash()));
+    }
+
+    #[tokio::test]
+    async fn returns_transactions_of_disconnected_blocks_to_the_mempool() {
+        let (node, _) = Node::new(0);
+        let funding = funding_block();
+        connect_funding(&node, &funding).await;
+        let txn = spendable_transaction(&funding);
+        node.submit_transaction(txn.clone()).await.unwrap();
+
+        let block = Block::new(2, vec![txn.clone()], hex::encode(funding.hash()), 1).unwrap();
+        let connected = ChainUpdate {
+            disconnected: Vec::new(),
+            connected: vec![block.clone()],
+        };
+        node.apply_to_mempool(&connected).await;
+        assert!(node.mem_pool.is_empty().await);
+
+        // Reorganized away, the transaction waits for the next block again
+        let disconnected = ChainUpdate {
+            disconnected: vec![block],
+            connected: Vec::new(),
+        };
+        node.apply_to_mempool(&disconnected).await;
+        assert!(node.mem_pool.contains(&txn.hash_id).await);
+    }
+
+    #[tokio::test]
+    async fn checks_the_unlocking_script_of_every_input() {
+        let (node, _) = Node::new(0);
+        let funding = funding_block();
+        connect_funding(&node, &funding).await;
+
+        // A pay-to-pubkey-hash input signed by someone else than its owner
+        let mut forged = spendable_transaction(&funding);
+        let thief = SigningKey::from_bytes(&[8u8; 32]);
+        let signature = forged.sign_input(0, SigHash::All, &thief).unwrap();
+        forged.set_unlocking_scripts(vec![format!(
+            "{} {}",
+            hex::encode(signature),
+            hex::encode(thief.verifying_key().to_bytes())
+        )]);
+        assert!(node.submit_transaction(forged).await.is_err());
+
+        // A 2-of-2 multisig output, confirmed by the next block, needs both
+        // cosigners
+        let mut owner = SigningKey::from_bytes(&[7u8; 32]);
+        let cosigners = [
+            SigningKey::from_bytes(&[10u8; 32]),
+            SigningKey::from_bytes(&[11u8; 32]),
+        ];
+        let public_keys = cosigners
+            .each_ref()
+            .map(|key| key.verifying_key().to_bytes());
+        let script = Script::multisig(2, &public_keys).unwrap();
+        let mut funding_txn = spendable_transaction(&funding);
+        funding_txn
+            .add_outputs(
+                vec![UTXO::new_multisig(5_000, 0, 2, &public_keys).unwrap()],
+                &mut owner,
+            )
+            .unwrap();
+        let signature = funding_txn.sign_input(0, SigHash::All, &owner).unwrap();
+        funding_txn.set_unlocking_scripts(vec![format!(
+            "{} {}",
+            hex::encode(signature),
+            hex::encode(owner.verifying_key().to_bytes())
+        )]);
+        let block = BlockBuilder::new(2, hex::encode(funding.hash()), 1, [1u8; 32])
+            .transaction(funding_txn.clone())
+            .build()
+            .unwrap();
+        node.process_block(block).await.unwrap();
+
+        let input = funding_txn.outputs[0]
+            .clone()
+            .confirm_utxo(funding_txn.hash_id, 2, false)
+            .unwrap();
+        let mut sender = SigningKey::from_bytes(&[9u8; 32]);
+        let mut txn = Transaction::new(&mut sender, [1u8; 32]).unwrap();
+        txn.add_inputs(vec![input], &mut sender).unwrap();
+
+        let signature = hex::encode(txn.sign_input(0, SigHash::All, &cosigners[0]).unwrap());
+        let mut half_signed = txn.clone();
+        half_signed.set_unlocking_scripts(vec![format!(
+            "{signature} {signature} {}",
+            hex::encode(script.to_string())
+        )]);
+        assert!(node.submit_transaction(half_signed).await.is_err());
+
+        let signatures = txn
+            .sign_multisig(0, SigHash::All, &script, &cosigners)
+            .unwrap();
+        txn.set_unlocking_scripts(vec![signatures.script_hash_unlocking_script().unwrap()]);
+        node.submit_transaction(txn.clone()).await.unwrap();
+        assert!(node.mem_pool.contains(&txn.hash_id).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_inputs_declaring_another_output_than_stored() {
+        let (node, _) = Node::new(0);
+        let funding = funding_block();
+        connect_funding(&node, &funding).await;
+        let stored = coinbase_output(&funding);
+
+        // Spends the funding output declared as worth more than it is, or as
+        // locked to the spender's key
+        let owner = SigningKey::from_bytes(&[7u8; 32]);
+        let thief = SigningKey::from_bytes(&[8u8; 32]);
+        let mut inflated = stored.clone();
+        if let UTXO::Confirmed { value, .. } = &mut inflated {
+            *value += 1_000_000;
+        }
+        let mut relocked = stored.clone();
+        if let UTXO::Confirmed { script_pubkey, .. } = &mut relocked {
+            *script_pubkey =
+                Script::pay_to_pubkey_hash(&thief.verifying_key().to_bytes()).to_string();
+        }
+        for (input, mut key) in [(inflated, owner), (relocked, thief)] {
+            let mut txn = Transaction::new(&mut key, [1u8; 32]).unwrap();
+            txn.add_inputs(vec![input], &mut key).unwrap();
+            let signature = txn.sign_input(0, SigHash::All, &key).unwrap();
+            txn.set_unlocking_scripts(vec![format!(
+                "{} {}",
+                hex::encode(signature),
+                hex::encode(key.verifying_key().to_bytes())
+            )]);
+            assert!(node.submit_transaction(txn).await.is_err());
+        }
+
+        // Outputs the chain doesn't have are unknown
+        let unknown = spendable_transaction(&funding_block());
+        assert!(node.submit_transaction(unknown).await.is_err());
+
+        node.submit_transaction(spendable_transaction(&funding))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn encrypts_connections_between_nodes_offering_it() {
+        let (sender, _) = Node::new(0);
+        let (sender, listener) = sender
+            .with_encrypted_transport(true)
+            .listen()
+            .await
+            .unwrap();
+        {
+            let sender = sender.clone();
+            tokio::spawn(async move { sender.run(listener).await });
+        }
+        let funding = funding_block();
+        connect_funding(&sender, &funding).await;
+        let txn = spendable_transaction(&funding);
+        sender.submit_transaction(txn.clone()).await.unwrap();
+
+        // The mempool arrives over the encrypted connection
+        let (receiver, responses) = Node::new(0);
+        let receiver = responsive_node(receiver.with_encrypted_transport(true), responses);
+        connect_funding(&receiver, &funding).await;
+        receiver.introduce(sender.listen_address()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !receiver.mem_pool.contains(&txn.hash_id).await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let peers = receiver.get_peer_info().await;
+        assert!(peers[0].encrypted);
+        assert_eq!(peers[0].node_id, Some(sender.node_id()));
+
+        // Nodes not offering it stay in the clear
+        let (plain, responses) = Node::new(0);
+        let plain = responsive_node(plain, responses);
+        connect_funding(&plain, &funding).await;
+        plain.introduce(sender.listen_address()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !plain.mem_pool.contains(&txn.hash_id).await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(!plain.get_peer_info().await[0].encrypted);
+    }
+
+    #[tokio::test]
+    async fn snapshots_stay_at_their_tip() {
+        let (node, _) = Node::new(0);
+        let genesis = genesis();
+        node.process_block(genesis.clone()).await.unwrap();
+
+        // A block connected while the snapshot is read goes to a copy
+        let snapshot = node.chain_snapshot().await.unwrap();
+        node.process_block(next_block(1, Some(&genesis)))
+            .await
+            .unwrap();
+        assert_eq!(snapshot.height(), 1);
+        assert_eq!(node.get_block_count().await, 2);
+
+        let snapshot = node.chain_snapshot().await.unwrap();
+        assert_eq!(snapshot.height(), 2);
+    }
+
+    #[tokio::test]
+    async fn answers_utxo_and_balance_queries() {
+        let (node, _) = Node::new(0);
+        let owner = [5u8; 32];
+        let paid = BlockBuilder::new(1, hex::encode(genesis().hash()), 1, owner)
+            .build()
+            .unwrap();
+        node.process_block(genesis()).await.unwrap();
+        node.process_block(paid.clone()).await.unwrap();
+        let query = |message: Message| Request::new(Command::Get, Some(message)).unwrap();
+
+        let response = node
+            .handle_request(query(Message::GetUtxos(owner)))
+            .await
+            .unwrap();
+        let Some(Message::Utxos(unspent)) = response.payload() else {
+            panic!("expected UTXOs, got {:?}", response.payload());
+        };
+        assert!(!unspent.is_empty());
+        assert_eq!(*unspent, node.get_unspent(&owner).await);
+
+        let response = node
+            .handle_request(query(Message::GetBalance(owner)))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.payload(),
+            &Some(Message::Balance(paid.transactions()[0].output_value()))
+        );
+
+        // Unknown keys have nothing
+        let response = node
+            .handle_request(query(Message::GetBalance([9u8; 32])))
+            .await
+            .unwrap();
+        assert_eq!(response.payload(), &Some(Message::Balance(0)));
+        let response = node
+            .handle_request(query(Message::GetUtxos([9u8; 32])))
+            .await
+            .unwrap();
+        assert_eq!(response.payload(), &Some(Message::Utxos(Vec::new())));
+    }
+
+    #[tokio::test]
+    async fn serves_blocks_of_any_branch_by_hash() {
+        let (node, _) = Node::new(0);
+        let genesis = genesis();
+        let first = next_block(1, Some(&genesis));
+        let mut signing_key = SigningKey::from_bytes(&[8u8; 32]);
+        let txn = Transaction::new(&mut signing_key, [2u8; 32]).unwrap();
+        let fork = Block::new(1, vec![txn], hex::encode(genesis.hash()), 1).unwrap();
+        for block in [genesis, first.clone(), fork.clone()] {
+            node.process_block(block).await.unwrap();
+        }
+
+        let get = Message::BlockRequestByHash(fork.hash());
+        let response = node
+            .handle_request(Request::new(Command::Get, Some(get)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            response.payload(),
+            &Some(Message::BlockResponse(fork.clone()))
+        );
+
+        let get = Message::GetBlocksByHash(vec![first.hash(), [9u8; 32], fork.hash()]);
+        let response = node
+            .handle_request(Request::new(Command::Get, Some(get)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            response.payload(),
+            &Some(Message::Blocks(vec![first, fork.clone()]))
+        );
+
+        let get = Message::BlockRequestByHash([9u8; 32]);
+        let response = node
+            .handle_request(Request::new(Command::Get, Some(get)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), &StatusCode::NotFound);
+
+        // Or in chunks, up to the end of its encoding
+        let get = Message::GetBlockChunk(fork.hash(), 0);
+        let response = node
+            .handle_request(Request::new(Command::Get, Some(get)).unwrap())
+            .await
+            .unwrap();
+        let Some(Message::BlockChunk(chunk)) = response.payload() else {
+            panic!("expected a chunk, got {response:?}");
+        };
+        let mut download = ChunkedBlock::default();
+        assert_eq!(download.push(chunk).unwrap(), Some(fork.clone()));
+
+        let get = Message::GetBlockChunk(fork.hash(), chunk.total);
+        let response = node
+            .handle_request(Request::new(Command::Get, Some(get)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), &StatusCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn gives_up_downloads_of_departed_peers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (close, closed) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = closed.await;
+            drop(stream);
+        });
+
+        let (node, _) = Node::new(0);
+        node.peers.connect(address).await.unwrap();
+        let hash = [5u8; 32];
+
+        // A peer without the block answers not found
+        node.download_chunked(address, hash).await.unwrap();
+        node.drop_stale_downloads(Instant::now()).await;
+        assert!(node.downloads.read().await.contains_key(&hash));
+        let not_found = Response::new(StatusCode::NotFound, None).unwrap();
+        node.handle_response(address, not_found).await;
+        assert!(node.downloads.read().await.is_empty());
+
+        // One leaving halfway through never sends the remaining chunks
+        node.download_chunked(address, hash).await.unwrap();
+        close.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while node.peers.is_connected(&address).await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        node.drop_stale_downloads(Instant::now()).await;
+        assert!(node.downloads.read().await.is_empty());
+
+        // Nor can requests go out to it
+        assert!(node.download_chunked(address, hash).await.is_err());
+        assert!(node.downloads.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_versions_and_drops_unknown_ones() {
+        let identity = SigningKey::from_bytes(&[4u8; 32]);
+        let (node, _) = Node::new(0);
+        let node = node.with_identity(identity.clone());
+        let version = |versions: Vec<u16>| {
+            let mut handshake = Handshake::new(5).with_nonce(11);
+            handshake.versions = versions;
+            Request::new(
+                Command::Post,
+                Some(Message::Version(
+                    handshake.sign(&SigningKey::from_bytes(&[3u8; 32])),
+                )),
+            )
+            .unwrap()
+        };
+
+        // Signed by the node for the nonce of the version it answers
+        let response = node
+            .handle_request(version(SupportedVersions::all()))
+            .await
+            .unwrap();
+        let Some(Message::VerAck(verack)) = response.payload() else {
+            panic!("expected a VerAck, got {:?}", response.payload());
+        };
+        assert!(verack.verify().is_ok());
+        assert_eq!(verack.challenge, 11);
+        assert_eq!(verack.node_id(), node.node_id());
+        assert_eq!(
+            *verack,
+            Handshake::new(0)
+                .with_timestamp(verack.timestamp)
+                .with_nonce(verack.nonce)
+                .answering(&Handshake::new(5).with_nonce(11))
+                .sign(&identity)
+        );
+
+        // Unsigned versions are refused
+        let unsigned = Request::new(Command::Post, Some(Message::Version(Handshake::new(5))));
+        assert!(matches!(
+            node.handle_request(unsigned.unwrap()).await,
+            Err(corelib::errors::Error::InvalidSignature)
+        ));
+
+        // Versions without a common one are told which are accepted
+        let response = node.handle_request(version(vec![99])).await.unwrap();
+        assert_eq!(*response.status(), StatusCode::Error);
+        assert!(matches!(response.payload(), Some(Message::Outdated(1, _))));
+        assert_eq!(node.get_rejected_versions(), BTreeMap::from([(99, 1)]));
+    }
+
+    #[tokio::test]
+    async fn refuses_replayed_versions() {
+        use corelib::net::codec::{read_response, write_request};
+
+        let (node, _) = Node::new(0);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut connections = Vec::new();
+            for _ in 0..2 {
+                let (stream, peer) = listener.accept().await.unwrap();
+                let node = node.clone();
+                connections.push(tokio::spawn(async move {
+                    node.handle_connection(stream, peer).await
+                }));
+            }
+            connections
+        });
+
+        let identity = SigningKey::from_bytes(&[3u8; 32]);
+        let version = Request::new(
+            Command::Post,
+            Some(Message::Version(
+                Handshake::new(0).with_nonce(11).sign(&identity),
+            )),
+        )
+        .unwrap();
+        async fn shake(stream: &mut TcpStream, version: &Request) -> Handshake {
+            write_request(stream, version).await.unwrap();
+            let response = read_response(stream, MAX_PAYLOAD_SIZE)
+                .await
+                .unwrap()
+                .unwrap();
+            let Some(Message::VerAck(verack)) = response.payload() else {
+                panic!("expected a VerAck, got {:?}", response.payload());
+            };
+            verack.clone()
+        }
+        let authenticate = |proof| Request::new(Command::Post, Some(Message::Authenticate(proof)));
+
+        // The owner of the key proves it for the nonce it was challenged with
+        let mut owner = TcpStream::connect(address).await.unwrap();
+        let verack = shake(&mut owner, &version).await;
+        let proof = verack.prove(&identity);
+        write_request(&mut owner, &authenticate(proof).unwrap())
+            .await
+            .unwrap();
+        let response = read_response(&mut owner, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*response.status(), StatusCode::OK);
+
+        // Replaying its version and proof on another connection fails, the
+        // challenge is a different one
+        let mut replayer = TcpStream::connect(address).await.unwrap();
+        let replayed = shake(&mut replayer, &version).await;
+        assert_ne!(replayed.nonce, verack.nonce);
+        write_request(&mut replayer, &authenticate(proof).unwrap())
+            .await
+            .unwrap();
+        assert!(read_response(&mut replayer, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .is_none());
+
+        drop(owner);
+        for connection in server.await.unwrap() {
+            connection.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn judges_block_times_by_the_peers_clocks() {
+        use corelib::config::MAX_FUTURE_BLOCK_TIME;
+
+        let (node, _) = Node::new(0);
+        let genesis = genesis();
+        node.process_block(genesis.clone()).await.unwrap();
+
+        // Connecting peers' clocks aren't trusted
+        let ahead = 30 * 60 * 1_000;
+        let handshake = |seed| {
+            Handshake::new(0)
+                .with_timestamp(now_millis() as u64 + ahead)
+                .sign(&SigningKey::from_bytes(&[seed; 32]))
+        };
+        for seed in 1..=5u8 {
+            let version = Request::new(Command::Post, Some(Message::Version(handshake(seed))));
+            node.handle_request(version.unwrap()).await.unwrap();
+        }
+        assert_eq!(node.time_offset().await, 0);
+
+        // Five peers in different networks agree the local clock is half an
+        // hour behind
+        for seed in 1..=5u8 {
+            let address = SocketAddr::from(([10, seed, 0, 1], 8333));
+            node.sample_clock(address, &handshake(seed)).await;
+        }
+        let offset = node.time_offset().await;
+        assert!(offset > ahead as i64 - 1_000 && offset <= ahead as i64);
+
+        // Too far ahead of the local clock, but not of the adjusted one
+        let mut block = next_block(1, Some(&genesis));
+        block.set_timestamp(now_millis() + MAX_FUTURE_BLOCK_TIME + ahead as u128 / 2);
+        block.mine_block();
+        node.process_block(block.clone()).await.unwrap();
+        assert_eq!(node.get_block_count().await, 2);
+    }
+
+    #[tokio::test]
+    async fn tells_outdated_peers_to_upgrade() {
+        use corelib::net::codec::{read_response, write_request};
+
+        let (node, _) = Node::new(0);
+        let node = node.with_min_peer_version(2);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = {
+            let node = node.clone();
+            tokio::spawn(async move {
+                let (stream, peer) = listener.accept().await.unwrap();
+                node.handle_connection(stream, peer).await
+            })
+        };
+        let version = |versions: Vec<u16>| {
+            let mut handshake = Handshake::new(0);
+            handshake.versions = versions;
+            let version = Message::Version(handshake.sign(&SigningKey::from_bytes(&[3u8; 32])));
+            Request::new(Command::Post, Some(version)).unwrap()
+        };
+
+        // Newer peers still connect
+        let response = node.handle_request(version(vec![1, 2])).await.unwrap();
+        assert!(matches!(response.payload(), Some(Message::VerAck(_))));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        write_request(&mut stream, &version(vec![1]).with_version(1))
+            .await
+            .unwrap();
+        let response = read_response(&mut stream, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .unwrap();
+        let Some(Message::Outdated(minimum, reason)) = response.payload() else {
+            panic!(
+                "expected a deprecation notice, got {:?}",
+                response.payload()
+            );
+        };
+        assert_eq!(*minimum, 2);
+        assert!(reason.contains("version 1"));
+        assert_eq!(response.header().version(), 1);
+
+        // The connection is closed after the notice
+        assert!(read_response(&mut stream, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .is_none());
+        server.await.unwrap().unwrap();
+        assert_eq!(node.get_node_stats().handshakes_rejected, 1);
+        assert_eq!(node.get_rejected_versions(), BTreeMap::from([(1, 1)]));
+    }
+
+    #[tokio::test]
+    async fn filters_what_light_clients_receive() {
+        use corelib::net::codec::{read_response, write_request};
+
+        let (node, _) = Node::new(0);
+        let funding = funding_block();
+        let paid = BlockBuilder::new(2, hex::encode(funding.hash()), 1, [5u8; 32])
+            .build()
+            .unwrap();
+        connect_funding(&node, &funding).await;
+        node.process_block(paid.clone()).await.unwrap();
+        let txn = spendable_transaction(&funding);
+        node.submit_transaction(txn.clone()).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        {
+            let node = node.clone();
+            tokio::spawn(async move {
+                let (stream, peer) = listener.accept().await.unwrap();
+                node.handle_connection(stream, peer).await
+            });
+        }
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        async fn ask(stream: &mut TcpStream, command: Command, message: Message) -> Response {
+            let request = Request::new(command, Some(message)).unwrap();
+            write_request(stream, &request).await.unwrap();
+            read_response(stream, MAX_PAYLOAD_SIZE)
+                .await
+                .unwrap()
+                .unwrap()
+        }
+
+        // Nothing in the pool matches the client's key
+        let mut filter = BloomFilter::new(2, 0.0001, 3);
+        filter.insert(&[5u8; 32]);
+        let response = ask(
+            &mut stream,
+            Command::Post,
+            Message::SetFilter(filter.clone()),
+        )
+        .await;
+        assert_eq!(*response.status(), StatusCode::OK);
+        let response = ask(&mut stream, Command::Get, Message::GetMempool).await;
+        assert_eq!(response.payload(), &None);
+
+        // The block paying it comes with the proof
+        let response = ask(
+            &mut stream,
+            Command::Get,
+            Message::GetFilteredBlock(paid.hash()),
+        )
+        .await;
+        let Some(Message::FilteredBlock(filtered)) = response.payload() else {
+            panic!("expected a filtered block, got {:?}", response.payload());
+        };
+        assert!(filtered.verify());
+        assert_eq!(filtered.header, *paid.header());
+        assert_eq!(filtered.transactions.len(), 1);
+
+        filter.insert(&txn.receiver);
+        ask(&mut stream, Command::Post, Message::SetFilter(filter)).await;
+        let response = ask(&mut stream, Command::Get, Message::GetMempool).await;
+        assert_eq!(
+            response.payload(),
+            &Some(Message::Inv(vec![Inventory::Transaction(txn.hash_id)]))
+        );
+
+        // Once cleared nothing is filtered
+        ask(&mut stream, Command::Post, Message::ClearFilter).await;
+        let response = ask(
+            &mut stream,
+            Command::Get,
+            Message::GetFilteredBlock([9u8; 32]),
+        )
+        .await;
+        assert_eq!(*response.status(), StatusCode::NotFound);
+        let response = ask(
+            &mut stream,
+            Command::Get,
+            Message::GetFilteredBlock(paid.hash()),
+        )
+        .await;
+        let Some(Message::FilteredBlock(filtered)) = response.payload() else {
+            panic!("expected a filtered block, got {:?}", response.payload());
+        };
+        assert_eq!(filtered.transactions.len(), paid.transactions().len());
+    }
+
+    #[tokio::test]
+    async fn relays_filtered_only_to_peers_proving_their_address() {
+        use corelib::net::codec::{read_response, write_request};
+
+        let peer_identity = SigningKey::from_bytes(&[12u8; 32]);
+        let (peer, _) = Node::new(0);
+        let (peer, peer_listener) = peer
+            .with_identity(peer_identity.clone())
+            .listen()
+            .await
+            .unwrap();
+        {
+            let peer = peer.clone();
+            tokio::spawn(async move { peer.run(peer_listener).await });
+        }
+        let (node, responses) = Node::new(0);
+        let (node, listener) = node.listen().await.unwrap();
+        let node = responsive_node(node, responses);
+        {
+            let node = node.clone();
+            tokio::spawn(async move { node.run(listener).await });
+        }
+        node.introduce(peer.listen_address()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while node.peers.node_id(&peer.listen_address()).await.is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // Connects as the owner of the key, claiming the peer's address
+        let connect = |identity: SigningKey| {
+            let (address, origin) = (node.listen_address(), peer.listen_address());
+            async move {
+                let mut stream = TcpStream::connect(address).await.unwrap();
+                let mut ask = async |message| {
+                    let request = Request::new(Command::Post, Some(message)).unwrap();
+                    write_request(&mut stream, &request).await.unwrap();
+                    read_response(&mut stream, MAX_PAYLOAD_SIZE)
+                        .await
+                        .unwrap()
+                        .unwrap()
+                };
+                let version = Handshake::new(0).with_nonce(11).sign(&identity);
+                let Some(Message::VerAck(verack)) =
+                    ask(Message::Version(version)).await.payload().clone()
+                else {
+                    panic!("expected a VerAck");
+                };
+                ask(Message::PeerIntroduction(origin.to_string())).await;
+                ask(Message::Authenticate(verack.prove(&identity))).await;
+                let nothing = BloomFilter::new(1, 0.0001, 0);
+                ask(Message::SetFilter(nothing)).await;
+            }
+        };
+
+        // Someone else can't silence relay to the peer
+        connect(SigningKey::from_bytes(&[13u8; 32])).await;
+        assert!(node.peers.filter(&peer.listen_address()).await.is_none());
+
+        connect(peer_identity).await;
+        assert!(node.peers.filter(&peer.listen_address()).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn introductions_return_known_peers() {
+        let (seed, _) = Node::new(0);
+        let introduce = |address: &str| {
+            Request::new(
+                Command::Post,
+                Some(Message::PeerIntroduction(address.to_string())),
+            )
+            .unwrap()
+        };
+
+        seed.handle_request(introduce("127.0.0.1:1")).await.unwrap();
+        seed.handle_request(introduce("127.0.0.1:1")).await.unwrap();
+        let response = seed.handle_request(introduce("127.0.0.1:2")).await.unwrap();
+
+        assert_eq!(
+            response.payload(),
+            &Some(Message::PeerList(vec!["127.0.0.1:1".to_string()]))
+        );
+        assert_eq!(seed.peers.known_addresses().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn connects_orphans_once_parent_arrives() {
+        let (node, _) = Node::new(0);
+
+        let genesis = genesis();
+        let first = next_block(1, Some(&genesis));
+        let second = next_block(2, Some(&first));
+
+        assert_eq!(
+            node.process_block(genesis.clone()).await.unwrap(),
+            BlockOutcome::Connected(0)
+        );
+        assert_eq!(
+            node.process_block(second).await.unwrap(),
+            BlockOutcome::Orphaned
+        );
+        assert_eq!(
+            node.process_block(first).await.unwrap(),
+            BlockOutcome::Connected(1)
+        );
+        assert_eq!(
+            node.process_block(genesis).await.unwrap(),
+            BlockOutcome::Known
+        );
+
+        let blockchain = node.blockchain.read().await;
+        assert_eq!(blockchain.as_ref().unwrap().height(), 3);
+        assert!(node.pending_blocks.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn validates_orphans_once_parent_arrives() {
+        let (node, _) = Node::new(0);
+
+        // Spending its input with someone else's signature
+        let funding = funding_block();
+        let mut forged = spendable_transaction(&funding);
+        let thief = SigningKey::from_bytes(&[8u8; 32]);
+        let signature = forged.sign_input(0, SigHash::All, &thief).unwrap();
+        forged.set_unlocking_scripts(vec![format!(
+            "{} {}",
+            hex::encode(signature),
+            hex::encode(thief.verifying_key().to_bytes())
+        )]);
+
+        let first = next_block(2, Some(&funding));
+        let coinbase = next_block(3, Some(&first)).transactions()[0].clone();
+        let second = Block::new(3, vec![coinbase, forged], hex::encode(first.hash()), 1).unwrap();
+
+        connect_funding(&node, &funding).await;
+        assert!(node.block_context(&second).await.is_none());
+        assert_eq!(
+            node.process_block(second.clone()).await.unwrap(),
+            BlockOutcome::Orphaned
+        );
+        assert_eq!(
+            node.process_block(first).await.unwrap(),
+            BlockOutcome::Connected(0)
+        );
+
+        assert_eq!(node.get_block_count().await, 3);
+        assert!(node.pending_blocks.read().await.is_empty());
+        assert!(node.relay.read().await.is_invalid(&second.hash()));
+    }
+
+    #[tokio::test]
+    async fn mines_on_top_of_the_best_tip() {
+        let (node, _) = Node::new(0);
+        let miner = node.clone();
+        let mining =
+            tokio::spawn(async move { miner.mine(Miner::new(2), [4u8; 32], b"test").await });
+
+        while node.get_block_count().await < 2 {
+            tokio::task::yield_now().await;
+        }
+        mining.abort();
+
+        let blockchain = node.blockchain.read().await;
+        let chain = blockchain.as_ref().unwrap();
+        assert_eq!(
+            chain.block(1).unwrap().previous_hash(),
+            hex::encode(chain.block(0).unwrap().hash())
+        );
+        assert!(node.get_node_stats().blocks_mined >= 2);
+        assert_eq!(
+            chain.block(1).unwrap().transactions()[0].coinbase_tag(),
+            Some(&b"test"[..])
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_oldest_orphans_over_memory_budget() {
+        let genesis = genesis();
+        let first = next_block(1, Some(&genesis));
+        let second = next_block(2, Some(&first));
+        let third = next_block(3, Some(&second));
+
+        let (node, _) = Node::new(0);
+        let node = node.with_memory_budget(MemoryBudget {
+            orphans: second.memory_usage() + third.memory_usage(),
+            ..MemoryBudget::default()
+        });
+
+        for orphan in [first, second.clone(), third.clone()] {
+            assert_eq!(
+                node.process_block(orphan).await.unwrap(),
+                BlockOutcome::Orphaned
+            );
+        }
+
+        assert_eq!(*node.pending_blocks.read().await, vec![second, third]);
+        let info = node.get_memory_info().await;
+        assert!(info.orphans <= info.budget.orphans);
+        assert_eq!(info.utxos, 0);
+    }
+
+    #[tokio::test]
+    async fn resumes_from_storage_after_restart() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+
+        let (node, _) = Node::new(0);
+        let node = node
+            .with_storage(Storage::open(&dir).await.unwrap())
+            .await
+            .unwrap();
+        let mut checkpoint = SyncCheckpoint::default();
+        checkpoint.next_window(4, 4);
+        *node.sync.write().await = SyncState::new(checkpoint);
+
+        let genesis = genesis();
+        let first = next_block(1, Some(&genesis));
+        node.process_block(genesis).await.unwrap();
+        node.process_block(first.clone()).await.unwrap();
+        // Saved every interval, see `persist_stats`
+        let storage = node.storage.as_ref().unwrap();
+        storage.save_stats(&node.stats.snapshot()).await.unwrap();
+
+        let (restarted, _) = Node::new(0);
+        let restarted = restarted
+            .with_storage(Storage::open(&dir).await.unwrap())
+            .await
+            .unwrap();
+
+        let blockchain = restarted.blockchain.read().await;
+        assert_eq!(blockchain.as_ref().unwrap().tip(), &first);
+
+        let sync = restarted.sync.read().await;
+        assert_eq!(sync.checkpoint().validated(), 2);
+        assert_eq!(
+            sync.checkpoint().missing_heights().collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(restarted.get_node_stats().blocks_validated, 2);
+        // Peers still know the node by the same id
+        assert_eq!(restarted.node_id(), node.node_id());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn starts_in_safe_mode_on_corrupt_storage() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+
+        let (node, _) = Node::new(0);
+        let node = node
+            .with_storage(Storage::open(&dir).await.unwrap())
+            .await
+            .unwrap();
+        let genesis = genesis();
+        node.process_block(genesis.clone()).await.unwrap();
+        assert!(node.safe_mode().is_none());
+
+        let path = dir.join("blocks.dat");
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+
+        let (restarted, _) = Node::new(0);
+        let storage = Storage::open(&dir).await.unwrap();
+        let restarted = restarted.with_storage(storage.clone()).await.unwrap();
+        assert!(restarted.safe_mode().unwrap().contains("checksum"));
+        assert_eq!(restarted.get_block_count().await, 0);
+        assert!(restarted.process_block(genesis.clone()).await.is_err());
+
+        storage.reindex().await.unwrap();
+        let (reindexed, _) = Node::new(0);
+        let reindexed = reindexed.with_storage(storage).await.unwrap();
+        assert!(reindexed.safe_mode().is_none());
+        reindexed.process_block(genesis).await.unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn scrubbing_rewrites_a_rotten_stored_chain() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+
+        let (node, _) = Node::new(0);
+        let storage = Storage::open(&dir).await.unwrap();
+        let node = node.with_storage(storage.clone()).await.unwrap();
+        node.process_block(genesis()).await.unwrap();
+
+        let path = dir.join("blocks.dat");
+        let mut bytes = std::fs::read(&path).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(storage.load_chain(node.network, node.params).await.is_err());
+
+        // The chain in memory is intact, so it replaces the stored one
+        node.scrub().await;
+        assert!(node.safe_mode().is_none());
+        let stored = storage
+            .load_chain(node.network, node.params)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.height(), 1);
+        stored.check_integrity().unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn syncs_headers_then_blocks() {
+        let (source, _) = Node::new(0);
+        let genesis = genesis();
+        let first = next_block(1, Some(&genesis));
+        let second = next_block(2, Some(&first));
+        for block in [genesis, first, second.clone()] {
+            source.process_block(block).await.unwrap();
+        }
+
+        let get_headers = Request::new(Command::Get, Some(Message::GetHeaders(0))).unwrap();
+        let headers = source.handle_request(get_headers).await.unwrap();
+        let get_blocks = Request::new(Command::Get, Some(Message::GetBlocks(0, 3))).unwrap();
+        let blocks = source.handle_request(get_blocks).await.unwrap();
+
+        let (syncing, _) = Node::new(0);
+        let pipeline = syncing.clone();
+        tokio::spawn(async move { pipeline.run_sync_pipeline().await });
+
+        // The stages run on their own tasks
+        let wait_for = |done: fn(&SyncState) -> bool| {
+            let syncing = syncing.clone();
+            async move {
+                for _ in 0..100 {
+                    if done(&*syncing.sync.read().await) {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                panic!("sync stalled");
+            }
+        };
+
+        let address = "127.0.0.1:1".parse().unwrap();
+        syncing.handle_response(address, headers).await;
+        wait_for(|sync| sync.target_height() == 3).await;
+
+        syncing.handle_response(address, blocks).await;
+        wait_for(SyncState::is_synced).await;
+        let blockchain = syncing.blockchain.read().await;
+        assert_eq!(blockchain.as_ref().unwrap().tip(), &second);
+    }
+
+    #[tokio::test]
+    async fn answers_requests_reaching_past_the_tip() {
+        let (node, _) = Node::new(0);
+        let genesis = genesis();
+        let first = next_block(1, Some(&genesis));
+        for block in [genesis, first.clone()] {
+            node.process_block(block).await.unwrap();
+        }
+
+        // Ranges running past the last height end at the tip
+        let get = Message::GetHeaders(u64::MAX);
+        let response = node
+            .handle_request(Request::new(Command::Get, Some(get)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.payload(), &Some(Message::Headers(Vec::new())));
+
+        let get = Message::GetBlocks(1, u64::MAX);
+        let response = node
+            .handle_request(Request::new(Command::Get, Some(get)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.payload(), &Some(Message::Blocks(vec![first])));
+    }
+
+    // Blocks following `previous`, `length` of them. Without a previous block
+    // they start with the genesis block of `syncing_node`'s network
+    fn chain_of(length: u64, previous: Option<&Block>) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        let start = previous.map_or(0, |block| block.index() + 1);
+        for index in start..start + length {
+            let block = match blocks.last().or(previous) {
+                Some(parent) => next_block(index, Some(parent)),
+                None => Block::genesis(Network::Regtest),
+            };
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    // Polls until `done` holds, the pipeline stages run on their own tasks
+    async fn eventually<F, Fut>(done: F)
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = bool>,
+    {
+        for _ in 0..500 {
+            if done().await {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("sync stalled");
+    }
+
+    // Node running the sync pipeline, with the headers of `blocks` linked up.
+    // Regtest keeps the difficulty of the test blocks past a retarget
+    async fn syncing_node(blocks: &[Block]) -> Node {
+        let (node, _) = Node::new(0);
+        let node = node.with_network(Network::Regtest);
+        let pipeline = node.clone();
+        tokio::spawn(async move { pipeline.run_sync_pipeline().await });
+
+        let headers = blocks.iter().map(|block| block.header().clone()).collect();
+        let response = Response::new(StatusCode::OK, Some(Message::Headers(headers))).unwrap();
+        node.handle_response("127.0.0.1:1".parse().unwrap(), response)
+            .await;
+
+        let node_ref = &node;
+        let height = blocks.len() as u64;
+        eventually(|| async move { node_ref.sync.read().await.target_height() == height }).await;
+        node
+    }
+
+    #[tokio::test]
+    async fn connects_piped_blocks_in_download_order() {
+        use crate::pipeline::{CHECK_QUEUE, CONNECT_QUEUE};
+
+        // More blocks than the check and connect queues hold, their checks
+        // finish in any order on the blocking workers
+        let blocks = chain_of((CHECK_QUEUE + CONNECT_QUEUE) as u64 + 20, None);
+        let node = syncing_node(&blocks).await;
+
+        // Any block connected ahead of its parent would wait as an orphan
+        let watcher = node.clone();
+        let count = blocks.len() as u64;
+        let orphaned = tokio::spawn(async move {
+            let mut orphaned = false;
+            while watcher.get_block_count().await < count {
+                orphaned |= !watcher.pending_blocks.read().await.is_empty();
+                tokio::task::yield_now().await;
+            }
+            orphaned
+        });
+
+        let response =
+            Response::new(StatusCode::OK, Some(Message::Blocks(blocks.clone()))).unwrap();
+        node.handle_response("127.0.0.1:1".parse().unwrap(), response)
+            .await;
+
+        assert!(!orphaned.await.unwrap());
+        let blockchain = node.blockchain.read().await;
+        assert_eq!(blockchain.as_ref().unwrap().tip(), blocks.last().unwrap());
+    }
+
+    #[tokio::test]
+    async fn piped_invalid_blocks_stop_only_their_descendants() {
+        let mut blocks = chain_of(41, None);
+
+        // The coinbase claims a fee no transaction pays
+        let coinbase = Transaction::coinbase([1u8; 32], 41, 1).unwrap();
+        let previous_hash = hex::encode(blocks.last().unwrap().hash());
+        let invalid = Block::new(41, vec![coinbase], previous_hash, 1).unwrap();
+        let descendant = next_block(42, Some(&invalid));
+        blocks.extend([invalid.clone(), descendant.clone()]);
+        let node = syncing_node(&blocks).await;
+
+        // The later half, invalid block included, arrives first. The earlier
+        // half still goes through after it
+        let address = "127.0.0.1:1".parse().unwrap();
+        for half in [&blocks[20..], &blocks[..20]] {
+            let response =
+                Response::new(StatusCode::OK, Some(Message::Blocks(half.to_vec()))).unwrap();
+            node.handle_response(address, response).await;
+        }
+
+        let node_ref = &node;
+        eventually(|| async move { node_ref.get_block_count().await == 41 }).await;
+        assert!(node.relay.read().await.is_invalid(&invalid.hash()));
+
+        let blockchain = node.blockchain.read().await;
+        let chain = blockchain.as_ref().unwrap();
+        assert_eq!(chain.tip(), &blocks[40]);
+        assert!(!chain.contains(&invalid.hash()));
+        assert!(!chain.contains(&descendant.hash()));
+    }
+
+    #[tokio::test]
+    async fn refuses_to_relay_invalid_mined_blocks() {
+        let (node, _) = Node::new(0);
+        let genesis = genesis();
+        node.process_block(genesis.clone()).await.unwrap();
+
+        // The coinbase claims a fee no transaction pays
+        let coinbase = Transaction::coinbase([1u8; 32], 0, 1).unwrap();
+        let overpaid = Block::new(1, vec![coinbase], hex::encode(genesis.hash()), 1).unwrap();
+        let error = node.submit_mined_block(overpaid.clone()).await.unwrap_err();
+        assert!(format!("{error:#}").starts_with("block under rules"));
+        assert!(node.relay.read().await.is_invalid(&overpaid.hash()));
+
+        // Valid on its own but not where it goes in the chain
+        let txn = Transaction::new(&mut SigningKey::from_bytes(&[7u8; 32]), [1u8; 32]).unwrap();
+        let too_hard = Block::new(1, vec![txn], hex::encode(genesis.hash()), 2).unwrap();
+        let error = node.submit_mined_block(too_hard).await.unwrap_err();
+        assert!(format!("{error:#}").contains("position in the chain: "));
+        assert_eq!(node.get_block_count().await, 1);
+
+        let block = next_block(1, Some(&genesis));
+        assert!(matches!(
+            node.submit_mined_block(block).await.unwrap(),
+            BlockOutcome::Connected(0)
+        ));
+        assert_eq!(node.get_block_count().await, 2);
+    }
+
+    #[tokio::test]
+    async fn starts_the_chain_from_the_networks_genesis_block() {
+        let (node, _) = Node::new(0);
+
+        // A genesis block of its own or another network's
+        for other in [next_block(0, None), Block::genesis(Network::Testnet)] {
+            assert!(matches!(
+                node.process_block(other).await,
+                Err(BlockError::Invalid(_))
+            ));
+        }
+        assert_eq!(node.get_block_count().await, 0);
+
+        // Miners build on the network's genesis block instead of mining one
+        let template = node.block_template([1u8; 32], &[]).await.unwrap();
+        assert_eq!(template.index(), 1);
+        assert_eq!(
+            template.previous_hash(),
+            hex::encode(Network::Mainnet.genesis_hash())
+        );
+        assert_eq!(node.get_block_count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn rejected_blocks_are_not_processed_again() {
+        let (node, _) = Node::new(0);
+
+        // The coinbase claims a fee no transaction pays
+        let coinbase = Transaction::coinbase([1u8; 32], 0, 1).unwrap();
+        let block = Block::new(0, vec![coinbase], hex::encode([0u8; 32]), 1).unwrap();
+
+        assert!(node.process_block(block.clone()).await.is_err());
+        assert!(node.relay.read().await.is_invalid(&block.hash()));
+
+        // The header was announced once and is never announced again
+        assert!(!node.relay.write().await.announce(block.hash()));
+        assert!(!node.relay.write().await.relay(block.hash()));
+
+        let error = node.process_block(block).await.unwrap_err();
+        assert!(error.to_string().contains("rejected before"));
+    }
+
+    #[tokio::test]
+    async fn checks_the_header_before_the_transactions() {
+        let (node, _) = Node::new(0);
+        let genesis = genesis();
+        node.process_block(genesis.clone()).await.unwrap();
+
+        // Its coinbase claims a fee no transaction pays as well
+        let coinbase = Transaction::coinbase([1u8; 32], 1, 1).unwrap();
+        let block = Block::new(1, vec![coinbase], hex::encode(genesis.hash()), 2).unwrap();
+
+        let error = node.process_block(block.clone()).await.unwrap_err();
+        assert!(matches!(error, BlockError::Invalid(_)));
+        assert!(error.to_string().contains("expected difficulty"));
+    }
+
+    #[tokio::test]
+    async fn tampered_transactions_dont_reject_the_block() {
+        let (node, _) = Node::new(0);
+        let genesis = genesis();
+        node.process_block(genesis.clone()).await.unwrap();
+
+        // A relayer swaps the coinbase's signature, leaving its id and the
+        // block's hash alone
+        let block = next_block(1, Some(&genesis));
+        let (header, mut transactions) = block.clone().into_parts();
+        transactions[0].signature[0] ^= 1;
+        let tampered: Block =
+            borsh::from_slice(&borsh::to_vec(&(header, transactions)).unwrap()).unwrap();
+        assert_eq!(tampered.hash(), block.hash());
+
+        assert!(matches!(
+            node.process_block(tampered).await,
+            Err(BlockError::Invalid(_))
+        ));
+        assert!(!node.relay.read().await.is_invalid(&block.hash()));
+
+        assert!(matches!(
+            node.process_block(block).await.unwrap(),
+            BlockOutcome::Connected(0)
+        ));
+    }
+
+    #[tokio::test]
+    async fn only_blames_peers_for_invalid_blocks() {
+        use corelib::config::MAX_FUTURE_BLOCK_TIME;
+
+        let peer: SocketAddr = "10.0.0.1:8333".parse().unwrap();
+        let send = |block: Block| {
+            Response::new(StatusCode::OK, Some(Message::BlockResponse(block))).unwrap()
+        };
+        let (node, _) = Node::new(0);
+        let genesis = genesis();
+        node.process_block(genesis.clone()).await.unwrap();
+
+        // Ahead of this node's clock, which may just be behind
+        let mut ahead = next_block(1, Some(&genesis));
+        ahead.set_timestamp(now_millis() + MAX_FUTURE_BLOCK_TIME + 60_000);
+        ahead.mine_block();
+        node.handle_response(peer, send(ahead.clone())).await;
+        assert_eq!(node.misbehavior_score(&peer.ip()).await, 0);
+        assert!(!node.relay.read().await.is_invalid(&ahead.hash()));
+
+        // A node in safe mode can't take any block
+        node.enter_safe_mode("test".to_string());
+        let block = next_block(1, Some(&genesis));
+        node.handle_response(peer, send(block.clone())).await;
+        assert_eq!(node.misbehavior_score(&peer.ip()).await, 0);
+        assert!(node.list_banned().await.is_empty());
+        assert!(!node.relay.read().await.is_invalid(&block.hash()));
+
+        // The coinbase claims a fee no transaction pays
+        let (node, _) = Node::new(0);
+        let coinbase = Transaction::coinbase([1u8; 32], 0, 1).unwrap();
+        let invalid = Block::new(0, vec![coinbase], hex::encode([0u8; 32]), 1).unwrap();
+        node.handle_response(peer, send(invalid)).await;
+        assert!(node.is_banned(&peer).await);
+    }
 }
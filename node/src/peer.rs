@@ -0,0 +1,727 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use corelib::{
+    config::Network,
+    net::{
+        bloom::BloomFilter,
+        codec::MAX_PAYLOAD_SIZE,
+        features::Features,
+        message::{Inventory, Message},
+        protocol::{
+            Command, Handshake, Request, Response, SupportedVersions, DEFAULT_IDLE_TIMEOUT,
+        },
+        transport::{EphemeralKey, Transport},
+    },
+    rng::Entropy,
+    transaction::Transaction,
+};
+use rand::seq::IteratorRandom;
+use tokio::{
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::{mpsc, oneshot, watch, RwLock},
+    task::JoinHandle,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    dial::{DialConfig, DialQueue},
+    relay::RecentHashes,
+    stats::StatCounters,
+};
+
+// Response received from a peer along with the address it came from
+pub type PeerResponse = (SocketAddr, Response);
+
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub address: SocketAddr,
+    // Id the peer proved in the handshake, none until it completed
+    pub node_id: Option<String>,
+    // Protocol version of the last message received from the peer
+    pub version: u16,
+    // Extensions both sides support, none until the handshake completed
+    pub features: Features,
+    // Chain height the peer advertised in the handshake
+    pub height: u64,
+    pub last_seen: Instant,
+    // Time without a message after which the connection is closed, this
+    // node's own until the handshake negotiated one
+    pub idle_timeout: Duration,
+    // Whether the handshake set up an encrypted session
+    pub encrypted: bool,
+    // Whether the peer set a bloom filter, only transactions matching it are
+    // announced to it
+    pub filtered: bool,
+}
+
+#[derive(Debug)]
+struct Peer {
+    info: PeerInfo,
+    outgoing: mpsc::UnboundedSender<Request>,
+    // Tells the read and write tasks about the negotiated idle timeout
+    idle_timeout: watch::Sender<Duration>,
+    // Tells the write task the negotiated protocol version
+    version: watch::Sender<u16>,
+    // Sent in this node's `Version`, the peer signs it in its answer
+    nonce: u64,
+    // Public half of the ephemeral key offered in this node's `Version`,
+    // none if it doesn't ask for encryption
+    ephemeral: Option<[u8; 32]>,
+    // Hashes of the transactions and blocks the peer has, from its own
+    // announcements and ours. They're not announced to it again
+    known: RecentHashes,
+    filter: Option<BloomFilter>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.writer.abort();
+    }
+}
+
+// Keeps track of the outbound connections to other nodes.
+//
+// Every connection gets a read task forwarding the peer's responses to the
+// channel returned by [`PeerManager::new`] and a write task draining the
+// requests queued for the peer. The write task pings a peer it has nothing
+// else to send to, a peer that doesn't answer within the idle timeout is
+// taken for a half-open connection and dropped to free its slot.
+#[derive(Debug, Clone)]
+pub struct PeerManager {
+    peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
+    // Every address learned about, connected or not
+    known: Arc<RwLock<HashSet<SocketAddr>>>,
+    max_peers: usize,
+    // Requests are sent on it, peers answering on another one are dropped
+    network: Network,
+    idle_timeout: Duration,
+    // Whether encrypted sessions are offered in the handshake
+    encrypted_transport: bool,
+    // Draws the handshake nonces, ephemeral keys and random peers
+    entropy: Entropy,
+    dialer: DialQueue,
+    // Counts the connections dropped for idling
+    stats: Arc<StatCounters>,
+    responses: mpsc::UnboundedSender<PeerResponse>,
+}
+
+impl PeerManager {
+    pub fn new(max_peers: usize) -> (Self, mpsc::UnboundedReceiver<PeerResponse>) {
+        let (responses, receiver) = mpsc::unbounded_channel();
+
+        let manager = Self {
+            peers: Arc::new(RwLock::new(HashMap::new())),
+            known: Arc::new(RwLock::new(HashSet::new())),
+            max_peers,
+            network: Network::Mainnet,
+            idle_timeout: Duration::from_millis(DEFAULT_IDLE_TIMEOUT as u64),
+            encrypted_transport: false,
+            entropy: Entropy::os(),
+            dialer: DialQueue::default(),
+            stats: Arc::new(StatCounters::default()),
+            responses,
+        };
+
+        (manager, receiver)
+    }
+
+    // Talks to peers of the network instead of mainnet
+    pub fn with_network(mut self, network: Network) -> Self {
+        self.network = network;
+        self
+    }
+
+    // Idle timeout this node asks for, peers may negotiate a longer one
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    // Offers peers to encrypt the connections to them
+    pub fn with_encrypted_transport(mut self, encrypted_transport: bool) -> Self {
+        self.encrypted_transport = encrypted_transport;
+        self
+    }
+
+    pub fn with_entropy(mut self, entropy: Entropy) -> Self {
+        self.entropy = entropy;
+        self
+    }
+
+    pub fn with_dial_config(mut self, config: DialConfig) -> Self {
+        self.dialer = DialQueue::new(config);
+        self
+    }
+
+    pub fn with_stats(mut self, stats: Arc<StatCounters>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub async fn connect(&self, address: SocketAddr) -> anyhow::Result<()> {
+        if self.is_connected(&address).await {
+            bail!("Already connected to peer {address}");
+        }
+        if self.is_full().await {
+            bail!("Peer limit of {} reached", self.max_peers);
+        }
+
+        let stream = self.dialer.dial(address).await?;
+        self.add_peer(address, stream).await
+    }
+
+    // Addresses of a `host:port` seed, see `DialQueue::resolve`
+    pub async fn resolve(&self, host: &str) -> anyhow::Result<Vec<SocketAddr>> {
+        self.dialer.resolve(host).await
+    }
+
+    pub async fn add_peer(&self, address: SocketAddr, stream: TcpStream) -> anyhow::Result<()> {
+        let mut peers = self.peers.write().await;
+
+        if peers.contains_key(&address) {
+            bail!("Already connected to peer {address}");
+        }
+        if peers.len() >= self.max_peers {
+            bail!("Peer limit of {} reached", self.max_peers);
+        }
+        self.known.write().await.insert(address);
+
+        let (reader, writer) = stream.into_split();
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let (idle_timeout, idle_timeout_rx) = watch::channel(self.idle_timeout);
+        // Every node reads version 1 frames, newer ones are only sent once
+        // the handshake negotiated them
+        let (version, version_rx) = watch::channel(SupportedVersions::One.as_u16());
+        // The read task sets up the session from the offer the write task
+        // sent and the peer's answer, and hands the write task its half
+        let nonce = u64::from_le_bytes(self.entropy.bytes());
+        let ephemeral = self
+            .encrypted_transport
+            .then(|| EphemeralKey::generate(&self.entropy));
+        let (offer, offer_rx) = oneshot::channel();
+        let (session, session_rx) = oneshot::channel();
+
+        let peer = Peer {
+            info: PeerInfo {
+                address,
+                node_id: None,
+                version: SupportedVersions::One.as_u16(),
+                features: Features::NONE,
+                height: 0,
+                last_seen: Instant::now(),
+                idle_timeout: self.idle_timeout,
+                encrypted: false,
+                filtered: false,
+            },
+            outgoing,
+            ephemeral: ephemeral.as_ref().map(EphemeralKey::public_key),
+            reader: tokio::spawn(read_loop(
+                self.clone(),
+                address,
+                reader,
+                idle_timeout_rx.clone(),
+                ephemeral.map(|key| (key, offer_rx, session)),
+            )),
+            writer: tokio::spawn(write_loop(
+                self.clone(),
+                address,
+                writer,
+                outgoing_rx,
+                idle_timeout_rx,
+                version_rx,
+                (offer, session_rx),
+            )),
+            idle_timeout,
+            version,
+            nonce,
+            known: RecentHashes::default(),
+            filter: None,
+        };
+        peers.insert(address, peer);
+
+        info!("Connected to peer {address}");
+        Ok(())
+    }
+
+    pub async fn remove_peer(&self, address: &SocketAddr) -> Option<PeerInfo> {
+        let peer = self.peers.write().await.remove(address)?;
+        info!("Disconnected from peer {address}");
+
+        Some(peer.info.clone())
+    }
+
+    // Sends the peer why it's disconnected, the connection is closed once the
+    // message went out
+    pub async fn dismiss(&self, address: &SocketAddr, message: Message) {
+        let sent = match Request::new(Command::Post, Some(message)) {
+            Ok(request) => self.send(address, request).await,
+            Err(e) => Err(e.into()),
+        };
+        if sent.is_err() {
+            self.remove_peer(address).await;
+        }
+    }
+
+    pub async fn send(&self, address: &SocketAddr, request: Request) -> anyhow::Result<()> {
+        let peers = self.peers.read().await;
+        let peer = peers
+            .get(address)
+            .ok_or_else(|| anyhow!("Unknown peer {address}"))?;
+
+        peer.outgoing
+            .send(request)
+            .map_err(|_| anyhow!("Connection to peer {address} is closed"))
+    }
+
+    // Queues the message for every connected peer, returns the number of
+    // peers it was queued for
+    pub async fn broadcast(&self, message: Message) -> anyhow::Result<usize> {
+        let request = Request::new(Command::Post, Some(message))?;
+
+        let peers = self.peers.read().await;
+        let sent = peers
+            .values()
+            .filter(|peer| peer.outgoing.send(request.clone()).is_ok())
+            .count();
+
+        Ok(sent)
+    }
+
+    // Announces the items in an `Inv` to every peer not known to have them,
+    // returns the number of peers they were announced to
+    pub async fn announce(&self, items: &[Inventory]) -> usize {
+        let mut peers = self.peers.write().await;
+        peers
+            .values_mut()
+            .map(|peer| announce_to(peer, items))
+            .filter(|announced| *announced)
+            .count()
+    }
+
+    // Announces the transactions to every peer, peers that set a filter
+    // only get the ones matching it
+    pub async fn announce_transactions(&self, txns: &[Transaction]) -> usize {
+        let mut peers = self.peers.write().await;
+        peers
+            .values_mut()
+            .map(|peer| {
+                let items = matching(peer, txns);
+                announce_to(peer, &items)
+            })
+            .filter(|announced| *announced)
+            .count()
+    }
+
+    // Like `announce_transactions`, but only to `count` randomly picked
+    // peers interested in any of them
+    pub async fn announce_transactions_to_random(
+        &self,
+        txns: &[Transaction],
+        count: usize,
+    ) -> usize {
+        let mut peers = self.peers.write().await;
+        peers
+            .values_mut()
+            .map(|peer| (matching(peer, txns), peer))
+            .filter(|(items, _)| !items.is_empty())
+            .choose_multiple(&mut self.entropy.clone(), count)
+            .into_iter()
+            .map(|(items, peer)| announce_to(peer, &items))
+            .filter(|announced| *announced)
+            .count()
+    }
+
+    // Sets or clears the bloom filter of the peer
+    pub async fn set_filter(&self, address: &SocketAddr, filter: Option<BloomFilter>) {
+        if let Some(peer) = self.peers.write().await.get_mut(address) {
+            peer.info.filtered = filter.is_some();
+            peer.filter = filter;
+        }
+    }
+
+    pub async fn filter(&self, address: &SocketAddr) -> Option<BloomFilter> {
+        self.peers.read().await.get(address)?.filter.clone()
+    }
+
+    // Remembers that the peer has the items, e.g. because it sent or
+    // announced them
+    pub async fn mark_known(&self, address: &SocketAddr, items: &[Inventory]) {
+        if let Some(peer) = self.peers.write().await.get_mut(address) {
+            for item in items {
+                peer.known.insert(item.hash());
+            }
+        }
+    }
+
+    pub async fn peers(&self) -> Vec<PeerInfo> {
+        self.peers
+            .read()
+            .await
+            .values()
+            .map(|peer| peer.info.clone())
+            .collect()
+    }
+
+    pub async fn count(&self) -> usize {
+        self.peers.read().await.len()
+    }
+
+    pub async fn is_full(&self) -> bool {
+        self.count().await >= self.max_peers
+    }
+
+    pub async fn is_connected(&self, address: &SocketAddr) -> bool {
+        self.peers.read().await.contains_key(address)
+    }
+
+    // Remembers an address, returns false if it was already known
+    pub async fn add_known(&self, address: SocketAddr) -> bool {
+        self.known.write().await.insert(address)
+    }
+
+    pub async fn known_addresses(&self) -> Vec<SocketAddr> {
+        self.known.read().await.iter().copied().collect()
+    }
+
+    // Records what was negotiated in the handshake with a connected peer
+    pub async fn complete_handshake(
+        &self,
+        address: &SocketAddr,
+        node_id: String,
+        version: u16,
+        features: Features,
+        height: u64,
+        idle_timeout: Duration,
+    ) {
+        if let Some(peer) = self.peers.write().await.get_mut(address) {
+            peer.info.node_id = Some(node_id);
+            peer.info.version = version;
+            peer.info.features = features;
+            peer.info.height = height;
+            peer.info.idle_timeout = idle_timeout;
+            peer.idle_timeout.send_replace(idle_timeout);
+            peer.version.send_replace(version);
+        }
+    }
+
+    // Id the peer proved in its handshake, None until it completed
+    pub async fn node_id(&self, address: &SocketAddr) -> Option<String> {
+        self.peers.read().await.get(address)?.info.node_id.clone()
+    }
+
+    // Nonce the handshake with the peer is signed for
+    pub async fn handshake_nonce(&self, address: &SocketAddr) -> Option<u64> {
+        Some(self.peers.read().await.get(address)?.nonce)
+    }
+
+    // Ephemeral key the handshake with the peer offers, all zeroes if it
+    // doesn't ask for encryption
+    pub async fn handshake_ephemeral(&self, address: &SocketAddr) -> Option<[u8; 32]> {
+        let peers = self.peers.read().await;
+        Some(peers.get(address)?.ephemeral.unwrap_or_default())
+    }
+
+    async fn mark_encrypted(&self, address: &SocketAddr) {
+        if let Some(peer) = self.peers.write().await.get_mut(address) {
+            peer.info.encrypted = true;
+        }
+    }
+
+    async fn touch(&self, address: &SocketAddr, version: u16) {
+        if let Some(peer) = self.peers.write().await.get_mut(address) {
+            peer.info.version = version;
+            peer.info.last_seen = Instant::now();
+        }
+    }
+}
+
+// Items of the transactions matching the peer's filter, all of them if it
+// set none
+fn matching(peer: &Peer, txns: &[Transaction]) -> Vec<Inventory> {
+    txns.iter()
+        .filter(|txn| {
+            peer.filter
+                .as_ref()
+                .is_none_or(|filter| filter.matches(txn))
+        })
+        .map(|txn| Inventory::Transaction(txn.hash_id))
+        .collect()
+}
+
+// Queues an `Inv` of the items the peer doesn't have yet, returns false if
+// there were none
+fn announce_to(peer: &mut Peer, items: &[Inventory]) -> bool {
+    let unknown: Vec<Inventory> = items
+        .iter()
+        .filter(|item| peer.known.insert(item.hash()))
+        .copied()
+        .collect();
+    if unknown.is_empty() {
+        return false;
+    }
+
+    Request::new(Command::Post, Some(Message::Inv(unknown)))
+        .is_ok_and(|request| peer.outgoing.send(request).is_ok())
+}
+
+async fn read_loop(
+    manager: PeerManager,
+    address: SocketAddr,
+    mut reader: OwnedReadHalf,
+    idle_timeout: watch::Receiver<Duration>,
+    mut session: Option<(
+        EphemeralKey,
+        oneshot::Receiver<Handshake>,
+        oneshot::Sender<Transport>,
+    )>,
+) {
+    let mut transport = Transport::Plain;
+    loop {
+        let idle_timeout = *idle_timeout.borrow();
+        let Ok(read) = tokio::time::timeout(
+            idle_timeout,
+            transport.read_response(&mut reader, MAX_PAYLOAD_SIZE),
+        )
+        .await
+        else {
+            warn!("Peer {address} sent nothing for {idle_timeout:?}, disconnecting");
+            manager.stats.record_reaped();
+            break;
+        };
+
+        match read {
+            Ok(Some(response)) if response.header().network() != manager.network => {
+                warn!(
+                    "Peer {address} is on the {} network, disconnecting",
+                    response.header().network()
+                );
+                break;
+            }
+            Ok(Some(response)) => {
+                manager.touch(&address, response.header().version()).await;
+
+                // The peer encrypts everything after its answer. The offer
+                // was handed over before it went out, an answer to nothing
+                // sets up no session
+                if let (Some(Message::VerAck(theirs)), Some((key, mut offer, sender))) =
+                    (response.payload(), session.take())
+                {
+                    let ciphers = offer
+                        .try_recv()
+                        .ok()
+                        .and_then(|ours| key.agree(&ours, theirs, true).ok());
+                    let outgoing = match ciphers {
+                        Some((send, receive)) => {
+                            transport = Transport::Encrypted(receive);
+                            manager.mark_encrypted(&address).await;
+                            info!("Encrypted the connection to peer {address}");
+                            Transport::Encrypted(send)
+                        }
+                        None => Transport::Plain,
+                    };
+                    let _ = sender.send(outgoing);
+                }
+
+                if manager.responses.send((address, response)).is_err() {
+                    break;
+                }
+            }
+            Ok(None) => {
+                info!("Peer {address} closed the connection");
+                break;
+            }
+            Err(corelib::errors::Error::Protocol(e)) => {
+                // The next read skips ahead to the next frame's magic
+                warn!("Malformed response from peer {address}: {e}");
+            }
+            Err(e) => {
+                error!("Lost connection to peer {address}: {e}");
+                break;
+            }
+        }
+    }
+
+    manager.remove_peer(&address).await;
+}
+
+async fn write_loop(
+    manager: PeerManager,
+    address: SocketAddr,
+    mut writer: OwnedWriteHalf,
+    mut outgoing: mpsc::UnboundedReceiver<Request>,
+    idle_timeout: watch::Receiver<Duration>,
+    version: watch::Receiver<u16>,
+    session: (oneshot::Sender<Handshake>, oneshot::Receiver<Transport>),
+) {
+    let mut transport = Transport::Plain;
+    let mut session = Some(session);
+    loop {
+        // Pinged well within the timeout so a late answer doesn't get the
+        // connection reaped
+        let keep_alive = *idle_timeout.borrow() / 3;
+        let request = match tokio::time::timeout(keep_alive, outgoing.recv()).await {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(_) => Request::new(Command::Ping, None).expect("pings have no payload"),
+        };
+
+        let request = request
+            .with_network(manager.network)
+            .with_version(*version.borrow());
+        // An offer of encryption goes to the read task before the peer can
+        // answer it
+        let negotiated = match request.payload() {
+            Some(Message::Version(ours)) if ours.is_encrypted() => {
+                session.take().map(|(offer, negotiated)| {
+                    let _ = offer.send(ours.clone());
+                    negotiated
+                })
+            }
+            _ => None,
+        };
+        if let Err(e) = transport.write_request(&mut writer, &request).await {
+            error!("Failed to write to peer {address}: {e}");
+            break;
+        }
+        if matches!(request.payload(), Some(Message::Outdated(..))) {
+            break;
+        }
+
+        // Nothing else is sent until the answer to the offer tells whether
+        // to encrypt it
+        if let Some(negotiated) = negotiated {
+            let idle_timeout = *idle_timeout.borrow();
+            match tokio::time::timeout(idle_timeout, negotiated).await {
+                Ok(Ok(negotiated)) => transport = negotiated,
+                _ => {
+                    warn!("Peer {address} didn't answer the handshake, disconnecting");
+                    break;
+                }
+            }
+        }
+    }
+
+    manager.remove_peer(&address).await;
+}
+
+#[cfg(test)]
+mod test {
+    use ed25519_dalek::SigningKey;
+    use tokio::{io::AsyncReadExt as _, net::TcpListener};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn broadcasts_to_connected_peers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let (manager, _responses) = PeerManager::new(8);
+        manager.connect(address).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        assert_eq!(manager.count().await, 1);
+        assert!(!manager.add_known(address).await);
+        assert!(manager.connect(address).await.is_err());
+
+        let message = Message::PeerIntroduction("127.0.0.1:9000".to_string());
+        assert_eq!(manager.broadcast(message.clone()).await.unwrap(), 1);
+
+        // Sent as version 1, there was no handshake negotiating a newer one
+        let expected = Request::new(Command::Post, Some(message))
+            .unwrap()
+            .with_version(1);
+        let mut received = vec![0u8; expected.to_bytes().unwrap().len()];
+        stream.read_exact(&mut received).await.unwrap();
+
+        let request = Request::from_bytes(&received).unwrap();
+        assert_eq!(request.command(), &Command::Post);
+        assert_eq!(request.payload(), expected.payload());
+    }
+
+    #[tokio::test]
+    async fn announces_items_once_per_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let (manager, _responses) = PeerManager::new(8);
+        manager.connect(address).await.unwrap();
+        let _stream = listener.accept().await.unwrap();
+
+        let items = [
+            Inventory::Transaction([1u8; 32]),
+            Inventory::Block([2u8; 32]),
+        ];
+        assert_eq!(manager.announce(&items).await, 1);
+        assert_eq!(manager.announce(&items).await, 0);
+
+        // Items the peer sent aren't announced back to it
+        let sent = Inventory::Transaction([3u8; 32]);
+        manager.mark_known(&address, &[sent]).await;
+        assert_eq!(manager.announce(&[sent]).await, 0);
+
+        // Only transactions matching a filter are announced to the peer
+        let mut signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let txn = Transaction::new(&mut signing_key, [1u8; 32]).unwrap();
+        let mut filter = BloomFilter::new(1, 0.0001, 0);
+        filter.insert(&[2u8; 32]);
+        manager.set_filter(&address, Some(filter.clone())).await;
+        assert!(manager.peers().await[0].filtered);
+        assert_eq!(
+            manager
+                .announce_transactions_to_random(std::slice::from_ref(&txn), 1)
+                .await,
+            0
+        );
+        filter.insert(&[1u8; 32]);
+        manager.set_filter(&address, Some(filter)).await;
+        assert_eq!(
+            manager
+                .announce_transactions(std::slice::from_ref(&txn))
+                .await,
+            1
+        );
+        assert_eq!(manager.announce_transactions(&[txn]).await, 0);
+    }
+
+    #[tokio::test]
+    async fn pings_and_reaps_peers_that_go_quiet() {
+        use corelib::net::codec::read_request;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let stats = Arc::new(StatCounters::default());
+        let (manager, _responses) = PeerManager::new(1);
+        let manager = manager
+            .with_idle_timeout(Duration::from_millis(150))
+            .with_stats(stats.clone());
+        manager.connect(address).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        // Nothing to send, so the peer is pinged
+        let request = read_request(&mut stream, MAX_PAYLOAD_SIZE)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.command(), &Command::Ping);
+
+        // The ping goes unanswered, the slot is freed for another peer
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(manager.count().await, 0);
+        assert_eq!(stats.snapshot().connections_reaped, 1);
+        manager.connect(address).await.unwrap();
+    }
+}
@@ -0,0 +1,108 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use corelib::{
+    block::{Block, BlockHeader},
+    deployment::Rules,
+    utxo::UTXO,
+};
+use tokio::{
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
+
+// Header batches waiting for their proof of work to be checked
+const HEADER_QUEUE: usize = 4;
+// Downloaded blocks waiting for their transactions to be checked
+pub const CHECK_QUEUE: usize = 64;
+// Blocks being checked, in the order they're connected in
+pub const CONNECT_QUEUE: usize = 16;
+
+// What the transactions of a block are validated against: the rules
+// deployed on its branch and the outputs they spend there, in the order of
+// the transactions and their inputs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockContext {
+    pub rules: Rules,
+    pub spent: Vec<Vec<UTXO>>,
+}
+
+// Block whose proof of work checked out, and its transactions in `context`
+// unless the outputs they spend weren't known yet
+#[derive(Debug)]
+pub struct CheckedBlock {
+    pub block: Block,
+    pub context: Option<BlockContext>,
+}
+
+// Why a block failed its checks
+#[derive(Debug)]
+pub enum CheckFailure {
+    // The transactions don't match the header, they were tampered with on
+    // the way. The block the header commits to may still be valid
+    Uncommitted(anyhow::Error),
+    // The block the header commits to breaks the rules
+    Invalid(anyhow::Error),
+}
+
+// Result of checking a block on a blocking worker
+pub type BlockCheck = JoinHandle<(Block, Result<Option<BlockContext>, CheckFailure>)>;
+
+// Ends of the queues the stages read from
+#[derive(Debug)]
+pub struct Stages {
+    pub headers: mpsc::Receiver<(SocketAddr, Vec<BlockHeader>)>,
+    pub blocks: mpsc::Receiver<Block>,
+    pub checks_tx: mpsc::Sender<BlockCheck>,
+    pub checks: mpsc::Receiver<BlockCheck>,
+}
+
+// Queues between the stages of the initial block download.
+//
+// Downloaded headers and blocks go through download → header check → script
+// check → connect, every stage runs on its own task so a slow stage doesn't
+// hold up the others. The queues are bounded, a stage that falls behind
+// makes the ones before it wait instead of buffering without limit
+#[derive(Debug, Clone)]
+pub struct SyncPipeline {
+    headers: mpsc::Sender<(SocketAddr, Vec<BlockHeader>)>,
+    blocks: mpsc::Sender<Block>,
+    // Taken by the stages once they start
+    stages: Arc<Mutex<Option<Stages>>>,
+}
+
+impl Default for SyncPipeline {
+    fn default() -> Self {
+        let (headers, headers_rx) = mpsc::channel(HEADER_QUEUE);
+        let (blocks, blocks_rx) = mpsc::channel(CHECK_QUEUE);
+        let (checks_tx, checks) = mpsc::channel(CONNECT_QUEUE);
+
+        Self {
+            headers,
+            blocks,
+            stages: Arc::new(Mutex::new(Some(Stages {
+                headers: headers_rx,
+                blocks: blocks_rx,
+                checks_tx,
+                checks,
+            }))),
+        }
+    }
+}
+
+impl SyncPipeline {
+    // Queues headers received from a peer, waits while the queue is full
+    pub async fn push_headers(&self, address: SocketAddr, headers: Vec<BlockHeader>) {
+        // Sending only fails once the stages stopped
+        let _ = self.headers.send((address, headers)).await;
+    }
+
+    // Queues a downloaded block, waits while the queue is full
+    pub async fn push_block(&self, block: Block) {
+        let _ = self.blocks.send(block).await;
+    }
+
+    // The queue ends for the stages, `None` if they were started before
+    pub async fn take_stages(&self) -> Option<Stages> {
+        self.stages.lock().await.take()
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use corelib::{net::message::Inventory, transaction::Transaction};
+
+// Block hashes remembered by the relay, the oldest are forgotten first
+const MAX_REMEMBERED: usize = 10_000;
+
+// Bounded set of recently seen hashes
+#[derive(Debug, Default)]
+pub struct RecentHashes {
+    hashes: HashSet<[u8; 32]>,
+    // Insertion order, oldest first
+    order: VecDeque<[u8; 32]>,
+}
+
+impl RecentHashes {
+    // Returns false if the hash was already remembered
+    pub fn insert(&mut self, hash: [u8; 32]) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+
+        self.order.push_back(hash);
+        if self.order.len() > MAX_REMEMBERED {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.hashes.contains(hash)
+    }
+}
+
+// What the node already forwarded to its peers.
+//
+// Headers are announced as soon as they pass the header checks, before the
+// block is fully validated, while full blocks are relayed once connected.
+// Blocks failing validation are remembered so they're never announced or
+// relayed again.
+#[derive(Debug, Default)]
+pub struct RelayState {
+    announced: RecentHashes,
+    relayed: RecentHashes,
+    invalid: RecentHashes,
+}
+
+impl RelayState {
+    // Whether the header should be announced, marks it as announced
+    pub fn announce(&mut self, hash: [u8; 32]) -> bool {
+        !self.invalid.contains(&hash) && self.announced.insert(hash)
+    }
+
+    // Whether the block should be relayed, marks it as relayed
+    pub fn relay(&mut self, hash: [u8; 32]) -> bool {
+        !self.invalid.contains(&hash) && self.relayed.insert(hash)
+    }
+
+    pub fn reject(&mut self, hash: [u8; 32]) {
+        self.invalid.insert(hash);
+    }
+
+    pub fn is_invalid(&self, hash: &[u8; 32]) -> bool {
+        self.invalid.contains(hash)
+    }
+}
+
+// How many transactions and blocks received from peers are remembered, and
+// for how long
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeenCacheConfig {
+    pub capacity: usize,
+    pub expiry: Duration,
+}
+
+impl Default for SeenCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 50_000,
+            expiry: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+// Transactions and blocks recently received from peers.
+//
+// Without it an item pushed by many peers at once, or passed around a loop
+// of peers, is validated and relayed again every time it arrives. Items are
+// forgotten once they expire, so one rejected for a parent that was still
+// missing gets another chance, or when the cache is full, oldest first
+#[derive(Debug)]
+pub struct SeenCache {
+    config: SeenCacheConfig,
+    seen: HashMap<Inventory, Instant>,
+    // Insertion order, oldest first
+    order: VecDeque<(Inventory, Instant)>,
+}
+
+impl SeenCache {
+    pub fn new(config: SeenCacheConfig) -> Self {
+        Self {
+            config,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    // Remembers the item as seen at `now`, returns false if it was seen
+    // before and didn't expire yet
+    pub fn insert(&mut self, item: Inventory, now: Instant) -> bool {
+        self.expire(now);
+        if self.seen.contains_key(&item) {
+            return false;
+        }
+
+        self.seen.insert(item, now);
+        self.order.push_back((item, now));
+        while self.order.len() > self.config.capacity {
+            if let Some((oldest, _)) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, item: &Inventory, now: Instant) -> bool {
+        self.seen
+            .get(item)
+            .is_some_and(|seen| now.saturating_duration_since(*seen) < self.config.expiry)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((item, seen)) = self.order.front() {
+            if now.saturating_duration_since(*seen) < self.config.expiry {
+                break;
+            }
+            self.seen.remove(item);
+            self.order.pop_front();
+        }
+    }
+}
+
+// How transactions submitted to this node are first announced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalRelayConfig {
+    // Time transactions are held before their first announcement
+    pub delay: Duration,
+    // Peers the first announcement goes to
+    pub fanout: usize,
+}
+
+// Transactions submitted to this node, announced in batches.
+//
+// Announcing a transaction the moment it's submitted, to every peer, gives
+// away which node it came from. Held back, a batch first goes to a few
+// random peers and only a delay later to the rest, by which time the first
+// peers may have spread it on their own.
+#[derive(Debug)]
+pub struct LocalRelay {
+    config: LocalRelayConfig,
+    // Waiting for their first announcement
+    queued: Vec<Transaction>,
+    // Announced to the first peers, sent to every peer next
+    announced: Vec<Transaction>,
+}
+
+impl LocalRelay {
+    pub fn new(config: LocalRelayConfig) -> Self {
+        Self {
+            config,
+            queued: Vec::new(),
+            announced: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> LocalRelayConfig {
+        self.config
+    }
+
+    pub fn queue(&mut self, txn: Transaction) {
+        self.queued.push(txn);
+    }
+
+    // Transactions due for their first announcement and the ones announced
+    // last time to send to every peer now
+    pub fn next_batch(&mut self) -> (Vec<Transaction>, Vec<Transaction>) {
+        let spread = std::mem::take(&mut self.announced);
+        let first = std::mem::take(&mut self.queued);
+        self.announced = first.clone();
+
+        (first, spread)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn never_forwards_twice_or_after_rejection() {
+        let mut relay = RelayState::default();
+
+        assert!(relay.announce([1u8; 32]));
+        assert!(!relay.announce([1u8; 32]));
+        assert!(relay.relay([1u8; 32]));
+        assert!(!relay.relay([1u8; 32]));
+
+        relay.reject([2u8; 32]);
+        assert!(!relay.announce([2u8; 32]));
+        assert!(!relay.relay([2u8; 32]));
+
+        let mut recent = RecentHashes::default();
+        for i in 0..=MAX_REMEMBERED as u32 {
+            let mut hash = [0u8; 32];
+            hash[..4].copy_from_slice(&i.to_le_bytes());
+            recent.insert(hash);
+        }
+        assert!(!recent.contains(&[0u8; 32]));
+    }
+
+    #[test]
+    fn forgets_seen_items_once_expired_or_full() {
+        let mut seen = SeenCache::new(SeenCacheConfig {
+            capacity: 2,
+            expiry: Duration::from_secs(60),
+        });
+        let start = Instant::now();
+        let (first, second, third) = (
+            Inventory::Transaction([1u8; 32]),
+            Inventory::Block([1u8; 32]),
+            Inventory::Transaction([3u8; 32]),
+        );
+
+        assert!(seen.insert(first, start));
+        assert!(!seen.insert(first, start + Duration::from_secs(1)));
+        // A block and a transaction with the same hash are different items
+        assert!(seen.insert(second, start + Duration::from_secs(2)));
+        assert!(seen.contains(&first, start + Duration::from_secs(59)));
+        assert!(!seen.contains(&first, start + Duration::from_secs(60)));
+
+        // The oldest makes room once the cache is full
+        assert!(seen.insert(third, start + Duration::from_secs(3)));
+        assert_eq!(seen.seen.len(), 2);
+        assert!(!seen.contains(&first, start + Duration::from_secs(3)));
+
+        // and expired items are dropped
+        assert!(seen.insert(first, start + Duration::from_secs(62)));
+        assert_eq!(seen.seen.len(), 2);
+        assert!(seen.insert(second, start + Duration::from_secs(62)));
+    }
+
+    #[test]
+    fn announces_local_transactions_in_two_steps() {
+        let txn = Transaction::coinbase([1u8; 32], 0, 0).unwrap();
+        let mut relay = LocalRelay::new(LocalRelayConfig {
+            delay: Duration::from_secs(1),
+            fanout: 2,
+        });
+
+        relay.queue(txn.clone());
+        assert_eq!(relay.next_batch(), (vec![txn.clone()], Vec::new()));
+        assert_eq!(relay.next_batch(), (Vec::new(), vec![txn]));
+        assert_eq!(relay.next_batch(), (Vec::new(), Vec::new()));
+    }
+}
@@ -0,0 +1,980 @@
+use std::net::SocketAddr;
+
+use corelib::{
+    address::{self, Address},
+    block::Block,
+    blockchain::BlockChain,
+    config::{ChainParams, Network},
+    deployment::Activation,
+    net::{features::Features, protocol::SupportedVersions},
+    transaction::Transaction,
+    utxo::UTXO,
+};
+use hex::FromHex;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{info, warn};
+
+use crate::{
+    audit::{AuditAction, AuditEntry},
+    node::Node,
+};
+
+// Largest request accepted, enough for the hex of a large raw transaction
+const MAX_REQUEST_SIZE: usize = 4 * 1024 * 1024;
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// Known method that failed, e.g. a rejected transaction or a missing block
+const SERVER_ERROR: i64 = -32000;
+
+// Entries `getauditlog` returns unless asked for another number
+const DEFAULT_AUDIT_ENTRIES: usize = 100;
+// Most headers one `getheaders` call returns
+const MAX_RPC_HEADERS: u64 = 2_000;
+
+// Access a method needs. Admin methods change the node's state or reveal how
+// it's run, e.g. its peers, bans and audit log, every call to them goes to the
+// audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Privilege {
+    Public,
+    Admin,
+}
+
+impl Privilege {
+    fn of(method: &str) -> Self {
+        match method {
+            "sendrawtransaction" | "getpeerinfo" | "listbanned" | "getauditlog" => Privilege::Admin,
+            _ => Privilege::Public,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(INVALID_PARAMS, message)
+    }
+
+    fn server(message: impl Into<String>) -> Self {
+        Self::new(SERVER_ERROR, message)
+    }
+}
+
+// JSON-RPC 2.0 over HTTP for wallets and explorers, served on its own port
+// next to the peer protocol.
+//
+// Every connection carries a single POST request, byte arrays are hex
+// encoded and transactions and UTXOs are exchanged as hex encoded Borsh.
+#[derive(Debug, Clone)]
+pub struct NodeRpc {
+    node: Node,
+}
+
+impl NodeRpc {
+    pub fn new(node: Node) -> Self {
+        Self { node }
+    }
+
+    // Accepts connections forever, only local clients can connect
+    pub async fn serve(self, port: u16) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+        info!("RPC listening on {}", listener.local_addr()?);
+
+        loop {
+            let (stream, address) = listener.accept().await?;
+            let rpc = self.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = rpc.handle_connection(stream, address).await {
+                    warn!("RPC connection with {address} failed: {e}");
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(
+        &self,
+        mut stream: TcpStream,
+        address: SocketAddr,
+    ) -> anyhow::Result<()> {
+        let response = match read_body(&mut stream).await? {
+            Some(body) => self.handle_body(&body).await,
+            None => {
+                warn!("Malformed RPC request from {address}");
+                error_response(
+                    Value::Null,
+                    RpcError::new(INVALID_REQUEST, "Invalid HTTP request"),
+                )
+            }
+        };
+
+        let body = response.to_string();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await?;
+
+        Ok(())
+    }
+
+    // Answers a JSON-RPC request body with the response object
+    pub async fn handle_body(&self, body: &[u8]) -> Value {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(e) => {
+                return error_response(Value::Null, RpcError::new(PARSE_ERROR, e.to_string()))
+            }
+        };
+
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            return error_response(id, RpcError::new(INVALID_REQUEST, "Missing method"));
+        };
+        let params = request.get("params").cloned().unwrap_or(json!([]));
+
+        match self.handle(method, &params).await {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => error_response(id, e),
+        }
+    }
+
+    // Dispatches a call by method name, params are positional
+    pub async fn handle(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        if Privilege::of(method) == Privilege::Admin {
+            self.node
+                .audit(AuditAction::Rpc {
+                    method: method.to_string(),
+                })
+                .await;
+        }
+
+        match method {
+            "getblockcount" => Ok(json!(self.node.get_block_count().await)),
+            "getsafemode" => Ok(json!({
+                "enabled": self.node.safe_mode().is_some(),
+                "reason": self.node.safe_mode(),
+            })),
+            // Effective consensus parameters and the ones overridden from the
+            // defaults, to spot a misconfigured custom network
+            "getchainparams" => {
+                let params = self.node.chain_params();
+                let defaults = ChainParams::default();
+                let values = |params: &ChainParams| {
+                    ChainParams::NAMES
+                        .into_iter()
+                        .map(|name| {
+                            (
+                                name.to_string(),
+                                json!(params.get(name).unwrap_or(0) as u64),
+                            )
+                        })
+                        .collect::<serde_json::Map<_, _>>()
+                };
+
+                Ok(json!({
+                    "network": self.node.network().name(),
+                    "params": values(params),
+                    "defaults": values(&defaults),
+                    "overridden": params.overridden(),
+                }))
+            }
+            "getdeploymentinfo" => {
+                let (version, deployments) = self.node.get_deployment_info().await;
+                let deployments = deployments
+                    .iter()
+                    .map(|info| {
+                        let (activation_height, bit) = match info.activation {
+                            Activation::Height(height) => (Some(height), None),
+                            Activation::Signaling { bit, .. } => (None, Some(bit)),
+                        };
+                        json!({
+                            "rules": info.rules.names(),
+                            "state": info.state.name(),
+                            "activation_height": activation_height,
+                            "bit": bit,
+                            "signaling": info.signaling,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                Ok(json!({
+                    "height": self.node.get_block_count().await,
+                    "block_version": version,
+                    "deployments": deployments,
+                }))
+            }
+            "getblock" => {
+                // Blocks are looked up by hash or by height on the best chain.
+                // Without `verbose` the block's canonical encoding is returned
+                // as hex
+                let verbose = params.get(1).and_then(Value::as_bool).unwrap_or(true);
+                let block = match params.get(0) {
+                    Some(Value::Number(height)) => {
+                        let height = height
+                            .as_u64()
+                            .ok_or_else(|| RpcError::invalid_params("expected height"))?;
+                        self.node.get_block_at(height).await
+                    }
+                    _ => {
+                        let hash = hex_param::<[u8; 32]>(params, 0, "block hash")?;
+                        self.node.get_block(&hash).await
+                    }
+                };
+
+                let block = block.ok_or_else(|| RpcError::server("Block not found"))?;
+                match verbose {
+                    true => Ok(block_json(&block)),
+                    false => Ok(Value::String(hex::encode(block.to_bytes()))),
+                }
+            }
+            "getrawtransaction" => {
+                let txid = hex_param::<[u8; 32]>(params, 0, "txid")?;
+                let txn = self
+                    .node
+                    .get_raw_transaction(&txid)
+                    .await
+                    .ok_or_else(|| RpcError::server("Transaction not found"))?;
+
+                let bytes = borsh::to_vec(&txn).map_err(|e| RpcError::server(e.to_string()))?;
+                Ok(Value::String(hex::encode(bytes)))
+            }
+            // Where a transaction stands and every known block containing
+            // it, stale ones included, so wallets can tell a reorganization
+            // undid a payment
+            "gettxconfirmations" => {
+                let txid = hex_param::<[u8; 32]>(params, 0, "txid")?;
+                let status = self.node.get_tx_confirmations(&txid).await;
+                let blocks = self
+                    .node
+                    .chain_snapshot()
+                    .await
+                    .map_or(Vec::new(), |chain| chain.find_transaction(&txid));
+
+                Ok(json!({ "status": status, "blocks": blocks }))
+            }
+            // Headers of the best chain from a height on, hex encoded in
+            // their canonical encoding, for light wallets validating the
+            // chain themselves
+            "getheaders" => {
+                let from = params
+                    .get(0)
+                    .and_then(Value::as_u64)
+                    .ok_or_else(|| RpcError::invalid_params("expected height"))?;
+                let count = match params.get(1) {
+                    Some(count) => count
+                        .as_u64()
+                        .ok_or_else(|| RpcError::invalid_params("expected number of headers"))?,
+                    None => MAX_RPC_HEADERS,
+                };
+                let headers = self
+                    .node
+                    .chain_snapshot()
+                    .await
+                    .map_or(Vec::new(), |chain| {
+                        (from..from.saturating_add(count.min(MAX_RPC_HEADERS)))
+                            .map_while(|height| chain.block(height))
+                            .map(|block| Value::String(hex::encode(block.header().to_bytes())))
+                            .collect()
+                    });
+
+                Ok(Value::Array(headers))
+            }
+            // Merkle proof that a transaction is in a block of the best
+            // chain, null while it's unconfirmed
+            "gettxproof" => {
+                let txid = hex_param::<[u8; 32]>(params, 0, "txid")?;
+                let Some(chain) = self.node.chain_snapshot().await else {
+                    return Ok(Value::Null);
+                };
+                let found = chain
+                    .find_transaction(&txid)
+                    .into_iter()
+                    .filter(|inclusion| inclusion.on_best_chain)
+                    .find_map(|inclusion| {
+                        let proof = chain.block(inclusion.height)?.merkle_proof(&txid)?;
+                        Some((inclusion, proof))
+                    });
+                let Some((inclusion, proof)) = found else {
+                    return Ok(Value::Null);
+                };
+
+                let proof = borsh::to_vec(&proof).map_err(|e| RpcError::server(e.to_string()))?;
+                Ok(json!({
+                    "block_hash": hex::encode(inclusion.block_hash),
+                    "height": inclusion.height,
+                    "proof": hex::encode(proof),
+                }))
+            }
+            "sendrawtransaction" => {
+                let bytes = Vec::<u8>::from_hex(str_param(params, 0, "transaction")?)
+                    .map_err(|_| RpcError::invalid_params("expected transaction as hex"))?;
+                let txn: Transaction = borsh::from_slice(&bytes)
+                    .map_err(|_| RpcError::invalid_params("malformed transaction"))?;
+                let txid = txn.hash_id;
+
+                self.node
+                    .send_raw_transaction(txn)
+                    .await
+                    .map_err(|e| RpcError::server(format!("Transaction rejected: {e}")))?;
+                Ok(Value::String(hex::encode(txid)))
+            }
+            "getmempoolinfo" => {
+                let info = self.node.get_mempool_info().await;
+
+                Ok(json!({
+                    "size": info.size,
+                    "bytes": info.bytes,
+                    "max_size": info.max_size,
+                    "max_bytes": info.max_bytes,
+                }))
+            }
+            // Recent transactions that left the pool and why, oldest first
+            "getmempoolremovals" => {
+                let removals = self.node.get_mempool_removals().await;
+
+                Ok(Value::Array(
+                    removals
+                        .iter()
+                        .map(|removal| {
+                            json!({
+                                "txid": hex::encode(removal.txn_hash),
+                                "reason": removal.reason.to_string(),
+                                "timestamp": removal.timestamp as u64,
+                            })
+                        })
+                        .collect(),
+                ))
+            }
+            // Fee rate likely to confirm within the number of blocks, per
+            // kilobyte and rounded up per byte
+            "estimatefeerate" => {
+                let target_blocks = params
+                    .get(0)
+                    .and_then(Value::as_u64)
+                    .filter(|blocks| *blocks > 0)
+                    .ok_or_else(|| RpcError::invalid_params("expected number of blocks"))?;
+                let rate = self.node.estimate_fee_rate(target_blocks).await;
+
+                Ok(json!({
+                    "blocks": target_blocks,
+                    "fee_rate": rate.per_kb(),
+                    "per_byte": rate.fee_for(1),
+                }))
+            }
+            "getmemoryinfo" => {
+                let info = self.node.get_memory_info().await;
+
+                Ok(json!({
+                    "total": info.total(),
+                    "mempool": { "usage": info.mempool, "budget": info.budget.mempool },
+                    "utxos": { "usage": info.utxos, "budget": info.budget.utxos },
+                    "orphans": { "usage": info.orphans, "budget": info.budget.orphans },
+                }))
+            }
+            "getnetworkinfo" => Ok(json!({
+                "node_id": self.node.node_id(),
+                "network": self.node.network().name(),
+                "protocol_versions": SupportedVersions::all(),
+                "features": Features::SUPPORTED.names(),
+                "encrypted_transport": self.node.encrypted_transport(),
+                "min_peer_version": self.node.min_peer_version(),
+                "time_offset_ms": self.node.time_offset().await,
+            })),
+            "getnodestats" => {
+                let stats = self.node.get_node_stats();
+
+                Ok(json!({
+                    "blocks_validated": stats.blocks_validated,
+                    "bytes_relayed": stats.bytes_relayed,
+                    "blocks_mined": stats.blocks_mined,
+                    "connections_reaped": stats.connections_reaped,
+                    "handshakes_rejected": stats.handshakes_rejected,
+                    "rejected_versions": self.node.get_rejected_versions(),
+                }))
+            }
+            "getmempoolentry" => {
+                let txid = hex_param::<[u8; 32]>(params, 0, "txid")?;
+                let entry = self
+                    .node
+                    .get_mempool_entry(&txid)
+                    .await
+                    .ok_or_else(|| RpcError::server("Transaction not in mempool"))?;
+
+                Ok(json!({
+                    "fee": entry.fee,
+                    "size": entry.size,
+                    "fee_rate": entry.fee_rate.per_kb(),
+                    "time_in_pool": entry.time_in_pool as u64,
+                    "ancestor_count": entry.ancestor_count,
+                    "ancestor_fees": entry.ancestor_fees,
+                    "ancestor_size": entry.ancestor_size,
+                    "descendant_count": entry.descendant_count,
+                    "descendant_fees": entry.descendant_fees,
+                    "descendant_size": entry.descendant_size,
+                    "replaceable": entry.replaceable,
+                }))
+            }
+            "getbalance" => {
+                let address = address_param(params, 0, self.node.network())?;
+                Ok(json!(self.node.get_balance(&address).await))
+            }
+            "getpeerinfo" => {
+                let mut peers = Vec::new();
+                for peer in self.node.get_peer_info().await {
+                    let misbehavior = self.node.misbehavior_score(&peer.address.ip()).await;
+                    peers.push(json!({
+                        "address": peer.address.to_string(),
+                        "node_id": peer.node_id,
+                        "version": peer.version,
+                        "height": peer.height,
+                        "features": peer.features.names(),
+                        "last_seen_secs": peer.last_seen.elapsed().as_secs(),
+                        "idle_timeout_ms": peer.idle_timeout.as_millis() as u64,
+                        "encrypted": peer.encrypted,
+                        "filtered": peer.filtered,
+                        "misbehavior": misbehavior,
+                    }));
+                }
+
+                Ok(Value::Array(peers))
+            }
+            "listbanned" => Ok(Value::Array(
+                self.node
+                    .list_banned()
+                    .await
+                    .iter()
+                    .map(|ban| {
+                        json!({
+                            "address": ban.address,
+                            "until": ban.until as u64,
+                        })
+                    })
+                    .collect(),
+            )),
+            // Scans of the whole chain run on a snapshot, blocks connected
+            // meanwhile don't show up halfway. `scanunspent` and
+            // `getaddresshistory` report the tip their result is as of
+            "listunspent" => {
+                let address = address_param(params, 0, self.node.network())?;
+                let chain = self.node.chain_snapshot().await;
+                // Outputs spent by pending transactions aren't offered again,
+                // a wallet would double spend its own payments
+                let unspent = self.node.unspent_outputs(chain.as_deref(), &address).await;
+                Ok(Value::Array(unspent_json(&unspent)?))
+            }
+            "scanunspent" => {
+                let address = address_param(params, 0, self.node.network())?;
+                let chain = self.node.chain_snapshot().await;
+
+                Ok(json!({
+                    "address": Address::new(self.node.network(), address).to_string(),
+                    "tip": chain.as_deref().map(tip_json),
+                    "balance": chain.as_deref().map_or(0, |chain| chain.balance(&address)),
+                    "unspent": unspent_json(chain.iter().flat_map(|chain| chain.unspent(&address)))?,
+                }))
+            }
+            "getaddresshistory" => {
+                let address = address_param(params, 0, self.node.network())?;
+                let chain = self.node.chain_snapshot().await;
+                let owned = |utxos: &[UTXO]| -> u64 {
+                    utxos
+                        .iter()
+                        .filter(|utxo| utxo.is_owned_by(&address))
+                        .map(UTXO::value)
+                        .sum()
+                };
+                let history: Vec<Value> = chain
+                    .as_deref()
+                    .into_iter()
+                    .flat_map(|chain| chain.address_history(&address))
+                    .map(|(height, txn)| {
+                        json!({
+                            "txid": hex::encode(txn.hash_id),
+                            "height": height,
+                            "received": owned(&txn.outputs),
+                            "sent": owned(&txn.inputs),
+                        })
+                    })
+                    .collect();
+
+                Ok(json!({
+                    "address": Address::new(self.node.network(), address).to_string(),
+                    "tip": chain.as_deref().map(tip_json),
+                    "transactions": history,
+                }))
+            }
+            "getauditlog" => {
+                let count = match params.get(0) {
+                    Some(count) => count
+                        .as_u64()
+                        .ok_or_else(|| RpcError::invalid_params("expected number of entries"))?
+                        as usize,
+                    None => DEFAULT_AUDIT_ENTRIES,
+                };
+                let audit = self
+                    .node
+                    .audit_log()
+                    .ok_or_else(|| RpcError::server("No audit log configured"))?;
+                let entries = audit
+                    .entries()
+                    .await
+                    .map_err(|e| RpcError::server(e.to_string()))?;
+                let verification = audit.verify(&entries);
+
+                // The whole log is verified, only the most recent entries are
+                // returned
+                let recent = entries
+                    .iter()
+                    .skip(entries.len().saturating_sub(count))
+                    .map(audit_entry_json)
+                    .collect::<Vec<_>>();
+
+                Ok(json!({
+                    "length": entries.len(),
+                    "valid": verification.is_ok(),
+                    "error": verification.err().map(|e| e.to_string()),
+                    "entries": recent,
+                }))
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method {method}"),
+            )),
+        }
+    }
+}
+
+fn block_json(block: &Block) -> Value {
+    json!({
+        "hash": hex::encode(block.hash()),
+        "height": block.index(),
+        "previous_hash": block.previous_hash(),
+        "timestamp": block.timestamp() as u64,
+        "difficulty": block.difficulty(),
+        "version": block.version(),
+        "merkle_root": hex::encode(block.merkle_root()),
+        "witness_root": hex::encode(block.header().witness_root),
+        "nonce": block.header().nonce,
+        // Miners tag the coinbase with e.g. their pool's name
+        "coinbase_tag": block
+            .transactions()
+            .first()
+            .and_then(Transaction::coinbase_tag)
+            .map(|tag| String::from_utf8_lossy(tag)),
+        "transactions": block
+            .transactions()
+            .iter()
+            .map(|txn| hex::encode(txn.hash_id))
+            .collect::<Vec<_>>(),
+    })
+}
+
+// Hash and height of the chain's tip
+fn tip_json(chain: &BlockChain) -> Value {
+    json!({
+        "hash": hex::encode(chain.tip().hash()),
+        "height": chain.tip().index(),
+    })
+}
+
+// Unspent outputs as hex encoded Borsh
+fn unspent_json<'a>(utxos: impl IntoIterator<Item = &'a UTXO>) -> Result<Vec<Value>, RpcError> {
+    utxos
+        .into_iter()
+        .map(|utxo| {
+            let bytes = borsh::to_vec(utxo).map_err(|e| RpcError::server(e.to_string()))?;
+            Ok(Value::String(hex::encode(bytes)))
+        })
+        .collect()
+}
+
+fn audit_entry_json(entry: &AuditEntry) -> Value {
+    json!({
+        "sequence": entry.sequence,
+        "timestamp": entry.timestamp as u64,
+        "action": entry.action.to_string(),
+        "hash": hex::encode(entry.hash),
+        "signature": hex::encode(entry.signature),
+    })
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": error.code, "message": error.message },
+    })
+}
+
+fn str_param<'a>(params: &'a Value, index: usize, name: &str) -> Result<&'a str, RpcError> {
+    params
+        .get(index)
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::invalid_params(format!("expected {name}")))
+}
+
+// Address of the node's network, or a hex encoded public key
+fn address_param(params: &Value, index: usize, network: Network) -> Result<[u8; 32], RpcError> {
+    address::parse_key(str_param(params, index, "address")?, network)
+        .map_err(|e| RpcError::invalid_params(e.to_string()))
+}
+
+fn hex_param<T: FromHex>(params: &Value, index: usize, name: &str) -> Result<T, RpcError> {
+    T::from_hex(str_param(params, index, name)?)
+        .map_err(|_| RpcError::invalid_params(format!("expected {name} as hex")))
+}
+
+// Reads the body of an HTTP request, `None` if the request is malformed or
+// larger than `MAX_REQUEST_SIZE`
+async fn read_body(stream: &mut TcpStream) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 4096];
+
+    let header_end = loop {
+        if let Some(position) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break position + 4;
+        }
+        if request.len() > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buffer[..read]);
+    };
+
+    let headers = String::from_utf8_lossy(&request[..header_end]).to_lowercase();
+    let Some(length) = headers
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|length| length.trim().parse::<usize>().ok())
+    else {
+        return Ok(None);
+    };
+    if length > MAX_REQUEST_SIZE {
+        return Ok(None);
+    }
+
+    while request.len() < header_end + length {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    Ok(Some(request[header_end..header_end + length].to_vec()))
+}
+
+#[cfg(test)]
+mod test {
+    use corelib::{
+        block::{BlockBuilder, BlockHeader},
+        deployment::Deployments,
+        mempool::RemovalReason,
+        merkle,
+        script::SigHash,
+    };
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+    use crate::audit::AuditLog;
+
+    #[tokio::test]
+    async fn audits_admin_calls() {
+        let dir = std::env::temp_dir().join(format!("aurelius-{}", uuid::Uuid::new_v4()));
+        let (node, _) = Node::new(0);
+        let node = node.with_audit_log(AuditLog::open(&dir).await.unwrap());
+        let rpc = NodeRpc::new(node.clone());
+
+        for method in [
+            "getblockcount",
+            "getpeerinfo",
+            "listbanned",
+            "getmempoolinfo",
+        ] {
+            rpc.handle(method, &json!([])).await.unwrap();
+        }
+        let log = rpc.handle("getauditlog", &json!([])).await.unwrap();
+
+        let methods = node
+            .audit_log()
+            .unwrap()
+            .entries()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.action.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            methods,
+            ["rpc getpeerinfo", "rpc listbanned", "rpc getauditlog"]
+        );
+        assert_eq!(log["length"], json!(3));
+        assert_eq!(log["valid"], json!(true));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_mempool_removals() {
+        let (node, _) = Node::new(0);
+        let rpc = NodeRpc::new(node.clone());
+        let genesis = Block::genesis(Network::Mainnet);
+        node.process_block(genesis.clone()).await.unwrap();
+
+        let mut signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let owner = signing_key.verifying_key().to_bytes();
+        let funding = BlockBuilder::new(1, hex::encode(genesis.hash()), 1, owner)
+            .build()
+            .unwrap();
+        node.process_block(funding.clone()).await.unwrap();
+        assert_eq!(
+            rpc.handle("getmempoolremovals", &json!([])).await.unwrap(),
+            json!([])
+        );
+
+        let coinbase = &funding.transactions()[0];
+        let output = coinbase.outputs[0]
+            .clone()
+            .confirm_utxo(coinbase.hash_id, 1, true)
+            .unwrap();
+        let mut txn = Transaction::new(&mut signing_key, [1u8; 32]).unwrap();
+        txn.add_inputs(vec![output], &mut signing_key).unwrap();
+        let signature = txn.sign_input(0, SigHash::All, &signing_key).unwrap();
+        txn.set_unlocking_scripts(vec![format!(
+            "{} {}",
+            hex::encode(signature),
+            hex::encode(owner)
+        )]);
+        node.send_raw_transaction(txn.clone()).await.unwrap();
+
+        let block = BlockBuilder::new(2, hex::encode(funding.hash()), 1, [1u8; 32])
+            .transaction(txn.clone())
+            .build()
+            .unwrap();
+        node.process_block(block).await.unwrap();
+
+        let removals = rpc.handle("getmempoolremovals", &json!([])).await.unwrap();
+        assert_eq!(removals.as_array().unwrap().len(), 1);
+        assert_eq!(removals[0]["txid"], json!(hex::encode(txn.hash_id)));
+        assert_eq!(
+            removals[0]["reason"],
+            json!(RemovalReason::Mined.to_string())
+        );
+        assert!(removals[0]["timestamp"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn answers_chain_queries_over_http() {
+        let (node, _) = Node::new(0);
+        let node = node.with_deployments(Deployments::for_network(Network::Mainnet));
+        let genesis = Block::genesis(Network::Mainnet);
+        node.process_block(genesis.clone()).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        tokio::spawn(NodeRpc::new(node.clone()).serve(port));
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let call = |body: String| async move {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let request = format!(
+                "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            let body = response.split("\r\n\r\n").nth(1).unwrap();
+            serde_json::from_str::<Value>(body).unwrap()
+        };
+
+        let response = call(r#"{"jsonrpc":"2.0","id":1,"method":"getblockcount"}"#.into()).await;
+        assert_eq!(response["result"], json!(1));
+        assert_eq!(response["id"], json!(1));
+
+        let response = call(format!(
+            r#"{{"jsonrpc":"2.0","id":2,"method":"getblock","params":["{}"]}}"#,
+            hex::encode(genesis.hash())
+        ))
+        .await;
+        assert_eq!(response["result"]["height"], json!(0));
+
+        let response =
+            call(r#"{"jsonrpc":"2.0","id":2,"method":"getblock","params":[0, false]}"#.into())
+                .await;
+        let bytes = Vec::<u8>::from_hex(response["result"].as_str().unwrap()).unwrap();
+        assert_eq!(Block::from_bytes(&bytes).unwrap(), genesis);
+
+        let coinbase = hex::encode(genesis.transactions()[0].hash_id);
+        let response = call(format!(
+            r#"{{"jsonrpc":"2.0","id":3,"method":"getrawtransaction","params":["{coinbase}"]}}"#
+        ))
+        .await;
+        let bytes = Vec::<u8>::from_hex(response["result"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            borsh::from_slice::<Transaction>(&bytes).unwrap(),
+            genesis.transactions()[0]
+        );
+        let response = call(format!(
+            r#"{{"jsonrpc":"2.0","id":3,"method":"gettxconfirmations","params":["{coinbase}"]}}"#
+        ))
+        .await;
+        assert_eq!(response["result"]["status"]["confirmations"], json!(1));
+        assert_eq!(
+            response["result"]["blocks"],
+            json!([{
+                "block_hash": hex::encode(genesis.hash()),
+                "height": 0,
+                "on_best_chain": true,
+                "confirmations": 1,
+            }])
+        );
+
+        let response = call(format!(
+            r#"{{"jsonrpc":"2.0","id":3,"method":"gettxproof","params":["{coinbase}"]}}"#
+        ))
+        .await;
+        assert_eq!(response["result"]["height"], json!(0));
+        let proof = Vec::<u8>::from_hex(response["result"]["proof"].as_str().unwrap()).unwrap();
+        assert!(merkle::Tree::verify_proof(
+            genesis.transactions()[0].hash_id,
+            &borsh::from_slice(&proof).unwrap(),
+            genesis.merkle_root()
+        ));
+        let response = call(format!(
+            r#"{{"jsonrpc":"2.0","id":3,"method":"gettxproof","params":["{}"]}}"#,
+            "00".repeat(32)
+        ))
+        .await;
+        assert_eq!(response["result"], Value::Null);
+
+        let response =
+            call(r#"{"jsonrpc":"2.0","id":3,"method":"getheaders","params":[0, 5]}"#.into()).await;
+        let header = Vec::<u8>::from_hex(response["result"][0].as_str().unwrap()).unwrap();
+        assert_eq!(BlockHeader::from_bytes(&header).unwrap(), *genesis.header());
+        assert_eq!(response["result"].as_array().unwrap().len(), 1);
+
+        // Nothing competes for the room in the next block
+        let response =
+            call(r#"{"jsonrpc":"2.0","id":3,"method":"estimatefeerate","params":[6]}"#.into())
+                .await;
+        assert_eq!(response["result"]["fee_rate"], json!(0));
+        let response =
+            call(r#"{"jsonrpc":"2.0","id":3,"method":"estimatefeerate","params":[0]}"#.into())
+                .await;
+        assert_eq!(response["error"]["code"], json!(INVALID_PARAMS));
+
+        let response = call(format!(
+            r#"{{"jsonrpc":"2.0","id":4,"method":"getbalance","params":["{}"]}}"#,
+            hex::encode([0u8; 32])
+        ))
+        .await;
+        assert_eq!(
+            response["result"],
+            json!(genesis.transactions()[0].output_value())
+        );
+        // Addresses of another network are refused
+        let key = SigningKey::from_bytes(&[6u8; 32]).verifying_key();
+        let response = call(format!(
+            r#"{{"jsonrpc":"2.0","id":4,"method":"getbalance","params":["{}"]}}"#,
+            Address::from_verifying_key(Network::Testnet, &key)
+        ))
+        .await;
+        assert_eq!(response["error"]["code"], json!(INVALID_PARAMS));
+        let response = call(format!(
+            r#"{{"jsonrpc":"2.0","id":4,"method":"getbalance","params":["{}"]}}"#,
+            Address::from_verifying_key(Network::Mainnet, &key)
+        ))
+        .await;
+        assert_eq!(response["result"], json!(0));
+
+        // Scans report the tip they ran at
+        let response = call(format!(
+            r#"{{"jsonrpc":"2.0","id":4,"method":"scanunspent","params":["{}"]}}"#,
+            hex::encode([0u8; 32])
+        ))
+        .await;
+        let tip = json!({ "hash": hex::encode(genesis.hash()), "height": 0 });
+        assert_eq!(response["result"]["tip"], tip);
+        assert_eq!(
+            response["result"]["address"],
+            json!(Address::new(Network::Mainnet, [0u8; 32]).to_string())
+        );
+        assert_eq!(
+            response["result"]["balance"],
+            json!(genesis.transactions()[0].output_value())
+        );
+        assert_eq!(response["result"]["unspent"].as_array().unwrap().len(), 1);
+        let response = call(format!(
+            r#"{{"jsonrpc":"2.0","id":4,"method":"getaddresshistory","params":["{}"]}}"#,
+            hex::encode([0u8; 32])
+        ))
+        .await;
+        assert_eq!(response["result"]["tip"], tip);
+        assert_eq!(
+            response["result"]["transactions"],
+            json!([{
+                "txid": coinbase,
+                "height": 0,
+                "received": genesis.transactions()[0].output_value(),
+                "sent": 0,
+            }])
+        );
+
+        // Main network miners signal for the timelocks from the start
+        let response =
+            call(r#"{"jsonrpc":"2.0","id":5,"method":"getdeploymentinfo"}"#.into()).await;
+        assert_eq!(response["result"]["block_version"], json!(1));
+        let deployment = &response["result"]["deployments"][0];
+        assert_eq!(deployment["rules"], json!(["timelocks"]));
+        assert_eq!(deployment["state"], json!("started"));
+        assert_eq!(deployment["signaling"], json!(0));
+
+        let response = call(r#"{"jsonrpc":"2.0","id":7,"method":"getchainparams"}"#.into()).await;
+        assert_eq!(
+            response["result"]["params"]["halving_interval"],
+            json!(210_000)
+        );
+        assert_eq!(response["result"]["overridden"], json!([]));
+
+        let response = call(r#"{"jsonrpc":"2.0","id":8,"method":"getnetworkinfo"}"#.into()).await;
+        assert_eq!(response["result"]["node_id"], json!(node.node_id()));
+        assert_eq!(response["result"]["protocol_versions"], json!([1, 2]));
+
+        let response = call(r#"{"jsonrpc":"2.0","id":6,"method":"stop"}"#.into()).await;
+        assert_eq!(response["error"]["code"], json!(METHOD_NOT_FOUND));
+
+        let response = call("not json".into()).await;
+        assert_eq!(response["error"]["code"], json!(PARSE_ERROR));
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+// Lifetime statistics of the node, kept across restarts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct NodeStats {
+    // Blocks that passed full validation
+    pub blocks_validated: u64,
+    // Bytes of the messages broadcast to peers
+    pub bytes_relayed: u64,
+    pub blocks_mined: u64,
+    // Connections closed for going quiet past their idle timeout, counted
+    // for the current run only
+    #[borsh(skip)]
+    pub connections_reaped: u64,
+    // Handshakes refused for the peer's protocol versions, counted for the
+    // current run only
+    #[borsh(skip)]
+    pub handshakes_rejected: u64,
+}
+
+// Counters of the current run, added on top of the totals of earlier runs
+#[derive(Debug, Default)]
+pub struct StatCounters {
+    previous: RwLock<NodeStats>,
+    blocks_validated: AtomicU64,
+    bytes_relayed: AtomicU64,
+    blocks_mined: AtomicU64,
+    connections_reaped: AtomicU64,
+    // Rejected handshakes by the latest version the peer advertised, which
+    // tells how many nodes an upgrade would still cut off
+    rejected_versions: RwLock<BTreeMap<u16, u64>>,
+}
+
+impl StatCounters {
+    // Continues counting from the totals saved by an earlier run
+    pub fn resume(&self, previous: NodeStats) {
+        *self.previous.write().unwrap_or_else(|e| e.into_inner()) = previous;
+    }
+
+    pub fn record_validated(&self) {
+        self.blocks_validated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_relayed(&self, bytes: u64) {
+        self.bytes_relayed.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_mined(&self) {
+        self.blocks_mined.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_reaped(&self) {
+        self.connections_reaped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rejected_version(&self, version: u16) {
+        *self
+            .rejected_versions
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(version)
+            .or_default() += 1;
+    }
+
+    pub fn rejected_versions(&self) -> BTreeMap<u16, u64> {
+        self.rejected_versions
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn snapshot(&self) -> NodeStats {
+        let previous = *self.previous.read().unwrap_or_else(|e| e.into_inner());
+
+        NodeStats {
+            blocks_validated: previous.blocks_validated
+                + self.blocks_validated.load(Ordering::Relaxed),
+            bytes_relayed: previous.bytes_relayed + self.bytes_relayed.load(Ordering::Relaxed),
+            blocks_mined: previous.blocks_mined + self.blocks_mined.load(Ordering::Relaxed),
+            connections_reaped: self.connections_reaped.load(Ordering::Relaxed),
+            handshakes_rejected: self.rejected_versions().values().sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn adds_current_run_to_previous_totals() {
+        let counters = StatCounters::default();
+        counters.record_validated();
+        counters.record_relayed(100);
+
+        counters.resume(NodeStats {
+            blocks_validated: 10,
+            bytes_relayed: 1_000,
+            blocks_mined: 2,
+            connections_reaped: 5,
+            handshakes_rejected: 4,
+        });
+        counters.record_mined();
+        counters.record_reaped();
+        counters.record_rejected_version(1);
+        counters.record_rejected_version(1);
+
+        assert_eq!(
+            counters.snapshot(),
+            NodeStats {
+                blocks_validated: 11,
+                bytes_relayed: 1_100,
+                blocks_mined: 3,
+                // Not carried over from earlier runs
+                connections_reaped: 1,
+                handshakes_rejected: 2,
+            }
+        );
+        assert_eq!(counters.rejected_versions(), BTreeMap::from([(1, 2)]));
+    }
+}
@@ -0,0 +1,411 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::anyhow;
+use borsh::{BorshDeserialize, BorshSerialize};
+use corelib::{
+    block::Block,
+    blockchain::BlockChain,
+    config::{ChainParams, Network},
+};
+use hex::FromHex;
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+use crate::{misbehavior::Ban, stats::NodeStats, sync::SyncCheckpoint, webhooks::Delivery};
+
+const BLOCKS_FILE: &str = "blocks.dat";
+const CHECKPOINT_FILE: &str = "sync.bin";
+const WEBHOOK_QUEUE_FILE: &str = "webhooks.bin";
+const STATS_FILE: &str = "stats.bin";
+const BANS_FILE: &str = "bans.bin";
+const IDENTITY_FILE: &str = "identity.bin";
+const CHECKSUM_LEN: usize = 32;
+// Length of a block's encoding, in front of its checksum in the block store
+const LENGTH_LEN: usize = 4;
+
+// On-disk state of the node, kept in a single data directory. Every file
+// starts with the SHA-256 of its contents so corruption is caught on load.
+//
+// Blocks are the exception, they're appended to the block store as they're
+// connected so a new block costs a write of its own size. Every block there
+// is preceded by its length and checksum, and the chain is built again from
+// them on load.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    dir: PathBuf,
+    // Held while the block store is written, appends don't interleave
+    blocks: Arc<Mutex<()>>,
+}
+
+impl Storage {
+    pub async fn open(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).await?;
+
+        Ok(Self {
+            dir,
+            blocks: Arc::default(),
+        })
+    }
+
+    // Connects the stored blocks again under the network's rules, on a
+    // blocking worker
+    pub async fn load_chain(
+        &self,
+        network: Network,
+        params: ChainParams,
+    ) -> anyhow::Result<Option<BlockChain>> {
+        let blocks = self.load_blocks().await?;
+
+        tokio::task::spawn_blocking(move || rebuild_chain(blocks, network, params)).await?
+    }
+
+    // Blocks in the order they were stored. A block cut short by a crash
+    // mid-append is left out
+    pub async fn load_blocks(&self) -> anyhow::Result<Vec<Block>> {
+        let bytes = {
+            let _writing = self.blocks.lock().await;
+            match fs::read(self.dir.join(BLOCKS_FILE)).await {
+                Ok(bytes) => bytes,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        let mut blocks = Vec::new();
+        let mut records = &bytes[..];
+        while let Some((record, rest)) = split_record(records) {
+            let (checksum, contents) = record.split_at(CHECKSUM_LEN);
+            if Sha256::digest(contents).as_slice() != checksum {
+                return Err(anyhow!(
+                    "{BLOCKS_FILE} doesn't match its checksum at block {}",
+                    blocks.len()
+                ));
+            }
+            blocks.push(Block::from_bytes(contents)?);
+            records = rest;
+        }
+
+        Ok(blocks)
+    }
+
+    // Appends newly connected blocks to the block store, after their
+    // parents
+    pub async fn append_blocks(&self, blocks: &[Block]) -> anyhow::Result<()> {
+        let records = blocks
+            .iter()
+            .map(block_record)
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .concat();
+
+        let _writing = self.blocks.lock().await;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(BLOCKS_FILE))
+            .await?;
+        file.write_all(&records).await?;
+        file.sync_data().await?;
+
+        Ok(())
+    }
+
+    // Cuts off a block a crash left half written at the end of the block
+    // store, later blocks would be appended behind it otherwise
+    pub async fn recover_blocks(&self) -> anyhow::Result<()> {
+        let _writing = self.blocks.lock().await;
+        let path = self.dir.join(BLOCKS_FILE);
+        let bytes = match fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut records = &bytes[..];
+        while let Some((_, rest)) = split_record(records) {
+            records = rest;
+        }
+        if !records.is_empty() {
+            let file = fs::OpenOptions::new().write(true).open(&path).await?;
+            file.set_len((bytes.len() - records.len()) as u64).await?;
+            file.sync_all().await?;
+        }
+
+        Ok(())
+    }
+
+    // Replaces the block store with the blocks of the chain's best chain,
+    // e.g. when the stored ones rotted
+    pub async fn rewrite_blocks(&self, chain: &BlockChain) -> anyhow::Result<()> {
+        let records = (0..chain.height())
+            .filter_map(|height| chain.block(height))
+            .map(block_record)
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .concat();
+
+        let _writing = self.blocks.lock().await;
+        write_durably(&self.dir.join(BLOCKS_FILE), &records).await
+    }
+
+    // Drops the chain, the block download progress and the statistics
+    // counting its blocks so the chain is downloaded and validated again
+    pub async fn reindex(&self) -> anyhow::Result<()> {
+        for name in [BLOCKS_FILE, CHECKPOINT_FILE, STATS_FILE] {
+            match fs::remove_file(self.dir.join(name)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn load_checkpoint(&self) -> anyhow::Result<SyncCheckpoint> {
+        Ok(self.read(CHECKPOINT_FILE).await?.unwrap_or_default())
+    }
+
+    pub async fn save_checkpoint(&self, checkpoint: &SyncCheckpoint) -> anyhow::Result<()> {
+        self.write(CHECKPOINT_FILE, checkpoint).await
+    }
+
+    pub async fn load_stats(&self) -> anyhow::Result<NodeStats> {
+        Ok(self.read(STATS_FILE).await?.unwrap_or_default())
+    }
+
+    pub async fn save_stats(&self, stats: &NodeStats) -> anyhow::Result<()> {
+        self.write(STATS_FILE, stats).await
+    }
+
+    pub async fn load_bans(&self) -> anyhow::Result<Vec<Ban>> {
+        Ok(self.read(BANS_FILE).await?.unwrap_or_default())
+    }
+
+    pub async fn save_bans(&self, bans: &[Ban]) -> anyhow::Result<()> {
+        self.write(BANS_FILE, bans).await
+    }
+
+    // Secret key of the node's identity, kept across reindexing
+    pub async fn load_identity(&self) -> anyhow::Result<Option<[u8; 32]>> {
+        self.read(IDENTITY_FILE).await
+    }
+
+    // Only readable by the owner, the key lets anyone impersonate the node
+    pub async fn save_identity(&self, secret: &[u8; 32]) -> anyhow::Result<()> {
+        let contents = borsh::to_vec(secret)?;
+        let mut bytes = Sha256::digest(&contents).to_vec();
+        bytes.extend(contents);
+
+        write_secret(&self.dir.join(IDENTITY_FILE), &bytes).await
+    }
+
+    pub async fn load_deliveries(&self) -> anyhow::Result<VecDeque<Delivery>> {
+        Ok(self.read(WEBHOOK_QUEUE_FILE).await?.unwrap_or_default())
+    }
+
+    pub async fn save_deliveries(&self, deliveries: &VecDeque<Delivery>) -> anyhow::Result<()> {
+        self.write(WEBHOOK_QUEUE_FILE, deliveries).await
+    }
+
+    async fn read<T: BorshDeserialize>(&self, name: &str) -> anyhow::Result<Option<T>> {
+        let bytes = match fs::read(self.dir.join(name)).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        if bytes.len() < CHECKSUM_LEN {
+            return Err(anyhow!("{name} is truncated"));
+        }
+        let (checksum, contents) = bytes.split_at(CHECKSUM_LEN);
+        if Sha256::digest(contents).as_slice() != checksum {
+            return Err(anyhow!("{name} doesn't match its checksum"));
+        }
+
+        Ok(Some(T::try_from_slice(contents)?))
+    }
+
+    async fn write<T: BorshSerialize + ?Sized>(&self, name: &str, value: &T) -> anyhow::Result<()> {
+        let contents = borsh::to_vec(value)?;
+        let mut bytes = Sha256::digest(&contents).to_vec();
+        bytes.extend(contents);
+
+        write_durably(&self.dir.join(name), &bytes).await
+    }
+}
+
+// Length and checksum of the block followed by its encoding
+fn block_record(block: &Block) -> anyhow::Result<Vec<u8>> {
+    let contents = block.to_bytes();
+    let length = u32::try_from(contents.len())?;
+
+    let mut record = length.to_le_bytes().to_vec();
+    record.extend(Sha256::digest(&contents));
+    record.extend(contents);
+    Ok(record)
+}
+
+// The checksum and encoding of the first block in the records and the
+// records after it, `None` if there's no whole block left
+fn split_record(records: &[u8]) -> Option<(&[u8], &[u8])> {
+    let length = u32::from_le_bytes(records.get(..LENGTH_LEN)?.try_into().ok()?) as usize;
+    let end = (LENGTH_LEN + CHECKSUM_LEN).checked_add(length)?;
+    if records.len() < end {
+        return None;
+    }
+
+    Some((&records[LENGTH_LEN..end], &records[end..]))
+}
+
+// Connects blocks in the order they were stored. Blocks connected one right
+// after the other may have been appended the other way around, a block is
+// held back until its parent is in. Blocks whose parent never was stored are
+// dropped, they're downloaded again
+fn rebuild_chain(
+    mut blocks: Vec<Block>,
+    network: Network,
+    params: ChainParams,
+) -> anyhow::Result<Option<BlockChain>> {
+    let Some(genesis) = blocks.iter().position(|block| block.index() == 0) else {
+        return Ok(None);
+    };
+    let mut chain = BlockChain::new(blocks.remove(genesis), network)?.with_params(params);
+
+    let mut waiting = Vec::new();
+    for block in blocks {
+        waiting.push(block);
+
+        while let Some(position) = waiting.iter().position(|block| {
+            <[u8; 32]>::from_hex(block.previous_hash()).is_ok_and(|parent| chain.contains(&parent))
+        }) {
+            let block = waiting.remove(position);
+            if chain.contains(&block.hash()) {
+                continue;
+            }
+            // The blocks were judged against the clock when they were
+            // connected
+            chain
+                .add_block_at(block, u128::MAX)
+                .map_err(|e| anyhow!("stored block doesn't connect: {e}"))?;
+        }
+    }
+
+    Ok(Some(chain))
+}
+
+// Writes to a temporary file first so a crash never leaves a torn file. The
+// contents are synced before the rename and the directory after it, without
+// that the rename could reach the disk ahead of the data
+pub async fn write_durably(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    write_file(path, contents, 0o666).await
+}
+
+// Same as `write_durably` for keys, on unix only the owner can read them
+pub async fn write_secret(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    write_file(path, contents, 0o600).await
+}
+
+async fn write_file(path: &Path, contents: &[u8], mode: u32) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    // A leftover temporary file would keep its old mode
+    match fs::remove_file(&tmp).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+    let mut file = options.open(&tmp).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(&tmp, path).await?;
+
+    // Directories can't be opened for syncing on windows
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::File::open(dir).await?.sync_all().await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_the_identity_private() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let storage = Storage::open(&dir).await.unwrap();
+
+        storage.save_identity(&[7u8; 32]).await.unwrap();
+        assert_eq!(storage.load_identity().await.unwrap(), Some([7u8; 32]));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let metadata = std::fs::metadata(dir.join(IDENTITY_FILE)).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn appends_blocks_and_recovers_torn_ones() {
+        use corelib::block::BlockBuilder;
+
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let storage = Storage::open(&dir).await.unwrap();
+        let (network, params) = (Network::Regtest, ChainParams::default());
+        assert!(storage.load_chain(network, params).await.unwrap().is_none());
+
+        let genesis = Block::genesis(network);
+        let first = BlockBuilder::new(1, hex::encode(genesis.hash()), 1, [1u8; 32])
+            .build()
+            .unwrap();
+        let second = BlockBuilder::new(2, hex::encode(first.hash()), 1, [1u8; 32])
+            .build()
+            .unwrap();
+
+        // Blocks connected one after the other may be appended the other way
+        // around
+        storage
+            .append_blocks(std::slice::from_ref(&genesis))
+            .await
+            .unwrap();
+        storage
+            .append_blocks(std::slice::from_ref(&second))
+            .await
+            .unwrap();
+        storage
+            .append_blocks(std::slice::from_ref(&first))
+            .await
+            .unwrap();
+        let chain = storage.load_chain(network, params).await.unwrap().unwrap();
+        assert_eq!(chain.tip(), &second);
+
+        // A crash mid-append leaves part of a block behind
+        let path = dir.join(BLOCKS_FILE);
+        let whole = std::fs::metadata(&path).unwrap().len();
+        let record = block_record(&first).unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        std::io::Write::write_all(&mut file, &record[..record.len() / 2]).unwrap();
+        assert_eq!(storage.load_blocks().await.unwrap().len(), 3);
+
+        storage.recover_blocks().await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), whole);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,485 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use corelib::{
+    config::{ChainParams, Network},
+    deployment::Deployments,
+    net::protocol::SupportedVersions,
+    rng::Entropy,
+};
+use tokio::task::JoinSet;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::{
+    audit::{AuditAction, AuditLog},
+    dial::DialConfig,
+    explorer::Explorer,
+    limits::ConnectionLimits,
+    memory::MemoryBudget,
+    miner::{Miner, MiningConfig},
+    misbehavior::BanConfig,
+    node::Node,
+    relay::{LocalRelayConfig, SeenCacheConfig},
+    rpc::NodeRpc,
+    storage::Storage,
+    webhooks::WebhookDispatcher,
+};
+
+const MEMPOOL_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+const DOWNLOAD_EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+const MEMORY_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+// How often a node without peers goes back to its seeds
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(60);
+// How often the stored chain is read back and checked, by default
+const DEFAULT_SCRUB_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+// Everything needed to run the node of one network
+#[derive(Debug, Clone)]
+pub struct ChainConfig {
+    pub network: Network,
+    // Address peers connect to, loopback unless set
+    pub bind: IpAddr,
+    pub port: u16,
+    // Port of the JSON-RPC server, which only accepts local connections
+    pub rpc_port: u16,
+    // Port of the read-only block explorer API, also local only, off unless
+    // set
+    pub explorer_port: Option<u16>,
+    pub data_dir: PathBuf,
+    // `host:port` of the peers first connected to
+    pub seeds: Vec<String>,
+    // JSON file listing the webhooks notified of the chain's events
+    pub webhooks: Option<PathBuf>,
+    // Drop the stored chain and download it again
+    pub reindex: bool,
+    // Encrypt the connections with peers that support it
+    pub encrypted_transport: bool,
+    // Lowest protocol version peers may speak
+    pub min_peer_version: u16,
+    // Delays the first announcement of transactions submitted over RPC
+    pub local_relay: Option<LocalRelayConfig>,
+    // How many transactions and blocks from peers are remembered to drop
+    // repeats, and for how long
+    pub seen_cache: SeenCacheConfig,
+    // Misbehavior score at which peers are banned and for how long
+    pub bans: BanConfig,
+    // Parallel dials, seed name caching and cooldown of failed addresses
+    pub dial: DialConfig,
+    // Inbound connection slots and the message rate of every connection
+    pub limits: ConnectionLimits,
+    // Rules added after launch and when they activate
+    pub deployments: Deployments,
+    // Consensus parameters overriding the defaults, checked before the
+    // chain starts
+    pub params: ChainParams,
+    pub memory_budget: MemoryBudget,
+    // How often the stored chain is checked for bit-rot, never if unset
+    pub scrub_interval: Option<Duration>,
+    // Mines blocks on top of the best tip, if configured
+    pub mining: Option<MiningConfig>,
+    // Seeds the node's randomness to replay a simulation, never on mainnet
+    pub rng_seed: Option<u64>,
+}
+
+impl ChainConfig {
+    // Config with the network's default ports, mainnet keeps its data
+    // directly in `data_dir` and other networks in a subdirectory
+    pub fn new(network: Network, data_dir: impl Into<PathBuf>) -> Self {
+        let data_dir = data_dir.into();
+        let data_dir = match network {
+            Network::Mainnet => data_dir,
+            _ => data_dir.join(network.name()),
+        };
+
+        Self {
+            network,
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: network.default_port(),
+            rpc_port: network.default_rpc_port(),
+            explorer_port: None,
+            data_dir,
+            seeds: Vec::new(),
+            webhooks: None,
+            reindex: false,
+            encrypted_transport: false,
+            min_peer_version: SupportedVersions::One.as_u16(),
+            local_relay: None,
+            seen_cache: SeenCacheConfig::default(),
+            bans: BanConfig::default(),
+            dial: DialConfig::default(),
+            limits: ConnectionLimits::default(),
+            deployments: Deployments::for_network(network),
+            params: ChainParams::default(),
+            memory_budget: MemoryBudget::default(),
+            scrub_interval: Some(DEFAULT_SCRUB_INTERVAL),
+            mining: None,
+            rng_seed: None,
+        }
+    }
+}
+
+// Runs the nodes of several networks in one process.
+//
+// Every chain gets its own node, storage, mempool and peers so the networks
+// stay isolated, their logs are told apart by the `chain` span.
+#[derive(Debug, Default)]
+pub struct Supervisor {
+    chains: Vec<ChainConfig>,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn chain(mut self, config: ChainConfig) -> anyhow::Result<Self> {
+        if config.port == config.rpc_port {
+            return Err(anyhow!(
+                "Chain {} uses port {} for both peers and RPC",
+                config.network,
+                config.port
+            ));
+        }
+        if let Some(port) = config
+            .explorer_port
+            .filter(|port| [config.port, config.rpc_port].contains(port))
+        {
+            return Err(anyhow!(
+                "Chain {} uses port {port} for both the explorer and peers or RPC",
+                config.network
+            ));
+        }
+
+        // Anyone knowing the seed could predict the node's keys
+        if config.network == Network::Mainnet && config.rng_seed.is_some() {
+            return Err(anyhow!("Chain mainnet can't run with seeded randomness"));
+        }
+
+        config
+            .params
+            .validate()
+            .map_err(|e| anyhow!("Chain {}: {e}", config.network))?;
+
+        let ports: Vec<u16> = [config.port, config.rpc_port]
+            .into_iter()
+            .chain(config.explorer_port)
+            .collect();
+        if let Some(existing) = self.chains.iter().find(|chain| {
+            chain.network == config.network
+                || ports.contains(&chain.port)
+                || ports.contains(&chain.rpc_port)
+                || chain
+                    .explorer_port
+                    .is_some_and(|port| ports.contains(&port))
+                || chain.data_dir == config.data_dir
+        }) {
+            return Err(anyhow!(
+                "Chain {} conflicts with the {} chain, networks need their own ports and data directory",
+                config.network,
+                existing.network
+            ));
+        }
+
+        self.chains.push(config);
+        Ok(self)
+    }
+
+    // Starts every chain and runs until one of them fails
+    pub async fn run(self) -> anyhow::Result<()> {
+        if self.chains.is_empty() {
+            return Err(anyhow!("No chain to run"));
+        }
+
+        let mut chains = JoinSet::new();
+        for config in self.chains {
+            let span = info_span!("chain", network = %config.network);
+            start_chain(&mut chains, config).instrument(span).await?;
+        }
+
+        while let Some(result) = chains.join_next().await {
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    error!("Chain stopped: {e}");
+                    return Err(e);
+                }
+                Err(e) => return Err(anyhow!("Chain task failed: {e}")),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Restores the chain's node from its storage and spawns its listener and
+// background tasks, a node in safe mode only gets the RPC server
+async fn start_chain(
+    tasks: &mut JoinSet<anyhow::Result<()>>,
+    config: ChainConfig,
+) -> anyhow::Result<()> {
+    let (node, mut responses) = Node::new(config.port);
+    let node = node
+        .with_listen_address(SocketAddr::new(config.bind, config.port))
+        .with_network(config.network)
+        .with_chain_params(config.params)
+        .with_deployments(config.deployments.clone())
+        .with_memory_budget(config.memory_budget)
+        .with_seen_cache(config.seen_cache)
+        .with_ban_config(config.bans)
+        .with_dial_config(config.dial)
+        .with_limits(config.limits)
+        .with_encrypted_transport(config.encrypted_transport)
+        .with_min_peer_version(config.min_peer_version);
+    let node = match config.rng_seed {
+        Some(seed) => {
+            warn!("Randomness is seeded, keys and nonces are predictable");
+            node.with_entropy(Entropy::seeded(seed))
+        }
+        None => node,
+    };
+    let storage = Storage::open(&config.data_dir).await?;
+    let audit = AuditLog::open(&config.data_dir).await?;
+    if config.reindex {
+        info!("Reindexing, the chain is downloaded again from the peers");
+        audit.record(AuditAction::Reindex).await?;
+        storage.reindex().await?;
+    }
+    let node = node
+        .with_audit_log(audit)
+        .with_storage(storage.clone())
+        .await?;
+
+    // Only the RPC server and explorer run so the chain can still be
+    // inspected, nothing touches the corrupt storage until the node is
+    // reindexed
+    if node.safe_mode().is_some() {
+        let rpc = NodeRpc::new(node.clone());
+        tasks.spawn(rpc.serve(config.rpc_port).in_current_span());
+        if let Some(port) = config.explorer_port {
+            tasks.spawn(Explorer::new(node.clone()).serve(port).in_current_span());
+        }
+
+        warn!(
+            "Starting {} chain in safe mode, RPC on port {}, with data in {}",
+            config.network,
+            config.rpc_port,
+            config.data_dir.display()
+        );
+        return Ok(());
+    }
+    let (mut node, listener) = node.listen().await?;
+
+    if let Some(path) = config.webhooks.as_ref() {
+        let hooks = WebhookDispatcher::parse_config(&tokio::fs::read_to_string(path).await?)?;
+        let webhooks = WebhookDispatcher::new(hooks, Some(storage)).await?;
+
+        let dispatcher = webhooks.clone();
+        tasks.spawn(
+            async move {
+                dispatcher.run().await;
+                Ok(())
+            }
+            .in_current_span(),
+        );
+        node = node.with_webhooks(webhooks);
+    }
+
+    if let Some(local_relay) = config.local_relay {
+        node = node.with_local_relay(local_relay);
+
+        let relay = node.clone();
+        tasks.spawn(
+            async move {
+                relay.relay_local_transactions().await;
+                Ok(())
+            }
+            .in_current_span(),
+        );
+    }
+
+    // Responses from outbound peers are handled separately from the listener
+    let handler = node.clone();
+    tasks.spawn(
+        async move {
+            while let Some((address, response)) = responses.recv().await {
+                handler.handle_response(address, response).await;
+            }
+            Ok(())
+        }
+        .in_current_span(),
+    );
+
+    let pipeline = node.clone();
+    tasks.spawn(
+        async move {
+            pipeline.run_sync_pipeline().await;
+            Ok(())
+        }
+        .in_current_span(),
+    );
+
+    let expiry = node.clone();
+    tasks.spawn(
+        async move {
+            expiry.expire_transactions(MEMPOOL_EXPIRY_INTERVAL).await;
+            Ok(())
+        }
+        .in_current_span(),
+    );
+
+    let downloads = node.clone();
+    tasks.spawn(
+        async move {
+            downloads.expire_downloads(DOWNLOAD_EXPIRY_INTERVAL).await;
+            Ok(())
+        }
+        .in_current_span(),
+    );
+
+    let templates = node.clone();
+    tasks.spawn(
+        async move {
+            templates.watch_templates().await;
+            Ok(())
+        }
+        .in_current_span(),
+    );
+
+    let stats = node.clone();
+    tasks.spawn(
+        async move {
+            stats.persist_stats(STATS_INTERVAL).await;
+            Ok(())
+        }
+        .in_current_span(),
+    );
+
+    if let Some(interval) = config.scrub_interval {
+        let scrubber = node.clone();
+        tasks.spawn(
+            async move {
+                scrubber.scrub_storage(interval).await;
+                Ok(())
+            }
+            .in_current_span(),
+        );
+    }
+
+    if let Some(mining) = config.mining {
+        let miner = node.clone();
+        tasks.spawn(
+            async move {
+                miner
+                    .mine(
+                        Miner::new(mining.workers),
+                        mining.address,
+                        &mining.coinbase_tag,
+                    )
+                    .await;
+                Ok(())
+            }
+            .in_current_span(),
+        );
+    }
+
+    let memory = node.clone();
+    tasks.spawn(
+        async move {
+            memory.report_memory(MEMORY_REPORT_INTERVAL).await;
+            Ok(())
+        }
+        .in_current_span(),
+    );
+
+    let rpc = NodeRpc::new(node.clone());
+    let rpc_port = config.rpc_port;
+    tasks.spawn(rpc.serve(rpc_port).in_current_span());
+    if let Some(port) = config.explorer_port {
+        tasks.spawn(Explorer::new(node.clone()).serve(port).in_current_span());
+    }
+
+    let bootstrap = node.clone();
+    let seeds = config.seeds;
+    tasks.spawn(
+        async move {
+            bootstrap.bootstrap(&seeds).await;
+            bootstrap.keep_connected(&seeds, RECONNECT_INTERVAL).await;
+            Ok(())
+        }
+        .in_current_span(),
+    );
+
+    info!(
+        "Starting {} chain on {}, RPC on port {}, with data in {}",
+        config.network,
+        node.listen_address(),
+        config.rpc_port,
+        config.data_dir.display()
+    );
+    tasks.spawn(async move { node.run(listener).await }.in_current_span());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keeps_networks_isolated() {
+        let dir = std::env::temp_dir().join(format!("aurelius-{}", uuid::Uuid::new_v4()));
+
+        let testnet = ChainConfig::new(Network::Testnet, &dir);
+        let regtest = ChainConfig::new(Network::Regtest, &dir);
+        assert_eq!(testnet.data_dir, dir.join("testnet"));
+        assert_ne!(testnet.port, regtest.port);
+        assert_ne!(testnet.rpc_port, regtest.rpc_port);
+
+        let supervisor = Supervisor::new()
+            .chain(testnet.clone())
+            .unwrap()
+            .chain(regtest)
+            .unwrap();
+        assert!(supervisor.chain(testnet).is_err());
+
+        let mut clashing = ChainConfig::new(Network::Mainnet, &dir);
+        clashing.port = Network::Testnet.default_port();
+        assert!(Supervisor::new()
+            .chain(ChainConfig::new(Network::Testnet, &dir))
+            .unwrap()
+            .chain(clashing)
+            .is_err());
+
+        let mut clashing = ChainConfig::new(Network::Mainnet, &dir);
+        clashing.rpc_port = Network::Testnet.default_port();
+        assert!(Supervisor::new()
+            .chain(ChainConfig::new(Network::Testnet, &dir))
+            .unwrap()
+            .chain(clashing)
+            .is_err());
+
+        let mut clashing = ChainConfig::new(Network::Mainnet, &dir);
+        clashing.explorer_port = Some(Network::Testnet.default_rpc_port());
+        assert!(Supervisor::new()
+            .chain(ChainConfig::new(Network::Testnet, &dir))
+            .unwrap()
+            .chain(clashing)
+            .is_err());
+        let mut clashing = ChainConfig::new(Network::Regtest, &dir);
+        clashing.explorer_port = Some(clashing.rpc_port);
+        assert!(Supervisor::new().chain(clashing).is_err());
+
+        let mut seeded = ChainConfig::new(Network::Mainnet, &dir);
+        seeded.rng_seed = Some(7);
+        assert!(Supervisor::new().chain(seeded).is_err());
+
+        // Broken parameters stop the chain from starting
+        let mut broken = ChainConfig::new(Network::Regtest, &dir);
+        broken.params.halving_interval = 0;
+        assert!(Supervisor::new().chain(broken).is_err());
+    }
+}
@@ -0,0 +1,537 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use corelib::{block::BlockHeader, blockchain::block_work};
+
+// Range of block heights requested from peers in one go, `end` is exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Window {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Window {
+    pub fn heights(&self) -> std::ops::Range<u64> {
+        self.start..self.end
+    }
+}
+
+// Progress of the initial block download.
+//
+// The checkpoint is persisted after every connected block, so a node restarted
+// mid-sync resumes from the last validated height and only re-requests the
+// windows that were still in flight.
+#[derive(Debug, Clone, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct SyncCheckpoint {
+    // Number of blocks validated and connected to the chain
+    validated: u64,
+    // Windows requested from peers that weren't fully received yet
+    in_flight: Vec<Window>,
+}
+
+impl SyncCheckpoint {
+    pub fn validated(&self) -> u64 {
+        self.validated
+    }
+
+    pub fn in_flight(&self) -> &[Window] {
+        &self.in_flight
+    }
+
+    // Reserves the next window of at most `size` heights after everything
+    // validated or already in flight, heights from `limit` on aren't reserved
+    pub fn next_window(&mut self, size: u64, limit: u64) -> Option<Window> {
+        let start = self
+            .in_flight
+            .iter()
+            .map(|window| window.end)
+            .max()
+            .unwrap_or(self.validated)
+            .max(self.validated);
+
+        if start >= limit {
+            return None;
+        }
+
+        let window = Window {
+            start,
+            end: (start + size).min(limit),
+        };
+        self.in_flight.push(window);
+
+        Some(window)
+    }
+
+    // Records that the chain now holds `height` blocks, windows below that
+    // are done and trimmed from the in-flight set
+    pub fn set_validated(&mut self, height: u64) {
+        self.validated = height;
+
+        self.in_flight.retain(|window| window.end > height);
+        for window in self.in_flight.iter_mut() {
+            window.start = window.start.max(height);
+        }
+    }
+
+    // Heights still to be downloaded after a restart
+    pub fn missing_heights(&self) -> impl Iterator<Item = u64> + '_ {
+        self.in_flight.iter().flat_map(Window::heights)
+    }
+}
+
+// Where headers received from a peer attach, see `SyncState::attachment`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attachment {
+    // On top of this many of the best headers, none being the chain tip
+    Headers(usize),
+    // On top of the peer's branch
+    Branch,
+}
+
+// Headers first block download.
+//
+// Headers of the peers' chains are fetched and checked to link up first, the
+// blocks of the branch with the most work are then requested in windows
+// spread over every connected peer and checked against the headers as they
+// arrive.
+#[derive(Debug, Default)]
+pub struct SyncState {
+    checkpoint: SyncCheckpoint,
+    // Linked headers above the chain tip on the branch with the most work,
+    // lowest first, along with the peer each came from
+    headers: Vec<(BlockHeader, SocketAddr)>,
+    // Headers peers sent on branches with less work, kept for their later
+    // headers to extend. Each leaves the best headers above the given number
+    // of them
+    branches: HashMap<SocketAddr, (usize, Vec<BlockHeader>)>,
+    // Peer each in-flight window was requested from, keyed by window end
+    requested: HashMap<u64, SocketAddr>,
+}
+
+impl SyncState {
+    pub fn new(checkpoint: SyncCheckpoint) -> Self {
+        Self {
+            checkpoint,
+            ..Default::default()
+        }
+    }
+
+    pub fn checkpoint(&self) -> &SyncCheckpoint {
+        &self.checkpoint
+    }
+
+    // Height the chain reaches once every known header is downloaded
+    pub fn target_height(&self) -> u64 {
+        self.headers
+            .last()
+            .map_or(self.checkpoint.validated, |(header, _)| header.index + 1)
+    }
+
+    // Height to ask a peer for headers from, above its branch if it has one
+    pub fn header_height(&self, peer: &SocketAddr) -> u64 {
+        self.branches
+            .get(peer)
+            .and_then(|(_, branch)| branch.last())
+            .map_or(self.target_height(), |header| header.index + 1)
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.checkpoint.validated >= self.target_height() && self.checkpoint.in_flight.is_empty()
+    }
+
+    // Where headers from a peer starting with `first` attach: on top of its
+    // branch, the best headers or the chain tip. Without a tip they start at
+    // the genesis block
+    pub fn attachment(
+        &self,
+        peer: &SocketAddr,
+        tip: Option<&BlockHeader>,
+        first: &BlockHeader,
+    ) -> Result<Attachment, String> {
+        let extends = |parent: &BlockHeader| first.previous_hash == hex::encode(parent.hash);
+
+        let branch = self
+            .branches
+            .get(peer)
+            .and_then(|(_, branch)| branch.last());
+        if branch.is_some_and(extends) {
+            return Ok(Attachment::Branch);
+        }
+
+        if let Some(position) = self.headers.iter().rposition(|(header, _)| extends(header)) {
+            return Ok(Attachment::Headers(position + 1));
+        }
+
+        match tip {
+            Some(tip) if extends(tip) => Ok(Attachment::Headers(0)),
+            None if first.index == 0 => Ok(Attachment::Headers(0)),
+            _ => Err(format!("header {} doesn't link up", first.index)),
+        }
+    }
+
+    // Headers below an attachment of the peer's, latest first. The headers
+    // attaching there are checked against them
+    pub fn previous(
+        &self,
+        peer: &SocketAddr,
+        attachment: Attachment,
+    ) -> impl Iterator<Item = &BlockHeader> + '_ {
+        let (kept, branch) = match attachment {
+            Attachment::Headers(kept) => (kept, &[][..]),
+            Attachment::Branch => self
+                .branches
+                .get(peer)
+                .map_or((0, &[][..]), |(kept, branch)| (*kept, &branch[..])),
+        };
+
+        branch
+            .iter()
+            .rev()
+            .chain(self.headers[..kept].iter().rev().map(|(header, _)| header))
+    }
+
+    // Adds checked headers from a peer where they attach. They become the
+    // best headers if their branch has more work, see `check_headers`.
+    // Returns whether they did
+    pub fn add_headers(
+        &mut self,
+        peer: SocketAddr,
+        attachment: Attachment,
+        headers: Vec<BlockHeader>,
+    ) -> bool {
+        let (kept, mut branch) = match attachment {
+            Attachment::Headers(kept) => (kept, Vec::new()),
+            Attachment::Branch => self.branches.remove(&peer).unwrap_or_default(),
+        };
+        branch.extend(headers);
+
+        let replaced = self.headers[kept..].iter().map(|(header, _)| header);
+        if work(branch.iter()) <= work(replaced) {
+            self.branches.insert(peer, (kept, branch));
+            return false;
+        }
+
+        if let Some(first) = branch.first() {
+            self.drop_windows(first.index);
+        }
+        self.headers.truncate(kept);
+        self.headers
+            .extend(branch.into_iter().map(|header| (header, peer)));
+        self.branches.retain(|_, (attached, _)| *attached <= kept);
+
+        true
+    }
+
+    // Drops the best headers from `height` on, e.g. when the block at
+    // `height` turned out to be invalid. Returns the peer that sent them
+    pub fn drop_headers(&mut self, height: u64) -> Option<SocketAddr> {
+        let position = self
+            .headers
+            .iter()
+            .position(|(header, _)| header.index == height)?;
+        let (_, source) = self.headers[position];
+
+        self.drop_windows(height);
+        self.headers.truncate(position);
+        self.branches
+            .retain(|_, (attached, _)| *attached <= position);
+
+        Some(source)
+    }
+
+    // Hands the window holding `height` out again, e.g. when the block the
+    // peer sent for it doesn't match the headers
+    pub fn retry(&mut self, height: u64) {
+        let window = self
+            .checkpoint
+            .in_flight
+            .iter()
+            .find(|window| window.heights().contains(&height));
+        if let Some(window) = window {
+            self.requested.remove(&window.end);
+        }
+    }
+
+    // Gives up the in-flight windows reaching `height` or above, their
+    // blocks are requested again for the new headers
+    fn drop_windows(&mut self, height: u64) {
+        self.checkpoint
+            .in_flight
+            .retain(|window| window.end <= height);
+        let in_flight = &self.checkpoint.in_flight;
+        self.requested
+            .retain(|end, _| in_flight.iter().any(|window| window.end == *end));
+    }
+
+    // Hash the block at `height` must have according to the headers
+    pub fn expected_hash(&self, height: u64) -> Option<[u8; 32]> {
+        let (first, _) = self.headers.first()?;
+        let offset = height.checked_sub(first.index)?;
+
+        self.headers
+            .get(offset as usize)
+            .map(|(header, _)| header.hash)
+    }
+
+    // Hashes of the window's blocks according to the headers, `None` unless
+    // every height is covered
+    pub fn window_hashes(&self, window: Window) -> Option<Vec<[u8; 32]>> {
+        window
+            .heights()
+            .map(|height| self.expected_hash(height))
+            .collect()
+    }
+
+    // Hands out block windows to the connected peers round-robin. Windows
+    // left unassigned by a restart or a disconnected peer are handed out
+    // again before new ones are reserved.
+    pub fn schedule(
+        &mut self,
+        peers: &[SocketAddr],
+        window_size: u64,
+        max_in_flight: usize,
+    ) -> Vec<(SocketAddr, Window)> {
+        if peers.is_empty() {
+            return Vec::new();
+        }
+
+        self.requested.retain(|_, peer| peers.contains(peer));
+
+        let mut unassigned: Vec<Window> = self
+            .checkpoint
+            .in_flight
+            .iter()
+            .filter(|window| !self.requested.contains_key(&window.end))
+            .copied()
+            .collect();
+
+        let limit = self.target_height();
+        while self.checkpoint.in_flight.len() < max_in_flight {
+            match self.checkpoint.next_window(window_size, limit) {
+                Some(window) => unassigned.push(window),
+                None => break,
+            }
+        }
+
+        unassigned
+            .into_iter()
+            .zip(peers.iter().cycle())
+            .map(|(window, peer)| {
+                self.requested.insert(window.end, *peer);
+                (*peer, window)
+            })
+            .collect()
+    }
+
+    // Records that the chain now holds `height` blocks
+    pub fn set_validated(&mut self, height: u64) {
+        self.checkpoint.set_validated(height);
+
+        let connected = self
+            .headers
+            .iter()
+            .take_while(|(header, _)| header.index < height)
+            .count();
+        self.headers.drain(..connected);
+        self.branches
+            .retain(|_, (attached, _)| *attached >= connected);
+        for (attached, _) in self.branches.values_mut() {
+            *attached -= connected;
+        }
+
+        let in_flight = &self.checkpoint.in_flight;
+        self.requested
+            .retain(|end, _| in_flight.iter().any(|window| window.end == *end));
+    }
+}
+
+// Work of the blocks of the headers
+fn work<'a>(headers: impl Iterator<Item = &'a BlockHeader>) -> u128 {
+    headers
+        .map(|header| block_work(header.difficulty))
+        .fold(0, u128::saturating_add)
+}
+
+// Checks that the headers hash to their claimed hashes and meet their
+// targets. Needs no state, so it runs apart from the rest of the download
+pub fn check_proof_of_work(headers: &[BlockHeader]) -> Result<(), String> {
+    match headers.iter().find(|header| !header.is_valid()) {
+        Some(header) => Err(format!(
+            "header {} has an invalid proof of work",
+            header.index
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use corelib::block::BlockHeader;
+
+    use super::*;
+
+    fn headers(start: u64, count: u64, previous: Option<&BlockHeader>) -> Vec<BlockHeader> {
+        branch(start, count, previous, 0)
+    }
+
+    // Headers differing from the ones of other branches by their version
+    fn branch(
+        start: u64,
+        count: u64,
+        previous: Option<&BlockHeader>,
+        version: u32,
+    ) -> Vec<BlockHeader> {
+        let mut previous_hash = previous.map_or([0u8; 32], |p| p.hash);
+
+        (start..start + count)
+            .map(|index| {
+                let mut header = BlockHeader {
+                    index,
+                    version,
+                    timestamp: 0,
+                    previous_hash: hex::encode(previous_hash),
+                    merkle_root: [0u8; 32],
+                    witness_root: [0u8; 32],
+                    nonce: 0,
+                    difficulty: 1,
+                    hash: [0u8; 32],
+                };
+                header.hash = header.calculate_hash();
+                while !header.meets_target() {
+                    header.nonce += 1;
+                    header.hash = header.calculate_hash();
+                }
+                previous_hash = header.hash;
+                header
+            })
+            .collect()
+    }
+
+    #[test]
+    fn resumes_from_validated_height() {
+        let mut checkpoint = SyncCheckpoint::default();
+
+        assert_eq!(
+            checkpoint.next_window(4, 10),
+            Some(Window { start: 0, end: 4 })
+        );
+        assert_eq!(
+            checkpoint.next_window(4, 10),
+            Some(Window { start: 4, end: 8 })
+        );
+
+        checkpoint.set_validated(6);
+        assert_eq!(checkpoint.in_flight(), &[Window { start: 6, end: 8 }]);
+
+        let restored =
+            SyncCheckpoint::try_from_slice(&borsh::to_vec(&checkpoint).unwrap()).unwrap();
+        assert_eq!(restored.validated(), 6);
+        assert_eq!(restored.missing_heights().collect::<Vec<_>>(), vec![6, 7]);
+
+        checkpoint.set_validated(8);
+        assert!(checkpoint.in_flight().is_empty());
+        assert_eq!(
+            checkpoint.next_window(4, 10),
+            Some(Window { start: 8, end: 10 })
+        );
+        assert_eq!(checkpoint.next_window(4, 10), None);
+    }
+
+    #[test]
+    fn spreads_windows_over_peers() {
+        let mut state = SyncState::default();
+        let peer = "127.0.0.1:1".parse().unwrap();
+        let batch = headers(0, 5, None);
+        assert_eq!(
+            state.attachment(&peer, None, &batch[0]),
+            Ok(Attachment::Headers(0))
+        );
+        assert!(state.add_headers(peer, Attachment::Headers(0), batch.clone()));
+        assert_eq!(state.target_height(), 5);
+        assert_eq!(state.expected_hash(3), Some(batch[3].hash));
+        assert_eq!(
+            state.window_hashes(Window { start: 1, end: 3 }),
+            Some(vec![batch[1].hash, batch[2].hash])
+        );
+        assert_eq!(state.window_hashes(Window { start: 4, end: 6 }), None);
+
+        // Headers that don't continue the known ones are rejected
+        let unlinked = headers(7, 1, None);
+        assert!(state.attachment(&peer, None, &unlinked[0]).is_err());
+
+        let mut forged = headers(5, 1, batch.last());
+        assert!(check_proof_of_work(&forged).is_ok());
+        forged[0].nonce += 1;
+        assert!(check_proof_of_work(&forged).is_err());
+
+        let peers = [
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        ];
+        let requests = state.schedule(&peers, 2, 8);
+        assert_eq!(
+            requests,
+            vec![
+                (peers[0], Window { start: 0, end: 2 }),
+                (peers[1], Window { start: 2, end: 4 }),
+                (peers[0], Window { start: 4, end: 5 }),
+            ]
+        );
+        assert!(state.schedule(&peers, 2, 8).is_empty());
+
+        // Windows of a peer that went away are requested from another one
+        state.set_validated(2);
+        let requests = state.schedule(&peers[..1], 2, 8);
+        assert_eq!(requests, vec![(peers[0], Window { start: 2, end: 4 })]);
+
+        state.set_validated(5);
+        assert!(state.is_synced());
+    }
+
+    #[test]
+    fn follows_the_branch_with_the_most_work() {
+        let mut state = SyncState::default();
+        let (honest, forger) = (
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+        );
+        let tip = headers(0, 1, None).remove(0);
+
+        let forged = branch(1, 4, Some(&tip), 1);
+        state.add_headers(forger, Attachment::Headers(0), forged.clone());
+        state.schedule(&[forger], 2, 8);
+        assert_eq!(state.expected_hash(4), Some(forged[3].hash));
+
+        // A shorter branch is kept aside until the peer's later headers give
+        // it more work
+        let first = headers(1, 3, Some(&tip));
+        let attachment = state.attachment(&honest, Some(&tip), &first[0]).unwrap();
+        assert_eq!(attachment, Attachment::Headers(0));
+        assert!(!state.add_headers(honest, attachment, first.clone()));
+        assert_eq!(state.header_height(&honest), 4);
+        assert_eq!(state.expected_hash(1), Some(forged[0].hash));
+
+        let second = headers(4, 2, first.last());
+        let attachment = state.attachment(&honest, Some(&tip), &second[0]).unwrap();
+        assert_eq!(attachment, Attachment::Branch);
+        assert_eq!(
+            state
+                .previous(&honest, attachment)
+                .map(|header| header.index)
+                .collect::<Vec<_>>(),
+            vec![3, 2, 1]
+        );
+        assert!(state.add_headers(honest, attachment, second.clone()));
+        assert_eq!(state.target_height(), 6);
+        assert_eq!(state.expected_hash(1), Some(first[0].hash));
+        assert_eq!(state.expected_hash(5), Some(second[1].hash));
+
+        // The windows of the forged blocks are requested again
+        assert!(state.checkpoint().in_flight().is_empty());
+
+        // A branch turning out invalid is dropped, along with who sent it
+        assert_eq!(state.drop_headers(3), Some(honest));
+        assert_eq!(state.target_height(), 3);
+        assert_eq!(state.drop_headers(3), None);
+    }
+}
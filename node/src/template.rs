@@ -0,0 +1,186 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
+
+use crate::mempool::{MemPoolEvent, MemPoolHandle};
+
+// Capacity of the update channel, a lagging miner just treats its template
+// as stale
+const UPDATE_CAPACITY: usize = 64;
+// Fees a template can miss out on before it's worth rebuilding
+pub const DEFAULT_MIN_FEE_INCREASE: u64 = 10_000;
+
+// Why the block template a miner works on went stale
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemplateUpdate {
+    // The best tip changed, blocks built on the old tip would be orphaned
+    NewTip { hash: [u8; 32], height: u64 },
+    // Transactions paying this much in fees entered the mempool since the
+    // last update
+    Fees(u64),
+}
+
+// Tells miners when their block template should be rebuilt, on every new
+// tip and once enough fees entered the mempool.
+//
+// Smaller mempool changes aren't signalled so miners don't rebuild their
+// template for every transaction.
+#[derive(Debug, Clone)]
+pub struct TemplateNotifier {
+    updates: broadcast::Sender<TemplateUpdate>,
+    min_fee_increase: u64,
+    // Fees added to the mempool since the last update
+    pending_fees: Arc<AtomicU64>,
+}
+
+impl TemplateNotifier {
+    pub fn new(min_fee_increase: u64) -> Self {
+        let (updates, _) = broadcast::channel(UPDATE_CAPACITY);
+
+        Self {
+            updates,
+            min_fee_increase,
+            pending_fees: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn subscribe(&self) -> TemplateWatch {
+        TemplateWatch {
+            updates: self.updates.subscribe(),
+        }
+    }
+
+    pub fn new_tip(&self, hash: [u8; 32], height: u64) {
+        // A template for the new tip includes the pending fees anyway
+        self.pending_fees.store(0, Ordering::Relaxed);
+        self.notify(TemplateUpdate::NewTip { hash, height });
+    }
+
+    pub fn fee_added(&self, fee: u64) {
+        let total = self
+            .pending_fees
+            .fetch_add(fee, Ordering::Relaxed)
+            .saturating_add(fee);
+
+        if total >= self.min_fee_increase
+            && self
+                .pending_fees
+                .compare_exchange(total, 0, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.notify(TemplateUpdate::Fees(total));
+        }
+    }
+
+    // Adds up the fees of the transactions entering the mempool until the
+    // pool is dropped
+    pub async fn watch_mempool(&self, mem_pool: MemPoolHandle) {
+        let mut events = mem_pool.subscribe();
+
+        loop {
+            match events.recv().await {
+                Ok(MemPoolEvent::Added(txn_hash)) => {
+                    if let Some(entry) = mem_pool.entry(&txn_hash).await {
+                        self.fee_added(entry.fee);
+                    }
+                }
+                Ok(MemPoolEvent::Removed(_)) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    fn notify(&self, update: TemplateUpdate) {
+        // Sending only fails when no miner is subscribed
+        let _ = self.updates.send(update);
+    }
+}
+
+// A miner's subscription to the template updates
+#[derive(Debug)]
+pub struct TemplateWatch {
+    updates: broadcast::Receiver<TemplateUpdate>,
+}
+
+impl TemplateWatch {
+    // Whether the template went stale since the last check, without waiting.
+    // Meant to be called between mining rounds
+    pub fn is_stale(&mut self) -> bool {
+        let mut stale = false;
+
+        loop {
+            match self.updates.try_recv() {
+                Ok(_) | Err(TryRecvError::Lagged(_)) => stale = true,
+                Err(TryRecvError::Empty | TryRecvError::Closed) => return stale,
+            }
+        }
+    }
+
+    // Waits for the next update, `None` once the node is gone
+    pub async fn changed(&mut self) -> Option<TemplateUpdate> {
+        loop {
+            match self.updates.recv().await {
+                Ok(update) => return Some(update),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use corelib::{transaction::Transaction, utxo::UTXO};
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn signals_new_tips_and_large_fee_additions() {
+        let notifier = TemplateNotifier::new(1_000);
+        let mut watch = notifier.subscribe();
+        assert!(!watch.is_stale());
+
+        notifier.fee_added(600);
+        assert!(!watch.is_stale());
+        notifier.fee_added(600);
+        assert_eq!(watch.changed().await, Some(TemplateUpdate::Fees(1_200)));
+
+        // A new tip resets the fees counted towards the next update
+        notifier.fee_added(600);
+        notifier.new_tip([1u8; 32], 5);
+        notifier.fee_added(600);
+        assert!(watch.is_stale());
+        assert!(!watch.is_stale());
+
+        let mem_pool = MemPoolHandle::new(10);
+        let watcher = notifier.clone();
+        let pool = mem_pool.clone();
+        tokio::spawn(async move { watcher.watch_mempool(pool).await });
+        tokio::task::yield_now().await;
+
+        let mut signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let mut txn = Transaction::new(&mut signing_key, [1u8; 32]).unwrap();
+        let input = UTXO::new(5_000, 0, [7u8; 32])
+            .unwrap()
+            .confirm_utxo([2u8; 32], 1, false)
+            .unwrap();
+        let utxos = [(input.outpoint().unwrap(), input.clone())]
+            .into_iter()
+            .collect();
+        txn.add_inputs(vec![input], &mut signing_key).unwrap();
+        txn.add_outputs(
+            vec![UTXO::new(3_000, 0, [1u8; 32]).unwrap()],
+            &mut signing_key,
+        )
+        .unwrap();
+        mem_pool.add(txn, &utxos, 1).await.unwrap();
+        assert_eq!(watch.changed().await, Some(TemplateUpdate::Fees(2_600)));
+    }
+}
@@ -0,0 +1,428 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail};
+use borsh::{BorshDeserialize, BorshSerialize};
+use corelib::{block::Block, blockchain::ChainUpdate, transaction::Transaction};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt},
+    net::TcpStream,
+    sync::RwLock,
+};
+use tracing::{info, warn};
+
+use crate::storage::Storage;
+
+// How often the queue is checked for deliveries that are due
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Retries back off exponentially from the base delay up to the maximum
+const BASE_BACKOFF: u128 = 1_000;
+const MAX_BACKOFF: u128 = 10 * 60 * 1_000;
+// Deliveries still failing after this many attempts are dropped
+const MAX_ATTEMPTS: u32 = 20;
+// Undelivered events kept in the queue, the oldest are dropped first
+const MAX_QUEUE_SIZE: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    NewBlock,
+    AddressActivity,
+    Reorg,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookConfig {
+    // Only plain `http://host:port/path` URLs are supported
+    pub url: String,
+    // Key of the HMAC-SHA256 signature sent with every delivery
+    pub secret: String,
+    pub events: Vec<EventKind>,
+    // Hex encoded addresses watched for `address_activity` events
+    #[serde(default)]
+    pub addresses: Vec<String>,
+}
+
+impl WebhookConfig {
+    fn watches(&self, address: &[u8; 32]) -> bool {
+        self.events.contains(&EventKind::AddressActivity)
+            && self
+                .addresses
+                .iter()
+                .any(|watched| watched.eq_ignore_ascii_case(&hex::encode(address)))
+    }
+}
+
+// Event waiting to be delivered to a webhook
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Delivery {
+    pub url: String,
+    pub secret: String,
+    pub body: String,
+    pub attempts: u32,
+    // Milliseconds since the unix epoch
+    pub next_attempt: u128,
+}
+
+// Sends chain events to the configured webhooks.
+//
+// Events are queued and persisted before being delivered so a downstream
+// service being down, or the node restarting, doesn't lose them.
+#[derive(Debug, Clone)]
+pub struct WebhookDispatcher {
+    hooks: Arc<Vec<WebhookConfig>>,
+    queue: Arc<RwLock<VecDeque<Delivery>>>,
+    storage: Option<Storage>,
+}
+
+impl WebhookDispatcher {
+    // Resumes the deliveries queued by a previous run
+    pub async fn new(hooks: Vec<WebhookConfig>, storage: Option<Storage>) -> anyhow::Result<Self> {
+        let queue = match storage.as_ref() {
+            Some(storage) => storage.load_deliveries().await?,
+            None => VecDeque::new(),
+        };
+        if !queue.is_empty() {
+            info!("Resuming {} queued webhook deliveries", queue.len());
+        }
+
+        Ok(Self {
+            hooks: Arc::new(hooks),
+            queue: Arc::new(RwLock::new(queue)),
+            storage,
+        })
+    }
+
+    // Parses the webhooks from a JSON array of configs
+    pub fn parse_config(json: &str) -> anyhow::Result<Vec<WebhookConfig>> {
+        let hooks: Vec<WebhookConfig> = serde_json::from_str(json)?;
+
+        for hook in hooks.iter() {
+            parse_url(&hook.url)?;
+        }
+        Ok(hooks)
+    }
+
+    // Queues the events of a chain update for every webhook subscribed to them
+    pub async fn notify(&self, update: &ChainUpdate) {
+        let now = now();
+        let mut deliveries = Vec::new();
+
+        for hook in self.hooks.iter() {
+            for event in events_for(hook, update) {
+                deliveries.push(Delivery {
+                    url: hook.url.clone(),
+                    secret: hook.secret.clone(),
+                    body: event.to_string(),
+                    attempts: 0,
+                    next_attempt: now,
+                });
+            }
+        }
+        if deliveries.is_empty() {
+            return;
+        }
+
+        let mut queue = self.queue.write().await;
+        for delivery in deliveries {
+            if queue.len() >= MAX_QUEUE_SIZE {
+                warn!("Webhook queue is full, dropped the oldest delivery");
+                queue.pop_front();
+            }
+            queue.push_back(delivery);
+        }
+        self.persist(&queue).await;
+    }
+
+    pub async fn pending(&self) -> Vec<Delivery> {
+        self.queue.read().await.iter().cloned().collect()
+    }
+
+    // Delivers queued events forever
+    pub async fn run(self) {
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+
+        loop {
+            ticker.tick().await;
+            self.deliver_due(now()).await;
+        }
+    }
+
+    // Attempts every delivery due at `now`, failed ones are rescheduled.
+    // Returns the number of successful deliveries
+    async fn deliver_due(&self, now: u128) -> usize {
+        let due: Vec<Delivery> = self
+            .queue
+            .read()
+            .await
+            .iter()
+            .filter(|delivery| delivery.next_attempt <= now)
+            .cloned()
+            .collect();
+        if due.is_empty() {
+            return 0;
+        }
+
+        let mut delivered = Vec::new();
+        let mut failed = Vec::new();
+        for delivery in due {
+            match post(
+                &delivery.url,
+                &delivery.body,
+                &sign(&delivery.secret, &delivery.body),
+            )
+            .await
+            {
+                Ok(()) => delivered.push(delivery),
+                Err(e) => {
+                    warn!("Webhook delivery to {} failed: {e}", delivery.url);
+                    failed.push(delivery);
+                }
+            }
+        }
+
+        let mut queue = self.queue.write().await;
+        queue.retain(|queued| !delivered.contains(queued));
+        for delivery in failed {
+            let Some(queued) = queue.iter_mut().find(|queued| **queued == delivery) else {
+                continue;
+            };
+
+            queued.attempts += 1;
+            queued.next_attempt = now + backoff(queued.attempts);
+        }
+        queue.retain(|queued| {
+            let keep = queued.attempts < MAX_ATTEMPTS;
+            if !keep {
+                warn!(
+                    "Dropped webhook delivery to {} after {MAX_ATTEMPTS} attempts",
+                    queued.url
+                );
+            }
+            keep
+        });
+        self.persist(&queue).await;
+
+        delivered.len()
+    }
+
+    async fn persist(&self, queue: &VecDeque<Delivery>) {
+        if let Some(storage) = self.storage.as_ref() {
+            if let Err(e) = storage.save_deliveries(queue).await {
+                warn!("Failed to persist the webhook queue: {e}");
+            }
+        }
+    }
+}
+
+fn events_for(hook: &WebhookConfig, update: &ChainUpdate) -> Vec<serde_json::Value> {
+    let mut events = Vec::new();
+
+    if update.is_reorg() && hook.events.contains(&EventKind::Reorg) {
+        events.push(json!({
+            "event": "reorg",
+            "disconnected": update.disconnected.iter().map(block_summary).collect::<Vec<_>>(),
+            "connected": update.connected.iter().map(block_summary).collect::<Vec<_>>(),
+        }));
+    }
+
+    for block in update.connected.iter() {
+        if hook.events.contains(&EventKind::NewBlock) {
+            events.push(json!({
+                "event": "new_block",
+                "block": block_summary(block),
+            }));
+        }
+
+        for txn in block.transactions() {
+            for address in touched_addresses(txn) {
+                if hook.watches(&address) {
+                    events.push(json!({
+                        "event": "address_activity",
+                        "address": hex::encode(address),
+                        "txid": hex::encode(txn.hash_id),
+                        "block": block_summary(block),
+                    }));
+                }
+            }
+        }
+    }
+
+    events
+}
+
+fn block_summary(block: &Block) -> serde_json::Value {
+    json!({
+        "hash": hex::encode(block.hash()),
+        "height": block.index(),
+    })
+}
+
+// Addresses sending or receiving funds in the transaction
+fn touched_addresses(txn: &Transaction) -> Vec<[u8; 32]> {
+    let mut addresses = vec![txn.receiver];
+    if !txn.is_coinbase() {
+        addresses.push(txn.sender);
+    }
+    addresses.dedup();
+    addresses
+}
+
+fn backoff(attempts: u32) -> u128 {
+    BASE_BACKOFF
+        .saturating_mul(1 << attempts.min(20))
+        .min(MAX_BACKOFF)
+}
+
+// Hex encoded HMAC-SHA256 of the body, sent in the `X-Aurelius-Signature`
+// header so receivers can authenticate deliveries
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Splits an `http://host:port/path` URL into the address and the path
+fn parse_url(url: &str) -> anyhow::Result<(String, String)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow!("Unsupported webhook URL {url}, only http is supported"))?;
+
+    let (host, path) = match rest.find('/') {
+        Some(position) => rest.split_at(position),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        bail!("Webhook URL {url} has no host");
+    }
+
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{host}:80")
+    };
+    Ok((address, path.to_string()))
+}
+
+async fn post(url: &str, body: &str, signature: &str) -> anyhow::Result<()> {
+    let (address, path) = parse_url(url)?;
+    let host = address.trim_end_matches(":80");
+
+    let request = format!(
+        "POST {path} HTTP/1.1\r\n\
+         Host: {host}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         X-Aurelius-Signature: sha256={signature}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    let status = tokio::time::timeout(REQUEST_TIMEOUT, async {
+        let mut stream = TcpStream::connect(&address).await?;
+        stream.write_all(request.as_bytes()).await?;
+
+        // Only the status line matters
+        let mut response = vec![0u8; 1024];
+        let read = stream.read(&mut response).await?;
+        let response = String::from_utf8_lossy(&response[..read]).to_string();
+
+        anyhow::Ok(
+            response
+                .split_whitespace()
+                .nth(1)
+                .and_then(|status| status.parse::<u16>().ok()),
+        )
+    })
+    .await
+    .map_err(|_| anyhow!("Request timed out"))??;
+
+    match status {
+        Some(status) if (200..300).contains(&status) => Ok(()),
+        Some(status) => bail!("Webhook responded with status {status}"),
+        None => bail!("Malformed webhook response"),
+    }
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis())
+}
+
+#[cfg(test)]
+mod test {
+    use corelib::block::BlockBuilder;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // Answers the first request with `first_status` and the rest with 200,
+    // returns the received requests
+    async fn serve(listener: TcpListener, first_status: u16, count: usize) -> Vec<String> {
+        let mut requests = Vec::new();
+
+        for i in 0..count {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0u8; 4096];
+            let read = stream.read(&mut request).await.unwrap();
+            requests.push(String::from_utf8_lossy(&request[..read]).to_string());
+
+            let status = if i == 0 { first_status } else { 200 };
+            let response = format!("HTTP/1.1 {status} Status\r\nContent-Length: 0\r\n\r\n");
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+        requests
+    }
+
+    #[tokio::test]
+    async fn retries_failed_deliveries_with_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, 500, 2));
+
+        let hooks = WebhookDispatcher::parse_config(&format!(
+            r#"[{{"url": "http://{address}/hook", "secret": "key", "events": ["new_block"]}}]"#
+        ))
+        .unwrap();
+        let dispatcher = WebhookDispatcher::new(hooks, None).await.unwrap();
+
+        let genesis = BlockBuilder::new(0, "0".repeat(64), 1, [0u8; 32])
+            .build()
+            .unwrap();
+        dispatcher
+            .notify(&ChainUpdate {
+                disconnected: Vec::new(),
+                connected: vec![genesis],
+            })
+            .await;
+
+        let now = now();
+        assert_eq!(dispatcher.deliver_due(now).await, 0);
+        let queued = dispatcher.pending().await;
+        assert_eq!(queued[0].attempts, 1);
+        assert_eq!(queued[0].next_attempt, now + BASE_BACKOFF * 2);
+
+        // Nothing is attempted before the backoff elapses
+        assert_eq!(dispatcher.deliver_due(now + 1).await, 0);
+        assert_eq!(dispatcher.pending().await[0].attempts, 1);
+
+        assert_eq!(dispatcher.deliver_due(now + BASE_BACKOFF * 2).await, 1);
+        assert!(dispatcher.pending().await.is_empty());
+
+        let requests = server.await.unwrap();
+        let body = requests[1].split("\r\n\r\n").nth(1).unwrap();
+        assert!(body.contains("new_block"));
+        assert!(requests[1].contains(&format!("sha256={}", sign("key", body))));
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use corelib::{
+    address::{self, Address},
+    config::Network,
+};
+use hex::FromHex;
+
+use crate::errors::{Error, Result};
+
+// Someone the wallet pays, stored under a name the user picked
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Payee {
+    pub address: [u8; 32],
+    pub notes: String,
+}
+
+// Named payees so payments can be sent by name instead of pasting addresses
+#[derive(Debug, Clone, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct AddressBook {
+    payees: BTreeMap<String, Payee>,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, name: &str, address: [u8; 32], notes: String) -> Result<()> {
+        validate_name(name)?;
+        if self.payees.contains_key(name) {
+            return Err(Error::PayeeExists(name.to_string()));
+        }
+
+        self.payees
+            .insert(name.to_string(), Payee { address, notes });
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Payee> {
+        self.payees.get(name)
+    }
+
+    // Replaces the address and notes of an existing payee
+    pub fn update(&mut self, name: &str, address: [u8; 32], notes: String) -> Result<()> {
+        let payee = self
+            .payees
+            .get_mut(name)
+            .ok_or_else(|| Error::UnknownPayee(name.to_string()))?;
+
+        *payee = Payee { address, notes };
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<Payee> {
+        self.payees
+            .remove(name)
+            .ok_or_else(|| Error::UnknownPayee(name.to_string()))
+    }
+
+    // Payees ordered by name
+    pub fn payees(&self) -> impl Iterator<Item = (&str, &Payee)> {
+        self.payees
+            .iter()
+            .map(|(name, payee)| (name.as_str(), payee))
+    }
+
+    pub fn len(&self) -> usize {
+        self.payees.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.payees.is_empty()
+    }
+
+    // Looks up a payee by name, falling back to an address of the network or
+    // a hex encoded public key
+    pub fn resolve(&self, payee: &str, network: Network) -> Result<[u8; 32]> {
+        if let Some(payee) = self.payees.get(payee) {
+            return Ok(payee.address);
+        }
+
+        match address::parse_key(payee, network) {
+            Ok(key) => Ok(key),
+            // A mistyped or foreign address is reported as such
+            Err(e) if looks_like_address(payee) => Err(e.into()),
+            Err(_) => Err(Error::UnknownPayee(payee.to_string())),
+        }
+    }
+}
+
+// Names that read as an address would make `resolve` ambiguous
+fn validate_name(name: &str) -> Result<()> {
+    if name.trim().is_empty() {
+        return Err(Error::InvalidPayeeName("name is empty".to_string()));
+    }
+    if <[u8; 32]>::from_hex(name).is_ok() || looks_like_address(name) {
+        return Err(Error::InvalidPayeeName(
+            "name can't be an address".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Anything with the prefix of a network and a separator, valid or not
+fn looks_like_address(input: &str) -> bool {
+    input.parse::<Address>().is_ok()
+        || Network::ALL.iter().any(|network| {
+            input
+                .to_lowercase()
+                .starts_with(&format!("{}1", network.address_prefix()))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use ed25519_dalek::SigningKey;
+
+    use super::*;
+
+    #[test]
+    fn manages_and_resolves_payees() {
+        let mut book = AddressBook::new();
+        let alice = [1u8; 32];
+
+        book.add("alice", alice, "rent".to_string()).unwrap();
+        assert!(matches!(
+            book.add("alice", [2u8; 32], String::new()),
+            Err(Error::PayeeExists(_))
+        ));
+        assert!(matches!(
+            book.add(&hex::encode([3u8; 32]), [3u8; 32], String::new()),
+            Err(Error::InvalidPayeeName(_))
+        ));
+
+        assert_eq!(book.resolve("alice", Network::Mainnet).unwrap(), alice);
+        assert_eq!(
+            book.resolve(&hex::encode([4u8; 32]), Network::Mainnet)
+                .unwrap(),
+            [4u8; 32]
+        );
+        assert!(matches!(
+            book.resolve("bob", Network::Mainnet),
+            Err(Error::UnknownPayee(_))
+        ));
+
+        // Addresses resolve on their own network only, and can't be names
+        let key = SigningKey::from_bytes(&[6u8; 32]).verifying_key();
+        let carol = Address::from_verifying_key(Network::Testnet, &key).to_string();
+        assert_eq!(
+            book.resolve(&carol, Network::Testnet).unwrap(),
+            key.to_bytes()
+        );
+        assert!(matches!(
+            book.resolve(&carol, Network::Mainnet),
+            Err(Error::Core(_))
+        ));
+        assert!(matches!(
+            book.add(&carol, key.to_bytes(), String::new()),
+            Err(Error::InvalidPayeeName(_))
+        ));
+
+        book.update("alice", [5u8; 32], "new key".to_string())
+            .unwrap();
+        assert_eq!(book.get("alice").unwrap().notes, "new key");
+        assert!(book.update("bob", alice, String::new()).is_err());
+
+        assert_eq!(book.remove("alice").unwrap().address, [5u8; 32]);
+        assert!(book.is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+use corelib::utxo::UTXO;
+
+// Upper bounds, in confirmations, of the age buckets. Outputs older than the
+// last bound go into an open-ended bucket
+const AGE_BOUNDS: [u64; 3] = [6, 100, 1_000];
+
+// Outputs confirmed for `min_confirmations` up to `max_confirmations` blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AgeBucket {
+    pub min_confirmations: u64,
+    // `None` for the bucket of the oldest outputs
+    pub max_confirmations: Option<u64>,
+    pub count: usize,
+    pub value: u64,
+}
+
+// Summary of the outputs a wallet holds, to tell when consolidating them is
+// worth it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UtxoStats {
+    pub count: usize,
+    pub value: u64,
+    // Outputs by the blocks they've been confirmed for, youngest first
+    pub ages: Vec<AgeBucket>,
+    // Outputs worth no more than the fee to spend them at the fee rate
+    pub dust: usize,
+    pub dust_value: u64,
+}
+
+impl UtxoStats {
+    // Ages are counted at a chain of `chain_height` blocks, dust at
+    // `fee_rate` per byte
+    pub fn new<'a>(
+        utxos: impl IntoIterator<Item = &'a UTXO>,
+        chain_height: u64,
+        fee_rate: u64,
+    ) -> Self {
+        let ages = std::iter::once(1)
+            .chain(AGE_BOUNDS.iter().map(|bound| bound + 1))
+            .zip(AGE_BOUNDS.iter().copied().map(Some).chain([None]))
+            .map(|(min_confirmations, max_confirmations)| AgeBucket {
+                min_confirmations,
+                max_confirmations,
+                count: 0,
+                value: 0,
+            })
+            .collect();
+        let mut stats = Self {
+            count: 0,
+            value: 0,
+            ages,
+            dust: 0,
+            dust_value: 0,
+        };
+
+        for utxo in utxos {
+            stats.count += 1;
+            stats.value = stats.value.saturating_add(utxo.value());
+
+            let confirmations = utxo.confirmations_at(chain_height.saturating_sub(1));
+            if let Some(bucket) = stats.ages.iter_mut().find(|bucket| {
+                bucket
+                    .max_confirmations
+                    .is_none_or(|max| confirmations <= max)
+            }) {
+                bucket.count += 1;
+                bucket.value = bucket.value.saturating_add(utxo.value());
+            }
+
+            if is_dust(utxo, fee_rate) {
+                stats.dust += 1;
+                stats.dust_value = stats.dust_value.saturating_add(utxo.value());
+            }
+        }
+
+        stats
+    }
+}
+
+// Fee spending the output costs at `fee_rate` per byte
+pub fn spend_cost(utxo: &UTXO, fee_rate: u64) -> u64 {
+    (utxo.size() as u64).saturating_mul(fee_rate)
+}
+
+// Whether spending the output costs at least as much as it's worth
+pub fn is_dust(utxo: &UTXO, fee_rate: u64) -> bool {
+    utxo.value() <= spend_cost(utxo, fee_rate)
+}
+
+// Lowest fee rate at which the output is dust
+pub fn uneconomical_fee_rate(utxo: &UTXO) -> u64 {
+    utxo.value().div_ceil(utxo.size() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn confirmed(value: u64, block_height: u32) -> UTXO {
+        UTXO::new(value, 0, [1u8; 32])
+            .unwrap()
+            .confirm_utxo(
+                *blake3::hash(&block_height.to_le_bytes()).as_bytes(),
+                block_height,
+                false,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn buckets_outputs_by_age_and_counts_dust() {
+        let young = confirmed(50, 1_098);
+        let old = confirmed(10_000, 1_050);
+        let ancient = confirmed(7_000, 0);
+        let stats = UtxoStats::new([&young, &old, &ancient], 1_100, 1);
+
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.value, 17_050);
+        let counts: Vec<usize> = stats.ages.iter().map(|bucket| bucket.count).collect();
+        assert_eq!(counts, vec![1, 1, 0, 1]);
+        assert_eq!(stats.ages[0].value, 50);
+        assert_eq!(stats.ages[3].min_confirmations, 1_001);
+        assert_eq!(stats.ages[3].max_confirmations, None);
+
+        // The young output costs more to spend than it's worth
+        assert_eq!((stats.dust, stats.dust_value), (1, 50));
+        assert!(spend_cost(&young, 1) >= 50);
+
+        let rate = uneconomical_fee_rate(&old);
+        assert!(is_dust(&old, rate));
+        assert!(!is_dust(&old, rate - 1));
+    }
+}
@@ -0,0 +1,341 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use corelib::{
+    errors::Error as CoreError,
+    keystore::{self, KdfParams},
+};
+use ed25519_dalek::SigningKey;
+use rand::{rngs::OsRng, RngCore};
+
+use crate::{
+    errors::{Error, Result},
+    keychain::Derivation,
+    wallet::{HistoryEntry, Wallet},
+};
+
+const MAGIC: &[u8; 8] = b"AURWBKUP";
+const KEY_CONTEXT: &str = "aurelius wallet 2024-11 backup encryption";
+
+// Version 2 added the derivation of the addresses, version 3 replaced the
+// blake3 keystream with the keystore's argon2id and XChaCha20-Poly1305
+pub const BACKUP_VERSION: u16 = 3;
+
+// Archive layout: magic, version, KDF parameters, salt, nonce and the
+// contents sealed with the rest as associated data
+const HEADER_SIZE: usize = MAGIC.len() + 2 + 12 + 16 + 24;
+
+// Version 1 and 2 archives: magic, version, salt, keystream encrypted
+// contents and a blake3 checksum of everything before it
+const LEGACY_HEADER_SIZE: usize = MAGIC.len() + 2 + 16;
+const CHECKSUM_SIZE: usize = 32;
+
+// Everything needed to recreate a wallet, UTXOs are left out as they can be
+// rescanned from the chain
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct Backup {
+    pub public_key: [u8; 32],
+    secret_key: [u8; 32],
+    // Whether the wallet was protected by a passphrase
+    pub encrypted: bool,
+    pub labels: Vec<([u8; 32], String)>,
+    pub history: Vec<HistoryEntry>,
+    // Milliseconds since the unix epoch
+    pub created_at: u128,
+    pub derivation: Derivation,
+}
+
+// Contents of version 1 backups, made before seed phrases
+#[derive(BorshDeserialize)]
+struct BackupV1 {
+    public_key: [u8; 32],
+    secret_key: [u8; 32],
+    encrypted: bool,
+    labels: Vec<([u8; 32], String)>,
+    history: Vec<HistoryEntry>,
+    created_at: u128,
+}
+
+impl From<BackupV1> for Backup {
+    fn from(backup: BackupV1) -> Self {
+        Self {
+            public_key: backup.public_key,
+            secret_key: backup.secret_key,
+            encrypted: backup.encrypted,
+            labels: backup.labels,
+            history: backup.history,
+            created_at: backup.created_at,
+            derivation: Derivation::Hashed,
+        }
+    }
+}
+
+impl Backup {
+    // The wallet must be unlocked so its key can be exported
+    pub fn from_wallet(wallet: &mut Wallet) -> Result<Self> {
+        Ok(Self {
+            public_key: wallet.public_key(),
+            secret_key: wallet.secret_key()?,
+            encrypted: wallet.is_encrypted(),
+            labels: wallet
+                .labels()
+                .iter()
+                .map(|(address, label)| (*address, label.clone()))
+                .collect(),
+            history: wallet.history().to_vec(),
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis()),
+            derivation: wallet.derivation(),
+        })
+    }
+
+    // Recreates the wallet, wallets that had a passphrase are encrypted with
+    // the backup passphrase again
+    pub fn into_wallet(self, passphrase: &str) -> Result<Wallet> {
+        let mut wallet =
+            Wallet::with_derivation(SigningKey::from_bytes(&self.secret_key), self.derivation);
+
+        for (address, label) in self.labels {
+            wallet.set_label(address, label);
+        }
+        for entry in self.history {
+            wallet.record(entry);
+        }
+        if self.encrypted {
+            wallet.encrypt(passphrase)?;
+        }
+
+        Ok(wallet)
+    }
+
+    pub fn to_archive(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let kdf = KdfParams::default();
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 24];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+
+        let mut archive = Vec::new();
+        archive.extend_from_slice(MAGIC);
+        archive.extend_from_slice(&BACKUP_VERSION.to_be_bytes());
+        archive.extend(borsh::to_vec(&kdf)?);
+        archive.extend_from_slice(&salt);
+        archive.extend_from_slice(&nonce);
+
+        let contents = borsh::to_vec(self)?;
+        let sealed = keystore::seal(passphrase, &kdf, &salt, &nonce, &contents, &archive)?;
+        archive.extend(sealed);
+
+        Ok(archive)
+    }
+
+    // A wrong passphrase and a tampered archive both fail authentication,
+    // older archives are checked against their checksum instead
+    pub fn from_archive(archive: &[u8], passphrase: &str) -> Result<Self> {
+        if archive.len() < MAGIC.len() + 2 {
+            return Err(Error::InvalidBackup("archive is truncated".to_string()));
+        }
+        if &archive[..MAGIC.len()] != MAGIC {
+            return Err(Error::InvalidBackup("not a wallet backup".to_string()));
+        }
+
+        let version = u16::from_be_bytes([archive[MAGIC.len()], archive[MAGIC.len() + 1]]);
+        if !(1..=BACKUP_VERSION).contains(&version) {
+            return Err(Error::UnsupportedBackupVersion(version));
+        }
+
+        let plaintext = if version < 3 {
+            open_legacy(archive, passphrase)?
+        } else {
+            open_sealed(archive, passphrase)?
+        };
+        let backup = match version {
+            1 => BackupV1::try_from_slice(&plaintext).map(Backup::from),
+            _ => Backup::try_from_slice(&plaintext),
+        }
+        .map_err(|_| Error::WrongPassphrase)?;
+        let public_key = SigningKey::from_bytes(&backup.secret_key)
+            .verifying_key()
+            .to_bytes();
+        if public_key != backup.public_key {
+            return Err(Error::WrongPassphrase);
+        }
+
+        Ok(backup)
+    }
+}
+
+fn open_sealed(archive: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    if archive.len() < HEADER_SIZE {
+        return Err(Error::InvalidBackup("archive is truncated".to_string()));
+    }
+    let (header, sealed) = archive.split_at(HEADER_SIZE);
+
+    let kdf = KdfParams::try_from_slice(&header[MAGIC.len() + 2..MAGIC.len() + 14])
+        .map_err(|e| Error::InvalidBackup(e.to_string()))?;
+    let salt: [u8; 16] = header[MAGIC.len() + 14..MAGIC.len() + 30]
+        .try_into()
+        .expect("header has a 16 byte salt");
+    let nonce: [u8; 24] = header[MAGIC.len() + 30..]
+        .try_into()
+        .expect("header has a 24 byte nonce");
+
+    match keystore::open(passphrase, &kdf, &salt, &nonce, sealed, header) {
+        Ok(plaintext) => Ok(plaintext.to_vec()),
+        Err(CoreError::WrongPassphrase) => Err(Error::WrongPassphrase),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn open_legacy(archive: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    if archive.len() < LEGACY_HEADER_SIZE + CHECKSUM_SIZE {
+        return Err(Error::InvalidBackup("archive is truncated".to_string()));
+    }
+
+    let (contents, checksum) = archive.split_at(archive.len() - CHECKSUM_SIZE);
+    if blake3::hash(contents).as_bytes() != checksum {
+        return Err(Error::BackupChecksumMismatch);
+    }
+
+    let salt: [u8; 16] = contents[MAGIC.len() + 2..LEGACY_HEADER_SIZE]
+        .try_into()
+        .expect("header has a 16 byte salt");
+    Ok(apply_keystream(
+        contents[LEGACY_HEADER_SIZE..].to_vec(),
+        &salt,
+        passphrase,
+    ))
+}
+
+fn apply_keystream(mut data: Vec<u8>, salt: &[u8; 16], passphrase: &str) -> Vec<u8> {
+    let mut keystream = vec![0u8; data.len()];
+    blake3::Hasher::new_derive_key(KEY_CONTEXT)
+        .update(salt)
+        .update(passphrase.as_bytes())
+        .finalize_xof()
+        .fill(&mut keystream);
+
+    for (byte, key) in data.iter_mut().zip(keystream) {
+        *byte ^= key;
+    }
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn restores_labels_and_history() {
+        let mut wallet = Wallet::new();
+        let friend = Wallet::new().public_key();
+        wallet.set_label(friend, "friend".to_string());
+        wallet.encrypt("backup pass").unwrap();
+        wallet
+            .unlock("backup pass", std::time::Duration::from_secs(60))
+            .unwrap();
+
+        let archive = Backup::from_wallet(&mut wallet)
+            .unwrap()
+            .to_archive("backup pass")
+            .unwrap();
+
+        assert!(matches!(
+            Backup::from_archive(&archive, "wrong pass"),
+            Err(Error::WrongPassphrase)
+        ));
+
+        let backup = Backup::from_archive(&archive, "backup pass").unwrap();
+        assert_eq!(backup.public_key, wallet.public_key());
+
+        let mut restored = backup.into_wallet("backup pass").unwrap();
+        assert_eq!(restored.public_key(), wallet.public_key());
+        assert_eq!(restored.label(&friend), Some("friend"));
+        assert!(restored.is_locked());
+
+        // The wallet file keeps the key encrypted
+        let reloaded = Wallet::from_bytes(&restored.to_bytes().unwrap()).unwrap();
+        assert!(reloaded.is_encrypted());
+    }
+
+    #[test]
+    fn refuses_tampered_archives() {
+        let mut wallet = Wallet::new();
+        let archive = Backup::from_wallet(&mut wallet)
+            .unwrap()
+            .to_archive("pass")
+            .unwrap();
+
+        // Flipping a bit of the contents, the salt or the KDF parameters
+        // fails authentication
+        for index in [
+            HEADER_SIZE,
+            archive.len() - 1,
+            MAGIC.len() + 14,
+            MAGIC.len() + 5,
+        ] {
+            let mut tampered = archive.clone();
+            tampered[index] ^= 1;
+            assert!(Backup::from_archive(&tampered, "pass").is_err());
+        }
+        assert!(matches!(
+            Backup::from_archive(&archive[..HEADER_SIZE - 1], "pass"),
+            Err(Error::InvalidBackup(_))
+        ));
+        assert!(Backup::from_archive(&archive, "pass").is_ok());
+    }
+
+    #[test]
+    fn reads_legacy_archives() {
+        let mut wallet = Wallet::new();
+        let backup = Backup::from_wallet(&mut wallet).unwrap();
+
+        // Version 2 layout, as written before the switch to XChaCha20-Poly1305
+        let salt = [7u8; 16];
+        let mut archive = MAGIC.to_vec();
+        archive.extend_from_slice(&2u16.to_be_bytes());
+        archive.extend_from_slice(&salt);
+        archive.extend(apply_keystream(
+            borsh::to_vec(&backup).unwrap(),
+            &salt,
+            "pass",
+        ));
+        let checksum = blake3::hash(&archive);
+        archive.extend_from_slice(checksum.as_bytes());
+
+        let restored = Backup::from_archive(&archive, "pass").unwrap();
+        assert_eq!(restored.public_key, wallet.public_key());
+        assert!(matches!(
+            Backup::from_archive(&archive, "wrong"),
+            Err(Error::WrongPassphrase)
+        ));
+
+        let mut corrupted = archive.clone();
+        corrupted[LEGACY_HEADER_SIZE] ^= 1;
+        assert!(matches!(
+            Backup::from_archive(&corrupted, "pass"),
+            Err(Error::BackupChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn restores_seed_phrase_addresses() {
+        let phrase = crate::hd::generate_mnemonic();
+        let mut wallet = Wallet::from_mnemonic(&phrase, "", 3).unwrap();
+        let archive = Backup::from_wallet(&mut wallet)
+            .unwrap()
+            .to_archive("pass")
+            .unwrap();
+
+        let mut restored = Backup::from_archive(&archive, "pass")
+            .unwrap()
+            .into_wallet("pass")
+            .unwrap();
+        assert_eq!(restored.derivation(), wallet.derivation());
+        assert_eq!(
+            restored.new_address().unwrap(),
+            wallet.new_address().unwrap()
+        );
+    }
+}
@@ -0,0 +1,262 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use borsh::BorshDeserialize;
+use corelib::{
+    block::BlockHeader,
+    blockchain::{TxInclusion, TxStatus},
+    transaction::Transaction,
+    utxo::UTXO,
+};
+use hex::FromHex;
+use serde_json::{json, Value};
+
+use crate::{
+    errors::{Error, Result},
+    spv::TxProof,
+};
+
+// Time a node has to answer a call
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// Blocking client of a node's JSON-RPC server, the wallet only sends signed
+// transactions to the node and never its keys
+#[derive(Debug, Clone)]
+pub struct NodeClient {
+    // host:port of the node's RPC server
+    address: String,
+}
+
+impl NodeClient {
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+        }
+    }
+
+    // Confirmed balance of a public key
+    pub fn balance(&self, address: &[u8; 32]) -> Result<u64> {
+        self.call("getbalance", json!([hex::encode(address)]))?
+            .as_u64()
+            .ok_or_else(|| Error::InvalidResponse("expected balance".to_string()))
+    }
+
+    // Number of blocks in the node's chain, the height of the next block
+    pub fn block_count(&self) -> Result<u64> {
+        self.call("getblockcount", json!([]))?
+            .as_u64()
+            .ok_or_else(|| Error::InvalidResponse("expected block count".to_string()))
+    }
+
+    // Fee per byte likely to confirm a payment within the number of blocks,
+    // as the node estimates it
+    pub fn estimate_fee_rate(&self, target_blocks: u64) -> Result<u64> {
+        self.call("estimatefeerate", json!([target_blocks]))?["per_byte"]
+            .as_u64()
+            .ok_or_else(|| Error::InvalidResponse("expected fee rate".to_string()))
+    }
+
+    // Confirmed outputs a public key can spend
+    pub fn unspent(&self, address: &[u8; 32]) -> Result<Vec<UTXO>> {
+        let result = self.call("listunspent", json!([hex::encode(address)]))?;
+        let unspent = result
+            .as_array()
+            .ok_or_else(|| Error::InvalidResponse("expected list of UTXOs".to_string()))?;
+
+        unspent.iter().map(|utxo| decode(utxo, "UTXO")).collect()
+    }
+
+    // Status of a transaction and every block known to contain it, a payment
+    // is only settled while one of them is on the best chain
+    pub fn transaction_status(&self, txid: &[u8; 32]) -> Result<(TxStatus, Vec<TxInclusion>)> {
+        let mut result = self.call("gettxconfirmations", json!([hex::encode(txid)]))?;
+        let invalid = |_| Error::InvalidResponse("expected transaction status".to_string());
+
+        Ok((
+            serde_json::from_value(result["status"].take()).map_err(invalid)?,
+            serde_json::from_value(result["blocks"].take()).map_err(invalid)?,
+        ))
+    }
+
+    // Headers of the node's best chain from the height on, unvalidated
+    pub fn headers(&self, from: u64) -> Result<Vec<BlockHeader>> {
+        let result = self.call("getheaders", json!([from]))?;
+        let headers = result
+            .as_array()
+            .ok_or_else(|| Error::InvalidResponse("expected list of headers".to_string()))?;
+
+        headers
+            .iter()
+            .map(|header| decode(header, "header"))
+            .collect()
+    }
+
+    // Merkle proof of a transaction of the node's best chain, `None` while
+    // it's unconfirmed. See `TxProof::verify` before trusting it
+    pub fn tx_proof(&self, txid: &[u8; 32]) -> Result<Option<TxProof>> {
+        let result = self.call("gettxproof", json!([hex::encode(txid)]))?;
+        if result.is_null() {
+            return Ok(None);
+        }
+        let invalid = || Error::InvalidResponse("expected merkle proof".to_string());
+
+        Ok(Some(TxProof {
+            txid: *txid,
+            block_hash: result["block_hash"]
+                .as_str()
+                .and_then(|hash| <[u8; 32]>::from_hex(hash).ok())
+                .ok_or_else(invalid)?,
+            height: result["height"].as_u64().ok_or_else(invalid)?,
+            proof: decode(&result["proof"], "merkle proof")?,
+        }))
+    }
+
+    // Submits a signed transaction to the node's mempool, returns its txid
+    pub fn send_transaction(&self, txn: &Transaction) -> Result<[u8; 32]> {
+        let bytes = borsh::to_vec(txn)?;
+        let result = self.call("sendrawtransaction", json!([hex::encode(bytes)]))?;
+
+        result
+            .as_str()
+            .and_then(|txid| <[u8; 32]>::from_hex(txid).ok())
+            .ok_or_else(|| Error::InvalidResponse("expected txid".to_string()))
+    }
+
+    // Calls a method with positional params, returns its result
+    pub fn call(&self, method: &str, params: Value) -> Result<Value> {
+        let body =
+            json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }).to_string();
+        let request = format!(
+            "POST / HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            self.address,
+            body.len()
+        );
+
+        let mut stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.write_all(request.as_bytes())?;
+
+        // The node closes the connection after the response
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        let body = response
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body)
+            .ok_or_else(|| Error::InvalidResponse("malformed HTTP response".to_string()))?;
+        let mut response: Value = serde_json::from_str(body)
+            .map_err(|e| Error::InvalidResponse(format!("malformed JSON: {e}")))?;
+
+        if let Some(error) = response.get("error") {
+            return Err(Error::Rpc {
+                code: error.get("code").and_then(Value::as_i64).unwrap_or(0),
+                message: error
+                    .get("message")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+            });
+        }
+
+        Ok(response["result"].take())
+    }
+}
+
+// Decodes a hex encoded Borsh value of a response
+fn decode<T: BorshDeserialize>(value: &Value, name: &str) -> Result<T> {
+    value
+        .as_str()
+        .and_then(|value| Vec::<u8>::from_hex(value).ok())
+        .and_then(|bytes| T::try_from_slice(&bytes).ok())
+        .ok_or_else(|| Error::InvalidResponse(format!("expected {name} as hex")))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+    use crate::wallet::Wallet;
+
+    // Answers the next `bodies.len()` requests with the given JSON bodies
+    fn serve(bodies: Vec<Value>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            for body in bodies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0u8; 4096];
+                let _ = stream.read(&mut request).unwrap();
+
+                let body = body.to_string();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn decodes_results_and_errors() {
+        let owner = Wallet::new().public_key();
+        let utxo = UTXO::new(5_000, 0, owner)
+            .unwrap()
+            .confirm_utxo([3u8; 32], 1, false)
+            .unwrap();
+
+        let address = serve(vec![
+            json!({ "jsonrpc": "2.0", "id": 1, "result": 5_000 }),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "result": [hex::encode(borsh::to_vec(&utxo).unwrap())]
+            }),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "result": {
+                    "status": { "status": "orphaned", "block_hash": hex::encode([4u8; 32]), "height": 3 },
+                    "blocks": [{
+                        "block_hash": hex::encode([4u8; 32]),
+                        "height": 3,
+                        "on_best_chain": false,
+                        "confirmations": 0,
+                    }],
+                }
+            }),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "error": { "code": -32000, "message": "Transaction rejected" }
+            }),
+        ]);
+        let client = NodeClient::new(address);
+
+        assert_eq!(client.balance(&owner).unwrap(), 5_000);
+        assert_eq!(client.unspent(&owner).unwrap(), vec![utxo]);
+        let (status, blocks) = client.transaction_status(&[3u8; 32]).unwrap();
+        assert_eq!(
+            status,
+            TxStatus::Orphaned {
+                block_hash: [4u8; 32],
+                height: 3
+            }
+        );
+        assert!(!blocks[0].on_best_chain);
+        assert!(matches!(
+            client.call("sendrawtransaction", json!(["00"])),
+            Err(Error::Rpc { code: -32000, .. })
+        ));
+    }
+}
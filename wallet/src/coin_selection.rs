@@ -0,0 +1,228 @@
+use corelib::{
+    script::P2PKH_UNLOCKING_SIZE,
+    transaction::BASE_SIZE,
+    utxo::{PENDING_SIZE, UTXO},
+};
+
+use crate::errors::{Error, Result};
+
+// Upper bound of branches explored by branch-and-bound before falling back
+const BNB_MAX_TRIES: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub inputs: Vec<UTXO>,
+    // Value paid back to the sender, zero when no change output is needed
+    pub change: u64,
+    pub fee: u64,
+}
+
+// Bytes an input adds to a transaction along with its unlocking script,
+// taken to be a pay-to-pubkey-hash one
+fn input_size(input: &UTXO) -> usize {
+    input.size() + P2PKH_UNLOCKING_SIZE
+}
+
+// Fee paid by a transaction spending `inputs` into `outputs` pending outputs
+fn fee_for(inputs: &[UTXO], outputs: usize, fee_rate: u64) -> u64 {
+    let size = BASE_SIZE + inputs.iter().map(input_size).sum::<usize>() + outputs * PENDING_SIZE;
+    (size as u64).saturating_mul(fee_rate)
+}
+
+// Tries an exact match first so no change output is created, falls back to
+// largest-first otherwise
+pub fn select_coins(utxos: &[UTXO], amount: u64, fee_rate: u64) -> Result<Selection> {
+    match branch_and_bound(utxos, amount, fee_rate) {
+        Some(selection) => Ok(selection),
+        None => largest_first(utxos, amount, fee_rate),
+    }
+}
+
+// Spends the biggest UTXOs first until the amount and fee are covered.
+//
+// Change smaller than the cost of its own output is left to the miner instead
+// of creating a dust output.
+pub fn largest_first(utxos: &[UTXO], amount: u64, fee_rate: u64) -> Result<Selection> {
+    let mut sorted = utxos.to_vec();
+    sorted.sort_by_key(|u| std::cmp::Reverse(u.value()));
+
+    let cost_of_change = (PENDING_SIZE as u64).saturating_mul(fee_rate);
+
+    let mut inputs = Vec::new();
+    let mut total: u64 = 0;
+
+    for utxo in sorted {
+        total = total.saturating_add(utxo.value());
+        inputs.push(utxo);
+
+        if total < amount.saturating_add(fee_for(&inputs, 1, fee_rate)) {
+            continue;
+        }
+
+        let change_fee = fee_for(&inputs, 2, fee_rate);
+        let change = total.saturating_sub(amount).saturating_sub(change_fee);
+
+        if change > cost_of_change {
+            return Ok(Selection {
+                inputs,
+                change,
+                fee: change_fee,
+            });
+        }
+
+        return Ok(Selection {
+            inputs,
+            change: 0,
+            fee: total - amount,
+        });
+    }
+
+    Err(Error::InsufficientFunds {
+        needed: amount.saturating_add(fee_for(&inputs, 1, fee_rate)),
+        available: total,
+    })
+}
+
+// Searches for a set of UTXOs whose effective value (value minus the fee to
+// spend it) covers the amount without needing a change output, wasting at most
+// the cost of that change output.
+pub fn branch_and_bound(utxos: &[UTXO], amount: u64, fee_rate: u64) -> Option<Selection> {
+    let mut candidates: Vec<(u64, &UTXO)> = utxos
+        .iter()
+        .filter_map(|u| {
+            let cost = (input_size(u) as u64).saturating_mul(fee_rate);
+            u.value()
+                .checked_sub(cost)
+                .filter(|v| *v > 0)
+                .map(|v| (v, u))
+        })
+        .collect();
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.0));
+
+    let target = amount.checked_add(fee_for(&[], 1, fee_rate))?;
+    let upper = target.saturating_add((PENDING_SIZE as u64).saturating_mul(fee_rate));
+    let remaining = candidates
+        .iter()
+        .fold(0u64, |acc, c| acc.saturating_add(c.0));
+
+    let mut search = Search {
+        candidates: &candidates,
+        target,
+        upper,
+        tries: BNB_MAX_TRIES,
+        selected: Vec::new(),
+        best: None,
+    };
+    search.explore(0, 0, remaining);
+
+    let (indexes, _) = search.best?;
+    let inputs: Vec<UTXO> = indexes.iter().map(|i| candidates[*i].1.clone()).collect();
+    let total: u64 = inputs.iter().map(UTXO::value).sum();
+
+    Some(Selection {
+        inputs,
+        change: 0,
+        fee: total - amount,
+    })
+}
+
+struct Search<'a> {
+    candidates: &'a [(u64, &'a UTXO)],
+    target: u64,
+    upper: u64,
+    tries: usize,
+    selected: Vec<usize>,
+    // Best selection found so far along with its waste
+    best: Option<(Vec<usize>, u64)>,
+}
+
+impl Search<'_> {
+    fn explore(&mut self, index: usize, sum: u64, remaining: u64) {
+        if self.tries == 0 || sum > self.upper {
+            return;
+        }
+        self.tries -= 1;
+
+        if sum >= self.target {
+            let waste = sum - self.target;
+            let improves = match self.best {
+                Some((_, best_waste)) => waste < best_waste,
+                None => true,
+            };
+            if improves {
+                self.best = Some((self.selected.clone(), waste));
+            }
+            return;
+        }
+
+        if index == self.candidates.len() || sum.saturating_add(remaining) < self.target {
+            return;
+        }
+
+        let value = self.candidates[index].0;
+
+        // Branch including the candidate first, then the one skipping it
+        self.selected.push(index);
+        self.explore(index + 1, sum.saturating_add(value), remaining - value);
+        self.selected.pop();
+        self.explore(index + 1, sum, remaining - value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn confirmed_utxos(values: &[u64]) -> Vec<UTXO> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, v)| {
+                UTXO::new(*v, i as u32, [7u8; 32])
+                    .unwrap()
+                    .confirm_utxo([i as u8; 32], 1, false)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn branch_and_bound_avoids_change() {
+        let utxos = confirmed_utxos(&[10_000, 4_000, 3_000]);
+        let input_cost = input_size(&utxos[0]) as u64;
+        // Exactly two inputs plus the fee of a single output transaction
+        let amount = 7_000 - fee_for(&utxos[1..], 1, 1);
+
+        let selection = branch_and_bound(&utxos, amount, 1).unwrap();
+
+        assert_eq!(selection.inputs.len(), 2);
+        assert_eq!(selection.change, 0);
+        assert!(selection.fee >= fee_for(&selection.inputs, 1, 1));
+        assert!(selection.fee < fee_for(&selection.inputs, 1, 1) + input_cost);
+    }
+
+    #[test]
+    fn largest_first_creates_change() {
+        let utxos = confirmed_utxos(&[10_000, 4_000, 3_000]);
+
+        let selection = largest_first(&utxos, 5_000, 2).unwrap();
+
+        assert_eq!(selection.inputs.len(), 1);
+        assert_eq!(selection.inputs[0].value(), 10_000);
+        assert_eq!(selection.fee, fee_for(&selection.inputs, 2, 2));
+        assert_eq!(selection.change, 10_000 - 5_000 - selection.fee);
+    }
+
+    #[test]
+    fn fails_on_insufficient_funds() {
+        let utxos = confirmed_utxos(&[1_000, 2_000]);
+
+        assert!(matches!(
+            select_coins(&utxos, 3_000, 1),
+            Err(Error::InsufficientFunds {
+                available: 3_000,
+                ..
+            })
+        ));
+    }
+}
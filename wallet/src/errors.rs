@@ -0,0 +1,103 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Core Error: {0}")]
+    Core(#[from] corelib::errors::Error),
+
+    #[error("Insufficient funds: needed {needed}, available {available}")]
+    InsufficientFunds { needed: u64, available: u64 },
+
+    #[error("UTXO is not owned by this wallet")]
+    NotOwned,
+
+    #[error("Only confirmed UTXOs can be tracked")]
+    UnconfirmedUTXO,
+
+    #[error("Amount must be non zero")]
+    ZeroAmount,
+
+    #[error("Wallet is locked")]
+    Locked,
+
+    #[error("Wrong wallet passphrase")]
+    WrongPassphrase,
+
+    #[error("Wallet is not encrypted")]
+    NotEncrypted,
+
+    #[error("Wallet is already encrypted")]
+    AlreadyEncrypted,
+
+    #[error("Unlock timeout of {0:?} is too long")]
+    UnlockTimeoutTooLong(std::time::Duration),
+
+    #[error("Unknown RPC method: {0}")]
+    UnknownMethod(String),
+
+    #[error("Invalid RPC params: {0}")]
+    InvalidParams(String),
+
+    #[error("IO Error: {0}")]
+    IO(#[from] std::io::Error),
+
+    #[error("Invalid backup: {0}")]
+    InvalidBackup(String),
+
+    #[error("Unsupported backup version: {0}")]
+    UnsupportedBackupVersion(u16),
+
+    #[error("Backup checksum mismatch, the file is corrupted")]
+    BackupChecksumMismatch,
+
+    #[error("Node RPC error {code}: {message}")]
+    Rpc { code: i64, message: String },
+
+    #[error("Invalid response from node: {0}")]
+    InvalidResponse(String),
+
+    #[error("Payee {0} is already in the address book")]
+    PayeeExists(String),
+
+    #[error("Unknown payee: {0}")]
+    UnknownPayee(String),
+
+    #[error("Invalid payee name: {0}")]
+    InvalidPayeeName(String),
+
+    #[error("Payment of {amount} exceeds the limit of {limit} per transaction")]
+    TransactionLimitExceeded { amount: u64, limit: u64 },
+
+    #[error("Payment of {amount} exceeds the daily limit of {limit}, {remaining} left today")]
+    DailyLimitExceeded {
+        amount: u64,
+        remaining: u64,
+        limit: u64,
+    },
+
+    #[error("Destination {0} is not allowed by the spending policy")]
+    DestinationNotAllowed(String),
+
+    #[error("Payments of {threshold} or more need the approval token")]
+    ApprovalRequired { threshold: u64 },
+
+    #[error("Wrong approval token")]
+    WrongApprovalToken,
+
+    #[error("Spending policy isn't signed by the wallet's key")]
+    PolicyNotSigned,
+
+    #[error("Invalid seed phrase: {0}")]
+    InvalidMnemonic(String),
+
+    #[error("Invalid derivation path: {0}")]
+    InvalidDerivationPath(String),
+
+    #[error("Invalid header: {0}")]
+    InvalidHeader(String),
+
+    #[error("Invalid merkle proof of {0}")]
+    InvalidProof(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,228 @@
+use std::{fmt, str::FromStr};
+
+use bip39::Mnemonic;
+use ed25519_dalek::SigningKey;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha512;
+
+use crate::{
+    errors::{Error, Result},
+    keychain::KeyChain,
+};
+
+// Key of the HMAC turning a seed into the master key, fixed by SLIP-10
+const SEED_KEY: &[u8] = b"ed25519 seed";
+const HARDENED: u32 = 1 << 31;
+
+// Paths follow BIP-44, m/44'/coin'/account'/chain'/index'. The coin type
+// isn't registered with SLIP-44
+pub const PURPOSE: u32 = 44;
+pub const COIN_TYPE: u32 = 7879;
+
+// Words of the seed phrases generated by the wallet
+const MNEMONIC_WORDS: usize = 24;
+
+// Key and chain code of a node of the SLIP-10 tree. ed25519 only has
+// hardened children, so the public half of a node can't derive anything
+#[derive(Clone)]
+pub struct ExtendedKey {
+    secret: [u8; 32],
+    chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    // Root of the tree of a BIP-39 seed
+    pub fn from_seed(seed: &[u8]) -> Self {
+        Self::from_hmac(SEED_KEY, &[seed])
+    }
+
+    pub fn from_parts(signing_key: &SigningKey, chain_code: [u8; 32]) -> Self {
+        Self {
+            secret: signing_key.to_bytes(),
+            chain_code,
+        }
+    }
+
+    // Hardened child `index`, counted from zero without the hardened bit
+    pub fn child(&self, index: u32) -> Self {
+        Self::from_hmac(
+            &self.chain_code,
+            &[&[0], &self.secret, &(index | HARDENED).to_be_bytes()],
+        )
+    }
+
+    // Descendant at the path below this key
+    pub fn derive(&self, path: &[u32]) -> Self {
+        path.iter()
+            .fold(self.clone(), |key, index| key.child(*index))
+    }
+
+    pub fn signing_key(&self) -> SigningKey {
+        SigningKey::from_bytes(&self.secret)
+    }
+
+    pub fn chain_code(&self) -> [u8; 32] {
+        self.chain_code
+    }
+
+    fn from_hmac(key: &[u8], data: &[&[u8]]) -> Self {
+        let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC takes keys of any size");
+        for data in data {
+            mac.update(data);
+        }
+        let output = mac.finalize().into_bytes();
+
+        let (secret, chain_code) = output.split_at(32);
+        Self {
+            secret: secret.try_into().expect("32 bytes"),
+            chain_code: chain_code.try_into().expect("32 bytes"),
+        }
+    }
+}
+
+// Where a wallet key sits in the tree of its seed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DerivationPath {
+    pub account: u32,
+    pub chain: KeyChain,
+    pub index: u32,
+}
+
+impl DerivationPath {
+    // Path of the account key, the key of the wallet itself
+    pub fn account(account: u32) -> [u32; 3] {
+        [PURPOSE, COIN_TYPE, account]
+    }
+
+    pub fn components(&self) -> [u32; 5] {
+        [
+            PURPOSE,
+            COIN_TYPE,
+            self.account,
+            self.chain as u32,
+            self.index,
+        ]
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("m")?;
+        for index in self.components() {
+            write!(f, "/{index}'")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DerivationPath {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidDerivationPath(s.to_string());
+
+        let mut parts = s.split('/');
+        if parts.next() != Some("m") {
+            return Err(invalid());
+        }
+        let components = parts
+            .map(|part| {
+                part.strip_suffix('\'')
+                    .or_else(|| part.strip_suffix('h'))
+                    .and_then(|index| index.parse::<u32>().ok())
+                    .filter(|index| *index < HARDENED)
+                    .ok_or_else(invalid)
+            })
+            .collect::<Result<Vec<u32>>>()?;
+
+        let [PURPOSE, COIN_TYPE, account, chain, index] = components[..] else {
+            return Err(invalid());
+        };
+        let chain = match chain {
+            0 => KeyChain::Receive,
+            1 => KeyChain::Change,
+            _ => return Err(invalid()),
+        };
+
+        Ok(Self {
+            account,
+            chain,
+            index,
+        })
+    }
+}
+
+// New BIP-39 seed phrase from the OS's randomness
+pub fn generate_mnemonic() -> String {
+    let mut entropy = [0u8; MNEMONIC_WORDS / 3 * 4];
+    OsRng.fill_bytes(&mut entropy);
+
+    Mnemonic::from_entropy(&entropy)
+        .expect("entropy has a valid length")
+        .to_string()
+}
+
+// Seed of a BIP-39 phrase, the passphrase makes it a different seed
+pub fn mnemonic_seed(phrase: &str, passphrase: &str) -> Result<[u8; 64]> {
+    let mnemonic = Mnemonic::parse(phrase).map_err(|e| Error::InvalidMnemonic(e.to_string()))?;
+    Ok(mnemonic.to_seed(passphrase))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn derives_slip10_test_vectors() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedKey::from_seed(&seed);
+        assert_eq!(
+            hex::encode(master.secret),
+            "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7"
+        );
+        assert_eq!(
+            hex::encode(master.chain_code()),
+            "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb"
+        );
+
+        let child = master.derive(&[0, 1]);
+        assert_eq!(
+            hex::encode(child.secret),
+            "b1d0bad404bf35da785a64ca1ac54b2617211d2777696fbffaf208f746ae84f2"
+        );
+        assert_eq!(
+            hex::encode(child.chain_code()),
+            "a320425f77d1b5c2505a6b1b27382b37368ee640e3557c315416801243552f14"
+        );
+    }
+
+    #[test]
+    fn reads_seed_phrases_and_paths() {
+        let phrase = "abandon abandon abandon abandon abandon abandon \
+                      abandon abandon abandon abandon abandon about";
+        assert_eq!(
+            hex::encode(mnemonic_seed(phrase, "TREZOR").unwrap()),
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f\
+             09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"
+        );
+        assert!(matches!(
+            mnemonic_seed("abandon about", ""),
+            Err(Error::InvalidMnemonic(_))
+        ));
+
+        let generated = generate_mnemonic();
+        assert_eq!(generated.split(' ').count(), MNEMONIC_WORDS);
+        assert!(mnemonic_seed(&generated, "").is_ok());
+
+        let path = DerivationPath {
+            account: 2,
+            chain: KeyChain::Change,
+            index: 5,
+        };
+        assert_eq!(path.to_string(), "m/44'/7879'/2'/1'/5'");
+        assert_eq!(path.to_string().parse::<DerivationPath>().unwrap(), path);
+        assert!("m/44'/7879'/2'/1'/5".parse::<DerivationPath>().is_err());
+        assert!("m/44'/0'/2'/1'/5'".parse::<DerivationPath>().is_err());
+    }
+}
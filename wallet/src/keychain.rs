@@ -0,0 +1,220 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use ed25519_dalek::SigningKey;
+
+use crate::hd::{DerivationPath, ExtendedKey};
+
+const KEY_CONTEXT: &str = "aurelius wallet 2024-11 key derivation";
+
+// Unused addresses kept derived ahead of the last used one of each chain
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+// Chains of keys derived from the wallet's key, change is kept apart so
+// handing out receive addresses never skips over change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum KeyChain {
+    Receive,
+    Change,
+}
+
+// How the keys of the chains are derived from the wallet's key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum Derivation {
+    // Hashed from the wallet's key, for wallets created from a random key
+    Hashed,
+    // SLIP-10 children of the wallet's key, the account key of a seed
+    // phrase, so any wallet knowing the phrase derives the same addresses
+    Slip10 { account: u32, chain_code: [u8; 32] },
+}
+
+// Key `index` of the chain. Every key is derived from the master secret, so
+// addresses can only be derived while the wallet is unlocked
+pub fn derive_key(master: &SigningKey, chain: KeyChain, index: u32) -> SigningKey {
+    let mut hasher = blake3::Hasher::new_derive_key(KEY_CONTEXT);
+    hasher.update(&master.to_bytes());
+    hasher.update(&[chain as u8]);
+    hasher.update(&index.to_le_bytes());
+
+    SigningKey::from_bytes(hasher.finalize().as_bytes())
+}
+
+// Addresses derived for a chain, in index order
+#[derive(Debug, Clone, Default, BorshSerialize, BorshDeserialize)]
+struct Branch {
+    addresses: Vec<[u8; 32]>,
+    // Leading addresses handed out or seen on chain
+    used: u32,
+}
+
+// Addresses the wallet derived ahead of time.
+//
+// Every chain keeps `gap_limit` unused addresses after its last used one. A
+// wallet restored from its key derives the same addresses, and as funds show
+// up on the last ones the window moves on, so a rescan finds every address
+// that was used as long as no more than `gap_limit` in a row were skipped
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct Keychain {
+    gap_limit: u32,
+    receive: Branch,
+    change: Branch,
+    derivation: Derivation,
+}
+
+impl Keychain {
+    pub fn new(gap_limit: u32) -> Self {
+        Self::with_derivation(gap_limit, Derivation::Hashed)
+    }
+
+    pub fn with_derivation(gap_limit: u32, derivation: Derivation) -> Self {
+        Self {
+            gap_limit,
+            receive: Branch::default(),
+            change: Branch::default(),
+            derivation,
+        }
+    }
+
+    pub fn derivation(&self) -> Derivation {
+        self.derivation
+    }
+
+    // Key `index` of the chain derived from the wallet's key
+    pub fn derive(&self, master: &SigningKey, chain: KeyChain, index: u32) -> SigningKey {
+        match self.derivation {
+            Derivation::Hashed => derive_key(master, chain, index),
+            Derivation::Slip10 { chain_code, .. } => ExtendedKey::from_parts(master, chain_code)
+                .derive(&[chain as u32, index])
+                .signing_key(),
+        }
+    }
+
+    // Path of the key in the tree of its seed phrase, `None` for keys not
+    // derived from one
+    pub fn path(&self, chain: KeyChain, index: u32) -> Option<DerivationPath> {
+        match self.derivation {
+            Derivation::Hashed => None,
+            Derivation::Slip10 { account, .. } => Some(DerivationPath {
+                account,
+                chain,
+                index,
+            }),
+        }
+    }
+
+    pub fn gap_limit(&self) -> u32 {
+        self.gap_limit
+    }
+
+    pub fn set_gap_limit(&mut self, gap_limit: u32) {
+        self.gap_limit = gap_limit;
+    }
+
+    // Whether addresses have to be derived to restore the window
+    pub fn needs_top_up(&self) -> bool {
+        [&self.receive, &self.change]
+            .into_iter()
+            .any(|branch| (branch.addresses.len() as u32) < branch.used + self.gap_limit)
+    }
+
+    // Derives addresses until every chain has `gap_limit` unused ones
+    pub fn top_up(&mut self, master: &SigningKey) {
+        for chain in [KeyChain::Receive, KeyChain::Change] {
+            while (self.branch(chain).addresses.len() as u32)
+                < self.branch(chain).used + self.gap_limit
+            {
+                let index = self.branch(chain).addresses.len() as u32;
+                let key = self.derive(master, chain, index);
+                self.branch_mut(chain)
+                    .addresses
+                    .push(key.verifying_key().to_bytes());
+            }
+        }
+    }
+
+    // Hands out the first unused address of the chain, `None` once the
+    // window ran out and has to be topped up
+    pub fn next(&mut self, chain: KeyChain) -> Option<[u8; 32]> {
+        let branch = self.branch_mut(chain);
+        let address = *branch.addresses.get(branch.used as usize)?;
+        branch.used += 1;
+
+        Some(address)
+    }
+
+    // Marks the address and the ones before it on its chain as used
+    pub fn mark_used(&mut self, chain: KeyChain, index: u32) {
+        let branch = self.branch_mut(chain);
+        branch.used = branch.used.max(index + 1);
+    }
+
+    // Chain and index of the first derived address matching `owns`
+    pub fn find(&self, owns: impl Fn(&[u8; 32]) -> bool) -> Option<(KeyChain, u32)> {
+        [KeyChain::Receive, KeyChain::Change]
+            .into_iter()
+            .find_map(|chain| {
+                let index = self.branch(chain).addresses.iter().position(&owns)?;
+                Some((chain, index as u32))
+            })
+    }
+
+    // Every derived address, used or not
+    pub fn addresses(&self) -> impl Iterator<Item = &[u8; 32]> {
+        self.receive
+            .addresses
+            .iter()
+            .chain(self.change.addresses.iter())
+    }
+
+    fn branch(&self, chain: KeyChain) -> &Branch {
+        match chain {
+            KeyChain::Receive => &self.receive,
+            KeyChain::Change => &self.change,
+        }
+    }
+
+    fn branch_mut(&mut self, chain: KeyChain) -> &mut Branch {
+        match chain {
+            KeyChain::Receive => &mut self.receive,
+            KeyChain::Change => &mut self.change,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keeps_a_window_of_unused_addresses() {
+        let master = SigningKey::from_bytes(&[3u8; 32]);
+        let mut keychain = Keychain::new(3);
+        keychain.top_up(&master);
+        assert_eq!(keychain.addresses().count(), 6);
+
+        // Derivation is deterministic and the chains don't overlap
+        let first = derive_key(&master, KeyChain::Receive, 0);
+        assert_eq!(
+            keychain.next(KeyChain::Receive),
+            Some(first.verifying_key().to_bytes())
+        );
+        assert_ne!(
+            derive_key(&master, KeyChain::Change, 0).to_bytes(),
+            first.to_bytes()
+        );
+
+        keychain.mark_used(KeyChain::Receive, 2);
+        assert!(keychain.next(KeyChain::Receive).is_none());
+        assert!(keychain.needs_top_up());
+
+        keychain.top_up(&master);
+        assert!(!keychain.needs_top_up());
+        assert_eq!(keychain.addresses().count(), 9);
+
+        let address = derive_key(&master, KeyChain::Receive, 4)
+            .verifying_key()
+            .to_bytes();
+        assert_eq!(
+            keychain.find(|a| *a == address),
+            Some((KeyChain::Receive, 4))
+        );
+    }
+}
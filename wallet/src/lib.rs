@@ -0,0 +1,12 @@
+pub mod address_book;
+pub mod analytics;
+pub mod backup;
+pub mod client;
+pub mod coin_selection;
+pub mod errors;
+pub mod hd;
+pub mod keychain;
+pub mod policy;
+pub mod rpc;
+pub mod spv;
+pub mod wallet;
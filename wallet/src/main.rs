@@ -1 +1,760 @@
-fn main() {}
+use std::{
+    io::{BufRead, Write},
+    path::Path,
+    time::Duration,
+};
+
+use corelib::{
+    address::{self, Address},
+    config::Network,
+    keystore::Keystore,
+    script::Script,
+    sign::verify_message,
+    transaction::Transaction,
+};
+use hex::FromHex;
+use wallet::{
+    analytics::uneconomical_fee_rate,
+    backup::Backup,
+    client::NodeClient,
+    errors::{Error, Result},
+    hd::generate_mnemonic,
+    policy::SpendingPolicy,
+    rpc::WalletRpc,
+    spv::HeaderChain,
+    wallet::Wallet,
+};
+
+const USAGE: &str = "usage:
+  wallet keygen [--seed-phrase]
+  wallet recover [account]
+  wallet import-keystore <path>
+  wallet address
+  wallet new-address
+  wallet gap-limit <addresses>
+  wallet balance
+  wallet estimate-fee <blocks>
+  wallet utxos [fee per byte]
+  wallet send <payee or address> <amount> [fee per byte]
+  wallet send-to-script <script hash> <amount> [fee per byte]
+  wallet script add <redeem script>
+  wallet history
+  wallet prove
+  wallet payee add <name> <address> [notes]
+  wallet payee update <name> <address> [notes]
+  wallet payee remove <name>
+  wallet payee list
+  wallet policy
+  wallet policy limit <per-transaction|per-day|approval> <amount or none>
+  wallet policy allow <payee or address>
+  wallet policy deny <payee or address>
+  wallet policy unlist <payee or address>
+  wallet policy token
+  wallet verify-message <address> <message> <signature>
+  wallet backup <path>
+  wallet restore <path> [--dry-run]
+  wallet serve <port>
+
+addresses are of the network in AURELIUS_NETWORK, mainnet unless set,
+hex encoded public keys are accepted too. Payments without a fee pay the
+node's estimate for confirming within 6 blocks, estimates above 100 per byte
+have to be confirmed. serve answers wallet_unlock, wallet_lock,
+sign_message and the other wallet RPC calls from local clients";
+
+// Wallet file used by the commands
+const DEFAULT_WALLET: &str = "wallet.dat";
+// RPC server of a local mainnet node
+const DEFAULT_NODE: &str = "127.0.0.1:7879";
+const DEFAULT_FEE_RATE: u64 = 1;
+// Blocks payments without a fee rate are meant to confirm within
+const DEFAULT_TARGET_BLOCKS: u64 = 6;
+// Highest estimated fee rate paid without asking, a node reporting more may
+// be wrong or hostile. Higher rates can still be given with the payment
+const MAX_FEE_RATE: u64 = 100;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let wallet_path = std::env::var("AURELIUS_WALLET").unwrap_or(DEFAULT_WALLET.to_string());
+    let node = NodeClient::new(std::env::var("AURELIUS_NODE").unwrap_or(DEFAULT_NODE.to_string()));
+    if let Ok(Err(e)) = std::env::var("AURELIUS_NETWORK").map(|name| name.parse::<Network>()) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["keygen"] => exit_on_error(keygen(&wallet_path, false)),
+        ["keygen", "--seed-phrase"] => exit_on_error(keygen(&wallet_path, true)),
+        ["recover"] => exit_on_error(recover(&wallet_path, "0")),
+        ["recover", account] => exit_on_error(recover(&wallet_path, account)),
+        ["import-keystore", path] => exit_on_error(import_keystore(&wallet_path, path)),
+        ["address"] => exit_on_error(Wallet::load(&wallet_path).map(|wallet| {
+            println!("{}", show_address(&wallet.public_key()));
+        })),
+        ["new-address"] => exit_on_error(new_address(&wallet_path)),
+        ["gap-limit", gap_limit] => exit_on_error(set_gap_limit(&wallet_path, gap_limit)),
+        ["balance"] => exit_on_error(balance(&wallet_path, &node)),
+        ["estimate-fee", blocks] => exit_on_error(estimate_fee(&node, blocks)),
+        ["utxos"] => exit_on_error(utxos(&wallet_path, &node, None)),
+        ["utxos", fee_rate] => exit_on_error(utxos(&wallet_path, &node, Some(fee_rate))),
+        ["send", receiver, amount] => {
+            exit_on_error(send(&wallet_path, &node, receiver, amount, None))
+        }
+        ["send", receiver, amount, fee_rate] => {
+            exit_on_error(send(&wallet_path, &node, receiver, amount, Some(fee_rate)))
+        }
+        ["send-to-script", script_hash, amount] => exit_on_error(send_to_script(
+            &wallet_path,
+            &node,
+            script_hash,
+            amount,
+            None,
+        )),
+        ["send-to-script", script_hash, amount, fee_rate] => exit_on_error(send_to_script(
+            &wallet_path,
+            &node,
+            script_hash,
+            amount,
+            Some(fee_rate),
+        )),
+        ["script", "add", script] => exit_on_error(add_script(&wallet_path, script)),
+        ["history"] => exit_on_error(history(&wallet_path)),
+        ["prove"] => exit_on_error(prove(&wallet_path, &node)),
+        ["payee", "add", name, address] => {
+            exit_on_error(save_payee(&wallet_path, name, address, "", false))
+        }
+        ["payee", "add", name, address, notes] => {
+            exit_on_error(save_payee(&wallet_path, name, address, notes, false))
+        }
+        ["payee", "update", name, address] => {
+            exit_on_error(save_payee(&wallet_path, name, address, "", true))
+        }
+        ["payee", "update", name, address, notes] => {
+            exit_on_error(save_payee(&wallet_path, name, address, notes, true))
+        }
+        ["payee", "remove", name] => exit_on_error(remove_payee(&wallet_path, name)),
+        ["payee", "list"] => exit_on_error(list_payees(&wallet_path)),
+        ["policy"] => exit_on_error(show_policy(&wallet_path)),
+        ["policy", "limit", limit, amount] => {
+            exit_on_error(set_policy_limit(&wallet_path, limit, amount))
+        }
+        ["policy", list @ ("allow" | "deny" | "unlist"), destination] => {
+            exit_on_error(list_destination(&wallet_path, list, destination))
+        }
+        ["policy", "token"] => exit_on_error(set_approval_token(&wallet_path)),
+        ["verify-message", address, message, signature] => {
+            let (Ok(address), Ok(signature)) = (
+                address::parse_key(address, network()),
+                <[u8; 64]>::from_hex(signature),
+            ) else {
+                eprintln!("expected an address and a hex encoded signature");
+                std::process::exit(1);
+            };
+
+            match verify_message(&address, message.as_bytes(), &signature) {
+                Ok(()) => println!("Signature is valid"),
+                Err(e) => {
+                    println!("{e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        ["backup", path] => exit_on_error(backup(&wallet_path, path)),
+        ["restore", path] => exit_on_error(restore(&wallet_path, path, false)),
+        ["restore", path, "--dry-run"] => exit_on_error(restore(&wallet_path, path, true)),
+        ["serve", port] => exit_on_error(serve(&wallet_path, port)),
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Creates a wallet with a new key, or the first account of a new seed
+// phrase which is shown once. An empty passphrase leaves it unencrypted
+fn keygen(wallet_path: &str, seed_phrase: bool) -> Result<()> {
+    refuse_overwrite(wallet_path)?;
+
+    let wallet = match seed_phrase {
+        true => {
+            let phrase = generate_mnemonic();
+            eprintln!("Seed phrase, write it down to recover the wallet:\n{phrase}");
+            Wallet::from_mnemonic(&phrase, "", 0)?
+        }
+        false => Wallet::new(),
+    };
+    save_new(wallet_path, wallet)
+}
+
+// Recreates the wallet of an account of a seed phrase, the outputs of its
+// addresses are found again by the rescan of `utxos` and `send`
+fn recover(wallet_path: &str, account: &str) -> Result<()> {
+    let account = account
+        .parse::<u32>()
+        .map_err(|_| Error::InvalidParams("invalid account".to_string()))?;
+    refuse_overwrite(wallet_path)?;
+
+    let phrase = prompt("Seed phrase: ")?;
+    let seed_passphrase = prompt("Seed passphrase (empty for none): ")?;
+    save_new(
+        wallet_path,
+        Wallet::from_mnemonic(phrase.trim(), &seed_passphrase, account)?,
+    )
+}
+
+// Creates a wallet spending what's paid to the key of a keystore file, e.g.
+// the rewards of a node mining with AURELIUS_MINING_KEYSTORE. The wallet
+// keeps the keystore's passphrase
+fn import_keystore(wallet_path: &str, keystore_path: &str) -> Result<()> {
+    refuse_overwrite(wallet_path)?;
+
+    let keystore = Keystore::load(keystore_path)?;
+    let passphrase = prompt("Keystore passphrase: ")?;
+    let wallet = Wallet::from_keystore(keystore, &passphrase)?;
+    wallet.save(wallet_path)?;
+
+    println!("{}", show_address(&wallet.public_key()));
+    Ok(())
+}
+
+fn save_new(wallet_path: &str, mut wallet: Wallet) -> Result<()> {
+    let passphrase = prompt("Passphrase (empty for none): ")?;
+    if !passphrase.is_empty() {
+        wallet.encrypt(&passphrase)?;
+    }
+    wallet.save(wallet_path)?;
+
+    println!("{}", show_address(&wallet.public_key()));
+    Ok(())
+}
+
+// Hands out a fresh receive address, a locked wallet is unlocked if the
+// derived addresses ran out
+fn new_address(wallet_path: &str) -> Result<()> {
+    let mut wallet = Wallet::load(wallet_path)?;
+
+    let address = match wallet.new_address() {
+        Err(Error::Locked) => {
+            let passphrase = prompt("Passphrase: ")?;
+            wallet.unlock(&passphrase, Duration::from_secs(60))?;
+            wallet.new_address()?
+        }
+        result => result?,
+    };
+    wallet.save(wallet_path)?;
+
+    println!("{}", show_address(&address));
+    Ok(())
+}
+
+fn set_gap_limit(wallet_path: &str, gap_limit: &str) -> Result<()> {
+    let gap_limit = gap_limit
+        .parse::<u32>()
+        .map_err(|_| Error::InvalidParams("invalid gap limit".to_string()))?;
+
+    let mut wallet = Wallet::load(wallet_path)?;
+    if wallet.is_encrypted() {
+        let passphrase = prompt("Passphrase: ")?;
+        wallet.unlock(&passphrase, Duration::from_secs(60))?;
+    }
+    wallet.set_gap_limit(gap_limit);
+    wallet.save(wallet_path)
+}
+
+// Balance of every address of the wallet
+fn balance(wallet_path: &str, node: &NodeClient) -> Result<()> {
+    let wallet = Wallet::load(wallet_path)?;
+
+    let mut balance = 0;
+    for address in wallet.addresses() {
+        balance += node.balance(address)?;
+    }
+    println!("{balance}");
+    Ok(())
+}
+
+fn estimate_fee(node: &NodeClient, blocks: &str) -> Result<()> {
+    let blocks = blocks
+        .parse::<u64>()
+        .ok()
+        .filter(|blocks| *blocks > 0)
+        .ok_or_else(|| Error::InvalidParams("invalid number of blocks".to_string()))?;
+
+    println!("{} per byte", node.estimate_fee_rate(blocks)?);
+    Ok(())
+}
+
+// Outputs shown as the largest and smallest ones
+const RANKED_UTXOS: usize = 5;
+
+// Ages of the wallet's outputs, the ones costing more to spend than they're
+// worth at the fee rate, and the largest and smallest ones
+fn utxos(wallet_path: &str, node: &NodeClient, fee_rate: Option<&str>) -> Result<()> {
+    let fee_rate = fee_rate
+        .map(str::parse::<u64>)
+        .transpose()
+        .map_err(|_| Error::InvalidParams("invalid fee rate".to_string()))?
+        .unwrap_or(DEFAULT_FEE_RATE);
+
+    let mut wallet = Wallet::load(wallet_path)?;
+    wallet.rescan(|address| node.unspent(address))?;
+    let stats = wallet.utxo_stats(node.block_count()?, fee_rate);
+
+    println!("{} outputs worth {}", stats.count, stats.value);
+    for bucket in stats.ages.iter() {
+        let ages = match bucket.max_confirmations {
+            Some(max) => format!("{}-{max}", bucket.min_confirmations),
+            None => format!("{}+", bucket.min_confirmations),
+        };
+        println!(
+            "  {ages} confirmations: {} worth {}",
+            bucket.count, bucket.value
+        );
+    }
+    println!(
+        "{} dust outputs worth {} at {fee_rate} per byte",
+        stats.dust, stats.dust_value
+    );
+
+    for (title, ranked) in [
+        ("largest", wallet.largest_utxos(RANKED_UTXOS)),
+        ("smallest", wallet.smallest_utxos(RANKED_UTXOS)),
+    ] {
+        println!("{title}:");
+        for utxo in ranked {
+            let Some(outpoint) = utxo.outpoint() else {
+                continue;
+            };
+            println!(
+                "  {}:{} {} (dust from {} per byte)",
+                hex::encode(outpoint.txid),
+                outpoint.vout,
+                utxo.value(),
+                uneconomical_fee_rate(utxo)
+            );
+        }
+    }
+    Ok(())
+}
+
+// Signs the payment locally with outputs synced from the node, which only
+// receives the signed transaction
+fn send(
+    wallet_path: &str,
+    node: &NodeClient,
+    receiver: &str,
+    amount: &str,
+    fee_rate: Option<&str>,
+) -> Result<()> {
+    pay(
+        wallet_path,
+        node,
+        amount,
+        fee_rate,
+        |wallet, amount, fee_rate| {
+            let receiver = wallet.address_book().resolve(receiver, network())?;
+            wallet.send(receiver, amount, fee_rate)
+        },
+    )
+}
+
+// Locks the payment to the hash of a redeem script instead of a key
+fn send_to_script(
+    wallet_path: &str,
+    node: &NodeClient,
+    script_hash: &str,
+    amount: &str,
+    fee_rate: Option<&str>,
+) -> Result<()> {
+    let script_hash = <[u8; 32]>::from_hex(script_hash)
+        .map_err(|_| Error::InvalidParams("invalid script hash".to_string()))?;
+
+    pay(
+        wallet_path,
+        node,
+        amount,
+        fee_rate,
+        |wallet, amount, fee_rate| wallet.send_to_script_hash(script_hash, amount, fee_rate),
+    )
+}
+
+fn pay(
+    wallet_path: &str,
+    node: &NodeClient,
+    amount: &str,
+    fee_rate: Option<&str>,
+    build: impl FnOnce(&mut Wallet, u64, u64) -> Result<Transaction>,
+) -> Result<()> {
+    let amount = amount
+        .parse::<u64>()
+        .map_err(|_| Error::InvalidParams("invalid amount".to_string()))?;
+    let fee_rate = match fee_rate {
+        Some(fee_rate) => fee_rate
+            .parse::<u64>()
+            .map_err(|_| Error::InvalidParams("invalid fee rate".to_string()))?,
+        // Nodes without fee estimation leave it at the default
+        None => {
+            let estimate = node
+                .estimate_fee_rate(DEFAULT_TARGET_BLOCKS)
+                .map_or(DEFAULT_FEE_RATE, |rate| rate.max(DEFAULT_FEE_RATE));
+            if estimate > MAX_FEE_RATE {
+                let answer = prompt(&format!(
+                    "The node estimates {estimate} per byte, over {MAX_FEE_RATE}. Pay it? [y/N] "
+                ))?;
+                if !answer.eq_ignore_ascii_case("y") {
+                    return Err(Error::InvalidParams(format!(
+                        "estimated fee rate {estimate} refused, give the fee rate to pay"
+                    )));
+                }
+            }
+            estimate
+        }
+    };
+
+    let mut wallet = Wallet::load(wallet_path)?;
+
+    // Unlocked before the rescan so the address window can move on
+    if wallet.is_encrypted() {
+        let passphrase = prompt("Passphrase: ")?;
+        wallet.unlock(&passphrase, Duration::from_secs(60))?;
+    }
+    wallet.rescan(|address| node.unspent(address))?;
+    // Payments are locked to the next block, AURELIUS_NO_LOCKTIME opts out
+    wallet.set_chain_height(node.block_count()?);
+    wallet.set_anti_fee_sniping(std::env::var_os("AURELIUS_NO_LOCKTIME").is_none());
+
+    // Large payments take the approval token of the spending policy
+    if wallet
+        .policy()
+        .approval_threshold
+        .is_some_and(|threshold| amount >= threshold)
+    {
+        let token = prompt("Approval token: ")?;
+        wallet.approve(&token)?;
+    }
+
+    let txn = build(&mut wallet, amount, fee_rate)?;
+    let txid = node.send_transaction(&txn)?;
+    wallet.save(wallet_path)?;
+
+    println!("{}", hex::encode(txid));
+    Ok(())
+}
+
+// Remembers a redeem script so outputs paid to its hash can be spent, prints
+// the hash to pay to
+fn add_script(wallet_path: &str, script: &str) -> Result<()> {
+    let script = script
+        .parse::<Script>()
+        .map_err(|e| Error::InvalidParams(e.to_string()))?;
+
+    let mut wallet = Wallet::load(wallet_path)?;
+    let script_hash = wallet.add_redeem_script(&script)?;
+    wallet.save(wallet_path)?;
+
+    println!("{}", hex::encode(script_hash));
+    Ok(())
+}
+
+fn history(wallet_path: &str) -> Result<()> {
+    let wallet = Wallet::load(wallet_path)?;
+
+    for entry in wallet.history() {
+        let counterparty = entry
+            .counterparty
+            .map(|address| payee_name(&wallet, &address).unwrap_or(show_address(&address)))
+            .unwrap_or_default();
+        println!(
+            "{} {} {:?} {} (fee {}) {counterparty}",
+            entry.timestamp,
+            hex::encode(entry.txid),
+            entry.direction,
+            entry.amount,
+            entry.fee
+        );
+    }
+    Ok(())
+}
+
+// Syncs the wallet's own copy of the headers from the node, then asks it for
+// merkle proofs of the history's transactions. They're checked against the
+// headers and kept in the wallet as evidence of the payments
+fn prove(wallet_path: &str, node: &NodeClient) -> Result<()> {
+    let mut wallet = Wallet::load(wallet_path)?;
+
+    let headers_path = Path::new(wallet_path).with_extension("headers");
+    let mut headers = match headers_path.exists() {
+        true => HeaderChain::load(&headers_path)?,
+        false => HeaderChain::new(network()),
+    };
+    if headers.network() != network() {
+        return Err(Error::InvalidHeader(format!(
+            "{} holds {} headers",
+            headers_path.display(),
+            headers.network()
+        )));
+    }
+    headers.sync(|from| node.headers(from))?;
+    headers.save(&headers_path)?;
+
+    wallet.prove_payments(&headers, |txid| node.tx_proof(txid))?;
+    wallet.save(wallet_path)?;
+
+    for entry in wallet.history() {
+        match wallet.payment_proof(&entry.txid) {
+            Some(proof) => println!(
+                "{} in block {} at height {}, {} confirmations",
+                hex::encode(entry.txid),
+                hex::encode(proof.block_hash),
+                proof.height,
+                proof.confirmations(&headers)
+            ),
+            None => println!("{} unconfirmed", hex::encode(entry.txid)),
+        }
+    }
+    Ok(())
+}
+
+fn save_payee(
+    wallet_path: &str,
+    name: &str,
+    address: &str,
+    notes: &str,
+    update: bool,
+) -> Result<()> {
+    let address = address::parse_key(address, network())?;
+
+    let mut wallet = Wallet::load(wallet_path)?;
+    let book = wallet.address_book_mut();
+    if update {
+        book.update(name, address, notes.to_string())?;
+    } else {
+        book.add(name, address, notes.to_string())?;
+    }
+    wallet.save(wallet_path)
+}
+
+fn remove_payee(wallet_path: &str, name: &str) -> Result<()> {
+    let mut wallet = Wallet::load(wallet_path)?;
+    wallet.address_book_mut().remove(name)?;
+    wallet.save(wallet_path)
+}
+
+fn list_payees(wallet_path: &str) -> Result<()> {
+    let wallet = Wallet::load(wallet_path)?;
+
+    for (name, payee) in wallet.address_book().payees() {
+        println!("{name} {} {}", show_address(&payee.address), payee.notes);
+    }
+    Ok(())
+}
+
+fn show_policy(wallet_path: &str) -> Result<()> {
+    let wallet = Wallet::load(wallet_path)?;
+    let policy = wallet.policy();
+    let limit = |limit: Option<u64>| limit.map_or("none".to_string(), |limit| limit.to_string());
+
+    println!("per transaction: {}", limit(policy.max_per_transaction));
+    println!(
+        "per day: {} ({} spent today)",
+        limit(policy.max_per_day),
+        wallet.spent_today()
+    );
+    println!(
+        "approval from: {}{}",
+        limit(policy.approval_threshold),
+        match policy.has_approval_token() {
+            true => "",
+            false => " (no token set)",
+        }
+    );
+    for (list, addresses) in [("allow", &policy.allowlist), ("deny", &policy.denylist)] {
+        for address in addresses {
+            let name = payee_name(&wallet, address).unwrap_or_default();
+            println!("{list} {} {name}", show_address(address));
+        }
+    }
+    Ok(())
+}
+
+fn set_policy_limit(wallet_path: &str, limit: &str, amount: &str) -> Result<()> {
+    let amount = match amount {
+        "none" => None,
+        amount => Some(
+            amount
+                .parse::<u64>()
+                .map_err(|_| Error::InvalidParams("invalid amount".to_string()))?,
+        ),
+    };
+
+    update_policy(wallet_path, |_, policy| {
+        match limit {
+            "per-transaction" => policy.max_per_transaction = amount,
+            "per-day" => policy.max_per_day = amount,
+            "approval" => policy.approval_threshold = amount,
+            _ => return Err(Error::InvalidParams(format!("unknown limit {limit}"))),
+        }
+        Ok(())
+    })
+}
+
+// Moves a destination to the allowlist or denylist, or off both
+fn list_destination(wallet_path: &str, list: &str, destination: &str) -> Result<()> {
+    update_policy(wallet_path, |wallet, policy| {
+        let destination = wallet.address_book().resolve(destination, network())?;
+        policy.allowlist.remove(&destination);
+        policy.denylist.remove(&destination);
+        match list {
+            "allow" => policy.allowlist.insert(destination),
+            "deny" => policy.denylist.insert(destination),
+            _ => false,
+        };
+        Ok(())
+    })
+}
+
+fn set_approval_token(wallet_path: &str) -> Result<()> {
+    update_policy(wallet_path, |_, policy| {
+        match prompt("New approval token (empty to remove it): ")?.as_str() {
+            "" => policy.clear_approval_token(),
+            token => policy.set_approval_token(token)?,
+        }
+        Ok(())
+    })
+}
+
+// Changes the spending policy, which takes the passphrase and the approval
+// token of the current policy
+fn update_policy(
+    wallet_path: &str,
+    change: impl FnOnce(&Wallet, &mut SpendingPolicy) -> Result<()>,
+) -> Result<()> {
+    let mut wallet = Wallet::load(wallet_path)?;
+    let mut policy = wallet.policy().clone();
+    change(&wallet, &mut policy)?;
+
+    if wallet.is_encrypted() {
+        let passphrase = prompt("Passphrase: ")?;
+        wallet.unlock(&passphrase, Duration::from_secs(60))?;
+    }
+    if wallet.policy().has_approval_token() {
+        let token = prompt("Approval token: ")?;
+        wallet.approve(&token)?;
+    }
+    wallet.set_policy(policy)?;
+    wallet.save(wallet_path)
+}
+
+fn payee_name(wallet: &Wallet, address: &[u8; 32]) -> Option<String> {
+    wallet
+        .address_book()
+        .payees()
+        .find(|(_, payee)| &payee.address == address)
+        .map(|(name, _)| name.to_string())
+}
+
+// Network addresses are shown and read for, checked by `main`
+fn network() -> Network {
+    std::env::var("AURELIUS_NETWORK")
+        .ok()
+        .and_then(|name| name.parse().ok())
+        .unwrap_or(Network::Mainnet)
+}
+
+fn show_address(key: &[u8; 32]) -> String {
+    Address::new(network(), *key).to_string()
+}
+
+// Serves the wallet RPC until the listener fails, the wallet is saved after
+// calls changing it
+fn serve(wallet_path: &str, port: &str) -> Result<()> {
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| Error::InvalidParams("invalid port".to_string()))?;
+    let wallet = Wallet::load(wallet_path)?;
+
+    eprintln!("Wallet RPC listening on 127.0.0.1:{port}");
+    WalletRpc::new(wallet)
+        .with_network(network())
+        .with_path(wallet_path)
+        .serve(port)
+}
+
+fn backup(wallet_path: &str, path: &str) -> Result<()> {
+    let mut wallet = Wallet::load(wallet_path)?;
+    let passphrase = prompt("Backup passphrase: ")?;
+
+    // Encrypted wallets are unlocked with the same passphrase
+    if wallet.is_encrypted() {
+        wallet.unlock(&passphrase, std::time::Duration::from_secs(60))?;
+    }
+
+    let archive = Backup::from_wallet(&mut wallet)?.to_archive(&passphrase)?;
+    std::fs::write(path, archive)?;
+
+    println!("Wallet backed up to {path}");
+    Ok(())
+}
+
+fn restore(wallet_path: &str, path: &str, dry_run: bool) -> Result<()> {
+    let archive = std::fs::read(path)?;
+    let passphrase = prompt("Backup passphrase: ")?;
+    let backup = Backup::from_archive(&archive, &passphrase)?;
+
+    println!("Address: {}", show_address(&backup.public_key));
+    println!("Encrypted: {}", backup.encrypted);
+    println!("Labels: {}", backup.labels.len());
+    for (address, label) in backup.labels.iter() {
+        println!("  {} {label}", show_address(address));
+    }
+    println!("History entries: {}", backup.history.len());
+    for entry in backup.history.iter() {
+        println!(
+            "  {} {:?} {} (fee {})",
+            hex::encode(entry.txid),
+            entry.direction,
+            entry.amount,
+            entry.fee
+        );
+    }
+
+    if dry_run {
+        return Ok(());
+    }
+    refuse_overwrite(wallet_path)?;
+
+    backup.into_wallet(&passphrase)?.save(wallet_path)?;
+    println!("Wallet restored to {wallet_path}");
+    Ok(())
+}
+
+fn refuse_overwrite(wallet_path: &str) -> Result<()> {
+    if Path::new(wallet_path).exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("refusing to overwrite existing wallet {wallet_path}"),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn prompt(message: &str) -> Result<String> {
+    eprint!("{message}");
+    std::io::stderr().flush()?;
+
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn exit_on_error(result: Result<()>) {
+    if let Err(e) = result {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::BTreeSet;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use borsh::{BorshDeserialize, BorshSerialize};
+use corelib::{errors::Error as CoreError, keystore::KdfParams};
+use rand::{rngs::OsRng, RngCore};
+
+use crate::errors::{Error, Result};
+
+// Domain separation context of the wallet key's signature over the policy
+const POLICY_CONTEXT: &[u8] = b"aurelius wallet 2026-10 spending policy";
+
+// Window the daily limit applies to, in milliseconds
+pub const DAY: u128 = 24 * 60 * 60 * 1_000;
+
+// Limits on the payments a wallet signs, for hot wallets run by services.
+// Every limit is optional, the default policy allows any payment
+#[derive(Debug, Clone, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct SpendingPolicy {
+    pub max_per_transaction: Option<u64>,
+    // Sum of the payments over the last 24 hours
+    pub max_per_day: Option<u64>,
+    // Destinations payments may go to, any destination if empty
+    pub allowlist: BTreeSet<[u8; 32]>,
+    pub denylist: BTreeSet<[u8; 32]>,
+    // Payments of at least this amount need the approval token
+    pub approval_threshold: Option<u64>,
+    approval_token: Option<ApprovalToken>,
+}
+
+// Only a salted argon2id hash of the token is kept in the wallet file, so
+// guessing the token from a stolen file is as slow as guessing a passphrase
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+struct ApprovalToken {
+    kdf: KdfParams,
+    salt: [u8; 16],
+    hash: [u8; 32],
+}
+
+impl SpendingPolicy {
+    pub fn set_approval_token(&mut self, token: &str) -> Result<()> {
+        let kdf = KdfParams::default();
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+
+        self.approval_token = Some(ApprovalToken {
+            kdf,
+            salt,
+            hash: token_hash(&kdf, &salt, token)?,
+        });
+        Ok(())
+    }
+
+    pub fn clear_approval_token(&mut self) {
+        self.approval_token = None;
+    }
+
+    pub fn has_approval_token(&self) -> bool {
+        self.approval_token.is_some()
+    }
+
+    pub fn verify_token(&self, token: &str) -> bool {
+        self.approval_token.as_ref().is_some_and(|stored| {
+            token_hash(&stored.kdf, &stored.salt, token).is_ok_and(|hash| hash == stored.hash)
+        })
+    }
+
+    // What the wallet key signs so the policy in the wallet file can't be
+    // changed without it
+    pub(crate) fn signed_message(&self) -> Vec<u8> {
+        let policy = borsh::to_vec(self).expect("policies are always serializable");
+        [POLICY_CONTEXT, &policy].concat()
+    }
+
+    // Checks a payment of `amount` to `destinations` given what was already
+    // spent today, `approved` tells whether the approval token was given
+    pub fn check(
+        &self,
+        destinations: &[[u8; 32]],
+        amount: u64,
+        spent_today: u64,
+        approved: bool,
+    ) -> Result<()> {
+        if let Some(destination) = destinations.iter().find(|destination| {
+            self.denylist.contains(*destination)
+                || (!self.allowlist.is_empty() && !self.allowlist.contains(*destination))
+        }) {
+            return Err(Error::DestinationNotAllowed(hex::encode(destination)));
+        }
+        if let Some(limit) = self.max_per_transaction.filter(|limit| amount > *limit) {
+            return Err(Error::TransactionLimitExceeded { amount, limit });
+        }
+        if let Some(limit) = self.max_per_day {
+            let remaining = limit.saturating_sub(spent_today);
+            if amount > remaining {
+                return Err(Error::DailyLimitExceeded {
+                    amount,
+                    remaining,
+                    limit,
+                });
+            }
+        }
+        if let Some(threshold) = self.approval_threshold {
+            if amount >= threshold && !approved {
+                return Err(Error::ApprovalRequired { threshold });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn token_hash(kdf: &KdfParams, salt: &[u8; 16], token: &str) -> Result<[u8; 32]> {
+    let invalid = |e: argon2::Error| CoreError::InvalidKeystore(e.to_string());
+    let params =
+        Params::new(kdf.memory_kib, kdf.iterations, kdf.parallelism, Some(32)).map_err(invalid)?;
+    let mut hash = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(token.as_bytes(), salt, &mut hash)
+        .map_err(invalid)?;
+
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn enforces_limits_and_destinations() {
+        let (alice, bob) = ([1u8; 32], [2u8; 32]);
+        let mut policy = SpendingPolicy {
+            max_per_transaction: Some(1_000),
+            max_per_day: Some(1_500),
+            approval_threshold: Some(800),
+            ..SpendingPolicy::default()
+        };
+        assert!(SpendingPolicy::default()
+            .check(&[alice], u64::MAX, u64::MAX, false)
+            .is_ok());
+
+        assert!(policy.check(&[alice], 500, 0, false).is_ok());
+        assert!(matches!(
+            policy.check(&[alice], 1_001, 0, true),
+            Err(Error::TransactionLimitExceeded { limit: 1_000, .. })
+        ));
+        assert!(matches!(
+            policy.check(&[alice], 600, 1_000, true),
+            Err(Error::DailyLimitExceeded { remaining: 500, .. })
+        ));
+        assert!(matches!(
+            policy.check(&[alice], 800, 0, false),
+            Err(Error::ApprovalRequired { threshold: 800 })
+        ));
+        assert!(policy.check(&[alice], 800, 0, true).is_ok());
+
+        policy.denylist.insert(bob);
+        assert!(matches!(
+            policy.check(&[bob], 1, 0, false),
+            Err(Error::DestinationNotAllowed(_))
+        ));
+        policy.denylist.clear();
+        policy.allowlist.insert(alice);
+        assert!(policy.check(&[alice], 1, 0, false).is_ok());
+        assert!(policy.check(&[bob], 1, 0, false).is_err());
+        assert!(policy.check(&[alice, bob], 1, 0, false).is_err());
+    }
+
+    #[test]
+    fn keeps_only_a_hash_of_the_token() {
+        let mut policy = SpendingPolicy::default();
+        assert!(!policy.verify_token(""));
+
+        policy.set_approval_token("second factor").unwrap();
+        assert!(policy.verify_token("second factor"));
+        assert!(!policy.verify_token("guess"));
+
+        let bytes = borsh::to_vec(&policy).unwrap();
+        assert!(!bytes
+            .windows("second factor".len())
+            .any(|window| window == b"second factor"));
+        assert_eq!(SpendingPolicy::try_from_slice(&bytes).unwrap(), policy);
+    }
+}
@@ -0,0 +1,452 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use corelib::{
+    address::{self, Address},
+    config::Network,
+    sign::verify_message,
+    utxo::OutPoint,
+};
+use hex::FromHex;
+use serde_json::{json, Value};
+
+use crate::{
+    errors::{Error, Result},
+    wallet::Wallet,
+};
+
+// Largest request accepted, messages to sign are short
+const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+// Time a client has to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// Known method that failed, e.g. signing with a locked wallet
+const SERVER_ERROR: i64 = -32000;
+
+// Wallet methods exposed over RPC, the wallet is shared between calls
+#[derive(Debug, Clone)]
+pub struct WalletRpc {
+    wallet: Arc<Mutex<Wallet>>,
+    // Network of the addresses taken and handed out
+    network: Network,
+    // File the wallet is saved to after calls changing it
+    path: Option<PathBuf>,
+}
+
+impl WalletRpc {
+    pub fn new(wallet: Wallet) -> Self {
+        Self {
+            wallet: Arc::new(Mutex::new(wallet)),
+            network: Network::Mainnet,
+            path: None,
+        }
+    }
+
+    pub fn with_network(mut self, network: Network) -> Self {
+        self.network = network;
+        self
+    }
+
+    // Saves the wallet to the file whenever a call changes it, e.g. hands
+    // out a new address
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    // Answers JSON-RPC 2.0 over HTTP until accepting fails, every connection
+    // carries a single POST request. Only local clients can connect, the
+    // calls unlock the wallet and sign with its keys
+    pub fn serve(self, port: u16) -> Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let rpc = self.clone();
+            // A client hanging up only loses its own answer
+            thread::spawn(move || rpc.handle_connection(stream));
+        }
+
+        Ok(())
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let response = match read_body(&mut stream)? {
+            Some(body) => self.handle_body(&body),
+            None => error_response(Value::Null, INVALID_REQUEST, "Invalid HTTP request"),
+        };
+
+        let body = response.to_string();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes())?;
+
+        Ok(())
+    }
+
+    // Answers a JSON-RPC request body with the response object
+    pub fn handle_body(&self, body: &[u8]) -> Value {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(e) => return error_response(Value::Null, PARSE_ERROR, &e.to_string()),
+        };
+
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            return error_response(id, INVALID_REQUEST, "Missing method");
+        };
+        let params = request.get("params").cloned().unwrap_or(json!([]));
+
+        match self.handle(method, &params) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => {
+                let code = match e {
+                    Error::UnknownMethod(_) => METHOD_NOT_FOUND,
+                    Error::InvalidParams(_) | Error::UnlockTimeoutTooLong(_) => INVALID_PARAMS,
+                    _ => SERVER_ERROR,
+                };
+                error_response(id, code, &e.to_string())
+            }
+        }
+    }
+
+    fn save(&self, wallet: &Wallet) -> Result<()> {
+        match &self.path {
+            Some(path) => wallet.save(path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn wallet(&self) -> MutexGuard<'_, Wallet> {
+        self.wallet.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn wallet_unlock(&self, passphrase: &str, timeout: Duration) -> Result<()> {
+        self.wallet().unlock(passphrase, timeout)
+    }
+
+    pub fn wallet_lock(&self) -> Result<()> {
+        self.wallet().lock()
+    }
+
+    // Signs `message` with the key of `address`, returns the hex signature
+    pub fn sign_message(&self, address: &[u8; 32], message: &str) -> Result<String> {
+        let signature = self.wallet().sign_message(address, message.as_bytes())?;
+        Ok(hex::encode(signature))
+    }
+
+    pub fn verify_message(&self, address: &[u8; 32], message: &str, signature: &[u8; 64]) -> bool {
+        verify_message(address, message.as_bytes(), signature).is_ok()
+    }
+
+    // Unspent outputs of the wallet, identified by their outpoint
+    pub fn list_unspent(&self) -> Vec<(OutPoint, u64)> {
+        let mut unspent: Vec<(OutPoint, u64)> = self
+            .wallet()
+            .utxos()
+            .filter_map(|utxo| Some((utxo.outpoint()?, utxo.value())))
+            .collect();
+        unspent.sort();
+        unspent
+    }
+
+    // Stops tracking an output, e.g. one spent by another wallet using the
+    // same key
+    pub fn forget_unspent(&self, outpoint: &OutPoint) -> bool {
+        self.wallet().remove_utxo(outpoint).is_some()
+    }
+
+    // Dispatches a call by method name, params are positional
+    pub fn handle(&self, method: &str, params: &Value) -> Result<Value> {
+        match method {
+            "sign_message" => {
+                let address = self.address_param(params, 0)?;
+                let message = str_param(params, 1, "message")?;
+
+                Ok(Value::String(self.sign_message(&address, message)?))
+            }
+            "verify_message" => {
+                let address = self.address_param(params, 0)?;
+                let message = str_param(params, 1, "message")?;
+                let signature = hex_param::<[u8; 64]>(params, 2, "signature")?;
+
+                Ok(Value::Bool(
+                    self.verify_message(&address, message, &signature),
+                ))
+            }
+            "wallet_unlock" => {
+                let passphrase = str_param(params, 0, "passphrase")?;
+                let timeout = params.get(1).and_then(Value::as_u64).ok_or_else(|| {
+                    Error::InvalidParams("expected timeout in seconds".to_string())
+                })?;
+
+                self.wallet_unlock(passphrase, Duration::from_secs(timeout))?;
+                Ok(Value::Null)
+            }
+            "get_new_address" => {
+                let mut wallet = self.wallet();
+                let key = wallet.new_address()?;
+                self.save(&wallet)?;
+                Ok(Value::String(Address::new(self.network, key).to_string()))
+            }
+            "wallet_lock" => {
+                self.wallet_lock()?;
+                Ok(Value::Null)
+            }
+            "list_unspent" => Ok(Value::Array(
+                self.list_unspent()
+                    .into_iter()
+                    .map(|(outpoint, value)| {
+                        json!({ "outpoint": outpoint.to_string(), "value": value })
+                    })
+                    .collect(),
+            )),
+            "forget_unspent" => {
+                let outpoint = outpoint_param(params, 0, "outpoint")?;
+                let forgotten = self.forget_unspent(&outpoint);
+                self.save(&self.wallet())?;
+                Ok(Value::Bool(forgotten))
+            }
+            _ => Err(Error::UnknownMethod(method.to_string())),
+        }
+    }
+
+    // Address of the wallet's network, or a hex encoded public key
+    fn address_param(&self, params: &Value, index: usize) -> Result<[u8; 32]> {
+        address::parse_key(str_param(params, index, "address")?, self.network)
+            .map_err(|e| Error::InvalidParams(e.to_string()))
+    }
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+// Reads the body of an HTTP request, `None` if the request is malformed or
+// larger than `MAX_REQUEST_SIZE`
+fn read_body(stream: &mut TcpStream) -> Result<Option<Vec<u8>>> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 4096];
+
+    let header_end = loop {
+        if let Some(position) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break position + 4;
+        }
+        if request.len() > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buffer[..read]);
+    };
+
+    let headers = String::from_utf8_lossy(&request[..header_end]).to_lowercase();
+    let Some(length) = headers
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|length| length.trim().parse::<usize>().ok())
+    else {
+        return Ok(None);
+    };
+    if length > MAX_REQUEST_SIZE {
+        return Ok(None);
+    }
+
+    while request.len() < header_end + length {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    Ok(Some(request[header_end..header_end + length].to_vec()))
+}
+
+fn str_param<'a>(params: &'a Value, index: usize, name: &str) -> Result<&'a str> {
+    params
+        .get(index)
+        .and_then(Value::as_str)
+        .ok_or_else(|| Error::InvalidParams(format!("expected {name}")))
+}
+
+fn outpoint_param(params: &Value, index: usize, name: &str) -> Result<OutPoint> {
+    str_param(params, index, name)?
+        .parse()
+        .map_err(|_| Error::InvalidParams(format!("expected {name} as <txid>:<vout>")))
+}
+
+fn hex_param<T: FromHex>(params: &Value, index: usize, name: &str) -> Result<T> {
+    T::from_hex(str_param(params, index, name)?)
+        .map_err(|_| Error::InvalidParams(format!("expected {name} as hex")))
+}
+
+#[cfg(test)]
+mod test {
+    use corelib::utxo::UTXO;
+
+    use super::*;
+    use crate::client::NodeClient;
+
+    #[test]
+    fn serves_calls_over_http() {
+        let mut wallet = Wallet::new();
+        wallet.encrypt("secret").unwrap();
+        let address = Address::new(Network::Regtest, wallet.public_key()).to_string();
+        let rpc = WalletRpc::new(wallet).with_network(Network::Regtest);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        thread::spawn({
+            let rpc = rpc.clone();
+            move || rpc.serve(port)
+        });
+        thread::sleep(Duration::from_millis(50));
+        let client = NodeClient::new(format!("127.0.0.1:{port}"));
+
+        assert!(matches!(
+            client.call("sign_message", json!([address, "proof"])),
+            Err(Error::Rpc {
+                code: SERVER_ERROR,
+                ..
+            })
+        ));
+        client.call("wallet_unlock", json!(["secret", 60])).unwrap();
+        let signature = client
+            .call("sign_message", json!([address, "proof"]))
+            .unwrap();
+        assert_eq!(
+            rpc.handle("verify_message", &json!([address, "proof", signature]))
+                .unwrap(),
+            Value::Bool(true)
+        );
+        client.call("wallet_lock", json!([])).unwrap();
+        assert!(rpc.wallet().is_locked());
+
+        // Timeouts the clock can't count are refused
+        assert!(matches!(
+            client.call("wallet_unlock", json!(["secret", u64::MAX])),
+            Err(Error::Rpc {
+                code: INVALID_PARAMS,
+                ..
+            })
+        ));
+        assert!(matches!(
+            client.call("wallet_dump", json!([])),
+            Err(Error::Rpc {
+                code: METHOD_NOT_FOUND,
+                ..
+            })
+        ));
+        assert_eq!(
+            rpc.handle_body(b"not json")["error"]["code"],
+            json!(PARSE_ERROR)
+        );
+    }
+
+    #[test]
+    fn unlocks_and_locks_over_rpc() {
+        let mut wallet = Wallet::new();
+        wallet.encrypt("secret").unwrap();
+        let rpc = WalletRpc::new(wallet);
+
+        rpc.handle("wallet_unlock", &json!(["secret", 60])).unwrap();
+        assert!(!rpc.wallet().is_locked());
+
+        rpc.handle("wallet_lock", &json!([])).unwrap();
+        assert!(rpc.wallet().is_locked());
+
+        assert!(matches!(
+            rpc.handle("wallet_unlock", &json!(["secret"])),
+            Err(Error::InvalidParams(_))
+        ));
+        assert!(matches!(
+            rpc.handle("wallet_dump", &json!([])),
+            Err(Error::UnknownMethod(_))
+        ));
+    }
+
+    #[test]
+    fn signs_and_verifies_messages_over_rpc() {
+        let rpc = WalletRpc::new(Wallet::new()).with_network(Network::Regtest);
+        let address = Address::new(Network::Regtest, rpc.wallet().public_key()).to_string();
+
+        let signature = rpc
+            .handle("sign_message", &json!([address, "proof"]))
+            .unwrap();
+
+        let valid = rpc
+            .handle("verify_message", &json!([address, "proof", signature]))
+            .unwrap();
+        assert_eq!(valid, Value::Bool(true));
+
+        let valid = rpc
+            .handle("verify_message", &json!([address, "forged", signature]))
+            .unwrap();
+        assert_eq!(valid, Value::Bool(false));
+
+        // Hex keys are still taken, addresses of other networks aren't
+        let key = hex::encode(rpc.wallet().public_key());
+        let valid = rpc
+            .handle("verify_message", &json!([key, "proof", signature]))
+            .unwrap();
+        assert_eq!(valid, Value::Bool(true));
+        let mainnet = Address::new(Network::Mainnet, rpc.wallet().public_key()).to_string();
+        assert!(matches!(
+            rpc.handle("sign_message", &json!([mainnet, "proof"])),
+            Err(Error::InvalidParams(_))
+        ));
+    }
+
+    #[test]
+    fn lists_unspent_outputs_by_outpoint() {
+        let mut wallet = Wallet::new();
+        let utxo = UTXO::new(5_000, 1, wallet.public_key())
+            .unwrap()
+            .confirm_utxo([3u8; 32], 1, false)
+            .unwrap();
+        wallet.add_utxo(utxo).unwrap();
+        let rpc = WalletRpc::new(wallet);
+
+        let outpoint = format!("{}:1", hex::encode([3u8; 32]));
+        assert_eq!(
+            rpc.handle("list_unspent", &json!([])).unwrap(),
+            json!([{ "outpoint": outpoint, "value": 5_000 }])
+        );
+
+        assert_eq!(
+            rpc.handle("forget_unspent", &json!([outpoint])).unwrap(),
+            Value::Bool(true)
+        );
+        assert_eq!(rpc.handle("list_unspent", &json!([])).unwrap(), json!([]));
+        assert!(matches!(
+            rpc.handle("forget_unspent", &json!(["not an outpoint"])),
+            Err(Error::InvalidParams(_))
+        ));
+    }
+}
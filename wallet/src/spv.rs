@@ -0,0 +1,376 @@
+use std::path::Path;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use corelib::{
+    block::BlockHeader,
+    config::{retarget, ChainParams, Network},
+    merkle::{Proof, Tree},
+};
+
+use crate::errors::{Error, Result};
+
+// Best chain of headers as the wallet validated them, its own view of the
+// chain that a node's merkle proofs are checked against. Every header must
+// hash to its hash, meet its target, link to its parent and have the
+// difficulty the retargeting rules give it, so a node can't make up blocks
+// without doing the work. The genesis header is taken as the node sends it
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize)]
+pub struct HeaderChain {
+    network: Network,
+    #[borsh(skip)]
+    params: ChainParams,
+    headers: Vec<BlockHeader>,
+}
+
+impl HeaderChain {
+    pub fn new(network: Network) -> Self {
+        Self {
+            network,
+            params: ChainParams::default(),
+            headers: Vec::new(),
+        }
+    }
+
+    // Follows custom consensus parameters instead of the defaults
+    pub fn with_params(mut self, params: ChainParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::try_from_slice(&std::fs::read(path)?)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+
+        std::fs::write(&tmp, borsh::to_vec(self)?)?;
+        std::fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    // Number of headers, the height of the next one
+    pub fn height(&self) -> u64 {
+        self.headers.len() as u64
+    }
+
+    pub fn header(&self, height: u64) -> Option<&BlockHeader> {
+        self.headers.get(height as usize)
+    }
+
+    // Adds headers continuing the chain or a branch of it. A branch only
+    // replaces the headers it forks off from when it has more work, as after
+    // a reorganization of the node's chain
+    pub fn extend(&mut self, headers: Vec<BlockHeader>) -> Result<()> {
+        let Some(first) = headers.first() else {
+            return Ok(());
+        };
+        let fork = first.index as usize;
+        if fork > self.headers.len() {
+            return Err(Error::InvalidHeader(format!(
+                "header {} doesn't connect to the wallet's {} headers",
+                first.index,
+                self.headers.len()
+            )));
+        }
+        if fork == 0 && !self.headers.is_empty() {
+            return Err(Error::InvalidHeader(
+                "the node's chain has another genesis block".to_string(),
+            ));
+        }
+
+        // Ancestors of the new headers come from the chain below the fork
+        let at = |height: u64| match (height as usize).checked_sub(fork) {
+            Some(offset) => headers.get(offset),
+            None => self.headers.get(height as usize),
+        };
+        for (offset, header) in headers.iter().enumerate() {
+            if header.index != (fork + offset) as u64 {
+                return Err(Error::InvalidHeader(format!(
+                    "expected header {}, got {}",
+                    fork + offset,
+                    header.index
+                )));
+            }
+            self.check(header, at)?;
+        }
+
+        if work(&headers) <= work(&self.headers[fork..]) {
+            return Err(Error::InvalidHeader(format!(
+                "branch forking at height {fork} has less work than the wallet's headers"
+            )));
+        }
+        self.headers.truncate(fork);
+        self.headers.extend(headers);
+
+        Ok(())
+    }
+
+    // Gets the node's headers with `fetch(height)`, which returns them from
+    // the height on, until the wallet has them all. While they don't match
+    // the wallet's, headers are fetched from further back to find where the
+    // node's chain forked off after a reorganization
+    pub fn sync(&mut self, mut fetch: impl FnMut(u64) -> Result<Vec<BlockHeader>>) -> Result<()> {
+        // The wallet's tip is fetched again, to notice it was reorganized away
+        let mut back = 1;
+
+        loop {
+            let from = self.height().saturating_sub(back);
+            let headers = fetch(from)?;
+            if headers.first().is_some_and(|first| first.index != from) {
+                return Err(Error::InvalidHeader(format!(
+                    "expected headers from height {from}"
+                )));
+            }
+
+            let known = headers
+                .iter()
+                .zip(&self.headers[from as usize..])
+                .take_while(|(header, known)| header == known)
+                .count();
+            if known == 0 && from < self.height() {
+                if from == 0 {
+                    return Err(Error::InvalidHeader(
+                        "the node's chain has another genesis block".to_string(),
+                    ));
+                }
+                back = back.saturating_mul(2);
+                continue;
+            }
+
+            let new = headers[known..].to_vec();
+            if new.is_empty() {
+                return Ok(());
+            }
+            self.extend(new)?;
+            back = 1;
+        }
+    }
+
+    fn check<'a>(
+        &self,
+        header: &BlockHeader,
+        at: impl Fn(u64) -> Option<&'a BlockHeader>,
+    ) -> Result<()> {
+        let invalid =
+            |reason: &str| Error::InvalidHeader(format!("header {}: {reason}", header.index));
+        if !header.is_valid() {
+            return Err(invalid("invalid proof of work"));
+        }
+        let Some(parent_height) = header.index.checked_sub(1) else {
+            return Ok(());
+        };
+
+        let parent = at(parent_height).ok_or_else(|| invalid("unknown parent"))?;
+        if header.previous_hash != hex::encode(parent.hash) {
+            return Err(invalid("doesn't link up"));
+        }
+        if header.difficulty != self.expected_difficulty(header.index, parent, at) {
+            return Err(invalid("unexpected difficulty"));
+        }
+
+        Ok(())
+    }
+
+    // Same rules as `BlockChain::next_difficulty`, from headers only
+    fn expected_difficulty<'a>(
+        &self,
+        height: u64,
+        parent: &BlockHeader,
+        at: impl Fn(u64) -> Option<&'a BlockHeader>,
+    ) -> u32 {
+        if let Some(difficulty) = self.network.fixed_difficulty() {
+            return difficulty;
+        }
+
+        let interval = self.params.retarget_interval;
+        if !height.is_multiple_of(interval) {
+            return parent.difficulty;
+        }
+        let Some(first) = at(height - interval) else {
+            return parent.difficulty;
+        };
+
+        let actual_time = parent.timestamp.saturating_sub(first.timestamp);
+        let target_time = (interval - 1) as u128 * self.params.target_block_time;
+        retarget(parent.difficulty, actual_time, target_time)
+    }
+}
+
+// Evidence of a payment, the merkle proof that a transaction is in a block
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct TxProof {
+    pub txid: [u8; 32],
+    pub block_hash: [u8; 32],
+    pub height: u64,
+    pub proof: Proof,
+}
+
+impl TxProof {
+    // Checks the block is on the validated headers' best chain and has the
+    // transaction under its merkle root
+    pub fn verify(&self, headers: &HeaderChain) -> Result<()> {
+        let invalid =
+            |reason: &str| Error::InvalidProof(format!("{}: {reason}", hex::encode(self.txid)));
+
+        let header = headers
+            .header(self.height)
+            .filter(|header| header.hash == self.block_hash)
+            .ok_or_else(|| invalid("block isn't on the validated chain"))?;
+        if !Tree::verify_proof(self.txid, &self.proof, header.merkle_root) {
+            return Err(invalid("transaction isn't under the block's merkle root"));
+        }
+
+        Ok(())
+    }
+
+    // Blocks on top of and including the proof's block
+    pub fn confirmations(&self, headers: &HeaderChain) -> u64 {
+        headers.height().saturating_sub(self.height)
+    }
+}
+
+fn work(headers: &[BlockHeader]) -> u128 {
+    headers
+        .iter()
+        .map(|header| 1u128.checked_shl(header.difficulty).unwrap_or(u128::MAX))
+        .fold(0, u128::saturating_add)
+}
+
+#[cfg(test)]
+mod test {
+    use corelib::block::{Block, BlockBuilder};
+
+    use super::*;
+    use crate::wallet::{Direction, HistoryEntry, Wallet};
+
+    // Mined blocks on top of `parent`, paying the miner
+    fn blocks(parent: Option<&Block>, count: u64, miner: u8) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for _ in 0..count {
+            let parent = blocks.last().or(parent);
+            let (index, previous_hash) = parent.map_or((0, "0".repeat(64)), |parent| {
+                (parent.index() + 1, hex::encode(parent.hash()))
+            });
+            blocks.push(
+                BlockBuilder::new(index, previous_hash, 1, [miner; 32])
+                    .build()
+                    .unwrap(),
+            );
+        }
+        blocks
+    }
+
+    fn headers_of(blocks: &[Block]) -> Vec<BlockHeader> {
+        blocks.iter().map(|block| block.header().clone()).collect()
+    }
+
+    #[test]
+    fn validates_headers_and_follows_reorganizations() {
+        let chain = blocks(None, 3, 1);
+        let mut headers = HeaderChain::new(Network::Regtest);
+        let node = headers_of(&chain);
+        headers
+            .sync(|from| Ok(node[from as usize..].to_vec()))
+            .unwrap();
+        assert_eq!(headers.height(), 3);
+
+        // Headers without the work behind them are refused
+        let mut forged = blocks(Some(&chain[2]), 1, 1)[0].header().clone();
+        forged.nonce += 1;
+        assert!(matches!(
+            headers.extend(vec![forged]),
+            Err(Error::InvalidHeader(_))
+        ));
+
+        // A branch from the first block with more work replaces the rest
+        let branch = blocks(Some(&chain[0]), 3, 2);
+        let node: Vec<BlockHeader> = headers_of(&chain[..1])
+            .into_iter()
+            .chain(headers_of(&branch))
+            .collect();
+        headers
+            .sync(|from| Ok(node[from as usize..].to_vec()))
+            .unwrap();
+        assert_eq!(headers.height(), 4);
+        assert_eq!(headers.header(3).unwrap(), branch[2].header());
+
+        // The old, shorter branch doesn't come back
+        assert!(headers.extend(headers_of(&chain[1..])).is_err());
+        assert_eq!(headers.header(1).unwrap(), branch[0].header());
+    }
+
+    #[test]
+    fn verifies_merkle_proofs_against_the_headers() {
+        let chain = blocks(None, 2, 1);
+        let mut headers = HeaderChain::new(Network::Regtest);
+        headers.extend(headers_of(&chain)).unwrap();
+
+        let coinbase = chain[1].transactions()[0].hash_id;
+        let proof = TxProof {
+            txid: coinbase,
+            block_hash: chain[1].hash(),
+            height: 1,
+            proof: chain[1].merkle_proof(&coinbase).unwrap(),
+        };
+        proof.verify(&headers).unwrap();
+        assert_eq!(proof.confirmations(&headers), 1);
+
+        let other = TxProof {
+            txid: [7u8; 32],
+            ..proof.clone()
+        };
+        assert!(matches!(
+            other.verify(&headers),
+            Err(Error::InvalidProof(_))
+        ));
+        let unknown = TxProof {
+            block_hash: [7u8; 32],
+            ..proof.clone()
+        };
+        assert!(matches!(
+            unknown.verify(&headers),
+            Err(Error::InvalidProof(_))
+        ));
+
+        // The wallet keeps the proofs of its payments once they check out
+        let mut wallet = Wallet::new();
+        for txid in [coinbase, [7u8; 32]] {
+            wallet.record(HistoryEntry {
+                txid,
+                direction: Direction::Received,
+                counterparty: None,
+                amount: 1_000,
+                fee: 0,
+                timestamp: 0,
+            });
+        }
+        let proven = wallet
+            .prove_payments(&headers, |txid| {
+                Ok((*txid == coinbase).then(|| proof.clone()))
+            })
+            .unwrap();
+        assert_eq!(proven, vec![coinbase]);
+        let wallet = Wallet::from_bytes(&wallet.to_bytes().unwrap()).unwrap();
+        assert_eq!(wallet.payment_proof(&coinbase), Some(&proof));
+
+        // Proven payments aren't asked for again, and a node handing out a
+        // proof that doesn't check out is caught
+        let mut wallet = wallet;
+        let forged = |txid: &[u8; 32]| {
+            assert_ne!(*txid, coinbase);
+            Ok(Some(other.clone()))
+        };
+        assert!(matches!(
+            wallet.prove_payments(&headers, forged),
+            Err(Error::InvalidProof(_))
+        ));
+        assert!(wallet.payment_proof(&[7u8; 32]).is_none());
+    }
+}
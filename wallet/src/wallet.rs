@@ -0,0 +1,1394 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io::Write,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use corelib::{
+    errors::Error as CoreError,
+    keystore::Keystore,
+    mempool,
+    script::{sign_digest, Script, SigHash, SigHashes, SpendContext},
+    sign,
+    transaction::Transaction,
+    utxo::{OutPoint, UTXO},
+};
+use ed25519_dalek::SigningKey;
+use rand::rngs::OsRng;
+
+use crate::{
+    address_book::AddressBook,
+    analytics::UtxoStats,
+    coin_selection::select_coins,
+    errors::{Error, Result},
+    hd::{mnemonic_seed, DerivationPath, ExtendedKey},
+    keychain::{Derivation, KeyChain, Keychain, DEFAULT_GAP_LIMIT},
+    policy::{SpendingPolicy, DAY},
+    spv::{HeaderChain, TxProof},
+};
+
+#[derive(Debug, Clone)]
+pub struct Wallet {
+    public_key: [u8; 32],
+    // Only held while the wallet is unlocked, a wallet without a passphrase
+    // is never locked
+    signing_key: Option<SigningKey>,
+    keystore: Option<Keystore>,
+    // Time after which the wallet locks itself again
+    unlocked_until: Option<Instant>,
+    // Confirmed UTXOs spendable by this wallet, keyed by the UTXO id
+    utxos: HashMap<OutPoint, UTXO>,
+    // Outputs spent by payments that aren't confirmed yet and when they were
+    // spent, in milliseconds since the unix epoch. Syncing doesn't offer
+    // them again while a node still lists them as unspent
+    pending_spends: BTreeMap<OutPoint, u128>,
+    // Names the user gave to addresses
+    labels: BTreeMap<[u8; 32], String>,
+    history: Vec<HistoryEntry>,
+    // Merkle proofs of the history's transactions, keyed by txid
+    proofs: BTreeMap<[u8; 32], TxProof>,
+    address_book: AddressBook,
+    // Redeem scripts of pay-to-script-hash outputs the wallet can spend,
+    // keyed by their hash
+    redeem_scripts: BTreeMap<[u8; 32], String>,
+    // Receive and change addresses derived from the key
+    keychain: Keychain,
+    // Height of the next block as last reported by a node. Payments are
+    // locked to it so they can't be mined into a block replacing the tip,
+    // which leaves no fees to gain from orphaning it
+    chain_height: Option<u64>,
+    // Whether payments are locked to the chain height
+    anti_fee_sniping: bool,
+    // Limits checked before a spend is signed
+    policy: SpendingPolicy,
+    // The wallet key's signature over the policy, a policy edited in the
+    // wallet file without the key doesn't load
+    policy_signature: [u8; 64],
+    // Whether the approval token was given for the next payment or policy
+    // change, never saved
+    approved: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+// Payment made or received by the wallet
+#[derive(Debug, Clone, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct HistoryEntry {
+    pub txid: [u8; 32],
+    pub direction: Direction,
+    // Receiver of a sent payment, the sender of received ones isn't known
+    pub counterparty: Option<[u8; 32]>,
+    pub amount: u64,
+    pub fee: u64,
+    // Milliseconds since the unix epoch
+    pub timestamp: u128,
+}
+
+// Layout of the wallet file, the secret key is only stored in the clear for
+// wallets without a passphrase
+#[derive(BorshSerialize, BorshDeserialize)]
+struct WalletFile {
+    public_key: [u8; 32],
+    secret_key: Option<[u8; 32]>,
+    keystore: Option<Keystore>,
+    utxos: Vec<UTXO>,
+    labels: BTreeMap<[u8; 32], String>,
+    history: Vec<HistoryEntry>,
+    proofs: BTreeMap<[u8; 32], TxProof>,
+    address_book: AddressBook,
+    redeem_scripts: BTreeMap<[u8; 32], String>,
+    keychain: Keychain,
+    policy: SpendingPolicy,
+    pending_spends: BTreeMap<OutPoint, u128>,
+    policy_signature: [u8; 64],
+}
+
+impl Default for Wallet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Wallet {
+    pub fn new() -> Self {
+        Self::from_signing_key(SigningKey::generate(&mut OsRng))
+    }
+
+    pub fn from_signing_key(signing_key: SigningKey) -> Self {
+        Self::with_derivation(signing_key, Derivation::Hashed)
+    }
+
+    // Wallet of an account of a BIP-39 seed phrase. Its key is the account
+    // key, m/44'/coin'/account', and its addresses are SLIP-10 children of
+    // it, so the phrase alone recovers every address
+    pub fn from_mnemonic(phrase: &str, passphrase: &str, account: u32) -> Result<Self> {
+        let seed = mnemonic_seed(phrase, passphrase)?;
+        let account_key = ExtendedKey::from_seed(&seed).derive(&DerivationPath::account(account));
+
+        Ok(Self::with_derivation(
+            account_key.signing_key(),
+            Derivation::Slip10 {
+                account,
+                chain_code: account_key.chain_code(),
+            },
+        ))
+    }
+
+    // Wallet of the key in a keystore, e.g. the node's mining key. It stays
+    // encrypted with the keystore's passphrase and starts out locked
+    pub fn from_keystore(keystore: Keystore, passphrase: &str) -> Result<Self> {
+        let mut wallet = Self::from_signing_key(unlock_keystore(&keystore, passphrase)?);
+        wallet.keystore = Some(keystore);
+        wallet.signing_key = None;
+
+        Ok(wallet)
+    }
+
+    pub fn with_derivation(signing_key: SigningKey, derivation: Derivation) -> Self {
+        let mut keychain = Keychain::with_derivation(DEFAULT_GAP_LIMIT, derivation);
+        keychain.top_up(&signing_key);
+        let policy = SpendingPolicy::default();
+        let policy_signature = sign::sign_message(&signing_key, &policy.signed_message());
+
+        Self {
+            public_key: signing_key.verifying_key().to_bytes(),
+            signing_key: Some(signing_key),
+            keystore: None,
+            unlocked_until: None,
+            utxos: HashMap::new(),
+            pending_spends: BTreeMap::new(),
+            labels: BTreeMap::new(),
+            history: Vec::new(),
+            proofs: BTreeMap::new(),
+            address_book: AddressBook::new(),
+            redeem_scripts: BTreeMap::new(),
+            keychain,
+            chain_height: None,
+            anti_fee_sniping: true,
+            policy,
+            policy_signature,
+            approved: false,
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    // Only the owner can read the file, it holds the keys
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        write_secret(path.as_ref(), &self.to_bytes()?)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let file = WalletFile {
+            public_key: self.public_key,
+            secret_key: match self.keystore {
+                Some(_) => None,
+                None => self.signing_key.as_ref().map(SigningKey::to_bytes),
+            },
+            keystore: self.keystore.clone(),
+            utxos: self.utxos.values().cloned().collect(),
+            labels: self.labels.clone(),
+            history: self.history.clone(),
+            proofs: self.proofs.clone(),
+            address_book: self.address_book.clone(),
+            redeem_scripts: self.redeem_scripts.clone(),
+            keychain: self.keychain.clone(),
+            policy: self.policy.clone(),
+            pending_spends: self.pending_spends.clone(),
+            policy_signature: self.policy_signature,
+        };
+
+        Ok(borsh::to_vec(&file)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let file = WalletFile::try_from_slice(bytes)?;
+
+        // Encrypted wallets check against the key the keystore authenticates
+        let key = file
+            .keystore
+            .as_ref()
+            .map_or(file.public_key, Keystore::public_key);
+        if key != file.public_key
+            || sign::verify_message(&key, &file.policy.signed_message(), &file.policy_signature)
+                .is_err()
+        {
+            return Err(Error::PolicyNotSigned);
+        }
+
+        Ok(Self {
+            public_key: file.public_key,
+            signing_key: file.secret_key.as_ref().map(SigningKey::from_bytes),
+            keystore: file.keystore,
+            unlocked_until: None,
+            utxos: file
+                .utxos
+                .into_iter()
+                .filter_map(|utxo| Some((utxo.outpoint()?, utxo)))
+                .collect(),
+            labels: file.labels,
+            history: file.history,
+            proofs: file.proofs,
+            address_book: file.address_book,
+            redeem_scripts: file.redeem_scripts,
+            keychain: file.keychain,
+            chain_height: None,
+            anti_fee_sniping: true,
+            policy: file.policy,
+            pending_spends: file.pending_spends,
+            policy_signature: file.policy_signature,
+            approved: false,
+        })
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public_key
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.keystore.is_some()
+    }
+
+    // Keystore holding the signing key of an encrypted wallet
+    pub fn keystore(&self) -> Option<&Keystore> {
+        self.keystore.as_ref()
+    }
+
+    // Protects the signing key with a passphrase and locks the wallet
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        if self.is_encrypted() {
+            return Err(Error::AlreadyEncrypted);
+        }
+
+        let signing_key = self.signing_key.as_ref().ok_or(Error::Locked)?;
+        self.keystore = Some(Keystore::create(signing_key, passphrase)?);
+        self.signing_key = None;
+
+        Ok(())
+    }
+
+    // Decrypts the signing key and keeps it in memory for `timeout`
+    pub fn unlock(&mut self, passphrase: &str, timeout: Duration) -> Result<()> {
+        let keystore = self.keystore.as_ref().ok_or(Error::NotEncrypted)?;
+        let unlocked_until = Instant::now()
+            .checked_add(timeout)
+            .ok_or(Error::UnlockTimeoutTooLong(timeout))?;
+
+        self.signing_key = Some(unlock_keystore(keystore, passphrase)?);
+        self.unlocked_until = Some(unlocked_until);
+        // Addresses used while the wallet was locked left the window short
+        self.top_up_keychain();
+
+        Ok(())
+    }
+
+    pub fn lock(&mut self) -> Result<()> {
+        if !self.is_encrypted() {
+            return Err(Error::NotEncrypted);
+        }
+
+        // The key is zeroized when dropped
+        self.signing_key = None;
+        self.unlocked_until = None;
+
+        Ok(())
+    }
+
+    pub fn is_locked(&mut self) -> bool {
+        self.expire_unlock();
+        self.signing_key.is_none()
+    }
+
+    fn expire_unlock(&mut self) {
+        if self
+            .unlocked_until
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            self.signing_key = None;
+            self.unlocked_until = None;
+        }
+    }
+
+    fn signing_key(&mut self) -> Result<&mut SigningKey> {
+        self.expire_unlock();
+        self.signing_key.as_mut().ok_or(Error::Locked)
+    }
+
+    pub(crate) fn secret_key(&mut self) -> Result<[u8; 32]> {
+        Ok(self.signing_key()?.to_bytes())
+    }
+
+    // Hands out a receive address that wasn't handed out before
+    pub fn new_address(&mut self) -> Result<[u8; 32]> {
+        self.next_address(KeyChain::Receive)
+    }
+
+    // The wallet's own key followed by every address derived from it
+    pub fn addresses(&self) -> impl Iterator<Item = &[u8; 32]> {
+        std::iter::once(&self.public_key).chain(self.keychain.addresses())
+    }
+
+    pub fn derivation(&self) -> Derivation {
+        self.keychain.derivation()
+    }
+
+    // Path of a derived address in the tree of the wallet's seed phrase
+    pub fn derivation_path(&self, address: &[u8; 32]) -> Option<DerivationPath> {
+        let (chain, index) = self.keychain.find(|derived| derived == address)?;
+        self.keychain.path(chain, index)
+    }
+
+    // Payments are locked to the height from now on, see `chain_height`
+    pub fn set_chain_height(&mut self, height: u64) {
+        self.chain_height = Some(height);
+    }
+
+    // Whether payments are locked to the chain height, on by default
+    pub fn set_anti_fee_sniping(&mut self, enabled: bool) {
+        self.anti_fee_sniping = enabled;
+    }
+
+    pub fn gap_limit(&self) -> u32 {
+        self.keychain.gap_limit()
+    }
+
+    // Unused addresses derived ahead on every chain. A larger window is
+    // derived right away if the wallet is unlocked, otherwise on unlock
+    pub fn set_gap_limit(&mut self, gap_limit: u32) {
+        self.keychain.set_gap_limit(gap_limit);
+        self.top_up_keychain();
+    }
+
+    fn next_address(&mut self, chain: KeyChain) -> Result<[u8; 32]> {
+        self.top_up_keychain();
+        let address = self.keychain.next(chain).ok_or(Error::Locked)?;
+        self.top_up_keychain();
+
+        Ok(address)
+    }
+
+    // Derives the addresses missing from the window, if the wallet is
+    // unlocked
+    fn top_up_keychain(&mut self) {
+        self.expire_unlock();
+        if let Some(signing_key) = self.signing_key.as_ref() {
+            if self.keychain.needs_top_up() {
+                self.keychain.top_up(signing_key);
+            }
+        }
+    }
+
+    // Moves the window on past the derived address the UTXO is paid to
+    fn mark_used(&mut self, utxo: &UTXO) {
+        if let Some((chain, index)) = self.keychain.find(|address| utxo.is_owned_by(address)) {
+            self.keychain.mark_used(chain, index);
+            self.top_up_keychain();
+        }
+    }
+
+    // Key signing for outputs paid to the wallet's key or one derived from
+    // it, `None` for other outputs
+    fn key_for(&mut self, utxo: &UTXO) -> Result<Option<SigningKey>> {
+        if utxo.is_owned_by(&self.public_key) {
+            return Ok(Some(self.signing_key()?.clone()));
+        }
+
+        let Some((chain, index)) = self.keychain.find(|address| utxo.is_owned_by(address)) else {
+            return Ok(None);
+        };
+        let master = self.signing_key()?.clone();
+        Ok(Some(self.keychain.derive(&master, chain, index)))
+    }
+
+    pub fn set_label(&mut self, address: [u8; 32], label: String) {
+        self.labels.insert(address, label);
+    }
+
+    pub fn label(&self, address: &[u8; 32]) -> Option<&str> {
+        self.labels.get(address).map(String::as_str)
+    }
+
+    pub fn labels(&self) -> &BTreeMap<[u8; 32], String> {
+        &self.labels
+    }
+
+    pub fn policy(&self) -> &SpendingPolicy {
+        &self.policy
+    }
+
+    // Replaces the spending policy. It takes the signing key, and the
+    // approval token once the current policy has one, so a stolen wallet
+    // file alone can't loosen it
+    pub fn set_policy(&mut self, policy: SpendingPolicy) -> Result<()> {
+        self.signing_key()?;
+        if self.policy.has_approval_token() && !std::mem::take(&mut self.approved) {
+            return Err(Error::WrongApprovalToken);
+        }
+
+        self.policy_signature = sign::sign_message(self.signing_key()?, &policy.signed_message());
+        self.policy = policy;
+        Ok(())
+    }
+
+    // Approves the next payment or policy change with the token of the
+    // spending policy
+    pub fn approve(&mut self, token: &str) -> Result<()> {
+        if !self.policy.verify_token(token) {
+            return Err(Error::WrongApprovalToken);
+        }
+
+        self.approved = true;
+        Ok(())
+    }
+
+    // Amount sent over the last 24 hours along with the fees paid, what the
+    // daily limit counts
+    pub fn spent_today(&self) -> u64 {
+        let since = now().saturating_sub(DAY);
+        self.history
+            .iter()
+            .filter(|entry| entry.direction == Direction::Sent && entry.timestamp >= since)
+            .map(|entry| entry.amount + entry.fee)
+            .sum()
+    }
+
+    pub fn address_book(&self) -> &AddressBook {
+        &self.address_book
+    }
+
+    pub fn address_book_mut(&mut self) -> &mut AddressBook {
+        &mut self.address_book
+    }
+
+    // Remembers a redeem script the wallet's signature alone satisfies, so
+    // outputs paid to its hash can be tracked and spent. Returns the hash
+    pub fn add_redeem_script(&mut self, script: &Script) -> Result<[u8; 32]> {
+        let script_hash = script.script_hash();
+
+        // Checked against a spend signing the script hash in place of a
+        // transaction
+        let unlocking = self.script_hash_unlocking_script(&script_hash, &script.to_string())?;
+        let context = SpendContext {
+            sighashes: SigHashes::only(SigHash::All, script_hash),
+            ..SpendContext::default()
+        };
+        Script::execute(
+            &unlocking.parse()?,
+            &Script::pay_to_script_hash(&script_hash),
+            &context,
+        )
+        .map_err(|_| Error::NotOwned)?;
+
+        self.redeem_scripts.insert(script_hash, script.to_string());
+        Ok(script_hash)
+    }
+
+    pub fn redeem_script_hashes(&self) -> impl Iterator<Item = &[u8; 32]> {
+        self.redeem_scripts.keys()
+    }
+
+    // Whether the UTXO is paid to the wallet's key, a key derived from it or
+    // one of its redeem scripts
+    pub fn can_spend(&self, utxo: &UTXO) -> bool {
+        utxo.is_owned_by(&self.public_key)
+            || self
+                .keychain
+                .find(|address| utxo.is_owned_by(address))
+                .is_some()
+            || self
+                .redeem_scripts
+                .keys()
+                .any(|script_hash| utxo.is_owned_by(script_hash))
+    }
+
+    pub fn history(&self) -> &[HistoryEntry] {
+        &self.history
+    }
+
+    pub(crate) fn record(&mut self, entry: HistoryEntry) {
+        self.history.push(entry);
+    }
+
+    // Evidence that a payment of the history was confirmed
+    pub fn payment_proof(&self, txid: &[u8; 32]) -> Option<&TxProof> {
+        self.proofs.get(txid)
+    }
+
+    // Asks `proof_of` for merkle proofs of the history's transactions
+    // without one valid under the headers, new ones as well as ones whose
+    // block was reorganized away. Proofs are verified before they're kept,
+    // an invalid one fails the call. Returns the newly proven txids
+    pub fn prove_payments(
+        &mut self,
+        headers: &HeaderChain,
+        mut proof_of: impl FnMut(&[u8; 32]) -> Result<Option<TxProof>>,
+    ) -> Result<Vec<[u8; 32]>> {
+        let unproven: BTreeSet<[u8; 32]> = self
+            .history
+            .iter()
+            .map(|entry| entry.txid)
+            .filter(|txid| {
+                self.proofs
+                    .get(txid)
+                    .is_none_or(|proof| proof.verify(headers).is_err())
+            })
+            .collect();
+
+        let mut proven = Vec::new();
+        for txid in unproven {
+            self.proofs.remove(&txid);
+            let Some(proof) = proof_of(&txid)? else {
+                continue;
+            };
+            if proof.txid != txid {
+                return Err(Error::InvalidProof(format!(
+                    "{}: proves another transaction",
+                    hex::encode(txid)
+                )));
+            }
+
+            proof.verify(headers)?;
+            self.proofs.insert(txid, proof);
+            proven.push(txid);
+        }
+
+        Ok(proven)
+    }
+
+    // Starts tracking a confirmed UTXO the wallet can spend
+    pub fn add_utxo(&mut self, utxo: UTXO) -> Result<()> {
+        let outpoint = utxo.outpoint().ok_or(Error::UnconfirmedUTXO)?;
+
+        if !self.can_spend(&utxo) {
+            return Err(Error::NotOwned);
+        }
+        self.mark_used(&utxo);
+
+        if let UTXO::Confirmed {
+            txn_hash, value, ..
+        } = utxo
+        {
+            self.record(HistoryEntry {
+                txid: txn_hash,
+                direction: Direction::Received,
+                counterparty: None,
+                amount: value,
+                fee: 0,
+                timestamp: now(),
+            });
+        }
+
+        self.utxos.insert(outpoint, utxo);
+        Ok(())
+    }
+
+    // Replaces the tracked UTXOs with the ones a node reports as unspent.
+    // Outputs seen for the first time are recorded as received, unless they
+    // are the change of a payment the wallet sent.
+    //
+    // Outputs of the wallet's pending payments stay spent until the node
+    // stops listing them, or until the payment must have expired from the
+    // mempools and its outputs can be spent again
+    pub fn sync_utxos(&mut self, unspent: Vec<UTXO>) -> Result<()> {
+        let outpoints: HashSet<OutPoint> = unspent.iter().filter_map(UTXO::outpoint).collect();
+        self.utxos
+            .retain(|outpoint, _| outpoints.contains(outpoint));
+        let now = now();
+        self.pending_spends.retain(|outpoint, spent_at| {
+            outpoints.contains(outpoint)
+                && now.saturating_sub(*spent_at) < mempool::DEFAULT_TTL.as_millis()
+        });
+
+        for utxo in unspent {
+            let Some(outpoint) = utxo.outpoint() else {
+                continue;
+            };
+            if self.utxos.contains_key(&outpoint) || self.pending_spends.contains_key(&outpoint) {
+                continue;
+            }
+
+            let is_change = self
+                .history
+                .iter()
+                .any(|entry| entry.txid == outpoint.txid && entry.direction == Direction::Sent);
+            if is_change && self.can_spend(&utxo) {
+                self.mark_used(&utxo);
+                self.utxos.insert(outpoint, utxo);
+            } else {
+                self.add_utxo(utxo)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Syncs the outputs `unspent_of` lists for every address and redeem
+    // script of the wallet, as after restoring it. Outputs found near the end
+    // of the window move it on while the wallet is unlocked, and the newly
+    // derived addresses are scanned as well
+    pub fn rescan(
+        &mut self,
+        mut unspent_of: impl FnMut(&[u8; 32]) -> Result<Vec<UTXO>>,
+    ) -> Result<()> {
+        let mut scanned = HashSet::new();
+        let mut unspent = Vec::new();
+
+        loop {
+            let pending: Vec<[u8; 32]> = self
+                .addresses()
+                .chain(self.redeem_script_hashes())
+                .filter(|address| !scanned.contains(*address))
+                .copied()
+                .collect();
+            if pending.is_empty() {
+                break;
+            }
+
+            for address in pending {
+                let found = unspent_of(&address)?;
+                for utxo in found.iter() {
+                    self.mark_used(utxo);
+                }
+
+                unspent.extend(found);
+                scanned.insert(address);
+            }
+        }
+
+        self.sync_utxos(unspent)
+    }
+
+    pub fn remove_utxo(&mut self, outpoint: &OutPoint) -> Option<UTXO> {
+        self.utxos.remove(outpoint)
+    }
+
+    pub fn utxos(&self) -> impl Iterator<Item = &UTXO> {
+        self.utxos.values()
+    }
+
+    pub fn balance(&self) -> u64 {
+        self.utxos.values().map(UTXO::value).sum()
+    }
+
+    // Age and dust summary of the outputs at a chain of `chain_height`
+    // blocks and `fee_rate` per byte
+    pub fn utxo_stats(&self, chain_height: u64, fee_rate: u64) -> UtxoStats {
+        UtxoStats::new(self.utxos.values(), chain_height, fee_rate)
+    }
+
+    // The `count` most valuable outputs, largest first
+    pub fn largest_utxos(&self, count: usize) -> Vec<&UTXO> {
+        let mut utxos: Vec<&UTXO> = self.utxos.values().collect();
+        utxos.sort_by_key(|utxo| std::cmp::Reverse(utxo.value()));
+        utxos.truncate(count);
+        utxos
+    }
+
+    // The `count` least valuable outputs, smallest first. These are the ones
+    // worth consolidating while fees are low
+    pub fn smallest_utxos(&self, count: usize) -> Vec<&UTXO> {
+        let mut utxos: Vec<&UTXO> = self.utxos.values().collect();
+        utxos.sort_by_key(|utxo| utxo.value());
+        utxos.truncate(count);
+        utxos
+    }
+
+    // Script unlocking input `input` of `txn` when it spends a
+    // pay-to-pubkey-hash output owned by the wallet
+    pub fn unlocking_script(&mut self, txn: &Transaction, input: usize) -> Result<String> {
+        self.authorize(txn)?;
+        Ok(format!(
+            "{} {}",
+            self.key_signature(&input_digest(txn, input)?)?,
+            hex::encode(self.public_key)
+        ))
+    }
+
+    // Scripts unlocking each input of a transaction spending the wallet's
+    // outputs, in the order of the inputs
+    pub fn unlocking_scripts(&mut self, txn: &Transaction) -> Result<Vec<String>> {
+        self.authorize(txn)?;
+        txn.inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| {
+                if let Some(key) = self.key_for(input)? {
+                    let signature = sign_digest(&key, &input_digest(txn, i)?, SigHash::All);
+                    return Ok(format!(
+                        "{} {}",
+                        hex::encode(signature),
+                        hex::encode(key.verifying_key().to_bytes())
+                    ));
+                }
+
+                let redeem_script = self
+                    .redeem_scripts
+                    .iter()
+                    .find(|(script_hash, _)| input.is_owned_by(script_hash))
+                    .map(|(_, script)| script.clone())
+                    .ok_or(Error::NotOwned)?;
+                self.script_hash_unlocking_script(&input_digest(txn, i)?, &redeem_script)
+            })
+            .collect()
+    }
+
+    // Checks a spend against the spending policy before any of its inputs is
+    // signed. Everything leaving the wallet counts, the payments as well as
+    // the fee, and it's recorded as sent for the daily limit. Spends signed
+    // before, e.g. for another of their inputs, aren't counted twice
+    fn authorize(&mut self, txn: &Transaction) -> Result<()> {
+        if self
+            .history
+            .iter()
+            .any(|entry| entry.txid == txn.hash_id && entry.direction == Direction::Sent)
+        {
+            return Ok(());
+        }
+
+        let external: Vec<&UTXO> = txn
+            .outputs
+            .iter()
+            .filter(|output| !self.can_spend(output))
+            .collect();
+        let destinations = external
+            .iter()
+            .map(|output| payee(output))
+            .collect::<Result<Vec<[u8; 32]>>>()?;
+        let amount: u64 = external.iter().map(|output| output.value()).sum();
+        let input_value: u64 = txn.inputs.iter().map(UTXO::value).sum();
+        let fee = input_value.saturating_sub(txn.output_value());
+
+        self.policy.check(
+            &destinations,
+            amount + fee,
+            self.spent_today(),
+            self.approved,
+        )?;
+        // An approval covers a single spend
+        self.approved = false;
+
+        self.record(HistoryEntry {
+            txid: txn.hash_id,
+            direction: Direction::Sent,
+            counterparty: destinations.first().copied(),
+            amount,
+            fee,
+            timestamp: now(),
+        });
+        Ok(())
+    }
+
+    // The wallet's signature followed by the redeem script
+    fn script_hash_unlocking_script(
+        &mut self,
+        digest: &[u8; 32],
+        redeem_script: &str,
+    ) -> Result<String> {
+        Ok(format!(
+            "{} {}",
+            self.key_signature(digest)?,
+            hex::encode(redeem_script)
+        ))
+    }
+
+    // Signature checked by `OP_CHECKSIG`, committing to every input and
+    // output of the spend `digest` is the hash of
+    fn key_signature(&mut self, digest: &[u8; 32]) -> Result<String> {
+        let signature = sign_digest(self.signing_key()?, digest, SigHash::All);
+
+        Ok(hex::encode(signature))
+    }
+
+    // Signs an arbitrary message proving control of `address` off-chain
+    pub fn sign_message(&mut self, address: &[u8; 32], message: &[u8]) -> Result<[u8; 64]> {
+        if *address != self.public_key {
+            return Err(Error::NotOwned);
+        }
+
+        Ok(sign::sign_message(self.signing_key()?, message))
+    }
+
+    // Builds and signs a transaction paying `amount` to `receiver`.
+    //
+    // `fee_rate` is the fee paid per byte of the transaction, any change left
+    // after the fee is paid back to a fresh change address of the wallet as a
+    // second output.
+    pub fn send(&mut self, receiver: [u8; 32], amount: u64, fee_rate: u64) -> Result<Transaction> {
+        if amount == 0 {
+            return Err(Error::ZeroAmount);
+        }
+
+        self.pay(receiver, UTXO::new(amount, 0, receiver)?, fee_rate)
+    }
+
+    // Same as `send` with the payment locked to the hash of a redeem script
+    pub fn send_to_script_hash(
+        &mut self,
+        script_hash: [u8; 32],
+        amount: u64,
+        fee_rate: u64,
+    ) -> Result<Transaction> {
+        if amount == 0 {
+            return Err(Error::ZeroAmount);
+        }
+
+        let payment = UTXO::new_script_hash(amount, 0, script_hash)?;
+        self.pay(script_hash, payment, fee_rate)
+    }
+
+    fn pay(&mut self, receiver: [u8; 32], payment: UTXO, fee_rate: u64) -> Result<Transaction> {
+        let candidates: Vec<UTXO> = self.utxos.values().cloned().collect();
+        let selection = select_coins(&candidates, payment.value(), fee_rate)?;
+
+        let mut outputs = vec![payment];
+        if selection.change > 0 {
+            let change_address = self.next_address(KeyChain::Change)?;
+            outputs.push(UTXO::new(selection.change, 1, change_address)?);
+        }
+
+        let locktime = self.chain_height.filter(|_| self.anti_fee_sniping);
+
+        let signing_key = self.signing_key()?;
+        let mut txn = Transaction::new(signing_key, receiver)?;
+        txn.add_inputs(selection.inputs.clone(), signing_key)?;
+        txn.add_outputs(outputs, signing_key)?;
+        if let Some(locktime) = locktime {
+            txn.set_locktime(locktime, signing_key);
+        }
+        // Checked against the spending policy and recorded as it's signed
+        let unlocking_scripts = self.unlocking_scripts(&txn)?;
+        txn.set_unlocking_scripts(unlocking_scripts);
+
+        // Spent UTXOs can't be selected again, nor synced back while the
+        // payment is pending
+        let spent_at = now();
+        for outpoint in selection.inputs.iter().filter_map(UTXO::outpoint) {
+            self.utxos.remove(&outpoint);
+            self.pending_spends.insert(outpoint, spent_at);
+        }
+
+        Ok(txn)
+    }
+}
+
+fn unlock_keystore(keystore: &Keystore, passphrase: &str) -> Result<SigningKey> {
+    keystore.unlock(passphrase).map_err(|e| match e {
+        CoreError::WrongPassphrase => Error::WrongPassphrase,
+        e => e.into(),
+    })
+}
+
+// Key or redeem script hash an output of a spend pays to
+fn payee(output: &UTXO) -> Result<[u8; 32]> {
+    match output {
+        UTXO::Pending { owner, .. } => Ok(*owner),
+        UTXO::PendingScriptHash { script_hash, .. } => Ok(*script_hash),
+        UTXO::Confirmed { script_pubkey, .. } => {
+            Err(Error::DestinationNotAllowed(script_pubkey.clone()))
+        }
+    }
+}
+
+// Digest the wallet signs to spend input `input` of `txn`
+fn input_digest(txn: &Transaction, input: usize) -> Result<[u8; 32]> {
+    Ok(txn
+        .signature_hash(input, SigHash::All)
+        .ok_or(corelib::errors::Error::InvalidSigHash(input))?)
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis())
+}
+
+// Writes the file to a temporary one that only the owner can read first, so a
+// crash never leaves a torn file, and syncs both the file and its directory
+// before returning so the write survives a power loss
+fn write_secret(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    // A leftover temporary file would keep its old mode
+    match std::fs::remove_file(&tmp) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&tmp, path)?;
+
+    // Directories can't be opened for syncing on windows
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn funded_wallet(values: &[u64]) -> Wallet {
+        let mut wallet = Wallet::new();
+        let owner = wallet.public_key();
+
+        for (i, value) in values.iter().enumerate() {
+            let utxo = UTXO::new(*value, i as u32, owner)
+                .unwrap()
+                .confirm_utxo([i as u8; 32], 1, false)
+                .unwrap();
+            wallet.add_utxo(utxo).unwrap();
+        }
+
+        wallet
+    }
+
+    #[test]
+    fn sends_with_change() {
+        let mut wallet = funded_wallet(&[5_000, 3_000, 2_000]);
+        let receiver = Wallet::new().public_key();
+
+        let txn = wallet.send(receiver, 6_000, 1).unwrap();
+        let (input, output, fee) = txn
+            .verify(&wallet.unlocking_script(&txn, 0).unwrap())
+            .unwrap();
+
+        assert_eq!(input, output + fee);
+        assert!(fee >= txn.serialized_size() as u64);
+        assert_eq!(txn.outputs[0].value(), 6_000);
+        // Change goes to a derived address
+        assert!(txn
+            .outputs
+            .iter()
+            .skip(1)
+            .all(|o| wallet.can_spend(o) && !o.is_owned_by(&wallet.public_key())));
+        assert_eq!(wallet.balance(), 10_000 - input);
+    }
+
+    #[test]
+    fn syncs_unspent_outputs_from_node() {
+        let mut wallet = funded_wallet(&[5_000, 3_000]);
+        let owner = wallet.public_key();
+
+        let txn = wallet.send(Wallet::new().public_key(), 1_000, 1).unwrap();
+        let change = txn.outputs[1]
+            .clone()
+            .confirm_utxo(txn.hash_id, 2, false)
+            .unwrap();
+        let received = UTXO::new(700, 0, owner)
+            .unwrap()
+            .confirm_utxo([9u8; 32], 2, false)
+            .unwrap();
+        let history = wallet.history().len();
+        let remaining = wallet.balance();
+
+        let unspent: Vec<UTXO> = wallet
+            .utxos()
+            .cloned()
+            .chain([change.clone(), received])
+            .collect();
+        wallet.sync_utxos(unspent).unwrap();
+
+        assert_eq!(wallet.balance(), remaining + change.value() + 700);
+        // Only the payment from someone else shows up as received
+        assert_eq!(wallet.history().len(), history + 1);
+        assert_eq!(wallet.history()[history].amount, 700);
+
+        wallet.sync_utxos(Vec::new()).unwrap();
+        assert_eq!(wallet.balance(), 0);
+    }
+
+    #[test]
+    fn keeps_pending_spends_locked_across_syncs() {
+        let mut wallet = funded_wallet(&[5_000, 3_000]);
+        let reported: Vec<UTXO> = wallet.utxos().cloned().collect();
+
+        let txn = wallet.send(Wallet::new().public_key(), 1_000, 1).unwrap();
+        let spent = txn.inputs[0].clone();
+        let remaining = wallet.balance();
+
+        // The node doesn't know the payment yet and still lists its input
+        let mut wallet = Wallet::from_bytes(&wallet.to_bytes().unwrap()).unwrap();
+        wallet.sync_utxos(reported.clone()).unwrap();
+        assert_eq!(wallet.balance(), remaining);
+        let next = wallet.send(Wallet::new().public_key(), 1_000, 1).unwrap();
+        assert!(!next.inputs.contains(&spent));
+
+        // The lock goes once the node stops listing the inputs
+        let mut confirmed = wallet.clone();
+        confirmed.sync_utxos(Vec::new()).unwrap();
+        assert!(confirmed.pending_spends.is_empty());
+
+        // A payment that expired from the mempools frees its inputs
+        for spent_at in wallet.pending_spends.values_mut() {
+            *spent_at -= mempool::DEFAULT_TTL.as_millis();
+        }
+        wallet.sync_utxos(reported).unwrap();
+        assert_eq!(wallet.balance(), 8_000);
+    }
+
+    #[test]
+    fn rescans_addresses_past_the_window() {
+        let signing_key = SigningKey::from_bytes(&[5u8; 32]);
+        let mut original = Wallet::from_signing_key(signing_key.clone());
+        let addresses: Vec<[u8; 32]> = (0..30).map(|_| original.new_address().unwrap()).collect();
+
+        // The second payment is past the window a restored wallet starts with
+        let funded: HashMap<[u8; 32], UTXO> = [10, 29]
+            .into_iter()
+            .map(|i| {
+                let utxo = UTXO::new(1_000, 0, addresses[i])
+                    .unwrap()
+                    .confirm_utxo([i as u8; 32], 1, false)
+                    .unwrap();
+                (addresses[i], utxo)
+            })
+            .collect();
+
+        let mut restored = Wallet::from_signing_key(signing_key);
+        restored
+            .rescan(|address| Ok(funded.get(address).cloned().into_iter().collect()))
+            .unwrap();
+        assert_eq!(restored.balance(), 2_000);
+        assert_eq!(
+            restored.new_address().unwrap(),
+            original.new_address().unwrap()
+        );
+
+        let spend = restored.send(Wallet::new().public_key(), 800, 1).unwrap();
+        spend
+            .verify_inputs(&restored.unlocking_scripts(&spend).unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn derives_addresses_from_a_seed_phrase() {
+        let phrase = crate::hd::generate_mnemonic();
+        let mut wallet = Wallet::from_mnemonic(&phrase, "", 0).unwrap();
+        let address = wallet.new_address().unwrap();
+
+        // The phrase recovers the same addresses, other accounts don't share any
+        let mut recovered = Wallet::from_mnemonic(&phrase, "", 0).unwrap();
+        assert_eq!(recovered.public_key(), wallet.public_key());
+        assert_eq!(recovered.new_address().unwrap(), address);
+        let other = Wallet::from_mnemonic(&phrase, "", 1).unwrap();
+        assert!(other
+            .addresses()
+            .all(|a| !wallet.addresses().any(|b| a == b)));
+
+        assert_eq!(
+            wallet.derivation_path(&address).unwrap().to_string(),
+            format!("m/44'/{}'/0'/0'/0'", crate::hd::COIN_TYPE)
+        );
+        assert!(Wallet::new().derivation_path(&address).is_none());
+
+        // Outputs paid to derived addresses are spent with the derived key
+        let utxo = UTXO::new(2_000, 0, address)
+            .unwrap()
+            .confirm_utxo([4u8; 32], 1, false)
+            .unwrap();
+        recovered.add_utxo(utxo).unwrap();
+        let spend = recovered
+            .send(Wallet::new().public_key(), 1_000, 1)
+            .unwrap();
+        spend
+            .verify_inputs(&recovered.unlocking_scripts(&spend).unwrap())
+            .unwrap();
+
+        // The derivation survives the wallet file
+        let reloaded = Wallet::from_bytes(&recovered.to_bytes().unwrap()).unwrap();
+        assert_eq!(reloaded.derivation(), wallet.derivation());
+    }
+
+    #[test]
+    fn locks_payments_to_the_chain_height() {
+        let mut wallet = funded_wallet(&[5_000, 5_000, 5_000]);
+        let receiver = Wallet::new().public_key();
+        assert_eq!(wallet.send(receiver, 1_000, 1).unwrap().locktime, 0);
+
+        wallet.set_chain_height(7);
+        let txn = wallet.send(receiver, 1_000, 1).unwrap();
+        assert_eq!(txn.locktime, 7);
+        assert!(!txn.is_final(6, now()));
+        txn.verify_inputs(&wallet.unlocking_scripts(&txn).unwrap())
+            .unwrap();
+
+        wallet.set_anti_fee_sniping(false);
+        assert_eq!(wallet.send(receiver, 1_000, 1).unwrap().locktime, 0);
+    }
+
+    #[test]
+    fn ranks_outputs_by_value() {
+        let wallet = funded_wallet(&[400, 9_000, 2_500, 70]);
+
+        let largest: Vec<u64> = wallet
+            .largest_utxos(2)
+            .into_iter()
+            .map(UTXO::value)
+            .collect();
+        assert_eq!(largest, vec![9_000, 2_500]);
+        let smallest: Vec<u64> = wallet
+            .smallest_utxos(3)
+            .into_iter()
+            .map(UTXO::value)
+            .collect();
+        assert_eq!(smallest, vec![70, 400, 2_500]);
+        assert_eq!(wallet.largest_utxos(10).len(), 4);
+
+        let stats = wallet.utxo_stats(2, 1);
+        assert_eq!((stats.count, stats.value), (4, 11_970));
+        assert_eq!(stats.dust, 1);
+    }
+
+    #[test]
+    fn rejects_foreign_utxo() {
+        let mut wallet = Wallet::new();
+        let utxo = UTXO::new(1_000, 0, Wallet::new().public_key())
+            .unwrap()
+            .confirm_utxo([1u8; 32], 1, false)
+            .unwrap();
+
+        assert!(matches!(wallet.add_utxo(utxo), Err(Error::NotOwned)));
+    }
+
+    #[test]
+    fn sends_to_and_spends_script_hash_outputs() {
+        let mut wallet = funded_wallet(&[5_000]);
+        let keys = |n: u8| {
+            format!(
+                "OP_{n} {} {} OP_2 OP_CHECKMULTISIG",
+                hex::encode(wallet.public_key()),
+                hex::encode(Wallet::new().public_key())
+            )
+            .parse::<Script>()
+            .unwrap()
+        };
+        let (one_of_two, two_of_two) = (keys(1), keys(2));
+
+        let script_hash = wallet.add_redeem_script(&one_of_two).unwrap();
+        // The other key's signature would be needed as well
+        assert!(matches!(
+            wallet.add_redeem_script(&two_of_two),
+            Err(Error::NotOwned)
+        ));
+
+        let txn = wallet.send_to_script_hash(script_hash, 2_000, 1).unwrap();
+        txn.verify_inputs(&wallet.unlocking_scripts(&txn).unwrap())
+            .unwrap();
+
+        for output in txn.outputs.iter() {
+            let utxo = output.clone().confirm_utxo(txn.hash_id, 2, false).unwrap();
+            wallet.add_utxo(utxo).unwrap();
+        }
+        assert_eq!(wallet.utxos().count(), 2);
+
+        // Spends the script hash output and the change together
+        let receiver = Wallet::new().public_key();
+        let spend = wallet.send(receiver, wallet.balance() - 1_500, 1).unwrap();
+        assert_eq!(spend.inputs.len(), 2);
+
+        let scripts = wallet.unlocking_scripts(&spend).unwrap();
+        spend.verify_inputs(&scripts).unwrap();
+        // The spend carries them to the node
+        spend.verify_inputs(&spend.unlocking_scripts).unwrap();
+        assert!(spend
+            .verify(&wallet.unlocking_script(&spend, 0).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn signs_messages_for_own_address() {
+        let mut wallet = Wallet::new();
+        let address = wallet.public_key();
+
+        let signature = wallet.sign_message(&address, b"hello").unwrap();
+        sign::verify_message(&address, b"hello", &signature).unwrap();
+
+        let other = Wallet::new().public_key();
+        assert!(matches!(
+            wallet.sign_message(&other, b"hello"),
+            Err(Error::NotOwned)
+        ));
+    }
+
+    #[test]
+    fn signing_requires_unlock() {
+        let mut wallet = funded_wallet(&[5_000]);
+        let receiver = Wallet::new().public_key();
+        wallet.encrypt("correct horse").unwrap();
+
+        assert!(wallet.is_locked());
+        assert!(matches!(
+            wallet.send(receiver, 1_000, 1),
+            Err(Error::Locked)
+        ));
+        assert!(matches!(
+            wallet.unlock("wrong", Duration::from_secs(60)),
+            Err(Error::WrongPassphrase)
+        ));
+
+        wallet
+            .unlock("correct horse", Duration::from_secs(60))
+            .unwrap();
+        let txn = wallet.send(receiver, 1_000, 1).unwrap();
+        assert!(wallet.unlocking_script(&txn, 0).is_ok());
+
+        wallet.lock().unwrap();
+        assert!(matches!(
+            wallet.unlocking_script(&txn, 0),
+            Err(Error::Locked)
+        ));
+
+        wallet.unlock("correct horse", Duration::ZERO).unwrap();
+        assert!(wallet.is_locked());
+        // A timeout past what the clock can count is refused
+        assert!(matches!(
+            wallet.unlock("correct horse", Duration::MAX),
+            Err(Error::UnlockTimeoutTooLong(_))
+        ));
+        assert!(wallet.is_locked());
+
+        // The keystore alone recovers the key, still behind the passphrase
+        let keystore = wallet.keystore().unwrap().clone();
+        assert!(matches!(
+            Wallet::from_keystore(keystore.clone(), "wrong"),
+            Err(Error::WrongPassphrase)
+        ));
+        let mut imported = Wallet::from_keystore(keystore, "correct horse").unwrap();
+        assert_eq!(imported.public_key(), wallet.public_key());
+        assert!(imported.is_locked());
+    }
+
+    #[test]
+    fn enforces_the_spending_policy_when_signing() {
+        let mut wallet = funded_wallet(&[20_000, 20_000, 20_000, 20_000]);
+        let receiver = Wallet::new().public_key();
+        let mut policy = SpendingPolicy::default();
+        policy.max_per_day = Some(12_000);
+        policy.approval_threshold = Some(5_000);
+        policy.set_approval_token("second factor").unwrap();
+        wallet.set_policy(policy).unwrap();
+
+        assert!(matches!(
+            wallet.send(receiver, 5_000, 1),
+            Err(Error::ApprovalRequired { .. })
+        ));
+        assert!(matches!(
+            wallet.approve("guess"),
+            Err(Error::WrongApprovalToken)
+        ));
+        wallet.approve("second factor").unwrap();
+        wallet.send(receiver, 5_000, 1).unwrap();
+        // The approval was used up
+        assert!(wallet.send(receiver, 5_000, 1).is_err());
+
+        wallet.send(receiver, 4_000, 1).unwrap();
+        // Fees count towards the limits too
+        let fees: u64 = wallet.history().iter().map(|entry| entry.fee).sum();
+        assert!(fees > 0);
+        assert_eq!(wallet.spent_today(), 9_000 + fees);
+        assert!(matches!(
+            wallet.send(receiver, 2_000, 1),
+            Err(Error::DailyLimitExceeded { remaining, .. }) if remaining == 3_000 - fees
+        ));
+
+        // Spends built elsewhere are checked when their inputs are signed,
+        // the fee leaving the wallet takes this one over the limit
+        let mut signing_key = SigningKey::generate(&mut OsRng);
+        let mut txn = Transaction::new(&mut signing_key, receiver).unwrap();
+        let input = wallet.utxos().next().unwrap().clone();
+        let change = input.value() - 2_000;
+        txn.add_inputs(vec![input], &mut signing_key).unwrap();
+        let outputs = vec![
+            UTXO::new(1_000, 0, receiver).unwrap(),
+            UTXO::new(change, 1, wallet.public_key()).unwrap(),
+        ];
+        txn.add_outputs(outputs, &mut signing_key).unwrap();
+        assert!(matches!(
+            wallet.unlocking_script(&txn, 0),
+            Err(Error::DailyLimitExceeded { amount: 2_000, .. })
+        ));
+        assert!(wallet.unlocking_scripts(&txn).is_err());
+
+        // Loosening the policy takes the token too, which the file keeps
+        let mut wallet = Wallet::from_bytes(&wallet.to_bytes().unwrap()).unwrap();
+        assert!(matches!(
+            wallet.set_policy(SpendingPolicy::default()),
+            Err(Error::WrongApprovalToken)
+        ));
+        wallet.approve("second factor").unwrap();
+        wallet.set_policy(SpendingPolicy::default()).unwrap();
+        wallet.send(receiver, 2_000, 1).unwrap();
+    }
+
+    #[test]
+    fn refuses_policies_changed_without_the_key() {
+        let mut wallet = Wallet::new();
+        let mut policy = SpendingPolicy::default();
+        policy.max_per_transaction = Some(1_000);
+        wallet.set_policy(policy).unwrap();
+        wallet.encrypt("correct horse").unwrap();
+        let bytes = wallet.to_bytes().unwrap();
+        assert!(Wallet::from_bytes(&bytes).is_ok());
+
+        // Dropping the limit from the file alone
+        let mut file = WalletFile::try_from_slice(&bytes).unwrap();
+        file.policy = SpendingPolicy::default();
+        assert!(matches!(
+            Wallet::from_bytes(&borsh::to_vec(&file).unwrap()),
+            Err(Error::PolicyNotSigned)
+        ));
+
+        // Or signing it with another key
+        let other = SigningKey::generate(&mut OsRng);
+        file.public_key = other.verifying_key().to_bytes();
+        file.policy_signature = sign::sign_message(&other, &file.policy.signed_message());
+        assert!(matches!(
+            Wallet::from_bytes(&borsh::to_vec(&file).unwrap()),
+            Err(Error::PolicyNotSigned)
+        ));
+    }
+
+    #[test]
+    fn saves_the_wallet_privately() {
+        let dir = std::env::temp_dir().join(format!("aurelius-wallet-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wallet.dat");
+        // Left behind by a crash, with a mode anyone can read
+        std::fs::write(path.with_extension("tmp"), b"torn").unwrap();
+
+        let wallet = funded_wallet(&[5_000]);
+        wallet.save(&path).unwrap();
+        let loaded = Wallet::load(&path).unwrap();
+        assert_eq!(loaded.public_key(), wallet.public_key());
+        assert_eq!(loaded.balance(), wallet.balance());
+        assert!(!path.with_extension("tmp").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
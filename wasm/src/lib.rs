@@ -0,0 +1,277 @@
+use std::fmt::Display;
+
+use borsh::BorshDeserialize;
+use corelib::{
+    address::Address, config::Network, merkle, sign, transaction::Transaction, utxo::UTXO,
+};
+use ed25519_dalek::SigningKey;
+use wasm_bindgen::prelude::*;
+
+// Bindings exposing transaction building, signing, addresses and merkle proof
+// verification to JavaScript. Transactions and UTXOs cross the boundary
+// Borsh encoded, the same bytes the node's RPC hex encodes. Networks are
+// passed by name, e.g. `"mainnet"`.
+
+fn js_error(e: impl Display) -> JsError {
+    JsError::new(&e.to_string())
+}
+
+fn signing_key(secret_key: &[u8]) -> Result<SigningKey, JsError> {
+    let secret_key =
+        <[u8; 32]>::try_from(secret_key).map_err(|_| js_error("secret key must be 32 bytes"))?;
+    Ok(SigningKey::from_bytes(&secret_key))
+}
+
+fn network(name: &str) -> Result<Network, JsError> {
+    name.parse().map_err(js_error)
+}
+
+// Address of the secret key on the network, the one funds are sent to
+#[wasm_bindgen]
+pub fn address(secret_key: &[u8], network_name: &str) -> Result<String, JsError> {
+    let key = signing_key(secret_key)?.verifying_key();
+    Ok(Address::from_verifying_key(network(network_name)?, &key).to_string())
+}
+
+// Public key of an address, rejects addresses of other networks and ones
+// that aren't valid keys
+#[wasm_bindgen(js_name = decodeAddress)]
+pub fn decode_address(address: &str, network_name: &str) -> Result<Vec<u8>, JsError> {
+    parse_address(address, network(network_name)?)
+        .map(|public_key| public_key.to_vec())
+        .map_err(js_error)
+}
+
+// Errors are plain strings until they reach JavaScript, a `JsError` can only
+// be created in a wasm runtime
+fn parse_address(address: &str, network: Network) -> Result<[u8; 32], String> {
+    let address: Address = address.parse().map_err(|e: corelib::errors::Error| e.to_string())?;
+    if address.network() != network {
+        return Err(format!(
+            "{address} is a {} address, expected {network}",
+            address.network()
+        ));
+    }
+
+    Ok(address.key())
+}
+
+#[wasm_bindgen(js_name = signMessage)]
+pub fn sign_message(secret_key: &[u8], message: &str) -> Result<Vec<u8>, JsError> {
+    Ok(sign::sign_message(&signing_key(secret_key)?, message.as_bytes()).to_vec())
+}
+
+#[wasm_bindgen(js_name = verifyMessage)]
+pub fn verify_message(address: &str, message: &str, signature: &[u8]) -> bool {
+    let (Ok(address), Ok(signature)) =
+        (address.parse::<Address>(), <[u8; 64]>::try_from(signature))
+    else {
+        return false;
+    };
+
+    sign::verify_message(&address.key(), message.as_bytes(), &signature).is_ok()
+}
+
+// Hex encoded id of a Borsh encoded transaction
+#[wasm_bindgen(js_name = transactionId)]
+pub fn transaction_id(transaction: &[u8]) -> Result<String, JsError> {
+    let transaction = Transaction::try_from_slice(transaction).map_err(js_error)?;
+    Ok(hex::encode(transaction.hash_id))
+}
+
+// Checks a Borsh encoded proof that `leaf` is part of the tree with `root`
+#[wasm_bindgen(js_name = verifyMerkleProof)]
+pub fn verify_merkle_proof(leaf: &[u8], proof: &[u8], root: &[u8]) -> bool {
+    let (Ok(leaf), Ok(proof), Ok(root)) = (
+        <[u8; 32]>::try_from(leaf),
+        merkle::Proof::try_from_slice(proof),
+        <[u8; 32]>::try_from(root),
+    ) else {
+        return false;
+    };
+
+    merkle::Tree::verify_proof(leaf, &proof, root)
+}
+
+// Builds and signs a payment from the key's confirmed UTXOs.
+//
+// Once the chain height is known the payment is locked to it, so it can only
+// be mined on top of the current tip and not into a block replacing it. That
+// takes away the reason to orphan the tip just to collect its fees
+#[wasm_bindgen]
+pub struct TransactionBuilder {
+    signing_key: SigningKey,
+    // Network the addresses paid to belong to
+    network: Network,
+    receiver: [u8; 32],
+    inputs: Vec<UTXO>,
+    outputs: Vec<UTXO>,
+    // Height of the next block, as the node's `getblockcount` reports it
+    chain_height: Option<u64>,
+    // Set explicitly, overrides the chain height
+    locktime: Option<u64>,
+}
+
+#[wasm_bindgen]
+impl TransactionBuilder {
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        secret_key: &[u8],
+        receiver: &str,
+        network_name: &str,
+    ) -> Result<TransactionBuilder, JsError> {
+        let network = network(network_name)?;
+
+        Ok(Self {
+            signing_key: signing_key(secret_key)?,
+            network,
+            receiver: parse_address(receiver, network).map_err(js_error)?,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            chain_height: None,
+            locktime: None,
+        })
+    }
+
+    // Locks the payment to the height by default
+    #[wasm_bindgen(js_name = setChainHeight)]
+    pub fn set_chain_height(&mut self, height: u64) {
+        self.chain_height = Some(height);
+    }
+
+    // Locks the payment until the block height or time instead, 0 leaves it
+    // unlocked
+    #[wasm_bindgen(js_name = setLocktime)]
+    pub fn set_locktime(&mut self, locktime: u64) {
+        self.locktime = Some(locktime);
+    }
+
+    // Spends a Borsh encoded confirmed UTXO, as listed by the node
+    #[wasm_bindgen(js_name = addInput)]
+    pub fn add_input(&mut self, utxo: &[u8]) -> Result<(), JsError> {
+        let utxo = UTXO::try_from_slice(utxo).map_err(js_error)?;
+        if utxo.outpoint().is_none() {
+            return Err(js_error("only confirmed UTXOs can be spent"));
+        }
+
+        self.inputs.push(utxo);
+        Ok(())
+    }
+
+    // Pays `value` to `owner`, outputs are indexed in the order they're added
+    #[wasm_bindgen(js_name = addOutput)]
+    pub fn add_output(&mut self, value: u64, owner: &str) -> Result<(), JsError> {
+        let output = UTXO::new(
+            value,
+            self.outputs.len() as u32,
+            parse_address(owner, self.network).map_err(js_error)?,
+        )
+        .map_err(js_error)?;
+
+        self.outputs.push(output);
+        Ok(())
+    }
+
+    // Signs the transaction and returns its Borsh encoding. `timestamp` is in
+    // milliseconds since the unix epoch, e.g. `Date.now()`, as wasm has no
+    // clock of its own
+    pub fn build(&self, timestamp: f64) -> Result<Vec<u8>, JsError> {
+        let mut signing_key = self.signing_key.clone();
+
+        let mut transaction =
+            Transaction::with_timestamp(&mut signing_key, self.receiver, timestamp as u128);
+        transaction
+            .add_inputs(self.inputs.clone(), &mut signing_key)
+            .map_err(js_error)?;
+        transaction
+            .add_outputs(self.outputs.clone(), &mut signing_key)
+            .map_err(js_error)?;
+
+        let locktime = self.locktime.or(self.chain_height).unwrap_or(0);
+        if locktime > 0 {
+            transaction.set_locktime(locktime, &mut signing_key);
+        }
+
+        borsh::to_vec(&transaction).map_err(js_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use corelib::script::SigHash;
+
+    use super::*;
+
+    #[test]
+    fn builds_transactions_the_node_accepts() {
+        let secret_key = [7u8; 32];
+        let sender = decode_address(&address(&secret_key, "regtest").unwrap(), "regtest").unwrap();
+        let receiver_key = SigningKey::from_bytes(&[8u8; 32]).verifying_key();
+        let receiver = Address::from_verifying_key(Network::Regtest, &receiver_key).to_string();
+        assert!(receiver.starts_with(Network::Regtest.address_prefix()));
+
+        // Addresses of another network and bare keys are refused
+        let mainnet = address(&secret_key, "mainnet").unwrap();
+        assert!(parse_address(&mainnet, Network::Regtest).is_err());
+        assert!(parse_address(&hex::encode(&sender), Network::Regtest).is_err());
+
+        let utxo = UTXO::new(1_000, 0, sender.clone().try_into().unwrap())
+            .unwrap()
+            .confirm_utxo([1u8; 32], 1, false)
+            .unwrap();
+
+        let mut builder = TransactionBuilder::new(&secret_key, &receiver, "regtest").unwrap();
+        builder.add_input(&borsh::to_vec(&utxo).unwrap()).unwrap();
+        builder.add_output(900, &receiver).unwrap();
+        let encoded = builder.build(1_700_000_000_000.0).unwrap();
+
+        let transaction = Transaction::try_from_slice(&encoded).unwrap();
+        assert_eq!(
+            transaction_id(&encoded).unwrap(),
+            hex::encode(transaction.hash_id)
+        );
+        assert_eq!(transaction.timestamp, 1_700_000_000_000);
+
+        let key = SigningKey::from_bytes(&secret_key);
+        let unlocking_script = format!(
+            "{} {}",
+            hex::encode(transaction.sign_input(0, SigHash::All, &key).unwrap()),
+            hex::encode(&sender)
+        );
+        assert_eq!(transaction.verify(&unlocking_script).unwrap().2, 100);
+        assert_eq!(transaction.locktime, 0);
+
+        // Locked to the chain height unless opted out
+        builder.set_chain_height(42);
+        let locked =
+            Transaction::try_from_slice(&builder.build(1_700_000_000_000.0).unwrap()).unwrap();
+        assert_eq!(locked.locktime, 42);
+        assert!(!locked.is_final(41, 1_700_000_000_000));
+        assert!(locked.is_final(42, 1_700_000_000_000));
+
+        builder.set_locktime(0);
+        let unlocked =
+            Transaction::try_from_slice(&builder.build(1_700_000_000_000.0).unwrap()).unwrap();
+        assert_eq!(unlocked.locktime, 0);
+
+        let signature = sign_message(&secret_key, "hello").unwrap();
+        assert!(verify_message(
+            &address(&secret_key, "mainnet").unwrap(),
+            "hello",
+            &signature
+        ));
+        assert!(!verify_message(&receiver, "hello", &signature));
+    }
+
+    #[test]
+    fn verifies_merkle_proofs() {
+        let leaves: Vec<[u8; 32]> = (1..=3u8).map(|i| [i; 32]).collect();
+        let tree = merkle::Tree::with_hashes(&leaves);
+        let root = tree.root_hash().unwrap();
+        let proof = borsh::to_vec(&tree.generate_proof(2).unwrap()).unwrap();
+
+        assert!(verify_merkle_proof(&leaves[2], &proof, &root));
+        assert!(!verify_merkle_proof(&leaves[1], &proof, &root));
+        assert!(!verify_merkle_proof(&leaves[2], &[1, 2, 3], &root));
+    }
+}